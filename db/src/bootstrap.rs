@@ -10,6 +10,8 @@
 
 #![allow(dead_code)]
 
+use std::collections::BTreeMap;
+
 use {to_namespaced_keyword};
 use edn;
 use edn::types::Value;
@@ -20,124 +22,45 @@ use mentat_tx_parser;
 use types::{IdentMap, Partition, PartitionMap, Schema, TypedValue};
 use values;
 
-lazy_static! {
-    static ref V1_IDENTS: Vec<(&'static str, i64)> = {
-        vec![(":db/ident",             entids::DB_IDENT),
-             (":db.part/db",           entids::DB_PART_DB),
-             (":db/txInstant",         entids::DB_TX_INSTANT),
-             (":db.install/partition", entids::DB_INSTALL_PARTITION),
-             (":db.install/valueType", entids::DB_INSTALL_VALUETYPE),
-             (":db.install/attribute", entids::DB_INSTALL_ATTRIBUTE),
-             (":db/valueType",         entids::DB_VALUE_TYPE),
-             (":db/cardinality",       entids::DB_CARDINALITY),
-             (":db/unique",            entids::DB_UNIQUE),
-             (":db/isComponent",       entids::DB_IS_COMPONENT),
-             (":db/index",             entids::DB_INDEX),
-             (":db/fulltext",          entids::DB_FULLTEXT),
-             (":db/noHistory",         entids::DB_NO_HISTORY),
-             (":db/add",               entids::DB_ADD),
-             (":db/retract",           entids::DB_RETRACT),
-             (":db.part/user",         entids::DB_PART_USER),
-             (":db.part/tx",           entids::DB_PART_TX),
-             (":db/excise",            entids::DB_EXCISE),
-             (":db.excise/attrs",      entids::DB_EXCISE_ATTRS),
-             (":db.excise/beforeT",    entids::DB_EXCISE_BEFORE_T),
-             (":db.excise/before",     entids::DB_EXCISE_BEFORE),
-             (":db.alter/attribute",   entids::DB_ALTER_ATTRIBUTE),
-             (":db.type/ref",          entids::DB_TYPE_REF),
-             (":db.type/keyword",      entids::DB_TYPE_KEYWORD),
-             (":db.type/long",         entids::DB_TYPE_LONG),
-             (":db.type/double",       entids::DB_TYPE_DOUBLE),
-             (":db.type/string",       entids::DB_TYPE_STRING),
-             (":db.type/boolean",      entids::DB_TYPE_BOOLEAN),
-             (":db.type/instant",      entids::DB_TYPE_INSTANT),
-             (":db.type/bytes",        entids::DB_TYPE_BYTES),
-             (":db.cardinality/one",   entids::DB_CARDINALITY_ONE),
-             (":db.cardinality/many",  entids::DB_CARDINALITY_MANY),
-             (":db.unique/value",      entids::DB_UNIQUE_VALUE),
-             (":db.unique/identity",   entids::DB_UNIQUE_IDENTITY),
-             (":db/doc",               entids::DB_DOC),
-        ]
-    };
-
-    static ref V2_IDENTS: Vec<(&'static str, i64)> = {
-        [(*V1_IDENTS).clone(),
-         vec![(":db.schema/version",   entids::DB_SCHEMA_VERSION),
-              (":db.schema/attribute", entids::DB_SCHEMA_ATTRIBUTE),
-         ]].concat()
-    };
+/// The first transaction ID applied to the knowledge base.
+///
+/// This is the start of the :db.part/tx partition.  The bootstrap assertions are all made in this
+/// transaction.
+pub const TX0: i64 = 0x10000000;
 
-    static ref V1_PARTS: Vec<(&'static str, i64, i64)> = {
-        vec![(":db.part/db", 0, (1 + V1_IDENTS.len()) as i64),
-             (":db.part/user", 0x10000, 0x10000),
-             (":db.part/tx", 0x10000000, 0x10000000),
-        ]
+lazy_static! {
+    static ref IDENTS: Vec<(&'static str, i64)> = {
+        entids::CORE_IDENTS.iter().map(|core| (core.ident, core.entid)).collect()
     };
 
-    static ref V2_PARTS: Vec<(&'static str, i64, i64)> = {
-        vec![(":db.part/db", 0, (1 + V2_IDENTS.len()) as i64),
+    static ref PARTS: Vec<(&'static str, i64, i64)> = {
+        vec![(":db.part/db", 0, (1 + IDENTS.len()) as i64),
              (":db.part/user", 0x10000, 0x10000),
-             (":db.part/tx", 0x10000000, 0x10000000),
+             // The bootstrap transaction is TX0; the first user transaction is TX0 + 1.
+             (":db.part/tx", TX0, TX0 + 1),
         ]
     };
 
-    static ref V1_SYMBOLIC_SCHEMA: Value = {
-        let s = r#"
-{:db/ident             {:db/valueType   :db.type/keyword
-                       :db/cardinality :db.cardinality/one
-                       :db/unique      :db.unique/identity}
- :db.install/partition {:db/valueType   :db.type/ref
-                        :db/cardinality :db.cardinality/many}
- :db.install/valueType {:db/valueType   :db.type/ref
-                        :db/cardinality :db.cardinality/many}
- :db.install/attribute {:db/valueType   :db.type/ref
-                        :db/cardinality :db.cardinality/many}
- ;; TODO: support user-specified functions in the future.
- ;; :db.install/function {:db/valueType :db.type/ref
- ;;                       :db/cardinality :db.cardinality/many}
- :db/txInstant         {:db/valueType   :db.type/long
-                        :db/cardinality :db.cardinality/one
-                        :db/index       true}
- :db/valueType         {:db/valueType   :db.type/ref
-                        :db/cardinality :db.cardinality/one}
- :db/cardinality       {:db/valueType   :db.type/ref
-                        :db/cardinality :db.cardinality/one}
- :db/doc               {:db/valueType   :db.type/string
-                        :db/cardinality :db.cardinality/one}
- :db/unique            {:db/valueType   :db.type/ref
-                        :db/cardinality :db.cardinality/one}
- :db/isComponent       {:db/valueType   :db.type/boolean
-                        :db/cardinality :db.cardinality/one}
- :db/index             {:db/valueType   :db.type/boolean
-                        :db/cardinality :db.cardinality/one}
- :db/fulltext          {:db/valueType   :db.type/boolean
-                        :db/cardinality :db.cardinality/one}
- :db/noHistory         {:db/valueType   :db.type/boolean
-                        :db/cardinality :db.cardinality/one}}"#;
-        edn::parse::value(s)
-            .map_err(|_| ErrorKind::BadBootstrapDefinition("Unable to parse V1_SYMBOLIC_SCHEMA".into()))
+    static ref SYMBOLIC_SCHEMA: Value = {
+        core_symbolic_schema(entids::CORE_IDENTS)
+            .map_err(|_| ErrorKind::BadBootstrapDefinition("Unable to parse the core schema".into()))
             .unwrap()
     };
+}
 
-    static ref V2_SYMBOLIC_SCHEMA: Value = {
-        let s = r#"
-{:db.alter/attribute   {:db/valueType   :db.type/ref
-                        :db/cardinality :db.cardinality/many}
- :db.schema/version    {:db/valueType   :db.type/long
-                        :db/cardinality :db.cardinality/one}
-
- ;; unique-value because an attribute can only belong to a single
- ;; schema fragment.
- :db.schema/attribute  {:db/valueType   :db.type/ref
-                        :db/unique      :db.unique/value
-                        :db/cardinality :db.cardinality/many}}"#;
-        let right = edn::parse::value(s)
-            .map_err(|_| ErrorKind::BadBootstrapDefinition("Unable to parse V2_SYMBOLIC_SCHEMA".into()))
-            .unwrap();
-        edn::utils::merge(&V1_SYMBOLIC_SCHEMA, &right)
-            .ok_or(ErrorKind::BadBootstrapDefinition("Unable to parse V2_SYMBOLIC_SCHEMA".into()))
-            .unwrap()
-    };
+/// Gather the schema of each core attribute into `{:db/ident {:db/valueType ...} ...}`.
+fn core_symbolic_schema(idents: &[entids::CoreIdent]) -> Result<Value> {
+    let mut schema = BTreeMap::new();
+    for core in idents {
+        if let Some(attribute) = core.schema {
+            let ident = to_namespaced_keyword(core.ident)
+                .ok_or(ErrorKind::BadBootstrapDefinition(format!("Expected a keyword but got {}", core.ident)))?;
+            let attribute = edn::parse::value(attribute)
+                .map_err(|_| ErrorKind::BadBootstrapDefinition(format!("Unable to parse the schema of {}", core.ident)))?;
+            schema.insert(Value::NamespacedKeyword(ident), attribute);
+        }
+    }
+    Ok(Value::Map(schema))
 }
 
 /// Convert (ident, entid) pairs into [:db/add IDENT :db/ident IDENT] `Value` instances.
@@ -183,7 +106,10 @@ fn symbolic_schema_to_triples(ident_map: &IdentMap, symbolic_schema: &Value) ->
                             // bootstrap symbolic schema, or by representing the initial bootstrap
                             // schema directly as Rust data.
                             let typed_value = match TypedValue::from_edn_value(value) {
-                                Some(TypedValue::Keyword(ref s)) => TypedValue::Ref(*ident_map.get(s).ok_or(ErrorKind::UnrecognizedIdent(s.clone()))?),
+                                Some(TypedValue::Keyword(ref s)) => {
+                                    let s = s.to_string();
+                                    TypedValue::Ref(*ident_map.get(&s).ok_or(ErrorKind::UnrecognizedIdent(s.clone()))?)
+                                },
                                 Some(v) => v,
                                 _ => bail!(ErrorKind::BadBootstrapDefinition(format!("Expected Mentat typed value for value but got '{:?}'", value)))
                             };
@@ -233,27 +159,35 @@ fn symbolic_schema_to_assertions(symbolic_schema: &Value) -> Result<Vec<Value>>
 }
 
 pub fn bootstrap_partition_map() -> PartitionMap {
-    V2_PARTS[..].iter()
+    PARTS[..].iter()
         .map(|&(part, start, index)| (part.to_string(), Partition::new(start, index)))
         .collect()
 }
 
 pub fn bootstrap_ident_map() -> IdentMap {
-    V2_IDENTS[..].iter()
+    IDENTS[..].iter()
         .map(|&(ident, entid)| (ident.to_string(), entid))
         .collect()
 }
 
+/// Return the bootstrap schema as vec![(String(:ident), String(:key), TypedValue(:value)), ...].
+///
+/// These are exactly the rows of the `schema` materialized view of a freshly created store.
+pub fn bootstrap_schema_triples() -> Vec<(String, String, TypedValue)> {
+    let ident_map = bootstrap_ident_map();
+    symbolic_schema_to_triples(&ident_map, &SYMBOLIC_SCHEMA).unwrap()
+}
+
 pub fn bootstrap_schema() -> Schema {
     let ident_map = bootstrap_ident_map();
-    let bootstrap_triples = symbolic_schema_to_triples(&ident_map, &V2_SYMBOLIC_SCHEMA).unwrap();
+    let bootstrap_triples = bootstrap_schema_triples();
     Schema::from_ident_map_and_triples(ident_map, bootstrap_triples).unwrap()
 }
 
 pub fn bootstrap_entities() -> Vec<Entity> {
     let bootstrap_assertions: Value = Value::Vector([
-        symbolic_schema_to_assertions(&V2_SYMBOLIC_SCHEMA).unwrap(),
-        idents_to_assertions(&V2_IDENTS[..]),
+        symbolic_schema_to_assertions(&SYMBOLIC_SCHEMA).unwrap(),
+        idents_to_assertions(&IDENTS[..]),
     ].concat());
 
     // Failure here is a coding error (since the inputs are fixed), not a runtime error.
@@ -261,3 +195,21 @@ pub fn bootstrap_entities() -> Vec<Entity> {
     let bootstrap_entities: Vec<Entity> = mentat_tx_parser::Tx::parse(&[bootstrap_assertions][..]).unwrap();
     return bootstrap_entities;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_core_idents() {
+        // The `:db.part/db` range assumes the core entids are 1, 2, 3, ...
+        for (i, core) in entids::CORE_IDENTS.iter().enumerate() {
+            assert_eq!(core.entid, 1 + i as i64, "{} is out of order", core.ident);
+        }
+        assert_eq!(bootstrap_ident_map().len(), entids::CORE_IDENTS.len());
+
+        let attributes = entids::CORE_IDENTS.iter().filter(|core| core.schema.is_some()).count();
+        assert_eq!(bootstrap_schema().schema_map.len(), attributes);
+        assert_eq!(entids::idents_added_in(9), vec![":db.type/tuple", ":db/tupleTypes"]);
+    }
+}
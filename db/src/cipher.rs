@@ -0,0 +1,158 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Values of attributes flagged `:db/encrypted true` are encrypted with a caller-provided `Cipher`
+//! before they're written to the datoms table or the transaction log, and decrypted when they're
+//! read back.  This keeps sensitive values off disk in plaintext without encrypting the whole
+//! store.
+//!
+//! An encrypted value is stored as a blob with its own value type tag.  The plaintext is the
+//! value's own type tag, as a single byte, followed by the value: the UTF-8 bytes of a string or
+//! json value, or the bytes of a bytes value.
+//!
+//! Encrypting a value twice needn't give the same ciphertext, so stored encrypted values are never
+//! compared in SQL: the transactor finds an entity's existing value by decrypting each of its
+//! values for the attribute, and queries can't constrain encrypted values.
+
+use rusqlite;
+
+use errors::*;
+use types::TypedValue;
+
+/// The `value_type_tag` of an encrypted value, which is stored as a blob.
+pub const ENCRYPTED_VALUE_TYPE_TAG: i32 = 15;
+
+/// Encrypts and decrypts the values of `:db/encrypted` attributes, with a key held by the caller.
+///
+/// Implementations should use an authenticated cipher, so that decrypting with the wrong key fails
+/// rather than returning garbage.
+pub trait Cipher: Send + Sync {
+    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8>;
+
+    /// Return the plaintext, or `None` if `ciphertext` can't be decrypted with this cipher's key.
+    fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Encrypt `typed_value`, which must be a string, bytes, or json value.
+pub fn encrypt_value(cipher: &Cipher, typed_value: &TypedValue) -> Result<Vec<u8>> {
+    let (tag, bytes): (u8, &[u8]) = match typed_value {
+        &TypedValue::String(ref x) => (10, x.as_bytes()),
+        &TypedValue::Bytes(ref x) => (12, &x[..]),
+        &TypedValue::Json(ref x) => (14, x.as_bytes()),
+        _ => bail!(ErrorKind::NotYetImplemented(format!("encrypting {:?} values", typed_value.value_type()))),
+    };
+    let mut plaintext = Vec::with_capacity(1 + bytes.len());
+    plaintext.push(tag);
+    plaintext.extend_from_slice(bytes);
+    Ok(cipher.encrypt(&plaintext))
+}
+
+/// Decrypt a value encrypted by `encrypt_value`.
+pub fn decrypt_value(cipher: &Cipher, ciphertext: &[u8]) -> Result<TypedValue> {
+    let plaintext = cipher.decrypt(ciphertext).ok_or(ErrorKind::DecryptionFailed)?;
+    let (tag, bytes) = match plaintext.split_first() {
+        Some((&tag, bytes)) => (tag, bytes.to_vec()),
+        None => bail!(ErrorKind::DecryptionFailed),
+    };
+    match tag {
+        10 => String::from_utf8(bytes).map(TypedValue::String).map_err(|_| ErrorKind::DecryptionFailed.into()),
+        12 => Ok(TypedValue::Bytes(bytes)),
+        14 => String::from_utf8(bytes).map(TypedValue::Json).map_err(|_| ErrorKind::DecryptionFailed.into()),
+        _ => bail!(ErrorKind::DecryptionFailed),
+    }
+}
+
+/// Like `TypedValue::from_sql_value_pair`, but decrypt encrypted values with `cipher`.  Fails with
+/// `MissingCipher` if an encrypted value is read without one.
+pub fn decrypt_sql_value_pair(value: rusqlite::types::Value, value_type_tag: &i32, cipher: Option<&Cipher>) -> Result<TypedValue> {
+    match (*value_type_tag, value) {
+        (ENCRYPTED_VALUE_TYPE_TAG, rusqlite::types::Value::Blob(ciphertext)) => {
+            let cipher = cipher.ok_or(ErrorKind::MissingCipher)?;
+            decrypt_value(cipher, &ciphertext)
+        },
+        (_, value) => TypedValue::from_sql_value_pair(value, value_type_tag),
+    }
+}
+
+/// XOR with a repeating key, after a check byte.  Only fit for tests, here and in the crates that
+/// use this one with the `testing` feature.  An empty key leaves the plaintext as it is.
+#[cfg(any(test, feature = "testing"))]
+pub struct XorCipher(pub Vec<u8>);
+
+#[cfg(any(test, feature = "testing"))]
+impl XorCipher {
+    fn key_byte(&self, i: usize) -> u8 {
+        if self.0.is_empty() { 0 } else { self.0[i % self.0.len()] }
+    }
+
+    fn xor(&self, bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().enumerate().map(|(i, b)| b ^ self.key_byte(i)).collect()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Cipher for XorCipher {
+    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut ciphertext = vec![self.key_byte(0)];
+        ciphertext.extend(self.xor(plaintext));
+        ciphertext
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        match ciphertext.split_first() {
+            Some((&check, rest)) if check == self.key_byte(0) => Some(self.xor(rest)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let cipher = XorCipher(b"key".to_vec());
+        for value in vec![TypedValue::String("secret".to_string()),
+                          TypedValue::Bytes(vec![0, 1, 2]),
+                          TypedValue::Json("{\"a\": 1}".to_string())] {
+            let ciphertext = encrypt_value(&cipher, &value).unwrap();
+            assert_eq!(decrypt_value(&cipher, &ciphertext).unwrap(), value);
+
+            let sql = rusqlite::types::Value::Blob(ciphertext);
+            assert_eq!(decrypt_sql_value_pair(sql.clone(), &ENCRYPTED_VALUE_TYPE_TAG, Some(&cipher as &Cipher)).unwrap(), value);
+            match decrypt_sql_value_pair(sql, &ENCRYPTED_VALUE_TYPE_TAG, None) {
+                Err(Error(ErrorKind::MissingCipher, _)) => (),
+                x => panic!("expected MissingCipher, got {:?}", x),
+            }
+        }
+
+        assert!(encrypt_value(&cipher, &TypedValue::Long(1)).is_err());
+
+        // Plain values pass through.
+        assert_eq!(decrypt_sql_value_pair(rusqlite::types::Value::Integer(7), &5, None).unwrap(), TypedValue::Long(7));
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let ciphertext = encrypt_value(&XorCipher(b"key".to_vec()), &TypedValue::String("secret".to_string())).unwrap();
+        match decrypt_value(&XorCipher(b"other".to_vec()), &ciphertext) {
+            Err(Error(ErrorKind::DecryptionFailed, _)) => (),
+            x => panic!("expected DecryptionFailed, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_empty_key() {
+        let cipher = XorCipher(vec![]);
+        let value = TypedValue::String("secret".to_string());
+        assert_eq!(decrypt_value(&cipher, &encrypt_value(&cipher, &value).unwrap()).unwrap(), value);
+    }
+}
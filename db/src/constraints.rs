@@ -0,0 +1,218 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Constraints on the values of particular attributes, beyond their value type: bounds, patterns,
+//! enumerations, and arbitrary predicates.
+//!
+//! Constraints belong to the connection, not to the schema: they aren't stored, and they're only
+//! checked by transactions given them.  The transactor checks each value asserted for a
+//! constrained attribute, and rejects a violating assertion with a `Constraint` conflict that names
+//! the offending entity.  Values already in the store aren't checked.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use errors::*;
+use types::{Attribute, Entid, Schema, TypedValue, ValueType};
+
+/// A check of an attribute's values: `Ok(())` if the value is acceptable, or a message saying why
+/// it isn't.
+pub type Predicate = Arc<Fn(&TypedValue) -> ::std::result::Result<(), String> + Send + Sync>;
+
+/// A constraint on the values of an attribute.
+#[derive(Clone)]
+pub enum Constraint {
+    /// Values must be at least the given value, which has the attribute's value type.
+    Min(TypedValue),
+
+    /// Values must be at most the given value, which has the attribute's value type.
+    Max(TypedValue),
+
+    /// String and keyword values must match the given pattern.  Anchor the pattern, like
+    /// `^[a-z]+$`, to match whole values.
+    Matches(Regex),
+
+    /// Ref values must be entities with one of the given idents, like `:todo.status/open`.
+    OneOf(BTreeSet<String>),
+
+    /// Values must satisfy the given predicate.
+    Predicate(Predicate),
+}
+
+impl fmt::Debug for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Constraint::Min(ref min) => write!(f, "Min({:?})", min),
+            &Constraint::Max(ref max) => write!(f, "Max({:?})", max),
+            &Constraint::Matches(ref regex) => write!(f, "Matches({:?})", regex.as_str()),
+            &Constraint::OneOf(ref idents) => write!(f, "OneOf({:?})", idents),
+            &Constraint::Predicate(_) => write!(f, "Predicate(..)"),
+        }
+    }
+}
+
+impl Constraint {
+    /// Values must match the given regular expression.  Fails if `pattern` doesn't compile.
+    pub fn matches(pattern: &str) -> Result<Constraint> {
+        Regex::new(pattern)
+            .map(Constraint::Matches)
+            .map_err(|e| ErrorKind::BadConstraint(format!("pattern {:?}: {}", pattern, e)).into())
+    }
+
+    /// Values must satisfy `predicate`, which returns a message saying why a value doesn't.
+    pub fn predicate<F>(predicate: F) -> Constraint
+        where F: Fn(&TypedValue) -> ::std::result::Result<(), String> + Send + Sync + 'static {
+        Constraint::Predicate(Arc::new(predicate))
+    }
+
+    /// Return `Ok(())` if this constraint can apply to the values of `attribute`.
+    fn check_attribute(&self, attribute: &Attribute) -> Result<()> {
+        let applies = match self {
+            &Constraint::Min(ref bound) | &Constraint::Max(ref bound) => bound.value_type() == attribute.value_type,
+            &Constraint::Matches(_) => attribute.value_type == ValueType::String || attribute.value_type == ValueType::Keyword,
+            &Constraint::OneOf(_) => attribute.value_type == ValueType::Ref,
+            &Constraint::Predicate(_) => true,
+        };
+        if !applies {
+            bail!(ErrorKind::BadConstraint(format!("{:?} for an attribute with value type {:?}", self, attribute.value_type)));
+        }
+        Ok(())
+    }
+
+    /// Return `None` if `value` satisfies this constraint, or a message saying why it doesn't.
+    /// Ref values are named by their idents in `schema`.
+    pub fn check(&self, schema: &Schema, value: &TypedValue) -> Option<String> {
+        match self {
+            &Constraint::Min(ref min) if value < min => Some(format!("{:?} is less than {:?}", value, min)),
+            &Constraint::Max(ref max) if value > max => Some(format!("{:?} is more than {:?}", value, max)),
+            &Constraint::Min(_) | &Constraint::Max(_) => None,
+            &Constraint::Matches(ref regex) => {
+                let matched = match value {
+                    &TypedValue::String(ref x) => regex.is_match(x),
+                    &TypedValue::Keyword(ref x) => regex.is_match(&x.to_string()),
+                    _ => false,
+                };
+                if matched { None } else { Some(format!("{:?} doesn't match {:?}", value, regex.as_str())) }
+            },
+            &Constraint::OneOf(ref idents) => {
+                let ident = match value {
+                    &TypedValue::Ref(entid) => schema.get_ident(&entid),
+                    _ => None,
+                };
+                match ident {
+                    Some(ident) if idents.contains(ident) => None,
+                    _ => Some(format!("{:?} isn't one of {:?}", value, idents)),
+                }
+            },
+            &Constraint::Predicate(ref predicate) => predicate(value).err(),
+        }
+    }
+}
+
+/// The constraints on the values of each attribute, keyed by attribute.
+#[derive(Clone,Debug,Default)]
+pub struct Constraints {
+    by_attribute: BTreeMap<Entid, Vec<Constraint>>,
+}
+
+impl Constraints {
+    pub fn new() -> Constraints {
+        Constraints::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_attribute.is_empty()
+    }
+
+    /// Constrain the values of attribute `a`, in addition to any constraints added before.  Fails
+    /// if `a` isn't an attribute of `schema`, or if `constraint` can't apply to its values, like a
+    /// `Min` of another value type.
+    pub fn add(&mut self, schema: &Schema, a: Entid, constraint: Constraint) -> Result<()> {
+        constraint.check_attribute(schema.require_attribute_for_entid(&a)?)?;
+        self.by_attribute.entry(a).or_insert_with(Vec::new).push(constraint);
+        Ok(())
+    }
+
+    /// Drop every constraint on attribute `a`.
+    pub fn remove(&mut self, a: Entid) {
+        self.by_attribute.remove(&a);
+    }
+
+    /// Return `None` if `value` satisfies every constraint on attribute `a`, or a message saying
+    /// why it doesn't satisfy the first it violates.
+    pub fn check(&self, schema: &Schema, a: Entid, value: &TypedValue) -> Option<String> {
+        self.by_attribute.get(&a)
+            .and_then(|constraints| constraints.iter().filter_map(|constraint| constraint.check(schema, value)).next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use edn::symbols::NamespacedKeyword;
+    use types::{IdentMap, SchemaMap};
+
+    fn schema() -> Schema {
+        let mut ident_map = IdentMap::new();
+        let mut schema_map = SchemaMap::new();
+        for &(ident, entid, ref value_type) in [(":test/age", 100, ValueType::Long),
+                                                (":test/code", 101, ValueType::String),
+                                                (":test/status", 102, ValueType::Ref),
+                                                (":test.status/open", 103, ValueType::Ref),
+                                                (":test.status/closed", 104, ValueType::Ref)].iter() {
+            ident_map.insert(ident.to_string(), entid);
+            schema_map.insert(entid, Attribute { value_type: value_type.clone(), ..Default::default() });
+        }
+        Schema::from(ident_map, schema_map).unwrap()
+    }
+
+    #[test]
+    fn test_constraints() {
+        let schema = schema();
+        let mut constraints = Constraints::new();
+        constraints.add(&schema, 100, Constraint::Min(TypedValue::Long(0))).unwrap();
+        constraints.add(&schema, 100, Constraint::Max(TypedValue::Long(150))).unwrap();
+        constraints.add(&schema, 101, Constraint::matches("^[A-Z]{3}$").unwrap()).unwrap();
+        constraints.add(&schema, 102, Constraint::OneOf(vec![":test.status/open".to_string()].into_iter().collect())).unwrap();
+        constraints.add(&schema, 101, Constraint::predicate(|value| {
+            if value == &TypedValue::String("BAD".to_string()) { Err("reserved".to_string()) } else { Ok(()) }
+        })).unwrap();
+
+        assert_eq!(constraints.check(&schema, 100, &TypedValue::Long(30)), None);
+        assert!(constraints.check(&schema, 100, &TypedValue::Long(-1)).is_some());
+        assert!(constraints.check(&schema, 100, &TypedValue::Long(151)).is_some());
+        assert_eq!(constraints.check(&schema, 101, &TypedValue::String("ABC".to_string())), None);
+        assert!(constraints.check(&schema, 101, &TypedValue::String("ABCD".to_string())).is_some());
+        assert_eq!(constraints.check(&schema, 101, &TypedValue::String("BAD".to_string())), Some("reserved".to_string()));
+        assert_eq!(constraints.check(&schema, 102, &TypedValue::Ref(103)), None);
+        assert!(constraints.check(&schema, 102, &TypedValue::Ref(104)).is_some());
+        assert!(constraints.check(&schema, 102, &TypedValue::Keyword(NamespacedKeyword::new("test.status", "open"))).is_some());
+
+        // Unconstrained attributes accept anything.
+        constraints.remove(100);
+        assert_eq!(constraints.check(&schema, 100, &TypedValue::Long(-1)), None);
+    }
+
+    #[test]
+    fn test_bad_constraints() {
+        let schema = schema();
+        let mut constraints = Constraints::new();
+        assert!(Constraint::matches("(").is_err());
+        assert!(constraints.add(&schema, 100, Constraint::Min(TypedValue::Double(0.0.into()))).is_err());
+        assert!(constraints.add(&schema, 100, Constraint::matches("x").unwrap()).is_err());
+        assert!(constraints.add(&schema, 101, Constraint::OneOf(BTreeSet::new())).is_err());
+        assert!(constraints.add(&schema, 999, Constraint::Min(TypedValue::Long(0))).is_err());
+        assert!(constraints.is_empty());
+    }
+}
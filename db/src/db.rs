@@ -10,18 +10,53 @@
 
 #![allow(dead_code)]
 
+use std::path::Path;
+
 use rusqlite;
 use rusqlite::types::{ToSql, ToSqlOutput};
+use rustc_serialize::json::Json;
 
+use {to_namespaced_keyword};
 use bootstrap;
+use cipher::{Cipher, decrypt_sql_value_pair};
+use entids;
 use edn::types::Value;
 use errors::*;
 use mentat_tx::entities as entmod;
 use mentat_tx::entities::Entity;
+use options::StoreOptions;
+use tuple;
 use types::*;
 
-pub fn new_connection() -> rusqlite::Connection {
-    return rusqlite::Connection::open_in_memory().unwrap();
+/// Open a SQLite connection to the store at `uri`.  An empty `uri` opens an in-memory store.
+///
+/// Stores on disk are put in WAL mode, so that connections reading inside a transaction keep
+/// seeing the store as it was when they started while another connection commits.
+pub fn new_connection<T>(uri: T) -> Result<rusqlite::Connection> where T: AsRef<Path> {
+    new_connection_with_options(uri, &StoreOptions::default())
+}
+
+/// Like `new_connection`, but tune the connection with `options` instead of the defaults.
+pub fn new_connection_with_options<T>(uri: T, options: &StoreOptions) -> Result<rusqlite::Connection> where T: AsRef<Path> {
+    let in_memory = uri.as_ref().to_string_lossy().len() == 0;
+    let conn = if in_memory {
+        rusqlite::Connection::open_in_memory()?
+    } else {
+        rusqlite::Connection::open(uri)?
+    };
+    options.apply(&conn, in_memory)?;
+    Ok(conn)
+}
+
+/// Open the existing SQLite store at `uri` for reading only.
+///
+/// Writes through the returned connection fail with `SQLITE_READONLY`.  There's no such thing as a
+/// read-only in-memory store, so `uri` must name a file.
+pub fn new_read_only_connection<T>(uri: T) -> Result<rusqlite::Connection> where T: AsRef<Path> {
+    if uri.as_ref().to_string_lossy().len() == 0 {
+        bail!(ErrorKind::NotYetImplemented("read-only in-memory stores".to_string()));
+    }
+    Ok(rusqlite::Connection::open_with_flags(uri, rusqlite::SQLITE_OPEN_READ_ONLY)?)
 }
 
 /// Version history:
@@ -29,7 +64,21 @@ pub fn new_connection() -> rusqlite::Connection {
 /// 1: initial schema.
 /// 2: added :db.schema/version and /attribute in bootstrap; assigned idents 36 and 37, so we bump
 ///    the part range here; tie bootstrapping to the SQLite user_version.
-pub const CURRENT_VERSION: i32 = 2;
+/// 3: moved fulltext_values from FTS4 to FTS5, which ranks matches with bm25() and extracts
+///    snippets with snippet().
+/// 4: added :db.fulltext/tokenizer and /prefix in bootstrap; assigned idents 38 and 39, so we bump
+///    the part range here.
+/// 5: added :db.type/json in bootstrap; assigned ident 40, so we bump the part range here.
+/// 6: added :db/encrypted in bootstrap; assigned ident 41, so we bump the part range here.
+/// 7: added :db/default in bootstrap; assigned ident 42, so we bump the part range here.
+/// 8: added :db/enum in bootstrap; assigned ident 43, so we bump the part range here.
+/// 9: added :db.type/tuple and :db/tupleTypes in bootstrap; assigned idents 44 and 45, so we bump
+///    the part range here.
+/// 10: added :db/tupleAttrs in bootstrap; assigned ident 46, so we bump the part range here.
+///
+/// Bumping the version means adding a `Migration` to `MIGRATIONS` that upgrades stores from the
+/// previous version in place.
+pub const CURRENT_VERSION: i32 = 10;
 
 const TRUE: &'static bool = &true;
 const FALSE: &'static bool = &false;
@@ -43,9 +92,9 @@ fn to_bool_ref(x: bool) -> &'static bool {
 }
 
 lazy_static! {
-    /// SQL statements to be executed, in order, to create the Mentat SQL schema (version 2).
+    /// SQL statements to be executed, in order, to create the Mentat SQL schema (version 3).
     #[cfg_attr(rustfmt, rustfmt_skip)]
-    static ref V2_STATEMENTS: Vec<&'static str> = { vec![
+    static ref V3_STATEMENTS: Vec<&'static str> = { vec![
         r#"CREATE TABLE datoms (e INTEGER NOT NULL, a SMALLINT NOT NULL, v BLOB NOT NULL, tx INTEGER NOT NULL,
                                 value_type_tag SMALLINT NOT NULL,
                                 index_avet TINYINT NOT NULL DEFAULT 0, index_vaet TINYINT NOT NULL DEFAULT 0,
@@ -82,9 +131,9 @@ lazy_static! {
         // tokenize="porter"#,
         // prefix='2,3'
         // By default we use Unicode-aware tokenizing (particularly for case folding), but preserve
-        // diacritics.
+        // diacritics.  FTS5 doesn't take column types or constraints.
         r#"CREATE VIRTUAL TABLE fulltext_values
-             USING FTS4 (text NOT NULL, searchid INT, tokenize=unicode61 "remove_diacritics=0")"#,
+             USING FTS5 (text, searchid UNINDEXED, tokenize="unicode61 remove_diacritics 0")"#,
 
         // This combination of view and triggers allows you to transparently
         // update-or-insert into FTS. Just INSERT INTO fulltext_values_view (text, searchid).
@@ -127,6 +176,88 @@ lazy_static! {
     };
 }
 
+/// (Re-)create the `datoms` table and its indices, as in the current version of the SQL schema.
+pub fn create_datoms_table(conn: &rusqlite::Connection) -> Result<()> {
+    for statement in (&V3_STATEMENTS).iter().filter(|s| s.contains(" datoms (")) {
+        conn.execute(statement, &[])?;
+    }
+    Ok(())
+}
+
+/// Drop and recreate the own fulltext table of the attribute with the given entid, filled with the
+/// attribute's current values.  If the attribute no longer has its own table, just drop it.
+///
+/// FTS5 fixes a table's tokenizer and prefixes when the table is created, so changing them means
+/// rebuilding the table.
+pub fn rebuild_fulltext_table(conn: &rusqlite::Connection, entid: Entid, attribute: Option<&Attribute>) -> Result<()> {
+    let table = fulltext_table_name(entid);
+    conn.execute(&format!("DROP TABLE IF EXISTS {}", table), &[])?;
+
+    let attribute = match attribute {
+        Some(attribute) if attribute.has_fulltext_table() => attribute,
+        _ => return Ok(()),
+    };
+    // Like fulltext_values, preserve diacritics by default.
+    let tokenizer = attribute.fulltext_tokenizer.clone().unwrap_or("unicode61 remove_diacritics 0".to_string());
+    let mut options = format!("tokenize = '{}'", tokenizer);
+    if !attribute.fulltext_prefixes.is_empty() {
+        let prefixes: Vec<String> = attribute.fulltext_prefixes.iter().map(|p| p.to_string()).collect();
+        options.push_str(&format!(", prefix = '{}'", prefixes.join(" ")));
+    }
+    conn.execute(&format!("CREATE VIRTUAL TABLE {} USING FTS5 (text, {})", table, options), &[])?;
+    conn.execute(&format!("INSERT INTO {} (rowid, text)
+                           SELECT DISTINCT f.rowid, f.text FROM datoms AS d, fulltext_values AS f
+                           WHERE d.a = ? AND d.v = f.rowid", table),
+                 &[&entid])?;
+    Ok(())
+}
+
+/// Rebuild the own fulltext table of every attribute in `schema` that has one, from the datoms
+/// table; see `rebuild_fulltext_table`.
+pub fn rebuild_fulltext_tables(conn: &rusqlite::Connection, schema: &Schema) -> Result<()> {
+    for (&entid, attribute) in schema.schema_map.iter() {
+        if attribute.has_fulltext_table() {
+            rebuild_fulltext_table(conn, entid, Some(attribute))?;
+        }
+    }
+    Ok(())
+}
+
+/// Add the fulltext value with the given rowid to the own fulltext table of the attribute with the
+/// given entid, unless it's already there.
+pub fn insert_fulltext_value(conn: &rusqlite::Connection, entid: Entid, rowid: i64, text: &String) -> Result<()> {
+    conn.execute(&format!("INSERT INTO {0} (rowid, text) SELECT ?, ? WHERE NOT EXISTS (SELECT 1 FROM {0} WHERE rowid = ?)",
+                          fulltext_table_name(entid)),
+                 &[&rowid, text, &rowid])?;
+    Ok(())
+}
+
+/// Return the value of a datom of attribute `a`, read from the transaction log as `value` and
+/// `value_type_tag`.  The log holds fulltext values as rowids into fulltext_values, and encrypted
+/// values are decrypted with `cipher`.
+pub fn read_log_value(conn: &rusqlite::Connection, schema: &Schema, a: Entid, value: rusqlite::types::Value, value_type_tag: &i32, cipher: Option<&Cipher>) -> Result<TypedValue> {
+    let fulltext = schema.attribute_for_entid(&a).map_or(false, |attribute| attribute.fulltext);
+    match (fulltext, value) {
+        (true, rusqlite::types::Value::Integer(rowid)) => {
+            let text: String = conn.query_row("SELECT text FROM fulltext_values WHERE rowid = ?", &[&rowid], |row| row.get(0))?;
+            Ok(TypedValue::String(text))
+        },
+        (_, value) => decrypt_sql_value_pair(value, value_type_tag, cipher),
+    }
+}
+
+/// Return the entity holding `typed_value` for the unique attribute `a`, if any.  Fulltext values
+/// are matched by their text.
+pub fn entity_with_value(conn: &rusqlite::Connection, a: Entid, typed_value: &TypedValue) -> Result<Option<Entid>> {
+    let (value, value_type_tag): (ToSqlOutput, i32) = typed_value.to_sql_value_pair();
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT e FROM all_datoms WHERE a = ? AND value_type_tag = ? AND v = ? LIMIT 1")?;
+    let mut rows = stmt.query(&[&a, &value_type_tag, &value])?;
+    match rows.next() {
+        Some(row) => Ok(Some(row?.get_checked(0)?)),
+        None => Ok(None),
+    }
+}
+
 /// Set the SQLite user version.
 ///
 /// Mentat manages its own SQL schema version using the user version.  See the [SQLite
@@ -152,7 +283,7 @@ fn get_user_version(conn: &rusqlite::Connection) -> Result<i32> {
 pub fn create_current_version(conn: &mut rusqlite::Connection) -> Result<i32> {
     let tx = conn.transaction()?;
 
-    for statement in (&V2_STATEMENTS).iter() {
+    for statement in (&V3_STATEMENTS).iter() {
         tx.execute(statement, &[])?;
     }
 
@@ -168,6 +299,10 @@ pub fn create_current_version(conn: &mut rusqlite::Connection) -> Result<i32> {
     let bootstrap_db = DB::new(bootstrap_partition_map, bootstrap::bootstrap_schema());
     bootstrap_db.transact_internal(&tx, &bootstrap::bootstrap_entities()[..])?;
 
+    // Populate the materialized views of the bootstrap schema.
+    write_ident_map(&tx, &bootstrap::bootstrap_ident_map())?;
+    write_schema_triples(&tx, &bootstrap::bootstrap_schema_triples()[..])?;
+
     set_user_version(&tx, CURRENT_VERSION)?;
     let user_version = get_user_version(&tx)?;
 
@@ -259,18 +394,192 @@ pub fn create_current_version(conn: &mut rusqlite::Connection) -> Result<i32> {
 //         (<? (<update-from-version db v bootstrapper))))))
 // */
 
+/// One step in upgrading the SQL store from one `user_version` to the next.
+///
+/// A migration applies to stores at `version - 1`.  Its `apply` function runs inside the SQL
+/// transaction that also bumps the `user_version`, so a failed migration leaves the store as it was.
+pub struct Migration {
+    /// The `user_version` this migration upgrades to.
+    pub version: i32,
+    pub description: &'static str,
+    pub apply: fn(&rusqlite::Connection) -> Result<()>,
+}
+
+/// The migrations that upgrade older stores to `CURRENT_VERSION`, in version order.
+pub static MIGRATIONS: &'static [Migration] = &[
+    Migration {
+        version: 2,
+        description: "install :db.schema/version and :db.schema/attribute",
+        apply: migrate_v1_to_v2,
+    },
+    Migration {
+        version: 3,
+        description: "move fulltext_values to FTS5",
+        apply: migrate_v2_to_v3,
+    },
+    Migration {
+        version: 4,
+        description: "install :db.fulltext/tokenizer and :db.fulltext/prefix",
+        apply: migrate_v3_to_v4,
+    },
+    Migration {
+        version: 5,
+        description: "install :db.type/json",
+        apply: migrate_v4_to_v5,
+    },
+    Migration {
+        version: 6,
+        description: "install :db/encrypted",
+        apply: migrate_v5_to_v6,
+    },
+    Migration {
+        version: 7,
+        description: "install :db/default",
+        apply: migrate_v6_to_v7,
+    },
+    Migration {
+        version: 8,
+        description: "install :db/enum",
+        apply: migrate_v7_to_v8,
+    },
+    Migration {
+        version: 9,
+        description: "install :db.type/tuple and :db/tupleTypes",
+        apply: migrate_v8_to_v9,
+    },
+    Migration {
+        version: 10,
+        description: "install :db/tupleAttrs",
+        apply: migrate_v9_to_v10,
+    },
+];
+
+/// Install the idents added in version 2, and bump the `:db.part/db` range past them.
+fn migrate_v1_to_v2(conn: &rusqlite::Connection) -> Result<()> {
+    install_bootstrap_idents(conn, 2)
+}
+
+/// Install the idents added in version 4, and bump the `:db.part/db` range past them.
+fn migrate_v3_to_v4(conn: &rusqlite::Connection) -> Result<()> {
+    install_bootstrap_idents(conn, 4)
+}
+
+/// Install the idents added in version 5, and bump the `:db.part/db` range past them.
+fn migrate_v4_to_v5(conn: &rusqlite::Connection) -> Result<()> {
+    install_bootstrap_idents(conn, 5)
+}
+
+/// Install the idents added in version 6, and bump the `:db.part/db` range past them.
+fn migrate_v5_to_v6(conn: &rusqlite::Connection) -> Result<()> {
+    install_bootstrap_idents(conn, 6)
+}
+
+/// Install the idents added in version 7, and bump the `:db.part/db` range past them.
+fn migrate_v6_to_v7(conn: &rusqlite::Connection) -> Result<()> {
+    install_bootstrap_idents(conn, 7)
+}
+
+/// Install the idents added in version 8, and bump the `:db.part/db` range past them.
+fn migrate_v7_to_v8(conn: &rusqlite::Connection) -> Result<()> {
+    install_bootstrap_idents(conn, 8)
+}
+
+/// Install the idents added in version 9, and bump the `:db.part/db` range past them.
+fn migrate_v8_to_v9(conn: &rusqlite::Connection) -> Result<()> {
+    install_bootstrap_idents(conn, 9)
+}
+
+/// Install the idents added in version 10, and bump the `:db.part/db` range past them.
+fn migrate_v9_to_v10(conn: &rusqlite::Connection) -> Result<()> {
+    install_bootstrap_idents(conn, 10)
+}
+
+/// Install the bootstrap idents added in `version`, with their schema, into an older store; and
+/// bump the `:db.part/db` range past them.
+fn install_bootstrap_idents(conn: &rusqlite::Connection, version: i32) -> Result<()> {
+    let new_idents = entids::idents_added_in(version);
+    let is_new = |ident: &String| new_idents.contains(&ident.as_str());
+
+    conn.execute("UPDATE parts SET idx = idx + ? WHERE part = ?", &[&(new_idents.len() as i64), &":db.part/db"])?;
+
+    let ident_map: IdentMap = bootstrap::bootstrap_ident_map().into_iter().filter(|&(ref ident, _)| is_new(ident)).collect();
+    write_ident_map(conn, &ident_map)?;
+
+    let triples: Vec<(String, String, TypedValue)> = bootstrap::bootstrap_schema_triples().into_iter().filter(|&(ref ident, _, _)| is_new(ident)).collect();
+    write_schema_triples(conn, &triples[..])?;
+
+    let entities: Vec<Entity> = bootstrap::bootstrap_entities().into_iter().filter(|entity| {
+        match entity {
+            &Entity::Add { e: entmod::EntidOrLookupRefOrTempId::Entid(entmod::Entid::Ident(ref e)), .. } => is_new(&e.to_string()),
+            _ => false,
+        }
+    }).collect();
+    let bootstrap_db = DB::new(bootstrap::bootstrap_partition_map(), bootstrap::bootstrap_schema());
+    bootstrap_db.transact_internal(conn, &entities[..])
+}
+
+/// Rebuild `fulltext_values` as an FTS5 table, preserving rowids: datoms refer to fulltext values by
+/// rowid.  The views and triggers over `fulltext_values` resolve it by name, so they carry over.
+fn migrate_v2_to_v3(conn: &rusqlite::Connection) -> Result<()> {
+    let create = V3_STATEMENTS.iter()
+        .find(|s| s.contains("VIRTUAL TABLE fulltext_values"))
+        .expect("fulltext_values statement");
+    conn.execute("CREATE TEMP TABLE fulltext_values_v2 AS SELECT rowid AS id, text, searchid FROM fulltext_values", &[])?;
+    conn.execute("DROP TABLE fulltext_values", &[])?;
+    conn.execute(create, &[])?;
+    conn.execute("INSERT INTO fulltext_values (rowid, text, searchid) SELECT id, text, searchid FROM temp.fulltext_values_v2", &[])?;
+    conn.execute("DROP TABLE temp.fulltext_values_v2", &[])?;
+    Ok(())
+}
+
+/// Apply the given `migration` to a store at `migration.version - 1`.
+///
+/// Returns the new `user_version`.
+pub fn apply_migration(conn: &mut rusqlite::Connection, migration: &Migration) -> Result<i32> {
+    let tx = conn.transaction()?;
+    let from_version = get_user_version(&tx)?;
+    if from_version != migration.version - 1 {
+        bail!(ErrorKind::BadSQLiteStoreVersion(from_version))
+    }
+
+    (migration.apply)(&tx).chain_err(|| format!("Could not migrate to version {}: {}", migration.version, migration.description))?;
+    set_user_version(&tx, migration.version)?;
+    let user_version = get_user_version(&tx)?;
+    tx.commit()?;
+
+    Ok(user_version)
+}
+
+/// Apply `migrations`, in order, until the store is at `target_version`.
+///
+/// Each migration is committed separately, so an interrupted upgrade resumes where it left off.
+pub fn migrate(conn: &mut rusqlite::Connection, migrations: &[Migration], target_version: i32) -> Result<i32> {
+    let mut user_version = get_user_version(conn)?;
+    while user_version < target_version {
+        match migrations.iter().find(|m| m.version == user_version + 1) {
+            Some(migration) => user_version = apply_migration(conn, migration)?,
+            None => bail!(ErrorKind::BadSQLiteStoreVersion(user_version)),
+        }
+    }
+    Ok(user_version)
+}
+
 pub fn update_from_version(conn: &mut rusqlite::Connection, current_version: i32) -> Result<i32> {
     if current_version < 0 || CURRENT_VERSION <= current_version {
         bail!(ErrorKind::BadSQLiteStoreVersion(current_version))
     }
 
-    let tx = conn.transaction()?;
-    // TODO: actually implement upgrade.
-    set_user_version(&tx, CURRENT_VERSION)?;
-    let user_version = get_user_version(&tx)?;
-    // TODO: use the drop semantics to do this automagically?
-    tx.commit()?;
+    migrate(conn, MIGRATIONS, CURRENT_VERSION)
+}
 
+/// Fail unless the store is already at `CURRENT_VERSION`, without creating or upgrading it.
+///
+/// Use this in place of `ensure_current_version` for connections that can't write.
+pub fn check_current_version(conn: &rusqlite::Connection) -> Result<i32> {
+    let user_version = get_user_version(conn)?;
+    if user_version != CURRENT_VERSION {
+        bail!(ErrorKind::BadSQLiteStoreVersion(user_version));
+    }
     Ok(user_version)
 }
 
@@ -294,7 +603,20 @@ impl TypedValue {
             (5, rusqlite::types::Value::Integer(x)) => Ok(TypedValue::Long(x)),
             (5, rusqlite::types::Value::Real(x)) => Ok(TypedValue::Double(x.into())),
             (10, rusqlite::types::Value::Text(x)) => Ok(TypedValue::String(x)),
-            (13, rusqlite::types::Value::Text(x)) => Ok(TypedValue::Keyword(x)),
+            (12, rusqlite::types::Value::Blob(x)) => Ok(TypedValue::Bytes(x)),
+            (14, rusqlite::types::Value::Text(x)) => Ok(TypedValue::Json(x)),
+            (tuple::TUPLE_VALUE_TYPE_TAG, rusqlite::types::Value::Text(x)) => {
+                match tuple::decode(&x) {
+                    Some(components) => Ok(TypedValue::Tuple(components)),
+                    None => bail!(ErrorKind::BadSQLValuePair(rusqlite::types::Value::Text(x), tuple::TUPLE_VALUE_TYPE_TAG)),
+                }
+            },
+            (13, rusqlite::types::Value::Text(x)) => {
+                match to_namespaced_keyword(&x) {
+                    Some(keyword) => Ok(TypedValue::Keyword(keyword)),
+                    None => bail!(ErrorKind::BadSQLValuePair(rusqlite::types::Value::Text(x), 13)),
+                }
+            },
             (_, value) => bail!(ErrorKind::BadSQLValuePair(value, *value_type_tag)),
         }
     }
@@ -310,9 +632,12 @@ impl TypedValue {
         match value {
             &Value::Boolean(x) => Some(TypedValue::Boolean(x)),
             &Value::Integer(x) => Some(TypedValue::Long(x)),
+            // Instants are stored as longs, in milliseconds since the Unix epoch.
+            &Value::Instant(x) => Some(TypedValue::Long(x)),
             &Value::Float(ref x) => Some(TypedValue::Double(x.clone())),
             &Value::Text(ref x) => Some(TypedValue::String(x.clone())),
-            &Value::NamespacedKeyword(ref x) => Some(TypedValue::Keyword(x.to_string())),
+            &Value::NamespacedKeyword(ref x) => Some(TypedValue::Keyword(x.clone())),
+            &Value::Bytes(ref x) => Some(TypedValue::Bytes(x.clone())),
             _ => None
         }
     }
@@ -326,10 +651,28 @@ impl TypedValue {
             &TypedValue::Long(x) => (rusqlite::types::Value::Integer(x).into(), 5),
             &TypedValue::Double(x) => (rusqlite::types::Value::Real(x.into_inner()).into(), 5),
             &TypedValue::String(ref x) => (rusqlite::types::ValueRef::Text(x.as_str()).into(), 10),
-            &TypedValue::Keyword(ref x) => (rusqlite::types::ValueRef::Text(x.as_str()).into(), 13),
+            // Keywords are stored in their EDN text form, like ":db/ident".
+            &TypedValue::Keyword(ref x) => (rusqlite::types::Value::Text(x.to_string()).into(), 13),
+            &TypedValue::Bytes(ref x) => (rusqlite::types::ValueRef::Blob(&x[..]).into(), 12),
+            // JSON is stored as text, so that SQLite's JSON functions can read it.
+            &TypedValue::Json(ref x) => (rusqlite::types::ValueRef::Text(x.as_str()).into(), 14),
+            // No stored tuple matches the encoding of one that can't be stored; writes use
+            // `to_stored_sql_value_pair`, which rejects them.
+            &TypedValue::Tuple(ref x) => (rusqlite::types::Value::Text(tuple::encode(x)).into(), tuple::TUPLE_VALUE_TYPE_TAG),
         }
     }
 
+    /// Like `to_sql_value_pair`, but fail with `BadEDNValuePair` for a value that can't be stored,
+    /// which is a tuple that isn't `tuple::is_storable`, rather than write it.
+    pub fn to_stored_sql_value_pair<'a>(&'a self) -> Result<(ToSqlOutput<'a>, i32)> {
+        if let &TypedValue::Tuple(ref x) = self {
+            if !tuple::is_storable(x) {
+                bail!(ErrorKind::BadEDNValuePair(self.to_edn_value_pair().0, ValueType::Tuple));
+            }
+        }
+        Ok(self.to_sql_value_pair())
+    }
+
     /// Return the corresponding EDN `value` and `value_type` pair.
     pub fn to_edn_value_pair(&self) -> (Value, ValueType) {
         match self {
@@ -338,11 +681,33 @@ impl TypedValue {
             &TypedValue::Long(x) => (Value::Integer(x), ValueType::Long),
             &TypedValue::Double(x) => (Value::Float(x), ValueType::Double),
             &TypedValue::String(ref x) => (Value::Text(x.clone()), ValueType::String),
-            &TypedValue::Keyword(ref x) => (Value::Text(x.clone()), ValueType::Keyword),
+            &TypedValue::Keyword(ref x) => (Value::NamespacedKeyword(x.clone()), ValueType::Keyword),
+            &TypedValue::Bytes(ref x) => (Value::Bytes(x.clone()), ValueType::Bytes),
+            &TypedValue::Json(ref x) => (Value::Text(x.clone()), ValueType::Json),
+            &TypedValue::Tuple(ref x) => (Value::Vector(x.iter().map(|component| component.to_edn_value_pair().0).collect()), ValueType::Tuple),
         }
     }
 }
 
+/// Write the given idents to the ident map materialized view.
+pub fn write_ident_map(conn: &rusqlite::Connection, ident_map: &IdentMap) -> Result<()> {
+    // TODO: one insert, chunk into 999/2 sections, for safety.
+    for (ident, entid) in ident_map.iter() {
+        conn.execute("INSERT INTO idents VALUES (?, ?)", &[ident, entid])?;
+    }
+    Ok(())
+}
+
+/// Write the given (ident, attr, value) triples to the schema materialized view.
+pub fn write_schema_triples(conn: &rusqlite::Connection, triples: &[(String, String, TypedValue)]) -> Result<()> {
+    // TODO: one insert, chunk into 999/4 sections, for safety.
+    for &(ref ident, ref attr, ref typed_value) in triples.iter() {
+        let (value, value_type_tag): (ToSqlOutput, i32) = typed_value.to_sql_value_pair();
+        conn.execute("INSERT INTO schema VALUES (?, ?, ?, ?)", &[ident, attr, &value, &value_type_tag])?;
+    }
+    Ok(())
+}
+
 /// Read the ident map materialized view from the given SQL store.
 pub fn read_ident_map(conn: &rusqlite::Connection) -> Result<IdentMap> {
     let mut stmt: rusqlite::Statement = conn.prepare("SELECT ident, entid FROM idents")?;
@@ -354,12 +719,23 @@ pub fn read_ident_map(conn: &rusqlite::Connection) -> Result<IdentMap> {
 
 
 /// Read the partition map materialized view from the given SQL store.
+///
+/// Fails with `BadPartition` if any partition's next entid lies outside the partition's range.
 pub fn read_partition_map(conn: &rusqlite::Connection) -> Result<PartitionMap> {
     let mut stmt: rusqlite::Statement = conn.prepare("SELECT part, start, idx FROM parts")?;
-    let m = stmt.query_and_then(&[], |row| -> Result<(String, Partition)> {
-        Ok((row.get_checked(0)?, Partition::new(row.get_checked(1)?, row.get_checked(2)?)))
+    let m: Result<PartitionMap> = stmt.query_and_then(&[], |row| -> Result<(String, Partition)> {
+        // Don't use `Partition::new`, which panics on bad input.
+        Ok((row.get_checked(0)?, Partition { start: row.get_checked(1)?, index: row.get_checked(2)? }))
     })?.collect();
-    m
+    let m = m?;
+
+    for (part, partition) in m.iter() {
+        let end = partition_end(&m, part).expect("partition to exist");
+        if partition.index < partition.start || partition.index > end {
+            bail!(ErrorKind::BadPartition(format!("next entid {} of partition '{}' is outside [{}, {}]", partition.index, part, partition.start, end)));
+        }
+    }
+    Ok(m)
 }
 
 /// Read the schema materialized view from the given SQL store.
@@ -390,12 +766,16 @@ pub fn read_db(conn: &rusqlite::Connection) -> Result<DB> {
     Ok(DB::new(partition_map, schema))
 }
 
-impl DB {
+impl Schema {
     /// Do schema-aware typechecking and coercion.
     ///
     /// Either assert that the given value is in the attribute's value set, or (in limited cases)
     /// coerce the given value into the attribute's value set.
     pub fn to_typed_value(&self, value: &Value, attribute: &Attribute) -> Result<TypedValue> {
+        if attribute.value_type == ValueType::Tuple {
+            return self.to_tuple_value(value, attribute);
+        }
+
         // TODO: encapsulate entid-ident-attribute for better error messages.
         match TypedValue::from_edn_value(value) {
             // We don't recognize this EDN at all.  Get out!
@@ -404,26 +784,66 @@ impl DB {
                 // Most types don't coerce at all.
                 (&ValueType::Boolean, tv @ TypedValue::Boolean(_)) => Ok(tv),
                 (&ValueType::Long, tv @ TypedValue::Long(_)) => Ok(tv),
+                (&ValueType::Instant, tv @ TypedValue::Long(_)) => Ok(tv),
                 (&ValueType::Double, tv @ TypedValue::Double(_)) => Ok(tv),
                 (&ValueType::String, tv @ TypedValue::String(_)) => Ok(tv),
                 (&ValueType::Keyword, tv @ TypedValue::Keyword(_)) => Ok(tv),
+                (&ValueType::Bytes, tv @ TypedValue::Bytes(_)) => Ok(tv),
+                // JSON is written as a string, which must parse.
+                (&ValueType::Json, TypedValue::String(x)) => {
+                    if Json::from_str(&x).is_err() {
+                        bail!(ErrorKind::BadEDNValuePair(value.clone(), ValueType::Json));
+                    }
+                    Ok(TypedValue::Json(x))
+                },
                 // Ref coerces a little: we interpret some things depending on the schema as a Ref.
                 (&ValueType::Ref, TypedValue::Long(x)) => Ok(TypedValue::Ref(x)),
-                (&ValueType::Ref, TypedValue::Keyword(ref x)) => self.schema.require_entid(&x.to_string()).map(|&entid| TypedValue::Ref(entid)),
+                (&ValueType::Ref, TypedValue::Keyword(ref x)) => self.require_entid(&x.to_string()).map(|&entid| TypedValue::Ref(entid)),
                 // Otherwise, we have a type mismatch.
                 (value_type, _) => bail!(ErrorKind::BadEDNValuePair(value.clone(), value_type.clone())),
             }
         }
     }
 
-    // TODO: move this to the transactor layer.
+    /// Type-check a value of the tuple attribute `attribute`.  Tuples are written as vectors.  Each
+    /// component is typed and coerced by the attribute's component types, if it has them, and
+    /// otherwise keeps the type it's written with.
+    fn to_tuple_value(&self, value: &Value, attribute: &Attribute) -> Result<TypedValue> {
+        let bad_tuple = || ErrorKind::BadEDNValuePair(value.clone(), ValueType::Tuple);
+        let components: Vec<TypedValue> = match value {
+            &Value::Vector(ref xs) if attribute.tuple_types.is_empty() => {
+                let components: Option<Vec<TypedValue>> = xs.iter().map(TypedValue::from_edn_value).collect();
+                components.ok_or_else(bad_tuple)?
+            },
+            &Value::Vector(ref xs) if xs.len() == attribute.tuple_types.len() => {
+                let components: Result<Vec<TypedValue>> = xs.iter().zip(attribute.tuple_types.iter())
+                    .map(|(x, value_type)| self.to_typed_value(x, &Attribute { value_type: value_type.clone(), ..Attribute::default() }))
+                    .collect();
+                components.map_err(|_| bad_tuple())?
+            },
+            _ => bail!(bad_tuple()),
+        };
+        if !tuple::is_storable(&components) {
+            bail!(bad_tuple());
+        }
+        Ok(TypedValue::Tuple(components))
+    }
+}
+
+impl DB {
+    /// Do schema-aware typechecking and coercion.  See `Schema::to_typed_value`.
+    pub fn to_typed_value(&self, value: &Value, attribute: &Attribute) -> Result<TypedValue> {
+        self.schema.to_typed_value(value, attribute)
+    }
+
+    /// Transact the bootstrap `entities`.  See `tx::transact` for the general transactor.
     pub fn transact_internal(&self, conn: &rusqlite::Connection, entities: &[Entity]) -> Result<()>{
-        // TODO: manage :db/tx, write :db/txInstant.
-        let tx = 1;
+        // TODO: write :db/txInstant.
+        let tx = bootstrap::TX0;
         let r: Vec<Result<()>> = entities.into_iter().map(|entity: &Entity| -> Result<()> {
             match *entity {
                 Entity::Add {
-                    e: entmod::EntidOrLookupRef::Entid(entmod::Entid::Ident(ref e_)),
+                    e: entmod::EntidOrLookupRefOrTempId::Entid(entmod::Entid::Ident(ref e_)),
                     a: entmod::Entid::Ident(ref a_),
                     v: entmod::ValueOrLookupRef::Value(ref v_),
                     tx: _ } => {
@@ -446,6 +866,11 @@ impl DB {
                     // Fun times, type signatures.
                     let values: [&ToSql; 9] = [&e, &a, &value, &tx, &value_type_tag, &attribute.index, to_bool_ref(attribute.value_type == ValueType::Ref), &attribute.fulltext, &attribute.unique_value];
                     stmt.insert(&values[..])?;
+
+                    // Record the assertion in the transaction log, so that bootstrap datoms are
+                    // indistinguishable from transacted ones.
+                    conn.execute("INSERT INTO transactions (e, a, v, tx, added, value_type_tag) VALUES (?, ?, ?, ?, 1, ?)",
+                                 &[&e, &a, &value, &tx, &value_type_tag])?;
                     Ok(())
                 },
                 // TODO: find a better error type for this.
@@ -461,8 +886,13 @@ impl DB {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
     use bootstrap;
     use debug;
+    use edn::symbols::NamespacedKeyword;
     use rusqlite;
     use types::*;
 
@@ -501,16 +931,141 @@ mod tests {
         // let _ = fs::remove_file("/Users/nalexander/Mozilla/mentat/test.db");
         // let mut conn = rusqlite::Connection::open("file:///Users/nalexander/Mozilla/mentat/test.db").unwrap();
 
-        let mut conn = new_connection();
+        let mut conn = new_connection("").expect("Couldn't open in-memory db");
 
         assert_eq!(ensure_current_version(&mut conn).unwrap(), CURRENT_VERSION);
 
         let bootstrap_db = DB::new(bootstrap::bootstrap_partition_map(), bootstrap::bootstrap_schema());
-        // TODO: write materialized view of bootstrapped schema to SQL store.
-        // let db = read_db(&conn).unwrap();
-        // assert_eq!(db, bootstrap_db);
+        let db = read_db(&conn).unwrap();
+        assert_eq!(db, bootstrap_db);
 
         let datoms = debug::datoms_after(&conn, &bootstrap_db, &0).unwrap();
-        assert_eq!(datoms.len(), 88);
+        assert_eq!(datoms.len(), 118);
+
+        // Every bootstrap datom is also in the transaction log.
+        let logged: i64 = conn.query_row("SELECT COUNT(*) FROM transactions WHERE tx = ? AND added = 1", &[&bootstrap::TX0], |row| row.get(0)).unwrap();
+        assert_eq!(logged, 118);
+    }
+
+    /// Copy the named fixture to a temporary file, so tests can modify it.
+    fn copy_fixture(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("mentat-test-{}-{}", process::id(), name));
+        fs::copy(Path::new("../fixtures").join(name), &path).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_migrate_v1_fixture() {
+        let path = copy_fixture("v1empty.db");
+        {
+            let mut conn = new_connection(&path).unwrap();
+            assert_eq!(get_user_version(&conn).unwrap(), 1);
+            assert_eq!(ensure_current_version(&mut conn).unwrap(), CURRENT_VERSION);
+
+            // The upgraded store has the same metadata as a freshly created one.
+            let mut expected = new_connection("").expect("Couldn't open in-memory db");
+            ensure_current_version(&mut expected).unwrap();
+            assert_eq!(read_db(&conn).unwrap(), read_db(&expected).unwrap());
+            let attribute = *read_db(&conn).unwrap().schema.require_entid(&":db.schema/attribute".to_string()).unwrap();
+            let count = |c: &rusqlite::Connection| -> i64 {
+                c.query_row("SELECT COUNT(*) FROM datoms WHERE e = ?", &[&attribute], |row| row.get(0)).unwrap()
+            };
+            assert_eq!(count(&conn), count(&expected));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_v2_fulltext() {
+        let path = copy_fixture("v2empty.db");
+        {
+            let mut conn = new_connection(&path).unwrap();
+            assert_eq!(get_user_version(&conn).unwrap(), 2);
+            conn.execute("INSERT INTO fulltext_values (rowid, text, searchid) VALUES (7, 'the quick brown fox', NULL)", &[]).unwrap();
+            assert_eq!(ensure_current_version(&mut conn).unwrap(), CURRENT_VERSION);
+
+            // Rowids survive the move to FTS5, and ranked search works.
+            let rowid: i64 = conn.query_row("SELECT rowid FROM fulltext_values WHERE fulltext_values MATCH 'brown' ORDER BY bm25(fulltext_values)", &[], |row| row.get(0)).unwrap();
+            assert_eq!(rowid, 7);
+            let text: String = conn.query_row("SELECT text FROM fulltext_values_view", &[], |row| row.get(0)).unwrap();
+            assert_eq!(text, "the quick brown fox");
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn add_column(conn: &rusqlite::Connection) -> Result<()> {
+        conn.execute("ALTER TABLE parts ADD COLUMN note TEXT", &[])?;
+        Ok(())
+    }
+
+    fn fail(conn: &rusqlite::Connection) -> Result<()> {
+        conn.execute("DELETE FROM parts", &[])?;
+        bail!(ErrorKind::NotYetImplemented("failing migration".to_string()))
+    }
+
+    #[test]
+    fn test_migration_in_isolation() {
+        let mut conn = new_connection("").expect("Couldn't open in-memory db");
+        ensure_current_version(&mut conn).unwrap();
+
+        let migrations = [
+            Migration { version: CURRENT_VERSION + 1, description: "add a column", apply: add_column },
+            Migration { version: CURRENT_VERSION + 2, description: "fail", apply: fail },
+        ];
+
+        // Each migration commits separately; a failed migration rolls back and leaves the version.
+        assert!(migrate(&mut conn, &migrations[..], CURRENT_VERSION + 2).is_err());
+        assert_eq!(get_user_version(&conn).unwrap(), CURRENT_VERSION + 1);
+        assert_eq!(read_partition_map(&conn).unwrap(), bootstrap::bootstrap_partition_map());
+        conn.execute("UPDATE parts SET note = 'x'", &[]).unwrap();
+
+        // A migration only applies to the version just before it.
+        assert!(apply_migration(&mut conn, &migrations[0]).is_err());
+
+        // There's no path to a version without migrations.
+        match migrate(&mut conn, &migrations[..1], CURRENT_VERSION + 3) {
+            Err(Error(ErrorKind::BadSQLiteStoreVersion(v), _)) => assert_eq!(v, CURRENT_VERSION + 1),
+            x => panic!("expected BadSQLiteStoreVersion, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_bytes_sql_value_pair() {
+        let bytes = TypedValue::Bytes(vec![0, 1, 255]);
+        let (value, value_type_tag) = bytes.to_sql_value_pair();
+        assert_eq!(value_type_tag, 12);
+        assert_eq!(value, rusqlite::types::ToSqlOutput::Borrowed(rusqlite::types::ValueRef::Blob(&[0, 1, 255])));
+        assert_eq!(TypedValue::from_sql_value_pair(rusqlite::types::Value::Blob(vec![0, 1, 255]), &12).unwrap(), bytes);
+        assert_eq!(bytes.to_edn_value_pair(), (Value::Bytes(vec![0, 1, 255]), ValueType::Bytes));
+
+        // Bytes are stored as blobs, never text.
+        assert!(TypedValue::from_sql_value_pair(rusqlite::types::Value::Text("AAH/".to_string()), &12).is_err());
+    }
+
+    #[test]
+    fn test_keyword_sql_value_pair() {
+        let keyword = TypedValue::Keyword(NamespacedKeyword::new("db.type", "keyword"));
+        let (value, value_type_tag) = keyword.to_sql_value_pair();
+        assert_eq!(value_type_tag, 13);
+        assert_eq!(value, rusqlite::types::ToSqlOutput::Owned(rusqlite::types::Value::Text(":db.type/keyword".to_string())));
+        assert_eq!(TypedValue::from_sql_value_pair(rusqlite::types::Value::Text(":db.type/keyword".to_string()), &13).unwrap(), keyword);
+        assert_eq!(keyword.to_edn_value_pair(), (Value::NamespacedKeyword(NamespacedKeyword::new("db.type", "keyword")), ValueType::Keyword));
+
+        // Keywords must be namespaced.
+        assert!(TypedValue::from_sql_value_pair(rusqlite::types::Value::Text("keyword".to_string()), &13).is_err());
+    }
+
+    #[test]
+    fn test_stored_tuple_sql_value_pair() {
+        let tuple = TypedValue::Tuple(vec![TypedValue::Long(1), TypedValue::String("a".to_string())]);
+        assert_eq!(tuple.to_stored_sql_value_pair().unwrap(), tuple.to_sql_value_pair());
+
+        // A tuple that can't be stored isn't written as anything.
+        for component in vec![TypedValue::Bytes(vec![1]), TypedValue::Double(::std::f64::NAN.into())] {
+            match TypedValue::Tuple(vec![component]).to_stored_sql_value_pair() {
+                Err(Error(ErrorKind::BadEDNValuePair(_, ValueType::Tuple), _)) => (),
+                x => panic!("expected BadEDNValuePair, got {:?}", x),
+            }
+        }
     }
 }
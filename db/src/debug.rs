@@ -15,6 +15,7 @@
 use rusqlite;
 
 use {to_namespaced_keyword};
+use bootstrap;
 use edn::types::{Value};
 use mentat_tx::entities::{Entid};
 use types::{DB, TypedValue};
@@ -30,15 +31,14 @@ pub struct Datom {
     tx: Option<i64>,
 }
 
-/// Return the complete set of datoms in the store, ordered by (e, a, v).
+/// Return the set of non-bootstrap datoms in the store, ordered by (tx, e, a, v).
 pub fn datoms(conn: &rusqlite::Connection, db: &DB) -> Result<Vec<Datom>> {
-    // TODO: fewer magic numbers!
-    datoms_after(conn, db, &0x10000000)
+    datoms_after(conn, db, &bootstrap::TX0)
 }
 
 /// Return the set of datoms in the store with transaction ID strictly
 /// greater than the given `tx`, ordered by (tx, e, a, v).
-pub fn datoms_after(conn: &rusqlite::Connection, db: &DB, tx: &i32) -> Result<Vec<Datom>> {
+pub fn datoms_after(conn: &rusqlite::Connection, db: &DB, tx: &i64) -> Result<Vec<Datom>> {
     let mut stmt: rusqlite::Statement = conn.prepare("SELECT e, a, v, value_type_tag FROM datoms WHERE tx > ? ORDER BY tx, e, a, v")?;
 
     // Convert numeric entid to entity Entid.
@@ -0,0 +1,114 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Displaying entids for people rather than programs.
+//!
+//! An entid with an ident displays as that ident, like `:person/name`.  Otherwise it displays with
+//! the partition it's in, like `#db/id[:db.part/user 65537]`, or, outside every partition, as
+//! itself.  Transaction reports display their entids the same way:
+//!
+//! ```text
+//! tx #db/id[:db.part/tx 268435457] at 1483228800000: "a" #db/id[:db.part/user 65537]
+//! ```
+
+use std::fmt;
+
+use tx::TxReport;
+use types::{DB, Entid, partition_for_entid};
+
+/// An entid, displayed as by `DB::display_entid`.
+pub struct DisplayEntid<'a> {
+    db: &'a DB,
+    entid: Entid,
+}
+
+impl<'a> fmt::Display for DisplayEntid<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ident) = self.db.schema.get_ident(&self.entid) {
+            return write!(f, "{}", ident);
+        }
+        match partition_for_entid(&self.db.partition_map, self.entid) {
+            Some(partition) => write!(f, "#db/id[{} {}]", partition, self.entid),
+            None => write!(f, "{}", self.entid),
+        }
+    }
+}
+
+/// A transaction report, displayed as by `TxReport::display`.
+pub struct DisplayTxReport<'a> {
+    db: &'a DB,
+    report: &'a TxReport,
+}
+
+impl<'a> fmt::Display for DisplayTxReport<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tx {} at {}", self.db.display_entid(self.report.tx_id), self.report.tx_instant)?;
+        if self.report.noop {
+            write!(f, " (no-op)")?;
+        }
+        for (i, (tempid, &e)) in self.report.tempids.iter().enumerate() {
+            write!(f, "{} {:?} {}", if i == 0 { ":" } else { "," }, tempid, self.db.display_entid(e))?;
+        }
+        Ok(())
+    }
+}
+
+impl DB {
+    /// Display `entid` by its ident, if it has one, and otherwise with its partition.
+    pub fn display_entid(&self, entid: Entid) -> DisplayEntid {
+        DisplayEntid {
+            db: self,
+            entid: entid,
+        }
+    }
+}
+
+impl TxReport {
+    /// Display this report, with its entids displayed as by `DB::display_entid` against `db`.
+    pub fn display<'a>(&'a self, db: &'a DB) -> DisplayTxReport<'a> {
+        DisplayTxReport {
+            db: db,
+            report: self,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use bootstrap;
+
+    #[test]
+    fn test_display_entid() {
+        let db = DB::new(bootstrap::bootstrap_partition_map(), bootstrap::bootstrap_schema());
+        let name = *db.schema.get_entid(&":db/ident".to_string()).unwrap();
+
+        assert_eq!(db.display_entid(name).to_string(), ":db/ident");
+        assert_eq!(db.display_entid(65537).to_string(), "#db/id[:db.part/user 65537]");
+        assert_eq!(db.display_entid(0x10000001).to_string(), "#db/id[:db.part/tx 268435457]");
+        assert_eq!(db.display_entid(-1).to_string(), "-1");
+
+        let mut tempids = BTreeMap::new();
+        tempids.insert("a".to_string(), 65537);
+        tempids.insert("b".to_string(), name);
+        let report = TxReport {
+            tx_id: 0x10000001,
+            tx_instant: 1483228800000,
+            tempids: tempids,
+            noop: false,
+            created_idents: BTreeMap::new(),
+        };
+        assert_eq!(report.display(&db).to_string(),
+                   "tx #db/id[:db.part/tx 268435457] at 1483228800000: \"a\" #db/id[:db.part/user 65537], \"b\" :db/ident");
+    }
+}
@@ -13,46 +13,157 @@
 /// Literal `Entid` values in the the "db" namespace.
 ///
 /// Used through-out the transactor to match core DB constructs.
+///
+/// Each core ident is declared once, in the `core_idents!` table below: its constant, its entid,
+/// its ident, the SQL schema version that added it, and, if it's an attribute, its schema.  The
+/// bootstrap transaction, the bootstrap ident map and schema, and the migrations that install new
+/// idents into older stores are all derived from the table.
 
 use types::{Entid};
 
-// Added in SQL schema v1.
-pub const DB_IDENT: Entid = 1;
-pub const DB_PART_DB: Entid = 2;
-pub const DB_TX_INSTANT: Entid = 3;
-pub const DB_INSTALL_PARTITION: Entid = 4;
-pub const DB_INSTALL_VALUETYPE: Entid = 5;
-pub const DB_INSTALL_ATTRIBUTE: Entid = 6;
-pub const DB_VALUE_TYPE: Entid = 7;
-pub const DB_CARDINALITY: Entid = 8;
-pub const DB_UNIQUE: Entid = 9;
-pub const DB_IS_COMPONENT: Entid = 10;
-pub const DB_INDEX: Entid = 11;
-pub const DB_FULLTEXT: Entid = 12;
-pub const DB_NO_HISTORY: Entid = 13;
-pub const DB_ADD: Entid = 14;
-pub const DB_RETRACT: Entid = 15;
-pub const DB_PART_USER: Entid = 16;
-pub const DB_PART_TX: Entid = 17;
-pub const DB_EXCISE: Entid = 18;
-pub const DB_EXCISE_ATTRS: Entid = 19;
-pub const DB_EXCISE_BEFORE_T: Entid = 20;
-pub const DB_EXCISE_BEFORE: Entid = 21;
-pub const DB_ALTER_ATTRIBUTE: Entid = 22;
-pub const DB_TYPE_REF: Entid = 23;
-pub const DB_TYPE_KEYWORD: Entid = 24;
-pub const DB_TYPE_LONG: Entid = 25;
-pub const DB_TYPE_DOUBLE: Entid = 26;
-pub const DB_TYPE_STRING: Entid = 27;
-pub const DB_TYPE_BOOLEAN: Entid = 28;
-pub const DB_TYPE_INSTANT: Entid = 29;
-pub const DB_TYPE_BYTES: Entid = 30;
-pub const DB_CARDINALITY_ONE: Entid = 31;
-pub const DB_CARDINALITY_MANY: Entid = 32;
-pub const DB_UNIQUE_VALUE: Entid = 33;
-pub const DB_UNIQUE_IDENTITY: Entid = 34;
-pub const DB_DOC: Entid = 35;
-
-// Added in SQL schema v2.
-pub const DB_SCHEMA_VERSION: Entid = 36;
-pub const DB_SCHEMA_ATTRIBUTE: Entid = 37;
+/// A core ident, which every store has.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub struct CoreIdent {
+    pub entid: Entid,
+    pub ident: &'static str,
+
+    /// The SQL schema version that added the ident.
+    pub version: i32,
+
+    /// The EDN map of the attribute's schema, like `{:db/valueType :db.type/long
+    /// :db/cardinality :db.cardinality/one}`, or `None` if the ident doesn't name an attribute.
+    pub schema: Option<&'static str>,
+}
+
+/// Declare a constant for each core ident, and `CORE_IDENTS`, describing them all.
+macro_rules! core_idents {
+    ($($(#[$attr:meta])* $name:ident = ($entid:expr, $ident:expr, $version:expr, $schema:expr);)*) => {
+        $($(#[$attr])* pub const $name: Entid = $entid;)*
+
+        /// Every core ident, in entid order.
+        pub const CORE_IDENTS: &'static [CoreIdent] = &[
+            $(CoreIdent { entid: $name, ident: $ident, version: $version, schema: $schema },)*
+        ];
+    }
+}
+
+core_idents! {
+    DB_IDENT = (1, ":db/ident", 1,
+                Some("{:db/valueType :db.type/keyword :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}"));
+    DB_PART_DB = (2, ":db.part/db", 1, None);
+    DB_TX_INSTANT = (3, ":db/txInstant", 1,
+                     Some("{:db/valueType :db.type/long :db/cardinality :db.cardinality/one :db/index true}"));
+    DB_INSTALL_PARTITION = (4, ":db.install/partition", 1,
+                            Some("{:db/valueType :db.type/ref :db/cardinality :db.cardinality/many}"));
+    DB_INSTALL_VALUETYPE = (5, ":db.install/valueType", 1,
+                            Some("{:db/valueType :db.type/ref :db/cardinality :db.cardinality/many}"));
+    DB_INSTALL_ATTRIBUTE = (6, ":db.install/attribute", 1,
+                            Some("{:db/valueType :db.type/ref :db/cardinality :db.cardinality/many}"));
+    DB_VALUE_TYPE = (7, ":db/valueType", 1,
+                     Some("{:db/valueType :db.type/ref :db/cardinality :db.cardinality/one}"));
+    DB_CARDINALITY = (8, ":db/cardinality", 1,
+                      Some("{:db/valueType :db.type/ref :db/cardinality :db.cardinality/one}"));
+    DB_UNIQUE = (9, ":db/unique", 1,
+                 Some("{:db/valueType :db.type/ref :db/cardinality :db.cardinality/one}"));
+    DB_IS_COMPONENT = (10, ":db/isComponent", 1,
+                       Some("{:db/valueType :db.type/boolean :db/cardinality :db.cardinality/one}"));
+    DB_INDEX = (11, ":db/index", 1,
+                Some("{:db/valueType :db.type/boolean :db/cardinality :db.cardinality/one}"));
+    DB_FULLTEXT = (12, ":db/fulltext", 1,
+                   Some("{:db/valueType :db.type/boolean :db/cardinality :db.cardinality/one}"));
+    DB_NO_HISTORY = (13, ":db/noHistory", 1,
+                     Some("{:db/valueType :db.type/boolean :db/cardinality :db.cardinality/one}"));
+    DB_ADD = (14, ":db/add", 1, None);
+    DB_RETRACT = (15, ":db/retract", 1, None);
+    DB_PART_USER = (16, ":db.part/user", 1, None);
+    DB_PART_TX = (17, ":db.part/tx", 1, None);
+    DB_EXCISE = (18, ":db/excise", 1, None);
+    DB_EXCISE_ATTRS = (19, ":db.excise/attrs", 1, None);
+    DB_EXCISE_BEFORE_T = (20, ":db.excise/beforeT", 1, None);
+    DB_EXCISE_BEFORE = (21, ":db.excise/before", 1, None);
+    /// Its schema was added in version 2, to new stores only.
+    DB_ALTER_ATTRIBUTE = (22, ":db.alter/attribute", 1,
+                          Some("{:db/valueType :db.type/ref :db/cardinality :db.cardinality/many}"));
+    DB_TYPE_REF = (23, ":db.type/ref", 1, None);
+    DB_TYPE_KEYWORD = (24, ":db.type/keyword", 1, None);
+    DB_TYPE_LONG = (25, ":db.type/long", 1, None);
+    DB_TYPE_DOUBLE = (26, ":db.type/double", 1, None);
+    DB_TYPE_STRING = (27, ":db.type/string", 1, None);
+    DB_TYPE_BOOLEAN = (28, ":db.type/boolean", 1, None);
+    DB_TYPE_INSTANT = (29, ":db.type/instant", 1, None);
+    DB_TYPE_BYTES = (30, ":db.type/bytes", 1, None);
+    DB_CARDINALITY_ONE = (31, ":db.cardinality/one", 1, None);
+    DB_CARDINALITY_MANY = (32, ":db.cardinality/many", 1, None);
+    DB_UNIQUE_VALUE = (33, ":db.unique/value", 1, None);
+    DB_UNIQUE_IDENTITY = (34, ":db.unique/identity", 1, None);
+    DB_DOC = (35, ":db/doc", 1,
+              Some("{:db/valueType :db.type/string :db/cardinality :db.cardinality/one}"));
+
+    DB_SCHEMA_VERSION = (36, ":db.schema/version", 2,
+                         Some("{:db/valueType :db.type/long :db/cardinality :db.cardinality/one}"));
+    /// Unique-value because an attribute can only belong to a single schema fragment.
+    DB_SCHEMA_ATTRIBUTE = (37, ":db.schema/attribute", 2,
+                           Some("{:db/valueType :db.type/ref :db/unique :db.unique/value :db/cardinality :db.cardinality/many}"));
+
+    /// The FTS5 tokenizer for a fulltext attribute's own table, like "porter unicode61".
+    DB_FULLTEXT_TOKENIZER = (38, ":db.fulltext/tokenizer", 4,
+                             Some("{:db/valueType :db.type/string :db/cardinality :db.cardinality/one}"));
+    /// The prefix lengths to index in a fulltext attribute's own table.
+    DB_FULLTEXT_PREFIX = (39, ":db.fulltext/prefix", 4,
+                          Some("{:db/valueType :db.type/long :db/cardinality :db.cardinality/many}"));
+
+    DB_TYPE_JSON = (40, ":db.type/json", 5, None);
+
+    /// Whether an attribute's values are encrypted before they're stored.
+    DB_ENCRYPTED = (41, ":db/encrypted", 6,
+                    Some("{:db/valueType :db.type/boolean :db/cardinality :db.cardinality/one}"));
+
+    /// The value asserted for an attribute when a new entity is created without one, as its EDN
+    /// text.  The transactor types the value asserted by its attribute, and writes its text.
+    DB_DEFAULT = (42, ":db/default", 7,
+                  Some("{:db/valueType :db.type/string :db/cardinality :db.cardinality/one}"));
+
+    /// Whether a ref attribute is an enumeration, whose values are the entities with idents in the
+    /// namespace named for it: `:task.status/open` for `:task/status`.
+    DB_ENUM = (43, ":db/enum", 8,
+               Some("{:db/valueType :db.type/boolean :db/cardinality :db.cardinality/one}"));
+
+    DB_TYPE_TUPLE = (44, ":db.type/tuple", 9, None);
+    /// The type of each component of a tuple attribute's values, like `[:db.type/long :db.type/string]`.
+    DB_TUPLE_TYPES = (45, ":db/tupleTypes", 9,
+                      Some("{:db/valueType :db.type/tuple :db/cardinality :db.cardinality/one}"));
+
+    /// The attributes from whose values a composite tuple attribute's values are derived, like
+    /// `[:reg/course :reg/semester]`.
+    DB_TUPLE_ATTRS = (46, ":db/tupleAttrs", 10,
+                      Some("{:db/valueType :db.type/tuple :db/cardinality :db.cardinality/one}"));
+}
+
+/// Return the core idents added in the given SQL schema version.
+pub fn idents_added_in(version: i32) -> Vec<&'static str> {
+    CORE_IDENTS.iter()
+        .filter(|core| core.version == version)
+        .map(|core| core.ident)
+        .collect()
+}
+
+/// Return `true` if asserting or retracting the given attribute changes the materialized `schema`
+/// view, i.e., if it is one of the attributes that defines an `Attribute`.
+pub fn is_schema_attribute(attribute: Entid) -> bool {
+    match attribute {
+        DB_VALUE_TYPE |
+        DB_CARDINALITY |
+        DB_UNIQUE |
+        DB_IS_COMPONENT |
+        DB_INDEX |
+        DB_FULLTEXT |
+        DB_FULLTEXT_TOKENIZER |
+        DB_FULLTEXT_PREFIX |
+        DB_ENCRYPTED |
+        DB_DEFAULT |
+        DB_ENUM |
+        DB_TUPLE_TYPES |
+        DB_TUPLE_ATTRS => true,
+        _ => false,
+    }
+}
@@ -13,6 +13,7 @@
 use edn;
 use rusqlite;
 
+use tx::{Ambiguity, Conflict};
 use types::{Entid, ValueType};
 
 error_chain! {
@@ -75,5 +76,108 @@ error_chain! {
             description("no ident found for entid")
             display("no ident found for entid: '{}'", entid)
         }
+
+        /// An ident->attribute mapping failed: the ident is unknown, or names an entity that isn't
+        /// an attribute.
+        UnrecognizedAttribute(ident: String) {
+            description("no attribute found for ident")
+            display("no attribute found for ident: '{}'", ident)
+        }
+
+        /// A partition name wasn't recognized.
+        UnrecognizedPartition(partition: String) {
+            description("no partition found")
+            display("no partition found: '{}'", partition)
+        }
+
+        /// A lookup-ref couldn't be resolved: its attribute isn't unique, or its value is
+        /// malformed.
+        BadLookupRef(t: String) {
+            description("bad lookup-ref")
+            display("bad lookup-ref: {}", t)
+        }
+
+        /// A lookup-ref didn't match any entity.
+        LookupRefNotFound(t: String) {
+            description("no entity found for lookup-ref")
+            display("no entity found for lookup-ref: {}", t)
+        }
+
+        /// Every entid in the named partition has been allocated.
+        PartitionExhausted(partition: String) {
+            description("no entids left in partition")
+            display("no entids left in partition: '{}'", partition)
+        }
+
+        /// A partition's next entid lies outside the partition's range.
+        BadPartition(t: String) {
+            description("bad partition")
+            display("bad partition: {}", t)
+        }
+
+        /// A transaction entity conflicts with the schema or with the store.  The `Conflict` names
+        /// the entity, the attribute, and any conflicting datoms.
+        TxConflict(conflict: Conflict) {
+            description("transaction conflict")
+            display("transaction conflict: {}", conflict)
+        }
+
+        /// A tempid's unique identities are held by different existing entities, so it can't
+        /// upsert.
+        AmbiguousUpsert(ambiguity: Ambiguity) {
+            description("ambiguous upsert")
+            display("ambiguous upsert: {}", ambiguity)
+        }
+
+        /// A `TempidHints` can't be followed, like a known entid outside `:db.part/user`.
+        BadTempidHint(t: String) {
+            description("bad tempid hint")
+            display("bad tempid hint: {}", t)
+        }
+
+        /// A transaction asserted an unacceptable `:db/txInstant`.
+        BadTxInstant(t: String) {
+            description("bad :db/txInstant")
+            display("bad :db/txInstant: {}", t)
+        }
+
+        /// The SQL store is damaged: a table has the wrong shape, or SQLite's integrity check
+        /// failed.
+        CorruptStore(t: String) {
+            description("corrupt SQL store")
+            display("corrupt SQL store: {}", t)
+        }
+
+        /// A value constraint can't be compiled, or can't apply to its attribute.
+        BadConstraint(t: String) {
+            description("bad value constraint")
+            display("bad value constraint: {}", t)
+        }
+
+        /// Connection options can't be applied, like a page size that isn't a power of two.
+        BadStoreOptions(t: String) {
+            description("bad store options")
+            display("bad store options: {}", t)
+        }
+
+        /// A transaction asserted or retracted a value of a composite tuple attribute, whose values
+        /// only the transactor derives.
+        DerivedAttribute(ident: String) {
+            description("can't transact a derived attribute")
+            display("can't transact a derived attribute: '{}'", ident)
+        }
+
+        /// An encrypted value was to be written or read, but no `Cipher` was given.
+        MissingCipher {
+            description("no cipher for encrypted values")
+            display("no cipher for encrypted values")
+        }
+
+        /// An encrypted value couldn't be decrypted: the cipher's key is probably not the one the
+        /// value was encrypted with.
+        DecryptionFailed {
+            description("couldn't decrypt value")
+            display("couldn't decrypt value")
+        }
     }
 }
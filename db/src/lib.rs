@@ -14,22 +14,39 @@ extern crate error_chain;
 extern crate lazy_static;
 extern crate num;
 extern crate ordered_float;
+extern crate regex;
 extern crate rusqlite;
+extern crate rustc_serialize;
 
 extern crate edn;
 extern crate mentat_tx;
 extern crate mentat_tx_parser;
 
+pub use cipher::{ENCRYPTED_VALUE_TYPE_TAG, decrypt_sql_value_pair, Cipher};
+#[cfg(any(test, feature = "testing"))]
+pub use cipher::XorCipher;
+pub use constraints::{Constraint, Constraints, Predicate};
+pub use db::{entity_with_value, read_log_value};
+pub use display::{DisplayEntid, DisplayTxReport};
 pub use errors::*;
 pub use schema::*;
+pub use tuple::TUPLE_VALUE_TYPE_TAG;
+pub use tx::{TX_TEMPID, now, transact, transact_with_options, validate, validate_with_options, Ambiguity, Conflict, ConflictKind, RetractPolicy, TempidHints, TxOptions, TxReport, ValidationError};
 pub use types::*;
 
 pub mod db;
+pub mod options;
+pub mod recovery;
 mod bootstrap;
+mod cipher;
+mod constraints;
 mod debug;
+mod display;
 mod entids;
 mod errors;
 mod schema;
+mod tuple;
+mod tx;
 mod types;
 mod values;
 
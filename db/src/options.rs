@@ -0,0 +1,247 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Tuning the SQLite connection underneath a store: page size, cache size, journal mode,
+//! synchronous level, and memory mapping.
+//!
+//! A `StoreOptions` is applied when a connection is opened, before the store is created or
+//! upgraded, so that a page size takes effect for a new store.  Options left unset keep SQLite's
+//! defaults.  The `mobile` and `desktop` presets trade memory and I/O for speed differently; the
+//! default options only put stores on disk in WAL mode.
+
+use rusqlite;
+
+use errors::{ErrorKind, Result};
+
+/// How SQLite journals a transaction, so that it can be rolled back.  See
+/// https://www.sqlite.org/pragma.html#pragma_journal_mode.
+#[derive(Clone,Copy,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    /// Write-ahead logging: readers don't block the writer, and the writer doesn't block readers.
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    fn as_sql(&self) -> &'static str {
+        match *self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF",
+        }
+    }
+}
+
+/// How hard SQLite works to get each commit onto the disk before carrying on.  See
+/// https://www.sqlite.org/pragma.html#pragma_synchronous.
+#[derive(Clone,Copy,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum Synchronous {
+    Off,
+    /// In WAL mode, a power loss can lose the last commits, but can't corrupt the store.
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn as_sql(&self) -> &'static str {
+        match *self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+/// The SQLite settings to apply to a connection when it's opened.  Build them up from `new`, or
+/// from one of the presets, like `StoreOptions::mobile().synchronous(Synchronous::Full)`.
+#[derive(Clone,Debug,Eq,Hash,PartialEq)]
+pub struct StoreOptions {
+    /// The size in bytes of a database page: a power of two from 512 to 65536.  Only takes effect
+    /// for a new store, or one not in WAL mode that is then vacuumed.
+    pub page_size: Option<u32>,
+
+    /// The number of pages to cache, or, if negative, the number of KiB of pages.
+    pub cache_size: Option<i64>,
+
+    /// Ignored for in-memory stores.  Where the mode isn't available, SQLite keeps its current
+    /// mode.
+    pub journal_mode: Option<JournalMode>,
+
+    pub synchronous: Option<Synchronous>,
+
+    /// The number of bytes of the store to map into memory; 0 turns memory mapping off.
+    pub mmap_size: Option<u64>,
+}
+
+impl Default for StoreOptions {
+    fn default() -> StoreOptions {
+        StoreOptions {
+            journal_mode: Some(JournalMode::Wal),
+            ..StoreOptions::new()
+        }
+    }
+}
+
+impl StoreOptions {
+    /// Options that change nothing: every setting is left at SQLite's default.
+    pub fn new() -> StoreOptions {
+        StoreOptions {
+            page_size: None,
+            cache_size: None,
+            journal_mode: None,
+            synchronous: None,
+            mmap_size: None,
+        }
+    }
+
+    /// For phones and tablets: a small cache, and no memory mapping, which turns I/O errors into
+    /// crashes.  WAL mode with `Normal` synchronization spares the flash from a sync per commit.
+    pub fn mobile() -> StoreOptions {
+        StoreOptions::new()
+            .page_size(4096)
+            .cache_size(-2048)
+            .journal_mode(JournalMode::Wal)
+            .synchronous(Synchronous::Normal)
+            .mmap_size(0)
+    }
+
+    /// For desktops and servers: a 64 MiB cache, and the first 256 MiB of the store mapped into
+    /// memory.
+    pub fn desktop() -> StoreOptions {
+        StoreOptions::new()
+            .page_size(4096)
+            .cache_size(-65536)
+            .journal_mode(JournalMode::Wal)
+            .synchronous(Synchronous::Normal)
+            .mmap_size(256 * 1024 * 1024)
+    }
+
+    pub fn page_size(mut self, bytes: u32) -> StoreOptions {
+        self.page_size = Some(bytes);
+        self
+    }
+
+    pub fn cache_size(mut self, size: i64) -> StoreOptions {
+        self.cache_size = Some(size);
+        self
+    }
+
+    pub fn journal_mode(mut self, mode: JournalMode) -> StoreOptions {
+        self.journal_mode = Some(mode);
+        self
+    }
+
+    pub fn synchronous(mut self, level: Synchronous) -> StoreOptions {
+        self.synchronous = Some(level);
+        self
+    }
+
+    pub fn mmap_size(mut self, bytes: u64) -> StoreOptions {
+        self.mmap_size = Some(bytes);
+        self
+    }
+
+    /// Apply these options to the newly opened connection `conn`.  Fails if an option is out of
+    /// range, without changing anything.
+    pub fn apply(&self, conn: &rusqlite::Connection, in_memory: bool) -> Result<()> {
+        if let Some(bytes) = self.page_size {
+            if bytes < 512 || bytes > 65536 || !bytes.is_power_of_two() {
+                bail!(ErrorKind::BadStoreOptions(format!("page size {} isn't a power of two from 512 to 65536", bytes)));
+            }
+        }
+
+        // The page size has to be set before the journal mode: it can't change in WAL mode.
+        let mut pragmas = vec![];
+        if let Some(bytes) = self.page_size {
+            pragmas.push(format!("PRAGMA page_size = {};", bytes));
+        }
+        if let Some(size) = self.cache_size {
+            pragmas.push(format!("PRAGMA cache_size = {};", size));
+        }
+        if let Some(bytes) = self.mmap_size {
+            pragmas.push(format!("PRAGMA mmap_size = {};", bytes));
+        }
+        if let Some(level) = self.synchronous {
+            pragmas.push(format!("PRAGMA synchronous = {};", level.as_sql()));
+        }
+        conn.execute_batch(&pragmas.join(""))?;
+
+        if let Some(mode) = self.journal_mode {
+            if !in_memory {
+                // Setting the journal mode reports the resulting mode as a row.  Where WAL isn't
+                // available, SQLite keeps the rollback journal, and readers block commits instead.
+                conn.query_row(&format!("PRAGMA journal_mode = {}", mode.as_sql()), &[], |row| row.get::<i32, String>(0))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn pragma(conn: &rusqlite::Connection, name: &str) -> String {
+        conn.query_row(&format!("PRAGMA {}", name), &[], |row| row.get::<i32, rusqlite::types::Value>(0))
+            .map(|value| match value {
+                rusqlite::types::Value::Integer(x) => x.to_string(),
+                rusqlite::types::Value::Text(x) => x.to_lowercase(),
+                value => format!("{:?}", value),
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_apply() {
+        let path = env::temp_dir().join(format!("mentat-test-{}-options.db", process::id()));
+        {
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            StoreOptions::mobile().page_size(8192).synchronous(Synchronous::Full).apply(&conn, false).unwrap();
+            conn.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+            assert_eq!(pragma(&conn, "page_size"), "8192");
+            assert_eq!(pragma(&conn, "cache_size"), "-2048");
+            assert_eq!(pragma(&conn, "journal_mode"), "wal");
+            assert_eq!(pragma(&conn, "synchronous"), "2");
+        }
+        fs::remove_file(&path).ok();
+        fs::remove_file(path.with_extension("db-wal")).ok();
+        fs::remove_file(path.with_extension("db-shm")).ok();
+
+        // In-memory stores keep their memory journal.
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        StoreOptions::desktop().apply(&conn, true).unwrap();
+        assert_eq!(pragma(&conn, "journal_mode"), "memory");
+        assert_eq!(pragma(&conn, "cache_size"), "-65536");
+    }
+
+    #[test]
+    fn test_bad_page_size() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert!(StoreOptions::new().page_size(1000).cache_size(10).apply(&conn, true).is_err());
+        assert!(StoreOptions::new().page_size(256).apply(&conn, true).is_err());
+
+        // Nothing was changed.
+        assert_ne!(pragma(&conn, "cache_size"), "10");
+    }
+}
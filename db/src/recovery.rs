@@ -0,0 +1,296 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Detecting and recovering from damage to a Mentat SQL store.
+//!
+//! Mobile devices lose power, fill their disks, and get restored from partial backups; a store
+//! that can't be opened strands its user's data.  `verify` checks that the core tables have the
+//! shape this version of Mentat expects and that SQLite's own integrity check passes.  `recover`
+//! tries to repair a store that fails verification, according to a `RecoveryPolicy`.
+//!
+//! The transaction log is the source of truth: every assertion and retraction is recorded in
+//! `transactions`, including those that install and alter the schema, so `datoms` can always be
+//! re-derived from it, and the `idents` and `schema` materialized views from `datoms`.
+
+use rusqlite;
+
+use db;
+use entids;
+use errors::{ErrorKind, Result};
+use types::ValueType;
+
+/// What to do when a store fails verification.
+#[derive(Clone,Copy,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum RecoveryPolicy {
+    /// Report the damage as an error and change nothing.
+    Fail,
+
+    /// Rebuild every index from its table with `REINDEX`.  Repairs damaged indices, but not
+    /// damaged tables.
+    RebuildIndexes,
+
+    /// Drop the `datoms` table and re-derive it from the `transactions` log, and the schema's
+    /// materialized views and the attributes' own fulltext tables from it.
+    RederiveDatoms,
+}
+
+impl Default for RecoveryPolicy {
+    fn default() -> RecoveryPolicy {
+        RecoveryPolicy::Fail
+    }
+}
+
+/// The columns, in order, of the core tables.
+const EXPECTED_TABLES: &'static [(&'static str, &'static [&'static str])] = &[
+    ("datoms", &["e", "a", "v", "tx", "value_type_tag", "index_avet", "index_vaet", "index_fulltext", "unique_value"]),
+    ("transactions", &["e", "a", "v", "tx", "added", "value_type_tag"]),
+    ("parts", &["part", "start", "idx"]),
+];
+
+fn table_columns(conn: &rusqlite::Connection, table: &str) -> Result<Vec<String>> {
+    let mut stmt: rusqlite::Statement = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns: Result<Vec<String>> = stmt.query_and_then(&[], |row| Ok(row.get_checked(1)?))?.collect();
+    columns
+}
+
+/// Check that the `datoms`, `transactions`, and `parts` tables have the expected columns.
+pub fn verify_tables(conn: &rusqlite::Connection) -> Result<()> {
+    for &(table, expected) in EXPECTED_TABLES.iter() {
+        let columns = table_columns(conn, table)?;
+        if columns.is_empty() {
+            bail!(ErrorKind::CorruptStore(format!("missing table {}", table)));
+        }
+        if columns != expected {
+            bail!(ErrorKind::CorruptStore(format!("table {} has columns {:?}, expected {:?}", table, columns, expected)));
+        }
+    }
+    Ok(())
+}
+
+/// Run `PRAGMA integrity_check`, failing with the problems it reports, if any.
+pub fn integrity_check(conn: &rusqlite::Connection) -> Result<()> {
+    let mut stmt: rusqlite::Statement = conn.prepare("PRAGMA integrity_check")?;
+    let problems: Result<Vec<String>> = stmt.query_and_then(&[], |row| Ok(row.get_checked(0)?))?.collect();
+    let problems = problems?;
+    if problems != vec!["ok".to_string()] {
+        bail!(ErrorKind::CorruptStore(problems.join("; ")));
+    }
+    Ok(())
+}
+
+/// Check that the store is undamaged.  See `verify_tables` and `integrity_check`.
+pub fn verify(conn: &rusqlite::Connection) -> Result<()> {
+    verify_tables(conn)?;
+    integrity_check(conn)
+}
+
+/// Replace the contents of the `idents` and `schema` materialized views with the schema implied by
+/// `datoms`: an ident for each `:db/ident` datom, and an entry for each datom of a schema attribute,
+/// like `:db/cardinality`, about an entity with an ident.
+pub fn rederive_schema(conn: &rusqlite::Connection) -> Result<()> {
+    conn.execute("DELETE FROM idents", &[])?;
+    conn.execute("INSERT INTO idents (ident, entid) SELECT v, e FROM datoms WHERE a = ?", &[&entids::DB_IDENT])?;
+
+    let attributes: Vec<String> = entids::CORE_IDENTS.iter()
+        .filter(|core| entids::is_schema_attribute(core.entid))
+        .map(|core| core.entid.to_string())
+        .collect();
+    conn.execute("DELETE FROM schema", &[])?;
+    conn.execute(&format!(r#"INSERT INTO schema (ident, attr, value, value_type_tag)
+                             SELECT e.ident, a.ident, d.v, d.value_type_tag FROM datoms AS d
+                             JOIN idents AS e ON e.entid = d.e
+                             JOIN idents AS a ON a.entid = d.a
+                             WHERE d.a IN ({})"#, attributes.join(", ")), &[])?;
+    Ok(())
+}
+
+/// Replace the contents of `datoms` with the datoms implied by the `transactions` log, and the
+/// schema's materialized views with the schema they imply; see `rederive_schema`.
+///
+/// A datom is present if the latest log entry for its `(e, a, v)` is an assertion.  The index flags
+/// are recomputed from the re-derived schema, and the own fulltext tables of the attributes that
+/// have one are rebuilt from the re-derived datoms.
+pub fn rederive_datoms(conn: &mut rusqlite::Connection) -> Result<()> {
+    let tx = conn.transaction()?;
+
+    tx.execute("DROP TABLE IF EXISTS datoms", &[])?;
+    db::create_datoms_table(&tx)?;
+
+    // Log entries are written in order, so within a transaction the later rowid wins.
+    tx.execute(r#"INSERT INTO datoms (e, a, v, tx, value_type_tag)
+                  SELECT t.e, t.a, t.v, t.tx, t.value_type_tag FROM transactions AS t
+                  WHERE t.added IS NOT 0
+                  AND t.rowid = (SELECT l.rowid FROM transactions AS l
+                                 WHERE l.e = t.e AND l.a = t.a AND l.value_type_tag = t.value_type_tag AND l.v = t.v
+                                 ORDER BY l.tx DESC, l.rowid DESC LIMIT 1)"#, &[])?;
+
+    rederive_schema(&tx)?;
+    let schema = db::read_schema(&tx, &db::read_ident_map(&tx)?)?;
+    for (a, attribute) in schema.schema_map.iter() {
+        let index_vaet = attribute.value_type == ValueType::Ref;
+        tx.execute("UPDATE datoms SET index_avet = ?, index_vaet = ?, index_fulltext = ?, unique_value = ? WHERE a = ?",
+                   &[&attribute.index, &index_vaet, &attribute.fulltext, &attribute.unique_value, a])?;
+    }
+    db::rebuild_fulltext_tables(&tx, &schema)?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Try to repair a store that failed verification, according to `policy`, and verify it again.
+pub fn recover(conn: &mut rusqlite::Connection, policy: RecoveryPolicy) -> Result<()> {
+    match policy {
+        RecoveryPolicy::Fail => return verify(conn),
+        RecoveryPolicy::RebuildIndexes => {
+            conn.execute("REINDEX", &[])?;
+        },
+        RecoveryPolicy::RederiveDatoms => {
+            rederive_datoms(conn)?;
+            conn.execute("REINDEX", &[])?;
+        },
+    }
+    verify(conn)
+}
+
+/// Verify the store, trying to repair it according to `policy` if it's damaged.
+pub fn verify_or_recover(conn: &mut rusqlite::Connection, policy: RecoveryPolicy) -> Result<()> {
+    match verify(conn) {
+        Ok(()) => Ok(()),
+        Err(_) if policy != RecoveryPolicy::Fail => recover(conn, policy),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use edn;
+    use mentat_tx_parser::Tx as TxParser;
+
+    use errors::Error;
+    use tx;
+    use types::fulltext_table_name;
+
+    fn test_conn() -> rusqlite::Connection {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        conn
+    }
+
+    fn datoms(conn: &rusqlite::Connection) -> Vec<(i64, i64, String, i64, i64, bool, bool, bool, bool)> {
+        let mut stmt = conn.prepare("SELECT e, a, quote(v), tx, value_type_tag, index_avet, index_vaet, index_fulltext, unique_value
+                                     FROM datoms ORDER BY e, a, value_type_tag, v").unwrap();
+        let rows = stmt.query_map(&[], |row| (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4),
+                                             row.get(5), row.get(6), row.get(7), row.get(8))).unwrap();
+        rows.map(|x| x.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_verify_fresh_store() {
+        let conn = test_conn();
+        verify(&conn).unwrap();
+    }
+
+    #[test]
+    fn test_verify_tables() {
+        let conn = test_conn();
+        conn.execute("DROP TABLE parts", &[]).unwrap();
+        match verify(&conn) {
+            Err(Error(ErrorKind::CorruptStore(_), _)) => (),
+            x => panic!("expected CorruptStore, got {:?}", x),
+        }
+
+        let conn = test_conn();
+        conn.execute("ALTER TABLE transactions ADD COLUMN extra INTEGER", &[]).unwrap();
+        assert!(verify(&conn).is_err());
+    }
+
+    #[test]
+    fn test_rederive_datoms() {
+        let mut conn = test_conn();
+        let expected = datoms(&conn);
+
+        // Lose some datoms, and the whole table.
+        conn.execute("DELETE FROM datoms WHERE e > 10", &[]).unwrap();
+        assert!(datoms(&conn) != expected);
+        rederive_datoms(&mut conn).unwrap();
+        assert_eq!(datoms(&conn), expected);
+
+        conn.execute("DROP TABLE datoms", &[]).unwrap();
+        assert!(verify(&conn).is_err());
+        assert!(verify_or_recover(&mut conn, RecoveryPolicy::Fail).is_err());
+        verify_or_recover(&mut conn, RecoveryPolicy::RederiveDatoms).unwrap();
+        assert_eq!(datoms(&conn), expected);
+    }
+
+    #[test]
+    fn test_rederive_schema() {
+        let mut conn = test_conn();
+        let db = db::read_db(&conn).unwrap();
+        let value = edn::parse::value(r#"[[:db/add "t" :db/ident :test/tags]
+                                          [:db/add "t" :db/valueType :db.type/string]
+                                          [:db/add "t" :db/cardinality :db.cardinality/one]]"#).unwrap();
+        let (report, db) = tx::transact(&conn, &db, &TxParser::parse(&[value][..]).unwrap()).unwrap();
+        let tags = report.tempids["t"];
+        let value = edn::parse::value(r#"[[:db/add :test/tags :db/cardinality :db.cardinality/many]
+                                          [:db/add :test/tags :db/ident :test/labels]]"#).unwrap();
+        let (_, db) = tx::transact(&conn, &db, &TxParser::parse(&[value][..]).unwrap()).unwrap();
+
+        // Altering the schema is logged like any other change.
+        let logged: i64 = conn.query_row("SELECT COUNT(*) FROM transactions WHERE e = ? AND a IN (?, ?)",
+                                         &[&tags, &entids::DB_IDENT, &entids::DB_CARDINALITY], |row| row.get(0)).unwrap();
+        assert_eq!(logged, 6);
+
+        // Lose the materialized views, and re-derive them from the log.
+        conn.execute("DELETE FROM idents", &[]).unwrap();
+        conn.execute("DELETE FROM schema", &[]).unwrap();
+        rederive_datoms(&mut conn).unwrap();
+        assert_eq!(db::read_db(&conn).unwrap(), db);
+        assert!(db.schema.attribute_for_ident_str(":test/labels").unwrap().0.multival);
+    }
+
+    #[test]
+    fn test_rederive_fulltext_tables() {
+        let mut conn = test_conn();
+        let db = db::read_db(&conn).unwrap();
+        let value = edn::parse::value(r#"[[:db/add "t" :db/ident :test/text]
+                                          [:db/add "t" :db/valueType :db.type/string]
+                                          [:db/add "t" :db/fulltext true]
+                                          [:db/add "t" :db.fulltext/tokenizer "porter unicode61"]]"#).unwrap();
+        let (report, db) = tx::transact(&conn, &db, &TxParser::parse(&[value][..]).unwrap()).unwrap();
+        let table = fulltext_table_name(report.tempids["t"]);
+        let value = edn::parse::value(r#"[[:db/add "a" :test/text "the fox jumps"]]"#).unwrap();
+        tx::transact(&conn, &db, &TxParser::parse(&[value][..]).unwrap()).unwrap();
+
+        // Lose the attribute's own table, and rebuild it from the log.
+        conn.execute(&format!("DROP TABLE {}", table), &[]).unwrap();
+        rederive_datoms(&mut conn).unwrap();
+        let matches: i64 = conn.query_row(&format!("SELECT COUNT(*) FROM {0} WHERE {0} MATCH 'jumping'", table), &[], |row| row.get(0)).unwrap();
+        assert_eq!(matches, 1);
+    }
+
+    #[test]
+    fn test_rederive_respects_retractions() {
+        let mut conn = test_conn();
+        conn.execute("INSERT INTO transactions (e, a, v, tx, added, value_type_tag) VALUES (100, 1, 'x', 1000, 1, 10)", &[]).unwrap();
+        conn.execute("INSERT INTO transactions (e, a, v, tx, added, value_type_tag) VALUES (100, 1, 'x', 1001, 0, 10)", &[]).unwrap();
+        conn.execute("INSERT INTO transactions (e, a, v, tx, added, value_type_tag) VALUES (100, 1, 'y', 1001, 1, 10)", &[]).unwrap();
+        rederive_datoms(&mut conn).unwrap();
+
+        let values: Vec<String> = conn.prepare("SELECT v FROM datoms WHERE e = 100").unwrap()
+            .query_map(&[], |row| row.get(0)).unwrap()
+            .map(|x| x.unwrap())
+            .collect();
+        assert_eq!(values, vec!["y".to_string()]);
+    }
+}
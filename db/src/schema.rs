@@ -10,32 +10,303 @@
 
 #![allow(dead_code)]
 
+use std::sync::Arc;
+
+use edn;
+use edn::symbols::NamespacedKeyword;
+use edn::types::Value;
+
 use entids;
 use errors::*;
-use types::{Attribute, Entid, EntidMap, IdentMap, Schema, SchemaMap, TypedValue, ValueType};
+use tuple;
+use types::{Attribute, Entid, EntidMap, IdentMap, Schema, SchemaMap, TypedValue, ValueType, enum_namespace};
+
+/// Return the value type named by the given `:db.type/*` entid, if it is one.
+pub fn value_type_for_entid(entid: Entid) -> Option<ValueType> {
+    match entid {
+        entids::DB_TYPE_REF => Some(ValueType::Ref),
+        entids::DB_TYPE_BOOLEAN => Some(ValueType::Boolean),
+        entids::DB_TYPE_LONG => Some(ValueType::Long),
+        entids::DB_TYPE_DOUBLE => Some(ValueType::Double),
+        entids::DB_TYPE_STRING => Some(ValueType::String),
+        entids::DB_TYPE_KEYWORD => Some(ValueType::Keyword),
+        entids::DB_TYPE_BYTES => Some(ValueType::Bytes),
+        entids::DB_TYPE_JSON => Some(ValueType::Json),
+        entids::DB_TYPE_TUPLE => Some(ValueType::Tuple),
+        _ => None,
+    }
+}
+
+/// Return `Ok(())` if `attribute` is a valid Mentat attribute for `entid`, whose composite sources,
+/// if any, are in `schema_map`.
+fn validate_attribute(entid_map: &EntidMap, schema_map: &SchemaMap, entid: &Entid, attribute: &Attribute) -> Result<()> {
+    let ident = entid_map.get(entid).ok_or(ErrorKind::BadSchemaAssertion(format!("Could not get ident for entid: {}", entid)))?;
+
+    if attribute.unique_identity && !attribute.unique_value {
+        bail!(ErrorKind::BadSchemaAssertion(format!(":db/unique :db/unique_identity without :db/unique :db/unique_value for entid: {}", ident)))
+    }
+    if attribute.fulltext && attribute.value_type != ValueType::String {
+        bail!(ErrorKind::BadSchemaAssertion(format!(":db/fulltext true without :db/valueType :db.type/string for entid: {}", ident)))
+    }
+    if (attribute.fulltext_tokenizer.is_some() || !attribute.fulltext_prefixes.is_empty()) && !attribute.fulltext {
+        bail!(ErrorKind::BadSchemaAssertion(format!(":db.fulltext/tokenizer or :db.fulltext/prefix without :db/fulltext true for entid: {}", ident)))
+    }
+    if attribute.component && attribute.value_type != ValueType::Ref {
+        bail!(ErrorKind::BadSchemaAssertion(format!(":db/isComponent true without :db/valueType :db.type/ref for entid: {}", ident)))
+    }
+    if attribute.encrypted {
+        match attribute.value_type {
+            ValueType::String | ValueType::Bytes | ValueType::Json => (),
+            _ => bail!(ErrorKind::BadSchemaAssertion(format!(":db/encrypted true without :db/valueType :db.type/string, :db.type/bytes, or :db.type/json for entid: {}", ident))),
+        }
+        // Each encryption of a value differs, so stored values can't be compared or ordered.
+        if attribute.unique_value || attribute.index || attribute.fulltext {
+            bail!(ErrorKind::BadSchemaAssertion(format!(":db/encrypted true with :db/unique, :db/index, or :db/fulltext for entid: {}", ident)))
+        }
+    }
+    if attribute.enumerated && attribute.value_type != ValueType::Ref {
+        bail!(ErrorKind::BadSchemaAssertion(format!(":db/enum true without :db/valueType :db.type/ref for entid: {}", ident)))
+    }
+    if !attribute.tuple_types.is_empty() && attribute.value_type != ValueType::Tuple {
+        bail!(ErrorKind::BadSchemaAssertion(format!(":db/tupleTypes without :db/valueType :db.type/tuple for entid: {}", ident)))
+    }
+    if let Some(value_type) = attribute.tuple_types.iter().find(|value_type| !tuple::is_component_type(value_type)) {
+        bail!(ErrorKind::BadSchemaAssertion(format!(":db/tupleTypes with component type {:?} for entid: {}", value_type, ident)))
+    }
+    for a in attribute.tuple_attrs.iter() {
+        let source = schema_map.get(a).ok_or(ErrorKind::BadSchemaAssertion(format!(":db/tupleAttrs with {}, which isn't an attribute, for entid: {}", a, ident)))?;
+        if attribute.value_type != ValueType::Tuple || source.multival || source.encrypted || !tuple::is_component_type(&source.value_type) {
+            bail!(ErrorKind::BadSchemaAssertion(format!(":db/tupleAttrs without :db/valueType :db.type/tuple, or with {}, which isn't a scalar cardinality one attribute, for entid: {}", a, ident)))
+        }
+    }
+    if let Some(ref default) = attribute.default {
+        // Instants are stored as longs.
+        let instant = attribute.value_type == ValueType::Instant && default.value_type() == ValueType::Long;
+        if default.value_type() != attribute.value_type && !instant {
+            bail!(ErrorKind::BadSchemaAssertion(format!(":db/default {:?} without :db/valueType {:?} for entid: {}", default, attribute.value_type, ident)))
+        }
+        // Every new entity would get the same value; and defaults aren't encrypted.
+        if attribute.unique_value || attribute.encrypted {
+            bail!(ErrorKind::BadSchemaAssertion(format!(":db/default with :db/unique or :db/encrypted true for entid: {}", ident)))
+        }
+    }
+    // TODO: consider warning if we have :db/index true for :db/valueType :db.type/string,
+    // since this may be inefficient.  More generally, we should try to drive complex
+    // :db/valueType (string, uri, json in the future) users to opt-in to some hash-indexing
+    // scheme, as discussed in https://github.com/mozilla/mentat/issues/69.
+    Ok(())
+}
+
+/// Read the EDN text of a `:db/default`, as the transactor writes it, into a value of the type the
+/// text reads as.  Vectors read as tuples.
+fn default_from_edn(text: &str) -> Option<TypedValue> {
+    match edn::parse::value(text) {
+        Ok(Value::Vector(ref components)) => {
+            let components: Option<Vec<TypedValue>> = components.iter().map(TypedValue::from_edn_value).collect();
+            components.map(TypedValue::Tuple)
+        },
+        Ok(ref value) => TypedValue::from_edn_value(value),
+        Err(_) => None,
+    }
+}
+
+/// Give the default of `attribute`, read by `default_from_edn`, the attribute's value type, once
+/// that's known: refs read back as longs, and JSON documents as strings.
+fn type_default(attribute: &mut Attribute) {
+    fn typed(value_type: &ValueType, value: TypedValue) -> TypedValue {
+        match (value_type, value) {
+            (&ValueType::Ref, TypedValue::Long(x)) => TypedValue::Ref(x),
+            (&ValueType::Json, TypedValue::String(x)) => TypedValue::Json(x),
+            (_, value) => value,
+        }
+    }
+    attribute.default = match attribute.default.take() {
+        Some(TypedValue::Tuple(components)) => {
+            let types = attribute.tuple_types.iter().map(Some).chain(::std::iter::repeat(None));
+            Some(TypedValue::Tuple(components.into_iter().zip(types).map(|(component, value_type)| {
+                match value_type {
+                    Some(value_type) => typed(value_type, component),
+                    None => component,
+                }
+            }).collect()))
+        },
+        Some(default) => Some(typed(&attribute.value_type, default)),
+        None => None,
+    };
+}
 
 /// Return `Ok(())` if `schema_map` defines a valid Mentat schema.
 fn validate_schema_map(entid_map: &EntidMap, schema_map: &SchemaMap) -> Result<()> {
     for (entid, attribute) in schema_map {
-        let ident = entid_map.get(entid).ok_or(ErrorKind::BadSchemaAssertion(format!("Could not get ident for entid: {}", entid)))?;
+        validate_attribute(entid_map, schema_map, entid, attribute)?;
+    }
+    Ok(())
+}
 
-        if attribute.unique_identity && !attribute.unique_value {
-            bail!(ErrorKind::BadSchemaAssertion(format!(":db/unique :db/unique_identity without :db/unique :db/unique_value for entid: {}", ident)))
-        }
-        if attribute.fulltext && attribute.value_type != ValueType::String {
-            bail!(ErrorKind::BadSchemaAssertion(format!(":db/fulltext true without :db/valueType :db.type/string for entid: {}", ident)))
-        }
-        if attribute.component && attribute.value_type != ValueType::Ref {
-            bail!(ErrorKind::BadSchemaAssertion(format!(":db/isComponent true without :db/valueType :db.type/ref for entid: {}", ident)))
+/// Apply the schema assertion `[ident attr value]` to `attributes`, the attribute flags of `ident`.
+/// Idents in `value`, like the component types of `:db/tupleTypes`, are looked up in `ident_map`.
+fn apply_assertion(attributes: &mut Attribute, ident_map: &IdentMap, ident: Entid, attr: Entid, value: &TypedValue) -> Result<()> {
+    // TODO: improve error messages throughout.
+    match attr {
+        entids::DB_VALUE_TYPE => {
+            match *value {
+                TypedValue::Ref(x) => {
+                    attributes.value_type = value_type_for_entid(x)
+                        .ok_or(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/valueType :db.type/*] but got [... :db/valueType {:?}] for ident '{}' and attribute '{}'", value, ident, attr)))?;
+                },
+                _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/valueType :db.type/*] but got [... :db/valueType {:?}] for ident '{}' and attribute '{}'", value, ident, attr)))
+            }
+        },
+
+        entids::DB_CARDINALITY => {
+            match *value {
+                TypedValue::Ref(entids::DB_CARDINALITY_MANY) => { attributes.multival = true; },
+                TypedValue::Ref(entids::DB_CARDINALITY_ONE) => { attributes.multival = false; },
+                _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/cardinality :db.cardinality/many|:db.cardinality/one] but got [... :db/cardinality {:?}]", value)))
+            }
+        },
+
+        entids::DB_UNIQUE => {
+            match *value {
+                TypedValue::Ref(entids::DB_UNIQUE_VALUE) => { attributes.unique_value = true; },
+                TypedValue::Ref(entids::DB_UNIQUE_IDENTITY) => {
+                    attributes.unique_value = true;
+                    attributes.unique_identity = true;
+                },
+                _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/unique :db.unique/value|:db.unique/identity] but got [... :db/unique {:?}]", value)))
+            }
+        },
+
+        entids::DB_INDEX => {
+            match *value {
+                TypedValue::Boolean(x) => { attributes.index = x },
+                _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/index true|false] but got [... :db/index {:?}]", value)))
+            }
+        },
+
+        entids::DB_FULLTEXT => {
+            match *value {
+                TypedValue::Boolean(x) => {
+                    attributes.fulltext = x;
+                    if attributes.fulltext {
+                        attributes.index = true;
+                    }
+                },
+                _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/fulltext true|false] but got [... :db/fulltext {:?}]", value)))
+            }
+        },
+
+        entids::DB_FULLTEXT_TOKENIZER => {
+            match *value {
+                // The tokenizer is interpolated into SQL, so we only allow words.
+                TypedValue::String(ref x) if !x.trim().is_empty() && x.chars().all(|c| c.is_digit(36) || c == '_' || c == ' ') => {
+                    attributes.fulltext_tokenizer = Some(x.clone());
+                },
+                _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db.fulltext/tokenizer \"tokenizer args...\"] but got [... :db.fulltext/tokenizer {:?}]", value)))
+            }
+        },
+
+        entids::DB_FULLTEXT_PREFIX => {
+            match *value {
+                TypedValue::Long(x) if x > 0 => { attributes.fulltext_prefixes.insert(x); },
+                _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db.fulltext/prefix n] for positive n but got [... :db.fulltext/prefix {:?}]", value)))
+            }
+        },
+
+        entids::DB_IS_COMPONENT => {
+            match *value {
+                TypedValue::Boolean(x) => { attributes.component = x },
+                _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/isComponent true|false] but got [... :db/isComponent {:?}]", value)))
+            }
+        },
+
+        entids::DB_ENCRYPTED => {
+            match *value {
+                TypedValue::Boolean(x) => { attributes.encrypted = x },
+                _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/encrypted true|false] but got [... :db/encrypted {:?}]", value)))
+            }
+        },
+
+        entids::DB_ENUM => {
+            match *value {
+                TypedValue::Boolean(x) => { attributes.enumerated = x },
+                _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/enum true|false] but got [... :db/enum {:?}]", value)))
+            }
+        },
+
+        entids::DB_DEFAULT => {
+            // The EDN text of the value; see `type_default`.
+            let default = match *value {
+                TypedValue::String(ref x) => default_from_edn(x),
+                _ => None,
+            };
+            attributes.default = Some(default
+                .ok_or(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/default \"edn\"] but got [... :db/default {:?}]", value)))?);
+        },
+
+        entids::DB_TUPLE_TYPES => {
+            // Component types are named by their idents, like :db.type/long.
+            let value_types: Option<Vec<ValueType>> = match *value {
+                TypedValue::Tuple(ref components) if !components.is_empty() => {
+                    components.iter().map(|component| {
+                        match *component {
+                            TypedValue::Keyword(ref x) => ident_map.get(&x.to_string()).and_then(|&entid| value_type_for_entid(entid)),
+                            TypedValue::Ref(x) => value_type_for_entid(x),
+                            _ => None,
+                        }
+                    }).collect()
+                },
+                _ => None,
+            };
+            attributes.tuple_types = value_types
+                .ok_or(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/tupleTypes [:db.type/* ...]] but got [... :db/tupleTypes {:?}]", value)))?;
+        },
+
+        entids::DB_TUPLE_ATTRS => {
+            // Source attributes are named by their idents, like :reg/course.
+            let sources: Option<Vec<Entid>> = match *value {
+                TypedValue::Tuple(ref components) if !components.is_empty() => {
+                    components.iter().map(|component| {
+                        match *component {
+                            TypedValue::Keyword(ref x) => ident_map.get(&x.to_string()).cloned(),
+                            TypedValue::Ref(x) => Some(x),
+                            _ => None,
+                        }
+                    }).collect()
+                },
+                _ => None,
+            };
+            attributes.tuple_attrs = sources
+                .ok_or(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/tupleAttrs [:attribute ...]] but got [... :db/tupleAttrs {:?}]", value)))?;
+        },
+
+        entids::DB_DOC => {
+            // Nothing for now.
+        },
+
+        entids::DB_IDENT => {
+            // Nothing for now.
+        },
+
+        entids::DB_INSTALL_ATTRIBUTE => {
+            // Nothing for now.
+        },
+
+        _ => {
+            bail!(ErrorKind::BadSchemaAssertion(format!("Do not recognize attribute '{}' for ident '{}'", attr, ident)))
         }
-        // TODO: consider warning if we have :db/index true for :db/valueType :db.type/string,
-        // since this may be inefficient.  More generally, we should try to drive complex
-        // :db/valueType (string, uri, json in the future) users to opt-in to some hash-indexing
-        // scheme, as discussed in https://github.com/mozilla/mentat/issues/69.
     }
     Ok(())
 }
 
+/// Return the component types of a composite with the given source attributes: their value types.
+/// A source that isn't in `schema_map` is taken to be a ref, and fails validation.
+fn composite_types(schema_map: &SchemaMap, sources: &[Entid]) -> Vec<ValueType> {
+    sources.iter()
+        .map(|a| schema_map.get(a).map_or(ValueType::Ref, |source| source.value_type.clone()))
+        .collect()
+}
+
 impl Schema {
     pub fn get_ident(&self, x: &Entid) -> Option<&String> {
         self.entid_map.get(x)
@@ -61,6 +332,41 @@ impl Schema {
         self.attribute_for_entid(entid).ok_or(ErrorKind::UnrecognizedEntid(*entid).into())
     }
 
+    /// Return the attribute named by `ident`, with its entid, if `ident` names an attribute.
+    pub fn attribute_for_ident(&self, ident: &NamespacedKeyword) -> Option<(&Attribute, Entid)> {
+        self.attribute_for_ident_str(&ident.to_string())
+    }
+
+    /// Like `attribute_for_ident`, but for an ident as the maps key it, like `:person/name`.
+    pub fn attribute_for_ident_str(&self, ident: &str) -> Option<(&Attribute, Entid)> {
+        self.ident_map.get(ident)
+            .and_then(|&entid| self.attribute_for_entid(&entid).map(|attribute| (attribute, entid)))
+    }
+
+    pub fn require_attribute_for_ident(&self, ident: &NamespacedKeyword) -> Result<(&Attribute, Entid)> {
+        self.require_attribute_for_ident_str(&ident.to_string())
+    }
+
+    pub fn require_attribute_for_ident_str(&self, ident: &str) -> Result<(&Attribute, Entid)> {
+        self.attribute_for_ident_str(ident).ok_or(ErrorKind::UnrecognizedAttribute(ident.to_string()).into())
+    }
+
+    /// Return every installed attribute, with its ident and entid, sorted by ident.
+    pub fn attributes(&self) -> Vec<(&String, Entid, &Attribute)> {
+        self.ident_map.iter()
+            .filter_map(|(ident, &entid)| self.attribute_for_entid(&entid).map(|attribute| (ident, entid, attribute)))
+            .collect()
+    }
+
+    /// Return `true` if `value` is one of the values of the enumeration `a`: an entity with an
+    /// ident in the enumeration's namespace.
+    pub fn is_enum_value(&self, a: &Entid, value: &Entid) -> bool {
+        match (self.get_ident(a), self.get_ident(value)) {
+            (Some(a), Some(value)) => value.starts_with(&format!(":{}/", enum_namespace(a))),
+            _ => false,
+        }
+    }
+
     /// Create a valid `Schema` from the constituent maps.
     pub fn from(ident_map: IdentMap, schema_map: SchemaMap) -> Result<Schema> {
         let entid_map: EntidMap = ident_map.iter().map(|(k, v)| (v.clone(), k.clone())).collect();
@@ -68,12 +374,97 @@ impl Schema {
         validate_schema_map(&entid_map, &schema_map)?;
 
         Ok(Schema {
-            ident_map: ident_map,
-            entid_map: entid_map,
-            schema_map: schema_map,
+            ident_map: Arc::new(ident_map),
+            entid_map: Arc::new(entid_map),
+            schema_map: Arc::new(schema_map),
         })
     }
 
+    /// Map `ident` to `entid`, and back, replacing any ident `entid` had.  The ident maps are
+    /// copied first if another schema shares them.
+    pub fn add_ident(&mut self, ident: String, entid: Entid) {
+        if let Some(old) = Arc::make_mut(&mut self.entid_map).insert(entid, ident.clone()) {
+            Arc::make_mut(&mut self.ident_map).remove(&old);
+        }
+        Arc::make_mut(&mut self.ident_map).insert(ident, entid);
+    }
+
+    /// Remove `ident`, and its mapping back from its entid.  The ident maps are copied first if
+    /// another schema shares them.
+    pub fn remove_ident(&mut self, ident: &str) {
+        if let Some(entid) = Arc::make_mut(&mut self.ident_map).remove(ident) {
+            Arc::make_mut(&mut self.entid_map).remove(&entid);
+        }
+    }
+
+    /// Set the attribute flags of `entid`, which must have an ident, and update the component types
+    /// of the composites it's a source of.  The attribute map is copied first if another schema
+    /// shares it.
+    ///
+    /// Fails, leaving the schema as it was, if the attribute isn't valid, as `Schema::from` checks,
+    /// or if a composite can't take its values.
+    pub fn set_attribute(&mut self, entid: Entid, attribute: Attribute) -> Result<()> {
+        validate_attribute(&self.entid_map, &self.schema_map, &entid, &attribute)?;
+        let composites: Vec<Entid> = self.schema_map.iter()
+            .filter(|&(&e, ref composite)| e != entid && composite.tuple_attrs.contains(&entid))
+            .map(|(&e, _)| e)
+            .collect();
+        if let Some(composite) = composites.first() {
+            if attribute.multival || attribute.encrypted || !tuple::is_component_type(&attribute.value_type) {
+                let ident = self.get_ident(composite).cloned().unwrap_or(composite.to_string());
+                bail!(ErrorKind::BadSchemaAssertion(format!(":db/tupleAttrs without :db/valueType :db.type/tuple, or with {}, which isn't a scalar cardinality one attribute, for entid: {}", entid, ident)))
+            }
+        }
+
+        let value_type = attribute.value_type.clone();
+        let schema_map = Arc::make_mut(&mut self.schema_map);
+        schema_map.insert(entid, attribute);
+        for e in composites {
+            if let Some(composite) = schema_map.get_mut(&e) {
+                for (a, component_type) in composite.tuple_attrs.iter().zip(composite.tuple_types.iter_mut()) {
+                    if *a == entid {
+                        *component_type = value_type.clone();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the attribute flags of `entid`, leaving its ident.  The attribute map is copied first
+    /// if another schema shares it.  Fails if a composite takes its values.
+    pub fn remove_attribute(&mut self, entid: Entid) -> Result<()> {
+        if let Some((&e, _)) = self.schema_map.iter().find(|&(_, composite)| composite.tuple_attrs.contains(&entid)) {
+            let ident = self.get_ident(&e).cloned().unwrap_or(e.to_string());
+            bail!(ErrorKind::BadSchemaAssertion(format!(":db/tupleAttrs with {}, which isn't an attribute, for entid: {}", entid, ident)))
+        }
+        if self.schema_map.contains_key(&entid) {
+            Arc::make_mut(&mut self.schema_map).remove(&entid);
+        }
+        Ok(())
+    }
+
+    /// Return the attribute flags of `entid` given by `assertions`, each a schema attribute and
+    /// its value, like `(:db/valueType, :db.type/string)` as entids, as the store's `schema` table
+    /// holds them.  The flags aren't validated until they're set with `set_attribute`.
+    ///
+    /// A composite's component types are the value types of its sources in this schema.
+    pub fn attribute_from_assertions<U>(&self, entid: Entid, assertions: U) -> Result<Attribute>
+        where U: IntoIterator<Item=(Entid, TypedValue)> {
+        let mut attribute = Attribute::default();
+        for (attr, ref value) in assertions.into_iter() {
+            apply_assertion(&mut attribute, &self.ident_map, entid, attr, value)?;
+        }
+        type_default(&mut attribute);
+        if !attribute.tuple_attrs.is_empty() {
+            if !attribute.tuple_types.is_empty() {
+                bail!(ErrorKind::BadSchemaAssertion(format!(":db/tupleAttrs with :db/tupleTypes for entid: {}", entid)))
+            }
+            attribute.tuple_types = composite_types(&self.schema_map, &attribute.tuple_attrs);
+        }
+        Ok(attribute)
+    }
+
     /// Turn vec![(String(:ident), String(:key), TypedValue(:value)), ...] into a Mentat `Schema`.
     pub fn from_ident_map_and_triples<U>(ident_map: IdentMap, assertions: U) -> Result<Schema>
         where U: IntoIterator<Item=(String, String, TypedValue)>{
@@ -81,84 +472,94 @@ impl Schema {
         for (ref symbolic_ident, ref symbolic_attr, ref value) in assertions.into_iter() {
             let ident: i64 = *ident_map.get(symbolic_ident).ok_or(ErrorKind::UnrecognizedIdent(symbolic_ident.clone()))?;
             let attr: i64 = *ident_map.get(symbolic_attr).ok_or(ErrorKind::UnrecognizedIdent(symbolic_attr.clone()))?;
-            let attributes = schema_map.entry(ident).or_insert(Attribute::default());
-
-            // TODO: improve error messages throughout.
-            match attr {
-                entids::DB_VALUE_TYPE => {
-                    match *value {
-                        TypedValue::Ref(entids::DB_TYPE_REF) => { attributes.value_type = ValueType::Ref; },
-                        TypedValue::Ref(entids::DB_TYPE_BOOLEAN) => { attributes.value_type = ValueType::Boolean; },
-                        TypedValue::Ref(entids::DB_TYPE_LONG) => { attributes.value_type = ValueType::Long; },
-                        TypedValue::Ref(entids::DB_TYPE_STRING) => { attributes.value_type = ValueType::String; },
-                        TypedValue::Ref(entids::DB_TYPE_KEYWORD) => { attributes.value_type = ValueType::Keyword; },
-                        _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/valueType :db.type/*] but got [... :db/valueType {:?}] for ident '{}' and attribute '{}'", value, ident, attr)))
-                    }
-                },
+            apply_assertion(schema_map.entry(ident).or_insert(Attribute::default()), &ident_map, ident, attr, value)?;
+        };
+        for attribute in schema_map.values_mut() {
+            type_default(attribute);
+        }
 
-                entids::DB_CARDINALITY => {
-                    match *value {
-                        TypedValue::Ref(entids::DB_CARDINALITY_MANY) => { attributes.multival = true; },
-                        TypedValue::Ref(entids::DB_CARDINALITY_ONE) => { attributes.multival = false; },
-                        _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/cardinality :db.cardinality/many|:db.cardinality/one] but got [... :db/cardinality {:?}]", value)))
-                    }
-                },
+        // A composite's component types are its source attributes' value types, which are only
+        // known once every attribute has been read.
+        let composites: Vec<(Entid, Vec<Entid>)> = schema_map.iter()
+            .filter(|&(_, attribute)| !attribute.tuple_attrs.is_empty())
+            .map(|(&entid, attribute)| (entid, attribute.tuple_attrs.clone()))
+            .collect();
+        for (entid, sources) in composites {
+            let tuple_types = composite_types(&schema_map, &sources);
+            if let Some(attribute) = schema_map.get_mut(&entid) {
+                if !attribute.tuple_types.is_empty() {
+                    bail!(ErrorKind::BadSchemaAssertion(format!(":db/tupleAttrs with :db/tupleTypes for entid: {}", entid)))
+                }
+                attribute.tuple_types = tuple_types;
+            }
+        }
 
-                entids::DB_UNIQUE => {
-                    match *value {
-                        TypedValue::Ref(entids::DB_UNIQUE_VALUE) => { attributes.unique_value = true; },
-                        TypedValue::Ref(entids::DB_UNIQUE_IDENTITY) => {
-                            attributes.unique_value = true;
-                            attributes.unique_identity = true;
-                        },
-                        _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/unique :db.unique/value|:db.unique/identity] but got [... :db/unique {:?}]", value)))
-                    }
-                },
+        Schema::from(ident_map.clone(), schema_map)
+    }
+}
 
-                entids::DB_INDEX => {
-                    match *value {
-                        TypedValue::Boolean(x) => { attributes.index = x },
-                        _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/index true|false] but got [... :db/index {:?}]", value)))
-                    }
-                },
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                entids::DB_FULLTEXT => {
-                    match *value {
-                        TypedValue::Boolean(x) => {
-                            attributes.fulltext = x;
-                            if attributes.fulltext {
-                                attributes.index = true;
-                            }
-                        },
-                        _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/fulltext true|false] but got [... :db/fulltext {:?}]", value)))
-                    }
-                },
+    #[test]
+    fn test_attribute_for_ident() {
+        let mut ident_map = IdentMap::new();
+        ident_map.insert(":person/name".to_string(), 100);
+        ident_map.insert(":person/age".to_string(), 101);
+        ident_map.insert(":kind/person".to_string(), 102);
+        let mut schema_map = SchemaMap::new();
+        schema_map.insert(100, Attribute { value_type: ValueType::String, ..Attribute::default() });
+        schema_map.insert(101, Attribute { value_type: ValueType::Long, ..Attribute::default() });
+        let schema = Schema::from(ident_map, schema_map).unwrap();
 
-                entids::DB_IS_COMPONENT => {
-                    match *value {
-                        TypedValue::Boolean(x) => { attributes.component = x },
-                        _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/isComponent true|false] but got [... :db/isComponent {:?}]", value)))
-                    }
-                },
+        let (attribute, entid) = schema.attribute_for_ident(&NamespacedKeyword::new("person", "name")).unwrap();
+        assert_eq!((attribute.value_type.clone(), entid), (ValueType::String, 100));
+        assert_eq!(schema.attribute_for_ident_str(":person/age").map(|(_, entid)| entid), Some(101));
 
-                entids::DB_DOC => {
-                    // Nothing for now.
-                },
+        // Idents of entities that aren't attributes, and unknown idents, name no attribute.
+        assert!(schema.attribute_for_ident_str(":kind/person").is_none());
+        assert!(schema.attribute_for_ident(&NamespacedKeyword::new("person", "email")).is_none());
+        match schema.require_attribute_for_ident_str(":kind/person") {
+            Err(Error(ErrorKind::UnrecognizedAttribute(ref ident), _)) => assert_eq!(ident, ":kind/person"),
+            x => panic!("expected UnrecognizedAttribute, got {:?}", x.map(|(_, entid)| entid)),
+        }
 
-                entids::DB_IDENT => {
-                    // Nothing for now.
-                },
+        // Attributes are sorted by ident.
+        let attributes: Vec<(&str, Entid)> = schema.attributes().into_iter().map(|(ident, entid, _)| (ident.as_str(), entid)).collect();
+        assert_eq!(attributes, vec![(":person/age", 101), (":person/name", 100)]);
+    }
 
-                entids::DB_INSTALL_ATTRIBUTE => {
-                    // Nothing for now.
-                },
+    #[test]
+    fn test_copy_on_write() {
+        let mut ident_map = IdentMap::new();
+        ident_map.insert(":person/name".to_string(), 100);
+        let mut schema_map = SchemaMap::new();
+        schema_map.insert(100, Attribute { value_type: ValueType::String, ..Attribute::default() });
+        let schema = Schema::from(ident_map, schema_map).unwrap();
 
-                _ => {
-                    bail!(ErrorKind::BadSchemaAssertion(format!("Do not recognize attribute '{}' for ident '{}'", attr, ident)))
-                }
-            }
-        };
+        // Clones share the maps.
+        let mut evolved = schema.clone();
+        assert!(Arc::ptr_eq(&schema.schema_map, &evolved.schema_map));
 
-        Schema::from(ident_map.clone(), schema_map)
+        // Changes copy only the maps they change, and leave the original alone.
+        evolved.add_ident(":person/age".to_string(), 101);
+        evolved.set_attribute(101, Attribute { value_type: ValueType::Long, ..Attribute::default() }).unwrap();
+        assert_eq!(evolved.attribute_for_ident_str(":person/age").map(|(_, entid)| entid), Some(101));
+        assert!(schema.get_entid(&":person/age".to_string()).is_none());
+        assert!(schema.attribute_for_entid(&101).is_none());
+
+        let mut renamed = evolved.clone();
+        renamed.add_ident(":person/years".to_string(), 101);
+        assert!(Arc::ptr_eq(&renamed.schema_map, &evolved.schema_map));
+        assert_eq!(renamed.get_ident(&101), Some(&":person/years".to_string()));
+        assert!(renamed.get_entid(&":person/age".to_string()).is_none());
+        assert_eq!(evolved.get_ident(&101), Some(&":person/age".to_string()));
+
+        // Invalid attributes, and attributes without idents, aren't set.
+        assert!(evolved.set_attribute(101, Attribute { value_type: ValueType::Long, fulltext: true, ..Attribute::default() }).is_err());
+        assert_eq!(evolved.attribute_for_entid(&101).map(|attribute| attribute.fulltext), Some(false));
+        assert!(evolved.set_attribute(102, Attribute { value_type: ValueType::Long, ..Attribute::default() }).is_err());
+        assert!(evolved.attribute_for_entid(&102).is_none());
     }
 }
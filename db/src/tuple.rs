@@ -0,0 +1,143 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Values of `:db.type/tuple` attributes are fixed-length sequences of scalar values, like
+//! `[:db/add 65536 :event/span [10 20]]`.  An attribute's `:db/tupleTypes`, like
+//! `[:db.type/long :db.type/long]`, fixes the length of its tuples and the type of each component.
+//!
+//! A tuple is stored as text with its own value type tag: a JSON array of `[tag, value]` pairs,
+//! one per component, like `[[5,10],[5,20]]`.  Each component is written the way SQLite would
+//! store it in the datoms table, so that queries can extract components with `json_extract`.
+//! Keywords are written in their EDN text form, booleans as 0 or 1, and doubles always with a
+//! decimal point, so that SQLite reads them back as reals.  The JSON is read and written with
+//! rustc-serialize, like `:db.type/json` values.
+//!
+//! The encoding of a tuple is canonical, so stored tuples can be compared for equality (and so
+//! made unique), but they aren't ordered meaningfully.
+
+use rustc_serialize::json::Json;
+
+use {to_namespaced_keyword};
+use types::{TypedValue, ValueType};
+
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some(x) => x, None => return None })
+}
+
+/// The `value_type_tag` of a tuple value, which is stored as text.
+pub const TUPLE_VALUE_TYPE_TAG: i32 = 16;
+
+/// Return `true` if values of type `value_type` can be components of a tuple.
+pub fn is_component_type(value_type: &ValueType) -> bool {
+    match *value_type {
+        ValueType::Ref | ValueType::Boolean | ValueType::Long | ValueType::Double | ValueType::String | ValueType::Keyword => true,
+        _ => false,
+    }
+}
+
+/// Return `true` if a tuple with the given components can be stored: none is a nested tuple,
+/// bytes, JSON, or a double that isn't finite.
+pub fn is_storable(components: &[TypedValue]) -> bool {
+    components.iter().all(|component| {
+        match component {
+            &TypedValue::Double(x) => x.into_inner().is_finite(),
+            component => is_component_type(&component.value_type()),
+        }
+    })
+}
+
+fn to_json(component: &TypedValue) -> Json {
+    let value = match component {
+        &TypedValue::Ref(x) | &TypedValue::Long(x) => Json::I64(x),
+        &TypedValue::Boolean(x) => Json::I64(if x { 1 } else { 0 }),
+        &TypedValue::Double(x) => Json::F64(x.into_inner()),
+        &TypedValue::String(ref x) | &TypedValue::Json(ref x) => Json::String(x.clone()),
+        &TypedValue::Keyword(ref x) => Json::String(x.to_string()),
+        &TypedValue::Bytes(ref x) => Json::Array(x.iter().map(|&b| Json::U64(b as u64)).collect()),
+        &TypedValue::Tuple(ref xs) => Json::Array(xs.iter().map(to_json).collect()),
+    };
+    let (_, value_type_tag) = component.to_sql_value_pair();
+    Json::Array(vec![Json::I64(value_type_tag as i64), value])
+}
+
+/// Return the stored text of a tuple with the given components.
+///
+/// Only tuples that are `is_storable` are stored; writing another fails.  Others are encoded all
+/// the same, so that they can be compared with stored tuples, which they never match: `decode`
+/// rejects their text.
+pub fn encode(components: &[TypedValue]) -> String {
+    Json::Array(components.iter().map(to_json).collect()).to_string()
+}
+
+fn from_json(component: &Json) -> Option<TypedValue> {
+    let pair = match component.as_array() {
+        Some(pair) if pair.len() == 2 => pair,
+        _ => return None,
+    };
+    let component = match (pair[0].as_i64(), &pair[1]) {
+        (Some(0), value) => TypedValue::Ref(try_opt!(value.as_i64())),
+        (Some(1), value) => TypedValue::Boolean(try_opt!(value.as_i64()) != 0),
+        (Some(5), &Json::F64(x)) => TypedValue::Double(x.into()),
+        (Some(5), value) => TypedValue::Long(try_opt!(value.as_i64())),
+        (Some(10), &Json::String(ref x)) => TypedValue::String(x.clone()),
+        (Some(13), &Json::String(ref x)) => TypedValue::Keyword(try_opt!(to_namespaced_keyword(x))),
+        _ => return None,
+    };
+    Some(component)
+}
+
+/// Return the components of a tuple stored as `text` by `encode`, or `None` if `text` isn't such a
+/// tuple.
+pub fn decode(text: &str) -> Option<Vec<TypedValue>> {
+    match Json::from_str(text) {
+        Ok(Json::Array(components)) => components.iter().map(from_json).collect(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use edn::symbols::NamespacedKeyword;
+
+    #[test]
+    fn test_round_trip() {
+        let components = vec![TypedValue::Ref(65536),
+                              TypedValue::Boolean(true),
+                              TypedValue::Long(-3),
+                              TypedValue::Double(2.0.into()),
+                              TypedValue::Double(0.25.into()),
+                              TypedValue::String("a \"quoted\"\n\u{1}ünïcode \\ string".to_string()),
+                              TypedValue::Keyword(NamespacedKeyword::new("db.type", "long"))];
+        assert!(is_storable(&components));
+        let text = encode(&components);
+        assert_eq!(&text[..30], r#"[[0,65536],[1,1],[5,-3],[5,2.0"#);
+        assert_eq!(decode(&text), Some(components));
+
+        assert_eq!(encode(&[]), "[]".to_string());
+        assert_eq!(decode("[]"), Some(vec![]));
+    }
+
+    #[test]
+    fn test_bad_tuples() {
+        assert!(!is_storable(&[TypedValue::Bytes(vec![1])]));
+        assert!(!is_storable(&[TypedValue::Double(::std::f64::NAN.into())]));
+        assert_eq!(decode(&encode(&[TypedValue::Bytes(vec![1])])), None);
+        assert_eq!(decode(&encode(&[TypedValue::Double(::std::f64::NAN.into())])), None);
+        assert_eq!(decode(&encode(&[TypedValue::String(encode(&[TypedValue::Long(1)]))])),
+                   Some(vec![TypedValue::String("[[5,1]]".to_string())]));
+
+        assert_eq!(decode("[[5,1]"), None);
+        assert_eq!(decode("[[5,1]]x"), None);
+        assert_eq!(decode("[[14,\"{}\"]]"), None);
+        assert_eq!(decode("[[13,\"nonamespace\"]]"), None);
+    }
+}
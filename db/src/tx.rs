@@ -0,0 +1,2264 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! This module implements the transaction processor: it takes a list of parsed `Entity`
+//! instances, resolves them against the current `DB`, and applies the resulting assertions and
+//! retractions to the SQL store.
+//!
+//! First, tempids that upsert are resolved.  A tempid upserts when the transaction asserts, for
+//! it, the value of a `:db.unique/identity` attribute that an existing entity holds: the tempid
+//! then names that entity.  The value may itself be a tempid, which must upsert first, so
+//! resolution repeats until no more tempids resolve; an upserted tempid also names the holder of
+//! each unique identity the transaction asserts for it.  Each round considers the assertions in
+//! order of tempid, then attribute entid, then value, so the outcome doesn't depend on the order of
+//! the transaction's entities.  A tempid whose unique identities are held by different entities is
+//! ambiguous, and fails the transaction with an `AmbiguousUpsert` error.  Tempids that don't upsert
+//! but share a unique identity are given the same fresh entid.
+//!
+//! Then the processor works entity-by-entity:
+//!
+//! 1. resolve the entity position (an entid, an ident, a lookup-ref, or a tempid) to an entid,
+//!    allocating fresh entids for new tempids;
+//! 2. resolve the attribute position to an installed attribute;
+//! 3. type-check (and in limited cases coerce) the value against the attribute's value type;
+//! 4. check that the value isn't held by another entity, if the attribute is unique, and that the
+//!    transaction doesn't assert another value, if the attribute is cardinality one;
+//! 5. write the datoms table and the transaction log, retracting the existing value of
+//!    cardinality-one attributes.
+//!
+//! Failures in steps 3 and 4 are reported as `TxConflict` errors, which carry the offending entity
+//! and the conflicting datoms.
+//!
+//! Once every entity has been applied, each entity created for a tempid gets the `:db/default` of
+//! each attribute it lacks in the namespaces of the attributes it was given, unless it was given a
+//! `:db/ident`.  Then each entity whose values of the
+//! source attributes of a composite tuple attribute changed gets its composite value derived
+//! afresh.  Defaults and composite values are checked against the constraints on their attributes
+//! like any other asserted value.
+//!
+//! Finally, we assert `:db/txInstant` for the transaction (now, unless the transaction asserted its
+//! own instant against the `"datomic.tx"` tempid), update the materialized views of the
+//! schema if any schema attributes were touched, and write back the partition map.
+//!
+//! The caller is responsible for wrapping all of this in a SQL transaction.
+
+#![allow(dead_code)]
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite;
+use rusqlite::types::{ToSqlOutput};
+
+use cipher::{ENCRYPTED_VALUE_TYPE_TAG, Cipher, decrypt_sql_value_pair, decrypt_value, encrypt_value};
+use constraints::Constraints;
+use db::{entity_with_value, insert_fulltext_value, rebuild_fulltext_table};
+use edn::symbols::NamespacedKeyword;
+use edn::types::Value;
+use entids;
+use errors::*;
+use mentat_tx::entities as entmod;
+use mentat_tx::entities::{Entity, EntidOrLookupRefOrTempId, LookupRef, ValueOrLookupRef};
+use schema::value_type_for_entid;
+use types::{Attribute, DB, Datom, Entid, PartitionMap, Schema, TypedValue, ValueType, enum_namespace, partition_end};
+
+/// A transaction report summarizes an applied transaction.
+#[derive(Clone,Debug,Default,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct TxReport {
+    /// The transaction ID of the transaction.
+    pub tx_id: Entid,
+
+    /// The timestamp when the transaction began to be committed, in milliseconds since the Unix
+    /// epoch.
+    pub tx_instant: i64,
+
+    /// A map from string literal tempid to allocated entid, or to the existing entid it upserted
+    /// to.
+    pub tempids: BTreeMap<String, Entid>,
+
+    /// `true` if the transaction changed no datoms other than its own `:db/txInstant`: every
+    /// assertion was of a datom already present, and every retraction was undone by a later
+    /// assertion (or vice versa).  Callers can roll back such a transaction rather than commit it.
+    pub noop: bool,
+
+    /// The idents the transaction created for keyword values of ref attributes, keyed by ident;
+    /// see `TxOptions::ident_namespaces`.  Callers that install attributes along with a
+    /// transaction, like a permissive `mentat::Store`, add them here too.
+    pub created_idents: BTreeMap<String, Entid>,
+}
+
+/// The tempid that refers to the transaction being applied, as in
+/// `[:db/add "datomic.tx" :db/txInstant 1483228800000]`.
+pub const TX_TEMPID: &'static str = "datomic.tx";
+
+/// Return the current time in milliseconds since the Unix epoch.
+pub fn now() -> i64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("system time after the Unix epoch");
+    (now.as_secs() as i64 * 1_000) + (now.subsec_nanos() as i64 / 1_000_000)
+}
+
+/// Return the namespace of `ident`, like `todo` for `:todo/done`, or `""` if it has none.
+fn ident_namespace(ident: &str) -> &str {
+    let ident = ident.trim_left_matches(':');
+    match ident.rfind('/') {
+        Some(i) => &ident[..i],
+        None => "",
+    }
+}
+
+/// Allocate a single fresh entid in the given `partition`.
+///
+/// Allocation only updates `partition_map`; the new high-water mark is written back to the `parts`
+/// table in the same SQL transaction as the datoms that use the entid, so a transaction that is
+/// interrupted before committing never leaves allocated entids behind.
+fn allocate_entid(partition_map: &mut PartitionMap, partition: &str) -> Result<Entid> {
+    let end = match partition_end(partition_map, partition) {
+        Some(end) => end,
+        None => bail!(ErrorKind::UnrecognizedPartition(partition.to_string())),
+    };
+
+    let p = partition_map.get_mut(partition).expect("partition to exist");
+    if p.index < p.start {
+        bail!(ErrorKind::BadPartition(format!("next entid {} of partition '{}' is below its start {}", p.index, partition, p.start)));
+    }
+    if p.index >= end {
+        bail!(ErrorKind::PartitionExhausted(partition.to_string()));
+    }
+    p.allocate_entid().ok_or(ErrorKind::PartitionExhausted(partition.to_string()).into())
+}
+
+/// What to do with a `:db/retract` of a datom that isn't present.
+#[derive(Clone,Copy,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum RetractPolicy {
+    /// Do nothing.
+    Ignore,
+
+    /// Fail the transaction with an `Absent` conflict.
+    Fail,
+}
+
+impl Default for RetractPolicy {
+    fn default() -> RetractPolicy {
+        RetractPolicy::Ignore
+    }
+}
+
+/// Hints for allocating entids to a transaction's tempids, so that they're the same from run to
+/// run, as test fixtures and repeated imports want.
+///
+/// Hints apply to tempids that don't upsert, once upserts are resolved, and before any entity is
+/// applied: first the known entids, then each run of contiguous tempids in turn.
+#[derive(Clone,Debug,Default,Eq,PartialEq)]
+pub struct TempidHints {
+    /// Tempids to give a known entid rather than a fresh one.  The entid must lie in the
+    /// `:db.part/user` partition; if it hasn't been allocated yet, it and every entid before it
+    /// are reserved.
+    pub entids: BTreeMap<String, Entid>,
+
+    /// Runs of tempids to give consecutive fresh entids, in order, whether or not the transaction
+    /// uses them.  Tempids that upsert or have a known entid are skipped.
+    pub contiguous: Vec<Vec<String>>,
+}
+
+impl TempidHints {
+    pub fn new() -> TempidHints {
+        TempidHints::default()
+    }
+
+    /// Give `tempid` the known `entid`.
+    pub fn entid(mut self, tempid: &str, entid: Entid) -> TempidHints {
+        self.entids.insert(tempid.to_string(), entid);
+        self
+    }
+
+    /// Give `tempids` consecutive fresh entids, in order.
+    pub fn contiguous(mut self, tempids: &[&str]) -> TempidHints {
+        self.contiguous.push(tempids.iter().map(|tempid| tempid.to_string()).collect());
+        self
+    }
+}
+
+/// How `transact_with_options` and `validate_with_options` apply a transaction, beyond its
+/// entities.  Build them up from `new`, like `TxOptions::new().cipher(&cipher)`; the defaults are
+/// those of `transact`.
+#[derive(Clone,Copy,Default)]
+pub struct TxOptions<'a> {
+    /// What to do with retractions of datoms that aren't present.
+    pub retract_policy: RetractPolicy,
+
+    /// The cipher for the values of `:db/encrypted` attributes.  Without one, transacting such
+    /// values fails with `MissingCipher`.
+    pub cipher: Option<&'a Cipher>,
+
+    /// The constraints on asserted values.  A value that violates one is rejected with a
+    /// `Constraint` conflict.
+    pub constraints: Option<&'a Constraints>,
+
+    /// How to allocate entids to tempids.
+    pub hints: Option<&'a TempidHints>,
+
+    /// Where a ref attribute is asserted with a keyword value, like `:task.status/blocked`, whose
+    /// namespace is one of these and which names no entity yet, create an entity with that
+    /// `:db/ident` in the same transaction.  The created idents are reported in
+    /// `TxReport::created_idents`, and count as values of the enumeration in their namespace.
+    pub ident_namespaces: Option<&'a BTreeSet<String>>,
+}
+
+impl<'a> TxOptions<'a> {
+    pub fn new() -> TxOptions<'a> {
+        TxOptions::default()
+    }
+
+    pub fn retract_policy(mut self, retract_policy: RetractPolicy) -> TxOptions<'a> {
+        self.retract_policy = retract_policy;
+        self
+    }
+
+    pub fn cipher(mut self, cipher: &'a Cipher) -> TxOptions<'a> {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    pub fn constraints(mut self, constraints: &'a Constraints) -> TxOptions<'a> {
+        self.constraints = Some(constraints);
+        self
+    }
+
+    pub fn hints(mut self, hints: &'a TempidHints) -> TxOptions<'a> {
+        self.hints = Some(hints);
+        self
+    }
+
+    pub fn ident_namespaces(mut self, ident_namespaces: &'a BTreeSet<String>) -> TxOptions<'a> {
+        self.ident_namespaces = Some(ident_namespaces);
+        self
+    }
+}
+
+/// The ways in which a transaction entity can conflict.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum ConflictKind {
+    /// The value is already held by another entity, and the attribute is `:db/unique`.
+    Unique,
+    /// The transaction asserts two different values for the same entity and cardinality-one
+    /// attribute.
+    Cardinality,
+    /// The value isn't in the attribute's value set, which is given.
+    ValueType(ValueType),
+    /// The retracted datom isn't present, and retractions are checked with `RetractPolicy::Fail`.
+    Absent,
+    /// The value violates a `Constraint` on the attribute, or isn't one of the values of an
+    /// enumeration, for the given reason.
+    Constraint(String),
+}
+
+/// A transaction entity that can't be applied, with the details needed to retry or merge.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Conflict {
+    pub kind: ConflictKind,
+
+    /// The offending entity, as given in the transaction.
+    pub entity: Entity,
+
+    /// The attribute involved.
+    pub attribute: Entid,
+
+    /// The datoms the entity conflicts with: for `Unique`, the datoms already holding the value;
+    /// for `Cardinality`, the datom asserted earlier in the same transaction, with the
+    /// transaction's ID; for `Absent`, the datoms the entity does have for the attribute.  Empty
+    /// for `ValueType` and `Constraint`.
+    pub existing: Vec<Datom>,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} conflict for attribute {} in {:?}", self.kind, self.attribute, self.entity)?;
+        if !self.existing.is_empty() {
+            write!(f, " with {:?}", self.existing)?;
+        }
+        Ok(())
+    }
+}
+
+/// A tempid that could upsert to more than one existing entity.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct Ambiguity {
+    pub tempid: String,
+
+    /// The `(e, a, v)` of each unique identity the transaction asserts for the tempid that an
+    /// existing entity `e` holds, for two different entities, in the order they were resolved.
+    pub candidates: Vec<(Entid, Entid, TypedValue)>,
+}
+
+impl fmt::Display for Ambiguity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tempid {:?} could be", self.tempid)?;
+        for (i, &(e, a, ref v)) in self.candidates.iter().enumerate() {
+            write!(f, "{} {} by [{} {:?}]", if i > 0 { " or" } else { "" }, e, a, v)?;
+        }
+        Ok(())
+    }
+}
+
+/// The value a transaction asserts for a tempid and a `:db.unique/identity` attribute.
+#[derive(Clone,Debug,Eq,Ord,PartialOrd,PartialEq)]
+enum UpsertValue {
+    Value(TypedValue),
+    /// A ref to another tempid, known only once that tempid upserts.
+    TempId(String),
+}
+
+/// An assertion by which a tempid might upsert.
+#[derive(Clone,Debug,Eq,Ord,PartialOrd,PartialEq)]
+struct Upsert {
+    tempid: String,
+    a: Entid,
+    value: UpsertValue,
+}
+
+/// Turn a type-check failure for `entity` into a `ValueType` conflict; pass other errors through.
+fn type_conflict(error: Error, entity: &Entity, a: Entid) -> Error {
+    match error {
+        Error(ErrorKind::BadEDNValuePair(_, value_type), _) => {
+            ErrorKind::TxConflict(Conflict {
+                kind: ConflictKind::ValueType(value_type),
+                entity: entity.clone(),
+                attribute: a,
+                existing: vec![],
+            }).into()
+        },
+        error => error,
+    }
+}
+
+/// A transaction on its way to being applied.
+struct Tx<'conn> {
+    /// The storage to apply against.  In the future, this will be a Mentat connection.
+    conn: &'conn rusqlite::Connection,
+
+    /// The schema to use when interpreting the transaction entities.
+    ///
+    /// Attributes installed by this transaction can't be used until the transaction commits.
+    schema: &'conn Schema,
+
+    /// The partition map, updated as entids are allocated.
+    partition_map: PartitionMap,
+
+    /// What to do with retractions of datoms that aren't present.
+    retract_policy: RetractPolicy,
+
+    /// The cipher for the values of `:db/encrypted` attributes, if any.
+    cipher: Option<&'conn Cipher>,
+
+    /// The constraints on asserted values.
+    constraints: &'conn Constraints,
+
+    /// The namespaces of the keyword values for which the transaction may create idents.
+    ident_namespaces: &'conn BTreeSet<String>,
+
+    /// The idents created so far for keyword values, keyed by ident.
+    created_idents: BTreeMap<String, Entid>,
+
+    /// The transaction ID of this transaction.
+    tx_id: Entid,
+
+    /// The timestamp of this transaction, in milliseconds since the Unix epoch.
+    ///
+    /// Defaults to now, but the transaction can assert its own `:db/txInstant`.
+    tx_instant: i64,
+
+    /// The timestamp of the latest committed transaction, if any.  Transaction instants never
+    /// decrease.
+    last_tx_instant: Option<i64>,
+
+    /// The `:db/txInstant` asserted by the transaction itself, if any.
+    asserted_tx_instant: Option<i64>,
+
+    /// Tempids allocated so far, and those that name existing entities.
+    tempids: BTreeMap<String, Entid>,
+
+    /// The tempids that name entities that existed before the transaction: those that upserted,
+    /// and those hinted to an entid already allocated.
+    existing: BTreeSet<String>,
+
+    /// Tempids that share a unique identity with another tempid, and so its entid, keyed to it.
+    aliases: BTreeMap<String, String>,
+
+    /// The values asserted so far for cardinality-one attributes, keyed by `(e, a)`.
+    asserted: BTreeMap<(Entid, Entid), TypedValue>,
+
+    /// `:db/ident` assertions (`true`) and retractions (`false`) made by this transaction.
+    idents: Vec<(Entid, String, bool)>,
+
+    /// Other schema attribute assertions (`true`) and retractions (`false`) made by this
+    /// transaction.
+    schema_changes: Vec<(Entid, Entid, TypedValue, bool)>,
+}
+
+impl<'conn> Tx<'conn> {
+    fn new(conn: &'conn rusqlite::Connection, schema: &'conn Schema, mut partition_map: PartitionMap, retract_policy: RetractPolicy, cipher: Option<&'conn Cipher>, constraints: &'conn Constraints, ident_namespaces: &'conn BTreeSet<String>) -> Result<Tx<'conn>> {
+        let tx_id = allocate_entid(&mut partition_map, ":db.part/tx")?;
+        let last_tx_instant: Option<i64> = conn.query_row("SELECT MAX(v) FROM datoms WHERE a = ?", &[&entids::DB_TX_INSTANT], |row| row.get(0))?;
+        // Even if the clock goes backwards, instants don't.
+        let tx_instant = last_tx_instant.map_or(now(), |last| cmp::max(last, now()));
+        Ok(Tx {
+            conn: conn,
+            schema: schema,
+            partition_map: partition_map,
+            retract_policy: retract_policy,
+            cipher: cipher,
+            constraints: constraints,
+            ident_namespaces: ident_namespaces,
+            created_idents: BTreeMap::new(),
+            tx_id: tx_id,
+            tx_instant: tx_instant,
+            last_tx_instant: last_tx_instant,
+            asserted_tx_instant: None,
+            tempids: BTreeMap::new(),
+            existing: BTreeSet::new(),
+            aliases: BTreeMap::new(),
+            asserted: BTreeMap::new(),
+            idents: vec![],
+            schema_changes: vec![],
+        })
+    }
+
+    fn entid_for_entid(&self, x: &entmod::Entid) -> Result<Entid> {
+        match *x {
+            entmod::Entid::Entid(e) => Ok(e),
+            entmod::Entid::Ident(ref kw) => self.schema.require_entid(&kw.to_string()).map(|&e| e),
+        }
+    }
+
+    fn attribute_for(&self, a: &entmod::Entid) -> Result<(Entid, &'conn Attribute)> {
+        let schema: &'conn Schema = self.schema;
+        let a = self.entid_for_entid(a)?;
+        let attribute = schema.require_attribute_for_entid(&a)?;
+        Ok((a, attribute))
+    }
+
+    fn resolve_lookup_ref(&self, lookup_ref: &LookupRef) -> Result<Entid> {
+        let (a, attribute) = self.attribute_for(&lookup_ref.a)?;
+        if !attribute.unique_value {
+            bail!(ErrorKind::BadLookupRef(format!("attribute {} is not :db/unique", a)));
+        }
+
+        let typed_value = self.schema.to_typed_value(&lookup_ref.v, attribute)?;
+        let (value, value_type_tag): (ToSqlOutput, i32) = typed_value.to_sql_value_pair();
+
+        let mut stmt: rusqlite::Statement = self.conn.prepare("SELECT e FROM all_datoms WHERE a = ? AND value_type_tag = ? AND v = ? LIMIT 1")?;
+        let mut rows = stmt.query(&[&a, &value_type_tag, &value])?;
+        match rows.next() {
+            Some(row) => {
+                let e: Entid = row?.get_checked(0)?;
+                Ok(e)
+            },
+            None => bail!(ErrorKind::LookupRefNotFound(format!("[{} {:?}]", a, lookup_ref.v))),
+        }
+    }
+
+    fn resolve_tempid(&mut self, tempid: &String) -> Result<Entid> {
+        if tempid == TX_TEMPID {
+            return Ok(self.tx_id);
+        }
+        if let Some(&entid) = self.tempids.get(tempid) {
+            return Ok(entid);
+        }
+        if let Some(other) = self.aliases.get(tempid).cloned() {
+            let entid = self.resolve_tempid(&other)?;
+            self.tempids.insert(tempid.clone(), entid);
+            return Ok(entid);
+        }
+
+        // TODO: allow tempids to specify a partition.
+        let entid = allocate_entid(&mut self.partition_map, ":db.part/user")?;
+        self.tempids.insert(tempid.clone(), entid);
+        Ok(entid)
+    }
+
+    /// Return the assertions of `entities` by which a tempid might upsert, in resolution order.
+    /// Assertions that can't be resolved are left for `transact_entity` to report.
+    fn upserts(&mut self, entities: &[Entity]) -> Vec<Upsert> {
+        let mut upserts = vec![];
+        for entity in entities {
+            if let Entity::Add { e: EntidOrLookupRefOrTempId::TempId(ref tempid), ref a, ref v, .. } = *entity {
+                if tempid == TX_TEMPID {
+                    continue;
+                }
+                let (a, attribute) = match self.attribute_for(a) {
+                    Ok((a, attribute)) if attribute.unique_identity => (a, attribute),
+                    _ => continue,
+                };
+                let value = match *v {
+                    ValueOrLookupRef::Value(Value::Text(ref other)) if attribute.value_type == ValueType::Ref && other != TX_TEMPID => {
+                        UpsertValue::TempId(other.clone())
+                    },
+                    _ => match self.resolve_v(attribute, v) {
+                        Ok(typed_value) => UpsertValue::Value(typed_value),
+                        Err(_) => continue,
+                    },
+                };
+                upserts.push(Upsert { tempid: tempid.clone(), a: a, value: value });
+            }
+        }
+        upserts.sort();
+        upserts.dedup();
+        upserts
+    }
+
+    /// Resolve the tempids of `entities` that upsert, and alias those that share a unique
+    /// identity; see the module documentation.
+    fn resolve_upserts(&mut self, entities: &[Entity]) -> Result<()> {
+        let upserts = self.upserts(entities);
+        if upserts.is_empty() {
+            return Ok(());
+        }
+
+        // Each upserted tempid, with the (e, a, v) it upserted by.
+        let mut resolved: BTreeMap<String, (Entid, Entid, TypedValue)> = BTreeMap::new();
+        // The unique identities that upserted tempids will hold once the transaction is applied.
+        let mut claimed: BTreeMap<(Entid, TypedValue), Entid> = BTreeMap::new();
+        loop {
+            let mut changed = false;
+            for upsert in upserts.iter() {
+                let v = match upsert.value {
+                    UpsertValue::Value(ref v) => v.clone(),
+                    UpsertValue::TempId(ref other) => match resolved.get(other) {
+                        Some(&(e, _, _)) => TypedValue::Ref(e),
+                        None => continue,
+                    },
+                };
+                let e = match claimed.get(&(upsert.a, v.clone())) {
+                    Some(&e) => e,
+                    None => match entity_with_value(self.conn, upsert.a, &v)? {
+                        Some(e) => e,
+                        None => continue,
+                    },
+                };
+                match resolved.get(&upsert.tempid).cloned() {
+                    Some((existing, _, _)) if existing == e => (),
+                    Some(first) => bail!(ErrorKind::AmbiguousUpsert(Ambiguity {
+                        tempid: upsert.tempid.clone(),
+                        candidates: vec![first, (e, upsert.a, v)],
+                    })),
+                    None => {
+                        resolved.insert(upsert.tempid.clone(), (e, upsert.a, v));
+                        changed = true;
+                    },
+                }
+            }
+            if !changed {
+                break;
+            }
+            for upsert in upserts.iter() {
+                if let (Some(&(e, _, _)), &UpsertValue::Value(ref v)) = (resolved.get(&upsert.tempid), &upsert.value) {
+                    claimed.entry((upsert.a, v.clone())).or_insert(e);
+                }
+            }
+        }
+
+        // Tempids that didn't upsert but share a unique identity name the same new entity, that of
+        // the first of them.
+        let mut first: BTreeMap<(Entid, &UpsertValue), &String> = BTreeMap::new();
+        for upsert in upserts.iter().filter(|upsert| !resolved.contains_key(&upsert.tempid)) {
+            let other = match first.get(&(upsert.a, &upsert.value)) {
+                Some(&other) => other,
+                None => {
+                    first.insert((upsert.a, &upsert.value), &upsert.tempid);
+                    continue;
+                },
+            };
+            let (x, y) = (self.alias_root(other), self.alias_root(&upsert.tempid));
+            if x != y {
+                self.aliases.insert(cmp::max(&x, &y).clone(), cmp::min(&x, &y).clone());
+            }
+        }
+
+        for (tempid, (e, _, _)) in resolved {
+            self.tempids.insert(tempid.clone(), e);
+            self.existing.insert(tempid);
+        }
+        Ok(())
+    }
+
+    /// Give the tempids of the transaction the entids `hints` ask for.
+    fn apply_hints(&mut self, hints: &TempidHints) -> Result<()> {
+        for (tempid, &entid) in hints.entids.iter() {
+            if tempid == TX_TEMPID {
+                bail!(ErrorKind::BadTempidHint(format!("the tempid {:?} names the transaction", tempid)));
+            }
+            let root = self.alias_root(tempid);
+            match self.tempids.get(&root) {
+                Some(&existing) if existing == entid => continue,
+                Some(&existing) => bail!(ErrorKind::BadTempidHint(format!("tempid {:?} is {}, not {}", tempid, existing, entid))),
+                None => (),
+            }
+            let end = partition_end(&self.partition_map, ":db.part/user").unwrap_or(i64::max_value());
+            let p = self.partition_map.get_mut(":db.part/user").expect("user partition to exist");
+            if entid < p.start || entid >= end {
+                bail!(ErrorKind::BadTempidHint(format!("entid {} for tempid {:?} isn't in :db.part/user", entid, tempid)));
+            }
+            if entid < p.index {
+                self.existing.insert(root.clone());
+            } else {
+                p.index = entid + 1;
+            }
+            self.tempids.insert(root, entid);
+        }
+
+        for run in hints.contiguous.iter() {
+            for tempid in run.iter() {
+                if tempid != TX_TEMPID && !self.tempids.contains_key(&self.alias_root(tempid)) {
+                    self.resolve_tempid(tempid)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the tempid whose entid `tempid` shares, which is `tempid` itself if it has no alias.
+    fn alias_root(&self, tempid: &String) -> String {
+        let mut root = tempid;
+        while let Some(other) = self.aliases.get(root) {
+            root = other;
+        }
+        root.clone()
+    }
+
+    fn resolve_e(&mut self, e: &EntidOrLookupRefOrTempId) -> Result<Entid> {
+        match *e {
+            EntidOrLookupRefOrTempId::Entid(ref e) => self.entid_for_entid(e),
+            EntidOrLookupRefOrTempId::LookupRef(ref lookup_ref) => self.resolve_lookup_ref(lookup_ref),
+            EntidOrLookupRefOrTempId::TempId(ref tempid) => self.resolve_tempid(tempid),
+        }
+    }
+
+    fn resolve_v(&mut self, attribute: &Attribute, v: &ValueOrLookupRef) -> Result<TypedValue> {
+        match *v {
+            ValueOrLookupRef::LookupRef(ref lookup_ref) => {
+                if attribute.value_type != ValueType::Ref {
+                    bail!(ErrorKind::BadLookupRef(format!("lookup-ref value for non-ref attribute with value type {:?}", attribute.value_type)));
+                }
+                self.resolve_lookup_ref(lookup_ref).map(TypedValue::Ref)
+            },
+            // A string value for a ref attribute is a tempid.
+            ValueOrLookupRef::Value(Value::Text(ref tempid)) if attribute.value_type == ValueType::Ref => {
+                self.resolve_tempid(tempid).map(TypedValue::Ref)
+            },
+            ValueOrLookupRef::Value(ref value) => self.schema.to_typed_value(value, attribute),
+        }
+    }
+
+    /// The ident to create for the value `v` of the ref `attribute`, if `v` is a keyword in one of
+    /// `ident_namespaces` that names no entity yet.
+    fn ident_to_create(&self, attribute: &Attribute, v: &ValueOrLookupRef) -> Option<NamespacedKeyword> {
+        match *v {
+            ValueOrLookupRef::Value(Value::NamespacedKeyword(ref ident)) if attribute.value_type == ValueType::Ref => {
+                if self.ident_namespaces.contains(&ident.namespace) && self.schema.get_entid(&ident.to_string()).is_none() {
+                    Some(ident.clone())
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// Allocate an entity with the given `ident`, unless this transaction already created it.
+    fn create_ident(&mut self, ident: NamespacedKeyword) -> Result<Entid> {
+        let name = ident.to_string();
+        if let Some(&e) = self.created_idents.get(&name) {
+            return Ok(e);
+        }
+        let schema: &'conn Schema = self.schema;
+        let attribute = schema.require_attribute_for_entid(&entids::DB_IDENT)?;
+        let e = allocate_entid(&mut self.partition_map, ":db.part/user")?;
+        self.assert(e, entids::DB_IDENT, attribute, TypedValue::Keyword(ident))?;
+        self.created_idents.insert(name, e);
+        Ok(e)
+    }
+
+    /// Type the value of a `:db/default` assertion or retraction by the value type of attribute
+    /// `e`, as asserted earlier in this transaction or already installed, and return its EDN text,
+    /// which `:db/default` holds.  If `e` has no value type yet, the value is written as given, and
+    /// the schema checks it once the transaction is applied.
+    fn resolve_default(&self, e: Entid, v: &ValueOrLookupRef) -> Result<TypedValue> {
+        let value = match *v {
+            ValueOrLookupRef::Value(ref value) => value,
+            ValueOrLookupRef::LookupRef(_) => bail!(ErrorKind::BadLookupRef("lookup-ref value for :db/default".to_string())),
+        };
+        let value_type = match self.asserted.get(&(e, entids::DB_VALUE_TYPE)) {
+            Some(&TypedValue::Ref(value_type)) => value_type_for_entid(value_type),
+            _ => self.schema.attribute_for_entid(&e).map(|attribute| attribute.value_type.clone()),
+        };
+        let text = match value_type {
+            Some(value_type) => {
+                let attribute = Attribute { value_type: value_type, ..Default::default() };
+                self.schema.to_typed_value(value, &attribute)?.to_edn_value_pair().0.to_string()
+            },
+            None => value.to_string(),
+        };
+        Ok(TypedValue::String(text))
+    }
+
+    /// Return the rowid of `text` in the fulltext values table, inserting it if `intern` is true.
+    fn fulltext_rowid(&self, text: &String, intern: bool) -> Result<Option<i64>> {
+        if intern {
+            self.conn.execute("INSERT INTO fulltext_values_view (text) VALUES (?)", &[text])?;
+        }
+
+        let mut stmt: rusqlite::Statement = self.conn.prepare("SELECT rowid FROM fulltext_values WHERE text = ? LIMIT 1")?;
+        let mut rows = stmt.query(&[text])?;
+        match rows.next() {
+            Some(row) => {
+                let rowid: i64 = row?.get_checked(0)?;
+                Ok(Some(rowid))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn require_cipher(&self) -> Result<&'conn Cipher> {
+        self.cipher.ok_or(ErrorKind::MissingCipher.into())
+    }
+
+    /// Return the ciphertext stored for the value `typed_value` of the encrypted attribute `a` of
+    /// `e`, if it has that value.
+    ///
+    /// Each encryption of a value differs, so this decrypts each of the entity's values for the
+    /// attribute in turn.
+    fn encrypted_value(&self, e: Entid, a: Entid, typed_value: &TypedValue) -> Result<Option<Vec<u8>>> {
+        let cipher = self.require_cipher()?;
+        let mut stmt: rusqlite::Statement = self.conn.prepare("SELECT v FROM datoms WHERE e = ? AND a = ? AND value_type_tag = ?")?;
+        let mut rows = stmt.query(&[&e, &a, &ENCRYPTED_VALUE_TYPE_TAG])?;
+        while let Some(row) = rows.next() {
+            let ciphertext: Vec<u8> = row?.get_checked(0)?;
+            if decrypt_value(cipher, &ciphertext)? == *typed_value {
+                return Ok(Some(ciphertext));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Return the SQL `(v, value_type_tag)` pair stored in the datoms table for `typed_value`.
+    ///
+    /// Fulltext values are stored as a rowid into the fulltext values table.  If `intern` is false
+    /// and the fulltext value is not already known, returns `None`.  Interned values are also added
+    /// to the own fulltext table of attribute `a`, if it has one.  Interning a value that can't be
+    /// stored fails; see `TypedValue::to_stored_sql_value_pair`.
+    ///
+    /// Values of encrypted attributes are encrypted afresh, so only interning makes sense for them;
+    /// use `encrypted_value` to find a stored value.
+    fn sql_value<'a>(&self, a: Entid, attribute: &Attribute, typed_value: &'a TypedValue, intern: bool) -> Result<Option<(ToSqlOutput<'a>, i32)>> {
+        if attribute.encrypted {
+            let ciphertext = encrypt_value(self.require_cipher()?, typed_value)?;
+            return Ok(Some((rusqlite::types::Value::Blob(ciphertext).into(), ENCRYPTED_VALUE_TYPE_TAG)));
+        }
+        if attribute.fulltext {
+            if let &TypedValue::String(ref text) = typed_value {
+                let rowid = self.fulltext_rowid(text, intern)?;
+                if let Some(rowid) = rowid {
+                    if intern && attribute.has_fulltext_table() {
+                        insert_fulltext_value(self.conn, a, rowid, text)?;
+                    }
+                }
+                return Ok(rowid.map(|rowid| (rusqlite::types::Value::Integer(rowid).into(), 10)));
+            }
+        }
+        if intern {
+            return Ok(Some(typed_value.to_stored_sql_value_pair()?));
+        }
+        Ok(Some(typed_value.to_sql_value_pair()))
+    }
+
+    fn datom_exists(&self, e: Entid, a: Entid, typed_value: &TypedValue) -> Result<bool> {
+        let (value, value_type_tag): (ToSqlOutput, i32) = typed_value.to_sql_value_pair();
+        let mut stmt: rusqlite::Statement = self.conn.prepare("SELECT 1 FROM all_datoms WHERE e = ? AND a = ? AND value_type_tag = ? AND v = ?")?;
+        let exists = stmt.exists(&[&e, &a, &value_type_tag, &value])?;
+        Ok(exists)
+    }
+
+    /// Return the datoms other than those of entity `e` holding `typed_value` for attribute `a`.
+    fn datoms_with_value(&self, e: Entid, a: Entid, attribute: &Attribute, typed_value: &TypedValue) -> Result<Vec<Datom>> {
+        let (value, value_type_tag) = match self.sql_value(a, attribute, typed_value, false)? {
+            Some(pair) => pair,
+            // An unknown fulltext value isn't held by any entity.
+            None => return Ok(vec![]),
+        };
+        let mut stmt: rusqlite::Statement = self.conn.prepare("SELECT e, tx FROM all_datoms WHERE a = ? AND value_type_tag = ? AND v = ? AND e != ?")?;
+        let datoms: Result<Vec<Datom>> = stmt.query_and_then(&[&a, &value_type_tag, &value, &e], |row| {
+            Ok(Datom {
+                e: row.get_checked(0)?,
+                a: a,
+                v: typed_value.clone(),
+                tx: row.get_checked(1)?,
+            })
+        })?.collect();
+        datoms
+    }
+
+    /// Fail with a `TxConflict` if asserting `typed_value` for attribute `a` of `e` would violate
+    /// uniqueness, or contradict an earlier assertion in this transaction.
+    fn check_conflicts(&self, entity: &Entity, e: Entid, a: Entid, attribute: &Attribute, typed_value: &TypedValue) -> Result<()> {
+        let conflict = |kind: ConflictKind, existing: Vec<Datom>| -> Error {
+            ErrorKind::TxConflict(Conflict {
+                kind: kind,
+                entity: entity.clone(),
+                attribute: a,
+                existing: existing,
+            }).into()
+        };
+
+        if !attribute.multival {
+            if let Some(existing) = self.asserted.get(&(e, a)) {
+                if existing != typed_value {
+                    let datom = Datom { e: e, a: a, v: existing.clone(), tx: self.tx_id };
+                    return Err(conflict(ConflictKind::Cardinality, vec![datom]));
+                }
+            }
+        }
+
+        if attribute.unique_value {
+            let existing = self.datoms_with_value(e, a, attribute, typed_value)?;
+            if !existing.is_empty() {
+                return Err(conflict(ConflictKind::Unique, existing));
+            }
+        }
+        Ok(())
+    }
+
+    fn note_schema_change(&mut self, e: Entid, a: Entid, typed_value: &TypedValue, added: bool) {
+        if a == entids::DB_IDENT {
+            if let &TypedValue::Keyword(ref ident) = typed_value {
+                self.idents.push((e, ident.to_string(), added));
+            }
+        } else if entids::is_schema_attribute(a) {
+            self.schema_changes.push((e, a, typed_value.clone(), added));
+        }
+    }
+
+    fn assert(&mut self, e: Entid, a: Entid, attribute: &Attribute, typed_value: TypedValue) -> Result<()> {
+        // Asserting an existing datom is a no-op.
+        let exists = if attribute.encrypted {
+            self.encrypted_value(e, a, &typed_value)?.is_some()
+        } else {
+            self.datom_exists(e, a, &typed_value)?
+        };
+        if exists {
+            return Ok(());
+        }
+
+        if !attribute.multival {
+            // Cardinality one: retract the existing value, if any.
+            self.conn.execute("INSERT INTO transactions (e, a, v, tx, added, value_type_tag)
+                               SELECT e, a, v, ?, 0, value_type_tag FROM datoms WHERE e = ? AND a = ?",
+                              &[&self.tx_id, &e, &a])?;
+            self.conn.execute("DELETE FROM datoms WHERE e = ? AND a = ?", &[&e, &a])?;
+        }
+
+        {
+            let (value, value_type_tag) = self.sql_value(a, attribute, &typed_value, true)?
+                .expect("interned values always have a SQL value");
+            let index_vaet = attribute.value_type == ValueType::Ref;
+
+            // TODO: prepare and cache these statements.
+            self.conn.execute("INSERT INTO datoms (e, a, v, tx, value_type_tag, index_avet, index_vaet, index_fulltext, unique_value)
+                               VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                              &[&e, &a, &value, &self.tx_id, &value_type_tag, &attribute.index, &index_vaet, &attribute.fulltext, &attribute.unique_value])?;
+            self.conn.execute("INSERT INTO transactions (e, a, v, tx, added, value_type_tag) VALUES (?, ?, ?, ?, 1, ?)",
+                              &[&e, &a, &value, &self.tx_id, &value_type_tag])?;
+        }
+
+        self.note_schema_change(e, a, &typed_value, true);
+        Ok(())
+    }
+
+    /// Retract the given datom, if it is present.  Returns `true` if it was.
+    fn retract(&mut self, e: Entid, a: Entid, attribute: &Attribute, typed_value: TypedValue) -> Result<bool> {
+        let stored: Option<(ToSqlOutput, i32)> = if attribute.encrypted {
+            self.encrypted_value(e, a, &typed_value)?
+                .map(|ciphertext| (rusqlite::types::Value::Blob(ciphertext).into(), ENCRYPTED_VALUE_TYPE_TAG))
+        } else {
+            self.sql_value(a, attribute, &typed_value, false)?
+        };
+        let retracted = match stored {
+            Some((value, value_type_tag)) => {
+                self.conn.execute("INSERT INTO transactions (e, a, v, tx, added, value_type_tag)
+                                   SELECT e, a, v, ?, 0, value_type_tag FROM datoms WHERE e = ? AND a = ? AND value_type_tag = ? AND v = ?",
+                                  &[&self.tx_id, &e, &a, &value_type_tag, &value])?;
+                self.conn.execute("DELETE FROM datoms WHERE e = ? AND a = ? AND value_type_tag = ? AND v = ?",
+                                  &[&e, &a, &value_type_tag, &value])?
+            },
+            // An unknown fulltext value can't be asserted, and an encrypted value that isn't found
+            // isn't asserted.
+            None => 0,
+        };
+
+        if retracted > 0 {
+            if self.asserted.get(&(e, a)) == Some(&typed_value) {
+                self.asserted.remove(&(e, a));
+            }
+            self.note_schema_change(e, a, &typed_value, false);
+        }
+        Ok(retracted > 0)
+    }
+
+    /// Fail with an `Absent` conflict if `entity` retracted nothing and the policy is strict.
+    fn check_retracted(&self, entity: &Entity, e: Entid, a: Entid, retracted: bool) -> Result<()> {
+        if retracted || self.retract_policy == RetractPolicy::Ignore {
+            return Ok(());
+        }
+        let mut stmt: rusqlite::Statement = self.conn.prepare("SELECT v, value_type_tag, tx FROM all_datoms WHERE e = ? AND a = ?")?;
+        let existing: Result<Vec<Datom>> = stmt.query_and_then(&[&e, &a], |row| {
+            let v: rusqlite::types::Value = row.get_checked(0)?;
+            let value_type_tag: i32 = row.get_checked(1)?;
+            Ok(Datom {
+                e: e,
+                a: a,
+                v: decrypt_sql_value_pair(v, &value_type_tag, self.cipher)?,
+                tx: row.get_checked(2)?,
+            })
+        })?.collect();
+        let existing = existing?;
+        bail!(ErrorKind::TxConflict(Conflict {
+            kind: ConflictKind::Absent,
+            entity: entity.clone(),
+            attribute: a,
+            existing: existing,
+        }))
+    }
+
+    /// Return all the `(a, v)` pairs asserted for `e`, optionally restricted to attribute `a`.
+    fn values_for(&self, e: Entid, a: Option<Entid>) -> Result<Vec<(Entid, TypedValue)>> {
+        let mut stmt: rusqlite::Statement = self.conn.prepare("SELECT a, v, value_type_tag FROM all_datoms WHERE e = ? AND (? IS NULL OR a = ?)")?;
+        let values: Result<Vec<(Entid, TypedValue)>> = stmt.query_and_then(&[&e, &a, &a], |row| {
+            let a: Entid = row.get_checked(0)?;
+            let v: rusqlite::types::Value = row.get_checked(1)?;
+            let value_type_tag: i32 = row.get_checked(2)?;
+            let typed_value = decrypt_sql_value_pair(v, &value_type_tag, self.cipher)?;
+            Ok((a, typed_value))
+        })?.collect();
+        values
+    }
+
+    /// Return the attribute `a` resolves to, failing if its values are derived by the transactor.
+    fn underived_attribute_for(&self, a: &entmod::Entid) -> Result<(Entid, &'conn Attribute)> {
+        let (a, attribute) = self.attribute_for(a)?;
+        if !attribute.tuple_attrs.is_empty() {
+            bail!(ErrorKind::DerivedAttribute(self.schema.get_ident(&a).cloned().unwrap_or(a.to_string())));
+        }
+        Ok((a, attribute))
+    }
+
+    fn transact_entity(&mut self, entity: &Entity) -> Result<()> {
+        match *entity {
+            Entity::Add { ref e, ref a, ref v, ref tx } => {
+                if tx.is_some() {
+                    bail!(ErrorKind::NotYetImplemented(format!("Transacting :db/add with explicit tx: {:?}", entity)));
+                }
+                let (a, attribute) = self.underived_attribute_for(a)?;
+                let e = self.resolve_e(e)?;
+                let typed_value = if a == entids::DB_DEFAULT {
+                    self.resolve_default(e, v)
+                } else if let Some(ident) = self.ident_to_create(attribute, v) {
+                    self.create_ident(ident).map(TypedValue::Ref)
+                } else {
+                    self.resolve_v(attribute, v)
+                }.map_err(|error| type_conflict(error, entity, a))?;
+                if a == entids::DB_TX_INSTANT {
+                    // Written by `assert_tx_instant`, once the whole transaction has been seen.
+                    return self.note_tx_instant(e, typed_value);
+                }
+                self.check_constraints(entity, a, attribute, &typed_value)?;
+                self.check_conflicts(entity, e, a, attribute, &typed_value)?;
+                self.assert(e, a, attribute, typed_value.clone())?;
+                if !attribute.multival {
+                    self.asserted.insert((e, a), typed_value);
+                }
+                Ok(())
+            },
+
+            Entity::Retract { ref e, ref a, ref v } => {
+                let (a, attribute) = self.underived_attribute_for(a)?;
+                let e = self.resolve_e(e)?;
+                let typed_value = if a == entids::DB_DEFAULT {
+                    self.resolve_default(e, v)
+                } else {
+                    self.resolve_v(attribute, v)
+                }.map_err(|error| type_conflict(error, entity, a))?;
+                let retracted = self.retract(e, a, attribute, typed_value)?;
+                self.check_retracted(entity, e, a, retracted)
+            },
+
+            Entity::RetractAttribute { ref e, ref a } => {
+                let (a, attribute) = self.underived_attribute_for(a)?;
+                let e = self.resolve_e(e)?;
+                for (_, typed_value) in self.values_for(e, Some(a))? {
+                    self.retract(e, a, attribute, typed_value)?;
+                }
+                Ok(())
+            },
+
+            Entity::RetractEntity { ref e } => {
+                // TODO: retract references to the entity, and component entities.
+                let e = self.resolve_e(e)?;
+                let schema: &'conn Schema = self.schema;
+                for (a, typed_value) in self.values_for(e, None)? {
+                    let attribute = schema.require_attribute_for_entid(&a)?;
+                    self.retract(e, a, attribute, typed_value)?;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Assert the `:db/default` of each installed attribute that an entity created by this
+    /// transaction lacks, of those in the namespaces of the attributes it was given: a new
+    /// `:todo/title` entity gets the default of `:todo/done`, but a new `:person/name` entity
+    /// doesn't.  Entities given a `:db/ident`, like new attributes and enumeration values, are
+    /// schema rather than data, and get no defaults.
+    fn assert_defaults(&mut self) -> Result<()> {
+        let schema: &'conn Schema = self.schema;
+        let defaults: Vec<(Entid, &'conn Attribute, &'conn TypedValue)> = schema.schema_map.iter()
+            .filter_map(|(&a, attribute)| attribute.default.as_ref().map(|default| (a, attribute, default)))
+            .collect();
+        if defaults.is_empty() {
+            return Ok(());
+        }
+
+        let created: BTreeSet<Entid> = self.tempids.iter()
+            .filter(|&(tempid, _)| !self.existing.contains(tempid))
+            .map(|(_, &e)| e)
+            .collect();
+        for e in created {
+            let given: BTreeSet<Entid> = {
+                let mut stmt: rusqlite::Statement = self.conn.prepare("SELECT DISTINCT a FROM datoms WHERE e = ?")?;
+                let given: Result<BTreeSet<Entid>> = stmt.query_and_then(&[&e], |row| Ok(row.get_checked(0)?))?.collect();
+                given?
+            };
+            if given.contains(&entids::DB_IDENT) {
+                continue;
+            }
+            let namespaces: BTreeSet<&str> = given.iter()
+                .filter_map(|a| schema.get_ident(a))
+                .map(|ident| ident_namespace(ident))
+                .collect();
+            for &(a, attribute, default) in defaults.iter() {
+                let owned = schema.get_ident(&a).map_or(false, |ident| namespaces.contains(ident_namespace(ident)));
+                if owned && !given.contains(&a) {
+                    let entity = Entity::Add {
+                        e: EntidOrLookupRefOrTempId::Entid(entmod::Entid::Entid(e)),
+                        a: entmod::Entid::Entid(a),
+                        v: ValueOrLookupRef::Value(default.to_edn_value_pair().0),
+                        tx: None,
+                    };
+                    self.check_constraints(&entity, a, attribute, default)?;
+                    self.assert(e, a, attribute, default.clone())?;
+                    if !attribute.multival {
+                        self.asserted.insert((e, a), default.clone());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Derive the value of each composite tuple attribute for each entity whose values of its
+    /// source attributes this transaction changed: the tuple of those values, if the entity has
+    /// them all, and otherwise none.
+    ///
+    /// Entities that already had source values when the composite was installed only get a
+    /// composite value once one of them changes.  A derived value held by another entity, for a
+    /// unique composite, is a `Unique` conflict.
+    fn assert_composites(&mut self) -> Result<()> {
+        let schema: &'conn Schema = self.schema;
+        let composites: Vec<(Entid, &'conn Attribute)> = schema.schema_map.iter()
+            .filter(|&(_, attribute)| !attribute.tuple_attrs.is_empty())
+            .map(|(&a, attribute)| (a, attribute))
+            .collect();
+
+        for (c, attribute) in composites {
+            let changed: BTreeSet<Entid> = {
+                let sources: Vec<String> = attribute.tuple_attrs.iter().map(|a| a.to_string()).collect();
+                let mut stmt: rusqlite::Statement = self.conn.prepare(&format!("SELECT DISTINCT e FROM transactions WHERE tx = ? AND a IN ({})", sources.join(", ")))?;
+                let changed: Result<BTreeSet<Entid>> = stmt.query_and_then(&[&self.tx_id], |row| Ok(row.get_checked(0)?))?.collect();
+                changed?
+            };
+            for e in changed {
+                let mut components = Vec::with_capacity(attribute.tuple_attrs.len());
+                for &a in attribute.tuple_attrs.iter() {
+                    if let Some((_, value)) = self.values_for(e, Some(a))?.into_iter().next() {
+                        components.push(value);
+                    }
+                }
+
+                if components.len() < attribute.tuple_attrs.len() {
+                    for (_, existing) in self.values_for(e, Some(c))? {
+                        self.retract(e, c, attribute, existing)?;
+                    }
+                    continue;
+                }
+
+                let typed_value = TypedValue::Tuple(components);
+                let entity = Entity::Add {
+                    e: EntidOrLookupRefOrTempId::Entid(entmod::Entid::Entid(e)),
+                    a: entmod::Entid::Entid(c),
+                    v: ValueOrLookupRef::Value(typed_value.to_edn_value_pair().0),
+                    tx: None,
+                };
+                self.check_constraints(&entity, c, attribute, &typed_value)?;
+                self.check_conflicts(&entity, e, c, attribute, &typed_value)?;
+                self.assert(e, c, attribute, typed_value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail with a `Constraint` conflict for `entity` if `typed_value` can't be asserted for
+    /// attribute `a`.
+    fn check_constraints(&self, entity: &Entity, a: Entid, attribute: &Attribute, typed_value: &TypedValue) -> Result<()> {
+        match self.violation(a, attribute, typed_value) {
+            Some(reason) => bail!(ErrorKind::TxConflict(Conflict {
+                kind: ConflictKind::Constraint(reason),
+                entity: entity.clone(),
+                attribute: a,
+                existing: vec![],
+            })),
+            None => Ok(()),
+        }
+    }
+
+    /// Return why `typed_value` can't be asserted for attribute `a`, if it isn't one of the values
+    /// of an enumeration or violates a constraint.
+    fn violation(&self, a: Entid, attribute: &Attribute, typed_value: &TypedValue) -> Option<String> {
+        if attribute.enumerated {
+            if let &TypedValue::Ref(value) = typed_value {
+                if !self.schema.is_enum_value(&a, &value) && !self.is_created_enum_value(a, value) {
+                    return Some(format!("{} isn't a value of the enumeration", value));
+                }
+            }
+        }
+        self.constraints.check(self.schema, a, typed_value)
+    }
+
+    /// Return `true` if `value` is an ident this transaction created in the namespace of the
+    /// enumeration `a`.
+    fn is_created_enum_value(&self, a: Entid, value: Entid) -> bool {
+        let prefix = match self.schema.get_ident(&a) {
+            Some(a) => format!(":{}/", enum_namespace(a)),
+            None => return false,
+        };
+        self.created_idents.iter().any(|(ident, &e)| e == value && ident.starts_with(&prefix))
+    }
+
+    /// Record an explicit `:db/txInstant` assertion, like `[:db/add "datomic.tx" :db/txInstant t]`.
+    ///
+    /// Only the transaction being applied can be given an instant, and only one.
+    fn note_tx_instant(&mut self, e: Entid, typed_value: TypedValue) -> Result<()> {
+        if e != self.tx_id {
+            bail!(ErrorKind::BadTxInstant(format!("can't assert :db/txInstant for {}, which isn't the current transaction", e)));
+        }
+        let instant = match typed_value {
+            TypedValue::Long(instant) => instant,
+            _ => bail!(ErrorKind::BadTxInstant(format!("expected a long, got {:?}", typed_value))),
+        };
+        match self.asserted_tx_instant {
+            Some(existing) if existing != instant => {
+                bail!(ErrorKind::BadTxInstant(format!("conflicting instants {} and {}", existing, instant)));
+            },
+            _ => (),
+        }
+        self.asserted_tx_instant = Some(instant);
+        Ok(())
+    }
+
+    fn assert_tx_instant(&mut self) -> Result<()> {
+        if let Some(instant) = self.asserted_tx_instant {
+            match self.last_tx_instant {
+                Some(last) if instant < last => {
+                    bail!(ErrorKind::BadTxInstant(format!("{} is before the previous transaction's instant {}", instant, last)));
+                },
+                _ => (),
+            }
+            self.tx_instant = instant;
+        }
+
+        let schema: &'conn Schema = self.schema;
+        let attribute = schema.require_attribute_for_entid(&entids::DB_TX_INSTANT)?;
+        let (tx_id, tx_instant) = (self.tx_id, self.tx_instant);
+        self.assert(tx_id, entids::DB_TX_INSTANT, attribute, TypedValue::Long(tx_instant))
+    }
+
+    fn ident_for_entid(&self, e: Entid) -> Result<Option<String>> {
+        let mut stmt: rusqlite::Statement = self.conn.prepare("SELECT ident FROM idents WHERE entid = ?")?;
+        let mut rows = stmt.query(&[&e])?;
+        match rows.next() {
+            Some(row) => {
+                let ident: String = row?.get_checked(0)?;
+                Ok(Some(ident))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Update the `idents` and `schema` materialized views to reflect the schema changes made by
+    /// this transaction.  Returns `true` if the materialized views changed.
+    fn update_materialized_views(&self) -> Result<bool> {
+        for &(e, ref ident, added) in self.idents.iter() {
+            if added {
+                // :db/ident is cardinality one: renaming an entity renames its schema entries.
+                if let Some(old_ident) = self.ident_for_entid(e)? {
+                    self.conn.execute("DELETE FROM idents WHERE ident = ?", &[&old_ident])?;
+                    self.conn.execute("UPDATE schema SET ident = ? WHERE ident = ?", &[ident, &old_ident])?;
+                }
+                self.conn.execute("INSERT INTO idents VALUES (?, ?)", &[ident, &e])?;
+            } else {
+                self.conn.execute("DELETE FROM idents WHERE ident = ? AND entid = ?", &[ident, &e])?;
+            }
+        }
+
+        for &(e, a, ref typed_value, added) in self.schema_changes.iter() {
+            let ident = match self.ident_for_entid(e)? {
+                Some(ident) => ident,
+                None => bail!(ErrorKind::BadSchemaAssertion(format!("Schema attribute asserted for entid {} without :db/ident", e))),
+            };
+            let attr = self.schema.require_ident(&a)?;
+            let (value, value_type_tag): (ToSqlOutput, i32) = typed_value.to_sql_value_pair();
+
+            // Values already written, and logged, were written as they were.
+            if a == entids::DB_ENCRYPTED || a == entids::DB_TUPLE_TYPES || a == entids::DB_TUPLE_ATTRS {
+                let logged: bool = self.conn.prepare("SELECT 1 FROM transactions WHERE a = ?")?.exists(&[&e])?;
+                if logged {
+                    bail!(ErrorKind::BadSchemaAssertion(format!("Can't change {} for {}, which already has values", attr, ident)));
+                }
+            }
+
+            if added {
+                // All schema attributes but :db.fulltext/prefix are cardinality one.
+                if a != entids::DB_FULLTEXT_PREFIX {
+                    self.conn.execute("DELETE FROM schema WHERE ident = ? AND attr = ?", &[&ident, attr])?;
+                }
+                self.conn.execute("INSERT INTO schema VALUES (?, ?, ?, ?)", &[&ident, attr, &value, &value_type_tag])?;
+            } else {
+                self.conn.execute("DELETE FROM schema WHERE ident = ? AND attr = ? AND value = ? AND value_type_tag = ?",
+                                  &[&ident, attr, &value, &value_type_tag])?;
+            }
+        }
+
+        Ok(!self.idents.is_empty() || !self.schema_changes.is_empty())
+    }
+
+    /// Return the schema as this transaction left it: `self.schema`, with the idents and attributes
+    /// the transaction changed.  Only the maps that changed are copied.
+    ///
+    /// Each changed attribute is read back from the `schema` materialized view, which
+    /// `update_materialized_views` must have written.
+    fn evolve_schema(&self) -> Result<Schema> {
+        let mut schema = self.schema.clone();
+        for &(e, ref ident, added) in self.idents.iter() {
+            if added {
+                schema.add_ident(ident.clone(), e);
+            } else if schema.attribute_for_entid(&e).is_some() {
+                bail!(ErrorKind::BadSchemaAssertion(format!("Can't retract the ident {} of an attribute", ident)));
+            } else {
+                schema.remove_ident(ident);
+            }
+        }
+
+        let changed: BTreeSet<Entid> = self.schema_changes.iter().map(|&(e, _, _, _)| e).collect();
+        let mut attributes: Vec<(Entid, Vec<(Entid, TypedValue)>)> = vec![];
+        for e in changed {
+            let ident = schema.require_ident(&e)?.clone();
+            let mut stmt = self.conn.prepare("SELECT attr, value, value_type_tag FROM schema WHERE ident = ?")?;
+            let assertions: Result<Vec<(Entid, TypedValue)>> = stmt.query_and_then(&[&ident], |row| {
+                let attr: String = row.get_checked(0)?;
+                let v: rusqlite::types::Value = row.get_checked(1)?;
+                let value_type_tag: i32 = row.get_checked(2)?;
+                Ok((*schema.require_entid(&attr)?, TypedValue::from_sql_value_pair(v, &value_type_tag)?))
+            })?.collect();
+            attributes.push((e, assertions?));
+        }
+
+        // Composites take their component types from their sources, so they're set last.
+        attributes.sort_by_key(|&(_, ref assertions)| assertions.iter().any(|&(a, _)| a == entids::DB_TUPLE_ATTRS));
+        for (e, assertions) in attributes {
+            if assertions.is_empty() {
+                schema.remove_attribute(e)?;
+            } else {
+                let attribute = schema.attribute_from_assertions(e, assertions)?;
+                schema.set_attribute(e, attribute)?;
+            }
+        }
+        Ok(schema)
+    }
+
+    /// Return the attributes whose fulltext configuration this transaction changed.
+    fn fulltext_changes(&self) -> BTreeSet<Entid> {
+        self.schema_changes.iter()
+            .filter(|&&(_, a, _, _)| a == entids::DB_FULLTEXT || a == entids::DB_FULLTEXT_TOKENIZER || a == entids::DB_FULLTEXT_PREFIX)
+            .map(|&(e, _, _, _)| e)
+            .collect()
+    }
+
+    /// Return `true` if this transaction has written no net change to any datom, ignoring its own
+    /// `:db/txInstant`.
+    ///
+    /// A retraction followed by an assertion of the same datom writes two rows to `transactions`
+    /// that cancel out, as does an assertion followed by a retraction.
+    fn is_noop(&self) -> Result<bool> {
+        let changed: i64 = self.conn.query_row("SELECT COUNT(*) FROM
+                                                (SELECT SUM(CASE WHEN added THEN 1 ELSE -1 END) AS net FROM transactions
+                                                 WHERE tx = ? AND NOT (e = ? AND a = ?)
+                                                 GROUP BY e, a, v, value_type_tag)
+                                                WHERE net != 0",
+                                               &[&self.tx_id, &self.tx_id, &entids::DB_TX_INSTANT],
+                                               |row| row.get(0))?;
+        Ok(changed == 0)
+    }
+
+    /// Write the partition map back to the `parts` materialized view.
+    fn update_partition_map(&self) -> Result<()> {
+        for (part, partition) in self.partition_map.iter() {
+            self.conn.execute("UPDATE parts SET idx = ? WHERE part = ?", &[&partition.index, part])?;
+        }
+        Ok(())
+    }
+}
+
+/// Transact the given `entities` against the given SQLite `conn`, using the metadata in `db`.
+///
+/// This approach is explained in https://github.com/mozilla/mentat/wiki/Transacting.
+///
+/// Returns a report summarizing the transaction, and the `DB` reflecting any changes to the
+/// partition map and the schema.  The caller should commit or roll back the underlying SQL
+/// transaction as appropriate; a transaction reported as a no-op can be rolled back without losing
+/// anything but its `:db/txInstant`.
+pub fn transact(conn: &rusqlite::Connection, db: &DB, entities: &[Entity]) -> Result<(TxReport, DB)> {
+    transact_with_options(conn, db, entities, &TxOptions::default())
+}
+
+/// Like `transact`, but apply the transaction as `options` ask.
+pub fn transact_with_options(conn: &rusqlite::Connection, db: &DB, entities: &[Entity], options: &TxOptions) -> Result<(TxReport, DB)> {
+    let no_constraints = Constraints::default();
+    let no_ident_namespaces = BTreeSet::new();
+    let mut tx = Tx::new(conn, &db.schema, db.partition_map.clone(), options.retract_policy, options.cipher,
+                         options.constraints.unwrap_or(&no_constraints),
+                         options.ident_namespaces.unwrap_or(&no_ident_namespaces))?;
+
+    tx.resolve_upserts(entities)?;
+    if let Some(hints) = options.hints {
+        tx.apply_hints(hints)?;
+    }
+    for entity in entities {
+        tx.transact_entity(entity)?;
+    }
+
+    tx.assert_defaults()?;
+    tx.assert_composites()?;
+    tx.assert_tx_instant()?;
+    let noop = tx.is_noop()?;
+    tx.update_materialized_views()?;
+    tx.update_partition_map()?;
+    let schema = tx.evolve_schema()?;
+
+    for e in tx.fulltext_changes() {
+        rebuild_fulltext_table(conn, e, schema.attribute_for_entid(&e))?;
+    }
+
+    let report = TxReport {
+        tx_id: tx.tx_id,
+        tx_instant: tx.tx_instant,
+        tempids: tx.tempids,
+        noop: noop,
+        created_idents: tx.created_idents,
+    };
+    Ok((report, DB::new(tx.partition_map, schema)))
+}
+
+/// A problem with a transaction, found by `validate`.
+#[derive(Debug)]
+pub struct ValidationError {
+    /// The index of the offending entity, or `None` for a problem with the transaction as a whole,
+    /// like a schema attribute asserted for an entity without a `:db/ident`.
+    pub entity: Option<usize>,
+    pub error: Error,
+}
+
+/// Check the given `entities` as `transact` would, against the given SQLite `conn` and the metadata
+/// in `db`, without changing the store.
+///
+/// Entities are applied in order, so later entities see the effects of earlier ones.  An entity
+/// that fails is rolled back and reported, and checking continues with the next.  Returns every
+/// problem found; if there are none, `transact` would succeed.
+///
+/// Everything is applied within a SQL savepoint that is always rolled back.  Fails only if the store
+/// can't be read, or the savepoint can't be managed.
+pub fn validate(conn: &rusqlite::Connection, db: &DB, entities: &[Entity]) -> Result<Vec<ValidationError>> {
+    validate_with_options(conn, db, entities, &TxOptions::default())
+}
+
+/// Like `validate`, but check the transaction as `transact_with_options` would apply it with
+/// `options`.
+pub fn validate_with_options(conn: &rusqlite::Connection, db: &DB, entities: &[Entity], options: &TxOptions) -> Result<Vec<ValidationError>> {
+    conn.execute("SAVEPOINT validate", &[])?;
+    let problems = validate_entities(conn, db, entities, options);
+    conn.execute("ROLLBACK TO validate", &[])?;
+    conn.execute("RELEASE validate", &[])?;
+    problems
+}
+
+fn validate_entities(conn: &rusqlite::Connection, db: &DB, entities: &[Entity], options: &TxOptions) -> Result<Vec<ValidationError>> {
+    let no_constraints = Constraints::default();
+    let no_ident_namespaces = BTreeSet::new();
+    let mut tx = Tx::new(conn, &db.schema, db.partition_map.clone(), options.retract_policy, options.cipher,
+                         options.constraints.unwrap_or(&no_constraints),
+                         options.ident_namespaces.unwrap_or(&no_ident_namespaces))?;
+    let mut problems = vec![];
+
+    if let Err(error) = tx.resolve_upserts(entities) {
+        problems.push(ValidationError { entity: None, error: error });
+    }
+    if let Some(hints) = options.hints {
+        if let Err(error) = tx.apply_hints(hints) {
+            problems.push(ValidationError { entity: None, error: error });
+        }
+    }
+    for (i, entity) in entities.iter().enumerate() {
+        conn.execute("SAVEPOINT validate_entity", &[])?;
+        if let Err(error) = tx.transact_entity(entity) {
+            conn.execute("ROLLBACK TO validate_entity", &[])?;
+            problems.push(ValidationError { entity: Some(i), error: error });
+        }
+        conn.execute("RELEASE validate_entity", &[])?;
+    }
+
+    if let Err(error) = validate_schema(conn, &mut tx) {
+        problems.push(ValidationError { entity: None, error: error });
+    }
+    Ok(problems)
+}
+
+/// Finish applying `tx` and check that the resulting schema is valid.
+fn validate_schema(conn: &rusqlite::Connection, tx: &mut Tx) -> Result<()> {
+    tx.assert_defaults()?;
+    tx.assert_composites()?;
+    tx.assert_tx_instant()?;
+    tx.update_materialized_views()?;
+    tx.evolve_schema()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bootstrap;
+    use cipher::XorCipher;
+    use constraints::Constraint;
+    use db;
+    use edn;
+    use mentat_tx_parser::Tx as TxParser;
+
+    fn transact_str(conn: &rusqlite::Connection, db: &DB, input: &str) -> Result<(TxReport, DB)> {
+        let value = edn::parse::value(input).expect("to parse EDN");
+        let entities = TxParser::parse(&[value][..]).expect("to parse transaction");
+        transact(conn, db, &entities[..])
+    }
+
+    #[test]
+    fn test_transact_schema_and_data() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "n" :db/ident :test/name]
+                                                        [:db/add "n" :db/valueType :db.type/string]
+                                                        [:db/add "n" :db/unique :db.unique/identity]]"#).unwrap();
+        let name = report.tempids["n"];
+        assert_eq!(db.schema.get_entid(&":test/name".to_string()), Some(&name));
+        assert_eq!(db.schema.require_attribute_for_entid(&name).unwrap().value_type, ValueType::String);
+        assert_eq!(db::read_db(&conn).unwrap(), db);
+
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "a" :test/name "Alice"]
+                                                        [:db/add "b" :test/name "Bob"]]"#).unwrap();
+        assert_eq!(report.tempids.len(), 2);
+        assert!(report.tempids["a"] != report.tempids["b"]);
+
+        // Cardinality one: asserting a new value replaces the old.
+        let alice = report.tempids["a"];
+        let (_, db) = transact_str(&conn, &db, &format!("[[:db/add {} :test/name \"Alicia\"]]", alice)).unwrap();
+        let (_, db) = transact_str(&conn, &db, "[[:db/retract [:test/name \"Bob\"] :test/name \"Bob\"]]").unwrap();
+
+        let names: Vec<String> = conn.prepare("SELECT v FROM datoms WHERE a = ? ORDER BY v").unwrap()
+            .query_map(&[&name], |row| row.get(0)).unwrap()
+            .map(|x| x.unwrap())
+            .collect();
+        assert_eq!(names, vec!["Alicia".to_string()]);
+
+        // The partition map is written back.
+        assert_eq!(db::read_db(&conn).unwrap().partition_map, db.partition_map);
+    }
+
+    #[test]
+    fn test_partition_bounds() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+
+        assert_eq!(partition_end(&db.partition_map, ":db.part/db"), Some(0x10000));
+        assert_eq!(partition_end(&db.partition_map, ":db.part/user"), Some(bootstrap::TX0));
+        assert_eq!(partition_end(&db.partition_map, ":db.part/tx"), Some(i64::max_value()));
+
+        // The last entid of a partition can be allocated, but no more.
+        let mut last = db.clone();
+        last.partition_map.get_mut(":db.part/user").unwrap().index = bootstrap::TX0 - 1;
+        let (report, last) = transact_str(&conn, &last, r#"[[:db/add "a" :db/doc "last"]]"#).unwrap();
+        assert_eq!(report.tempids["a"], bootstrap::TX0 - 1);
+        match transact_str(&conn, &last, r#"[[:db/add "b" :db/doc "too many"]]"#) {
+            Err(Error(ErrorKind::PartitionExhausted(ref p), _)) => assert_eq!(p, ":db.part/user"),
+            x => panic!("expected PartitionExhausted, got {:?}", x),
+        }
+
+        // The tx partition runs to the end of the entid space, which never overflows.
+        let mut end = db.clone();
+        end.partition_map.get_mut(":db.part/tx").unwrap().index = i64::max_value() - 1;
+        let (report, end) = transact_str(&conn, &end, r#"[[:db/add "a" :db/doc "end"]]"#).unwrap();
+        assert_eq!(report.tx_id, i64::max_value() - 1);
+        match transact_str(&conn, &end, r#"[[:db/add "b" :db/doc "past the end"]]"#) {
+            Err(Error(ErrorKind::PartitionExhausted(ref p), _)) => assert_eq!(p, ":db.part/tx"),
+            x => panic!("expected PartitionExhausted, got {:?}", x),
+        }
+
+        let mut below = db.clone();
+        below.partition_map.get_mut(":db.part/user").unwrap().index = 0x10000 - 1;
+        match transact_str(&conn, &below, r#"[[:db/add "a" :db/doc "below"]]"#) {
+            Err(Error(ErrorKind::BadPartition(_), _)) => (),
+            x => panic!("expected BadPartition, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_interrupted_transaction() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+
+        // Allocate entids, then stop before committing.
+        let allocated = {
+            let tx = conn.transaction().unwrap();
+            let (report, _) = transact_str(&tx, &db, r#"[[:db/add "a" :db/doc "lost"]]"#).unwrap();
+            report.tempids["a"]
+        };
+
+        // Neither the datoms nor the high-water mark survive.
+        assert_eq!(db::read_db(&conn).unwrap(), db);
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM datoms WHERE e = ?", &[&allocated], |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+
+        // The next transaction reuses the entid, and commits the high-water mark with its datoms.
+        let (report, db) = {
+            let tx = conn.transaction().unwrap();
+            let result = transact_str(&tx, &db, r#"[[:db/add "a" :db/doc "kept"]]"#).unwrap();
+            tx.commit().unwrap();
+            result
+        };
+        assert_eq!(report.tempids["a"], allocated);
+        assert_eq!(db::read_db(&conn).unwrap(), db);
+        assert_eq!(db.partition_map[":db.part/user"].index, allocated + 1);
+    }
+
+    #[test]
+    fn test_explicit_tx_instant() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+
+        // Importing historical data.
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "a" :db/doc "old"]
+                                                        [:db/add "datomic.tx" :db/txInstant 1000]]"#).unwrap();
+        assert_eq!(report.tx_instant, 1000);
+        assert!(!report.tempids.contains_key(TX_TEMPID));
+        let instant: i64 = conn.query_row("SELECT v FROM datoms WHERE e = ? AND a = ?", &[&report.tx_id, &entids::DB_TX_INSTANT], |row| row.get(0)).unwrap();
+        assert_eq!(instant, 1000);
+
+        // Instants can repeat, but not go backwards.
+        let (_, db) = transact_str(&conn, &db, r#"[[:db/add "datomic.tx" :db/txInstant 1000]]"#).unwrap();
+        match transact_str(&conn, &db, r#"[[:db/add "datomic.tx" :db/txInstant 999]]"#) {
+            Err(Error(ErrorKind::BadTxInstant(_), _)) => (),
+            x => panic!("expected BadTxInstant, got {:?}", x),
+        }
+
+        // Only the current transaction, and only one instant.
+        assert!(transact_str(&conn, &db, &format!("[[:db/add {} :db/txInstant 2000]]", report.tx_id)).is_err());
+        assert!(transact_str(&conn, &db, r#"[[:db/add "datomic.tx" :db/txInstant 2000]
+                                             [:db/add "datomic.tx" :db/txInstant 3000]]"#).is_err());
+
+        // Otherwise, now.
+        let before = now();
+        let (report, _) = transact_str(&conn, &db, r#"[[:db/add "a" :db/doc "new"]]"#).unwrap();
+        assert!(report.tx_instant >= before);
+    }
+
+    #[test]
+    fn test_lookup_ref_not_found() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+
+        match transact_str(&conn, &db, "[[:db/add [:db/ident :test/missing] :db/doc \"x\"]]") {
+            Err(Error(ErrorKind::LookupRefNotFound(_), _)) => (),
+            x => panic!("expected LookupRefNotFound, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_noop() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "n" :db/ident :test/name]
+                                                        [:db/add "n" :db/valueType :db.type/string]
+                                                        [:db/add "a" :test/name "Alice"]]"#).unwrap();
+        assert!(!report.noop);
+        let alice = report.tempids["a"];
+
+        // Asserting datoms that are already present.
+        let (report, db) = transact_str(&conn, &db, &format!("[[:db/add {} :test/name \"Alice\"]]", alice)).unwrap();
+        assert!(report.noop);
+
+        // Retracting, then asserting, the same datom.
+        let (report, db) = transact_str(&conn, &db, &format!("[[:db/retract {} :test/name \"Alice\"]
+                                                                [:db/add {} :test/name \"Alice\"]]", alice, alice)).unwrap();
+        assert!(report.noop);
+
+        // Asserting, then retracting, a new datom.
+        let (report, db) = transact_str(&conn, &db, &format!("[[:db/add {} :db/doc \"x\"]
+                                                                [:db/retract {} :db/doc \"x\"]]", alice, alice)).unwrap();
+        assert!(report.noop);
+
+        // An explicit instant alone changes nothing else.
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "datomic.tx" :db/txInstant 1000000000000000]]"#).unwrap();
+        assert!(report.noop);
+
+        let (report, _) = transact_str(&conn, &db, &format!("[[:db/add {} :test/name \"Alicia\"]]", alice)).unwrap();
+        assert!(!report.noop);
+    }
+
+    #[test]
+    fn test_retract_attribute() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "t" :db/ident :test/tag]
+                                                        [:db/add "t" :db/valueType :db.type/string]
+                                                        [:db/add "t" :db/cardinality :db.cardinality/many]
+                                                        [:db/add "a" :test/tag "x"]
+                                                        [:db/add "a" :test/tag "y"]
+                                                        [:db/add "a" :db/doc "kept"]]"#).unwrap();
+        let (tag, a) = (report.tempids["t"], report.tempids["a"]);
+
+        // Every value is retracted in one term, without reading them first.
+        let (report, _) = transact_str(&conn, &db, &format!("[[:db.fn/retractAttribute {} :test/tag]]", a)).unwrap();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM datoms WHERE e = ? AND a = ?", &[&a, &tag], |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+        let retracted: i64 = conn.query_row("SELECT COUNT(*) FROM transactions WHERE tx = ? AND a = ? AND added = 0", &[&report.tx_id, &tag], |row| row.get(0)).unwrap();
+        assert_eq!(retracted, 2);
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM datoms WHERE e = ? AND a = ?", &[&a, &entids::DB_DOC], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_retract_policy() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "a" :db/doc "x"]]"#).unwrap();
+        let a = report.tempids["a"];
+
+        let transact_strict = |input: &str| -> Result<(TxReport, DB)> {
+            let value = edn::parse::value(input).expect("to parse EDN");
+            let entities = TxParser::parse(&[value][..]).expect("to parse transaction");
+            transact_with_options(&conn, &db, &entities[..], &TxOptions::new().retract_policy(RetractPolicy::Fail))
+        };
+
+        // The conflict identifies the phantom retraction, and what is present instead.
+        match transact_strict(&format!("[[:db/retract {} :db/doc \"y\"]]", a)) {
+            Err(Error(ErrorKind::TxConflict(conflict), _)) => {
+                assert_eq!(conflict.kind, ConflictKind::Absent);
+                assert_eq!(conflict.attribute, entids::DB_DOC);
+                assert_eq!(conflict.existing, vec![Datom { e: a, a: entids::DB_DOC, v: TypedValue::String("x".to_string()), tx: report.tx_id }]);
+            },
+            x => panic!("expected TxConflict, got {:?}", x),
+        }
+
+        // Retracting what was asserted earlier in the same transaction is fine.
+        assert!(transact_strict(&format!("[[:db/add {} :db/doc \"z\"] [:db/retract {} :db/doc \"z\"]]", a, a)).is_ok());
+
+        // By default, phantom retractions are ignored.
+        assert!(transact_str(&conn, &db, &format!("[[:db/retract {} :db/doc \"y\"]]", a)).is_ok());
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "n" :db/ident :test/name]
+                                                        [:db/add "n" :db/valueType :db.type/string]
+                                                        [:db/add "n" :db/unique :db.unique/value]
+                                                        [:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let name = report.tempids["n"];
+        let alice = report.tempids["a"];
+
+        // Each attempt is rolled back.
+        let mut try_str = |input: &str| -> Result<(TxReport, DB)> {
+            let tx = conn.transaction().unwrap();
+            transact_str(&tx, &db, input)
+        };
+        fn conflict(result: Result<(TxReport, DB)>) -> Conflict {
+            match result {
+                Err(Error(ErrorKind::TxConflict(conflict), _)) => conflict,
+                x => panic!("expected TxConflict, got {:?}", x),
+            }
+        }
+
+        // Unique: the existing datom is reported.
+        let c = conflict(try_str(r#"[[:db/add "b" :test/name "Alice"]]"#));
+        assert_eq!(c.kind, ConflictKind::Unique);
+        assert_eq!(c.attribute, name);
+        assert_eq!(c.existing, vec![Datom { e: alice, a: name, v: TypedValue::String("Alice".to_string()), tx: report.tx_id }]);
+        match c.entity {
+            Entity::Add { ref e, .. } => assert_eq!(e, &EntidOrLookupRefOrTempId::TempId("b".to_string())),
+            ref x => panic!("expected the offending :db/add, got {:?}", x),
+        }
+
+        // Re-asserting an entity's own value is fine.
+        assert!(try_str(&format!("[[:db/add {} :test/name \"Alice\"]]", alice)).is_ok());
+
+        // Cardinality: the earlier assertion in the same transaction is reported.
+        let c = conflict(try_str(&format!("[[:db/add {} :test/name \"Ann\"] [:db/add {} :test/name \"Anne\"]]", alice, alice)));
+        assert_eq!(c.kind, ConflictKind::Cardinality);
+        assert_eq!(c.existing.len(), 1);
+        assert_eq!(c.existing[0].v, TypedValue::String("Ann".to_string()));
+
+        // Type: the expected value type is reported.
+        let c = conflict(try_str(r#"[[:db/add "b" :test/name 1]]"#));
+        assert_eq!(c.kind, ConflictKind::ValueType(ValueType::String));
+        assert!(c.existing.is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        let (_, db) = transact_str(&conn, &db, r#"[[:db/add "n" :db/ident :test/name]
+                                                   [:db/add "n" :db/valueType :db.type/string]
+                                                   [:db/add "n" :db/unique :db.unique/value]
+                                                   [:db/add "a" :test/name "Alice"]]"#).unwrap();
+
+        let validate_str = |input: &str| -> Vec<ValidationError> {
+            let value = edn::parse::value(input).expect("to parse EDN");
+            let entities = TxParser::parse(&[value][..]).expect("to parse transaction");
+            validate(&conn, &db, &entities[..]).unwrap()
+        };
+
+        assert!(validate_str(r#"[[:db/add "b" :test/name "Bob"]]"#).is_empty());
+
+        // Every problem is reported, not just the first.
+        let problems = validate_str(r#"[[:db/add "b" :test/unknown "x"]
+                                        [:db/add "b" :test/name "Bob"]
+                                        [:db/add "c" :test/name 1]
+                                        [:db/add "c" :test/name "Alice"]]"#);
+        assert_eq!(problems.iter().map(|p| p.entity).collect::<Vec<_>>(), vec![Some(0), Some(2), Some(3)]);
+        match problems[1].error {
+            Error(ErrorKind::TxConflict(ref conflict), _) => assert_eq!(conflict.kind, ConflictKind::ValueType(ValueType::String)),
+            ref x => panic!("expected TxConflict, got {:?}", x),
+        }
+
+        // Later entities see earlier ones.
+        let problems = validate_str(r#"[[:db/add "b" :test/name "Bob"]
+                                        [:db/add "c" :test/name "Bob"]]"#);
+        assert_eq!(problems.iter().map(|p| p.entity).collect::<Vec<_>>(), vec![Some(1)]);
+
+        // Schema problems belong to the transaction as a whole.
+        let problems = validate_str(r#"[[:db/add "x" :db/valueType :db.type/string]]"#);
+        assert_eq!(problems.iter().map(|p| p.entity).collect::<Vec<_>>(), vec![None]);
+
+        // Nothing is written.
+        assert_eq!(db::read_db(&conn).unwrap(), db);
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM datoms WHERE a = ?", &[&db.schema.get_entid(&":test/name".to_string()).cloned().unwrap()], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_encrypted_values() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        let (_, db) = transact_str(&conn, &db, r#"[[:db/add "t" :db/ident :test/token]
+                                                   [:db/add "t" :db/valueType :db.type/string]
+                                                   [:db/add "t" :db/encrypted true]]"#).unwrap();
+        let token = *db.schema.get_entid(&":test/token".to_string()).unwrap();
+        assert!(db.schema.require_attribute_for_entid(&token).unwrap().encrypted);
+
+        let cipher = XorCipher(b"key".to_vec());
+        let transact_cipher = |db: &DB, input: &str| -> Result<(TxReport, DB)> {
+            let value = edn::parse::value(input).expect("to parse EDN");
+            let entities = TxParser::parse(&[value][..]).expect("to parse transaction");
+            transact_with_options(&conn, db, &entities[..], &TxOptions::new().retract_policy(RetractPolicy::Fail).cipher(&cipher))
+        };
+        let stored = || -> Vec<(i64, Vec<u8>)> {
+            conn.prepare("SELECT value_type_tag, v FROM datoms WHERE a = ?").unwrap()
+                .query_map(&[&token], |row| (row.get(0), row.get(1))).unwrap()
+                .map(|x| x.unwrap())
+                .collect()
+        };
+
+        let (report, db) = transact_cipher(&db, r#"[[:db/add "a" :test/token "secret"]]"#).unwrap();
+        let a = report.tempids["a"];
+        let rows = stored();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].0, ENCRYPTED_VALUE_TYPE_TAG as i64);
+        assert!(rows[0].1 != b"secret".to_vec());
+        assert_eq!(decrypt_value(&cipher, &rows[0].1).unwrap(), TypedValue::String("secret".to_string()));
+
+        // The existing value is found, although it isn't compared in SQL.
+        let (report, db) = transact_cipher(&db, &format!("[[:db/add {} :test/token \"secret\"]]", a)).unwrap();
+        assert!(report.noop);
+        let (_, db) = transact_cipher(&db, &format!("[[:db/add {} :test/token \"other\"]]", a)).unwrap();
+        assert_eq!(stored().len(), 1);
+        assert!(transact_cipher(&db, &format!("[[:db/retract {} :test/token \"secret\"]]", a)).is_err());
+        let (_, db) = transact_cipher(&db, &format!("[[:db/retract {} :test/token \"other\"]]", a)).unwrap();
+        assert!(stored().is_empty());
+
+        // Nothing is written in plaintext, not even to the log.
+        let plaintext: i64 = conn.query_row("SELECT COUNT(*) FROM transactions WHERE a = ? AND value_type_tag != ?",
+                                            &[&token, &ENCRYPTED_VALUE_TYPE_TAG], |row| row.get(0)).unwrap();
+        assert_eq!(plaintext, 0);
+
+        // Encrypted values need a cipher.
+        match transact_str(&conn, &db, r#"[[:db/add "b" :test/token "secret"]]"#) {
+            Err(Error(ErrorKind::MissingCipher, _)) => (),
+            x => panic!("expected MissingCipher, got {:?}", x),
+        }
+
+        // Once an attribute has values, its encryption can't change.
+        match transact_str(&conn, &db, "[[:db/add :test/token :db/encrypted false]]") {
+            Err(Error(ErrorKind::BadSchemaAssertion(_), _)) => (),
+            x => panic!("expected BadSchemaAssertion, got {:?}", x),
+        }
+        assert!(transact_str(&conn, &db, r#"[[:db/add "u" :db/ident :test/unique]
+                                             [:db/add "u" :db/valueType :db.type/string]
+                                             [:db/add "u" :db/unique :db.unique/value]
+                                             [:db/add "u" :db/encrypted true]]"#).is_err());
+        assert!(transact_str(&conn, &db, r#"[[:db/add "f" :db/ident :test/fulltext]
+                                             [:db/add "f" :db/valueType :db.type/string]
+                                             [:db/add "f" :db/fulltext true]
+                                             [:db/add "f" :db/encrypted true]]"#).is_err());
+    }
+
+    #[test]
+    fn test_defaults() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        // A default can come before its attribute's value type.
+        let (_, db) = transact_str(&conn, &db, r#"[[:db/add "t" :db/ident :todo/title]
+                                                   [:db/add "t" :db/valueType :db.type/string]
+                                                   [:db/add "d" :db/ident :todo/done]
+                                                   [:db/add "d" :db/valueType :db.type/boolean]
+                                                   [:db/add "d" :db/default false]
+                                                   [:db/add "p" :db/ident :todo/priority]
+                                                   [:db/add "p" :db/default 1]
+                                                   [:db/add "p" :db/valueType :db.type/long]
+                                                   [:db/add "n" :db/ident :person/name]
+                                                   [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+        let done = *db.schema.get_entid(&":todo/done".to_string()).unwrap();
+        let priority = *db.schema.get_entid(&":todo/priority".to_string()).unwrap();
+        assert_eq!(db.schema.attribute_for_entid(&done).unwrap().default, Some(TypedValue::Boolean(false)));
+        assert_eq!(db.schema.attribute_for_entid(&priority).unwrap().default, Some(TypedValue::Long(1)));
+
+        let value = |e: Entid, a: Entid| -> Option<TypedValue> {
+            conn.prepare("SELECT v, value_type_tag FROM datoms WHERE e = ? AND a = ?").unwrap()
+                .query_and_then(&[&e, &a], |row| TypedValue::from_sql_value_pair(row.get_checked(0)?, &row.get_checked(1)?)).unwrap()
+                .map(|x| x.unwrap())
+                .next()
+        };
+
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "a" :todo/title "Write"]
+                                                        [:db/add "b" :todo/title "Test"]
+                                                        [:db/add "b" :todo/done true]
+                                                        [:db/add "c" :person/name "Alice"]]"#).unwrap();
+        let (a, b, c) = (report.tempids["a"], report.tempids["b"], report.tempids["c"]);
+        assert_eq!(value(a, done), Some(TypedValue::Boolean(false)));
+        assert_eq!(value(a, priority), Some(TypedValue::Long(1)));
+        assert_eq!(value(b, done), Some(TypedValue::Boolean(true)));
+        assert_eq!(value(b, priority), Some(TypedValue::Long(1)));
+        // Only the defaults in the namespaces of the entity's own attributes apply.
+        assert_eq!(value(c, done), None);
+        assert_eq!(value(c, priority), None);
+
+        // The default is held as its EDN text, as `:db/default` is declared.
+        assert_eq!(value(done, entids::DB_DEFAULT), Some(TypedValue::String("false".to_string())));
+
+        // Entities given an ident are schema, and get no defaults.
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "e" :db/ident :person/email]
+                                                        [:db/add "e" :db/valueType :db.type/string]]"#).unwrap();
+        assert_eq!(value(report.tempids["e"], done), None);
+
+        // Defaults must satisfy the constraints on their attributes.
+        let mut constraints = Constraints::new();
+        constraints.add(&db.schema, priority, Constraint::Min(TypedValue::Long(2))).unwrap();
+        let parsed = edn::parse::value(r#"[[:db/add "f" :todo/title "Fix"]]"#).expect("to parse EDN");
+        let entities = TxParser::parse(&[parsed][..]).expect("to parse transaction");
+        match transact_with_options(&conn, &db, &entities[..], &TxOptions::new().constraints(&constraints)) {
+            Err(Error(ErrorKind::TxConflict(ref conflict), _)) => {
+                assert_eq!(conflict.attribute, priority);
+                match conflict.kind {
+                    ConflictKind::Constraint(_) => (),
+                    ref kind => panic!("expected a Constraint conflict, got {:?}", kind),
+                }
+            },
+            x => panic!("expected TxConflict, got {:?}", x),
+        }
+        // Entities in other namespaces don't get the default, so aren't held to its constraints.
+        let parsed = edn::parse::value(r#"[[:db/add "g" :person/name "Bob"]]"#).expect("to parse EDN");
+        let entities = TxParser::parse(&[parsed][..]).expect("to parse transaction");
+        transact_with_options(&conn, &db, &entities[..], &TxOptions::new().constraints(&constraints)).unwrap();
+
+        // Existing entities are left alone.
+        let (_, db) = transact_str(&conn, &db, &format!("[[:db/retract {} :todo/done false] [:db/add {} :todo/title \"Edit\"]]", a, a)).unwrap();
+        assert_eq!(value(a, done), None);
+
+        // Defaults are type-checked against their attribute's value type.
+        match transact_str(&conn, &db, "[[:db/add :todo/title :db/default 1]]") {
+            Err(Error(ErrorKind::TxConflict(ref conflict), _)) => assert_eq!(conflict.kind, ConflictKind::ValueType(ValueType::String)),
+            x => panic!("expected TxConflict, got {:?}", x),
+        }
+        match transact_str(&conn, &db, r#"[[:db/add "x" :db/ident :todo/note]
+                                           [:db/add "x" :db/default 1]
+                                           [:db/add "x" :db/valueType :db.type/string]]"#) {
+            Err(Error(ErrorKind::BadSchemaAssertion(_), _)) => (),
+            x => panic!("expected BadSchemaAssertion, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_enum() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        let (_, db) = transact_str(&conn, &db, r#"[[:db/add "s" :db/ident :task/status]
+                                                   [:db/add "s" :db/valueType :db.type/ref]
+                                                   [:db/add "s" :db/enum true]
+                                                   [:db/add "o" :db/ident :task.status/open]
+                                                   [:db/add "d" :db/ident :task.status/done]
+                                                   [:db/add "x" :db/ident :other/thing]]"#).unwrap();
+        let status = *db.schema.get_entid(&":task/status".to_string()).unwrap();
+        assert!(db.schema.attribute_for_entid(&status).unwrap().enumerated);
+
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "t" :task/status :task.status/open]]"#).unwrap();
+        let t = report.tempids["t"];
+        let (_, db) = transact_str(&conn, &db, &format!("[[:db/add {} :task/status :task.status/done]]", t)).unwrap();
+
+        for value in vec![":other/thing".to_string(), t.to_string()] {
+            match transact_str(&conn, &db, &format!("[[:db/add {} :task/status {}]]", t, value)) {
+                Err(Error(ErrorKind::TxConflict(ref conflict), _)) => {
+                    assert_eq!(conflict.attribute, status);
+                    match conflict.kind {
+                        ConflictKind::Constraint(_) => (),
+                        ref kind => panic!("expected a Constraint conflict, got {:?}", kind),
+                    }
+                },
+                x => panic!("expected TxConflict, got {:?}", x),
+            }
+        }
+
+        match transact_str(&conn, &db, r#"[[:db/add "n" :db/ident :task/name]
+                                           [:db/add "n" :db/valueType :db.type/string]
+                                           [:db/add "n" :db/enum true]]"#) {
+            Err(Error(ErrorKind::BadSchemaAssertion(_), _)) => (),
+            x => panic!("expected BadSchemaAssertion, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_created_idents() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        let (_, db) = transact_str(&conn, &db, r#"[[:db/add "s" :db/ident :task/status]
+                                                   [:db/add "s" :db/valueType :db.type/ref]
+                                                   [:db/add "s" :db/enum true]
+                                                   [:db/add "o" :db/ident :task.status/open]]"#).unwrap();
+        let mut namespaces = BTreeSet::new();
+        namespaces.insert("task.status".to_string());
+        let transact_creating = |db: &DB, input: &str| {
+            let value = edn::parse::value(input).expect("to parse EDN");
+            let entities = TxParser::parse(&[value][..]).expect("to parse transaction");
+            transact_with_options(&conn, db, &entities[..], &TxOptions::new().ident_namespaces(&namespaces))
+        };
+
+        // Not created without the namespace.
+        assert!(transact_str(&conn, &db, r#"[[:db/add "t" :task/status :task.status/blocked]]"#).is_err());
+
+        let (report, db) = transact_creating(&db, r#"[[:db/add "t" :task/status :task.status/blocked]
+                                                      [:db/add "u" :task/status :task.status/blocked]
+                                                      [:db/add "v" :task/status :task.status/open]]"#).unwrap();
+        let blocked = *db.schema.get_entid(&":task.status/blocked".to_string()).unwrap();
+        assert_eq!(report.created_idents.into_iter().collect::<Vec<_>>(), vec![(":task.status/blocked".to_string(), blocked)]);
+        assert_eq!(report.tempids.len(), 3);
+
+        // Existing idents aren't created again, and other namespaces still fail.
+        let (report, db) = transact_creating(&db, r#"[[:db/add "t" :task/status :task.status/blocked]]"#).unwrap();
+        assert!(report.created_idents.is_empty());
+        assert!(transact_creating(&db, r#"[[:db/add "t" :task/status :other/thing]]"#).is_err());
+
+        // Validating creates them as transacting does.
+        let value = edn::parse::value(r#"[[:db/add "t" :task/status :task.status/done]]"#).expect("to parse EDN");
+        let entities = TxParser::parse(&[value][..]).expect("to parse transaction");
+        assert!(validate_with_options(&conn, &db, &entities[..], &TxOptions::new().ident_namespaces(&namespaces)).unwrap().is_empty());
+        assert_eq!(validate(&conn, &db, &entities[..]).unwrap().iter().map(|p| p.entity).collect::<Vec<_>>(), vec![Some(0)]);
+    }
+
+    #[test]
+    fn test_tuples() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        let (_, db) = transact_str(&conn, &db, r#"[[:db/add "s" :db/ident :event/span]
+                                                   [:db/add "s" :db/valueType :db.type/tuple]
+                                                   [:db/add "s" :db/tupleTypes [:db.type/long :db.type/long]]
+                                                   [:db/add "t" :db/ident :event/tag]
+                                                   [:db/add "t" :db/valueType :db.type/tuple]
+                                                   [:db/add "t" :db/tupleTypes [:db.type/ref :db.type/string]]
+                                                   [:db/add "t" :db/unique :db.unique/value]]"#).unwrap();
+        let span = *db.schema.get_entid(&":event/span".to_string()).unwrap();
+        let tag = *db.schema.get_entid(&":event/tag".to_string()).unwrap();
+        assert_eq!(db.schema.attribute_for_entid(&span).unwrap().tuple_types, vec![ValueType::Long, ValueType::Long]);
+
+        let value = |e: Entid, a: Entid| -> Option<TypedValue> {
+            conn.prepare("SELECT v, value_type_tag FROM datoms WHERE e = ? AND a = ?").unwrap()
+                .query_and_then(&[&e, &a], |row| TypedValue::from_sql_value_pair(row.get_checked(0)?, &row.get_checked(1)?)).unwrap()
+                .map(|x| x.unwrap())
+                .next()
+        };
+
+        // Components are coerced by their types: ref components can be idents.
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "e" :event/span [10 20]]
+                                                        [:db/add "e" :event/tag [:event/span "x"]]]"#).unwrap();
+        let e = report.tempids["e"];
+        assert_eq!(value(e, span), Some(TypedValue::Tuple(vec![TypedValue::Long(10), TypedValue::Long(20)])));
+        assert_eq!(value(e, tag), Some(TypedValue::Tuple(vec![TypedValue::Ref(span), TypedValue::String("x".to_string())])));
+
+        // Tuples compare equal by their stored text.
+        let (report, db) = transact_str(&conn, &db, &format!("[[:db/add {} :event/span [10 20]]]", e)).unwrap();
+        assert!(report.noop);
+        assert!(transact_str(&conn, &db, &format!(r#"[[:db/add "f" :event/tag [{} "x"]]]"#, span)).is_err());
+
+        for bad in vec!["[10]", "[10 20 30]", "[10 "20"]", "10"] {
+            match transact_str(&conn, &db, &format!("[[:db/add {} :event/span {}]]", e, bad)) {
+                Err(Error(ErrorKind::TxConflict(ref conflict), _)) => assert_eq!(conflict.kind, ConflictKind::ValueType(ValueType::Tuple)),
+                x => panic!("expected TxConflict for {}, got {:?}", bad, x),
+            }
+        }
+
+        // Component types are scalar, and fixed once the attribute has values.
+        match transact_str(&conn, &db, "[[:db/add :event/span :db/tupleTypes [:db.type/long :db.type/double]]]") {
+            Err(Error(ErrorKind::BadSchemaAssertion(_), _)) => (),
+            x => panic!("expected BadSchemaAssertion, got {:?}", x),
+        }
+        match transact_str(&conn, &db, r#"[[:db/add "b" :db/ident :event/blob]
+                                           [:db/add "b" :db/valueType :db.type/tuple]
+                                           [:db/add "b" :db/tupleTypes [:db.type/bytes]]]"#) {
+            Err(Error(ErrorKind::BadSchemaAssertion(_), _)) => (),
+            x => panic!("expected BadSchemaAssertion, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_composites() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        let (_, db) = transact_str(&conn, &db, r#"[[:db/add "c" :db/ident :reg/course]
+                                                   [:db/add "c" :db/valueType :db.type/ref]
+                                                   [:db/add "s" :db/ident :reg/semester]
+                                                   [:db/add "s" :db/valueType :db.type/string]
+                                                   [:db/add "g" :db/ident :reg/grade]
+                                                   [:db/add "g" :db/valueType :db.type/string]
+                                                   [:db/add "k" :db/ident :reg/courseSemester]
+                                                   [:db/add "k" :db/valueType :db.type/tuple]
+                                                   [:db/add "k" :db/tupleAttrs [:reg/course :reg/semester]]
+                                                   [:db/add "k" :db/unique :db.unique/value]]"#).unwrap();
+        let course = *db.schema.get_entid(&":reg/course".to_string()).unwrap();
+        let key = *db.schema.get_entid(&":reg/courseSemester".to_string()).unwrap();
+        let attribute = db.schema.attribute_for_entid(&key).unwrap();
+        assert_eq!(attribute.tuple_attrs, vec![course, course + 1]);
+        assert_eq!(attribute.tuple_types, vec![ValueType::Ref, ValueType::String]);
+        // The transactor's copy-on-write schema agrees with the store.
+        assert_eq!(db::read_db(&conn).unwrap().schema, db.schema);
+
+        let value = |e: Entid, a: Entid| -> Option<TypedValue> {
+            conn.prepare("SELECT v, value_type_tag FROM datoms WHERE e = ? AND a = ?").unwrap()
+                .query_and_then(&[&e, &a], |row| TypedValue::from_sql_value_pair(row.get_checked(0)?, &row.get_checked(1)?)).unwrap()
+                .map(|x| x.unwrap())
+                .next()
+        };
+        let key_value = |semester: &str| Some(TypedValue::Tuple(vec![TypedValue::Ref(course), TypedValue::String(semester.to_string())]));
+
+        // The composite is derived once every source has a value, and follows them.
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "r" :reg/course :reg/course]]"#).unwrap();
+        let r = report.tempids["r"];
+        assert_eq!(value(r, key), None);
+        let (_, db) = transact_str(&conn, &db, &format!(r#"[[:db/add {} :reg/semester "2017"]]"#, r)).unwrap();
+        assert_eq!(value(r, key), key_value("2017"));
+        let (_, db) = transact_str(&conn, &db, &format!(r#"[[:db/add {} :reg/semester "2018"]]"#, r)).unwrap();
+        assert_eq!(value(r, key), key_value("2018"));
+
+        // Composites can be lookup refs.
+        let (_, db) = transact_str(&conn, &db, &format!(r#"[[:db/add [:reg/courseSemester [{} "2018"]] :reg/grade "A"]]"#, course)).unwrap();
+        assert_eq!(value(r, course + 2), Some(TypedValue::String("A".to_string())));
+
+        // Composites are unique like any other attribute.
+        match transact_str(&conn, &db, r#"[[:db/add "o" :reg/course :reg/course] [:db/add "o" :reg/semester "2018"]]"#) {
+            Err(Error(ErrorKind::TxConflict(ref conflict), _)) => {
+                assert_eq!(conflict.kind, ConflictKind::Unique);
+                assert_eq!(conflict.attribute, key);
+            },
+            x => panic!("expected TxConflict, got {:?}", x),
+        }
+
+        // Composites can't be transacted directly.
+        match transact_str(&conn, &db, &format!(r#"[[:db/add {} :reg/courseSemester [{} "2019"]]]"#, r, course)) {
+            Err(Error(ErrorKind::DerivedAttribute(_), _)) => (),
+            x => panic!("expected DerivedAttribute, got {:?}", x),
+        }
+
+        // Losing a source loses the composite.
+        let (_, db) = transact_str(&conn, &db, &format!(r#"[[:db/retract {} :reg/semester "2018"]]"#, r)).unwrap();
+        assert_eq!(value(r, key), None);
+
+        // Sources are scalar and cardinality one.
+        match transact_str(&conn, &db, r#"[[:db/add "t" :db/ident :reg/tags]
+                                           [:db/add "t" :db/valueType :db.type/string]
+                                           [:db/add "t" :db/cardinality :db.cardinality/many]
+                                           [:db/add "x" :db/ident :reg/courseTags]
+                                           [:db/add "x" :db/valueType :db.type/tuple]
+                                           [:db/add "x" :db/tupleAttrs [:reg/course :reg/tags]]]"#) {
+            Err(Error(ErrorKind::BadSchemaAssertion(_), _)) => (),
+            x => panic!("expected BadSchemaAssertion, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_upserts() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        let (_, db) = transact_str(&conn, &db, r#"[[:db/add "n" :db/ident :test/name]
+                                                   [:db/add "n" :db/valueType :db.type/string]
+                                                   [:db/add "n" :db/unique :db.unique/identity]
+                                                   [:db/add "m" :db/ident :test/email]
+                                                   [:db/add "m" :db/valueType :db.type/string]
+                                                   [:db/add "m" :db/unique :db.unique/identity]
+                                                   [:db/add "f" :db/ident :test/friend]
+                                                   [:db/add "f" :db/valueType :db.type/ref]
+                                                   [:db/add "f" :db/unique :db.unique/identity]
+                                                   [:db/add "g" :db/ident :test/age]
+                                                   [:db/add "g" :db/valueType :db.type/long]]"#).unwrap();
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "a" :test/name "Alice"]
+                                                        [:db/add "a" :test/email "alice@example.com"]
+                                                        [:db/add "b" :test/name "Bob"]
+                                                        [:db/add "b" :test/email "bob@example.com"]
+                                                        [:db/add "c" :test/name "Carol"]
+                                                        [:db/add "c" :test/friend "a"]]"#).unwrap();
+        let (alice, bob, carol) = (report.tempids["a"], report.tempids["b"], report.tempids["c"]);
+
+        // A tempid with an existing unique identity names its holder.
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "x" :test/name "Alice"] [:db/add "x" :test/age 30]]"#).unwrap();
+        assert_eq!(report.tempids["x"], alice);
+
+        // Resolution repeats: "y" upserts once "x" has, whatever the order of the entities.
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "y" :test/friend "x"]
+                                                        [:db/add "y" :test/age 40]
+                                                        [:db/add "x" :test/email "alice@example.com"]]"#).unwrap();
+        assert_eq!((report.tempids["x"], report.tempids["y"]), (alice, carol));
+
+        // New entities sharing a unique identity are one entity.
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "p" :test/name "Dave"] [:db/add "q" :test/name "Dave"] [:db/add "q" :test/age 5]]"#).unwrap();
+        assert_eq!(report.tempids["p"], report.tempids["q"]);
+
+        // A tempid can't be two existing entities, and which two is reported the same way however
+        // the transaction is ordered.
+        let mut try_str = |input: &str| -> Result<(TxReport, DB)> {
+            let tx = conn.transaction().unwrap();
+            transact_str(&tx, &db, input)
+        };
+        let ambiguity = |result: Result<(TxReport, DB)>| -> Ambiguity {
+            match result {
+                Err(Error(ErrorKind::AmbiguousUpsert(ambiguity), _)) => ambiguity,
+                x => panic!("expected AmbiguousUpsert, got {:?}", x),
+            }
+        };
+        let first = ambiguity(try_str(r#"[[:db/add "z" :test/name "Alice"] [:db/add "z" :test/email "bob@example.com"]]"#));
+        let second = ambiguity(try_str(r#"[[:db/add "z" :test/email "bob@example.com"] [:db/add "z" :test/name "Alice"]]"#));
+        assert_eq!(first, second);
+        assert_eq!(first.tempid, "z");
+        assert_eq!(first.candidates.iter().map(|&(e, _, _)| e).collect::<Vec<_>>(), vec![alice, bob]);
+    }
+
+    #[test]
+    fn test_tempid_hints() {
+        let mut conn = db::new_connection("").expect("Couldn't open in-memory db");
+        db::ensure_current_version(&mut conn).unwrap();
+        let db = db::read_db(&conn).unwrap();
+        let (_, db) = transact_str(&conn, &db, r#"[[:db/add "n" :db/ident :test/name]
+                                                   [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+        let transact_hinted = |db: &DB, input: &str, hints: &TempidHints| -> Result<(TxReport, DB)> {
+            let value = edn::parse::value(input).expect("to parse EDN");
+            let entities = TxParser::parse(&[value][..]).expect("to parse transaction");
+            transact_with_options(&conn, db, &entities[..], &TxOptions::new().hints(hints))
+        };
+
+        // Runs are allocated in the order given, not the order of use.
+        let (report, db) = transact_hinted(&db, r#"[[:db/add "a" :test/name "A"] [:db/add "b" :test/name "B"] [:db/add "c" :test/name "C"]]"#,
+                                           &TempidHints::new().contiguous(&["c", "b", "a"])).unwrap();
+        let a = report.tempids["a"];
+        assert_eq!((report.tempids["b"], report.tempids["c"]), (a - 1, a - 2));
+
+        // Known entids are reserved, so later allocations follow them.
+        let (report, db) = transact_hinted(&db, r#"[[:db/add "x" :test/name "X"]]"#, &TempidHints::new().entid("x", a + 100)).unwrap();
+        assert_eq!(report.tempids["x"], a + 100);
+        let (report, db) = transact_str(&conn, &db, r#"[[:db/add "y" :test/name "Y"]]"#).unwrap();
+        assert_eq!(report.tempids["y"], a + 101);
+
+        // Or name existing entities.
+        let (report, db) = transact_hinted(&db, r#"[[:db/add "z" :test/name "Z"]]"#, &TempidHints::new().entid("z", a)).unwrap();
+        assert_eq!(report.tempids["z"], a);
+
+        match transact_hinted(&db, r#"[[:db/add "z" :test/name "Z"]]"#, &TempidHints::new().entid("z", 10)) {
+            Err(Error(ErrorKind::BadTempidHint(_), _)) => (),
+            x => panic!("expected BadTempidHint, got {:?}", x),
+        }
+
+        // Validating checks the hints too, for the transaction as a whole.
+        let value = edn::parse::value(r#"[[:db/add "z" :test/name "Z"]]"#).expect("to parse EDN");
+        let entities = TxParser::parse(&[value][..]).expect("to parse transaction");
+        let problems = validate_with_options(&conn, &db, &entities[..], &TxOptions::new().hints(&TempidHints::new().entid("z", 10))).unwrap();
+        assert_eq!(problems.iter().map(|p| p.entity).collect::<Vec<_>>(), vec![None]);
+        match problems[0].error {
+            Error(ErrorKind::BadTempidHint(_), _) => (),
+            ref x => panic!("expected BadTempidHint, got {:?}", x),
+        }
+    }
+}
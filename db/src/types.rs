@@ -10,8 +10,10 @@
 
 #![allow(dead_code)]
 
-use std::collections::{BTreeMap};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
 
+use edn::symbols::{NamespacedKeyword};
 use ordered_float::{OrderedFloat};
 
 /// Core types defining a Mentat knowledge base.
@@ -34,6 +36,10 @@ pub enum ValueType {
     Double,
     String,
     Keyword,
+    Bytes,
+    Json,
+    /// A fixed-length sequence of scalar values, whose types are the attribute's `tuple_types`.
+    Tuple,
 }
 
 /// Represents a Mentat value in a particular value set.
@@ -46,7 +52,14 @@ pub enum TypedValue {
     Double(OrderedFloat<f64>),
     // TODO: &str throughout?
     String(String),
-    Keyword(String),
+    Keyword(NamespacedKeyword),
+    /// An opaque byte string, like a favicon.  Bytes can only be compared for equality.
+    Bytes(Vec<u8>),
+    /// A JSON document, in its text form.  Documents are validated when they are transacted.
+    Json(String),
+    /// A sequence of scalar values, like `[10 20]`.  Tuples can only be compared for equality in
+    /// queries; see the `tuple` module.
+    Tuple(Vec<TypedValue>),
 }
 
 impl TypedValue {
@@ -58,10 +71,22 @@ impl TypedValue {
             &TypedValue::Double(_) => ValueType::Double,
             &TypedValue::String(_) => ValueType::String,
             &TypedValue::Keyword(_) => ValueType::Keyword,
+            &TypedValue::Bytes(_) => ValueType::Bytes,
+            &TypedValue::Json(_) => ValueType::Json,
+            &TypedValue::Tuple(_) => ValueType::Tuple,
         }
     }
 }
 
+/// A datom in the store: entity `e` has value `v` for attribute `a`, as of transaction `tx`.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct Datom {
+    pub e: Entid,
+    pub a: Entid,
+    pub v: TypedValue,
+    pub tx: Entid,
+}
+
 /// Represents one partition of the entid space.
 #[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
 pub struct Partition {
@@ -76,11 +101,48 @@ impl Partition {
         assert!(start <= next, "A partition represents a monotonic increasing sequence of entids.");
         Partition { start: start, index: next }
     }
+
+    /// Allocate the next entid in the partition, or return `None` if that would overflow the entid
+    /// space.
+    ///
+    /// This doesn't check the partition's bounds; callers should check against `partition_end`.
+    pub fn allocate_entid(&mut self) -> Option<i64> {
+        let entid = self.index;
+        match self.index.checked_add(1) {
+            Some(next) => {
+                self.index = next;
+                Some(entid)
+            },
+            None => None,
+        }
+    }
 }
 
 /// Map partition names to `Partition` instances.
 pub type PartitionMap = BTreeMap<String, Partition>;
 
+/// Return the first entid past the end of the named partition.
+///
+/// Partition ranges are implicit: each partition ends where the partition with the next highest
+/// start begins, and the last partition ends at `i64::max_value()`.
+pub fn partition_end(partition_map: &PartitionMap, partition: &str) -> Option<i64> {
+    partition_map.get(partition).map(|p| {
+        partition_map.values()
+            .map(|other| other.start)
+            .filter(|&start| start > p.start)
+            .min()
+            .unwrap_or(i64::max_value())
+    })
+}
+
+/// Return the name of the partition whose range holds `entid`, if any.  See `partition_end`.
+pub fn partition_for_entid(partition_map: &PartitionMap, entid: Entid) -> Option<&String> {
+    partition_map.iter()
+        .filter(|&(_, p)| p.start <= entid)
+        .max_by_key(|&(_, p)| p.start)
+        .map(|(name, _)| name)
+}
+
 /// A Mentat schema attribute has a value type and several other flags determining how assertions
 /// with the attribute are interpreted.
 ///
@@ -117,6 +179,14 @@ pub struct Attribute {
     /// Fulltext attributes always have string values.
     pub fulltext: bool,
 
+    /// The FTS5 tokenizer for this fulltext attribute's own table, i.e., its
+    /// `:db.fulltext/tokenizer`, like `"porter unicode61"`.
+    pub fulltext_tokenizer: Option<String>,
+
+    /// The prefix lengths indexed by this fulltext attribute's own table, i.e., its
+    /// `:db.fulltext/prefix` values.
+    pub fulltext_prefixes: BTreeSet<i64>,
+
     /// `true` if this attribute is a component, i.e., it is `:db/isComponent true`.
     ///
     /// Component attributes always have value type `Ref`.
@@ -124,6 +194,42 @@ pub struct Attribute {
     /// They are used to compose entities from component sub-entities: they are fetched recursively
     /// by pull expressions, and they are automatically recursively deleted where appropriate.
     pub component: bool,
+
+    /// `true` if this attribute's values are encrypted before they're stored, i.e., it is
+    /// `:db/encrypted true`.
+    ///
+    /// Encrypted attributes always have string, bytes, or json values, and are neither unique nor
+    /// indexed.  See the `cipher` module.
+    pub encrypted: bool,
+
+    /// The value asserted for this attribute when a new entity is created without one, i.e., its
+    /// `:db/default`.
+    ///
+    /// A default always has this attribute's value type.  The `:db/default` datom holds its EDN
+    /// text, like `"false"`.
+    pub default: Option<TypedValue>,
+
+    /// `true` if this attribute is an enumeration, i.e., it is `:db/enum true`.
+    ///
+    /// Enumerations always have value type `Ref`.  Their values are the entities with idents in
+    /// the namespace named for the attribute, like `:task.status/open` for `:task/status`; see
+    /// `enum_namespace`.
+    pub enumerated: bool,
+
+    /// The type of each component of this tuple attribute's values, i.e., its `:db/tupleTypes`.
+    ///
+    /// Only tuple attributes have component types, which are scalar: refs, booleans, longs,
+    /// doubles, strings, or keywords.  A tuple attribute without component types takes tuples of
+    /// any length, whose components have their own EDN types.
+    pub tuple_types: Vec<ValueType>,
+
+    /// The attributes from which this composite tuple attribute's values are derived, i.e., its
+    /// `:db/tupleAttrs`.
+    ///
+    /// The transactor keeps each entity's composite value in step with its values of these
+    /// attributes, which are cardinality one and scalar; the component types are theirs.
+    /// Composite values can't be asserted or retracted directly.
+    pub tuple_attrs: Vec<Entid>,
 }
 
 impl Default for Attribute {
@@ -132,15 +238,44 @@ impl Default for Attribute {
             // There's no particular reason to favour one value type, so Ref it is.
             value_type: ValueType::Ref,
             fulltext: false,
+            fulltext_tokenizer: None,
+            fulltext_prefixes: BTreeSet::new(),
             index: false,
             multival: false,
             unique_value: false,
             unique_identity: false,
             component: false,
+            encrypted: false,
+            default: None,
+            enumerated: false,
+            tuple_types: vec![],
+            tuple_attrs: vec![],
         }
     }
 }
 
+impl Attribute {
+    /// `true` if this fulltext attribute is configured with its own tokenizer or prefixes, and so
+    /// is searched through its own table rather than the shared `fulltext_values` table.
+    pub fn has_fulltext_table(&self) -> bool {
+        self.fulltext && (self.fulltext_tokenizer.is_some() || !self.fulltext_prefixes.is_empty())
+    }
+}
+
+/// Return the namespace of the values of the enumeration with the given ident, like `task.status`
+/// for `:task/status`.
+pub fn enum_namespace(ident: &str) -> String {
+    ident.trim_left_matches(':').replace('/', ".")
+}
+
+/// Return the name of the own fulltext table of the attribute with the given entid.  Only
+/// attributes for which `has_fulltext_table` is true have one.
+///
+/// The table holds the same rowids as `fulltext_values`, so datoms refer into both.
+pub fn fulltext_table_name(entid: Entid) -> String {
+    format!("fulltext_values_{}", entid)
+}
+
 /// Map `String` idents (`:db/ident`) to positive integer entids (`1`).
 pub type IdentMap = BTreeMap<String, Entid>;
 
@@ -155,6 +290,10 @@ pub type SchemaMap = BTreeMap<i64, Attribute>;
 /// Maintains the mapping between string idents and positive integer entids; and exposes the schema
 /// flags associated to a given entid (equivalently, ident).
 ///
+/// The maps are shared between clones, so cloning a schema -- as each transaction and each query
+/// snapshot does -- is cheap.  Changing a schema copies only the maps it changes, and only if
+/// another clone shares them; see `Schema::add_ident` and `Schema::set_attribute`.
+///
 /// TODO: consider a single bi-directional map instead of separate ident->entid and entid->ident
 /// maps.
 #[derive(Clone,Debug,Default,Eq,Hash,Ord,PartialOrd,PartialEq)]
@@ -162,18 +301,18 @@ pub struct Schema {
     /// Map entid->ident.
     ///
     /// Invariant: is the inverse map of `ident_map`.
-    pub entid_map: EntidMap,
+    pub entid_map: Arc<EntidMap>,
 
     /// Map ident->entid.
     ///
     /// Invariant: is the inverse map of `entid_map`.
-    pub ident_map: IdentMap,
+    pub ident_map: Arc<IdentMap>,
 
     /// Map entid->attribute flags.
     ///
     /// Invariant: key-set is the same as the key-set of `entid_map` (equivalently, the value-set of
     /// `ident_map`).
-    pub schema_map: SchemaMap,
+    pub schema_map: Arc<SchemaMap>,
 }
 
 /// Represents the metadata required to query from, or apply transactions to, a Mentat store.
@@ -10,9 +10,11 @@
 
 use std::collections::{BTreeSet, BTreeMap, LinkedList};
 use std::cmp::{Ordering, Ord, PartialOrd};
+use std::fmt;
 
 use symbols;
 use num::BigInt;
+use num::rational::BigRational;
 use ordered_float::OrderedFloat;
 
 /// Value represents one of the allowed values in an EDN string.
@@ -22,9 +24,17 @@ pub enum Value {
     Boolean(bool),
     Integer(i64),
     BigInteger(BigInt),
+    /// A ratio of integers, like `3/4`, in lowest terms.
+    Ratio(BigRational),
     // https://users.rust-lang.org/t/hashmap-key-cant-be-float-number-type-why/7892
     Float(OrderedFloat<f64>),
     Text(String),
+    /// A character, like `\c`, `\newline`, or `\u00e9`.
+    Char(char),
+    /// An instant, like `#inst "2017-01-01T00:00:00Z"`, in milliseconds since the Unix epoch.
+    Instant(i64),
+    /// A byte string, written in base64, like `#bytes "aGVsbG8="`.
+    Bytes(Vec<u8>),
     PlainSymbol(symbols::PlainSymbol),
     NamespacedSymbol(symbols::NamespacedSymbol),
     Keyword(symbols::Keyword),
@@ -41,6 +51,42 @@ pub enum Value {
 
 use self::Value::*;
 
+/// Define `fn $name(&self) -> Option<$t>`, returning the contents of a `$kind` value.
+macro_rules! def_as {
+    ($name: ident, $kind: ident, $t: ty) => {
+        pub fn $name(&self) -> Option<$t> {
+            match *self {
+                $kind(v) => Some(v),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Like `def_as`, but borrow the contents.
+macro_rules! def_as_ref {
+    ($name: ident, $kind: ident, $t: ty) => {
+        pub fn $name(&self) -> Option<&$t> {
+            match *self {
+                $kind(ref v) => Some(v),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Like `def_as`, but consume the value and move its contents out.
+macro_rules! def_into {
+    ($name: ident, $kind: ident, $t: ty) => {
+        pub fn $name(self) -> Option<$t> {
+            match self {
+                $kind(v) => Some(v),
+                _ => None,
+            }
+        }
+    }
+}
+
 impl Value {
     pub fn is_keyword(&self) -> bool {
         match *self {
@@ -48,6 +94,142 @@ impl Value {
             _          => false,
         }
     }
+
+    pub fn is_namespaced_keyword(&self) -> bool {
+        match *self {
+            NamespacedKeyword(_) => true,
+            _                    => false,
+        }
+    }
+
+    /// `true` if this is the keyword with the given namespace and name, like `:db/id` for
+    /// `matches_kw(Some("db"), "id")`, or the plain keyword `:find` for `matches_kw(None, "find")`.
+    pub fn matches_kw(&self, namespace: Option<&str>, name: &str) -> bool {
+        match (self, namespace) {
+            (&Keyword(ref k), None) => k.0 == name,
+            (&NamespacedKeyword(ref k), Some(ns)) => k.namespace == ns && k.name == name,
+            _ => false,
+        }
+    }
+
+    def_as!(as_boolean, Boolean, bool);
+    def_as!(as_integer, Integer, i64);
+    def_as!(as_float, Float, OrderedFloat<f64>);
+    def_as!(as_instant, Instant, i64);
+    def_as!(as_char, Char, char);
+
+    def_as_ref!(as_big_integer, BigInteger, BigInt);
+    def_as_ref!(as_ratio, Ratio, BigRational);
+    def_as_ref!(as_text, Text, String);
+    def_as_ref!(as_bytes, Bytes, Vec<u8>);
+    def_as_ref!(as_plain_symbol, PlainSymbol, symbols::PlainSymbol);
+    def_as_ref!(as_namespaced_symbol, NamespacedSymbol, symbols::NamespacedSymbol);
+    def_as_ref!(as_keyword, Keyword, symbols::Keyword);
+    def_as_ref!(as_namespaced_keyword, NamespacedKeyword, symbols::NamespacedKeyword);
+    def_as_ref!(as_vector, Vector, Vec<Value>);
+    def_as_ref!(as_list, List, LinkedList<Value>);
+    def_as_ref!(as_set, Set, BTreeSet<Value>);
+    def_as_ref!(as_map, Map, BTreeMap<Value, Value>);
+
+    def_into!(into_big_integer, BigInteger, BigInt);
+    def_into!(into_ratio, Ratio, BigRational);
+    def_into!(into_text, Text, String);
+    def_into!(into_bytes, Bytes, Vec<u8>);
+    def_into!(into_plain_symbol, PlainSymbol, symbols::PlainSymbol);
+    def_into!(into_namespaced_symbol, NamespacedSymbol, symbols::NamespacedSymbol);
+    def_into!(into_keyword, Keyword, symbols::Keyword);
+    def_into!(into_namespaced_keyword, NamespacedKeyword, symbols::NamespacedKeyword);
+    def_into!(into_vector, Vector, Vec<Value>);
+    def_into!(into_list, List, LinkedList<Value>);
+    def_into!(into_set, Set, BTreeSet<Value>);
+    def_into!(into_map, Map, BTreeMap<Value, Value>);
+}
+
+/// Write `values` separated by spaces.
+fn write_seq<'a, I>(f: &mut fmt::Formatter, values: I) -> fmt::Result where I: Iterator<Item=&'a Value> {
+    for (i, value) in values.enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", value)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Value {
+    /// Print the value in EDN format, such that the parser reads it back as an equal value.
+    ///
+    /// Tagged values print in their canonical forms: instants as UTC RFC 3339 timestamps with
+    /// milliseconds, and byte strings as padded base64.  The exceptions are values the grammar
+    /// can't express: non-finite floats, and instants outside the years 0000 to 9999.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Nil => write!(f, "nil"),
+            Boolean(b) => write!(f, "{}", b),
+            Integer(i) => write!(f, "{}", i),
+            BigInteger(ref i) => write!(f, "{}N", i),
+            // Always with a denominator, since `2/1` is a ratio but `2` is an integer.
+            Ratio(ref r) => write!(f, "{}/{}", r.numer(), r.denom()),
+            Float(OrderedFloat(x)) => {
+                // Floats are only read as such with a fraction or an exponent.
+                let s = x.to_string();
+                if x.is_finite() && !s.contains('.') && !s.contains('e') {
+                    write!(f, "{}.0", s)
+                } else {
+                    write!(f, "{}", s)
+                }
+            },
+            Text(ref t) => {
+                write!(f, "\"")?;
+                for c in t.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\t' => write!(f, "\\t")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            },
+            Char(c) => match c {
+                '\n' => write!(f, "\\newline"),
+                '\r' => write!(f, "\\return"),
+                ' ' => write!(f, "\\space"),
+                '\t' => write!(f, "\\tab"),
+                // Other whitespace and control characters would be invisible or ambiguous.
+                c if (c.is_whitespace() || c.is_control()) && (c as u32) <= 0xffff => write!(f, "\\u{:04x}", c as u32),
+                c => write!(f, "\\{}", c),
+            },
+            Instant(millis) => write!(f, "#inst \"{}\"", to_rfc3339(millis)),
+            Bytes(ref bytes) => write!(f, "#bytes \"{}\"", to_base64(bytes)),
+            PlainSymbol(ref s) => write!(f, "{}", s.to_string()),
+            NamespacedSymbol(ref s) => write!(f, "{}", s.to_string()),
+            Keyword(ref k) => write!(f, "{}", k.to_string()),
+            NamespacedKeyword(ref k) => write!(f, "{}", k.to_string()),
+            Vector(ref v) => {
+                write!(f, "[")?;
+                write_seq(f, v.iter())?;
+                write!(f, "]")
+            },
+            List(ref l) => {
+                write!(f, "(")?;
+                write_seq(f, l.iter())?;
+                write!(f, ")")
+            },
+            Set(ref s) => {
+                write!(f, "#{{")?;
+                write_seq(f, s.iter())?;
+                write!(f, "}}")
+            },
+            Map(ref m) => {
+                write!(f, "{{")?;
+                write_seq(f, m.iter().flat_map(|(k, v)| vec![k, v]))?;
+                write!(f, "}}")
+            },
+        }
+    }
 }
 
 impl PartialOrd for Value {
@@ -65,9 +247,13 @@ impl Ord for Value {
             Nil             => match *other { Nil             => Ordering::Equal, _ => ord_order },
             Boolean(bs)     => match *other { Boolean(bo)     => bo.cmp(&bs), _ => ord_order },
             BigInteger(ref bs) => match *other { BigInteger(ref bo) => bo.cmp(&bs), _ => ord_order },
+            Ratio(ref rs)   => match *other { Ratio(ref ro)   => ro.cmp(&rs), _ => ord_order },
             Integer(is)     => match *other { Integer(io)     => io.cmp(&is), _ => ord_order },
             Float(ref fs)   => match *other { Float(ref fo)   => fo.cmp(&fs), _ => ord_order },
             Text(ref ts)    => match *other { Text(ref to)    => to.cmp(&ts), _ => ord_order },
+            Char(cs)        => match *other { Char(co)        => co.cmp(&cs), _ => ord_order },
+            Instant(is)     => match *other { Instant(io)     => io.cmp(&is), _ => ord_order },
+            Bytes(ref bs)   => match *other { Bytes(ref bo)   => bo.cmp(&bs), _ => ord_order },
             PlainSymbol(ref ss)  => match *other { PlainSymbol(ref so)  => so.cmp(&ss), _ => ord_order },
             NamespacedSymbol(ref ss)
                 => match *other { NamespacedSymbol(ref so)    => so.cmp(&ss), _ => ord_order },
@@ -98,6 +284,10 @@ fn to_ord(value: &Value) -> i32 {
         List(_) => 11,
         Set(_) => 12,
         Map(_) => 13,
+        Instant(_) => 14,
+        Bytes(_) => 15,
+        Char(_) => 16,
+        Ratio(_) => 17,
     }
 }
 
@@ -116,3 +306,122 @@ pub fn to_keyword(namespace: Option<&str>, name: &str) -> Value {
     }
     return Value::Keyword(symbols::Keyword::new(name));
 }
+
+/// Return the signed offset, in minutes, of an RFC 3339 time zone offset like `-08:00`.
+pub fn offset_minutes(sign: &str, hours: &str, minutes: &str) -> i64 {
+    let offset = hours.parse::<i64>().unwrap() * 60 + minutes.parse::<i64>().unwrap();
+    if sign == "-" { -offset } else { offset }
+}
+
+/// Return the number of days from the Unix epoch to the given proleptic Gregorian date.
+///
+/// See http://howardhinnant.github.io/date_algorithms.html#days_from_civil.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Return the proleptic Gregorian `(year, month, day)` the given number of days from the Unix
+/// epoch.  The inverse of `days_from_civil`.
+///
+/// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+/// Return the canonical RFC 3339 timestamp, in UTC with milliseconds, for the given number of
+/// milliseconds since the Unix epoch, like `2017-01-01T00:00:00.000Z`.
+pub fn to_rfc3339(millis: i64) -> String {
+    let ms_per_day = 24 * 60 * 60 * 1000;
+    // Round down, not towards zero: instants before the epoch fall on earlier days.
+    let days = (if millis >= 0 { millis } else { millis - (ms_per_day - 1) }) / ms_per_day;
+    let ms = millis - days * ms_per_day;
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day,
+            ms / 3600000, ms / 60000 % 60, ms / 1000 % 60, ms % 1000)
+}
+
+/// Return the `Value::Instant` for the parts of an RFC 3339 timestamp.  Fractional seconds beyond
+/// milliseconds are truncated.
+pub fn to_instant(year: &str, month: &str, day: &str,
+                  hour: &str, minute: &str, second: &str,
+                  fraction: Option<&str>, offset_minutes: i64) -> Value {
+    let n = |s: &str| s.parse::<i64>().unwrap();
+    let days = days_from_civil(n(year), n(month), n(day));
+    let seconds = ((days * 24 + n(hour)) * 60 + n(minute) - offset_minutes) * 60 + n(second);
+    let millis = fraction.map_or(0, |f| {
+        let digits: String = f.chars().chain("00".chars()).take(3).collect();
+        n(&digits)
+    });
+    Value::Instant(seconds * 1000 + millis)
+}
+
+const BASE64_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Return the standard, padded base64 encoding of `bytes`.
+pub fn to_base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | (b[2] as usize);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Return the bytes encoded by the standard, padded base64 string `s`, or `None` if `s` isn't
+/// valid base64.
+pub fn from_base64(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 4 != 0 {
+        return None;
+    }
+    let trimmed = s.trim_right_matches('=');
+    if s.len() - trimmed.len() > 2 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    let mut n: u32 = 0;
+    for (i, c) in trimmed.bytes().enumerate() {
+        let digit = match BASE64_ALPHABET.iter().position(|&x| x == c) {
+            Some(digit) => digit as u32,
+            None => return None,
+        };
+        n = (n << 6) | digit;
+        if i % 4 == 3 {
+            out.push((n >> 16) as u8);
+            out.push((n >> 8) as u8);
+            out.push(n as u8);
+            n = 0;
+        }
+    }
+    match trimmed.len() % 4 {
+        0 => (),
+        2 => out.push((n >> 4) as u8),
+        3 => {
+            out.push((n >> 10) as u8);
+            out.push((n >> 2) as u8);
+        },
+        _ => return None,
+    }
+    Some(out)
+}
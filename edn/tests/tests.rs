@@ -15,6 +15,7 @@ extern crate ordered_float;
 use std::collections::{BTreeSet, BTreeMap, LinkedList};
 use std::iter::FromIterator;
 use num::bigint::ToBigInt;
+use num::rational::BigRational;
 use num::traits::{Zero, One};
 use ordered_float::OrderedFloat;
 use edn::symbols;
@@ -58,6 +59,12 @@ fn test_integer() {
     assert_eq!(integer("1").unwrap(), Integer(1i64));
     assert_eq!(integer("999").unwrap(), Integer(999i64));
     assert_eq!(integer("-999").unwrap(), Integer(-999i64));
+    assert_eq!(integer("9223372036854775807").unwrap(), Integer(i64::max_value()));
+    assert_eq!(integer("-9223372036854775808").unwrap(), Integer(i64::min_value()));
+
+    // Integers outside the 64-bit range are big integers rather than errors.
+    assert_eq!(integer("9223372036854775808").unwrap(),
+               BigInteger(i64::max_value().to_bigint().unwrap() + 1i64.to_bigint().unwrap()));
 
     assert!(integer("nil").is_err());
 }
@@ -92,6 +99,8 @@ fn test_text() {
     assert_eq!(text("\"\"").unwrap(), Text("".to_string()));
 
     assert!(text("\"").is_err());
+    assert_eq!(text(r#""say \"hi\"\\\n""#).unwrap(), Text("say \"hi\"\\\n".to_string()));
+    assert!(text(r#""\x""#).is_err());
     assert!(text("nil").is_err());
 }
 
@@ -110,6 +119,48 @@ fn test_keyword() {
 
     assert_eq!(keyword(":symbol").unwrap(), k_plain("symbol"));
     assert_eq!(keyword(":hello").unwrap(), k_plain("hello"));
+
+    assert_eq!(keyword(":as-of").unwrap(), k_plain("as-of"));
+    assert_eq!(keyword(":db.fn/retract-entity").unwrap(), k_ns("db.fn", "retract-entity"));
+    assert!(keyword(":-foo").is_err());
+}
+
+#[test]
+fn test_inst() {
+    assert_eq!(inst("#inst \"1970-01-01T00:00:00Z\"").unwrap(), Instant(0));
+    assert_eq!(inst("#inst \"2017-01-01T00:00:00Z\"").unwrap(), Instant(1483228800000));
+    assert_eq!(inst("#inst \"2017-01-01T00:00:00.5Z\"").unwrap(), Instant(1483228800500));
+    assert_eq!(inst("#inst \"2017-01-01T00:00:00.123456Z\"").unwrap(), Instant(1483228800123));
+    assert_eq!(inst("#inst \"2016-12-31T16:00:00-08:00\"").unwrap(), Instant(1483228800000));
+    assert_eq!(inst("#inst \"2000-02-29T12:00:00+01:00\"").unwrap(), Instant(951822000000));
+    assert_eq!(inst("#inst \"1969-12-31T23:59:59Z\"").unwrap(), Instant(-1000));
+
+    assert!(inst("#inst \"2017-13-01T00:00:00Z\"").is_err());
+    assert!(inst("#inst \"2017-01-01T24:00:00Z\"").is_err());
+    assert!(inst("#inst \"2017-01-01\"").is_err());
+    assert!(inst("#inst 0").is_err());
+
+    assert_eq!(value("[:as-of #inst \"2017-01-01T00:00:00Z\"]").unwrap(),
+               Vector(vec![k_plain("as-of"), Instant(1483228800000)]));
+}
+
+#[test]
+fn test_bytes() {
+    assert_eq!(bytes("#bytes \"\"").unwrap(), Bytes(vec![]));
+    assert_eq!(bytes("#bytes \"aGVsbG8=\"").unwrap(), Bytes(b"hello".to_vec()));
+    assert_eq!(bytes("#bytes \"aGk=\"").unwrap(), Bytes(b"hi".to_vec()));
+    assert_eq!(bytes("#bytes \"AP8A\"").unwrap(), Bytes(vec![0, 255, 0]));
+
+    assert!(bytes("#bytes \"aGVsbG8\"").is_err());
+    assert!(bytes("#bytes \"aG=k\"").is_err());
+    assert!(bytes("#bytes \"a===\"").is_err());
+    assert!(bytes("#bytes 0").is_err());
+
+    for n in 0..10 {
+        let data: Vec<u8> = (0..n).map(|i| (i * 37) as u8).collect();
+        let encoded = edn::types::to_base64(&data[..]);
+        assert_eq!(edn::types::from_base64(&encoded), Some(data));
+    }
 }
 
 #[test]
@@ -851,6 +902,61 @@ fn test_spurious_commas() {
     assert_eq!(value("[3,,]"), result);
 }
 
+#[test]
+fn test_empty_collections_with_whitespace() {
+    assert_eq!(value("[ ]"), Ok(Vector(vec![])));
+    assert_eq!(value("( , )"), Ok(List(LinkedList::new())));
+    assert_eq!(value("#{ }"), Ok(Set(BTreeSet::new())));
+    assert_eq!(value("{\n}"), Ok(Map(BTreeMap::new())));
+    assert_eq!(value("{:a 1, :b 2,}"), Ok(Map(BTreeMap::from_iter(vec![(k_plain("a"), Integer(1)),
+                                                                     (k_plain("b"), Integer(2))]))));
+}
+
+#[test]
+fn test_comments() {
+    let result = Ok(Vector(vec![Integer(1), Integer(2)]));
+    assert_eq!(value("[1 2] ; trailing"), result);
+    assert_eq!(value("; leading\n[1 2]"), result);
+    assert_eq!(value("[; first\n1 2]"), result);
+    assert_eq!(value("[1 2 ; last\n]"), result);
+    assert_eq!(value("[1 ;; between\r\n 2]"), result);
+    assert_eq!(value("[; only\n]"), Ok(Vector(vec![])));
+}
+
+#[test]
+fn test_discard() {
+    assert_eq!(value("#_1 2"), Ok(Integer(2)));
+    assert_eq!(value("[#_1]"), Ok(Vector(vec![])));
+    assert_eq!(value("[1 #_2 3]"), Ok(Vector(vec![Integer(1), Integer(3)])));
+    assert_eq!(value("[1 #_ [2 3] 4]"), Ok(Vector(vec![Integer(1), Integer(4)])));
+    assert_eq!(value("[#_ #_ 1 2 3]"), Ok(Vector(vec![Integer(3)])));
+    assert_eq!(value("(1 #_2)"), Ok(List(LinkedList::from_iter(vec![Integer(1)]))));
+    assert_eq!(value("#{#_1 2}"), Ok(Set(BTreeSet::from_iter(vec![Integer(2)]))));
+    assert_eq!(value("{:a #_:b 1}"), Ok(Map(BTreeMap::from_iter(vec![(k_plain("a"), Integer(1))]))));
+
+    // There must be something to discard.
+    assert!(value("[#_]").is_err());
+    assert!(value("#_1").is_err());
+}
+
+#[test]
+fn test_datomic_schema() {
+    // As copied from a Clojure source file.
+    let schema = r#"
+        ;; People.
+        [{:db/ident       :person/name
+          :db/valueType   :db.type/string
+          ; :db/index true
+          #_:db/unique    #_:db.unique/identity
+          :db/cardinality :db.cardinality/one},
+         ]"#;
+    let mut m = BTreeMap::new();
+    m.insert(k_ns("db", "ident"), k_ns("person", "name"));
+    m.insert(k_ns("db", "valueType"), k_ns("db.type", "string"));
+    m.insert(k_ns("db", "cardinality"), k_ns("db.cardinality", "one"));
+    assert_eq!(value(schema), Ok(Vector(vec![Map(m)])));
+}
+
 #[test]
 fn test_utils_merge() {
     // Take BTreeMap instances, wrap into Value::Map instances.
@@ -887,6 +993,182 @@ fn test_utils_merge() {
     test(&right, &left, &expected);
 }
 
+#[test]
+fn test_value_accessors() {
+    assert_eq!(Integer(1).as_integer(), Some(1));
+    assert_eq!(Boolean(true).as_integer(), None);
+    assert_eq!(Boolean(true).as_boolean(), Some(true));
+    assert_eq!(Float(OrderedFloat(0.5)).as_float(), Some(OrderedFloat(0.5)));
+    assert_eq!(Text("foo".to_string()).as_text().map(|s| s.as_str()), Some("foo"));
+    assert_eq!(Text("foo".to_string()).into_text(), Some("foo".to_string()));
+
+    assert_eq!(k_plain("foo").as_keyword(), Some(&symbols::Keyword::new("foo")));
+    assert_eq!(k_ns("foo", "bar").as_keyword(), None);
+    assert_eq!(k_ns("foo", "bar").as_namespaced_keyword(), Some(&symbols::NamespacedKeyword::new("foo", "bar")));
+    assert_eq!(s_plain("?x").into_plain_symbol(), Some(symbols::PlainSymbol::new("?x")));
+
+    let vector = Vector(vec![Integer(1), Nil]);
+    assert_eq!(vector.as_vector(), Some(&vec![Integer(1), Nil]));
+    assert_eq!(vector.as_list(), None);
+    assert_eq!(vector.into_vector(), Some(vec![Integer(1), Nil]));
+
+    let mut map = BTreeMap::new();
+    map.insert(k_plain("a"), Integer(1));
+    assert_eq!(Map(map.clone()).into_map(), Some(map));
+    assert_eq!(Set(BTreeSet::new()).into_map(), None);
+}
+
+#[test]
+fn test_matches_kw() {
+    assert!(k_ns("db", "id").matches_kw(Some("db"), "id"));
+    assert!(!k_ns("db", "id").matches_kw(Some("db"), "ident"));
+    assert!(!k_ns("db", "id").matches_kw(None, "id"));
+    assert!(k_plain("find").matches_kw(None, "find"));
+    assert!(!k_plain("find").matches_kw(Some("find"), "find"));
+    assert!(!s_plain("find").matches_kw(None, "find"));
+}
+
+#[test]
+fn test_char() {
+    assert_eq!(character("\\c").unwrap(), Char('c'));
+    assert_eq!(character("\\\\").unwrap(), Char('\\'));
+    assert_eq!(character("\\(").unwrap(), Char('('));
+    assert_eq!(character("\\é").unwrap(), Char('é'));
+    assert_eq!(character("\\newline").unwrap(), Char('\n'));
+    assert_eq!(character("\\return").unwrap(), Char('\r'));
+    assert_eq!(character("\\space").unwrap(), Char(' '));
+    assert_eq!(character("\\tab").unwrap(), Char('\t'));
+    assert_eq!(character("\\u00e9").unwrap(), Char('é'));
+    assert_eq!(character("\\u").unwrap(), Char('u'));
+
+    assert!(character("\\").is_err());
+    assert!(character("\\cd").is_err());
+    assert!(character("\\ud800").is_err());
+    assert!(character("c").is_err());
+
+    assert_eq!(value("[\\a \\b]").unwrap(), Vector(vec![Char('a'), Char('b')]));
+    assert_eq!(value(r#""\\a""#).unwrap(), Text("\\a".to_string()));
+}
+
+#[test]
+fn test_ratio() {
+    let r = |n: i64, d: i64| Ratio(BigRational::new(n.to_bigint().unwrap(), d.to_bigint().unwrap()));
+    assert_eq!(ratio("3/4").unwrap(), r(3, 4));
+    assert_eq!(ratio("-1/2").unwrap(), r(-1, 2));
+    assert_eq!(ratio("+6/8").unwrap(), r(3, 4));
+    assert_eq!(ratio("4/2").unwrap(), r(2, 1));
+    assert_eq!(ratio("1/007").unwrap(), r(1, 7));
+
+    assert!(ratio("1/0").is_err());
+    assert!(ratio("1/00").is_err());
+    assert!(ratio("1/-2").is_err());
+    assert!(ratio("1.5/2").is_err());
+    assert!(value("1/0").is_err());
+
+    assert_eq!(value("[3/4 3]").unwrap(), Vector(vec![r(3, 4), Integer(3)]));
+}
+
+#[test]
+fn test_print_tagged() {
+    assert_eq!(Instant(0).to_string(), "#inst \"1970-01-01T00:00:00.000Z\"");
+    assert_eq!(Instant(1483228800123).to_string(), "#inst \"2017-01-01T00:00:00.123Z\"");
+    assert_eq!(Instant(951822000000).to_string(), "#inst \"2000-02-29T11:00:00.000Z\"");
+    assert_eq!(Instant(-1).to_string(), "#inst \"1969-12-31T23:59:59.999Z\"");
+    assert_eq!(Instant(-62167219200000).to_string(), "#inst \"0000-01-01T00:00:00.000Z\"");
+    assert_eq!(Instant(253402300799999).to_string(), "#inst \"9999-12-31T23:59:59.999Z\"");
+
+    assert_eq!(Bytes(vec![]).to_string(), "#bytes \"\"");
+    assert_eq!(Bytes(b"hello".to_vec()).to_string(), "#bytes \"aGVsbG8=\"");
+
+    // Other offsets and precisions read as the same instant print canonically.
+    let parsed = value("#inst \"2016-12-31T16:00:00.5-08:00\"").unwrap();
+    assert_eq!(parsed.to_string(), "#inst \"2017-01-01T00:00:00.500Z\"");
+}
+
+#[test]
+fn test_print() {
+    assert_eq!(Nil.to_string(), "nil");
+    assert_eq!(Char('c').to_string(), "\\c");
+    assert_eq!(Char('\n').to_string(), "\\newline");
+    assert_eq!(Char('\u{a0}').to_string(), "\\u00a0");
+    assert_eq!(Ratio(BigRational::new(4i64.to_bigint().unwrap(), 2i64.to_bigint().unwrap())).to_string(), "2/1");
+    assert_eq!(Float(OrderedFloat(1.0)).to_string(), "1.0");
+    assert_eq!(Float(OrderedFloat(-0.25)).to_string(), "-0.25");
+    assert_eq!(BigInteger(1i64.to_bigint().unwrap()).to_string(), "1N");
+    assert_eq!(Text("a \"b\"\n\\".to_string()).to_string(), r#""a \"b\"\n\\""#);
+    assert_eq!(Vector(vec![k_ns("db", "id"), Integer(1), List(LinkedList::new())]).to_string(),
+               "[:db/id 1 ()]");
+    assert_eq!(Set(BTreeSet::from_iter(vec![Boolean(true)])).to_string(), "#{true}");
+    assert_eq!(Map(BTreeMap::from_iter(vec![(k_plain("a"), s_plain("?x"))])).to_string(), "{:a ?x}");
+}
+
+/// A small, deterministic pseudo-random number generator (xorshift64), so that the round-trip
+/// tests cover many values without depending on a property-testing crate.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn between(&mut self, lo: i64, hi: i64) -> i64 {
+        lo + self.below((hi - lo) as u64) as i64
+    }
+}
+
+fn arbitrary_scalar(rng: &mut Rng) -> Value {
+    // The first and last milliseconds an `#inst` can express.
+    let (first, last) = (-62167219200000, 253402300799999);
+    match rng.below(10) {
+        0 => Instant(rng.between(first, last + 1)),
+        1 => Instant(rng.between(-86400000, 86400000)),
+        2 => Bytes((0..rng.below(20)).map(|_| rng.next() as u8).collect()),
+        3 => Integer(rng.next() as i64),
+        4 => Float(OrderedFloat(rng.between(-1000000, 1000000) as f64 / (1 + rng.below(1000)) as f64)),
+        5 => {
+            let chars = ['a', 'Z', ' ', '"', '\\', '\n', '\t', '\r', 'é', '∆'];
+            Text((0..rng.below(10)).map(|_| chars[rng.below(chars.len() as u64) as usize]).collect())
+        },
+        6 => k_ns("test", &format!("k{}", rng.below(100))),
+        7 => Char(['a', '\\', '"', ' ', '\n', '\u{a0}', '\u{0}', 'é', '😀'][rng.below(9) as usize]),
+        8 => Ratio(BigRational::new(rng.between(-1000, 1000).to_bigint().unwrap(),
+                                    rng.between(1, 1000).to_bigint().unwrap())),
+        _ => Boolean(rng.below(2) == 0),
+    }
+}
+
+fn arbitrary_value(rng: &mut Rng, depth: usize) -> Value {
+    if depth == 0 || rng.below(3) == 0 {
+        return arbitrary_scalar(rng);
+    }
+    let n = rng.below(4);
+    match rng.below(4) {
+        0 => Vector((0..n).map(|_| arbitrary_value(rng, depth - 1)).collect()),
+        1 => List((0..n).map(|_| arbitrary_value(rng, depth - 1)).collect()),
+        2 => Set((0..n).map(|_| arbitrary_value(rng, depth - 1)).collect()),
+        _ => Map((0..n).map(|_| (arbitrary_scalar(rng), arbitrary_value(rng, depth - 1))).collect()),
+    }
+}
+
+#[test]
+fn test_print_round_trip() {
+    let mut rng = Rng(0x2545f4914f6cdd1d);
+    for _ in 0..2000 {
+        let v = arbitrary_value(&mut rng, 3);
+        let printed = v.to_string();
+        assert_eq!(value(&printed), Ok(v.clone()), "{} didn't round-trip", printed);
+        // Printing is canonical: a value reads back to exactly the text it printed as.
+        assert_eq!(value(&printed).unwrap().to_string(), printed);
+    }
+}
+
 /*
 // Handy templates for creating test cases follow:
 
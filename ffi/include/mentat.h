@@ -0,0 +1,102 @@
+/* Copyright 2016 Mozilla
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+ * this file except in compliance with the License. You may obtain a copy of the
+ * License at http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software distributed
+ * under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+ * CONDITIONS OF ANY KIND, either express or implied. See the License for the
+ * specific language governing permissions and limitations under the License. */
+
+/* The C API of Mentat, built by the mentat_ffi crate.  See ffi/src/lib.rs for the object model:
+ * who owns each handle, and how errors and strings are returned. */
+
+#ifndef MENTAT_H
+#define MENTAT_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct MentatStore MentatStore;
+typedef struct MentatInProgress MentatInProgress;
+typedef struct MentatTxReport MentatTxReport;
+typedef struct MentatQueryBuilder MentatQueryBuilder;
+typedef struct MentatQueryResults MentatQueryResults;
+typedef struct MentatTypedValue MentatTypedValue;
+
+typedef enum {
+    MentatValueTypeRef = 0,
+    MentatValueTypeBoolean = 1,
+    MentatValueTypeInstant = 2,
+    MentatValueTypeLong = 3,
+    MentatValueTypeDouble = 4,
+    MentatValueTypeString = 5,
+    MentatValueTypeKeyword = 6,
+    MentatValueTypeBytes = 7,
+    MentatValueTypeJson = 8,
+    MentatValueTypeTuple = 9,
+} MentatValueType;
+
+void mentat_string_destroy(char *s);
+
+/* Stores: reference-counted, and safe to use from any thread. */
+MentatStore *mentat_store_open(const char *path, char **error);
+MentatStore *mentat_store_retain(const MentatStore *store);
+void mentat_store_destroy(MentatStore *store);
+MentatTxReport *mentat_store_transact(const MentatStore *store, const char *transaction, char **error);
+MentatQueryResults *mentat_store_query(const MentatStore *store, const char *query, char **error);
+MentatInProgress *mentat_store_begin_transaction(const MentatStore *store, char **error);
+
+/* Write transactions: hold the store's lock until committed, rolled back, or destroyed, and must
+ * be finished on the thread that began them. */
+MentatTxReport *mentat_in_progress_transact(MentatInProgress *in_progress, const char *transaction, char **error);
+MentatQueryResults *mentat_in_progress_query(const MentatInProgress *in_progress, const char *query, char **error);
+bool mentat_in_progress_commit(MentatInProgress *in_progress, char **error);
+bool mentat_in_progress_rollback(MentatInProgress *in_progress, char **error);
+void mentat_in_progress_destroy(MentatInProgress *in_progress);
+
+/* Transaction reports. */
+int64_t mentat_tx_report_tx_id(const MentatTxReport *report);
+int64_t mentat_tx_report_tx_instant(const MentatTxReport *report);
+int64_t mentat_tx_report_tempid(const MentatTxReport *report, const char *tempid);
+void mentat_tx_report_destroy(MentatTxReport *report);
+
+/* Query builders: bind a query's :in variables, like "?name", then execute it. */
+MentatQueryBuilder *mentat_query_builder_new(const MentatStore *store, const char *query);
+bool mentat_query_builder_bind_ref(MentatQueryBuilder *builder, const char *variable, int64_t value);
+bool mentat_query_builder_bind_boolean(MentatQueryBuilder *builder, const char *variable, bool value);
+bool mentat_query_builder_bind_long(MentatQueryBuilder *builder, const char *variable, int64_t value);
+bool mentat_query_builder_bind_double(MentatQueryBuilder *builder, const char *variable, double value);
+bool mentat_query_builder_bind_string(MentatQueryBuilder *builder, const char *variable, const char *value);
+bool mentat_query_builder_bind_keyword(MentatQueryBuilder *builder, const char *variable, const char *value);
+MentatQueryResults *mentat_query_builder_execute(const MentatQueryBuilder *builder, char **error);
+void mentat_query_builder_destroy(MentatQueryBuilder *builder);
+
+/* Query results, as rows of values. */
+int64_t mentat_query_results_basis_tx(const MentatQueryResults *results);
+size_t mentat_query_results_row_count(const MentatQueryResults *results);
+size_t mentat_query_results_column_count(const MentatQueryResults *results);
+MentatTypedValue *mentat_query_results_value(const MentatQueryResults *results, size_t row, size_t column);
+void mentat_query_results_destroy(MentatQueryResults *results);
+
+/* Typed values. */
+MentatValueType mentat_typed_value_type(const MentatTypedValue *value);
+int64_t mentat_typed_value_long(const MentatTypedValue *value);
+bool mentat_typed_value_boolean(const MentatTypedValue *value);
+double mentat_typed_value_double(const MentatTypedValue *value);
+char *mentat_typed_value_string(const MentatTypedValue *value);
+const uint8_t *mentat_typed_value_bytes(const MentatTypedValue *value, size_t *length);
+size_t mentat_typed_value_tuple_length(const MentatTypedValue *value);
+MentatTypedValue *mentat_typed_value_tuple_component(const MentatTypedValue *value, size_t index);
+void mentat_typed_value_destroy(MentatTypedValue *value);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MENTAT_H */
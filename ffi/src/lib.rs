@@ -0,0 +1,679 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A C API mirroring the Rust API, for the Android and iOS layers to bind.
+//!
+//! Every Mentat type crosses the boundary as an opaque pointer to a handle, declared in
+//! `include/mentat.h`:
+//!
+//! - `MentatStore` is reference-counted: `mentat_store_retain` returns another handle to the same
+//!   store, which can be used from another thread, and the store is closed when the last handle
+//!   is passed to `mentat_store_destroy`.  Operations on a store are serialized.
+//! - `MentatInProgress` is a write transaction, as `Store::begin_transaction`.  It holds the
+//!   store's lock, and a reference to the store, until it's committed, rolled back, or destroyed:
+//!   meanwhile, other operations on the store wait, so don't call them on the same thread.  It
+//!   must be finished on the thread that began it.
+//! - `MentatTxReport`, `MentatQueryBuilder`, `MentatQueryResults`, and `MentatTypedValue` are owned
+//!   by the caller, and freed with their `_destroy` functions.
+//!
+//! Strings are UTF-8 and NUL-terminated.  Strings returned are owned by the caller, and freed with
+//! `mentat_string_destroy`.  Functions that can fail take an `error` out-parameter last: on failure
+//! they return null, `false`, or -1, and, if `error` isn't null, store a message there, which the
+//! caller frees with `mentat_string_destroy`.
+//!
+//! A string argument that's null or isn't UTF-8 fails the call, as above; functions without an
+//! `error` out-parameter return null, `false`, or -1.  Passing null for a handle, other than to a
+//! `_destroy` function, is a programmer error, and panics.
+
+#![allow(dead_code)]
+
+extern crate mentat;
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use mentat::{Consistency, InProgress, QueryInputs, QueryOutput, QueryResults, Store, TxReport, TypedValue, ValueType, Variable, to_namespaced_keyword};
+use mentat::edn::PlainSymbol;
+use mentat::shared::lock;
+
+/// The value types of `MentatTypedValue`s, as `MentatValueType` in the header.
+pub const MENTAT_VALUE_TYPE_REF: c_int = 0;
+pub const MENTAT_VALUE_TYPE_BOOLEAN: c_int = 1;
+pub const MENTAT_VALUE_TYPE_INSTANT: c_int = 2;
+pub const MENTAT_VALUE_TYPE_LONG: c_int = 3;
+pub const MENTAT_VALUE_TYPE_DOUBLE: c_int = 4;
+pub const MENTAT_VALUE_TYPE_STRING: c_int = 5;
+pub const MENTAT_VALUE_TYPE_KEYWORD: c_int = 6;
+pub const MENTAT_VALUE_TYPE_BYTES: c_int = 7;
+pub const MENTAT_VALUE_TYPE_JSON: c_int = 8;
+pub const MENTAT_VALUE_TYPE_TUPLE: c_int = 9;
+
+pub struct MentatStore {
+    store: Arc<Mutex<Store>>,
+}
+
+pub struct MentatInProgress {
+    // Fields are dropped in order: the transaction, which rolls back if it hasn't finished; then
+    // the lock it ran under; then the reference keeping the store alive.
+    in_progress: Option<InProgress<'static>>,
+    guard: MutexGuard<'static, Store>,
+    store: Arc<Mutex<Store>>,
+}
+
+pub struct MentatQueryBuilder {
+    store: Arc<Mutex<Store>>,
+    query: String,
+    inputs: QueryInputs,
+}
+
+/// Query results of any shape, as rows: a scalar or tuple is at most one row, and a collection is
+/// rows of one value.
+pub struct MentatQueryResults {
+    basis_tx: i64,
+    rows: Vec<Vec<TypedValue>>,
+}
+
+pub type MentatTxReport = TxReport;
+pub type MentatTypedValue = TypedValue;
+
+unsafe fn c_str<'a>(s: *const c_char) -> mentat::Result<&'a str> {
+    if s.is_null() {
+        return Err("string argument is null".into());
+    }
+    CStr::from_ptr(s).to_str().map_err(|e| format!("string argument isn't UTF-8: {}", e).into())
+}
+
+fn to_c_string(s: String) -> *mut c_char {
+    // Interior NULs can't be represented; truncate at the first.
+    let s = match s.find('\0') {
+        Some(nul) => s[..nul].to_string(),
+        None => s,
+    };
+    CString::new(s).map(|s| s.into_raw()).unwrap_or(ptr::null_mut())
+}
+
+/// Return the `Ok` value of `result`, or store its error message in `error` and return `None`.
+unsafe fn ok_or_report<T>(result: mentat::Result<T>, error: *mut *mut c_char) -> Option<T> {
+    match result {
+        Ok(x) => Some(x),
+        Err(e) => {
+            if !error.is_null() {
+                *error = to_c_string(e.to_string());
+            }
+            None
+        },
+    }
+}
+
+unsafe fn boxed_or_null<T>(result: mentat::Result<T>, error: *mut *mut c_char) -> *mut T {
+    ok_or_report(result, error).map(|x| Box::into_raw(Box::new(x))).unwrap_or(ptr::null_mut())
+}
+
+/// The error for using a write transaction that has already been committed or rolled back.
+fn finished() -> mentat::Error {
+    "the transaction has already been committed or rolled back".into()
+}
+
+fn results_from(output: QueryOutput) -> MentatQueryResults {
+    let rows = match output.results {
+        QueryResults::Scalar(value) => value.into_iter().map(|value| vec![value]).collect(),
+        QueryResults::Tuple(row) => row.into_iter().collect(),
+        QueryResults::Coll(values) => values.into_iter().map(|value| vec![value]).collect(),
+        QueryResults::Rel(rows) => rows,
+    };
+    MentatQueryResults {
+        basis_tx: output.basis_tx,
+        rows: rows,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_string_destroy(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+// Stores.
+
+/// Open the store at `path`, creating it if necessary; an empty `path` opens an in-memory store.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_store_open(path: *const c_char, error: *mut *mut c_char) -> *mut MentatStore {
+    boxed_or_null(c_str(path).and_then(Store::open).map(|store| MentatStore { store: Arc::new(Mutex::new(store)) }), error)
+}
+
+/// Return another handle to the same store.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_store_retain(store: *const MentatStore) -> *mut MentatStore {
+    assert!(!store.is_null());
+    Box::into_raw(Box::new(MentatStore { store: (*store).store.clone() }))
+}
+
+/// Release a handle to a store, closing the store if it was the last.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_store_destroy(store: *mut MentatStore) {
+    if !store.is_null() {
+        drop(Box::from_raw(store));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_store_transact(store: *const MentatStore, transaction: *const c_char, error: *mut *mut c_char) -> *mut MentatTxReport {
+    assert!(!store.is_null());
+    boxed_or_null(c_str(transaction).and_then(|transaction| lock(&(*store).store).transact(transaction)), error)
+}
+
+/// Run `query` once, without inputs.  For inputs, use a `MentatQueryBuilder`.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_store_query(store: *const MentatStore, query: *const c_char, error: *mut *mut c_char) -> *mut MentatQueryResults {
+    assert!(!store.is_null());
+    boxed_or_null(c_str(query).and_then(|query| lock(&(*store).store).q_once(query)).map(results_from), error)
+}
+
+/// Begin a write transaction, holding the store's lock until it ends.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_store_begin_transaction(store: *const MentatStore, error: *mut *mut c_char) -> *mut MentatInProgress {
+    assert!(!store.is_null());
+    let store: Arc<Mutex<Store>> = (*store).store.clone();
+
+    // The store lives in the `Arc`, which the handle keeps alive for as long as the lock and the
+    // transaction borrow it.
+    let mutex: &'static Mutex<Store> = &*(&*store as *const Mutex<Store>);
+    let mut guard = lock(mutex);
+    let inner: &'static mut Store = &mut *(&mut *guard as *mut Store);
+    match ok_or_report(inner.begin_transaction(), error) {
+        Some(in_progress) => Box::into_raw(Box::new(MentatInProgress {
+            in_progress: Some(in_progress),
+            guard: guard,
+            store: store,
+        })),
+        None => ptr::null_mut(),
+    }
+}
+
+// Write transactions.
+
+/// Apply `transaction` without committing it.  A transaction that fails leaves the earlier ones in
+/// place.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_in_progress_transact(in_progress: *mut MentatInProgress, transaction: *const c_char, error: *mut *mut c_char) -> *mut MentatTxReport {
+    assert!(!in_progress.is_null());
+    match (*in_progress).in_progress {
+        Some(ref mut in_progress) => boxed_or_null(c_str(transaction).and_then(|transaction| in_progress.transact(transaction)), error),
+        None => boxed_or_null(Err(finished()), error),
+    }
+}
+
+/// Run `query` once, including the uncommitted transactions.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_in_progress_query(in_progress: *const MentatInProgress, query: *const c_char, error: *mut *mut c_char) -> *mut MentatQueryResults {
+    assert!(!in_progress.is_null());
+    match (*in_progress).in_progress {
+        Some(ref in_progress) => boxed_or_null(c_str(query).and_then(|query| in_progress.q_once(query, Consistency::IncludeInFlight)).map(results_from), error),
+        None => boxed_or_null(Err(finished()), error),
+    }
+}
+
+/// Commit the transactions applied so far, and destroy `in_progress`.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_in_progress_commit(in_progress: *mut MentatInProgress, error: *mut *mut c_char) -> bool {
+    assert!(!in_progress.is_null());
+    let mut in_progress = Box::from_raw(in_progress);
+    match in_progress.in_progress.take() {
+        Some(in_progress) => ok_or_report(in_progress.commit(), error).is_some(),
+        None => ok_or_report::<()>(Err(finished()), error).is_some(),
+    }
+}
+
+/// Discard the transactions applied so far, and destroy `in_progress`.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_in_progress_rollback(in_progress: *mut MentatInProgress, error: *mut *mut c_char) -> bool {
+    assert!(!in_progress.is_null());
+    let mut in_progress = Box::from_raw(in_progress);
+    match in_progress.in_progress.take() {
+        Some(in_progress) => ok_or_report(in_progress.rollback(), error).is_some(),
+        None => ok_or_report::<()>(Err(finished()), error).is_some(),
+    }
+}
+
+/// Destroy `in_progress`, rolling back any transactions applied.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_in_progress_destroy(in_progress: *mut MentatInProgress) {
+    if !in_progress.is_null() {
+        drop(Box::from_raw(in_progress));
+    }
+}
+
+// Transaction reports.
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_tx_report_tx_id(report: *const MentatTxReport) -> i64 {
+    assert!(!report.is_null());
+    (*report).tx_id
+}
+
+/// The timestamp of the transaction, in milliseconds since the Unix epoch.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_tx_report_tx_instant(report: *const MentatTxReport) -> i64 {
+    assert!(!report.is_null());
+    (*report).tx_instant
+}
+
+/// The entid allocated for the string tempid `tempid`, or -1 if the transaction had no such tempid,
+/// or `tempid` isn't a valid string.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_tx_report_tempid(report: *const MentatTxReport, tempid: *const c_char) -> i64 {
+    assert!(!report.is_null());
+    c_str(tempid).ok().and_then(|tempid| (*report).tempids.get(tempid).cloned()).unwrap_or(-1)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_tx_report_destroy(report: *mut MentatTxReport) {
+    if !report.is_null() {
+        drop(Box::from_raw(report));
+    }
+}
+
+// Query builders.
+
+/// Begin building a run of `query` against `store`.  Bind the query's `:in` variables, then
+/// execute it.  Returns null if `query` isn't a valid string.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_builder_new(store: *const MentatStore, query: *const c_char) -> *mut MentatQueryBuilder {
+    assert!(!store.is_null());
+    match c_str(query) {
+        Ok(query) => Box::into_raw(Box::new(MentatQueryBuilder {
+            store: (*store).store.clone(),
+            query: query.to_string(),
+            inputs: QueryInputs::new(),
+        })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Bind `variable`, returning `false`, and binding nothing, if it isn't a valid string.
+unsafe fn bind(builder: *mut MentatQueryBuilder, variable: *const c_char, value: TypedValue) -> bool {
+    assert!(!builder.is_null());
+    match c_str(variable) {
+        Ok(variable) => {
+            (*builder).inputs.insert(Variable(PlainSymbol::new(variable)), value);
+            true
+        },
+        Err(_) => false,
+    }
+}
+
+/// Bind `variable`, like `?e`, to the entity `value`.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_builder_bind_ref(builder: *mut MentatQueryBuilder, variable: *const c_char, value: i64) -> bool {
+    bind(builder, variable, TypedValue::Ref(value))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_builder_bind_boolean(builder: *mut MentatQueryBuilder, variable: *const c_char, value: bool) -> bool {
+    bind(builder, variable, TypedValue::Boolean(value))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_builder_bind_long(builder: *mut MentatQueryBuilder, variable: *const c_char, value: i64) -> bool {
+    bind(builder, variable, TypedValue::Long(value))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_builder_bind_double(builder: *mut MentatQueryBuilder, variable: *const c_char, value: f64) -> bool {
+    bind(builder, variable, TypedValue::Double(value.into()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_builder_bind_string(builder: *mut MentatQueryBuilder, variable: *const c_char, value: *const c_char) -> bool {
+    match c_str(value) {
+        Ok(value) => bind(builder, variable, TypedValue::String(value.to_string())),
+        Err(_) => false,
+    }
+}
+
+/// Bind `variable` to the keyword `value`, like `:todo.status/open`.  Returns `false`, binding
+/// nothing, if `value` isn't a namespaced keyword.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_builder_bind_keyword(builder: *mut MentatQueryBuilder, variable: *const c_char, value: *const c_char) -> bool {
+    match c_str(value).ok().and_then(to_namespaced_keyword) {
+        Some(value) => bind(builder, variable, TypedValue::Keyword(value)),
+        None => false,
+    }
+}
+
+/// Run the query with the values bound so far.  The builder can be run again.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_builder_execute(builder: *const MentatQueryBuilder, error: *mut *mut c_char) -> *mut MentatQueryResults {
+    assert!(!builder.is_null());
+    let builder = &*builder;
+    boxed_or_null(lock(&builder.store).q_once_with_inputs(&builder.query, builder.inputs.clone()).map(results_from), error)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_builder_destroy(builder: *mut MentatQueryBuilder) {
+    if !builder.is_null() {
+        drop(Box::from_raw(builder));
+    }
+}
+
+// Query results.
+
+/// The store's latest transaction when the query ran.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_results_basis_tx(results: *const MentatQueryResults) -> i64 {
+    assert!(!results.is_null());
+    (*results).basis_tx
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_results_row_count(results: *const MentatQueryResults) -> usize {
+    assert!(!results.is_null());
+    (*results).rows.len()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_results_column_count(results: *const MentatQueryResults) -> usize {
+    assert!(!results.is_null());
+    (*results).rows.first().map_or(0, |row| row.len())
+}
+
+/// Return a copy of the value at `row` and `column`, or null if there's no such value.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_results_value(results: *const MentatQueryResults, row: usize, column: usize) -> *mut MentatTypedValue {
+    assert!(!results.is_null());
+    match (*results).rows.get(row).and_then(|row| row.get(column)) {
+        Some(value) => Box::into_raw(Box::new(value.clone())),
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_query_results_destroy(results: *mut MentatQueryResults) {
+    if !results.is_null() {
+        drop(Box::from_raw(results));
+    }
+}
+
+// Typed values.
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_type(value: *const MentatTypedValue) -> c_int {
+    assert!(!value.is_null());
+    match (*value).value_type() {
+        ValueType::Ref => MENTAT_VALUE_TYPE_REF,
+        ValueType::Boolean => MENTAT_VALUE_TYPE_BOOLEAN,
+        ValueType::Instant => MENTAT_VALUE_TYPE_INSTANT,
+        ValueType::Long => MENTAT_VALUE_TYPE_LONG,
+        ValueType::Double => MENTAT_VALUE_TYPE_DOUBLE,
+        ValueType::String => MENTAT_VALUE_TYPE_STRING,
+        ValueType::Keyword => MENTAT_VALUE_TYPE_KEYWORD,
+        ValueType::Bytes => MENTAT_VALUE_TYPE_BYTES,
+        ValueType::Json => MENTAT_VALUE_TYPE_JSON,
+        ValueType::Tuple => MENTAT_VALUE_TYPE_TUPLE,
+    }
+}
+
+/// The entid of a ref, or the value of a long; 0 for other values.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_long(value: *const MentatTypedValue) -> i64 {
+    assert!(!value.is_null());
+    match *value {
+        TypedValue::Ref(x) | TypedValue::Long(x) => x,
+        _ => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_boolean(value: *const MentatTypedValue) -> bool {
+    assert!(!value.is_null());
+    match *value {
+        TypedValue::Boolean(x) => x,
+        _ => false,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_double(value: *const MentatTypedValue) -> f64 {
+    assert!(!value.is_null());
+    match *value {
+        TypedValue::Double(x) => x.into_inner(),
+        _ => 0.0,
+    }
+}
+
+/// The text of a string, JSON document, or keyword, like `:todo.status/open`; null for other
+/// values.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_string(value: *const MentatTypedValue) -> *mut c_char {
+    assert!(!value.is_null());
+    match *value {
+        TypedValue::String(ref x) | TypedValue::Json(ref x) => to_c_string(x.clone()),
+        TypedValue::Keyword(ref x) => to_c_string(x.to_string()),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// The bytes of a bytes value, which stay valid until `value` is destroyed, storing their length
+/// in `length`; null for other values.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_bytes(value: *const MentatTypedValue, length: *mut usize) -> *const u8 {
+    assert!(!value.is_null() && !length.is_null());
+    match *value {
+        TypedValue::Bytes(ref x) => {
+            *length = x.len();
+            x.as_ptr()
+        },
+        _ => {
+            *length = 0;
+            ptr::null()
+        },
+    }
+}
+
+/// The number of components of a tuple; 0 for other values.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_tuple_length(value: *const MentatTypedValue) -> usize {
+    assert!(!value.is_null());
+    match *value {
+        TypedValue::Tuple(ref xs) => xs.len(),
+        _ => 0,
+    }
+}
+
+/// Return a copy of the component of a tuple at `index`, or null if there's no such component or
+/// the value isn't a tuple.
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_tuple_component(value: *const MentatTypedValue, index: usize) -> *mut MentatTypedValue {
+    assert!(!value.is_null());
+    match *value {
+        TypedValue::Tuple(ref xs) if index < xs.len() => Box::into_raw(Box::new(xs[index].clone())),
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mentat_typed_value_destroy(value: *mut MentatTypedValue) {
+    if !value.is_null() {
+        drop(Box::from_raw(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    unsafe fn take_string(s: *mut c_char) -> String {
+        assert!(!s.is_null());
+        let string = CStr::from_ptr(s).to_str().unwrap().to_string();
+        mentat_string_destroy(s);
+        string
+    }
+
+    #[test]
+    fn test_store_and_query_builder() {
+        unsafe {
+            let mut error: *mut c_char = ptr::null_mut();
+            let store = mentat_store_open(c("").as_ptr(), &mut error);
+            assert!(!store.is_null());
+
+            let report = mentat_store_transact(store, c(r#"[[:db/add "n" :db/ident :test/name]
+                                                            [:db/add "n" :db/valueType :db.type/string]]"#).as_ptr(), &mut error);
+            assert!(!report.is_null());
+            mentat_tx_report_destroy(report);
+
+            let report = mentat_store_transact(store, c(r#"[[:db/add "a" :test/name "Alice"]]"#).as_ptr(), &mut error);
+            let alice = mentat_tx_report_tempid(report, c("a").as_ptr());
+            assert!(alice > 0);
+            assert_eq!(mentat_tx_report_tempid(report, c("b").as_ptr()), -1);
+            assert_eq!(mentat_tx_report_tempid(report, ptr::null()), -1);
+            mentat_tx_report_destroy(report);
+
+            // Errors are reported through the out-parameter.
+            assert!(mentat_store_transact(store, c("[[:db/add").as_ptr(), &mut error).is_null());
+            assert!(!take_string(error).is_empty());
+            error = ptr::null_mut();
+
+            // So are string arguments that are null or aren't UTF-8.
+            assert!(mentat_store_query(store, ptr::null(), &mut error).is_null());
+            assert!(!take_string(error).is_empty());
+            error = ptr::null_mut();
+            let invalid = [0xffu8, 0];
+            assert!(mentat_store_transact(store, invalid.as_ptr() as *const c_char, &mut error).is_null());
+            assert!(!take_string(error).is_empty());
+            error = ptr::null_mut();
+
+            let builder = mentat_query_builder_new(store, c("[:find ?e ?name :in $ ?name :where [?e :test/name ?name]]").as_ptr());
+            mentat_query_builder_bind_string(builder, c("?name").as_ptr(), c("Alice").as_ptr());
+            assert!(!mentat_query_builder_bind_keyword(builder, c("?k").as_ptr(), c("nonamespace").as_ptr()));
+            let results = mentat_query_builder_execute(builder, &mut error);
+            assert!(!results.is_null());
+            assert_eq!(mentat_query_results_row_count(results), 1);
+            assert_eq!(mentat_query_results_column_count(results), 2);
+
+            let e = mentat_query_results_value(results, 0, 0);
+            assert_eq!(mentat_typed_value_type(e), MENTAT_VALUE_TYPE_REF);
+            assert_eq!(mentat_typed_value_long(e), alice);
+            mentat_typed_value_destroy(e);
+            let name = mentat_query_results_value(results, 0, 1);
+            assert_eq!(mentat_typed_value_type(name), MENTAT_VALUE_TYPE_STRING);
+            assert_eq!(take_string(mentat_typed_value_string(name)), "Alice");
+            mentat_typed_value_destroy(name);
+            assert!(mentat_query_results_value(results, 1, 0).is_null());
+            mentat_query_results_destroy(results);
+            mentat_query_builder_destroy(builder);
+
+            mentat_store_destroy(store);
+        }
+    }
+
+    #[test]
+    fn test_in_progress() {
+        unsafe {
+            let mut error: *mut c_char = ptr::null_mut();
+            let store = mentat_store_open(c("").as_ptr(), &mut error);
+            mentat_tx_report_destroy(mentat_store_transact(store, c(r#"[[:db/add "n" :db/ident :test/name]
+                                                                          [:db/add "n" :db/valueType :db.type/string]]"#).as_ptr(), &mut error));
+            let count = c("[:find [?name ...] :where [_ :test/name ?name]]");
+
+            // Destroying an in-progress transaction rolls it back.
+            let in_progress = mentat_store_begin_transaction(store, &mut error);
+            mentat_tx_report_destroy(mentat_in_progress_transact(in_progress, c(r#"[[:db/add "a" :test/name "Alice"]]"#).as_ptr(), &mut error));
+            let results = mentat_in_progress_query(in_progress, count.as_ptr(), &mut error);
+            assert_eq!(mentat_query_results_row_count(results), 1);
+            mentat_query_results_destroy(results);
+            mentat_in_progress_destroy(in_progress);
+
+            let results = mentat_store_query(store, count.as_ptr(), &mut error);
+            assert_eq!(mentat_query_results_row_count(results), 0);
+            mentat_query_results_destroy(results);
+
+            // The in-progress transaction keeps the store alive.
+            let in_progress = mentat_store_begin_transaction(store, &mut error);
+            let other = mentat_store_retain(store);
+            mentat_store_destroy(store);
+            mentat_tx_report_destroy(mentat_in_progress_transact(in_progress, c(r#"[[:db/add "b" :test/name "Bob"]]"#).as_ptr(), &mut error));
+            assert!(mentat_in_progress_commit(in_progress, &mut error));
+
+            // Other handles can be used from other threads.
+            let other = other as usize;
+            let rows = thread::spawn(move || {
+                let other = other as *mut MentatStore;
+                let mut error: *mut c_char = ptr::null_mut();
+                let results = mentat_store_query(other, c("[:find [?name ...] :where [_ :test/name ?name]]").as_ptr(), &mut error);
+                let rows = mentat_query_results_row_count(results);
+                mentat_query_results_destroy(results);
+                rows
+            }).join().unwrap();
+            assert_eq!(rows, 1);
+            let other = other as *mut MentatStore;
+
+            let results = mentat_store_query(other, count.as_ptr(), &mut error);
+            assert_eq!(mentat_query_results_row_count(results), 1);
+            mentat_query_results_destroy(results);
+
+            // A finished transaction reports an error rather than just returning null.
+            let in_progress = mentat_store_begin_transaction(other, &mut error);
+            (*in_progress).in_progress.take().unwrap().rollback().unwrap();
+            assert!(mentat_in_progress_transact(in_progress, c(r#"[[:db/add "c" :test/name "Carol"]]"#).as_ptr(), &mut error).is_null());
+            assert!(!take_string(error).is_empty());
+            error = ptr::null_mut();
+            assert!(mentat_in_progress_query(in_progress, count.as_ptr(), &mut error).is_null());
+            assert!(!take_string(error).is_empty());
+            error = ptr::null_mut();
+            assert!(!mentat_in_progress_commit(in_progress, &mut error));
+            assert!(!take_string(error).is_empty());
+            mentat_store_destroy(other);
+        }
+    }
+
+    #[test]
+    fn test_tuples() {
+        unsafe {
+            let mut error: *mut c_char = ptr::null_mut();
+            let store = mentat_store_open(c("").as_ptr(), &mut error);
+            mentat_tx_report_destroy(mentat_store_transact(store, c(r#"[[:db/add "p" :db/ident :test/point]
+                                                                          [:db/add "p" :db/valueType :db.type/tuple]
+                                                                          [:db/add "p" :db/tupleTypes [:db.type/long :db.type/string]]]"#).as_ptr(), &mut error));
+            mentat_tx_report_destroy(mentat_store_transact(store, c(r#"[[:db/add "a" :test/point [10 "north"]]]"#).as_ptr(), &mut error));
+
+            let results = mentat_store_query(store, c("[:find ?p . :where [_ :test/point ?p]]").as_ptr(), &mut error);
+            let point = mentat_query_results_value(results, 0, 0);
+            assert_eq!(mentat_typed_value_type(point), MENTAT_VALUE_TYPE_TUPLE);
+            assert_eq!(mentat_typed_value_tuple_length(point), 2);
+
+            let x = mentat_typed_value_tuple_component(point, 0);
+            assert_eq!(mentat_typed_value_type(x), MENTAT_VALUE_TYPE_LONG);
+            assert_eq!(mentat_typed_value_long(x), 10);
+            mentat_typed_value_destroy(x);
+            let direction = mentat_typed_value_tuple_component(point, 1);
+            assert_eq!(take_string(mentat_typed_value_string(direction)), "north");
+            mentat_typed_value_destroy(direction);
+            assert!(mentat_typed_value_tuple_component(point, 2).is_null());
+
+            // Other values have no components.
+            let name = TypedValue::String("north".to_string());
+            assert_eq!(mentat_typed_value_tuple_length(&name), 0);
+            assert!(mentat_typed_value_tuple_component(&name, 0).is_null());
+
+            mentat_typed_value_destroy(point);
+            mentat_query_results_destroy(results);
+            mentat_store_destroy(store);
+        }
+    }
+}
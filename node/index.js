@@ -0,0 +1,49 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+'use strict';
+
+// The native module, built from src/lib.rs.  See there for how values are converted.
+const native = require('./mentat_node.node');
+
+class Store {
+  // Open the store at `path`, or an in-memory store if `path` is empty.  Opening blocks.
+  static open(path) {
+    return new Store(native.open(path));
+  }
+
+  constructor(handle) {
+    this.handle = handle;
+  }
+
+  // A promise of `{ txId, txInstant, tempids }`.
+  transact(transaction) {
+    return native.transact(this.handle, transaction);
+  }
+
+  // A promise of `{ basisTx, results }`.  `inputs` maps variables, like '?name', to values.
+  query(query, inputs) {
+    return native.query(this.handle, query, inputs);
+  }
+
+  // Call `callback(key, changes)` after each transaction touching `attributes`, like
+  // [':todo/title'], or every transaction if `attributes` is empty.  Pass `{ batchMillis }` to have
+  // the transactions committed within that window delivered together.
+  observe(key, attributes, callback, options) {
+    const batchMillis = (options && options.batchMillis) || 0;
+    native.observe(this.handle, key, attributes, callback, batchMillis);
+  }
+
+  unobserve(key) {
+    native.unobserve(this.handle, key);
+  }
+}
+
+module.exports = { Store };
@@ -0,0 +1,306 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A Node.js binding, for Electron-based consumers.
+//!
+//! The native module exports plain functions over an opaque store handle; `index.js` wraps them in
+//! a `Store` class.  Transactions and queries run on libuv's thread pool and return promises, so
+//! they never block the event loop.  Results are plain JS values:
+//!
+//! - refs, longs, and doubles are numbers; booleans are booleans; strings and JSON documents are
+//!   strings; keywords are strings like `":todo.status/open"`; bytes are `Buffer`s; and tuples are
+//!   arrays of their components;
+//! - query results are `{ basisTx, results }`, where `results` is shaped by the find spec: a value
+//!   or `null`, an array, or an array of arrays;
+//! - transaction reports are `{ txId, txInstant, tempids }`, with `tempids` mapping each string
+//!   tempid to its entid.
+//!
+//! Query inputs are an object mapping variables, like `"?name"`, to values: numbers, booleans, and
+//! strings, or `{ ref: 65536 }` and `{ keyword: ":todo.status/open" }` for refs and keywords.
+//!
+//! Observers are called on the event loop, through a thread-safe function, with the key they were
+//! registered under and an array of `{ txId, txInstant, attributes }` for the transactions
+//! delivered.
+
+#[macro_use]
+extern crate napi_derive;
+extern crate mentat;
+extern crate napi;
+
+use std::time::Duration;
+
+use napi::{
+    CallContext,
+    Env,
+    JsExternal,
+    JsFunction,
+    JsNumber,
+    JsObject,
+    JsString,
+    JsUndefined,
+    JsUnknown,
+    Status,
+    Task,
+    ValueType as JsValueType,
+};
+use napi::threadsafe_function::{ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+
+use mentat::{QueryInputs, QueryOutput, QueryResults, SharedStore, TxChange, TxObserver, TxReport, TypedValue, Variable, to_namespaced_keyword};
+use mentat::edn::PlainSymbol;
+
+fn to_napi_error(e: mentat::Error) -> napi::Error {
+    napi::Error::new(Status::GenericFailure, e.to_string())
+}
+
+fn invalid_arg(message: String) -> napi::Error {
+    napi::Error::new(Status::InvalidArg, message)
+}
+
+/// The store behind the handle passed as argument `i`.
+fn store_arg(ctx: &CallContext, i: usize) -> napi::Result<SharedStore> {
+    let handle = ctx.get::<JsExternal>(i)?;
+    Ok(ctx.env.get_value_external::<SharedStore>(&handle)?.clone())
+}
+
+fn string_arg(ctx: &CallContext, i: usize) -> napi::Result<String> {
+    ctx.get::<JsString>(i)?.into_utf8()?.into_owned()
+}
+
+fn js_value(env: &Env, value: TypedValue) -> napi::Result<JsUnknown> {
+    Ok(match value {
+        TypedValue::Ref(x) | TypedValue::Long(x) => env.create_int64(x)?.into_unknown(),
+        TypedValue::Boolean(x) => env.get_boolean(x)?.into_unknown(),
+        TypedValue::Double(x) => env.create_double(x.into_inner())?.into_unknown(),
+        TypedValue::String(x) | TypedValue::Json(x) => env.create_string_from_std(x)?.into_unknown(),
+        TypedValue::Keyword(x) => env.create_string_from_std(x.to_string())?.into_unknown(),
+        TypedValue::Bytes(x) => env.create_buffer_with_data(x)?.into_raw().into_unknown(),
+        TypedValue::Tuple(components) => js_array(env, components)?.into_unknown(),
+    })
+}
+
+fn js_array(env: &Env, values: Vec<TypedValue>) -> napi::Result<JsObject> {
+    let mut array = env.create_array_with_length(values.len())?;
+    for (i, value) in values.into_iter().enumerate() {
+        array.set_element(i as u32, js_value(env, value)?)?;
+    }
+    Ok(array)
+}
+
+fn js_output(env: &Env, output: QueryOutput) -> napi::Result<JsObject> {
+    let results = match output.results {
+        QueryResults::Scalar(Some(value)) => js_value(env, value)?,
+        QueryResults::Tuple(Some(row)) => js_array(env, row)?.into_unknown(),
+        QueryResults::Scalar(None) | QueryResults::Tuple(None) => env.get_null()?.into_unknown(),
+        QueryResults::Coll(values) => js_array(env, values)?.into_unknown(),
+        QueryResults::Rel(rows) => {
+            let mut array = env.create_array_with_length(rows.len())?;
+            for (i, row) in rows.into_iter().enumerate() {
+                array.set_element(i as u32, js_array(env, row)?)?;
+            }
+            array.into_unknown()
+        },
+    };
+    let mut object = env.create_object()?;
+    object.set_named_property("basisTx", env.create_int64(output.basis_tx)?)?;
+    object.set_named_property("results", results)?;
+    Ok(object)
+}
+
+fn js_report(env: &Env, report: TxReport) -> napi::Result<JsObject> {
+    let mut tempids = env.create_object()?;
+    for (tempid, entid) in report.tempids {
+        tempids.set_named_property(&tempid, env.create_int64(entid)?)?;
+    }
+    let mut object = env.create_object()?;
+    object.set_named_property("txId", env.create_int64(report.tx_id)?)?;
+    object.set_named_property("txInstant", env.create_int64(report.tx_instant)?)?;
+    object.set_named_property("tempids", tempids)?;
+    Ok(object)
+}
+
+fn js_changes(env: &Env, changes: Vec<TxChange>) -> napi::Result<JsObject> {
+    let mut array = env.create_array_with_length(changes.len())?;
+    for (i, change) in changes.into_iter().enumerate() {
+        let mut attributes = env.create_array_with_length(change.attributes.len())?;
+        for (j, a) in change.attributes.into_iter().enumerate() {
+            attributes.set_element(j as u32, env.create_int64(a)?)?;
+        }
+        let mut object = env.create_object()?;
+        object.set_named_property("txId", env.create_int64(change.tx_id)?)?;
+        object.set_named_property("txInstant", env.create_int64(change.tx_instant)?)?;
+        object.set_named_property("attributes", attributes)?;
+        array.set_element(i as u32, object)?;
+    }
+    Ok(array)
+}
+
+/// Convert a JS query input: a number, boolean, or string, or `{ ref }` or `{ keyword }`.
+fn typed_value(value: JsUnknown) -> napi::Result<TypedValue> {
+    match value.get_type()? {
+        JsValueType::Boolean => Ok(TypedValue::Boolean(value.coerce_to_bool()?.get_value()?)),
+        JsValueType::Number => {
+            let x = value.coerce_to_number()?.get_double()?;
+            if x.fract() == 0.0 && x.abs() < 9007199254740992.0 {
+                Ok(TypedValue::Long(x as i64))
+            } else {
+                Ok(TypedValue::Double(x.into()))
+            }
+        },
+        JsValueType::String => Ok(TypedValue::String(value.coerce_to_string()?.into_utf8()?.into_owned()?)),
+        JsValueType::Object => {
+            let object = value.coerce_to_object()?;
+            if object.has_named_property("ref")? {
+                let entid = object.get_named_property::<JsNumber>("ref")?.get_int64()?;
+                Ok(TypedValue::Ref(entid))
+            } else if object.has_named_property("keyword")? {
+                let text = object.get_named_property::<JsString>("keyword")?.into_utf8()?.into_owned()?;
+                to_namespaced_keyword(&text).map(TypedValue::Keyword).ok_or_else(|| invalid_arg(format!("{} isn't a namespaced keyword", text)))
+            } else {
+                Err(invalid_arg("query inputs must be numbers, booleans, strings, { ref }, or { keyword }".to_string()))
+            }
+        },
+        t => Err(invalid_arg(format!("can't use a {:?} as a query input", t))),
+    }
+}
+
+fn query_inputs(object: JsObject) -> napi::Result<QueryInputs> {
+    let mut inputs = QueryInputs::new();
+    let names = object.get_property_names()?;
+    for i in 0..names.get_array_length()? {
+        let name = names.get_element::<JsString>(i)?.into_utf8()?.into_owned()?;
+        let value = object.get_named_property::<JsUnknown>(&name)?;
+        inputs.insert(Variable(PlainSymbol::new(&name)), typed_value(value)?);
+    }
+    Ok(inputs)
+}
+
+struct Transact {
+    store: SharedStore,
+    transaction: String,
+}
+
+impl Task for Transact {
+    type Output = TxReport;
+    type JsValue = JsObject;
+
+    fn compute(&mut self) -> napi::Result<TxReport> {
+        self.store.transact(&self.transaction).map_err(to_napi_error)
+    }
+
+    fn resolve(self, env: Env, report: TxReport) -> napi::Result<JsObject> {
+        js_report(&env, report)
+    }
+}
+
+struct Query {
+    store: SharedStore,
+    query: String,
+    inputs: QueryInputs,
+}
+
+impl Task for Query {
+    type Output = QueryOutput;
+    type JsValue = JsObject;
+
+    fn compute(&mut self) -> napi::Result<QueryOutput> {
+        let inputs = ::std::mem::replace(&mut self.inputs, QueryInputs::new());
+        let query = &self.query;
+        self.store.with_store(|store| store.q_once_with_inputs(query, inputs)).map_err(to_napi_error)
+    }
+
+    fn resolve(self, env: Env, output: QueryOutput) -> napi::Result<JsObject> {
+        js_output(&env, output)
+    }
+}
+
+/// `open(path)`: open the store at `path`, or an in-memory store if `path` is empty, and return a
+/// handle to it.  Opening blocks.
+#[js_function(1)]
+fn open(ctx: CallContext) -> napi::Result<JsExternal> {
+    let path = string_arg(&ctx, 0)?;
+    let store = SharedStore::open(&path).map_err(to_napi_error)?;
+    ctx.env.create_external(store, None)
+}
+
+/// `transact(store, transaction)`: a promise of the transaction's report.
+#[js_function(2)]
+fn transact(ctx: CallContext) -> napi::Result<JsObject> {
+    let task = Transact {
+        store: store_arg(&ctx, 0)?,
+        transaction: string_arg(&ctx, 1)?,
+    };
+    Ok(ctx.env.spawn(task)?.promise_object())
+}
+
+/// `query(store, query, inputs)`: a promise of the query's results.  `inputs` may be undefined.
+#[js_function(3)]
+fn query(ctx: CallContext) -> napi::Result<JsObject> {
+    let inputs = match ctx.get::<JsUnknown>(2)?.get_type()? {
+        JsValueType::Undefined | JsValueType::Null => QueryInputs::new(),
+        _ => query_inputs(ctx.get::<JsObject>(2)?)?,
+    };
+    let task = Query {
+        store: store_arg(&ctx, 0)?,
+        query: string_arg(&ctx, 1)?,
+        inputs: inputs,
+    };
+    Ok(ctx.env.spawn(task)?.promise_object())
+}
+
+/// `observe(store, key, attributes, callback, batchMillis)`: call `callback(key, changes)` after
+/// transactions touching any of `attributes`, an array of idents, or every transaction if it's
+/// empty.  A positive `batchMillis` batches deliveries within that window.
+#[js_function(5)]
+fn observe(ctx: CallContext) -> napi::Result<JsUndefined> {
+    let store = store_arg(&ctx, 0)?;
+    let key = string_arg(&ctx, 1)?;
+    let array = ctx.get::<JsObject>(2)?;
+    let mut attributes = vec![];
+    for i in 0..array.get_array_length()? {
+        attributes.push(array.get_element::<JsString>(i)?.into_utf8()?.into_owned()?);
+    }
+    let callback = ctx.get::<JsFunction>(3)?;
+    let batch_millis = ctx.get::<JsNumber>(4)?.get_int64()?;
+
+    let tsfn: ThreadsafeFunction<(String, Vec<TxChange>)> = ctx.env.create_threadsafe_function(&callback, 0, |ctx: ThreadSafeCallContext<(String, Vec<TxChange>)>| {
+        let (key, changes) = ctx.value;
+        Ok(vec![ctx.env.create_string_from_std(key)?.into_unknown(), js_changes(&ctx.env, changes)?.into_unknown()])
+    })?;
+    // The notification thread keeps the store's observers alive; don't keep Node running for them.
+    let mut tsfn = tsfn;
+    tsfn.unref(&ctx.env)?;
+
+    let attributes: Vec<&str> = attributes.iter().map(|a| a.as_str()).collect();
+    let mut observer = TxObserver::new(&attributes, move |key, changes| {
+        tsfn.call(Ok((key.to_string(), changes.to_vec())), ThreadsafeFunctionCallMode::NonBlocking);
+    });
+    if batch_millis > 0 {
+        observer = observer.batched(Duration::from_millis(batch_millis as u64));
+    }
+    store.register_observer(&key, observer).map_err(to_napi_error)?;
+    ctx.env.get_undefined()
+}
+
+/// `unobserve(store, key)`: unregister the observer registered under `key`.
+#[js_function(2)]
+fn unobserve(ctx: CallContext) -> napi::Result<JsUndefined> {
+    store_arg(&ctx, 0)?.unregister_observer(&string_arg(&ctx, 1)?);
+    ctx.env.get_undefined()
+}
+
+#[module_exports]
+fn init(mut exports: JsObject) -> napi::Result<()> {
+    exports.create_named_method("open", open)?;
+    exports.create_named_method("transact", transact)?;
+    exports.create_named_method("query", query)?;
+    exports.create_named_method("observe", observe)?;
+    exports.create_named_method("unobserve", unobserve)?;
+    Ok(())
+}
@@ -12,7 +12,7 @@ extern crate combine;
 extern crate edn;
 extern crate mentat_query;
 
-use self::mentat_query::{FindSpec, FindQuery};
+use self::mentat_query::{FindSpec, FindQuery, WhereClause};
 
 #[derive(Clone,Debug,Eq,PartialEq)]
 pub struct NotAVariableError(pub edn::Value);
@@ -20,6 +20,13 @@ pub struct NotAVariableError(pub edn::Value);
 #[derive(Clone,Debug,Eq,PartialEq)]
 pub enum FindParseError {
   Err,
+  /// Two `:find` elements have the same name, as in `:find ?x ?x`.
+  DuplicateName(String),
+}
+
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum WhereParseError {
+  Err,
 }
 
 #[derive(Clone,Debug,Eq,PartialEq)]
@@ -28,8 +35,10 @@ pub enum QueryParseError {
     EdnParseError(edn::parse::ParseError),
     MissingField(edn::Keyword),
     FindParseError(FindParseError),
+    WhereParseError(WhereParseError),
 }
 
 pub type FindParseResult = Result<FindSpec, FindParseError>;
+pub type WhereParseResult = Result<Vec<WhereClause>, WhereParseError>;
 pub type QueryParseResult = Result<FindQuery, QueryParseError>;
 
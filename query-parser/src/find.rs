@@ -38,16 +38,20 @@ extern crate mentat_query;
 
 use std::collections::BTreeMap;
 
-use self::mentat_query::{FindQuery, SrcVar};
+use self::mentat_query::{FindQuery, PointInTime, SrcVar, Variable};
 
 use super::error::{QueryParseError, QueryParseResult};
-use super::util::{values_to_variables, vec_to_keyword_map};
+use super::util::{value_to_variable, values_to_variables, vec_to_keyword_map};
 
 #[allow(unused_variables)]
 fn parse_find_parts(find: &[edn::Value],
                     ins: Option<&[edn::Value]>,
                     with: Option<&[edn::Value]>,
-                    wheres: &[edn::Value])
+                    wheres: &[edn::Value],
+                    as_of: Option<&[edn::Value]>,
+                    since: Option<&[edn::Value]>,
+                    history: Option<&[edn::Value]>,
+                    idents: Option<&[edn::Value]>)
                     -> QueryParseResult {
     // :find must be an array of plain var symbols (?foo), pull expressions, and aggregates.
     // For now we only support variables and the annotations necessary to declare which
@@ -57,25 +61,132 @@ fn parse_find_parts(find: &[edn::Value],
     //     ?x .           = FindScalar
     //     [?x ?y ?z]     = FindTuple
     //
-    // :in must be an array of sources ($), rules (%), and vars (?). For now we only support the
-    // default source. :in can be omitted, in which case the default is equivalent to `:in $`.
-    // TODO: process `ins`.
+    // :in must be an array of sources ($), rules (%), and bindings. For now we only support the
+    // default source, scalar vars (?x), collections ([?x ...]), and relations ([[?x ?y]]). :in can
+    // be omitted, in which case the default is equivalent to `:in $`.
     let source = SrcVar::DefaultSrc;
+    let (in_vars, in_rels) = match ins {
+        Some(ins) => parse_in_vars(ins)?,
+        None => (vec![], vec![]),
+    };
 
     // :with is an array of variables. This is simple, so we don't use a parser.
     let with_vars = with.map(values_to_variables);
     // :wheres is a whole datastructure.
+    let where_clauses = super::parse::clause_seq_to_patterns(wheres)
+        .map_err(QueryParseError::WhereParseError)?;
+    // :as-of and :since are each a single transaction ID or #inst.
+    let as_of = match as_of {
+        Some(values) => Some(parse_point_in_time(values)?),
+        None => None,
+    };
+    let since = match since {
+        Some(values) => Some(parse_point_in_time(values)?),
+        None => None,
+    };
+    // :history and :idents are each a single boolean.
+    let history = match history {
+        Some(values) => parse_boolean(values)?,
+        None => false,
+    };
+    let idents = match idents {
+        Some(values) => parse_boolean(values)?,
+        None => false,
+    };
 
     super::parse::find_seq_to_find_spec(find)
         .map(|spec| {
             FindQuery {
                 find_spec: spec,
                 default_source: source,
+                in_vars: in_vars,
+                in_rels: in_rels,
+                where_clauses: where_clauses,
+                as_of: as_of,
+                since: since,
+                history: history,
+                idents: idents,
             }
         })
         .map_err(QueryParseError::FindParseError)
+}
+
+/// Extract the scalar input variables, and the variables of each collection and relation input,
+/// from `:in`, skipping the default source `$`.  No variable can be bound twice.
+fn parse_in_vars(ins: &[edn::Value]) -> Result<(Vec<Variable>, Vec<Vec<Variable>>), QueryParseError> {
+    let mut vars = vec![];
+    let mut rels = vec![];
+    let mut seen: Vec<Variable> = vec![];
+    for value in ins {
+        if let edn::Value::PlainSymbol(ref s) = *value {
+            if s.0.as_str() == "$" {
+                continue;
+            }
+        }
+        let bound = match parse_in_binding(value) {
+            Some(InBinding::Scalar(var)) => {
+                vars.push(var.clone());
+                vec![var]
+            },
+            Some(InBinding::Rel(rel)) => {
+                rels.push(rel.clone());
+                rel
+            },
+            None => return Err(QueryParseError::InvalidInput(value.clone())),
+        };
+        for var in bound {
+            if seen.contains(&var) {
+                return Err(QueryParseError::InvalidInput(value.clone()));
+            }
+            seen.push(var);
+        }
+    }
+    Ok((vars, rels))
+}
+
+enum InBinding {
+    Scalar(Variable),
+    Rel(Vec<Variable>),
+}
+
+/// Parse a single `:in` binding: `?x`, `[?x ...]`, or `[[?x ?y]]`.
+fn parse_in_binding(value: &edn::Value) -> Option<InBinding> {
+    if let Some(var) = value_to_variable(value) {
+        return Some(InBinding::Scalar(var));
+    }
+    let items = match *value {
+        edn::Value::Vector(ref items) => items,
+        _ => return None,
+    };
+    match (items.get(0), items.get(1)) {
+        // A collection: `[?x ...]`.
+        (Some(first), Some(&edn::Value::PlainSymbol(ref s))) if items.len() == 2 && s.0.as_str() == "..." => {
+            value_to_variable(first).map(|var| InBinding::Rel(vec![var]))
+        },
+        // A relation: `[[?x ?y]]`.
+        (Some(&edn::Value::Vector(ref places)), None) if !places.is_empty() => {
+            values_to_variables(places).ok().map(InBinding::Rel)
+        },
+        _ => None,
+    }
+}
 
+fn parse_boolean(values: &[edn::Value]) -> Result<bool, QueryParseError> {
+    match values.first().and_then(|v| v.as_boolean()) {
+        Some(b) if values.len() == 1 => Ok(b),
+        _ => Err(QueryParseError::InvalidInput(edn::Value::Vector(values.to_vec()))),
+    }
+}
 
+fn parse_point_in_time(values: &[edn::Value]) -> Result<PointInTime, QueryParseError> {
+    if values.len() == 1 {
+        match values[0] {
+            edn::Value::Integer(tx) if tx >= 0 => return Ok(PointInTime::Tx(tx)),
+            edn::Value::Instant(instant) => return Ok(PointInTime::Instant(instant)),
+            _ => (),
+        }
+    }
+    Err(QueryParseError::InvalidInput(edn::Value::Vector(values.to_vec())))
 }
 
 fn parse_find_map(map: BTreeMap<edn::Keyword, Vec<edn::Value>>) -> QueryParseResult {
@@ -84,6 +195,10 @@ fn parse_find_map(map: BTreeMap<edn::Keyword, Vec<edn::Value>>) -> QueryParseRes
     let kw_in = edn::Keyword::new("in");
     let kw_with = edn::Keyword::new("with");
     let kw_where = edn::Keyword::new("where");
+    let kw_as_of = edn::Keyword::new("as-of");
+    let kw_since = edn::Keyword::new("since");
+    let kw_history = edn::Keyword::new("history");
+    let kw_idents = edn::Keyword::new("idents");
 
     // Oh, if only we had `guard`.
     if let Some(find) = map.get(&kw_find) {
@@ -91,7 +206,11 @@ fn parse_find_map(map: BTreeMap<edn::Keyword, Vec<edn::Value>>) -> QueryParseRes
             return parse_find_parts(find,
                                     map.get(&kw_in).map(|x| x.as_slice()),
                                     map.get(&kw_with).map(|x| x.as_slice()),
-                                    wheres);
+                                    wheres,
+                                    map.get(&kw_as_of).map(|x| x.as_slice()),
+                                    map.get(&kw_since).map(|x| x.as_slice()),
+                                    map.get(&kw_history).map(|x| x.as_slice()),
+                                    map.get(&kw_idents).map(|x| x.as_slice()));
         } else {
             return Err(QueryParseError::MissingField(kw_where));
         }
@@ -113,6 +232,10 @@ fn parse_find_edn_map(map: BTreeMap<edn::Value, edn::Value>) -> QueryParseResult
             if let edn::Value::Vector(vec) = v {
                 m.insert(kw, vec);
                 continue;
+            } else if kw.0 == "as-of" || kw.0 == "since" || kw.0 == "history" || kw.0 == "idents" {
+                // These take a single value, so needn't be wrapped in a vector.
+                m.insert(kw, vec![v]);
+                continue;
             } else {
                 return Err(QueryParseError::InvalidInput(v));
             }
@@ -129,10 +252,14 @@ pub fn parse_find(expr: edn::Value) -> QueryParseResult {
     if let edn::Value::Map(m) = expr {
         return parse_find_edn_map(m);
     }
-    if let edn::Value::Vector(ref v) = expr {
-        if let Some(m) = vec_to_keyword_map(v) {
-            return parse_find_map(m);
-        }
+    if let Some(m) = expr.as_vector().and_then(|v| vec_to_keyword_map(v)) {
+        return parse_find_map(m);
     }
     return Err(QueryParseError::InvalidInput(expr));
 }
+
+/// Parse the EDN text of a query, in either the vector or the map form, into a `FindQuery`.
+pub fn parse_find_string(string: &str) -> QueryParseResult {
+    let expr = edn::parse::value(string).map_err(QueryParseError::EdnParseError)?;
+    parse_find(expr)
+}
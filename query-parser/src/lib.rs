@@ -12,4 +12,24 @@ mod error;
 mod util;
 mod parse;
 pub mod find;
+pub mod render;
+
+pub use error::{
+    FindParseError,
+    NotAVariableError,
+    QueryParseError,
+    QueryParseResult,
+    WhereParseError,
+};
+
+pub use find::{
+    parse_find,
+    parse_find_string,
+};
+
+pub use render::{
+    Span,
+    error_span,
+    render_error,
+};
 
@@ -12,12 +12,32 @@ extern crate combine;
 extern crate edn;
 extern crate mentat_query;
 
-use self::combine::{eof, many1, parser, satisfy_map, Parser, ParseResult, Stream};
+use std::collections::BTreeSet;
+
+use self::combine::{eof, many, many1, optional, parser, satisfy_map, Parser, ParseResult, Stream};
 use self::combine::combinator::{Expected, FnParser, choice, try};
 use self::edn::Value::PlainSymbol;
-use self::mentat_query::{Element, FindSpec, Variable};
+use self::mentat_query::{
+    Binding,
+    Element,
+    FindSpec,
+    FnArg,
+    NonIntegerConstant,
+    NotJoin,
+    OrJoin,
+    OrWhereClause,
+    Pattern,
+    PatternNonValuePlace,
+    PatternValuePlace,
+    Predicate,
+    SrcVar,
+    UnifyVars,
+    Variable,
+    WhereClause,
+    WhereFn,
+};
 
-use super::error::{FindParseError, FindParseResult};
+use super::error::{FindParseError, FindParseResult, WhereParseError, WhereParseResult};
 
 pub struct FindSp<I>(::std::marker::PhantomData<fn(I) -> I>);
 
@@ -48,6 +68,54 @@ impl<I> FindSp<I>
         satisfy_map(|x: edn::Value| super::util::value_to_variable(&x)).parse_stream(input)
     }
 
+    fn alias() -> FindSpParser<String, I> {
+        fn_parser(FindSp::<I>::alias_, "alias")
+    }
+
+    /// Parse `:as :person/id`, yielding the keyword's EDN text.
+    fn alias_(input: I) -> ParseResult<String, I> {
+        let as_keyword = satisfy_map(|x: edn::Value| if x.matches_kw(None, "as") { Some(()) } else { None });
+        let name = satisfy_map(|x: edn::Value| match x {
+            edn::Value::Keyword(ref k) => Some(k.to_string()),
+            edn::Value::NamespacedKeyword(ref k) => Some(k.to_string()),
+            _ => None,
+        });
+        (as_keyword, name)
+            .map(|(_, name)| name)
+            .parse_stream(input)
+    }
+
+    fn element() -> FindSpParser<Element, I> {
+        fn_parser(FindSp::<I>::element_, "element")
+    }
+
+    fn element_(input: I) -> ParseResult<Element, I> {
+        let variable = (FindSp::variable(), optional(try(FindSp::alias())))
+            .map(|(var, alias)| match alias {
+                Some(alias) => Element::Aliased(var, alias),
+                None => Element::Variable(var),
+            });
+        try(FindSp::count()).or(variable).parse_stream(input)
+    }
+
+    fn count() -> FindSpParser<Element, I> {
+        fn_parser(FindSp::<I>::count_, "count")
+    }
+
+    /// Parse `(count ?x)`.
+    fn count_(input: I) -> ParseResult<Element, I> {
+        satisfy_map(|x: edn::Value| {
+                if let edn::Value::List(ref items) = x {
+                    let items: Vec<&edn::Value> = items.iter().collect();
+                    if items.len() == 2 && items[0] == &PlainSymbol(edn::PlainSymbol::new("count")) {
+                        return super::util::value_to_variable(items[1]).map(Element::Count);
+                    }
+                }
+                None
+            })
+            .parse_stream(input)
+    }
+
     fn period() -> FindSpParser<(), I> {
         fn_parser(FindSp::<I>::period_, "period")
     }
@@ -85,8 +153,8 @@ impl<I> FindSp<I>
     }
 
     fn find_scalar_(input: I) -> ParseResult<FindSpec, I> {
-        (FindSp::variable(), FindSp::period(), eof())
-            .map(|(var, _, _)| FindSpec::FindScalar(Element::Variable(var)))
+        (FindSp::element(), FindSp::period(), eof())
+            .map(|(element, _, _)| FindSpec::FindScalar(element))
             .parse_stream(input)
     }
 
@@ -96,8 +164,8 @@ impl<I> FindSp<I>
 
     fn find_coll_(input: I) -> ParseResult<FindSpec, I> {
         satisfy_unwrap!(edn::Value::Vector, y, {
-                let mut p = (FindSp::variable(), FindSp::ellipsis(), eof())
-                    .map(|(var, _, _)| FindSpec::FindColl(Element::Variable(var)));
+                let mut p = (FindSp::element(), FindSp::ellipsis(), eof())
+                    .map(|(element, _, _)| FindSpec::FindColl(element));
                 let r: ParseResult<FindSpec, _> = p.parse_lazy(&y[..]).into();
                 FindSp::to_parsed_value(r)
             })
@@ -109,12 +177,8 @@ impl<I> FindSp<I>
     }
 
     fn elements_(input: I) -> ParseResult<Vec<Element>, I> {
-        (many1::<Vec<Variable>, _>(FindSp::variable()), eof())
-            .map(|(vars, _)| {
-                vars.into_iter()
-                    .map(Element::Variable)
-                    .collect()
-            })
+        (many1::<Vec<Element>, _>(FindSp::element()), eof())
+            .map(|(elements, _)| elements)
             .parse_stream(input)
     }
 
@@ -160,6 +224,395 @@ impl<I> FindSp<I>
     }
 }
 
+pub struct Where<I>(::std::marker::PhantomData<fn(I) -> I>);
+
+type WhereParser<O, I> = Expected<FnParser<I, fn(I) -> ParseResult<O, I>>>;
+
+fn where_fn_parser<O, I>(f: fn(I) -> ParseResult<O, I>, err: &'static str) -> WhereParser<O, I>
+    where I: Stream<Item = edn::Value>
+{
+    parser(f).expected(err)
+}
+
+impl<I> Where<I>
+    where I: Stream<Item = edn::Value>
+{
+    fn src_var() -> WhereParser<SrcVar, I> {
+        where_fn_parser(Where::<I>::src_var_, "src_var")
+    }
+
+    fn src_var_(input: I) -> ParseResult<SrcVar, I> {
+        satisfy_map(|x: edn::Value| {
+                if let PlainSymbol(ref s) = x {
+                    if s.0.as_str() == "$" {
+                        return Some(SrcVar::DefaultSrc);
+                    }
+                    if s.0.starts_with('$') {
+                        return Some(SrcVar::NamedSrc(s.0[1..].to_string()));
+                    }
+                }
+                return None;
+            })
+            .parse_stream(input)
+    }
+
+    fn pattern_non_value_place() -> WhereParser<PatternNonValuePlace, I> {
+        where_fn_parser(Where::<I>::pattern_non_value_place_, "pattern_non_value_place")
+    }
+
+    fn pattern_non_value_place_(input: I) -> ParseResult<PatternNonValuePlace, I> {
+        satisfy_map(|x: edn::Value| match x {
+                PlainSymbol(ref s) if s.0.as_str() == "_" => Some(PatternNonValuePlace::Placeholder),
+                PlainSymbol(ref s) if s.0.starts_with('?') => Some(PatternNonValuePlace::Variable(Variable(s.clone()))),
+                // Entids are never negative.
+                edn::Value::Integer(x) if x >= 0 => Some(PatternNonValuePlace::Entid(x)),
+                edn::Value::NamespacedKeyword(ref kw) => Some(PatternNonValuePlace::Ident(kw.clone())),
+                _ => None,
+            })
+            .parse_stream(input)
+    }
+
+    fn pattern_value_place() -> WhereParser<PatternValuePlace, I> {
+        where_fn_parser(Where::<I>::pattern_value_place_, "pattern_value_place")
+    }
+
+    fn pattern_value_place_(input: I) -> ParseResult<PatternValuePlace, I> {
+        satisfy_map(|x: edn::Value| match x {
+                PlainSymbol(ref s) if s.0.as_str() == "_" => Some(PatternValuePlace::Placeholder),
+                PlainSymbol(ref s) if s.0.starts_with('?') => Some(PatternValuePlace::Variable(Variable(s.clone()))),
+                edn::Value::Integer(x) => Some(PatternValuePlace::EntidOrInteger(x)),
+                edn::Value::Instant(x) => Some(PatternValuePlace::Constant(NonIntegerConstant::Instant(x))),
+                edn::Value::NamespacedKeyword(ref kw) => Some(PatternValuePlace::Ident(kw.clone())),
+                edn::Value::Boolean(x) => Some(PatternValuePlace::Constant(NonIntegerConstant::Boolean(x))),
+                edn::Value::BigInteger(ref x) => Some(PatternValuePlace::Constant(NonIntegerConstant::BigInteger(x.clone()))),
+                edn::Value::Float(x) => Some(PatternValuePlace::Constant(NonIntegerConstant::Float(x))),
+                edn::Value::Text(ref x) => Some(PatternValuePlace::Constant(NonIntegerConstant::Text(x.clone()))),
+                edn::Value::Bytes(ref x) => Some(PatternValuePlace::Constant(NonIntegerConstant::Bytes(x.clone()))),
+                _ => None,
+            })
+            .parse_stream(input)
+    }
+
+    fn pattern() -> WhereParser<WhereClause, I> {
+        where_fn_parser(Where::<I>::pattern_, "pattern")
+    }
+
+    fn pattern_(input: I) -> ParseResult<WhereClause, I> {
+        satisfy_unwrap!(edn::Value::Vector, y, {
+                // While *technically* Datomic allows you to have a query like:
+                // [:find … :where [[?x]]]
+                // We don't, because the "implicit blank" rule only applies to the value and tx.
+                let mut p = (optional(Where::<&[edn::Value]>::src_var()),
+                             Where::<&[edn::Value]>::pattern_non_value_place(), // e
+                             Where::<&[edn::Value]>::pattern_non_value_place(), // a
+                             optional(Where::<&[edn::Value]>::pattern_value_place()), // v
+                             optional(Where::<&[edn::Value]>::pattern_non_value_place()), // tx
+                             optional(Where::<&[edn::Value]>::pattern_value_place()), // added
+                             eof())
+                    .map(|(src, e, a, v, tx, added, _)| {
+                        let v = v.unwrap_or(PatternValuePlace::Placeholder);
+                        let tx = tx.unwrap_or(PatternNonValuePlace::Placeholder);
+                        match added {
+                            None |
+                            Some(PatternValuePlace::Placeholder) |
+                            Some(PatternValuePlace::Variable(_)) |
+                            Some(PatternValuePlace::Constant(NonIntegerConstant::Boolean(_))) => (),
+                            // Only booleans say whether a datom was added.
+                            Some(_) => return None,
+                        }
+                        let added = added.unwrap_or(PatternValuePlace::Placeholder);
+                        Pattern::new(src, e, a, v, tx, added).map(WhereClause::Pattern)
+                    });
+                let r: ParseResult<Option<WhereClause>, _> = p.parse_lazy(&y[..]).into();
+                r.ok().and_then(|x| x.0)
+            })
+            .parse_stream(input)
+    }
+
+    fn fn_arg() -> WhereParser<FnArg, I> {
+        where_fn_parser(Where::<I>::fn_arg_, "fn_arg")
+    }
+
+    fn fn_arg_(input: I) -> ParseResult<FnArg, I> {
+        satisfy_map(|x: edn::Value| match x {
+                PlainSymbol(ref s) if s.0.starts_with('?') => Some(FnArg::Variable(Variable(s.clone()))),
+                PlainSymbol(ref s) if s.0.as_str() == "$" => Some(FnArg::SrcVar(SrcVar::DefaultSrc)),
+                PlainSymbol(ref s) if s.0.starts_with('$') => Some(FnArg::SrcVar(SrcVar::NamedSrc(s.0[1..].to_string()))),
+                edn::Value::Integer(x) => Some(FnArg::EntidOrInteger(x)),
+                edn::Value::Instant(x) => Some(FnArg::Constant(NonIntegerConstant::Instant(x))),
+                edn::Value::NamespacedKeyword(ref kw) => Some(FnArg::Ident(kw.clone())),
+                edn::Value::Boolean(x) => Some(FnArg::Constant(NonIntegerConstant::Boolean(x))),
+                edn::Value::BigInteger(ref x) => Some(FnArg::Constant(NonIntegerConstant::BigInteger(x.clone()))),
+                edn::Value::Float(x) => Some(FnArg::Constant(NonIntegerConstant::Float(x))),
+                edn::Value::Text(ref x) => Some(FnArg::Constant(NonIntegerConstant::Text(x.clone()))),
+                edn::Value::Bytes(ref x) => Some(FnArg::Constant(NonIntegerConstant::Bytes(x.clone()))),
+                _ => None,
+            })
+            .parse_stream(input)
+    }
+
+    /// A function call, like `(fulltext $ :foo/text "search")`.
+    fn fn_call() -> WhereParser<(edn::PlainSymbol, Vec<FnArg>), I> {
+        where_fn_parser(Where::<I>::fn_call_, "fn_call")
+    }
+
+    fn fn_call_(input: I) -> ParseResult<(edn::PlainSymbol, Vec<FnArg>), I> {
+        satisfy_unwrap!(edn::Value::List, y, {
+                let items: Vec<edn::Value> = y.into_iter().collect();
+                let operator = satisfy_map(|x: edn::Value| match x {
+                    PlainSymbol(ref s) if !s.0.starts_with('?') && !s.0.starts_with('$') => Some(s.clone()),
+                    _ => None,
+                });
+                let mut p = (operator, many::<Vec<FnArg>, _>(Where::<&[edn::Value]>::fn_arg()), eof())
+                    .map(|(operator, args, _)| (operator, args));
+                let r: ParseResult<(edn::PlainSymbol, Vec<FnArg>), _> = p.parse_lazy(&items[..]).into();
+                r.ok().map(|x| x.0)
+            })
+            .parse_stream(input)
+    }
+
+    fn binding_place() -> WhereParser<Option<Variable>, I> {
+        where_fn_parser(Where::<I>::binding_place_, "binding_place")
+    }
+
+    fn binding_place_(input: I) -> ParseResult<Option<Variable>, I> {
+        satisfy_map(|x: edn::Value| match x {
+                PlainSymbol(ref s) if s.0.as_str() == "_" => Some(None),
+                PlainSymbol(ref s) if s.0.starts_with('?') => Some(Some(Variable(s.clone()))),
+                _ => None,
+            })
+            .parse_stream(input)
+    }
+
+    /// A scalar binding, like `?out`.
+    fn scalar_binding() -> WhereParser<Binding, I> {
+        where_fn_parser(Where::<I>::scalar_binding_, "scalar_binding")
+    }
+
+    fn scalar_binding_(input: I) -> ParseResult<Binding, I> {
+        satisfy_map(|x: edn::Value| match x {
+                PlainSymbol(ref s) if s.0.starts_with('?') => Some(Binding::BindScalar(Variable(s.clone()))),
+                _ => None,
+            })
+            .parse_stream(input)
+    }
+
+    /// A collection binding, like `[?tx ...]`.
+    fn coll_binding() -> WhereParser<Binding, I> {
+        where_fn_parser(Where::<I>::coll_binding_, "coll_binding")
+    }
+
+    fn coll_binding_(input: I) -> ParseResult<Binding, I> {
+        satisfy_unwrap!(edn::Value::Vector, y, {
+                match (y.get(0), y.get(1)) {
+                    (Some(&PlainSymbol(ref s)), Some(&PlainSymbol(ref ellipsis)))
+                        if y.len() == 2 && s.0.starts_with('?') && ellipsis.0.as_str() == "..." => {
+                        Some(Binding::BindColl(Variable(s.clone())))
+                    },
+                    _ => None,
+                }
+            })
+            .parse_stream(input)
+    }
+
+    /// A relation binding, like `[[?e ?text _]]`.
+    fn rel_binding() -> WhereParser<Binding, I> {
+        where_fn_parser(Where::<I>::rel_binding_, "rel_binding")
+    }
+
+    fn rel_binding_(input: I) -> ParseResult<Binding, I> {
+        satisfy_unwrap!(edn::Value::Vector, y, {
+                if y.len() != 1 {
+                    return None;
+                }
+                y[0].as_vector().and_then(|places| {
+                    let mut p = (many1::<Vec<Option<Variable>>, _>(Where::<&[edn::Value]>::binding_place()), eof())
+                        .map(|(places, _)| Binding::BindRel(places));
+                    let r: ParseResult<Binding, _> = p.parse_lazy(&places[..]).into();
+                    r.ok().map(|x| x.0)
+                })
+            })
+            .parse_stream(input)
+    }
+
+    fn pred() -> WhereParser<WhereClause, I> {
+        where_fn_parser(Where::<I>::pred_, "pred")
+    }
+
+    fn pred_(input: I) -> ParseResult<WhereClause, I> {
+        satisfy_unwrap!(edn::Value::Vector, y, {
+                let mut p = (Where::<&[edn::Value]>::fn_call(), eof())
+                    .map(|((operator, args), _)| {
+                        WhereClause::Pred(Predicate {
+                            operator: operator,
+                            args: args,
+                        })
+                    });
+                let r: ParseResult<WhereClause, _> = p.parse_lazy(&y[..]).into();
+                r.ok().map(|x| x.0)
+            })
+            .parse_stream(input)
+    }
+
+    fn where_fn() -> WhereParser<WhereClause, I> {
+        where_fn_parser(Where::<I>::where_fn_, "where_fn")
+    }
+
+    fn where_fn_(input: I) -> ParseResult<WhereClause, I> {
+        satisfy_unwrap!(edn::Value::Vector, y, {
+                let mut p = (Where::<&[edn::Value]>::fn_call(),
+                             Where::<&[edn::Value]>::scalar_binding()
+                                 .or(Where::<&[edn::Value]>::coll_binding())
+                                 .or(Where::<&[edn::Value]>::rel_binding()),
+                             eof())
+                    .map(|((operator, args), binding, _)| {
+                        WhereClause::WhereFn(WhereFn {
+                            operator: operator,
+                            args: args,
+                            binding: binding,
+                        })
+                    });
+                let r: ParseResult<WhereClause, _> = p.parse_lazy(&y[..]).into();
+                r.ok().map(|x| x.0)
+            })
+            .parse_stream(input)
+    }
+
+    /// The variables declared by an `or-join` or `not-join`, like `[?x ?y]`.
+    fn rule_vars() -> WhereParser<Vec<Variable>, I> {
+        where_fn_parser(Where::<I>::rule_vars_, "rule_vars")
+    }
+
+    fn rule_vars_(input: I) -> ParseResult<Vec<Variable>, I> {
+        satisfy_unwrap!(edn::Value::Vector, y, {
+                let mut p = (many1::<Vec<Variable>, _>(FindSp::<&[edn::Value]>::variable()), eof())
+                    .map(|(vars, _)| vars);
+                let r: ParseResult<Vec<Variable>, _> = p.parse_lazy(&y[..]).into();
+                r.ok().map(|x| x.0)
+            })
+            .parse_stream(input)
+    }
+
+    /// A conjunction within an `or`, like `(and [?x :foo/bar ?y] [?y :foo/baz 1])`.
+    fn and_clause() -> WhereParser<OrWhereClause, I> {
+        where_fn_parser(Where::<I>::and_clause_, "and_clause")
+    }
+
+    fn and_clause_(input: I) -> ParseResult<OrWhereClause, I> {
+        satisfy_unwrap!(edn::Value::List, y, {
+                let items: Vec<edn::Value> = y.into_iter().collect();
+                match items.first() {
+                    Some(&PlainSymbol(ref s)) if s.0.as_str() == "and" => (),
+                    _ => return None,
+                }
+                let mut p = (many1::<Vec<WhereClause>, _>(Where::<&[edn::Value]>::clause()), eof())
+                    .map(|(clauses, _)| OrWhereClause::And(clauses));
+                let r: ParseResult<OrWhereClause, _> = p.parse_lazy(&items[1..]).into();
+                r.ok().map(|x| x.0)
+            })
+            .parse_stream(input)
+    }
+
+    fn or_where_clause() -> WhereParser<OrWhereClause, I> {
+        where_fn_parser(Where::<I>::or_where_clause_, "or_where_clause")
+    }
+
+    fn or_where_clause_(input: I) -> ParseResult<OrWhereClause, I> {
+        choice::<[&mut Parser<Input = I, Output = OrWhereClause>; 2],
+                 _>([&mut try(Where::<I>::and_clause()),
+                     &mut try(Where::<I>::clause().map(OrWhereClause::Clause))])
+            .parse_stream(input)
+    }
+
+    /// A disjunction, like `(or [?x :foo/bar 1] (and [?x :foo/baz ?y] [?y :foo/bar 2]))` or
+    /// `(or-join [?x] ...)`.
+    fn or_join() -> WhereParser<WhereClause, I> {
+        where_fn_parser(Where::<I>::or_join_, "or_join")
+    }
+
+    fn or_join_(input: I) -> ParseResult<WhereClause, I> {
+        satisfy_unwrap!(edn::Value::List, y, {
+                let items: Vec<edn::Value> = y.into_iter().collect();
+                split_join(&items, "or").and_then(|(unify_vars, rest)| {
+                    let mut p = (many1::<Vec<OrWhereClause>, _>(Where::<&[edn::Value]>::or_where_clause()), eof())
+                        .map(|(clauses, _)| WhereClause::OrJoin(OrJoin {
+                            unify_vars: unify_vars,
+                            clauses: clauses,
+                        }));
+                    let r: ParseResult<WhereClause, _> = p.parse_lazy(rest).into();
+                    r.ok().map(|x| x.0)
+                })
+            })
+            .parse_stream(input)
+    }
+
+    /// A negation, like `(not [?x :foo/bar 1])` or `(not-join [?x] ...)`.
+    fn not_join() -> WhereParser<WhereClause, I> {
+        where_fn_parser(Where::<I>::not_join_, "not_join")
+    }
+
+    fn not_join_(input: I) -> ParseResult<WhereClause, I> {
+        satisfy_unwrap!(edn::Value::List, y, {
+                let items: Vec<edn::Value> = y.into_iter().collect();
+                split_join(&items, "not").and_then(|(unify_vars, rest)| {
+                    let mut p = (many1::<Vec<WhereClause>, _>(Where::<&[edn::Value]>::clause()), eof())
+                        .map(|(clauses, _)| WhereClause::NotJoin(NotJoin {
+                            unify_vars: unify_vars,
+                            clauses: clauses,
+                        }));
+                    let r: ParseResult<WhereClause, _> = p.parse_lazy(rest).into();
+                    r.ok().map(|x| x.0)
+                })
+            })
+            .parse_stream(input)
+    }
+
+    fn clause() -> WhereParser<WhereClause, I> {
+        where_fn_parser(Where::<I>::clause_, "clause")
+    }
+
+    fn clause_(input: I) -> ParseResult<WhereClause, I> {
+        choice::<[&mut Parser<Input = I, Output = WhereClause>; 5],
+                 _>([&mut try(Where::<I>::pattern()),
+                     &mut try(Where::<I>::pred()),
+                     &mut try(Where::<I>::where_fn()),
+                     &mut try(Where::<I>::or_join()),
+                     &mut try(Where::<I>::not_join())])
+            .parse_stream(input)
+    }
+
+    fn clauses() -> WhereParser<Vec<WhereClause>, I> {
+        where_fn_parser(Where::<I>::clauses_, "clauses")
+    }
+
+    fn clauses_(input: I) -> ParseResult<Vec<WhereClause>, I> {
+        // Right now we support patterns, scalar- and relation-binding functions, `or`, and `not`.
+        // See #239 for more.
+        (many1::<Vec<WhereClause>, _>(Where::<I>::clause()), eof())
+            .map(|(patterns, _)| patterns)
+            .parse_stream(input)
+    }
+}
+
+/// Split the items of a list like `(or-join [?x] ...)` whose operator is `op` or `op-join` into its
+/// unify vars and its clauses.  Only the `-join` form declares its variables.
+fn split_join<'a>(items: &'a [edn::Value], op: &str) -> Option<(UnifyVars, &'a [edn::Value])> {
+    let operator = match items.first() {
+        Some(&PlainSymbol(ref s)) => s.0.as_str(),
+        _ => return None,
+    };
+    if operator == op {
+        return Some((UnifyVars::Implicit, &items[1..]));
+    }
+    if operator != format!("{}-join", op) || items.len() < 2 {
+        return None;
+    }
+    Where::<&[edn::Value]>::rule_vars()
+        .parse(&items[1..2])
+        .ok()
+        .map(|(vars, _)| (UnifyVars::Explicit(vars), &items[2..]))
+}
+
 macro_rules! assert_parses_to {
     ( $parser: path, $input: expr, $expected: expr ) => {{
         let mut par = $parser();
@@ -219,6 +672,258 @@ fn test_find_tuple() {
                                                Element::Variable(Variable(vy))]));
 }
 
+#[test]
+fn test_pattern_mixed() {
+    let e = edn::PlainSymbol::new("_");
+    let a = edn::NamespacedKeyword::new("foo", "bar");
+    let v = "hello".to_string();
+    let tx = edn::PlainSymbol::new("?tx");
+    let input = [edn::Value::Vector(vec!(edn::Value::PlainSymbol(e.clone()),
+                                         edn::Value::NamespacedKeyword(a.clone()),
+                                         edn::Value::Text(v.clone()),
+                                         edn::Value::PlainSymbol(tx.clone())))];
+    assert_parses_to!(Where::pattern, input, WhereClause::Pattern(Pattern {
+        source: None,
+        entity: PatternNonValuePlace::Placeholder,
+        attribute: PatternNonValuePlace::Ident(a),
+        value: PatternValuePlace::Constant(NonIntegerConstant::Text(v)),
+        tx: PatternNonValuePlace::Variable(Variable(tx)),
+        added: PatternValuePlace::Placeholder,
+    }));
+}
+
+#[test]
+fn test_pattern_vars() {
+    let s = edn::PlainSymbol::new("$x");
+    let e = edn::PlainSymbol::new("?e");
+    let a = edn::PlainSymbol::new("?a");
+    let v = edn::PlainSymbol::new("?v");
+    let tx = edn::PlainSymbol::new("?tx");
+    let input = [edn::Value::Vector(vec!(edn::Value::PlainSymbol(s.clone()),
+                                         edn::Value::PlainSymbol(e.clone()),
+                                         edn::Value::PlainSymbol(a.clone()),
+                                         edn::Value::PlainSymbol(v.clone()),
+                                         edn::Value::PlainSymbol(tx.clone())))];
+    assert_parses_to!(Where::pattern, input, WhereClause::Pattern(Pattern {
+        source: Some(SrcVar::NamedSrc("x".to_string())),
+        entity: PatternNonValuePlace::Variable(Variable(e)),
+        attribute: PatternNonValuePlace::Variable(Variable(a)),
+        value: PatternValuePlace::Variable(Variable(v)),
+        tx: PatternNonValuePlace::Variable(Variable(tx)),
+        added: PatternValuePlace::Placeholder,
+    }));
+}
+
+#[test]
+fn test_pattern_added() {
+    let e = edn::PlainSymbol::new("?e");
+    let a = edn::NamespacedKeyword::new("foo", "bar");
+    let v = edn::PlainSymbol::new("?v");
+    let tx = edn::PlainSymbol::new("?tx");
+    let added = edn::PlainSymbol::new("?added");
+    let input = [edn::Value::Vector(vec!(edn::Value::PlainSymbol(e.clone()),
+                                         edn::Value::NamespacedKeyword(a.clone()),
+                                         edn::Value::PlainSymbol(v.clone()),
+                                         edn::Value::PlainSymbol(tx.clone()),
+                                         edn::Value::PlainSymbol(added.clone())))];
+    assert_parses_to!(Where::pattern, input, WhereClause::Pattern(Pattern {
+        source: None,
+        entity: PatternNonValuePlace::Variable(Variable(e.clone())),
+        attribute: PatternNonValuePlace::Ident(a.clone()),
+        value: PatternValuePlace::Variable(Variable(v.clone())),
+        tx: PatternNonValuePlace::Variable(Variable(tx.clone())),
+        added: PatternValuePlace::Variable(Variable(added)),
+    }));
+
+    // Only booleans say whether a datom was added.
+    let input = [edn::Value::Vector(vec!(edn::Value::PlainSymbol(e),
+                                         edn::Value::NamespacedKeyword(a),
+                                         edn::Value::PlainSymbol(v),
+                                         edn::Value::PlainSymbol(tx),
+                                         edn::Value::Integer(1)))];
+    let mut par = Where::pattern();
+    assert!(par.parse(&input[..]).is_err());
+}
+
+#[test]
+fn test_pattern_reversed() {
+    let e = edn::PlainSymbol::new("_");
+    let a = edn::NamespacedKeyword::new("foo", "_bar");
+    let v = edn::PlainSymbol::new("?v");
+    let input = [edn::Value::Vector(vec!(edn::Value::PlainSymbol(e.clone()),
+                                         edn::Value::NamespacedKeyword(a.clone()),
+                                         edn::Value::PlainSymbol(v.clone())))];
+
+    // Note that the attribute is no longer reversed, and the entity and value have
+    // switched places.
+    assert_parses_to!(Where::pattern, input, WhereClause::Pattern(Pattern {
+        source: None,
+        entity: PatternNonValuePlace::Variable(Variable(v)),
+        attribute: PatternNonValuePlace::Ident(edn::NamespacedKeyword::new("foo", "bar")),
+        value: PatternValuePlace::Placeholder,
+        tx: PatternNonValuePlace::Placeholder,
+        added: PatternValuePlace::Placeholder,
+    }));
+}
+
+#[test]
+fn test_pattern_reversed_invalid() {
+    let e = edn::PlainSymbol::new("_");
+    let a = edn::NamespacedKeyword::new("foo", "_bar");
+    let v = edn::Value::Text("hello".to_string());
+    let input = [edn::Value::Vector(vec!(edn::Value::PlainSymbol(e.clone()),
+                                         edn::Value::NamespacedKeyword(a.clone()),
+                                         v))];
+
+    // A string can't be an entity, so this pattern can't be reversed.
+    let mut par = Where::pattern();
+    assert!(par.parse(&input[..]).is_err());
+}
+
+#[test]
+fn test_where_fn() {
+    let call: ::std::collections::LinkedList<edn::Value> = vec![
+        edn::Value::PlainSymbol(edn::PlainSymbol::new("fulltext")),
+        edn::Value::PlainSymbol(edn::PlainSymbol::new("$")),
+        edn::Value::NamespacedKeyword(edn::NamespacedKeyword::new("foo", "text")),
+        edn::Value::Text("search".to_string()),
+    ].into_iter().collect();
+    let binding = edn::Value::Vector(vec![edn::Value::Vector(vec![
+        edn::Value::PlainSymbol(edn::PlainSymbol::new("?e")),
+        edn::Value::PlainSymbol(edn::PlainSymbol::new("_")),
+        edn::Value::PlainSymbol(edn::PlainSymbol::new("?score")),
+    ])]);
+    let input = [edn::Value::Vector(vec![edn::Value::List(call.clone()), binding])];
+    assert_parses_to!(Where::where_fn, input, WhereClause::WhereFn(WhereFn {
+        operator: edn::PlainSymbol::new("fulltext"),
+        args: vec![FnArg::SrcVar(SrcVar::DefaultSrc),
+                   FnArg::Ident(edn::NamespacedKeyword::new("foo", "text")),
+                   FnArg::Constant(NonIntegerConstant::Text("search".to_string()))],
+        binding: Binding::BindRel(vec![Some(Variable(edn::PlainSymbol::new("?e"))),
+                                       None,
+                                       Some(Variable(edn::PlainSymbol::new("?score")))]),
+    }));
+
+    let scalar = [edn::Value::Vector(vec![edn::Value::List(call.clone()),
+                                          edn::Value::PlainSymbol(edn::PlainSymbol::new("?e"))])];
+    assert_parses_to!(Where::where_fn, scalar, WhereClause::WhereFn(WhereFn {
+        operator: edn::PlainSymbol::new("fulltext"),
+        args: vec![FnArg::SrcVar(SrcVar::DefaultSrc),
+                   FnArg::Ident(edn::NamespacedKeyword::new("foo", "text")),
+                   FnArg::Constant(NonIntegerConstant::Text("search".to_string()))],
+        binding: Binding::BindScalar(Variable(edn::PlainSymbol::new("?e"))),
+    }));
+
+    let coll = [edn::Value::Vector(vec![edn::Value::List(call.clone()),
+                                        edn::Value::Vector(vec![edn::Value::PlainSymbol(edn::PlainSymbol::new("?e")),
+                                                                edn::Value::PlainSymbol(edn::PlainSymbol::new("..."))])])];
+    assert_parses_to!(Where::where_fn, coll, WhereClause::WhereFn(WhereFn {
+        operator: edn::PlainSymbol::new("fulltext"),
+        args: vec![FnArg::SrcVar(SrcVar::DefaultSrc),
+                   FnArg::Ident(edn::NamespacedKeyword::new("foo", "text")),
+                   FnArg::Constant(NonIntegerConstant::Text("search".to_string()))],
+        binding: Binding::BindColl(Variable(edn::PlainSymbol::new("?e"))),
+    }));
+
+    // Placeholders can't be bound as scalars.
+    let placeholder = [edn::Value::Vector(vec![edn::Value::List(call),
+                                               edn::Value::PlainSymbol(edn::PlainSymbol::new("_"))])];
+    let mut par = Where::where_fn();
+    assert!(par.parse(&placeholder[..]).is_err());
+}
+
+#[test]
+fn test_pred() {
+    let call: ::std::collections::LinkedList<edn::Value> = vec![
+        edn::Value::PlainSymbol(edn::PlainSymbol::new("between")),
+        edn::Value::PlainSymbol(edn::PlainSymbol::new("?t")),
+        edn::Value::Instant(1483228800000),
+        edn::Value::Integer(1485907200000),
+    ].into_iter().collect();
+    let input = [edn::Value::Vector(vec![edn::Value::List(call.clone())])];
+    assert_parses_to!(Where::pred, input, WhereClause::Pred(Predicate {
+        operator: edn::PlainSymbol::new("between"),
+        args: vec![FnArg::Variable(Variable(edn::PlainSymbol::new("?t"))),
+                   FnArg::Constant(NonIntegerConstant::Instant(1483228800000)),
+                   FnArg::EntidOrInteger(1485907200000)],
+    }));
+
+    // A call with a binding is a where-function, not a predicate.
+    let bound = [edn::Value::Vector(vec![edn::Value::List(call),
+                                         edn::Value::PlainSymbol(edn::PlainSymbol::new("?x"))])];
+    let mut par = Where::pred();
+    assert!(par.parse(&bound[..]).is_err());
+}
+
+#[test]
+fn test_or_join() {
+    let x = Variable(edn::PlainSymbol::new("?x"));
+    let y = Variable(edn::PlainSymbol::new("?y"));
+    let pattern = |e: &Variable, a: &str, v: PatternValuePlace| {
+        WhereClause::Pattern(Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Variable(e.clone()),
+            attribute: PatternNonValuePlace::Ident(edn::NamespacedKeyword::new("foo", a)),
+            value: v,
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
+        })
+    };
+
+    let input = [edn::parse::value("(or [?x :foo/bar 1] (and [?x :foo/baz ?y] [?y :foo/bar 2]))").unwrap()];
+    assert_parses_to!(Where::or_join, input, WhereClause::OrJoin(OrJoin {
+        unify_vars: UnifyVars::Implicit,
+        clauses: vec![
+            OrWhereClause::Clause(pattern(&x, "bar", PatternValuePlace::EntidOrInteger(1))),
+            OrWhereClause::And(vec![pattern(&x, "baz", PatternValuePlace::Variable(y.clone())),
+                                    pattern(&y, "bar", PatternValuePlace::EntidOrInteger(2))]),
+        ],
+    }));
+
+    let input = [edn::parse::value("(or-join [?x] [?x :foo/bar ?y])").unwrap()];
+    assert_parses_to!(Where::or_join, input, WhereClause::OrJoin(OrJoin {
+        unify_vars: UnifyVars::Explicit(vec![x.clone()]),
+        clauses: vec![OrWhereClause::Clause(pattern(&x, "bar", PatternValuePlace::Variable(y.clone())))],
+    }));
+
+    // `or-join` must declare its variables, and `or` must have a leg.
+    for bad in ["(or-join [?x ?y])", "(or-join [] [?x :foo/bar 1])", "(or-join [:foo] [?x :foo/bar 1])", "(or)"].iter() {
+        let input = [edn::parse::value(bad).unwrap()];
+        let mut par = Where::or_join();
+        assert!(par.parse(&input[..]).is_err(), "{} should not parse", bad);
+    }
+}
+
+#[test]
+fn test_not_join() {
+    let x = Variable(edn::PlainSymbol::new("?x"));
+    let pattern = WhereClause::Pattern(Pattern {
+        source: None,
+        entity: PatternNonValuePlace::Variable(x.clone()),
+        attribute: PatternNonValuePlace::Ident(edn::NamespacedKeyword::new("foo", "bar")),
+        value: PatternValuePlace::Variable(Variable(edn::PlainSymbol::new("?y"))),
+        tx: PatternNonValuePlace::Placeholder,
+        added: PatternValuePlace::Placeholder,
+    });
+
+    let input = [edn::parse::value("(not [?x :foo/bar ?y])").unwrap()];
+    assert_parses_to!(Where::not_join, input, WhereClause::NotJoin(NotJoin {
+        unify_vars: UnifyVars::Implicit,
+        clauses: vec![pattern.clone()],
+    }));
+
+    let input = [edn::parse::value("(not-join [?x] [?x :foo/bar ?y])").unwrap()];
+    assert_parses_to!(Where::not_join, input, WhereClause::NotJoin(NotJoin {
+        unify_vars: UnifyVars::Explicit(vec![x]),
+        clauses: vec![pattern],
+    }));
+
+    // `and` only appears within `or`.
+    let input = [edn::parse::value("(not (and [?x :foo/bar ?y]))").unwrap()];
+    let mut par = Where::not_join();
+    assert!(par.parse(&input[..]).is_err());
+}
+
 // Parse a sequence of values into one of four find specs.
 //
 // `:find` must be an array of plain var symbols (?foo), pull expressions, and aggregates.
@@ -232,10 +937,22 @@ fn test_find_tuple() {
 //     `[?x ?y ?z]`     = FindTuple
 //
 pub fn find_seq_to_find_spec(find: &[edn::Value]) -> FindParseResult {
-    FindSp::find()
+    let spec = FindSp::find()
         .parse(find)
         .map(|x| x.0)
-        .map_err(|_| FindParseError::Err)
+        .map_err(|_| FindParseError::Err)?;
+
+    // Results are keyed by element name, so names must be unique.  The same variable can be
+    // projected more than once by aliasing it.
+    let mut names = BTreeSet::new();
+    for element in spec.elements() {
+        let name = element.name();
+        if names.contains(&name) {
+            return Err(FindParseError::DuplicateName(name));
+        }
+        names.insert(name);
+    }
+    Ok(spec)
 }
 
 #[test]
@@ -263,3 +980,69 @@ fn test_find_processing() {
                                          Element::Variable(Variable(vy.clone()))])),
                find_seq_to_find_spec(&rel));
 }
+
+#[test]
+fn test_find_count() {
+    let vx = edn::PlainSymbol::new("?x");
+    let count = |var: &edn::PlainSymbol| {
+        edn::Value::List(vec![edn::Value::PlainSymbol(edn::PlainSymbol::new("count")),
+                              edn::Value::PlainSymbol(var.clone())].into_iter().collect())
+    };
+
+    let scalar = [count(&vx), edn::Value::PlainSymbol(edn::PlainSymbol::new("."))];
+    assert_eq!(Ok(FindSpec::FindScalar(Element::Count(Variable(vx.clone())))),
+               find_seq_to_find_spec(&scalar));
+
+    // Only variables can be counted.
+    let constant = [edn::Value::List(vec![edn::Value::PlainSymbol(edn::PlainSymbol::new("count")),
+                                          edn::Value::Integer(1)].into_iter().collect()),
+                    edn::Value::PlainSymbol(edn::PlainSymbol::new("."))];
+    assert!(find_seq_to_find_spec(&constant).is_err());
+}
+
+#[test]
+fn test_find_aliases() {
+    let vx = edn::PlainSymbol::new("?x");
+    let as_ = edn::Value::Keyword(edn::Keyword::new("as"));
+    let id = edn::Value::NamespacedKeyword(edn::NamespacedKeyword::new("person", "id"));
+
+    // ?x ?x :as :person/id
+    let rel = [edn::Value::PlainSymbol(vx.clone()),
+               edn::Value::PlainSymbol(vx.clone()),
+               as_.clone(),
+               id.clone()];
+    assert_eq!(Ok(FindSpec::FindRel(vec![Element::Variable(Variable(vx.clone())),
+                                         Element::Aliased(Variable(vx.clone()), ":person/id".to_string())])),
+               find_seq_to_find_spec(&rel));
+
+    // [?x :as :id ...]
+    let coll = [edn::Value::Vector(vec![edn::Value::PlainSymbol(vx.clone()),
+                                        as_.clone(),
+                                        edn::Value::Keyword(edn::Keyword::new("id")),
+                                        edn::Value::PlainSymbol(edn::PlainSymbol::new("..."))])];
+    assert_eq!(Ok(FindSpec::FindColl(Element::Aliased(Variable(vx.clone()), ":id".to_string()))),
+               find_seq_to_find_spec(&coll));
+
+    // ?x ?x
+    let duplicate = [edn::Value::PlainSymbol(vx.clone()), edn::Value::PlainSymbol(vx.clone())];
+    assert_eq!(Err(FindParseError::DuplicateName("?x".to_string())),
+               find_seq_to_find_spec(&duplicate));
+
+    // ?x :as :person/id ?y :as :person/id
+    let duplicate = [edn::Value::PlainSymbol(vx.clone()), as_.clone(), id.clone(),
+                     edn::Value::PlainSymbol(edn::PlainSymbol::new("?y")), as_.clone(), id.clone()];
+    assert_eq!(Err(FindParseError::DuplicateName(":person/id".to_string())),
+               find_seq_to_find_spec(&duplicate));
+}
+
+// Parse a sequence of values into a sequence of where clauses.
+//
+// Right now only patterns are supported: `[?e :foo/bar ?v ?tx ?added]`, with optional
+// source, value, tx, and added; and functions that bind a relation, like
+// `[(fulltext $ :foo/text "search") [[?e ?text]]]`.
+pub fn clause_seq_to_patterns(clauses: &[edn::Value]) -> WhereParseResult {
+    Where::clauses()
+        .parse(clauses)
+        .map(|x| x.0)
+        .map_err(|_| WhereParseError::Err)
+}
@@ -0,0 +1,166 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Rendering query parse errors for people, with an excerpt of the query pointing at the fault.
+//!
+//! Only EDN syntax errors carry a position.  Other errors name the offending value, so we look for
+//! its text in the query; when it can't be found, the rendering has no excerpt.
+
+extern crate edn;
+
+use std::iter;
+
+use super::error::{FindParseError, QueryParseError, WhereParseError};
+
+/// A run of characters in query text.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct Span {
+    /// The line of the first character, counting from 1.
+    pub line: usize,
+    /// The column of the first character, counting characters from 1.
+    pub column: usize,
+    /// The number of characters, at least 1.
+    pub len: usize,
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "()[]{},".contains(c)
+}
+
+/// Return the byte offset of the `n`th whole-token occurrence of `token` in `query`.
+fn find_token(query: &str, token: &str, n: usize) -> Option<usize> {
+    query.match_indices(token)
+        .map(|(offset, _)| offset)
+        .filter(|&offset| {
+            let before = query[..offset].chars().next_back();
+            let after = query[offset + token.len()..].chars().next();
+            before.map_or(true, |c| is_delimiter(c) || c == '#') && after.map_or(true, is_delimiter)
+        })
+        .nth(n)
+}
+
+/// Return the span of `len` characters starting at the given byte offset into `query`.
+fn span_at(query: &str, offset: usize, len: usize) -> Span {
+    let before = &query[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Span {
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+        len: if len == 0 { 1 } else { len },
+    }
+}
+
+fn token_span(query: &str, token: &str, n: usize) -> Option<Span> {
+    find_token(query, token, n).map(|offset| span_at(query, offset, token.chars().count()))
+}
+
+/// Return the span of the query text responsible for `error`, if it can be found.
+pub fn error_span(query: &str, error: &QueryParseError) -> Option<Span> {
+    match error {
+        &QueryParseError::EdnParseError(ref e) => Some(Span { line: e.line, column: e.column, len: 1 }),
+        &QueryParseError::InvalidInput(ref value) => token_span(query, &value.to_string(), 0),
+        &QueryParseError::MissingField(_) => None,
+        &QueryParseError::FindParseError(FindParseError::DuplicateName(ref name)) => token_span(query, name, 1),
+        &QueryParseError::FindParseError(_) => token_span(query, ":find", 0),
+        &QueryParseError::WhereParseError(_) => token_span(query, ":where", 0),
+    }
+}
+
+fn message(error: &QueryParseError) -> String {
+    match error {
+        &QueryParseError::EdnParseError(_) => "invalid EDN".to_string(),
+        &QueryParseError::InvalidInput(ref value) => format!("unexpected `{}`", value),
+        &QueryParseError::MissingField(ref keyword) => format!("missing `{}`", keyword.to_string()),
+        &QueryParseError::FindParseError(FindParseError::DuplicateName(ref name)) => format!("`{}` is found twice", name),
+        &QueryParseError::FindParseError(FindParseError::Err) => "invalid `:find`".to_string(),
+        &QueryParseError::WhereParseError(WhereParseError::Err) => "invalid `:where`".to_string(),
+    }
+}
+
+/// Return a suggestion for fixing an `InvalidInput` error about `value`.  The parser reports the
+/// `:in` binding at fault, the arguments of `:as-of`, `:since`, `:history`, or `:idents`, or the
+/// whole query, so we tell them apart by their shape.
+fn invalid_input_hint(value: &edn::Value) -> &'static str {
+    let is_binding = |x: &edn::Value| match x {
+        &edn::Value::PlainSymbol(ref s) => s.0.starts_with('?') || s.0 == "...",
+        &edn::Value::Vector(_) => true,
+        _ => false,
+    };
+    match value {
+        // Only `:in` reports a lone variable, when it binds it a second time.
+        &edn::Value::PlainSymbol(ref s) if s.0.starts_with('?') => "each variable can be bound only once in `:in`",
+        &edn::Value::PlainSymbol(_) => "expected a variable like `?x`",
+        &edn::Value::Vector(ref items) if items.first().map_or(false, |x| x.is_keyword()) =>
+            "a query is a vector or map of clauses like `:find`, `:in`, and `:where`",
+        &edn::Value::Vector(ref items) if items.iter().any(is_binding) =>
+            "bindings look like `?x`, `[?x ...]`, or `[[?x ?y]]`, and bind each variable once",
+        &edn::Value::Vector(_) =>
+            "`:as-of` and `:since` take a transaction ID or an `#inst`; `:history` and `:idents` take `true` or `false`",
+        _ => "a query is a vector or map of clauses like `:find`, `:in`, and `:where`",
+    }
+}
+
+/// Return a suggestion for fixing `error` in `query`.
+pub fn error_hint(query: &str, error: &QueryParseError) -> String {
+    match error {
+        &QueryParseError::EdnParseError(ref e) => {
+            if e.offset >= query.len() {
+                "the query ends early; check for an unclosed bracket or string".to_string()
+            } else {
+                let mut expected: Vec<&str> = e.expected.iter().cloned().collect();
+                expected.sort();
+                format!("expected one of {}", expected.join(", "))
+            }
+        },
+        &QueryParseError::InvalidInput(ref value) => invalid_input_hint(value).to_string(),
+        &QueryParseError::MissingField(ref keyword) =>
+            format!("every query needs a `{}` clause", keyword.to_string()),
+        &QueryParseError::FindParseError(FindParseError::DuplicateName(_)) =>
+            "give one of them another name with `:as`".to_string(),
+        &QueryParseError::FindParseError(FindParseError::Err) =>
+            "expected a find spec like `?x`, `?x .`, `[?x ...]`, or `[?x ?y]`".to_string(),
+        &QueryParseError::WhereParseError(WhereParseError::Err) =>
+            "expected clauses like `[?e :person/name ?name]` or `[(< ?age 18)]`".to_string(),
+    }
+}
+
+/// Render `error`, from parsing `query`, as a multi-line message: what went wrong, the line of the
+/// query at fault with carets under the offending text, and a hint.
+///
+/// ```text
+/// unexpected `x` at line 1, column 15
+///   |
+/// 1 | [:find ?x :in x :where [?x :foo/bar ?y]]
+///   |               ^
+/// hint: expected a variable like `?x`
+/// ```
+pub fn render_error(query: &str, error: &QueryParseError) -> String {
+    let mut out = message(error);
+    if let Some(span) = error_span(query, error) {
+        let text = query.lines().nth(span.line - 1).unwrap_or("");
+        let number = span.line.to_string();
+        let gutter: String = iter::repeat(' ').take(number.len()).collect();
+        // Keep tabs, so that the carets line up however the tabs are displayed.
+        let indent: String = text.chars()
+            .take(span.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets: String = iter::repeat('^').take(span.len).collect();
+        out.push_str(&format!(" at line {}, column {}\n", span.line, span.column));
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", number, text));
+        out.push_str(&format!("{} | {}{}\n", gutter, indent, carets));
+    } else {
+        out.push('\n');
+    }
+    out.push_str("hint: ");
+    out.push_str(&error_hint(query, error));
+    out
+}
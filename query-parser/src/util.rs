@@ -48,6 +48,10 @@ fn test_values_to_variables() {
     // TODO
 }
 
+fn is_as_keyword(v: &edn::Value) -> bool {
+    v.matches_kw(None, "as")
+}
+
 /// Take a slice of EDN values, as would be extracted from an
 /// `edn::Value::Vector`, and turn it into a map.
 ///
@@ -87,20 +91,30 @@ pub fn vec_to_keyword_map(vec: &[edn::Value]) -> Option<BTreeMap<edn::Keyword, V
         }
 
         // The first item must be a keyword.
-        if let edn::Value::Keyword(ref k) = slice[0] {
+        if let Some(k) = slice[0].as_keyword() {
 
             // The second can't be: [:foo :bar 1 2 3] is invalid.
             if slice[1].is_keyword() {
                 return None;
             }
 
-            // Accumulate items until we reach the next keyword.
+            // Accumulate items until we reach the next keyword.  `:as` isn't a key: it and the
+            // value following it name the preceding item, as in `:find ?x :as :id`.
             let mut acc = Vec::new();
-            for v in &slice[1..] {
+            let mut i = 1;
+            while i < slice.len() {
+                let v = &slice[i];
+                if is_as_keyword(v) && i + 1 < slice.len() {
+                    acc.push(v.clone());
+                    acc.push(slice[i + 1].clone());
+                    i += 2;
+                    continue;
+                }
                 if v.is_keyword() {
                     break;
                 }
                 acc.push(v.clone());
+                i += 1;
             }
             return Some((k.clone(), acc));
         }
@@ -181,6 +195,19 @@ fn test_vec_to_keyword_map() {
                                         edn::Value::Keyword(bar.clone()),
                                         edn::Value::Integer(1))));
 
+    // `:as` and its alias are values, not keys.
+    let as_ = edn::symbols::Keyword("as".to_string());
+    let m = vec_to_keyword_map(&vec!(edn::Value::Keyword(foo.clone()),
+                                     edn::Value::Integer(1),
+                                     edn::Value::Keyword(as_.clone()),
+                                     edn::Value::Keyword(baz.clone()),
+                                     edn::Value::Keyword(bar.clone()),
+                                     edn::Value::Integer(2))).unwrap();
+    assert_eq!(m.get(&foo).unwrap(), &vec!(edn::Value::Integer(1),
+                                           edn::Value::Keyword(as_.clone()),
+                                           edn::Value::Keyword(baz.clone())));
+    assert_eq!(m.get(&bar).unwrap(), &vec!(edn::Value::Integer(2)));
+
     // Empty lists return an empty map.
     assert_eq!(BTreeMap::new(), vec_to_keyword_map(&vec!()).unwrap());
 }
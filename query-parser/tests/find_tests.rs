@@ -14,8 +14,10 @@ extern crate edn;
 
 use mentat_query::FindSpec::*;
 use mentat_query::Element;
+use mentat_query::PointInTime;
 use mentat_query::Variable;
 use edn::PlainSymbol;
+use mentat_query_parser::{Span, error_span, parse_find_string, render_error};
 
 ///! N.B., parsing a query can be done without reference to a DB.
 ///! Processing the parsed query into something we can work with
@@ -33,3 +35,137 @@ fn can_parse_trivial_find() {
         panic!()
     }
 }
+
+#[test]
+fn can_parse_simple_where() {
+    let query = r#"[:find ?x :where [?x :foo/bar "yyy"]]"#;
+    let parsed = mentat_query_parser::parse_find_string(query).expect("query to parse");
+    assert_eq!(1, parsed.where_clauses.len());
+    assert_eq!(FindRel(vec![Element::Variable(Variable(PlainSymbol("?x".to_string())))]),
+               parsed.find_spec);
+}
+
+#[test]
+fn can_parse_in_vars() {
+    let query = r#"[:find ?x :in $ ?name :where [?x :foo/bar ?name]]"#;
+    let parsed = mentat_query_parser::parse_find_string(query).expect("query to parse");
+    assert_eq!(vec![Variable(PlainSymbol("?name".to_string()))], parsed.in_vars);
+
+    let query = r#"[:find ?x :in $ ?name ?name :where [?x :foo/bar ?name]]"#;
+    assert!(mentat_query_parser::parse_find_string(query).is_err());
+}
+
+#[test]
+fn can_parse_in_rels() {
+    let name = Variable(PlainSymbol("?name".to_string()));
+    let age = Variable(PlainSymbol("?age".to_string()));
+    let query = r#"[:find ?x :in $ [?name ...] :where [?x :foo/bar ?name]]"#;
+    let parsed = mentat_query_parser::parse_find_string(query).expect("query to parse");
+    assert!(parsed.in_vars.is_empty());
+    assert_eq!(vec![vec![name.clone()]], parsed.in_rels);
+
+    let query = r#"[:find ?x :in $ ?x [[?name ?age]] :where [?x :foo/bar ?name] [?x :foo/baz ?age]]"#;
+    let parsed = mentat_query_parser::parse_find_string(query).expect("query to parse");
+    assert_eq!(vec![Variable(PlainSymbol("?x".to_string()))], parsed.in_vars);
+    assert_eq!(vec![vec![name, age]], parsed.in_rels);
+
+    for query in &[r#"[:find ?x :in $ ?name [?name ...] :where [?x :foo/bar ?name]]"#,
+                   r#"[:find ?x :in $ [[?name ?name]] :where [?x :foo/bar ?name]]"#,
+                   r#"[:find ?x :in $ [?name] :where [?x :foo/bar ?name]]"#,
+                   r#"[:find ?x :in $ [[]] :where [?x :foo/bar ?name]]"#] {
+        assert!(mentat_query_parser::parse_find_string(query).is_err(), "expected {} not to parse", query);
+    }
+}
+
+#[test]
+fn can_parse_aliased_find() {
+    let query = r#"[:find ?x :as :person/id ?x :where [?x :foo/bar "yyy"]]"#;
+    let parsed = mentat_query_parser::parse_find_string(query).expect("query to parse");
+    assert_eq!(FindRel(vec![Element::Aliased(Variable(PlainSymbol("?x".to_string())), ":person/id".to_string()),
+                            Element::Variable(Variable(PlainSymbol("?x".to_string())))]),
+               parsed.find_spec);
+
+    let query = r#"[:find ?x ?x :where [?x :foo/bar "yyy"]]"#;
+    assert!(mentat_query_parser::parse_find_string(query).is_err());
+}
+
+#[test]
+fn can_parse_time_travel() {
+    let query = r#"[:find ?x :as-of 268435460 :where [?x :foo/bar "yyy"]]"#;
+    let parsed = mentat_query_parser::parse_find_string(query).expect("query to parse");
+    assert_eq!(Some(PointInTime::Tx(268435460)), parsed.as_of);
+    assert_eq!(None, parsed.since);
+
+    let query = r#"{:find [?x] :where [[?x :foo/bar "yyy"]] :since #inst "2017-01-01T00:00:00Z"}"#;
+    let parsed = mentat_query_parser::parse_find_string(query).expect("query to parse");
+    assert_eq!(None, parsed.as_of);
+    assert_eq!(Some(PointInTime::Instant(1483228800000)), parsed.since);
+
+    let query = r#"[:find ?x :as-of 1 2 :where [?x :foo/bar "yyy"]]"#;
+    assert!(mentat_query_parser::parse_find_string(query).is_err());
+    let query = r#"[:find ?x :since "yesterday" :where [?x :foo/bar "yyy"]]"#;
+    assert!(mentat_query_parser::parse_find_string(query).is_err());
+}
+
+#[test]
+fn can_parse_history() {
+    let query = r#"[:find ?v ?tx ?added :history true :where [?x :foo/bar ?v ?tx ?added]]"#;
+    let parsed = mentat_query_parser::parse_find_string(query).expect("query to parse");
+    assert!(parsed.history);
+
+    let query = r#"{:find [?v] :where [[?x :foo/bar ?v _ false]] :history true}"#;
+    assert!(mentat_query_parser::parse_find_string(query).expect("query to parse").history);
+
+    let query = r#"[:find ?x :where [?x :foo/bar "yyy"]]"#;
+    assert!(!mentat_query_parser::parse_find_string(query).expect("query to parse").history);
+
+    let query = r#"[:find ?x :history 1 :where [?x :foo/bar "yyy"]]"#;
+    assert!(mentat_query_parser::parse_find_string(query).is_err());
+}
+
+#[test]
+fn can_parse_idents() {
+    let query = r#"[:find ?v :idents true :where [?x :foo/bar ?v]]"#;
+    assert!(mentat_query_parser::parse_find_string(query).expect("query to parse").idents);
+
+    let query = r#"{:find [?v] :where [[?x :foo/bar ?v]] :idents true}"#;
+    assert!(mentat_query_parser::parse_find_string(query).expect("query to parse").idents);
+
+    let query = r#"[:find ?v :where [?x :foo/bar ?v]]"#;
+    assert!(!mentat_query_parser::parse_find_string(query).expect("query to parse").idents);
+
+    let query = r#"[:find ?v :idents :yes :where [?x :foo/bar ?v]]"#;
+    assert!(mentat_query_parser::parse_find_string(query).is_err());
+}
+
+#[test]
+fn can_render_errors() {
+    let render = |query: &str| render_error(query, &parse_find_string(query).unwrap_err());
+
+    assert_eq!(render("[:find ?x :in x :where [?x :foo/bar ?y]]"),
+               "unexpected `x` at line 1, column 15\n  \
+                  |\n\
+                1 | [:find ?x :in x :where [?x :foo/bar ?y]]\n  \
+                  |               ^\n\
+                hint: expected a variable like `?x`");
+
+    // The excerpt is the line at fault, and tabs before the fault are kept.
+    let query = "[:find ?x ?x\n\t:where [?x :foo/bar ?y]]";
+    assert_eq!(error_span(query, &parse_find_string(query).unwrap_err()),
+               Some(Span { line: 1, column: 11, len: 2 }));
+    let rendered = render("[:find ?x\n\t:in $ x\n\t:where [?x :foo/bar ?y]]");
+    assert!(rendered.contains("2 | \t:in $ x\n  | \t      ^\n"), "{}", rendered);
+
+    // EDN errors point at where reading stopped.
+    let rendered = render("[:find ?x\n :where [?x :foo/bar ?y]");
+    assert!(rendered.starts_with("invalid EDN at line 2, column 25\n"), "{}", rendered);
+    assert!(rendered.ends_with("hint: the query ends early; check for an unclosed bracket or string"));
+
+    // Hints match the fault.
+    assert!(render("[:find ?x :in ?x ?x :where [?x :foo/bar ?y]]").ends_with("hint: each variable can be bound only once in `:in`"));
+    assert!(render("[:find ?x :in [?x] :where [?x :foo/bar ?y]]").ends_with("hint: bindings look like `?x`, `[?x ...]`, or `[[?x ?y]]`, and bind each variable once"));
+    assert!(render("[:find ?x :history 1 :where [?x :foo/bar ?y]]").ends_with("`:history` and `:idents` take `true` or `false`"));
+
+    // Some errors have nothing to point at.
+    assert_eq!(render("[:find ?x]"), "missing `:where`\nhint: every query needs a `:where` clause");
+}
@@ -0,0 +1,1671 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! This module implements the *conjoining clauses* (CC) approach to translating Datalog `:where`
+//! clauses into SQL: each pattern contributes a table alias to the `FROM` list, a set of column
+//! constraints to the `WHERE` list, and bindings from query variables to qualified columns.
+//!
+//! Each time a variable is bound to a second column, we add a constraint equating the two columns:
+//! that's the join.
+//!
+//! `not` and `or` open nested scopes, each with its own `ConjoiningClauses`.  The body of a `not`
+//! becomes a correlated `NOT EXISTS` subquery, and the legs of an `or` become a `UNION` subquery in
+//! the `FROM` list.  Only the unified variables cross a scope: variables bound within a `not`, or
+//! by just one leg of an `or-join`, are local to it.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use mentat_db::{Attribute, Entid, Schema, TypedValue, ValueType, TUPLE_VALUE_TYPE_TAG, fulltext_table_name};
+use mentat_query::{
+    Binding,
+    FnArg,
+    NonIntegerConstant,
+    NotJoin,
+    OrJoin,
+    Pattern,
+    PatternNonValuePlace,
+    PatternValuePlace,
+    Predicate,
+    SrcVar,
+    UnifyVars,
+    Variable,
+    WhereClause,
+    WhereFn,
+};
+
+use errors::*;
+
+/// One of the named tables or views holding datoms.
+#[derive(Clone,Copy,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum DatomsTable {
+    /// The non-fulltext datoms table.
+    Datoms,
+    /// The fulltext datoms view, which interpolates fulltext values.
+    FulltextDatoms,
+    /// The union of `Datoms` and `FulltextDatoms`, for when the attribute is unknown.
+    AllDatoms,
+    /// The fulltext values matching a search, with the `Rowid`, `Text`, `Score`, and `Snippet`
+    /// columns.  The searches are in `ConjoiningClauses::fulltext_searches`.
+    FulltextValues,
+    /// The JSON documents in the store, in the `Json` column, with the value at a path extracted
+    /// into the `Value` and `ValueTypeTag` columns.  The paths are in
+    /// `ConjoiningClauses::json_paths`.
+    JsonValues,
+    /// The tuples in the store, in the `Tuple` column, with the component at an index extracted
+    /// into the `Value` and `ValueTypeTag` columns.  The indexes are in
+    /// `ConjoiningClauses::tuple_indexes`.
+    TupleValues,
+    /// The instants in the store, in the `Instant` column, with a date or truncated instant
+    /// computed into the `Value` and `ValueTypeTag` columns.  The computations are in
+    /// `ConjoiningClauses::instant_fns`.
+    InstantValues,
+    /// The rows matching any leg of an `or`, with a `Unified` and a `UnifiedTypeTag` column for
+    /// each unified variable.  The legs are in `ConjoiningClauses::unions`.
+    Union,
+    /// The rows of a collection or relation input, with a `Unified` and a `UnifiedTypeTag` column
+    /// for each of its variables.  The rows are in `ConjoiningClauses::input_rows`.
+    Inputs,
+    /// The entities reachable by repeatedly following a ref attribute, with the `Start`, `Entity`,
+    /// and `Depth` columns.  The walks are in `ConjoiningClauses::closures`.
+    Closure,
+    /// The IDs of a range of transactions, in the `Tx` column.  The ranges are in
+    /// `ConjoiningClauses::tx_ranges`.
+    TxIds,
+    /// The datoms transactions asserted and retracted, read from the log, with the `Entity`,
+    /// `Attribute`, `Value`, `Tx`, `ValueTypeTag`, and `Added` columns.  What's needed to read them
+    /// is in `ConjoiningClauses::tx_data`.
+    TxData,
+}
+
+impl DatomsTable {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DatomsTable::Datoms => "datoms",
+            DatomsTable::FulltextDatoms => "fulltext_datoms",
+            DatomsTable::AllDatoms => "all_datoms",
+            DatomsTable::FulltextValues => "fulltext_values",
+            DatomsTable::JsonValues => "json_values",
+            DatomsTable::TupleValues => "tuple_values",
+            DatomsTable::InstantValues => "instant_values",
+            DatomsTable::Union => "union",
+            DatomsTable::Inputs => "inputs",
+            DatomsTable::Closure => "closure",
+            DatomsTable::TxIds => "tx_ids",
+            DatomsTable::TxData => "tx_data",
+        }
+    }
+}
+
+/// One of the columns of a table in the `FROM` list.
+#[derive(Clone,Copy,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum DatomsColumn {
+    Entity,
+    Attribute,
+    Value,
+    Tx,
+    ValueTypeTag,
+    /// The rowid of a fulltext value, which fulltext datoms hold in their value column.
+    Rowid,
+    /// The text of a fulltext value.
+    Text,
+    /// The relevance of a fulltext value to the search: higher is better.
+    Score,
+    /// An excerpt of a fulltext value, with the search terms highlighted.
+    Snippet,
+    /// A JSON document.
+    Json,
+    /// A tuple, in its stored form.
+    Tuple,
+    /// An instant, in milliseconds since the Unix epoch.
+    Instant,
+    /// Whether a logged datom was asserted or retracted.  Only the log has this column.
+    Added,
+    /// The entity a walk of a `Closure` starts from.
+    Start,
+    /// The least number of steps a walk of a `Closure` takes to reach its entity.
+    Depth,
+    /// The value of the nth unified variable of a `Union`, or the nth variable of an `Inputs`.
+    Unified(usize),
+    /// The value type tag of the nth unified variable of a `Union`, or the nth variable of an
+    /// `Inputs`.
+    UnifiedTypeTag(usize),
+}
+
+impl DatomsColumn {
+    pub fn name(&self) -> String {
+        match *self {
+            DatomsColumn::Entity => "e".to_string(),
+            DatomsColumn::Attribute => "a".to_string(),
+            DatomsColumn::Value => "v".to_string(),
+            DatomsColumn::Tx => "tx".to_string(),
+            DatomsColumn::ValueTypeTag => "value_type_tag".to_string(),
+            DatomsColumn::Rowid => "rowid".to_string(),
+            DatomsColumn::Text => "text".to_string(),
+            DatomsColumn::Score => "score".to_string(),
+            DatomsColumn::Snippet => "snippet".to_string(),
+            DatomsColumn::Json => "json".to_string(),
+            DatomsColumn::Tuple => "tuple".to_string(),
+            DatomsColumn::Instant => "instant".to_string(),
+            DatomsColumn::Added => "added".to_string(),
+            DatomsColumn::Start => "start".to_string(),
+            DatomsColumn::Depth => "depth".to_string(),
+            DatomsColumn::Unified(i) => format!("v{}", i),
+            DatomsColumn::UnifiedTypeTag(i) => format!("value_type_tag{}", i),
+        }
+    }
+
+    /// `true` if this column holds values of any type, described by an accompanying type tag column.
+    pub fn is_tagged(&self) -> bool {
+        match *self {
+            DatomsColumn::Value | DatomsColumn::Unified(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A fulltext search: the FTS table to search, and the search terms.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct FulltextSearch {
+    /// `fulltext_values`, or the attribute's own fulltext table.
+    pub table: String,
+    pub terms: String,
+}
+
+/// A specific instance of a table within a query, e.g., `datoms00`.
+pub type TableAlias = String;
+
+/// The association between a table and its alias, e.g., `datoms AS datoms00`.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct SourceAlias(pub DatomsTable, pub TableAlias);
+
+/// A particular column of a particular aliased table, e.g., `datoms00.e`.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct QualifiedAlias(pub TableAlias, pub DatomsColumn);
+
+impl QualifiedAlias {
+    /// Return the alias of the `value_type_tag` column accompanying this column.
+    pub fn for_type_tag(&self) -> QualifiedAlias {
+        match self.1 {
+            DatomsColumn::Unified(i) => QualifiedAlias(self.0.clone(), DatomsColumn::UnifiedTypeTag(i)),
+            _ => QualifiedAlias(self.0.clone(), DatomsColumn::ValueTypeTag),
+        }
+    }
+}
+
+/// A constraint on a single column of a single aliased table.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum ColumnConstraint {
+    /// The column holds the given entid.
+    EqualsEntity(QualifiedAlias, Entid),
+    /// The (value) column holds the given typed value, with the corresponding value type tag.
+    EqualsValue(QualifiedAlias, TypedValue),
+    /// The (value) column holds the given integer as either a ref or a long.  Used when the
+    /// attribute, and hence the interpretation of the integer, is unknown.
+    EqualsRefOrLong(QualifiedAlias, i64),
+    /// The two columns are equal.  This is a join.
+    EqualsColumn(QualifiedAlias, QualifiedAlias),
+    /// The (value type tag) column holds the given tag.
+    HasTypeTag(QualifiedAlias, i32),
+    /// The (added) column holds the given boolean.  It has no type tag.
+    EqualsAdded(QualifiedAlias, bool),
+    /// The column holds a long at least the first bound and less than the second.
+    Between(QualifiedAlias, i64, i64),
+    /// The body of a `not` matches nothing.  Its unified variables are bound to the enclosing
+    /// columns, which makes it a correlated subquery.
+    NotExists(ConjoiningClauses),
+}
+
+/// A calendar unit to which instants can be truncated.
+#[derive(Clone,Copy,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum TimeUnit {
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+}
+
+impl TimeUnit {
+    fn from_name(name: &str) -> Option<TimeUnit> {
+        match name {
+            "year" => Some(TimeUnit::Year),
+            "month" => Some(TimeUnit::Month),
+            "day" => Some(TimeUnit::Day),
+            "hour" => Some(TimeUnit::Hour),
+            "minute" => Some(TimeUnit::Minute),
+            _ => None,
+        }
+    }
+}
+
+/// A computation over instants, in the time zone `offset` minutes ahead of UTC: the calendar date
+/// of an instant, like `"2017-01-31"`, or the instant at the start of its year, month, and so on.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum InstantFn {
+    Date { offset: i64 },
+    Truncate { unit: TimeUnit, offset: i64 },
+}
+
+/// Return the number of minutes by which a UTC offset like `"+05:30"` or `"-08:00"` is ahead of
+/// UTC, or `None` if `offset` isn't such an offset.  `"Z"` is UTC itself.
+pub fn parse_utc_offset(offset: &str) -> Option<i64> {
+    if offset == "Z" {
+        return Some(0);
+    }
+    let sign = match offset.chars().next() {
+        Some('+') => 1,
+        Some('-') => -1,
+        _ => return None,
+    };
+    let mut parts = offset[1..].splitn(2, ':');
+    let (hours, minutes) = match (parts.next(), parts.next()) {
+        (Some(hours), Some(minutes)) if hours.len() == 2 && minutes.len() == 2 => (hours, minutes),
+        _ => return None,
+    };
+    match (hours.parse::<i64>(), minutes.parse::<i64>()) {
+        (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 => Some(sign * (hours * 60 + minutes)),
+        _ => None,
+    }
+}
+
+/// The default limit on the number of steps of a walk of a `Closure`.  A walk never goes around a
+/// cycle, so this only bounds very long chains.
+pub const DEFAULT_MAX_DEPTH: i64 = 1000;
+
+/// A walk along a ref attribute, like `:foo/parent`: from each start entity to its value
+/// (`ancestors`), or to the entities whose value it is (`descendants`), and so on, at most
+/// `max_depth` steps.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct Closure {
+    pub attribute: Entid,
+    /// `true` to walk from entity to value, `false` from value to entity.
+    pub forward: bool,
+    /// The entity every walk starts from, if it's known ahead of time.  Otherwise, walks start from
+    /// every entity with the attribute.
+    pub start: Option<Entid>,
+    pub max_depth: i64,
+}
+
+/// The transactions with IDs at least `start` and less than `end`.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub struct TxRange {
+    pub start: Entid,
+    pub end: Entid,
+}
+
+/// How to read the datoms of transactions from the log, whose fulltext datoms hold the rowids of
+/// their values.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct TxData {
+    /// The fulltext attributes, whose values are interpolated from `fulltext_values`.
+    pub fulltext_attributes: Vec<Entid>,
+}
+
+/// The legs of an `or`.  Each leg binds every one of `vars`, which the union projects in order as
+/// its `Unified` columns.
+///
+/// Legs don't refer to the enclosing scope, so the rows of a union depend only on its `clause` and
+/// the query's inputs: every occurrence of the same `or` clause in a query has the same rows.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct Union {
+    pub clause: OrJoin,
+    pub vars: Vec<Variable>,
+    pub legs: Vec<ConjoiningClauses>,
+}
+
+/// A hint for how SQLite should read the datoms of a pattern, for when its query planner chooses
+/// badly.  Only patterns that read the `datoms` table itself, in a query of the current state of
+/// the store, can take a hint.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum IndexHint {
+    /// Read through the named index, like `idx_datoms_avet`.  SQLite refuses to run the query if
+    /// the index can't serve the pattern.
+    IndexedBy(String),
+    /// Scan the table rather than use any index.
+    NotIndexed,
+}
+
+/// Why a set of clauses cannot yield results in the context of the current schema and inputs.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum EmptyBecause {
+    /// An ident that isn't installed, like `:foo/bar` in `[?x :db/ident :foo/bar]`.
+    UnknownIdent(String),
+    /// The attribute place of a pattern names an ident or entid that isn't an attribute.
+    UnknownAttribute(String),
+    /// An attribute with the given value type can never have the given value.
+    ValueTypeMismatch(ValueType, TypedValue),
+    /// An input used in an entity place isn't an entity.
+    NonEntityInput(Variable),
+    /// The `added` place of a pattern holds something other than a boolean.
+    NonBooleanAdded,
+    /// A collection or relation input, named by its first variable, has no rows.
+    EmptyInput(Variable),
+}
+
+/// A `ConjoiningClauses` (CC) accumulates the translation of a conjunction of `:where` clauses.
+#[derive(Clone,Debug,Default,Eq,PartialEq)]
+pub struct ConjoiningClauses {
+    /// `true` if this set of clauses cannot yield results in the context of the current schema,
+    /// e.g., because it names an attribute that isn't installed.
+    pub is_known_empty: bool,
+
+    /// The first reason found that this set of clauses cannot yield results.
+    pub empty_because: Option<EmptyBecause>,
+
+    /// A counter used to generate unique table aliases.
+    alias_counter: usize,
+
+    /// The `FROM` list: the tables and views this query draws from.
+    pub from: Vec<SourceAlias>,
+
+    /// A map from variable to the columns it is bound to.  The first is canonical: the others are
+    /// constrained to be equal to it.
+    pub column_bindings: BTreeMap<Variable, Vec<QualifiedAlias>>,
+
+    /// The `WHERE` list: the constraints on the `FROM` list.
+    pub wheres: Vec<ColumnConstraint>,
+
+    /// Variables bound to values ahead of time, like the inputs named by `:in`.  Patterns use
+    /// these as constants.
+    pub value_bindings: BTreeMap<Variable, TypedValue>,
+
+    /// The search of each `FulltextValues` alias in the `FROM` list.
+    pub fulltext_searches: BTreeMap<TableAlias, FulltextSearch>,
+
+    /// The path extracted by each `JsonValues` alias in the `FROM` list.
+    pub json_paths: BTreeMap<TableAlias, String>,
+
+    /// The component index extracted by each `TupleValues` alias in the `FROM` list.
+    pub tuple_indexes: BTreeMap<TableAlias, i64>,
+
+    /// The computation of each `InstantValues` alias in the `FROM` list.
+    pub instant_fns: BTreeMap<TableAlias, InstantFn>,
+
+    /// The legs of each `Union` alias in the `FROM` list.
+    pub unions: BTreeMap<TableAlias, Union>,
+
+    /// The rows of each `Inputs` alias in the `FROM` list.
+    pub input_rows: BTreeMap<TableAlias, Vec<Vec<TypedValue>>>,
+
+    /// The walk of each `Closure` alias in the `FROM` list.
+    pub closures: BTreeMap<TableAlias, Closure>,
+
+    /// The range of each `TxIds` alias in the `FROM` list.
+    pub tx_ranges: BTreeMap<TableAlias, TxRange>,
+
+    /// How to read each `TxData` alias in the `FROM` list.
+    pub tx_data: BTreeMap<TableAlias, TxData>,
+
+    /// The alias of each pattern applied to this conjunction, in order.
+    pub pattern_aliases: Vec<TableAlias>,
+
+    /// Index hints for aliases in the `FROM` list.
+    pub index_hints: BTreeMap<TableAlias, IndexHint>,
+
+    /// The variables bound to the values of encrypted attributes.  Their columns hold ciphertext,
+    /// so they can't be joined or passed to where-functions.
+    pub encrypted_vars: BTreeSet<Variable>,
+
+    /// The variables first bound to the values of enumerations.  They project as the idents of
+    /// their values, like `:task.status/open`, rather than as entids.
+    pub enum_vars: BTreeSet<Variable>,
+}
+
+/// Describe an `or` or `not` clause for error messages, like `(or-join [?x ?y] ...)`.
+fn describe_join(operator: &str, unify_vars: &UnifyVars) -> String {
+    match *unify_vars {
+        UnifyVars::Implicit => format!("({} ...)", operator),
+        UnifyVars::Explicit(ref vars) => {
+            let names: Vec<&str> = vars.iter().map(|var| (var.0).0.as_str()).collect();
+            format!("({}-join [{}] ...)", operator, names.join(" "))
+        },
+    }
+}
+
+/// Return the variables mentioned by the given clauses.
+fn clause_variables<'c, T>(clauses: T) -> BTreeSet<Variable> where T: IntoIterator<Item = &'c WhereClause> {
+    let mut vars = BTreeSet::new();
+    for clause in clauses {
+        clause.collect_variables(&mut vars);
+    }
+    vars
+}
+
+impl ConjoiningClauses {
+    /// Return a new `ConjoiningClauses` with the given variables bound to values.
+    pub fn with_value_bindings(value_bindings: BTreeMap<Variable, TypedValue>) -> ConjoiningClauses {
+        ConjoiningClauses {
+            value_bindings: value_bindings,
+            ..Default::default()
+        }
+    }
+
+    /// Return an empty `ConjoiningClauses` for a nested scope, like the body of a `not` or a leg of
+    /// an `or`, with this one's inputs.  Aliases continue this one's, so that the nested scope can
+    /// refer to this one's tables.
+    fn nested(&self) -> ConjoiningClauses {
+        ConjoiningClauses {
+            alias_counter: self.alias_counter,
+            value_bindings: self.value_bindings.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn next_alias(&mut self, table: DatomsTable) -> TableAlias {
+        let alias = format!("{}{:02}", table.name(), self.alias_counter);
+        self.alias_counter += 1;
+        alias
+    }
+
+    fn mark_known_empty(&mut self, why: EmptyBecause) {
+        self.is_known_empty = true;
+        if self.empty_because.is_none() {
+            self.empty_because = Some(why);
+        }
+    }
+
+    /// Constrain the values in `column` to those with the given type tag.  Columns without a type
+    /// tag column hold values of a single type, and aren't constrained.
+    fn constrain_type_tag(&mut self, column: &QualifiedAlias, value_type_tag: i32) {
+        if column.1.is_tagged() {
+            self.wheres.push(ColumnConstraint::HasTypeTag(column.for_type_tag(), value_type_tag));
+        }
+    }
+
+    /// Bind `var` to `column`, joining against any existing binding.
+    fn bind_column_to_var(&mut self, var: Variable, column: QualifiedAlias) {
+        let bindings = self.column_bindings.entry(var).or_insert(vec![]);
+        if let Some(existing) = bindings.first() {
+            self.wheres.push(ColumnConstraint::EqualsColumn(existing.clone(), column.clone()));
+        }
+        bindings.push(column);
+    }
+
+    /// Return the canonical column bound to `var`, if any.
+    pub fn binding_for_var(&self, var: &Variable) -> Option<&QualifiedAlias> {
+        self.column_bindings.get(var).and_then(|bindings| bindings.first())
+    }
+
+    /// `true` if `var` is bound to a column or to a value.
+    pub fn is_bound(&self, var: &Variable) -> bool {
+        self.column_bindings.contains_key(var) || self.value_bindings.contains_key(var)
+    }
+
+    fn entid_for_ident(&mut self, schema: &Schema, ident: &String) -> Option<Entid> {
+        let entid = schema.get_entid(ident).cloned();
+        if entid.is_none() {
+            // An unknown ident can't match anything.
+            self.mark_known_empty(EmptyBecause::UnknownIdent(ident.clone()));
+        }
+        entid
+    }
+
+    fn constrain_non_value_place(&mut self, schema: &Schema, column: QualifiedAlias, place: &PatternNonValuePlace) {
+        match *place {
+            PatternNonValuePlace::Placeholder => (),
+            PatternNonValuePlace::Variable(ref var) => {
+                match self.value_bindings.get(var).cloned() {
+                    // Only entities can appear in non-value places.
+                    Some(TypedValue::Ref(entid)) | Some(TypedValue::Long(entid)) => self.wheres.push(ColumnConstraint::EqualsEntity(column, entid)),
+                    Some(_) => self.mark_known_empty(EmptyBecause::NonEntityInput(var.clone())),
+                    None => self.bind_column_to_var(var.clone(), column),
+                }
+            },
+            PatternNonValuePlace::Entid(entid) => self.wheres.push(ColumnConstraint::EqualsEntity(column, entid)),
+            PatternNonValuePlace::Ident(ref kw) => {
+                if let Some(entid) = self.entid_for_ident(schema, &kw.to_string()) {
+                    self.wheres.push(ColumnConstraint::EqualsEntity(column, entid));
+                }
+            },
+        }
+    }
+
+    fn constrain_value(&mut self, column: QualifiedAlias, attribute: Option<&Attribute>, typed_value: TypedValue) {
+        if let Some(attribute) = attribute {
+            // Instants are stored as longs.
+            let instant = attribute.value_type == ValueType::Instant && typed_value.value_type() == ValueType::Long;
+            if attribute.value_type != typed_value.value_type() && !instant {
+                // The attribute can never have this value.
+                self.mark_known_empty(EmptyBecause::ValueTypeMismatch(attribute.value_type.clone(), typed_value));
+                return;
+            }
+        }
+        self.wheres.push(ColumnConstraint::EqualsValue(column, typed_value));
+    }
+
+    fn constrain_value_place(&mut self, schema: &Schema, column: QualifiedAlias, attribute: Option<&Attribute>, place: &PatternValuePlace) -> Result<()> {
+        match *place {
+            PatternValuePlace::Placeholder => (),
+            PatternValuePlace::Variable(ref var) => {
+                match self.value_bindings.get(var).cloned() {
+                    // A long input for a ref attribute names an entity.
+                    Some(TypedValue::Long(x)) if attribute.map_or(false, |a| a.value_type == ValueType::Ref) => {
+                        self.wheres.push(ColumnConstraint::EqualsEntity(column, x));
+                    },
+                    // So does a keyword input, like an enumeration's `:task.status/open`.
+                    Some(TypedValue::Keyword(ref kw)) if attribute.map_or(false, |a| a.value_type == ValueType::Ref) => {
+                        let ident = kw.to_string();
+                        match schema.get_entid(&ident).cloned() {
+                            Some(entid) => self.wheres.push(ColumnConstraint::EqualsEntity(column, entid)),
+                            // An unknown ident names no entity, so no value can match it.
+                            None => self.mark_known_empty(EmptyBecause::UnknownIdent(ident)),
+                        }
+                    },
+                    Some(value) => self.constrain_value(column, attribute, value),
+                    None => {
+                        if attribute.map_or(false, |a| a.enumerated) && !self.is_bound(var) {
+                            self.enum_vars.insert(var.clone());
+                        }
+                        self.bind_column_to_var(var.clone(), column)
+                    },
+                }
+            },
+            PatternValuePlace::EntidOrInteger(x) => {
+                match attribute.map(|attribute| &attribute.value_type) {
+                    Some(&ValueType::Ref) => self.wheres.push(ColumnConstraint::EqualsEntity(column, x)),
+                    // Integers are valid doubles.  Longs and doubles share a tag, so this is
+                    // exactly the SQL comparison we want.
+                    Some(&ValueType::Double) => self.constrain_value(column, attribute, TypedValue::Double((x as f64).into())),
+                    Some(_) => self.constrain_value(column, attribute, TypedValue::Long(x)),
+                    None => self.wheres.push(ColumnConstraint::EqualsRefOrLong(column, x)),
+                }
+            },
+            PatternValuePlace::Ident(ref kw) => {
+                match attribute.map(|attribute| &attribute.value_type) {
+                    Some(&ValueType::Keyword) => self.constrain_value(column, attribute, TypedValue::Keyword(kw.clone())),
+                    // Otherwise, a keyword in value position names an entity.
+                    _ => {
+                        if let Some(entid) = self.entid_for_ident(schema, &kw.to_string()) {
+                            self.constrain_value(column, attribute, TypedValue::Ref(entid));
+                        }
+                    },
+                }
+            },
+            PatternValuePlace::Constant(ref constant) => {
+                let typed_value = match *constant {
+                    NonIntegerConstant::Boolean(x) => TypedValue::Boolean(x),
+                    NonIntegerConstant::Float(x) => TypedValue::Double(x),
+                    NonIntegerConstant::Text(ref x) => TypedValue::String(x.clone()),
+                    NonIntegerConstant::Bytes(ref x) => TypedValue::Bytes(x.clone()),
+                    // Instants are stored as longs, in milliseconds since the Unix epoch.
+                    NonIntegerConstant::Instant(x) => TypedValue::Long(x),
+                    NonIntegerConstant::BigInteger(_) => bail!(ErrorKind::NotYetImplemented(format!("BigInteger value {:?}", constant))),
+                };
+                self.constrain_value(column, attribute, typed_value);
+            },
+        }
+        Ok(())
+    }
+
+    /// Constrain the `added` column of a logged datom.  Only booleans can match.
+    fn constrain_added_place(&mut self, column: QualifiedAlias, place: &PatternValuePlace) {
+        match *place {
+            PatternValuePlace::Placeholder => (),
+            PatternValuePlace::Variable(ref var) => {
+                match self.value_bindings.get(var).cloned() {
+                    Some(TypedValue::Boolean(x)) => self.wheres.push(ColumnConstraint::EqualsAdded(column, x)),
+                    Some(_) => self.mark_known_empty(EmptyBecause::NonBooleanAdded),
+                    None => self.bind_column_to_var(var.clone(), column),
+                }
+            },
+            PatternValuePlace::Constant(NonIntegerConstant::Boolean(x)) => self.wheres.push(ColumnConstraint::EqualsAdded(column, x)),
+            _ => self.mark_known_empty(EmptyBecause::NonBooleanAdded),
+        }
+    }
+
+    /// Return the attribute named by the attribute place of a pattern, if it is a known attribute.
+    ///
+    /// Marks the clauses as known-empty if the place names something that isn't an attribute.
+    fn attribute_for_place<'s>(&mut self, schema: &'s Schema, place: &PatternNonValuePlace) -> Option<&'s Attribute> {
+        let (entid, name) = match *place {
+            PatternNonValuePlace::Entid(entid) => (Some(entid), entid.to_string()),
+            PatternNonValuePlace::Ident(ref kw) => (schema.get_entid(&kw.to_string()).cloned(), kw.to_string()),
+            PatternNonValuePlace::Placeholder | PatternNonValuePlace::Variable(_) => return None,
+        };
+        let attribute = entid.and_then(|entid| schema.attribute_for_entid(&entid));
+        if attribute.is_none() {
+            self.mark_known_empty(EmptyBecause::UnknownAttribute(name));
+        }
+        attribute
+    }
+
+    /// Fail unless the value place of a pattern is one we can translate without comparing
+    /// encrypted values: an encrypted attribute's value can only be ignored or bound to a fresh
+    /// variable, and a variable bound to an encrypted value can't be used again.
+    fn check_encrypted_value_place(&mut self, attribute: Option<&Attribute>, place: &PatternValuePlace) -> Result<()> {
+        if let PatternValuePlace::Variable(ref var) = *place {
+            if self.encrypted_vars.contains(var) {
+                bail!(ErrorKind::EncryptedValue(format!("{} is bound to an encrypted value", (var.0).0)));
+            }
+        }
+        if !attribute.map_or(false, |attribute| attribute.encrypted) {
+            return Ok(());
+        }
+        match *place {
+            PatternValuePlace::Placeholder => Ok(()),
+            PatternValuePlace::Variable(ref var) if !self.is_bound(var) => {
+                self.encrypted_vars.insert(var.clone());
+                Ok(())
+            },
+            ref place => bail!(ErrorKind::EncryptedValue(format!("{:?}", place))),
+        }
+    }
+
+    /// Add the given `pattern` to this conjunction.
+    pub fn apply_pattern(&mut self, schema: &Schema, pattern: &Pattern) -> Result<()> {
+        match pattern.source {
+            None | Some(SrcVar::DefaultSrc) => (),
+            Some(SrcVar::NamedSrc(ref name)) => bail!(ErrorKind::NotYetImplemented(format!("Named source ${}", name))),
+        }
+
+        let attribute = self.attribute_for_place(schema, &pattern.attribute);
+        self.check_encrypted_value_place(attribute, &pattern.value)?;
+        let table = match attribute {
+            Some(attribute) if attribute.fulltext => DatomsTable::FulltextDatoms,
+            Some(_) => DatomsTable::Datoms,
+            None => DatomsTable::AllDatoms,
+        };
+        let alias = self.next_alias(table);
+        self.from.push(SourceAlias(table, alias.clone()));
+        self.pattern_aliases.push(alias.clone());
+
+        self.constrain_non_value_place(schema, QualifiedAlias(alias.clone(), DatomsColumn::Entity), &pattern.entity);
+        self.constrain_non_value_place(schema, QualifiedAlias(alias.clone(), DatomsColumn::Attribute), &pattern.attribute);
+        self.constrain_value_place(schema, QualifiedAlias(alias.clone(), DatomsColumn::Value), attribute, &pattern.value)?;
+        self.constrain_non_value_place(schema, QualifiedAlias(alias.clone(), DatomsColumn::Tx), &pattern.tx);
+        self.constrain_added_place(QualifiedAlias(alias.clone(), DatomsColumn::Added), &pattern.added);
+        Ok(())
+    }
+
+    /// Add a fulltext search to this conjunction, like
+    /// `[(fulltext $ :foo/text "search") [[?e ?text ?tx ?score ?snippet]]]`.
+    ///
+    /// The binding places are the entity, the matching value, the transaction, the relevance score,
+    /// and a snippet of the value with the search terms highlighted.  Trailing places can be omitted.
+    pub fn apply_fulltext(&mut self, schema: &Schema, where_fn: &WhereFn) -> Result<()> {
+        if where_fn.args.len() != 3 {
+            bail!(ErrorKind::InvalidArgument(format!("fulltext expects 3 arguments, got {}", where_fn.args.len())));
+        }
+        match where_fn.args[0] {
+            FnArg::SrcVar(SrcVar::DefaultSrc) => (),
+            FnArg::SrcVar(SrcVar::NamedSrc(ref name)) => bail!(ErrorKind::NotYetImplemented(format!("Named source ${}", name))),
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("fulltext expects a source, got {:?}", arg))),
+        }
+        let (entid, name) = match where_fn.args[1] {
+            FnArg::Ident(ref kw) => (schema.get_entid(&kw.to_string()).cloned(), kw.to_string()),
+            FnArg::EntidOrInteger(x) => (Some(x), x.to_string()),
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("fulltext expects an attribute, got {:?}", arg))),
+        };
+        // Attributes configured with their own tokenizer or prefixes are searched in their own table.
+        let table = match entid.and_then(|entid| schema.attribute_for_entid(&entid).map(|attribute| (entid, attribute))) {
+            Some((_, attribute)) if !attribute.fulltext => {
+                bail!(ErrorKind::InvalidArgument(format!("fulltext expects a fulltext attribute, got {:?}", where_fn.args[1])));
+            },
+            Some((entid, attribute)) if attribute.has_fulltext_table() => fulltext_table_name(entid),
+            Some(_) => DatomsTable::FulltextValues.name().to_string(),
+            None => {
+                self.mark_known_empty(EmptyBecause::UnknownAttribute(name));
+                DatomsTable::FulltextValues.name().to_string()
+            },
+        };
+        let terms = match where_fn.args[2] {
+            FnArg::Constant(NonIntegerConstant::Text(ref x)) => x.clone(),
+            FnArg::Variable(ref var) => {
+                match self.value_bindings.get(var) {
+                    Some(&TypedValue::String(ref x)) => x.clone(),
+                    Some(_) => bail!(ErrorKind::InvalidArgument(format!("fulltext expects a string search, got {}", (var.0).0))),
+                    None => bail!(ErrorKind::NotYetImplemented(format!("unbound fulltext search {}", (var.0).0))),
+                }
+            },
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("fulltext expects a string search, got {:?}", arg))),
+        };
+
+        let values = self.next_alias(DatomsTable::FulltextValues);
+        let datoms = self.next_alias(DatomsTable::Datoms);
+        self.from.push(SourceAlias(DatomsTable::FulltextValues, values.clone()));
+        self.from.push(SourceAlias(DatomsTable::Datoms, datoms.clone()));
+        self.fulltext_searches.insert(values.clone(), FulltextSearch { table: table, terms: terms });
+
+        if let Some(entid) = entid {
+            self.wheres.push(ColumnConstraint::EqualsEntity(QualifiedAlias(datoms.clone(), DatomsColumn::Attribute), entid));
+        }
+        self.wheres.push(ColumnConstraint::EqualsColumn(QualifiedAlias(datoms.clone(), DatomsColumn::Value),
+                                                        QualifiedAlias(values.clone(), DatomsColumn::Rowid)));
+
+        let binding = match where_fn.binding {
+            Binding::BindRel(ref places) => places,
+            ref binding => bail!(ErrorKind::InvalidArgument(format!("fulltext expects a relation binding, got {:?}", binding))),
+        };
+        let columns = [QualifiedAlias(datoms.clone(), DatomsColumn::Entity),
+                       QualifiedAlias(values.clone(), DatomsColumn::Text),
+                       QualifiedAlias(datoms.clone(), DatomsColumn::Tx),
+                       QualifiedAlias(values.clone(), DatomsColumn::Score),
+                       QualifiedAlias(values.clone(), DatomsColumn::Snippet)];
+        if binding.len() > columns.len() {
+            bail!(ErrorKind::InvalidArgument(format!("fulltext binds at most {} values, got {}", columns.len(), binding.len())));
+        }
+        for (place, column) in binding.iter().zip(columns.iter()) {
+            if let Some(ref var) = *place {
+                if self.value_bindings.contains_key(var) {
+                    bail!(ErrorKind::NotYetImplemented(format!("fulltext binding input {}", (var.0).0)));
+                }
+                self.bind_column_to_var(var.clone(), column.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a JSON path extraction to this conjunction, like `[(json-get ?v "$.path") ?out]`.
+    ///
+    /// `?v` must be bound to a JSON value by an earlier clause.  `?out` is bound to the value at the
+    /// path, as extracted by SQLite's `json_extract`: JSON strings, numbers, and booleans become
+    /// strings, longs or doubles, and booleans; objects and arrays remain JSON.  Documents without
+    /// a value at the path, or with `null` there, don't bind `?out`.
+    pub fn apply_json_get(&mut self, where_fn: &WhereFn) -> Result<()> {
+        if where_fn.args.len() != 2 {
+            bail!(ErrorKind::InvalidArgument(format!("json-get expects 2 arguments, got {}", where_fn.args.len())));
+        }
+        let out = match where_fn.binding {
+            Binding::BindScalar(ref var) => var,
+            ref binding => bail!(ErrorKind::InvalidArgument(format!("json-get expects a scalar binding, got {:?}", binding))),
+        };
+        if self.value_bindings.contains_key(out) {
+            bail!(ErrorKind::NotYetImplemented(format!("json-get binding input {}", (out.0).0)));
+        }
+        let document = match where_fn.args[0] {
+            FnArg::Variable(ref var) if self.encrypted_vars.contains(var) => {
+                bail!(ErrorKind::EncryptedValue(format!("json-get of {}", (var.0).0)));
+            },
+            FnArg::Variable(ref var) => {
+                match self.binding_for_var(var).cloned() {
+                    Some(column) => column,
+                    None if self.value_bindings.contains_key(var) => bail!(ErrorKind::NotYetImplemented(format!("json-get input {}", (var.0).0))),
+                    None => bail!(ErrorKind::UnboundVariable(var.clone())),
+                }
+            },
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("json-get expects a variable, got {:?}", arg))),
+        };
+        let path = match where_fn.args[1] {
+            FnArg::Constant(NonIntegerConstant::Text(ref x)) => x.clone(),
+            FnArg::Variable(ref var) => {
+                match self.value_bindings.get(var) {
+                    Some(&TypedValue::String(ref x)) => x.clone(),
+                    Some(_) => bail!(ErrorKind::InvalidArgument(format!("json-get expects a string path, got {}", (var.0).0))),
+                    None => bail!(ErrorKind::NotYetImplemented(format!("unbound json-get path {}", (var.0).0))),
+                }
+            },
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("json-get expects a string path, got {:?}", arg))),
+        };
+
+        let values = self.next_alias(DatomsTable::JsonValues);
+        self.from.push(SourceAlias(DatomsTable::JsonValues, values.clone()));
+        self.json_paths.insert(values.clone(), path);
+
+        // Only JSON values are documents, even if a string has the same text.
+        self.constrain_type_tag(&document, 14);
+        self.wheres.push(ColumnConstraint::EqualsColumn(document, QualifiedAlias(values.clone(), DatomsColumn::Json)));
+        self.bind_column_to_var(out.clone(), QualifiedAlias(values, DatomsColumn::Value));
+        Ok(())
+    }
+
+    /// Add a tuple component extraction to this conjunction, like `[(tuple-get ?t 0) ?out]`.
+    ///
+    /// `?t` must be bound to a tuple value by an earlier clause.  `?out` is bound to the component
+    /// at the given index, counting from zero, with the component's own type.  Tuples too short to
+    /// have the component don't bind `?out`.
+    pub fn apply_tuple_get(&mut self, where_fn: &WhereFn) -> Result<()> {
+        if where_fn.args.len() != 2 {
+            bail!(ErrorKind::InvalidArgument(format!("tuple-get expects 2 arguments, got {}", where_fn.args.len())));
+        }
+        let out = match where_fn.binding {
+            Binding::BindScalar(ref var) => var,
+            ref binding => bail!(ErrorKind::InvalidArgument(format!("tuple-get expects a scalar binding, got {:?}", binding))),
+        };
+        if self.value_bindings.contains_key(out) {
+            bail!(ErrorKind::NotYetImplemented(format!("tuple-get binding input {}", (out.0).0)));
+        }
+        let tuple = match where_fn.args[0] {
+            FnArg::Variable(ref var) => {
+                match self.binding_for_var(var).cloned() {
+                    Some(column) => column,
+                    None if self.value_bindings.contains_key(var) => bail!(ErrorKind::NotYetImplemented(format!("tuple-get input {}", (var.0).0))),
+                    None => bail!(ErrorKind::UnboundVariable(var.clone())),
+                }
+            },
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("tuple-get expects a variable, got {:?}", arg))),
+        };
+        let index = match where_fn.args[1] {
+            FnArg::EntidOrInteger(x) if x >= 0 => x,
+            FnArg::Variable(ref var) => {
+                match self.value_bindings.get(var) {
+                    Some(&TypedValue::Long(x)) if x >= 0 => x,
+                    Some(_) => bail!(ErrorKind::InvalidArgument(format!("tuple-get expects a non-negative index, got {}", (var.0).0))),
+                    None => bail!(ErrorKind::NotYetImplemented(format!("unbound tuple-get index {}", (var.0).0))),
+                }
+            },
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("tuple-get expects a non-negative index, got {:?}", arg))),
+        };
+
+        let values = self.next_alias(DatomsTable::TupleValues);
+        self.from.push(SourceAlias(DatomsTable::TupleValues, values.clone()));
+        self.tuple_indexes.insert(values.clone(), index);
+
+        // Only tuple values are tuples, even if a string has the same text.
+        self.constrain_type_tag(&tuple, TUPLE_VALUE_TYPE_TAG);
+        self.wheres.push(ColumnConstraint::EqualsColumn(tuple, QualifiedAlias(values.clone(), DatomsColumn::Tuple)));
+        self.bind_column_to_var(out.clone(), QualifiedAlias(values, DatomsColumn::Value));
+        Ok(())
+    }
+
+    /// Return the column bound to the instant `arg` of a call to `operator`, which must be a
+    /// variable bound by an earlier clause.
+    fn instant_column(&self, operator: &str, arg: &FnArg) -> Result<QualifiedAlias> {
+        match *arg {
+            FnArg::Variable(ref var) => {
+                match self.binding_for_var(var).cloned() {
+                    Some(column) => Ok(column),
+                    None if self.value_bindings.contains_key(var) => bail!(ErrorKind::NotYetImplemented(format!("{} input {}", operator, (var.0).0))),
+                    None => bail!(ErrorKind::UnboundVariable(var.clone())),
+                }
+            },
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("{} expects a variable, got {:?}", operator, arg))),
+        }
+    }
+
+    /// Return the UTC offset, in minutes, of the optional time zone `arg` of a call to `operator`.
+    fn utc_offset(&self, operator: &str, arg: Option<&FnArg>) -> Result<i64> {
+        let offset = match arg {
+            None => return Ok(0),
+            Some(&FnArg::Constant(NonIntegerConstant::Text(ref x))) => x.clone(),
+            Some(&FnArg::Variable(ref var)) => {
+                match self.value_bindings.get(var) {
+                    Some(&TypedValue::String(ref x)) => x.clone(),
+                    Some(_) => bail!(ErrorKind::InvalidArgument(format!("{} expects a UTC offset, got {}", operator, (var.0).0))),
+                    None => bail!(ErrorKind::NotYetImplemented(format!("unbound {} offset {}", operator, (var.0).0))),
+                }
+            },
+            Some(arg) => bail!(ErrorKind::InvalidArgument(format!("{} expects a UTC offset, got {:?}", operator, arg))),
+        };
+        match parse_utc_offset(&offset) {
+            Some(offset) => Ok(offset),
+            None => bail!(ErrorKind::InvalidArgument(format!("{} expects a UTC offset like \"+05:30\", got {:?}", operator, offset))),
+        }
+    }
+
+    /// Add a computation over instants to this conjunction, like `[(date ?t) ?day]` or
+    /// `[(truncate ?t "month" "-05:00") ?month]`.
+    ///
+    /// `?t` must be bound to an instant, stored as a long, by an earlier clause.  `date` binds
+    /// `?out` to the calendar date of the instant, as a string like `"2017-01-31"`; `truncate`
+    /// binds it to the instant at the start of the given `"year"`, `"month"`, `"day"`, `"hour"`, or
+    /// `"minute"`.  Both work in UTC, or in the time zone with the optional fixed offset from UTC.
+    pub fn apply_instant_fn(&mut self, where_fn: &WhereFn) -> Result<()> {
+        let operator = where_fn.operator.0.as_str();
+        let (min_args, max_args) = if operator == "date" { (1, 2) } else { (2, 3) };
+        if where_fn.args.len() < min_args || where_fn.args.len() > max_args {
+            bail!(ErrorKind::InvalidArgument(format!("{} expects {} or {} arguments, got {}", operator, min_args, max_args, where_fn.args.len())));
+        }
+        let out = match where_fn.binding {
+            Binding::BindScalar(ref var) => var,
+            ref binding => bail!(ErrorKind::InvalidArgument(format!("{} expects a scalar binding, got {:?}", operator, binding))),
+        };
+        if self.value_bindings.contains_key(out) {
+            bail!(ErrorKind::NotYetImplemented(format!("{} binding input {}", operator, (out.0).0)));
+        }
+        let instant = self.instant_column(operator, &where_fn.args[0])?;
+        let instant_fn = if operator == "date" {
+            InstantFn::Date { offset: self.utc_offset(operator, where_fn.args.get(1))? }
+        } else {
+            let unit = match where_fn.args[1] {
+                FnArg::Constant(NonIntegerConstant::Text(ref x)) => TimeUnit::from_name(x),
+                _ => None,
+            };
+            match unit {
+                Some(unit) => InstantFn::Truncate { unit: unit, offset: self.utc_offset(operator, where_fn.args.get(2))? },
+                None => bail!(ErrorKind::InvalidArgument(format!("{} expects a unit like \"day\", got {:?}", operator, where_fn.args[1]))),
+            }
+        };
+
+        let values = self.next_alias(DatomsTable::InstantValues);
+        self.from.push(SourceAlias(DatomsTable::InstantValues, values.clone()));
+        self.instant_fns.insert(values.clone(), instant_fn);
+
+        // Only longs are instants.
+        self.constrain_type_tag(&instant, 5);
+        self.wheres.push(ColumnConstraint::EqualsColumn(instant, QualifiedAlias(values.clone(), DatomsColumn::Instant)));
+        self.bind_column_to_var(out.clone(), QualifiedAlias(values, DatomsColumn::Value));
+        Ok(())
+    }
+
+    /// Add a range test to this conjunction, like
+    /// `[(between ?t #inst "2017-01-01T00:00:00Z" #inst "2017-02-01T00:00:00Z")]`.
+    ///
+    /// `?t` must be bound to a long, like an instant, by an earlier clause.  The range includes its
+    /// start but not its end, so that consecutive ranges don't overlap.
+    pub fn apply_between(&mut self, predicate: &Predicate) -> Result<()> {
+        if predicate.args.len() != 3 {
+            bail!(ErrorKind::InvalidArgument(format!("between expects 3 arguments, got {}", predicate.args.len())));
+        }
+        let column = self.instant_column("between", &predicate.args[0])?;
+        let mut bounds = vec![];
+        for arg in predicate.args[1..].iter() {
+            let bound = match *arg {
+                FnArg::EntidOrInteger(x) | FnArg::Constant(NonIntegerConstant::Instant(x)) => x,
+                FnArg::Variable(ref var) => {
+                    match self.value_bindings.get(var) {
+                        Some(&TypedValue::Long(x)) => x,
+                        Some(_) => bail!(ErrorKind::InvalidArgument(format!("between expects a long or instant bound, got {}", (var.0).0))),
+                        None => bail!(ErrorKind::NotYetImplemented(format!("unbound between bound {}", (var.0).0))),
+                    }
+                },
+                ref arg => bail!(ErrorKind::InvalidArgument(format!("between expects a long or instant bound, got {:?}", arg))),
+            };
+            bounds.push(bound);
+        }
+
+        self.constrain_type_tag(&column, 5);
+        self.wheres.push(ColumnConstraint::Between(column, bounds[0], bounds[1]));
+        Ok(())
+    }
+
+    /// Add the given predicate call to this conjunction.
+    pub fn apply_predicate(&mut self, predicate: &Predicate) -> Result<()> {
+        match predicate.operator.0.as_str() {
+            "between" => self.apply_between(predicate),
+            operator => bail!(ErrorKind::NotYetImplemented(format!("predicate {}", operator))),
+        }
+    }
+
+    /// Add a walk along a ref attribute to this conjunction, like
+    /// `[(ancestors ?e :foo/parent) [[?ancestor ?depth]]]` or
+    /// `[(descendants ?e :foo/parent 3) ?descendant]`.
+    ///
+    /// `ancestors` follows the attribute from entity to value, and `descendants` from value to
+    /// entity, one or more times.  The optional third argument limits the number of steps, which
+    /// otherwise defaults to `DEFAULT_MAX_DEPTH`.  The binding places are the entity reached and the
+    /// least number of steps it took; each entity is reached once, however many paths lead to it,
+    /// and a walk around a cycle stops when it comes back to an entity.  If `?e` isn't bound, walks
+    /// start from every entity with the attribute.
+    pub fn apply_closure(&mut self, schema: &Schema, where_fn: &WhereFn, forward: bool) -> Result<()> {
+        let operator = where_fn.operator.0.as_str();
+        if where_fn.args.len() != 2 && where_fn.args.len() != 3 {
+            bail!(ErrorKind::InvalidArgument(format!("{} expects 2 or 3 arguments, got {}", operator, where_fn.args.len())));
+        }
+        let mut start_var = None;
+        let start = match where_fn.args[0] {
+            FnArg::Variable(ref var) => {
+                match self.value_bindings.get(var).cloned() {
+                    Some(TypedValue::Ref(entid)) | Some(TypedValue::Long(entid)) => Some(entid),
+                    Some(_) => {
+                        self.mark_known_empty(EmptyBecause::NonEntityInput(var.clone()));
+                        None
+                    },
+                    None => {
+                        start_var = Some(var.clone());
+                        None
+                    },
+                }
+            },
+            FnArg::EntidOrInteger(entid) => Some(entid),
+            FnArg::Ident(ref kw) => self.entid_for_ident(schema, &kw.to_string()),
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("{} expects an entity, got {:?}", operator, arg))),
+        };
+        let (entid, name) = match where_fn.args[1] {
+            FnArg::Ident(ref kw) => (schema.get_entid(&kw.to_string()).cloned(), kw.to_string()),
+            FnArg::EntidOrInteger(x) => (Some(x), x.to_string()),
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("{} expects an attribute, got {:?}", operator, arg))),
+        };
+        let attribute = match entid.and_then(|entid| schema.attribute_for_entid(&entid).map(|attribute| (entid, attribute))) {
+            Some((_, attribute)) if attribute.value_type != ValueType::Ref => {
+                bail!(ErrorKind::InvalidArgument(format!("{} expects a ref attribute, got {}", operator, name)));
+            },
+            Some((entid, _)) => entid,
+            None => {
+                self.mark_known_empty(EmptyBecause::UnknownAttribute(name));
+                0
+            },
+        };
+        let max_depth = match where_fn.args.get(2) {
+            None => DEFAULT_MAX_DEPTH,
+            Some(&FnArg::EntidOrInteger(x)) if x > 0 => x,
+            Some(&FnArg::Variable(ref var)) => {
+                match self.value_bindings.get(var) {
+                    Some(&TypedValue::Long(x)) if x > 0 => x,
+                    _ => bail!(ErrorKind::InvalidArgument(format!("{} expects a positive depth, got {}", operator, (var.0).0))),
+                }
+            },
+            Some(arg) => bail!(ErrorKind::InvalidArgument(format!("{} expects a positive depth, got {:?}", operator, arg))),
+        };
+
+        let alias = self.next_alias(DatomsTable::Closure);
+        self.from.push(SourceAlias(DatomsTable::Closure, alias.clone()));
+        self.closures.insert(alias.clone(), Closure {
+            attribute: attribute,
+            forward: forward,
+            start: start,
+            max_depth: max_depth,
+        });
+        if let Some(var) = start_var {
+            self.bind_column_to_var(var, QualifiedAlias(alias.clone(), DatomsColumn::Start));
+        }
+
+        let places = match where_fn.binding {
+            Binding::BindScalar(ref var) | Binding::BindColl(ref var) => vec![Some(var.clone())],
+            Binding::BindRel(ref places) => places.clone(),
+        };
+        let columns = [QualifiedAlias(alias.clone(), DatomsColumn::Entity),
+                       QualifiedAlias(alias.clone(), DatomsColumn::Depth)];
+        if places.len() > columns.len() {
+            bail!(ErrorKind::InvalidArgument(format!("{} binds at most {} values, got {}", operator, columns.len(), places.len())));
+        }
+        for (place, column) in places.into_iter().zip(columns.iter()) {
+            if let Some(var) = place {
+                if self.value_bindings.contains_key(&var) {
+                    bail!(ErrorKind::NotYetImplemented(format!("{} binding input {}", operator, (var.0).0)));
+                }
+                self.bind_column_to_var(var, column.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail unless `arg`, the first argument of a call to `operator`, is the default source `$`.
+    fn check_default_source(&self, operator: &str, arg: &FnArg) -> Result<()> {
+        match *arg {
+            FnArg::SrcVar(SrcVar::DefaultSrc) => Ok(()),
+            FnArg::SrcVar(SrcVar::NamedSrc(ref name)) => bail!(ErrorKind::NotYetImplemented(format!("Named source ${}", name))),
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("{} expects a source, got {:?}", operator, arg))),
+        }
+    }
+
+    /// Return the transaction ID given by `arg` of a call to `operator`: a constant, or an input.
+    fn tx_bound(&self, operator: &str, arg: &FnArg) -> Result<Entid> {
+        match *arg {
+            FnArg::EntidOrInteger(x) => Ok(x),
+            FnArg::Variable(ref var) => {
+                match self.value_bindings.get(var) {
+                    Some(&TypedValue::Ref(x)) | Some(&TypedValue::Long(x)) => Ok(x),
+                    Some(_) => bail!(ErrorKind::InvalidArgument(format!("{} expects a transaction ID, got {}", operator, (var.0).0))),
+                    None => bail!(ErrorKind::NotYetImplemented(format!("unbound {} bound {}", operator, (var.0).0))),
+                }
+            },
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("{} expects a transaction ID, got {:?}", operator, arg))),
+        }
+    }
+
+    /// Add a range of transactions to this conjunction, like `[(tx-ids $ ?since ?until) [?tx ...]]`.
+    ///
+    /// The bounds are transaction IDs, as constants or inputs; the range includes its start but not
+    /// its end.  `?tx` is bound to each transaction in the range, for use in the tx place of a
+    /// pattern, as an entity, like `[?tx :db/txInstant ?when]`, or by `tx-data`.
+    pub fn apply_tx_ids(&mut self, where_fn: &WhereFn) -> Result<()> {
+        if where_fn.args.len() != 3 {
+            bail!(ErrorKind::InvalidArgument(format!("tx-ids expects 3 arguments, got {}", where_fn.args.len())));
+        }
+        self.check_default_source("tx-ids", &where_fn.args[0])?;
+        let range = TxRange {
+            start: self.tx_bound("tx-ids", &where_fn.args[1])?,
+            end: self.tx_bound("tx-ids", &where_fn.args[2])?,
+        };
+        let var = match where_fn.binding {
+            Binding::BindScalar(ref var) | Binding::BindColl(ref var) => var,
+            ref binding => bail!(ErrorKind::InvalidArgument(format!("tx-ids expects a collection binding, got {:?}", binding))),
+        };
+        if self.value_bindings.contains_key(var) {
+            bail!(ErrorKind::NotYetImplemented(format!("tx-ids binding input {}", (var.0).0)));
+        }
+
+        let alias = self.next_alias(DatomsTable::TxIds);
+        self.from.push(SourceAlias(DatomsTable::TxIds, alias.clone()));
+        self.tx_ranges.insert(alias.clone(), range);
+        self.bind_column_to_var(var.clone(), QualifiedAlias(alias, DatomsColumn::Tx));
+        Ok(())
+    }
+
+    /// Add the datoms of a transaction to this conjunction, like
+    /// `[(tx-data $ ?tx) [[?e ?a ?v _ ?added]]]`.
+    ///
+    /// The datoms are read from the log, so they include the transaction's retractions.  The
+    /// binding places are the entity, attribute, value, transaction, and whether the datom was
+    /// asserted; trailing places can be omitted.  `?tx` may be a transaction ID, an input, or a
+    /// variable bound by another clause, like `tx-ids`; if it's unbound, every transaction's datoms
+    /// are read.
+    pub fn apply_tx_data(&mut self, schema: &Schema, where_fn: &WhereFn) -> Result<()> {
+        if where_fn.args.len() != 2 {
+            bail!(ErrorKind::InvalidArgument(format!("tx-data expects 2 arguments, got {}", where_fn.args.len())));
+        }
+        self.check_default_source("tx-data", &where_fn.args[0])?;
+        let tx = match where_fn.args[1] {
+            FnArg::EntidOrInteger(x) => PatternNonValuePlace::Entid(x),
+            FnArg::Variable(ref var) => PatternNonValuePlace::Variable(var.clone()),
+            ref arg => bail!(ErrorKind::InvalidArgument(format!("tx-data expects a transaction, got {:?}", arg))),
+        };
+        let places = match where_fn.binding {
+            Binding::BindRel(ref places) => places,
+            ref binding => bail!(ErrorKind::InvalidArgument(format!("tx-data expects a relation binding, got {:?}", binding))),
+        };
+
+        let alias = self.next_alias(DatomsTable::TxData);
+        self.from.push(SourceAlias(DatomsTable::TxData, alias.clone()));
+        self.tx_data.insert(alias.clone(), TxData {
+            fulltext_attributes: schema.schema_map.iter()
+                .filter(|&(_, attribute)| attribute.fulltext)
+                .map(|(&entid, _)| entid)
+                .collect(),
+        });
+        self.constrain_non_value_place(schema, QualifiedAlias(alias.clone(), DatomsColumn::Tx), &tx);
+
+        let columns = [QualifiedAlias(alias.clone(), DatomsColumn::Entity),
+                       QualifiedAlias(alias.clone(), DatomsColumn::Attribute),
+                       QualifiedAlias(alias.clone(), DatomsColumn::Value),
+                       QualifiedAlias(alias.clone(), DatomsColumn::Tx),
+                       QualifiedAlias(alias.clone(), DatomsColumn::Added)];
+        if places.len() > columns.len() {
+            bail!(ErrorKind::InvalidArgument(format!("tx-data binds at most {} values, got {}", columns.len(), places.len())));
+        }
+        for (place, column) in places.iter().zip(columns.iter()) {
+            if let Some(ref var) = *place {
+                if self.value_bindings.contains_key(var) {
+                    bail!(ErrorKind::NotYetImplemented(format!("tx-data binding input {}", (var.0).0)));
+                }
+                self.bind_column_to_var(var.clone(), column.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the given where-function call to this conjunction.
+    pub fn apply_where_fn(&mut self, schema: &Schema, where_fn: &WhereFn) -> Result<()> {
+        match where_fn.operator.0.as_str() {
+            "fulltext" => self.apply_fulltext(schema, where_fn),
+            "json-get" => self.apply_json_get(where_fn),
+            "tuple-get" => self.apply_tuple_get(where_fn),
+            "date" | "truncate" => self.apply_instant_fn(where_fn),
+            "ancestors" => self.apply_closure(schema, where_fn, true),
+            "descendants" => self.apply_closure(schema, where_fn, false),
+            "tx-ids" => self.apply_tx_ids(where_fn),
+            "tx-data" => self.apply_tx_data(schema, where_fn),
+            operator => bail!(ErrorKind::NotYetImplemented(format!("where-function {}", operator))),
+        }
+    }
+
+    /// Add the given `not` or `not-join` to this conjunction.
+    ///
+    /// Every unified variable must already be bound, by an earlier clause or an input: a `not` can
+    /// only exclude results, never produce them.  Variables that aren't unified are local to the
+    /// body, even if the enclosing query binds them too.
+    pub fn apply_not_join(&mut self, schema: &Schema, not_join: &NotJoin) -> Result<()> {
+        let vars = match not_join.unify_vars {
+            UnifyVars::Implicit => clause_variables(not_join.clauses.iter()),
+            UnifyVars::Explicit(ref vars) => vars.iter().cloned().collect(),
+        };
+
+        let mut body = self.nested();
+        for var in vars.iter() {
+            if !self.is_bound(var) {
+                bail!(ErrorKind::UnboundInScope(var.clone(), describe_join("not", &not_join.unify_vars)));
+            }
+            if let Some(column) = self.binding_for_var(var) {
+                body.column_bindings.insert(var.clone(), vec![column.clone()]);
+            }
+            if self.encrypted_vars.contains(var) {
+                body.encrypted_vars.insert(var.clone());
+            }
+        }
+        body.apply_clauses(schema, not_join.clauses.iter())?;
+        self.alias_counter = body.alias_counter;
+
+        // A body that can't match excludes nothing.
+        if !body.is_known_empty {
+            self.wheres.push(ColumnConstraint::NotExists(body));
+        }
+        Ok(())
+    }
+
+    /// Add the given `or` or `or-join` to this conjunction.
+    ///
+    /// The legs of an `or` must all mention the same variables; the legs of an `or-join` must each
+    /// bind the declared variables, and any others are local to the leg.  Each leg is translated
+    /// on its own, and the union of their rows binds the unified variables.
+    pub fn apply_or_join(&mut self, schema: &Schema, or_join: &OrJoin) -> Result<()> {
+        let description = describe_join("or", &or_join.unify_vars);
+        let vars: BTreeSet<Variable> = match or_join.unify_vars {
+            UnifyVars::Implicit => {
+                let mut legs = or_join.clauses.iter().map(|leg| clause_variables(leg.clauses()));
+                let first = legs.next().unwrap_or_default();
+                for leg in legs {
+                    if let Some(var) = first.symmetric_difference(&leg).next() {
+                        bail!(ErrorKind::NonUnifiedVariable(var.clone(), description.clone()));
+                    }
+                }
+                first
+            },
+            UnifyVars::Explicit(ref vars) => vars.iter().cloned().collect(),
+        };
+
+        let mut legs = vec![];
+        for leg in or_join.clauses.iter() {
+            let mut cc = self.nested();
+            cc.apply_clauses(schema, leg.clauses())?;
+            self.alias_counter = cc.alias_counter;
+            if let Some(var) = vars.iter().find(|var| !cc.is_bound(var)) {
+                bail!(ErrorKind::NonUnifiedVariable(var.clone(), description.clone()));
+            }
+            // Variables bound to encrypted values stay encrypted outside the leg.
+            for var in vars.iter().filter(|var| cc.encrypted_vars.contains(var)) {
+                if self.is_bound(var) {
+                    bail!(ErrorKind::EncryptedValue(format!("{} is bound to an encrypted value in {}", (var.0).0, description)));
+                }
+                self.encrypted_vars.insert(var.clone());
+            }
+            legs.push(cc);
+        }
+        // Variables bound to enumeration values in every leg project as idents outside.
+        let enum_vars: Vec<Variable> = vars.iter()
+            .filter(|var| !self.is_bound(var) && legs.iter().all(|leg| leg.enum_vars.contains(var)))
+            .cloned()
+            .collect();
+        self.enum_vars.extend(enum_vars);
+        if legs.iter().all(|leg| leg.is_known_empty) {
+            if let Some(why) = legs.first().and_then(|leg| leg.empty_because.clone()) {
+                self.mark_known_empty(why);
+            }
+        }
+
+        // Inputs are constants in every leg, so there's nothing to unify.
+        let vars: Vec<Variable> = vars.into_iter().filter(|var| !self.value_bindings.contains_key(var)).collect();
+        let alias = self.next_alias(DatomsTable::Union);
+        self.from.push(SourceAlias(DatomsTable::Union, alias.clone()));
+        for (i, var) in vars.iter().enumerate() {
+            self.bind_column_to_var(var.clone(), QualifiedAlias(alias.clone(), DatomsColumn::Unified(i)));
+        }
+        self.unions.insert(alias, Union { clause: or_join.clone(), vars: vars, legs: legs });
+        Ok(())
+    }
+
+    /// Bind the variables of a collection or relation input, like `[?x ...]` or `[[?x ?y]]`, to
+    /// each of the given rows in turn.  Every row must have a value for each variable.
+    pub fn apply_input_rows(&mut self, vars: &[Variable], rows: Vec<Vec<TypedValue>>) -> Result<()> {
+        if let Some(row) = rows.iter().find(|row| row.len() != vars.len()) {
+            bail!(ErrorKind::InvalidInput(vars[0].clone(), format!("expected rows of {} values, got {}", vars.len(), row.len())));
+        }
+        if rows.is_empty() {
+            self.mark_known_empty(EmptyBecause::EmptyInput(vars[0].clone()));
+        }
+
+        let alias = self.next_alias(DatomsTable::Inputs);
+        self.from.push(SourceAlias(DatomsTable::Inputs, alias.clone()));
+        for (i, var) in vars.iter().enumerate() {
+            self.bind_column_to_var(var.clone(), QualifiedAlias(alias.clone(), DatomsColumn::Unified(i)));
+        }
+        self.input_rows.insert(alias, rows);
+        Ok(())
+    }
+
+    /// Add the given `clause` to this conjunction.
+    pub fn apply_clause(&mut self, schema: &Schema, clause: &WhereClause) -> Result<()> {
+        match *clause {
+            WhereClause::Pattern(ref pattern) => self.apply_pattern(schema, pattern),
+            WhereClause::WhereFn(ref where_fn) => self.apply_where_fn(schema, where_fn),
+            WhereClause::Pred(ref predicate) => self.apply_predicate(predicate),
+            WhereClause::OrJoin(ref or_join) => self.apply_or_join(schema, or_join),
+            WhereClause::NotJoin(ref not_join) => self.apply_not_join(schema, not_join),
+        }
+    }
+
+    /// Add the given clauses to this conjunction.  Predicates and `not` clauses are applied last, so
+    /// that they can use variables bound by any other clause.
+    pub fn apply_clauses<'c, T>(&mut self, schema: &Schema, clauses: T) -> Result<()> where T: IntoIterator<Item = &'c WhereClause> {
+        let (filters, others): (Vec<&WhereClause>, Vec<&WhereClause>) = clauses.into_iter().partition(|clause| {
+            match **clause {
+                WhereClause::Pred(_) | WhereClause::NotJoin(_) => true,
+                _ => false,
+            }
+        });
+        for clause in others.into_iter().chain(filters.into_iter()) {
+            self.apply_clause(schema, clause)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use edn::{NamespacedKeyword, PlainSymbol};
+    use mentat_db::{Attribute, Schema, ValueType};
+
+    fn add_attribute(schema: &mut Schema, ident: &str, entid: Entid, attribute: Attribute) {
+        schema.add_ident(ident.to_string(), entid);
+        schema.set_attribute(entid, attribute).expect("a valid attribute");
+    }
+
+    fn variable(name: &str) -> Variable {
+        Variable(PlainSymbol::new(name))
+    }
+
+    #[test]
+    fn test_unknown_ident() {
+        let mut cc = ConjoiningClauses::default();
+        let schema = Schema::default();
+
+        cc.apply_pattern(&schema, &Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Variable(variable("?x")),
+            attribute: PatternNonValuePlace::Ident(NamespacedKeyword::new("foo", "bar")),
+            value: PatternValuePlace::Constant(NonIntegerConstant::Boolean(true)),
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
+        }).unwrap();
+
+        assert!(cc.is_known_empty);
+        assert_eq!(cc.empty_because, Some(EmptyBecause::UnknownAttribute(":foo/bar".to_string())));
+    }
+
+    #[test]
+    fn test_integer_value() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/ref", 98, Attribute::default());
+        add_attribute(&mut schema, ":foo/double", 99, Attribute {
+            value_type: ValueType::Double,
+            ..Default::default()
+        });
+
+        let apply = |schema: &Schema, a: PatternNonValuePlace| {
+            let mut cc = ConjoiningClauses::default();
+            cc.apply_pattern(schema, &Pattern {
+                source: None,
+                entity: PatternNonValuePlace::Placeholder,
+                attribute: a,
+                value: PatternValuePlace::EntidOrInteger(5),
+                tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
+            }).unwrap();
+            cc.wheres.pop().unwrap()
+        };
+
+        assert_eq!(apply(&schema, PatternNonValuePlace::Ident(NamespacedKeyword::new("foo", "ref"))),
+                   ColumnConstraint::EqualsEntity(QualifiedAlias("datoms00".to_string(), DatomsColumn::Value), 5));
+        assert_eq!(apply(&schema, PatternNonValuePlace::Ident(NamespacedKeyword::new("foo", "double"))),
+                   ColumnConstraint::EqualsValue(QualifiedAlias("datoms00".to_string(), DatomsColumn::Value), TypedValue::Double(5.0.into())));
+        assert_eq!(apply(&schema, PatternNonValuePlace::Variable(variable("?a"))),
+                   ColumnConstraint::EqualsRefOrLong(QualifiedAlias("all_datoms00".to_string(), DatomsColumn::Value), 5));
+    }
+
+    #[test]
+    fn test_value_bindings() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+
+        let mut bindings = BTreeMap::new();
+        bindings.insert(variable("?e"), TypedValue::Ref(65536));
+        bindings.insert(variable("?v"), TypedValue::String("yyy".to_string()));
+        let mut cc = ConjoiningClauses::with_value_bindings(bindings);
+
+        cc.apply_pattern(&schema, &Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Variable(variable("?e")),
+            attribute: PatternNonValuePlace::Ident(NamespacedKeyword::new("foo", "bar")),
+            value: PatternValuePlace::Variable(variable("?v")),
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
+        }).unwrap();
+
+        assert!(!cc.is_known_empty);
+        assert!(cc.column_bindings.is_empty());
+        assert_eq!(cc.wheres, vec![ColumnConstraint::EqualsEntity(QualifiedAlias("datoms00".to_string(), DatomsColumn::Entity), 65536),
+                                   ColumnConstraint::EqualsEntity(QualifiedAlias("datoms00".to_string(), DatomsColumn::Attribute), 99),
+                                   ColumnConstraint::EqualsValue(QualifiedAlias("datoms00".to_string(), DatomsColumn::Value), TypedValue::String("yyy".to_string()))]);
+    }
+
+    #[test]
+    fn test_join_and_type_mismatch() {
+        let mut cc = ConjoiningClauses::default();
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::Boolean,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, ":foo/knows", 100, Attribute::default());
+
+        let x = variable("?x");
+        cc.apply_pattern(&schema, &Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Variable(x.clone()),
+            attribute: PatternNonValuePlace::Ident(NamespacedKeyword::new("foo", "bar")),
+            value: PatternValuePlace::Constant(NonIntegerConstant::Boolean(true)),
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
+        }).unwrap();
+        cc.apply_pattern(&schema, &Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Placeholder,
+            attribute: PatternNonValuePlace::Ident(NamespacedKeyword::new("foo", "knows")),
+            value: PatternValuePlace::Variable(x.clone()),
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
+        }).unwrap();
+
+        assert!(!cc.is_known_empty);
+        assert_eq!(cc.from, vec![SourceAlias(DatomsTable::Datoms, "datoms00".to_string()),
+                                 SourceAlias(DatomsTable::Datoms, "datoms01".to_string())]);
+        let d0e = QualifiedAlias("datoms00".to_string(), DatomsColumn::Entity);
+        let d0a = QualifiedAlias("datoms00".to_string(), DatomsColumn::Attribute);
+        let d0v = QualifiedAlias("datoms00".to_string(), DatomsColumn::Value);
+        let d1a = QualifiedAlias("datoms01".to_string(), DatomsColumn::Attribute);
+        let d1v = QualifiedAlias("datoms01".to_string(), DatomsColumn::Value);
+        assert_eq!(cc.wheres, vec![ColumnConstraint::EqualsEntity(d0a, 99),
+                                   ColumnConstraint::EqualsValue(d0v, TypedValue::Boolean(true)),
+                                   ColumnConstraint::EqualsEntity(d1a, 100),
+                                   ColumnConstraint::EqualsColumn(d0e.clone(), d1v.clone())]);
+        assert_eq!(cc.column_bindings.get(&x), Some(&vec![d0e, d1v]));
+
+        // :foo/bar is boolean-valued, so a string value can't match.
+        cc.apply_pattern(&schema, &Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Variable(x.clone()),
+            attribute: PatternNonValuePlace::Ident(NamespacedKeyword::new("foo", "bar")),
+            value: PatternValuePlace::Constant(NonIntegerConstant::Text("yes".to_string())),
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
+        }).unwrap();
+        assert!(cc.is_known_empty);
+        assert_eq!(cc.empty_because, Some(EmptyBecause::ValueTypeMismatch(ValueType::Boolean, TypedValue::String("yes".to_string()))));
+    }
+
+    #[test]
+    fn test_added() {
+        let mut cc = ConjoiningClauses::default();
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+
+        let added = variable("?added");
+        cc.apply_pattern(&schema, &Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Placeholder,
+            attribute: PatternNonValuePlace::Ident(NamespacedKeyword::new("foo", "bar")),
+            value: PatternValuePlace::Placeholder,
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Variable(added.clone()),
+        }).unwrap();
+        cc.apply_pattern(&schema, &Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Placeholder,
+            attribute: PatternNonValuePlace::Ident(NamespacedKeyword::new("foo", "bar")),
+            value: PatternValuePlace::Placeholder,
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Constant(NonIntegerConstant::Boolean(false)),
+        }).unwrap();
+
+        assert!(!cc.is_known_empty);
+        assert_eq!(cc.column_bindings.get(&added), Some(&vec![QualifiedAlias("datoms00".to_string(), DatomsColumn::Added)]));
+        assert_eq!(cc.wheres.last(), Some(&ColumnConstraint::EqualsAdded(QualifiedAlias("datoms01".to_string(), DatomsColumn::Added), false)));
+    }
+
+    #[test]
+    fn test_fulltext() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/text", 99, Attribute {
+            value_type: ValueType::String,
+            fulltext: true,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, ":foo/name", 100, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+
+        let fulltext = |attribute: &str, binding: Vec<Option<Variable>>| WhereFn {
+            operator: PlainSymbol::new("fulltext"),
+            args: vec![FnArg::SrcVar(SrcVar::DefaultSrc),
+                       FnArg::Ident(NamespacedKeyword::new("foo", attribute)),
+                       FnArg::Constant(NonIntegerConstant::Text("search".to_string()))],
+            binding: Binding::BindRel(binding),
+        };
+
+        let mut cc = ConjoiningClauses::default();
+        cc.apply_where_fn(&schema, &fulltext("text", vec![Some(variable("?e")), None, None, Some(variable("?score"))])).unwrap();
+        assert!(!cc.is_known_empty);
+        assert_eq!(cc.from, vec![SourceAlias(DatomsTable::FulltextValues, "fulltext_values00".to_string()),
+                                 SourceAlias(DatomsTable::Datoms, "datoms01".to_string())]);
+        assert_eq!(cc.fulltext_searches.get("fulltext_values00"),
+                   Some(&FulltextSearch { table: "fulltext_values".to_string(), terms: "search".to_string() }));
+        assert_eq!(cc.wheres, vec![ColumnConstraint::EqualsEntity(QualifiedAlias("datoms01".to_string(), DatomsColumn::Attribute), 99),
+                                   ColumnConstraint::EqualsColumn(QualifiedAlias("datoms01".to_string(), DatomsColumn::Value),
+                                                                  QualifiedAlias("fulltext_values00".to_string(), DatomsColumn::Rowid))]);
+        assert_eq!(cc.binding_for_var(&variable("?e")), Some(&QualifiedAlias("datoms01".to_string(), DatomsColumn::Entity)));
+        assert_eq!(cc.binding_for_var(&variable("?score")), Some(&QualifiedAlias("fulltext_values00".to_string(), DatomsColumn::Score)));
+
+        // Only fulltext attributes can be searched.
+        let mut cc = ConjoiningClauses::default();
+        match cc.apply_where_fn(&schema, &fulltext("name", vec![Some(variable("?e"))])) {
+            Err(Error(ErrorKind::InvalidArgument(_), _)) => (),
+            x => panic!("expected InvalidArgument, got {:?}", x),
+        }
+
+        // An unknown attribute can't match anything.
+        let mut cc = ConjoiningClauses::default();
+        cc.apply_where_fn(&schema, &fulltext("unknown", vec![Some(variable("?e"))])).unwrap();
+        assert!(cc.is_known_empty);
+        assert_eq!(cc.empty_because, Some(EmptyBecause::UnknownAttribute(":foo/unknown".to_string())));
+
+        // An attribute with its own tokenizer is searched in its own table.
+        add_attribute(&mut schema, ":foo/stemmed", 101, Attribute {
+            value_type: ValueType::String,
+            fulltext: true,
+            fulltext_tokenizer: Some("porter unicode61".to_string()),
+            ..Default::default()
+        });
+        let mut cc = ConjoiningClauses::default();
+        cc.apply_where_fn(&schema, &fulltext("stemmed", vec![Some(variable("?e"))])).unwrap();
+        assert_eq!(cc.fulltext_searches.get("fulltext_values00").map(|search| search.table.as_str()), Some("fulltext_values_101"));
+    }
+
+    #[test]
+    fn test_json_get() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/json", 99, Attribute {
+            value_type: ValueType::Json,
+            ..Default::default()
+        });
+
+        let json_get = |document: &str, binding: Binding| WhereFn {
+            operator: PlainSymbol::new("json-get"),
+            args: vec![FnArg::Variable(variable(document)),
+                       FnArg::Constant(NonIntegerConstant::Text("$.name".to_string()))],
+            binding: binding,
+        };
+
+        let mut cc = ConjoiningClauses::default();
+        cc.apply_pattern(&schema, &Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Variable(variable("?e")),
+            attribute: PatternNonValuePlace::Ident(NamespacedKeyword::new("foo", "json")),
+            value: PatternValuePlace::Variable(variable("?v")),
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
+        }).unwrap();
+        cc.apply_where_fn(&schema, &json_get("?v", Binding::BindScalar(variable("?name")))).unwrap();
+        assert_eq!(cc.from, vec![SourceAlias(DatomsTable::Datoms, "datoms00".to_string()),
+                                 SourceAlias(DatomsTable::JsonValues, "json_values01".to_string())]);
+        assert_eq!(cc.json_paths.get("json_values01"), Some(&"$.name".to_string()));
+        assert_eq!(cc.wheres[1..].to_vec(),
+                   vec![ColumnConstraint::HasTypeTag(QualifiedAlias("datoms00".to_string(), DatomsColumn::ValueTypeTag), 14),
+                        ColumnConstraint::EqualsColumn(QualifiedAlias("datoms00".to_string(), DatomsColumn::Value),
+                                                       QualifiedAlias("json_values01".to_string(), DatomsColumn::Json))]);
+        assert_eq!(cc.binding_for_var(&variable("?name")), Some(&QualifiedAlias("json_values01".to_string(), DatomsColumn::Value)));
+
+        // The document must already be bound.
+        let mut cc = ConjoiningClauses::default();
+        match cc.apply_where_fn(&schema, &json_get("?v", Binding::BindScalar(variable("?name")))) {
+            Err(Error(ErrorKind::UnboundVariable(_), _)) => (),
+            x => panic!("expected UnboundVariable, got {:?}", x),
+        }
+
+        // Only scalar bindings make sense.
+        match cc.apply_where_fn(&schema, &json_get("?v", Binding::BindRel(vec![Some(variable("?name"))]))) {
+            Err(Error(ErrorKind::InvalidArgument(_), _)) => (),
+            x => panic!("expected InvalidArgument, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_encrypted_value() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/token", 99, Attribute {
+            value_type: ValueType::String,
+            encrypted: true,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, ":foo/name", 98, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+        let pattern = |a: &str, v: PatternValuePlace| WhereClause::Pattern(Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Variable(variable("?e")),
+            attribute: PatternNonValuePlace::Ident(NamespacedKeyword::new("foo", a)),
+            value: v,
+            tx: PatternNonValuePlace::Placeholder,
+            added: PatternValuePlace::Placeholder,
+        });
+        let apply = |clauses: Vec<WhereClause>| {
+            let mut cc = ConjoiningClauses::default();
+            cc.apply_clauses(&schema, clauses.iter()).map(|_| cc)
+        };
+        let is_encrypted_value = |result: Result<ConjoiningClauses>| {
+            match result {
+                Err(Error(ErrorKind::EncryptedValue(_), _)) => true,
+                _ => false,
+            }
+        };
+
+        // Encrypted values can be bound to fresh variables, or ignored.
+        let cc = apply(vec![pattern("token", PatternValuePlace::Variable(variable("?t")))]).unwrap();
+        assert!(cc.encrypted_vars.contains(&variable("?t")));
+        assert!(apply(vec![pattern("token", PatternValuePlace::Placeholder)]).is_ok());
+
+        // But not compared.
+        assert!(is_encrypted_value(apply(vec![pattern("token", PatternValuePlace::Constant(NonIntegerConstant::Text("x".to_string())))])));
+        assert!(is_encrypted_value(apply(vec![pattern("name", PatternValuePlace::Variable(variable("?v"))),
+                                              pattern("token", PatternValuePlace::Variable(variable("?v")))])));
+        assert!(is_encrypted_value(apply(vec![pattern("token", PatternValuePlace::Variable(variable("?v"))),
+                                              pattern("name", PatternValuePlace::Variable(variable("?v")))])));
+    }
+}
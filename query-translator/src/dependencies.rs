@@ -0,0 +1,162 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! What a query reads: the attributes its clauses name, and whether it reads the store's history.
+//!
+//! A query's results can only change when a transaction asserts or retracts a datom of an
+//! attribute it depends on, so caches, live queries, and sync filters can skip the transactions
+//! that touch none of them.
+
+use std::collections::BTreeSet;
+
+use mentat_db::{Entid, Schema};
+use mentat_query::{FindQuery, FnArg, PatternNonValuePlace, WhereClause};
+
+/// The attributes and history a query reads.
+#[derive(Clone,Debug,Default,Eq,PartialEq)]
+pub struct Dependencies {
+    /// The attributes named by the query's patterns and functions.  Idents that aren't installed
+    /// are left out: they match nothing until they're installed, which changes the schema.
+    pub attributes: BTreeSet<Entid>,
+
+    /// `true` if a pattern can match any attribute, like `[?e ?a ?v]`, so the query depends on
+    /// every attribute.
+    pub any_attribute: bool,
+
+    /// `true` if the query reads the store's history, with `:as-of`, `:since`, or `:history`.
+    pub history: bool,
+}
+
+impl Dependencies {
+    /// `true` if a transaction that changed the given attributes can change the query's results.
+    pub fn depends_on(&self, changed: &BTreeSet<Entid>) -> bool {
+        if self.any_attribute {
+            !changed.is_empty()
+        } else {
+            !self.attributes.is_disjoint(changed)
+        }
+    }
+
+    /// The attributes the query reads, or `None` if it can read any attribute.
+    pub fn known_attributes(&self) -> Option<&BTreeSet<Entid>> {
+        if self.any_attribute { None } else { Some(&self.attributes) }
+    }
+
+    fn add_ident(&mut self, schema: &Schema, ident: String) {
+        if let Some(&entid) = schema.get_entid(&ident) {
+            self.attributes.insert(entid);
+        }
+    }
+
+    fn add_clause(&mut self, schema: &Schema, clause: &WhereClause) {
+        match clause {
+            &WhereClause::Pattern(ref pattern) => {
+                match pattern.attribute {
+                    PatternNonValuePlace::Entid(entid) => { self.attributes.insert(entid); },
+                    PatternNonValuePlace::Ident(ref kw) => self.add_ident(schema, kw.to_string()),
+                    PatternNonValuePlace::Placeholder | PatternNonValuePlace::Variable(_) => self.any_attribute = true,
+                }
+            },
+            // Functions read the attributes they name, like `fulltext`, and the values bound by
+            // patterns.
+            &WhereClause::WhereFn(ref where_fn) => {
+                for arg in where_fn.args.iter() {
+                    if let &FnArg::Ident(ref kw) = arg {
+                        self.add_ident(schema, kw.to_string());
+                    }
+                }
+                // These name their attribute in their second argument, maybe by entid.
+                match where_fn.operator.0.as_str() {
+                    "fulltext" | "ancestors" | "descendants" => {
+                        if let Some(&FnArg::EntidOrInteger(entid)) = where_fn.args.get(1) {
+                            self.attributes.insert(entid);
+                        }
+                    },
+                    // Every transaction adds to the log.
+                    "tx-ids" | "tx-data" => self.any_attribute = true,
+                    _ => (),
+                }
+            },
+            // Predicates only test the values bound by other clauses.
+            &WhereClause::Pred(_) => (),
+            &WhereClause::OrJoin(ref or_join) => {
+                for leg in or_join.clauses.iter() {
+                    for clause in leg.clauses() {
+                        self.add_clause(schema, clause);
+                    }
+                }
+            },
+            // A change to a datom matched by a `not` can add results as well as remove them.
+            &WhereClause::NotJoin(ref not_join) => {
+                for clause in not_join.clauses.iter() {
+                    self.add_clause(schema, clause);
+                }
+            },
+        }
+    }
+}
+
+/// Finding what a parsed query reads, like `query.dependencies(&schema)`.
+pub trait QueryDependencies {
+    fn dependencies(&self, schema: &Schema) -> Dependencies;
+}
+
+impl QueryDependencies for FindQuery {
+    fn dependencies(&self, schema: &Schema) -> Dependencies {
+        let mut dependencies = Dependencies::default();
+        for clause in self.where_clauses.iter() {
+            dependencies.add_clause(schema, clause);
+        }
+        dependencies.history = self.as_of.is_some() || self.since.is_some() || self.history;
+        dependencies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mentat_db::{Attribute, IdentMap, SchemaMap};
+    use mentat_query_parser::parse_find_string;
+
+    fn schema() -> Schema {
+        let mut ident_map = IdentMap::new();
+        let mut schema_map = SchemaMap::new();
+        for &(ident, entid) in [(":test/name", 100), (":test/age", 101), (":test/doc", 102)].iter() {
+            ident_map.insert(ident.to_string(), entid);
+            schema_map.insert(entid, Attribute::default());
+        }
+        Schema::from(ident_map, schema_map).unwrap()
+    }
+
+    fn dependencies(query: &str) -> Dependencies {
+        parse_find_string(query).unwrap().dependencies(&schema())
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let found = dependencies(r#"[:find ?e :where [?e :test/name "Alice"] (not [?e :test/age 30]) (or [?e 102 "x"] [?e :test/missing _])]"#);
+        assert_eq!(found.attributes, vec![100, 101, 102].into_iter().collect());
+        assert!(!found.any_attribute);
+        assert!(!found.history);
+        assert!(found.depends_on(&vec![101].into_iter().collect()));
+        assert!(!found.depends_on(&vec![103].into_iter().collect()));
+
+        let found = dependencies(r#"[:find ?e ?text :where [(fulltext $ :test/doc "x") [[?e ?text]]]]"#);
+        assert_eq!(found.known_attributes(), Some(&vec![102].into_iter().collect()));
+
+        let found = dependencies(r#"[:find ?v :where [?e :test/name "Alice"] [?e ?a ?v]]"#);
+        assert!(found.any_attribute);
+        assert_eq!(found.known_attributes(), None);
+        assert!(found.depends_on(&vec![103].into_iter().collect()));
+
+        assert!(dependencies(r#"[:find ?e :history true :where [?e :test/name _]]"#).history);
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+use mentat_db;
+use mentat_query::Variable;
+
+error_chain! {
+    types {
+        Error, ErrorKind, ResultExt, Result;
+    }
+
+    links {
+        DbError(mentat_db::Error, mentat_db::ErrorKind);
+    }
+
+    errors {
+        /// We're just not done yet.  Message that the feature is recognized but not yet
+        /// implemented.
+        NotYetImplemented(t: String) {
+            description("not yet implemented")
+            display("not yet implemented: {}", t)
+        }
+
+        /// A variable in the `:find` spec isn't bound by any `:where` clause.
+        UnboundVariable(var: Variable) {
+            description("unbound variable in :find")
+            display("unbound variable in :find: {}", (var.0).0)
+        }
+
+        /// A where-function was called with arguments it can't use.
+        InvalidArgument(t: String) {
+            description("invalid argument to where-function")
+            display("invalid argument to where-function: {}", t)
+        }
+
+        /// A variable named by `:in` wasn't given a value.
+        MissingInput(var: Variable) {
+            description("missing input for :in variable")
+            display("missing input for :in variable: {}", (var.0).0)
+        }
+
+        /// A pattern asks whether a datom was added, which only a `:history` query can answer.
+        RequiresHistory(t: String) {
+            description("pattern binds added outside a :history query")
+            display("pattern binds added outside a :history query: {}", t)
+        }
+
+        /// A variable unified by a `not` or `not-join` isn't bound outside of it.
+        UnboundInScope(var: Variable, clause: String) {
+            description("variable must be bound outside of not")
+            display("variable {} must be bound outside of {}", (var.0).0, clause)
+        }
+
+        /// A variable unified by an `or` or `or-join` isn't bound by one of its legs.
+        NonUnifiedVariable(var: Variable, clause: String) {
+            description("variable is not bound by every leg of or")
+            display("variable {} is not bound by every leg of {}", (var.0).0, clause)
+        }
+
+        /// An index hint can't be applied to the pattern it was given for.
+        InvalidIndexHint(t: String) {
+            description("invalid index hint")
+            display("invalid index hint: {}", t)
+        }
+
+        /// The rows given for a collection or relation input, named by its first variable, don't
+        /// fit its binding.
+        InvalidInput(var: Variable, t: String) {
+            description("invalid input for :in binding")
+            display("invalid input for :in binding {}: {}", (var.0).0, t)
+        }
+
+        /// A value was given for a variable not named by `:in`.
+        UnknownInput(var: Variable) {
+            description("input given for variable not named by :in")
+            display("input given for variable not named by :in: {}", (var.0).0)
+        }
+
+        /// A clause constrains the value of an encrypted attribute, which is stored as ciphertext
+        /// that SQL can't compare.  Encrypted values can only be bound to fresh variables.
+        EncryptedValue(t: String) {
+            description("encrypted values can't be constrained")
+            display("encrypted values can't be constrained: {}", t)
+        }
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#[macro_use]
+extern crate error_chain;
+
+extern crate edn;
+extern crate mentat_db;
+extern crate mentat_query;
+#[cfg(test)]
+extern crate mentat_query_parser;
+
+pub use errors::*;
+
+pub mod cc;
+mod dependencies;
+mod errors;
+mod translate;
+
+pub use cc::{EmptyBecause, IndexHint};
+pub use dependencies::{Dependencies, QueryDependencies};
+
+pub use translate::{
+    AlgebraicQuery,
+    History,
+    IndexHints,
+    InputTable,
+    MAX_SQL_VARIABLES,
+    QueryInputs,
+    RelationInputs,
+    SQLQuery,
+    TempTable,
+    algebrize,
+    algebrize_with_hints,
+    algebrize_with_inputs,
+    algebrize_with_relations,
+    find_spec_names,
+    find_spec_variables,
+    query_to_select,
+    translate,
+    translate_with_hints,
+    translate_with_inputs,
+    translate_with_relations,
+};
@@ -0,0 +1,1555 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! This module turns a parsed `FindQuery` into a SQL `SELECT` statement, by way of the
+//! `ConjoiningClauses` algebra.
+
+use std::collections::BTreeMap;
+use std::mem;
+
+use mentat_db::{Entid, Schema, TypedValue, TUPLE_VALUE_TYPE_TAG};
+use mentat_query::{
+    Element,
+    FindQuery,
+    FindSpec,
+    OrJoin,
+    PatternValuePlace,
+    PointInTime,
+    Variable,
+    WhereClause,
+    is_unit_limited,
+    requires_distinct,
+};
+
+use cc::{
+    Closure,
+    ColumnConstraint,
+    ConjoiningClauses,
+    DatomsColumn,
+    DatomsTable,
+    EmptyBecause,
+    IndexHint,
+    InstantFn,
+    QualifiedAlias,
+    SourceAlias,
+    TableAlias,
+    TimeUnit,
+    TxData,
+    TxRange,
+    Union,
+};
+use errors::*;
+
+/// The part of the store's history a query runs against, from its `:as-of`, `:since`, and
+/// `:history`.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct History {
+    pub as_of: Option<PointInTime>,
+    pub since: Option<PointInTime>,
+
+    /// `true` to query the log itself, every assertion and retraction, rather than the datoms
+    /// present at a point.
+    pub log: bool,
+
+    /// The entid of `:db/txInstant`, used to find the last transaction at or before an instant.
+    pub tx_instant: Entid,
+
+    /// The fulltext attributes, whose values are logged as rowids into `fulltext_values`.
+    pub fulltext_attributes: Vec<Entid>,
+}
+
+/// A query in algebraic form: the find spec and the conjoined `:where` clauses.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct AlgebraicQuery {
+    pub find_spec: FindSpec,
+    pub cc: ConjoiningClauses,
+
+    /// `None` to query the current state of the store.
+    pub history: Option<History>,
+
+    /// The alias of each top-level pattern, keyed by the pattern's position in `:where`.
+    pub pattern_aliases: BTreeMap<usize, TableAlias>,
+
+    /// `true` to project refs to entities that have idents as those idents; see
+    /// `FindQuery::idents`.
+    pub idents: bool,
+}
+
+/// A SQL query, ready to be executed, with its named arguments.
+///
+/// Each variable in the find spec projects to two columns: the value, and its value type tag.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct SQLQuery {
+    /// Empty if the query is known to be empty: there's nothing to run.
+    pub sql: String,
+
+    /// Named arguments, like `$v0`, and the typed values to bind to them.
+    pub args: Vec<(String, TypedValue)>,
+
+    pub find_spec: FindSpec,
+
+    /// Why the query can't yield results, if the algebrizer could tell without running it.
+    pub empty_because: Option<EmptyBecause>,
+
+    /// The alias of each top-level pattern, keyed by the pattern's position in `:where`.
+    pub pattern_aliases: BTreeMap<usize, TableAlias>,
+
+    /// Inputs too large to pass as arguments, which must be written to temporary tables before
+    /// the query runs.
+    pub input_tables: Vec<InputTable>,
+
+    /// Subqueries the query reads more than once, which must be run into temporary tables, in
+    /// order, before the query runs.
+    pub temp_tables: Vec<TempTable>,
+}
+
+/// SQLite's default limit on the number of arguments to a statement, `SQLITE_MAX_VARIABLE_NUMBER`.
+pub const MAX_SQL_VARIABLES: usize = 999;
+
+/// The rows of a collection or relation input too large to pass as arguments.  The query reads
+/// them from the temporary table `temp.<name>`, which holds a value column `v<i>` and a type tag
+/// column `value_type_tag<i>` for the ith variable of the input.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct InputTable {
+    /// The table's name, which is the alias of the input, like `inputs00`.
+    pub name: String,
+    /// The number of variables bound by each row.
+    pub width: usize,
+    pub rows: Vec<Vec<TypedValue>>,
+}
+
+/// The rows of a subquery that a query reads more than once, like an `or` clause repeated in each
+/// leg of an enclosing `or`.  Rather than inline the subquery at each use, the query reads the
+/// temporary table `temp.<name>`, which is filled once by running `sql` with `args`.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct TempTable {
+    /// The table's name, which is the alias of the first use of the subquery, like `union02`.
+    pub name: String,
+    /// A `SELECT` statement for the subquery's rows.
+    pub sql: String,
+    /// Named arguments of `sql`, like `$v0`, and the typed values to bind to them.
+    pub args: Vec<(String, TypedValue)>,
+}
+
+/// Return the variables projected by the given find spec, in order.
+///
+/// A variable may appear more than once, under different names.
+pub fn find_spec_variables(spec: &FindSpec) -> Vec<&Variable> {
+    spec.elements().into_iter().map(Element::variable).collect()
+}
+
+/// Return the names of the columns projected by the given find spec, in order.
+pub fn find_spec_names(spec: &FindSpec) -> Vec<String> {
+    spec.elements().into_iter().map(Element::name).collect()
+}
+
+/// Values for the variables named by a query's `:in`.
+pub type QueryInputs = BTreeMap<Variable, TypedValue>;
+
+/// Rows for the collection and relation inputs named by a query's `:in`, like `[?x ...]` and
+/// `[[?x ?y]]`, keyed by the variables of the binding.  Each row of a collection holds one value.
+pub type RelationInputs = BTreeMap<Vec<Variable>, Vec<Vec<TypedValue>>>;
+
+/// Convert the given `FindQuery` into algebraic form in the context of the given `schema`.
+pub fn algebrize(schema: &Schema, query: &FindQuery) -> Result<AlgebraicQuery> {
+    algebrize_with_inputs(schema, query, QueryInputs::new())
+}
+
+/// Index hints for some of a query's patterns, keyed by the position of the pattern in `:where`.
+pub type IndexHints = BTreeMap<usize, IndexHint>;
+
+/// Convert the given `FindQuery` into algebraic form, treating the `:in` variables as constants
+/// with the given values.  Every `:in` variable must have a value.
+pub fn algebrize_with_inputs(schema: &Schema, query: &FindQuery, inputs: QueryInputs) -> Result<AlgebraicQuery> {
+    algebrize_query(schema, query, inputs, RelationInputs::new(), &IndexHints::new())
+}
+
+/// Like `algebrize_with_inputs`, but with index hints for some of the query's top-level patterns.
+pub fn algebrize_with_hints(schema: &Schema, query: &FindQuery, inputs: QueryInputs, hints: &IndexHints) -> Result<AlgebraicQuery> {
+    algebrize_query(schema, query, inputs, RelationInputs::new(), hints)
+}
+
+/// Like `algebrize_with_inputs`, but with rows for the query's collection and relation inputs
+/// too.  Every such input must have rows, though there may be none of them.
+pub fn algebrize_with_relations(schema: &Schema, query: &FindQuery, inputs: QueryInputs, relations: RelationInputs) -> Result<AlgebraicQuery> {
+    algebrize_query(schema, query, inputs, relations, &IndexHints::new())
+}
+
+fn algebrize_query(schema: &Schema, query: &FindQuery, inputs: QueryInputs, mut relations: RelationInputs, hints: &IndexHints) -> Result<AlgebraicQuery> {
+    for var in query.in_vars.iter() {
+        if !inputs.contains_key(var) {
+            bail!(ErrorKind::MissingInput(var.clone()));
+        }
+    }
+    for var in inputs.keys() {
+        if !query.in_vars.contains(var) {
+            bail!(ErrorKind::UnknownInput(var.clone()));
+        }
+    }
+    // Rows that bind no variables can't change the results, so they're not worth an error.
+    for vars in relations.keys().filter(|vars| !query.in_rels.contains(vars)) {
+        if let Some(var) = vars.first() {
+            bail!(ErrorKind::UnknownInput(var.clone()));
+        }
+    }
+
+    let mut cc = ConjoiningClauses::with_value_bindings(inputs);
+    for vars in query.in_rels.iter() {
+        match relations.remove(vars) {
+            Some(rows) => cc.apply_input_rows(vars, rows)?,
+            None => bail!(ErrorKind::MissingInput(vars[0].clone())),
+        }
+    }
+    cc.apply_clauses(schema, query.where_clauses.iter())?;
+
+    // Top-level patterns are applied in order, each with an alias of its own.
+    let pattern_aliases: BTreeMap<usize, TableAlias> = query.where_clauses.iter()
+        .enumerate()
+        .filter(|&(_, clause)| match *clause {
+            WhereClause::Pattern(_) => true,
+            _ => false,
+        })
+        .map(|(i, _)| i)
+        .zip(cc.pattern_aliases.iter().cloned())
+        .collect();
+    for (i, hint) in hints.iter() {
+        match pattern_aliases.get(i) {
+            Some(alias) => { cc.index_hints.insert(alias.clone(), hint.clone()); },
+            None => bail!(ErrorKind::InvalidIndexHint(format!("clause {} of :where is not a pattern", i))),
+        }
+    }
+
+    if !query.history {
+        for clause in query.where_clauses.iter() {
+            check_added(clause)?;
+        }
+    }
+
+    let history = if query.as_of.is_some() || query.since.is_some() || query.history {
+        Some(History {
+            as_of: query.as_of,
+            since: query.since,
+            log: query.history,
+            tx_instant: *schema.require_entid(&":db/txInstant".to_string())?,
+            fulltext_attributes: schema.schema_map.iter()
+                .filter(|&(_, attribute)| attribute.fulltext)
+                .map(|(&entid, _)| entid)
+                .collect(),
+        })
+    } else {
+        None
+    };
+
+    Ok(AlgebraicQuery {
+        find_spec: query.find_spec.clone(),
+        cc: cc,
+        history: history,
+        pattern_aliases: pattern_aliases,
+        idents: query.idents,
+    })
+}
+
+/// Fail if the given clause, or any clause nested within it, asks whether a datom was added.
+fn check_added(clause: &WhereClause) -> Result<()> {
+    match *clause {
+        WhereClause::Pattern(ref pattern) => {
+            if pattern.added != PatternValuePlace::Placeholder {
+                bail!(ErrorKind::RequiresHistory(format!("{:?}", pattern.added)));
+            }
+        },
+        WhereClause::WhereFn(_) | WhereClause::Pred(_) => (),
+        WhereClause::OrJoin(ref or_join) => {
+            for leg in or_join.clauses.iter() {
+                for clause in leg.clauses() {
+                    check_added(clause)?;
+                }
+            }
+        },
+        WhereClause::NotJoin(ref not_join) => {
+            for clause in not_join.clauses.iter() {
+                check_added(clause)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+fn column_sql(column: &QualifiedAlias) -> String {
+    format!("{}.{}", column.0, column.1.name())
+}
+
+/// Return SQL for the value type tag of the value in the given column.
+///
+/// Only value columns have a meaningful tag; extracted JSON values and unified variables have theirs
+/// too.  Fulltext text and snippets are strings, scores are doubles, `added` is a boolean, depths
+/// and instants are longs, and everything else is an entid.
+fn type_tag_sql(column: &QualifiedAlias) -> String {
+    match column.1 {
+        DatomsColumn::Value | DatomsColumn::Unified(_) => column_sql(&column.for_type_tag()),
+        DatomsColumn::Added => "1".to_string(),
+        DatomsColumn::Text | DatomsColumn::Snippet => "10".to_string(),
+        DatomsColumn::Score | DatomsColumn::Depth | DatomsColumn::Instant => "5".to_string(),
+        _ => "0".to_string(),
+    }
+}
+
+/// Return SQL for the ID of the transaction at the given point in time.
+fn tx_sql(point: PointInTime, history: &History) -> String {
+    match point {
+        PointInTime::Tx(tx) => tx.to_string(),
+        // There may be no such transaction, in which case nothing happened at or before the instant.
+        PointInTime::Instant(instant) => {
+            format!("(SELECT COALESCE(MAX(e), 0) FROM datoms WHERE a = {} AND v <= {})", history.tx_instant, instant)
+        },
+    }
+}
+
+/// Return the SQL value expression, extra `FROM` tables, and extra filter with which to read the
+/// given datoms table from the log `transactions AS t`, or `None` if the table isn't read from the
+/// log.
+fn logged_value_sql(table: DatomsTable, history: &History) -> Option<(String, String, String)> {
+    let fulltext: Vec<String> = history.fulltext_attributes.iter().map(|a| a.to_string()).collect();
+    let value = match table {
+        DatomsTable::Datoms => ("t.v".to_string(), "".to_string(), "".to_string()),
+        DatomsTable::FulltextDatoms => {
+            ("f.text".to_string(),
+             ", fulltext_values AS f".to_string(),
+             format!(" AND t.a IN ({}) AND t.v = f.rowid", fulltext.join(", ")))
+        },
+        DatomsTable::AllDatoms if fulltext.is_empty() => ("t.v".to_string(), "".to_string(), "".to_string()),
+        DatomsTable::AllDatoms => {
+            (format!("CASE WHEN t.a IN ({}) THEN (SELECT text FROM fulltext_values WHERE rowid = t.v) ELSE t.v END", fulltext.join(", ")),
+             "".to_string(),
+             "".to_string())
+        },
+        // Fulltext values aren't logged; searches don't depend on history.
+        DatomsTable::FulltextValues => return None,
+        // JSON values are extracted from the whole log; see `json_values_sql`.
+        DatomsTable::JsonValues => return None,
+        // Likewise tuples; see `tuple_values_sql`.
+        DatomsTable::TupleValues => return None,
+        // Likewise instants; see `instant_values_sql`.
+        DatomsTable::InstantValues => return None,
+        // The rest are computed from other tables, or read from the log already.
+        DatomsTable::Union | DatomsTable::Inputs | DatomsTable::Closure | DatomsTable::TxIds | DatomsTable::TxData => return None,
+    };
+    Some(value)
+}
+
+/// Return SQL for every assertion and retraction of the given datoms table in the log, with the
+/// `added` column, restricted by `:as-of` and `:since`.
+fn log_table_sql(table: DatomsTable, history: &History) -> String {
+    let (v, from, filter) = match logged_value_sql(table, history) {
+        Some(value) => value,
+        None => return table.name().to_string(),
+    };
+    let as_of = match history.as_of {
+        Some(as_of) => format!(" AND t.tx <= {}", tx_sql(as_of, history)),
+        None => "".to_string(),
+    };
+    let since = match history.since {
+        Some(since) => format!(" AND t.tx > {}", tx_sql(since, history)),
+        None => "".to_string(),
+    };
+
+    format!("(SELECT t.e AS e, t.a AS a, {} AS v, t.tx AS tx, t.value_type_tag AS value_type_tag, t.added AS added \
+             FROM transactions AS t{} \
+             WHERE 1{}{}{})",
+            v, from, as_of, since, filter)
+}
+
+/// Return SQL for the contents of the given datoms table, restricted to part of the store's history.
+///
+/// For `:history`, we read the log itself; see `log_table_sql`.  For `:as-of`, we replay the
+/// transaction log: a datom is present if the latest log entry for it at or before the `:as-of`
+/// transaction is an assertion.  For `:since`, we keep only datoms whose `tx` is after the `:since`
+/// transaction.
+fn history_table_sql(table: DatomsTable, history: &History) -> String {
+    if history.log {
+        return log_table_sql(table, history);
+    }
+
+    let as_of = match history.as_of {
+        Some(as_of) => tx_sql(as_of, history),
+        None => {
+            let since = history.since.map(|since| tx_sql(since, history)).unwrap_or("0".to_string());
+            return format!("(SELECT * FROM {} WHERE tx > {})", table.name(), since);
+        },
+    };
+
+    let (v, from, filter) = match logged_value_sql(table, history) {
+        Some(value) => value,
+        None => return table.name().to_string(),
+    };
+    let since = match history.since {
+        Some(since) => format!(" AND t.tx > {}", tx_sql(since, history)),
+        None => "".to_string(),
+    };
+
+    format!("(SELECT t.e AS e, t.a AS a, {} AS v, t.tx AS tx, t.value_type_tag AS value_type_tag \
+             FROM transactions AS t{} \
+             WHERE t.added IS NOT 0 AND t.tx <= {}{}{} \
+             AND t.rowid = (SELECT l.rowid FROM transactions AS l \
+             WHERE l.e = t.e AND l.a = t.a AND l.value_type_tag = t.value_type_tag AND l.v = t.v AND l.tx <= {} \
+             ORDER BY l.tx DESC, l.rowid DESC LIMIT 1))",
+            v, from, as_of, since, filter, as_of)
+}
+
+/// Return SQL for the entities reached by the given walk over the datoms in `datoms`, each with the
+/// least number of steps it took to reach it from each start entity.
+///
+/// Each row carries the entities its path has reached so far, as `,e1,e2,`, and a step never
+/// revisits one of them, so a walk around a cycle ends where it comes back to an entity rather
+/// than running on to the depth limit.
+fn closure_sql(closure: &Closure, datoms: &str) -> String {
+    let (from, to) = if closure.forward { ("e", "v") } else { ("v", "e") };
+    let start = match closure.start {
+        Some(start) => format!(" AND {} = {}", from, start),
+        None => "".to_string(),
+    };
+    format!("(WITH RECURSIVE walk(start, e, depth, path) AS \
+             (SELECT {from}, {to}, 1, ',' || {to} || ',' FROM {datoms} WHERE a = {a}{start} \
+             UNION ALL SELECT walk.start, d.{to}, walk.depth + 1, walk.path || d.{to} || ',' FROM walk, {datoms} AS d \
+             WHERE d.a = {a} AND d.{from} = walk.e AND walk.depth < {max_depth} \
+             AND instr(walk.path, ',' || d.{to} || ',') = 0) \
+             SELECT start, e, MIN(depth) AS depth FROM walk GROUP BY start, e)",
+            from = from, to = to, datoms = datoms, a = closure.attribute, start = start, max_depth = closure.max_depth)
+}
+
+/// Return SQL for the values in the given FTS `table` matching the search bound to the named
+/// argument `terms`.
+///
+/// FTS5's `bm25()` is more negative for better matches, so we negate it to make higher scores
+/// better.
+fn fulltext_search_sql(table: &str, terms: &str) -> String {
+    format!("(SELECT rowid, text, -bm25({0}) AS score, \
+             snippet({0}, 0, '<b>', '</b>', '...', 10) AS snippet \
+             FROM {0} WHERE {0} MATCH {1})", table, terms)
+}
+
+/// Return SQL for the JSON documents in the given datoms `table`, with the value at the path bound
+/// to the named argument `path` extracted into `v` and its type into `value_type_tag`.
+///
+/// `json_type` is NULL where there is no value at the path, and `'null'` where the value is JSON
+/// `null`; neither binds a value.  Objects and arrays are extracted as JSON text.
+fn json_values_sql(table: &str, path: &str) -> String {
+    format!("(SELECT DISTINCT j.v AS json, json_extract(j.v, {1}) AS v, \
+             CASE json_type(j.v, {1}) WHEN 'true' THEN 1 WHEN 'false' THEN 1 \
+             WHEN 'integer' THEN 5 WHEN 'real' THEN 5 WHEN 'text' THEN 10 ELSE 14 END AS value_type_tag \
+             FROM {0} AS j WHERE j.value_type_tag = 14 AND json_type(j.v, {1}) != 'null')", table, path)
+}
+
+/// Return SQL for the tuples in the given datoms `table`, with the component at `index` extracted
+/// into `v` and its type into `value_type_tag`.
+///
+/// Each component of a stored tuple is a `[tag, value]` pair, like `[5,10]`.
+fn tuple_values_sql(table: &str, index: i64) -> String {
+    format!("(SELECT DISTINCT t.v AS tuple, json_extract(t.v, '$[{1}][1]') AS v, \
+             json_extract(t.v, '$[{1}][0]') AS value_type_tag \
+             FROM {0} AS t WHERE t.value_type_tag = {2} AND json_array_length(t.v) > {1})", table, index, TUPLE_VALUE_TYPE_TAG)
+}
+
+/// Return SQL for the longs in the given datoms `table`, as instants, with the computation `f`
+/// over each in `v` and its type into `value_type_tag`.
+///
+/// SQLite's date functions work in seconds, and in UTC, so we shift each instant by the offset of
+/// the time zone, format it, and shift truncated instants back.
+fn instant_values_sql(table: &str, f: &InstantFn) -> String {
+    let (format, offset) = match *f {
+        InstantFn::Date { offset } => ("%Y-%m-%d", offset),
+        InstantFn::Truncate { unit: TimeUnit::Year, offset } => ("%Y-01-01 00:00:00", offset),
+        InstantFn::Truncate { unit: TimeUnit::Month, offset } => ("%Y-%m-01 00:00:00", offset),
+        InstantFn::Truncate { unit: TimeUnit::Day, offset } => ("%Y-%m-%d 00:00:00", offset),
+        InstantFn::Truncate { unit: TimeUnit::Hour, offset } => ("%Y-%m-%d %H:00:00", offset),
+        InstantFn::Truncate { unit: TimeUnit::Minute, offset } => ("%Y-%m-%d %H:%M:00", offset),
+    };
+    let local = format!("strftime('{}', i.v / 1000.0, 'unixepoch', '{:+} minutes')", format, offset);
+    let (v, value_type_tag) = match *f {
+        InstantFn::Date { .. } => (local, 10),
+        InstantFn::Truncate { .. } => (format!("(CAST(strftime('%s', {}) AS INTEGER) - {}) * 1000", local, offset * 60), 5),
+    };
+    format!("(SELECT DISTINCT i.v AS instant, {1} AS v, {2} AS value_type_tag \
+             FROM {0} AS i WHERE i.value_type_tag = 5)", table, v, value_type_tag)
+}
+
+/// Return SQL for the IDs of the transactions in the given range.
+fn tx_ids_sql(range: &TxRange) -> String {
+    format!("(SELECT DISTINCT tx FROM transactions WHERE tx >= {} AND tx < {})", range.start, range.end)
+}
+
+/// Return SQL for every assertion and retraction in the log, with fulltext values interpolated.
+fn tx_data_sql(tx_data: &TxData) -> String {
+    let fulltext: Vec<String> = tx_data.fulltext_attributes.iter().map(|a| a.to_string()).collect();
+    let v = if fulltext.is_empty() {
+        "t.v".to_string()
+    } else {
+        format!("CASE WHEN t.a IN ({}) THEN (SELECT text FROM fulltext_values WHERE rowid = t.v) ELSE t.v END", fulltext.join(", "))
+    };
+    format!("(SELECT t.e AS e, t.a AS a, {} AS v, t.tx AS tx, t.value_type_tag AS value_type_tag, t.added AS added \
+             FROM transactions AS t)", v)
+}
+
+/// Accumulates the SQL text and named arguments of a query.
+struct SQLBuilder<'h> {
+    args: Vec<(String, TypedValue)>,
+
+    /// The part of the store's history every table is read from.
+    history: Option<&'h History>,
+
+    /// `true` to read collection and relation inputs from temporary tables rather than from
+    /// arguments.
+    materialize_inputs: bool,
+
+    /// The temporary tables the query reads, if `materialize_inputs`.
+    input_tables: Vec<InputTable>,
+
+    /// The `or` clauses that occur more than once in the query, whose unions are materialized.
+    repeated_or_joins: Vec<OrJoin>,
+
+    /// The materialized unions, each with the name of its temporary table, and the tables
+    /// themselves.
+    materialized: Vec<(OrJoin, String)>,
+    temp_tables: Vec<TempTable>,
+}
+
+impl<'h> SQLBuilder<'h> {
+    fn new(history: Option<&'h History>, materialize_inputs: bool, repeated_or_joins: Vec<OrJoin>) -> SQLBuilder<'h> {
+        SQLBuilder {
+            args: vec![],
+            history: history,
+            materialize_inputs: materialize_inputs,
+            input_tables: vec![],
+            repeated_or_joins: repeated_or_joins,
+            materialized: vec![],
+            temp_tables: vec![],
+        }
+    }
+
+    fn push_arg(&mut self, value: TypedValue) -> String {
+        let name = format!("$v{}", self.args.len());
+        self.args.push((name.clone(), value));
+        name
+    }
+
+    fn constraint_sql(&mut self, constraint: &ColumnConstraint) -> Result<String> {
+        let sql = match *constraint {
+            ColumnConstraint::EqualsEntity(ref column, entid) => {
+                format!("{} = {}", column_sql(column), entid)
+            },
+            ColumnConstraint::EqualsValue(ref column, ref value) => {
+                let (_, value_type_tag) = value.to_sql_value_pair();
+                let name = self.push_arg(value.clone());
+                format!("{} = {} AND {} = {}", column_sql(column), name, column_sql(&column.for_type_tag()), value_type_tag)
+            },
+            ColumnConstraint::EqualsRefOrLong(ref column, x) => {
+                // Longs share their tag with doubles, which SQLite would compare equal to an
+                // integer, so only integer values match.
+                let name = self.push_arg(TypedValue::Long(x));
+                format!("{0} = {1} AND {2} IN (0, 5) AND typeof({0}) = 'integer'", column_sql(column), name, column_sql(&column.for_type_tag()))
+            },
+            ColumnConstraint::EqualsColumn(ref left, ref right) => {
+                // Values of different types can be stored alike, so a unified variable matches a
+                // value only if their type tags match too.
+                match (&left.1, &right.1) {
+                    (&DatomsColumn::Unified(_), &DatomsColumn::Unified(_)) |
+                    (&DatomsColumn::Unified(_), &DatomsColumn::Value) |
+                    (&DatomsColumn::Value, &DatomsColumn::Unified(_)) => {
+                        format!("{} = {} AND {} = {}", column_sql(left), column_sql(right),
+                                column_sql(&left.for_type_tag()), column_sql(&right.for_type_tag()))
+                    },
+                    _ => format!("{} = {}", column_sql(left), column_sql(right)),
+                }
+            },
+            ColumnConstraint::HasTypeTag(ref column, value_type_tag) => {
+                format!("{} = {}", column_sql(column), value_type_tag)
+            },
+            ColumnConstraint::EqualsAdded(ref column, added) => {
+                format!("{} = {}", column_sql(column), if added { 1 } else { 0 })
+            },
+            ColumnConstraint::Between(ref column, start, end) => {
+                format!("{0} >= {1} AND {0} < {2}", column_sql(column), start, end)
+            },
+            ColumnConstraint::NotExists(ref cc) => {
+                format!("NOT EXISTS ({})", self.select_sql(false, vec!["1".to_string()], cc)?)
+            },
+        };
+        Ok(sql)
+    }
+
+    /// Return SQL for the source of the given `FROM` list entry.
+    fn table_sql(&mut self, cc: &ConjoiningClauses, source: &SourceAlias) -> Result<String> {
+        let table = match (source.0, self.history) {
+            // Fulltext values are never removed, so searches don't depend on history.
+            (DatomsTable::FulltextValues, _) => {
+                let search = match cc.fulltext_searches.get(&source.1) {
+                    Some(search) => search,
+                    None => bail!(ErrorKind::NotYetImplemented(format!("fulltext search without terms: {}", source.1))),
+                };
+                fulltext_search_sql(&search.table, &self.push_arg(TypedValue::String(search.terms.clone())))
+            },
+            // Documents in the past are in the log.
+            (DatomsTable::JsonValues, history) => {
+                let path = match cc.json_paths.get(&source.1) {
+                    Some(path) => path,
+                    None => bail!(ErrorKind::NotYetImplemented(format!("json-get without a path: {}", source.1))),
+                };
+                let table = if history.is_some() { "transactions" } else { "datoms" };
+                json_values_sql(table, &self.push_arg(TypedValue::String(path.clone())))
+            },
+            (DatomsTable::TupleValues, history) => {
+                let index = match cc.tuple_indexes.get(&source.1) {
+                    Some(&index) => index,
+                    None => bail!(ErrorKind::NotYetImplemented(format!("tuple-get without an index: {}", source.1))),
+                };
+                let table = if history.is_some() { "transactions" } else { "datoms" };
+                tuple_values_sql(table, index)
+            },
+            (DatomsTable::InstantValues, history) => {
+                let f = match cc.instant_fns.get(&source.1) {
+                    Some(f) => f,
+                    None => bail!(ErrorKind::NotYetImplemented(format!("instant function without a computation: {}", source.1))),
+                };
+                let table = if history.is_some() { "transactions" } else { "datoms" };
+                instant_values_sql(table, f)
+            },
+            // Each leg is read from the same part of the store's history.
+            (DatomsTable::Union, _) => {
+                let union = match cc.unions.get(&source.1) {
+                    Some(union) => union,
+                    None => bail!(ErrorKind::NotYetImplemented(format!("or without legs: {}", source.1))),
+                };
+                if self.repeated_or_joins.contains(&union.clause) {
+                    self.materialized_union_sql(union, &source.1)?
+                } else {
+                    self.union_sql(union)?
+                }
+            },
+            (DatomsTable::Closure, history) => {
+                let closure = match cc.closures.get(&source.1) {
+                    Some(closure) => closure,
+                    None => bail!(ErrorKind::NotYetImplemented(format!("walk without an attribute: {}", source.1))),
+                };
+                let datoms = match history {
+                    None => DatomsTable::Datoms.name().to_string(),
+                    Some(history) if history.log => bail!(ErrorKind::NotYetImplemented("walks in a :history query".to_string())),
+                    Some(history) => history_table_sql(DatomsTable::Datoms, history),
+                };
+                closure_sql(closure, &datoms)
+            },
+            // The log holds every transaction, whatever the part of the store's history.
+            (DatomsTable::TxIds, _) => {
+                match cc.tx_ranges.get(&source.1) {
+                    Some(range) => tx_ids_sql(range),
+                    None => bail!(ErrorKind::NotYetImplemented(format!("tx-ids without a range: {}", source.1))),
+                }
+            },
+            (DatomsTable::TxData, _) => {
+                match cc.tx_data.get(&source.1) {
+                    Some(tx_data) => tx_data_sql(tx_data),
+                    None => bail!(ErrorKind::NotYetImplemented(format!("tx-data without a source: {}", source.1))),
+                }
+            },
+            // Inputs are the same whatever the part of the store's history.
+            (DatomsTable::Inputs, _) => {
+                let rows = match cc.input_rows.get(&source.1) {
+                    Some(rows) => rows,
+                    None => bail!(ErrorKind::NotYetImplemented(format!("input without rows: {}", source.1))),
+                };
+                let width = rows.first().map_or(0, |row| row.len());
+                if self.materialize_inputs {
+                    self.input_tables.push(InputTable {
+                        name: source.1.clone(),
+                        width: width,
+                        rows: rows.clone(),
+                    });
+                    format!("temp.{}", source.1)
+                } else {
+                    self.input_rows_sql(width, rows)
+                }
+            },
+            (table, Some(history)) => history_table_sql(table, history),
+            (table, None) => table.name().to_string(),
+        };
+        Ok(table)
+    }
+
+    /// Return SQL for the rows matching any leg of the given union, projecting the value and type
+    /// tag of each unified variable.
+    fn union_sql(&mut self, union: &Union) -> Result<String> {
+        let mut legs: Vec<String> = vec![];
+        for leg in union.legs.iter() {
+            let mut projection: Vec<String> = vec![];
+            for (i, var) in union.vars.iter().enumerate() {
+                let column = match leg.binding_for_var(var) {
+                    Some(column) => column,
+                    None => bail!(ErrorKind::UnboundVariable(var.clone())),
+                };
+                projection.push(format!("{} AS {}", column_sql(column), DatomsColumn::Unified(i).name()));
+                projection.push(format!("{} AS {}", type_tag_sql(column), DatomsColumn::UnifiedTypeTag(i).name()));
+            }
+            // A union that unifies nothing is only a test that some leg matches.
+            if projection.is_empty() {
+                projection.push("1".to_string());
+            }
+            legs.push(self.select_sql(false, projection, leg)?);
+        }
+        Ok(format!("({})", legs.join(" UNION ")))
+    }
+
+    /// Return SQL for the temporary table holding the rows of the given union, which the query
+    /// reads more than once.  The first use, under `alias`, runs the union into the table.
+    fn materialized_union_sql(&mut self, union: &Union, alias: &TableAlias) -> Result<String> {
+        if let Some(&(_, ref name)) = self.materialized.iter().find(|&&(ref clause, _)| *clause == union.clause) {
+            return Ok(format!("temp.{}", name));
+        }
+
+        // The table is filled by a statement of its own, with arguments of its own.  Unions nested
+        // within this one are materialized first.
+        let args = mem::replace(&mut self.args, vec![]);
+        let sql = self.union_sql(union);
+        let union_args = mem::replace(&mut self.args, args);
+        self.temp_tables.push(TempTable {
+            name: alias.clone(),
+            sql: format!("SELECT * FROM {}", sql?),
+            args: union_args,
+        });
+        self.materialized.push((union.clause.clone(), alias.clone()));
+        Ok(format!("temp.{}", alias))
+    }
+
+    /// Return SQL for the given rows of a collection or relation input, each value an argument,
+    /// projecting the value and type tag of each variable.
+    fn input_rows_sql(&mut self, width: usize, rows: &[Vec<TypedValue>]) -> String {
+        let mut projection: Vec<String> = vec![];
+        for i in 0..width {
+            projection.push(format!("column{} AS {}", 2 * i + 1, DatomsColumn::Unified(i).name()));
+            projection.push(format!("column{} AS {}", 2 * i + 2, DatomsColumn::UnifiedTypeTag(i).name()));
+        }
+        let mut values: Vec<String> = vec![];
+        for row in rows {
+            let mut row_sql: Vec<String> = vec![];
+            for value in row {
+                let (_, value_type_tag) = value.to_sql_value_pair();
+                row_sql.push(self.push_arg(value.clone()));
+                row_sql.push(value_type_tag.to_string());
+            }
+            values.push(format!("({})", row_sql.join(", ")));
+        }
+        format!("(SELECT {} FROM (VALUES {}))", projection.join(", "), values.join(", "))
+    }
+
+    /// Return a `SELECT` statement projecting the given columns from the given clauses.
+    fn select_sql(&mut self, distinct: bool, projection: Vec<String>, cc: &ConjoiningClauses) -> Result<String> {
+        let mut from: Vec<String> = vec![];
+        for source in cc.from.iter() {
+            let table = self.table_sql(cc, source)?;
+            let hint = match cc.index_hints.get(&source.1) {
+                None => "".to_string(),
+                // Only the datoms table itself has indexes; views and history subqueries don't.
+                Some(_) if source.0 != DatomsTable::Datoms || self.history.is_some() => {
+                    bail!(ErrorKind::InvalidIndexHint(format!("{} doesn't read the datoms table", source.1)));
+                },
+                Some(&IndexHint::IndexedBy(ref index)) => {
+                    if index.is_empty() || !index.chars().all(|c| c == '_' || (c.is_alphanumeric() && (c as u32) < 128)) {
+                        bail!(ErrorKind::InvalidIndexHint(format!("not an index name: {}", index)));
+                    }
+                    format!(" INDEXED BY {}", index)
+                },
+                Some(&IndexHint::NotIndexed) => " NOT INDEXED".to_string(),
+            };
+            from.push(format!("{} {}{}", table, source.1, hint));
+        }
+
+        let mut wheres: Vec<String> = vec![];
+        for constraint in cc.wheres.iter() {
+            wheres.push(self.constraint_sql(constraint)?);
+        }
+        if cc.is_known_empty {
+            wheres.push("0".to_string());
+        }
+
+        let mut sql = format!("SELECT {}{}", if distinct { "DISTINCT " } else { "" }, projection.join(", "));
+        if !from.is_empty() {
+            sql.push_str(" FROM ");
+            sql.push_str(&from.join(", "));
+        }
+        if !wheres.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&wheres.join(" AND "));
+        }
+        Ok(sql)
+    }
+}
+
+/// Return SQL projecting the value and value type tag of each variable in the given find spec.
+///
+/// With `idents`, refs to entities that have idents project as those idents, as enumeration values
+/// always do.
+fn projection_sql(builder: &mut SQLBuilder, find_spec: &FindSpec, cc: &ConjoiningClauses, idents: bool) -> Result<Vec<String>> {
+    if let Some(&Element::Count(ref var)) = find_spec.elements().into_iter().find(|element| match **element {
+        Element::Count(_) => true,
+        _ => false,
+    }) {
+        match *find_spec {
+            FindSpec::FindScalar(_) => (),
+            _ => bail!(ErrorKind::NotYetImplemented(format!("(count {}) outside a scalar find spec", (var.0).0))),
+        }
+    }
+
+    let mut projection: Vec<String> = vec![];
+    for var in find_spec_variables(find_spec) {
+        let column = match cc.binding_for_var(var) {
+            Some(column) => column,
+            None => {
+                // Inputs project as constants.
+                match cc.value_bindings.get(var) {
+                    Some(value) => {
+                        let (_, value_type_tag) = value.to_sql_value_pair();
+                        projection.push(builder.push_arg(value.clone()));
+                        projection.push(value_type_tag.to_string());
+                        continue;
+                    },
+                    None => bail!(ErrorKind::UnboundVariable(var.clone())),
+                }
+            },
+        };
+        if cc.enum_vars.contains(var) || (idents && type_tag_sql(column) == "0") {
+            // Enumeration values project as their idents.  Values without idents, which the
+            // transactor doesn't allow, stay entids.
+            let entid = column_sql(column);
+            projection.push(format!("COALESCE((SELECT ident FROM idents WHERE entid = {0}), {0})", entid));
+            projection.push(format!("CASE WHEN {} IN (SELECT entid FROM idents) THEN 13 ELSE {} END", entid, type_tag_sql(column)));
+            continue;
+        }
+        if idents && column.1.is_tagged() {
+            // Only the refs among the values, tagged 0, are looked up.
+            let (value, tag) = (column_sql(column), type_tag_sql(column));
+            projection.push(format!("CASE WHEN {1} = 0 THEN COALESCE((SELECT ident FROM idents WHERE entid = {0}), {0}) ELSE {0} END", value, tag));
+            projection.push(format!("CASE WHEN {1} = 0 AND {0} IN (SELECT entid FROM idents) THEN 13 ELSE {1} END", value, tag));
+            continue;
+        }
+        projection.push(column_sql(column));
+        projection.push(type_tag_sql(column));
+    }
+    Ok(projection)
+}
+
+/// Return a `SELECT` statement for the results of the given find spec, with the given projection,
+/// over the given clauses.
+///
+/// A count, like `[:find (count ?e) . ...]`, is counted by SQLite rather than by fetching every
+/// value.  Values of an entity column need no type tag to tell them apart, so they're counted
+/// directly, with `COUNT(DISTINCT ...)`; other values are deduplicated, with their tags, first.
+fn find_sql(builder: &mut SQLBuilder, find_spec: &FindSpec, projection: Vec<String>, cc: &ConjoiningClauses) -> Result<String> {
+    let var = match *find_spec {
+        FindSpec::FindScalar(Element::Count(ref var)) => var,
+        _ => return builder.select_sql(requires_distinct(find_spec), projection, cc),
+    };
+    match cc.binding_for_var(var) {
+        Some(column) if !column.1.is_tagged() && !cc.enum_vars.contains(var) => {
+            builder.select_sql(false, vec![format!("COUNT(DISTINCT {})", column_sql(column)), "5".to_string()], cc)
+        },
+        _ => {
+            let values = builder.select_sql(true, projection, cc)?;
+            Ok(format!("SELECT COUNT(*), 5 FROM ({})", values))
+        },
+    }
+}
+
+/// Collect the `or` clauses of the given clauses, and of every scope nested within them.
+fn collect_or_joins<'c>(cc: &'c ConjoiningClauses, or_joins: &mut Vec<&'c OrJoin>) {
+    for union in cc.unions.values() {
+        or_joins.push(&union.clause);
+        for leg in union.legs.iter() {
+            collect_or_joins(leg, or_joins);
+        }
+    }
+    for constraint in cc.wheres.iter() {
+        if let ColumnConstraint::NotExists(ref body) = *constraint {
+            collect_or_joins(body, or_joins);
+        }
+    }
+}
+
+/// Return the `or` clauses that occur more than once in the given clauses, at any depth.
+fn repeated_or_joins(cc: &ConjoiningClauses) -> Vec<OrJoin> {
+    let mut or_joins = vec![];
+    collect_or_joins(cc, &mut or_joins);
+
+    let mut repeated: Vec<OrJoin> = vec![];
+    for (i, or_join) in or_joins.iter().enumerate() {
+        if !repeated.contains(*or_join) && or_joins[i + 1..].contains(or_join) {
+            repeated.push((*or_join).clone());
+        }
+    }
+    repeated
+}
+
+/// Translate the given algebraic query into SQL.
+///
+/// Collection and relation inputs are passed as arguments, unless that would take more than
+/// SQLite allows, in which case they're read from temporary tables; see `SQLQuery::input_tables`.
+/// Likewise, an `or` clause that occurs more than once is run once, into a temporary table that
+/// each occurrence reads; see `SQLQuery::temp_tables`.
+pub fn query_to_select(query: AlgebraicQuery) -> Result<SQLQuery> {
+    let cc = query.cc;
+    let repeated = repeated_or_joins(&cc);
+    let mut builder = SQLBuilder::new(query.history.as_ref(), false, repeated.clone());
+    let projection = projection_sql(&mut builder, &query.find_spec, &cc, query.idents)?;
+
+    // There's no need to ask the store when we already know the answer.
+    if cc.is_known_empty {
+        return Ok(SQLQuery {
+            sql: String::new(),
+            args: vec![],
+            find_spec: query.find_spec,
+            empty_because: cc.empty_because,
+            pattern_aliases: query.pattern_aliases,
+            input_tables: vec![],
+            temp_tables: vec![],
+        });
+    }
+
+    let mut sql = find_sql(&mut builder, &query.find_spec, projection, &cc)?;
+    if builder.args.len() > MAX_SQL_VARIABLES && !cc.input_rows.is_empty() {
+        builder = SQLBuilder::new(query.history.as_ref(), true, repeated);
+        let projection = projection_sql(&mut builder, &query.find_spec, &cc, query.idents)?;
+        sql = find_sql(&mut builder, &query.find_spec, projection, &cc)?;
+    }
+    if is_unit_limited(&query.find_spec) {
+        sql.push_str(" LIMIT 1");
+    }
+
+    Ok(SQLQuery {
+        sql: sql,
+        args: builder.args,
+        find_spec: query.find_spec,
+        empty_because: None,
+        pattern_aliases: query.pattern_aliases,
+        input_tables: builder.input_tables,
+        temp_tables: builder.temp_tables,
+    })
+}
+
+/// Translate the given `FindQuery` into SQL in the context of the given `schema`.
+pub fn translate(schema: &Schema, query: &FindQuery) -> Result<SQLQuery> {
+    query_to_select(algebrize(schema, query)?)
+}
+
+/// Translate the given `FindQuery` into SQL, binding its `:in` variables to the given `inputs`.
+pub fn translate_with_inputs(schema: &Schema, query: &FindQuery, inputs: QueryInputs) -> Result<SQLQuery> {
+    query_to_select(algebrize_with_inputs(schema, query, inputs)?)
+}
+
+/// Like `translate_with_inputs`, but with index hints for some of the query's top-level patterns.
+pub fn translate_with_hints(schema: &Schema, query: &FindQuery, inputs: QueryInputs, hints: &IndexHints) -> Result<SQLQuery> {
+    query_to_select(algebrize_with_hints(schema, query, inputs, hints)?)
+}
+
+/// Like `translate_with_inputs`, but with rows for the query's collection and relation inputs too.
+pub fn translate_with_relations(schema: &Schema, query: &FindQuery, inputs: QueryInputs, relations: RelationInputs) -> Result<SQLQuery> {
+    query_to_select(algebrize_with_relations(schema, query, inputs, relations)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use edn::{NamespacedKeyword, PlainSymbol};
+    use mentat_db::{Attribute, Entid, ValueType};
+    use mentat_query_parser::parse_find_string;
+
+    fn add_attribute(schema: &mut Schema, ident: &str, entid: Entid, attribute: Attribute) {
+        schema.add_ident(ident.to_string(), entid);
+        schema.set_attribute(entid, attribute).expect("a valid attribute");
+    }
+
+    fn translate_str(schema: &Schema, input: &str) -> Result<SQLQuery> {
+        translate(schema, &parse_find_string(input).expect("to parse query"))
+    }
+
+    #[test]
+    fn test_coll() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+
+        let query = translate_str(&schema, r#"[:find [?x ...] :where [?x :foo/bar "yyy"]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms00.e, 0 FROM datoms datoms00 WHERE datoms00.a = 99 AND datoms00.v = $v0 AND datoms00.value_type_tag = 10");
+        assert_eq!(query.args, vec![("$v0".to_string(), TypedValue::String("yyy".to_string()))]);
+    }
+
+    #[test]
+    fn test_scalar_unknown_attribute() {
+        let schema = Schema::default();
+
+        let query = translate_str(&schema, r#"[:find ?v . :where [_ ?a ?v]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT all_datoms00.v, all_datoms00.value_type_tag FROM all_datoms all_datoms00 LIMIT 1");
+
+        // Nothing can match an unknown attribute, so there's no SQL to run.
+        let query = translate_str(&schema, r#"[:find ?x :where [?x :foo/bar "yyy"]]"#).unwrap();
+        assert_eq!(query.sql, "");
+        assert_eq!(query.args, vec![]);
+        assert_eq!(query.empty_because, Some(EmptyBecause::UnknownAttribute(":foo/bar".to_string())));
+
+        // But the query must still make sense.
+        match translate_str(&schema, r#"[:find ?y :where [?x :foo/bar "yyy"]]"#) {
+            Err(Error(ErrorKind::UnboundVariable(_), _)) => (),
+            x => panic!("expected UnboundVariable, got {:?}", x),
+        }
+
+        // An integer matches refs and longs, but not doubles.
+        let query = translate_str(&schema, r#"[:find ?x :where [?x ?a 5]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT all_datoms00.e, 0 FROM all_datoms all_datoms00 \
+                               WHERE all_datoms00.v = $v0 AND all_datoms00.value_type_tag IN (0, 5) AND typeof(all_datoms00.v) = 'integer'");
+        assert_eq!(query.args, vec![("$v0".to_string(), TypedValue::Long(5))]);
+    }
+
+    #[test]
+    fn test_aliased_duplicate() {
+        let schema = Schema::default();
+        let query = translate_str(&schema, r#"[:find ?x ?x :as :copy :where [?x _ _]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT all_datoms00.e, 0, all_datoms00.e, 0 FROM all_datoms all_datoms00");
+        assert_eq!(find_spec_names(&query.find_spec), vec!["?x".to_string(), ":copy".to_string()]);
+    }
+
+    #[test]
+    fn test_inputs() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+        let query = parse_find_string(r#"[:find ?x ?name :in $ ?name :where [?x :foo/bar ?name]]"#).unwrap();
+        let name = Variable(PlainSymbol::new("?name"));
+
+        let mut inputs = QueryInputs::new();
+        inputs.insert(name.clone(), TypedValue::String("yyy".to_string()));
+        let sql = translate_with_inputs(&schema, &query, inputs).unwrap();
+        assert_eq!(sql.sql, "SELECT DISTINCT datoms00.e, 0, $v0, 10 FROM datoms datoms00 WHERE datoms00.a = 99 AND datoms00.v = $v1 AND datoms00.value_type_tag = 10");
+
+        match translate(&schema, &query) {
+            Err(Error(ErrorKind::MissingInput(ref var), _)) if *var == name => (),
+            x => panic!("expected MissingInput, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_enum() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":task/status", 99, Attribute {
+            value_type: ValueType::Ref,
+            enumerated: true,
+            ..Default::default()
+        });
+        schema.add_ident(":task.status/open".to_string(), 100);
+
+        // Values project as idents.
+        let query = translate_str(&schema, r#"[:find ?s :where [_ :task/status ?s]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT COALESCE((SELECT ident FROM idents WHERE entid = datoms00.v), datoms00.v), \
+                               CASE WHEN datoms00.v IN (SELECT entid FROM idents) THEN 13 ELSE datoms00.value_type_tag END \
+                               FROM datoms datoms00 WHERE datoms00.a = 99");
+
+        // Keyword inputs name values.
+        let query = parse_find_string(r#"[:find ?t :in $ ?s :where [?t :task/status ?s]]"#).unwrap();
+        let mut inputs = QueryInputs::new();
+        inputs.insert(Variable(PlainSymbol::new("?s")), TypedValue::Keyword(NamespacedKeyword::new("task.status", "open")));
+        let sql = translate_with_inputs(&schema, &query, inputs).unwrap();
+        assert_eq!(sql.sql, "SELECT DISTINCT datoms00.e, 0 FROM datoms datoms00 WHERE datoms00.a = 99 AND datoms00.v = 100");
+
+        // An unknown keyword input matches nothing.
+        let mut inputs = QueryInputs::new();
+        inputs.insert(Variable(PlainSymbol::new("?s")), TypedValue::Keyword(NamespacedKeyword::new("task.status", "closed")));
+        let sql = translate_with_inputs(&schema, &query, inputs).unwrap();
+        assert_eq!(sql.empty_because, Some(EmptyBecause::UnknownIdent(":task.status/closed".to_string())));
+    }
+
+    #[test]
+    fn test_idents() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":task/owner", 99, Attribute {
+            value_type: ValueType::Ref,
+            ..Default::default()
+        });
+
+        // Entities, and the refs among values, project as their idents.
+        let query = translate_str(&schema, r#"[:find ?t ?o :idents true :where [?t :task/owner ?o]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT COALESCE((SELECT ident FROM idents WHERE entid = datoms00.e), datoms00.e), \
+                               CASE WHEN datoms00.e IN (SELECT entid FROM idents) THEN 13 ELSE 0 END, \
+                               CASE WHEN datoms00.value_type_tag = 0 THEN COALESCE((SELECT ident FROM idents WHERE entid = datoms00.v), datoms00.v) ELSE datoms00.v END, \
+                               CASE WHEN datoms00.value_type_tag = 0 AND datoms00.v IN (SELECT entid FROM idents) THEN 13 ELSE datoms00.value_type_tag END \
+                               FROM datoms datoms00 WHERE datoms00.a = 99");
+
+        // Only with the flag.
+        let query = translate_str(&schema, r#"[:find ?o :where [_ :task/owner ?o]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms00.v, datoms00.value_type_tag FROM datoms datoms00 WHERE datoms00.a = 99");
+    }
+
+    #[test]
+    fn test_closure() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/name", 98, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, ":foo/parent", 99, Attribute::default());
+
+        let query = parse_find_string(r#"[:find ?a ?depth :in $ ?e :where [(ancestors ?e :foo/parent 5) [[?a ?depth]]]]"#).unwrap();
+        let mut inputs = QueryInputs::new();
+        inputs.insert(Variable(PlainSymbol::new("?e")), TypedValue::Ref(65536));
+        let sql = translate_with_inputs(&schema, &query, inputs).unwrap();
+        assert_eq!(sql.sql, "SELECT DISTINCT closure00.e, 0, closure00.depth, 5 \
+                             FROM (WITH RECURSIVE walk(start, e, depth, path) AS \
+                             (SELECT e, v, 1, ',' || v || ',' FROM datoms WHERE a = 99 AND e = 65536 \
+                             UNION ALL SELECT walk.start, d.v, walk.depth + 1, walk.path || d.v || ',' FROM walk, datoms AS d \
+                             WHERE d.a = 99 AND d.e = walk.e AND walk.depth < 5 \
+                             AND instr(walk.path, ',' || d.v || ',') = 0) \
+                             SELECT start, e, MIN(depth) AS depth FROM walk GROUP BY start, e) closure00");
+
+        // Walks start from the entities bound by other clauses.
+        let sql = translate_str(&schema, r#"[:find ?x ?d :where [?x :foo/name "x"] [(descendants ?x :foo/parent) ?d]]"#).unwrap();
+        assert_eq!(sql.sql, "SELECT DISTINCT datoms00.e, 0, closure01.e, 0 FROM datoms datoms00, \
+                             (WITH RECURSIVE walk(start, e, depth, path) AS \
+                             (SELECT v, e, 1, ',' || e || ',' FROM datoms WHERE a = 99 \
+                             UNION ALL SELECT walk.start, d.e, walk.depth + 1, walk.path || d.e || ',' FROM walk, datoms AS d \
+                             WHERE d.a = 99 AND d.v = walk.e AND walk.depth < 1000 \
+                             AND instr(walk.path, ',' || d.e || ',') = 0) \
+                             SELECT start, e, MIN(depth) AS depth FROM walk GROUP BY start, e) closure01 \
+                             WHERE datoms00.a = 98 AND datoms00.v = $v0 AND datoms00.value_type_tag = 10 AND datoms00.e = closure01.start");
+
+        for input in &[r#"[:find ?a :where [(ancestors 65536 :foo/name) ?a]]"#,
+                       r#"[:find ?a :where [(ancestors 65536 :foo/parent 0) ?a]]"#,
+                       r#"[:find ?a :where [(ancestors 65536) ?a]]"#] {
+            match translate_str(&schema, input) {
+                Err(Error(ErrorKind::InvalidArgument(_), _)) => (),
+                x => panic!("expected InvalidArgument, got {:?}", x),
+            }
+        }
+        assert_eq!(translate_str(&schema, r#"[:find ?a :where [(ancestors 65536 :foo/unknown) ?a]]"#).unwrap().empty_because,
+                   Some(EmptyBecause::UnknownAttribute(":foo/unknown".to_string())));
+    }
+
+    #[test]
+    fn test_repeated_or() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, ":foo/baz", 100, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+
+        // The inner `or` is run once, into a table that both legs of the outer `or` read.
+        let query = translate_str(&schema, r#"[:find ?x :where (or (and (or [?x :foo/bar "a"] [?x :foo/baz 1]) [?x :foo/bar "b"])
+                                                                   (and (or [?x :foo/bar "a"] [?x :foo/baz 1]) [?x :foo/baz 2]))]"#).unwrap();
+        assert_eq!(query.temp_tables, vec![TempTable {
+            name: "union02".to_string(),
+            sql: "SELECT * FROM (SELECT datoms00.e AS v0, 0 AS value_type_tag0 FROM datoms datoms00 \
+                  WHERE datoms00.a = 99 AND datoms00.v = $v0 AND datoms00.value_type_tag = 10 \
+                  UNION SELECT datoms01.e AS v0, 0 AS value_type_tag0 FROM datoms datoms01 \
+                  WHERE datoms01.a = 100 AND datoms01.v = $v1 AND datoms01.value_type_tag = 5)".to_string(),
+            args: vec![("$v0".to_string(), TypedValue::String("a".to_string())),
+                       ("$v1".to_string(), TypedValue::Long(1))],
+        }]);
+        assert_eq!(query.sql, "SELECT DISTINCT union08.v0, union08.value_type_tag0 \
+                               FROM (SELECT union02.v0 AS v0, union02.value_type_tag0 AS value_type_tag0 \
+                               FROM temp.union02 union02, datoms datoms03 \
+                               WHERE union02.v0 = datoms03.e AND datoms03.a = 99 AND datoms03.v = $v0 AND datoms03.value_type_tag = 10 \
+                               UNION SELECT union06.v0 AS v0, union06.value_type_tag0 AS value_type_tag0 \
+                               FROM temp.union02 union06, datoms datoms07 \
+                               WHERE union06.v0 = datoms07.e AND datoms07.a = 100 AND datoms07.v = $v1 AND datoms07.value_type_tag = 5) union08");
+        assert_eq!(query.args, vec![("$v0".to_string(), TypedValue::String("b".to_string())),
+                                    ("$v1".to_string(), TypedValue::Long(2))]);
+
+        // An `or` that occurs once is inlined.
+        let query = translate_str(&schema, r#"[:find ?x :where (or [?x :foo/bar "a"] [?x :foo/baz 1]) [?x :foo/bar "b"]]"#).unwrap();
+        assert!(query.temp_tables.is_empty());
+    }
+
+    #[test]
+    fn test_relation_inputs() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+        let query = parse_find_string(r#"[:find ?x :in $ [?name ...] :where [?x :foo/bar ?name]]"#).unwrap();
+        let name = Variable(PlainSymbol::new("?name"));
+        let translate_rows = |rows: Vec<Vec<TypedValue>>| {
+            let mut relations = RelationInputs::new();
+            relations.insert(vec![name.clone()], rows);
+            translate_with_relations(&schema, &query, QueryInputs::new(), relations)
+        };
+        let names = |count: usize| -> Vec<Vec<TypedValue>> {
+            (0..count).map(|i| vec![TypedValue::String(format!("name{}", i))]).collect()
+        };
+
+        let sql = translate_rows(names(2)).unwrap();
+        assert_eq!(sql.sql, "SELECT DISTINCT datoms01.e, 0 \
+                             FROM (SELECT column1 AS v0, column2 AS value_type_tag0 FROM (VALUES ($v0, 10), ($v1, 10))) inputs00, datoms datoms01 \
+                             WHERE datoms01.a = 99 AND inputs00.v0 = datoms01.v AND inputs00.value_type_tag0 = datoms01.value_type_tag");
+        assert_eq!(sql.args.len(), 2);
+        assert!(sql.input_tables.is_empty());
+
+        // Too many rows to pass as arguments are read from a temporary table instead.
+        let sql = translate_rows(names(MAX_SQL_VARIABLES + 1)).unwrap();
+        assert_eq!(sql.sql, "SELECT DISTINCT datoms01.e, 0 FROM temp.inputs00 inputs00, datoms datoms01 \
+                             WHERE datoms01.a = 99 AND inputs00.v0 = datoms01.v AND inputs00.value_type_tag0 = datoms01.value_type_tag");
+        assert!(sql.args.is_empty());
+        assert_eq!(sql.input_tables, vec![InputTable {
+            name: "inputs00".to_string(),
+            width: 1,
+            rows: names(MAX_SQL_VARIABLES + 1),
+        }]);
+
+        assert_eq!(translate_rows(vec![]).unwrap().empty_because, Some(EmptyBecause::EmptyInput(name.clone())));
+        match translate_rows(vec![vec![]]) {
+            Err(Error(ErrorKind::InvalidInput(ref var, _), _)) if *var == name => (),
+            x => panic!("expected InvalidInput, got {:?}", x),
+        }
+        match translate_with_inputs(&schema, &query, QueryInputs::new()) {
+            Err(Error(ErrorKind::MissingInput(ref var), _)) if *var == name => (),
+            x => panic!("expected MissingInput, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_tx_ids_and_tx_data() {
+        let schema = Schema::default();
+        let query = translate_str(&schema, r#"[:find ?tx ?e ?v :where [(tx-ids $ 268435456 268435460) [?tx ...]]
+                                                                      [(tx-data $ ?tx) [[?e _ ?v]]]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT tx_ids00.tx, 0, tx_data01.e, 0, tx_data01.v, tx_data01.value_type_tag \
+                               FROM (SELECT DISTINCT tx FROM transactions WHERE tx >= 268435456 AND tx < 268435460) tx_ids00, \
+                               (SELECT t.e AS e, t.a AS a, t.v AS v, t.tx AS tx, t.value_type_tag AS value_type_tag, t.added AS added \
+                               FROM transactions AS t) tx_data01 \
+                               WHERE tx_ids00.tx = tx_data01.tx");
+
+        // A transaction ID constrains the log directly.
+        let query = translate_str(&schema, r#"[:find ?e ?added :where [(tx-data $ 268435457) [[?e _ _ _ ?added]]]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT tx_data00.e, 0, tx_data00.added, 1 \
+                               FROM (SELECT t.e AS e, t.a AS a, t.v AS v, t.tx AS tx, t.value_type_tag AS value_type_tag, t.added AS added \
+                               FROM transactions AS t) tx_data00 \
+                               WHERE tx_data00.tx = 268435457");
+
+        for input in &[r#"[:find ?tx :where [(tx-ids $ 268435456) [?tx ...]]]"#,
+                       r#"[:find ?tx :where [(tx-ids $ 268435456 "later") [?tx ...]]]"#,
+                       r#"[:find ?tx :where [(tx-ids $ 268435456 268435460) [[?tx]]]]"#,
+                       r#"[:find ?e :where [(tx-data $ 268435457) ?e]]"#,
+                       r#"[:find ?e :where [(tx-data 268435457) [[?e]]]]"#] {
+            match translate_str(&schema, input) {
+                Err(Error(ErrorKind::InvalidArgument(_), _)) => (),
+                x => panic!("expected InvalidArgument, got {:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":db/txInstant", 3, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+
+        let query = translate_str(&schema, r#"[:find ?x :since 100 :where [?x :foo/bar "yyy"]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms00.e, 0 FROM (SELECT * FROM datoms WHERE tx > 100) datoms00 WHERE datoms00.a = 99 AND datoms00.v = $v0 AND datoms00.value_type_tag = 10");
+
+        let query = translate_str(&schema, r#"[:find ?x :as-of #inst "1970-01-01T00:00:01Z" :where [?x :foo/bar "yyy"]]"#).unwrap();
+        assert!(query.sql.starts_with("SELECT DISTINCT datoms00.e, 0 FROM (SELECT t.e AS e, t.a AS a, t.v AS v, t.tx AS tx, t.value_type_tag AS value_type_tag FROM transactions AS t WHERE t.added IS NOT 0 AND t.tx <= (SELECT COALESCE(MAX(e), 0) FROM datoms WHERE a = 3 AND v <= 1000) AND t.rowid = "),
+                "unexpected SQL: {}", query.sql);
+        assert!(query.sql.ends_with(") datoms00 WHERE datoms00.a = 99 AND datoms00.v = $v0 AND datoms00.value_type_tag = 10"));
+    }
+
+    #[test]
+    fn test_history_log() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":db/txInstant", 3, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+
+        let query = translate_str(&schema, r#"[:find ?v ?added :history true :where [_ :foo/bar ?v _ ?added]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms00.v, datoms00.value_type_tag, datoms00.added, 1 \
+                               FROM (SELECT t.e AS e, t.a AS a, t.v AS v, t.tx AS tx, t.value_type_tag AS value_type_tag, t.added AS added \
+                               FROM transactions AS t WHERE 1) datoms00 \
+                               WHERE datoms00.a = 99");
+
+        let query = translate_str(&schema, r#"[:find ?v :history true :as-of 200 :since 100 :where [_ :foo/bar ?v _ false]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms00.v, datoms00.value_type_tag \
+                               FROM (SELECT t.e AS e, t.a AS a, t.v AS v, t.tx AS tx, t.value_type_tag AS value_type_tag, t.added AS added \
+                               FROM transactions AS t WHERE 1 AND t.tx <= 200 AND t.tx > 100) datoms00 \
+                               WHERE datoms00.a = 99 AND datoms00.added = 0");
+
+        match translate_str(&schema, r#"[:find ?v :where [_ :foo/bar ?v _ ?added]]"#) {
+            Err(Error(ErrorKind::RequiresHistory(_), _)) => (),
+            x => panic!("expected RequiresHistory, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_fulltext() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/text", 99, Attribute {
+            value_type: ValueType::String,
+            fulltext: true,
+            ..Default::default()
+        });
+
+        let query = translate_str(&schema, r#"[:find ?e ?score ?snippet :where [(fulltext $ :foo/text "fox") [[?e _ _ ?score ?snippet]]]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms01.e, 0, fulltext_values00.score, 5, fulltext_values00.snippet, 10 \
+                               FROM (SELECT rowid, text, -bm25(fulltext_values) AS score, \
+                               snippet(fulltext_values, 0, '<b>', '</b>', '...', 10) AS snippet \
+                               FROM fulltext_values WHERE fulltext_values MATCH $v0) fulltext_values00, datoms datoms01 \
+                               WHERE datoms01.a = 99 AND datoms01.v = fulltext_values00.rowid");
+        assert_eq!(query.args, vec![("$v0".to_string(), TypedValue::String("fox".to_string()))]);
+    }
+
+    #[test]
+    fn test_json_get() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/json", 99, Attribute {
+            value_type: ValueType::Json,
+            ..Default::default()
+        });
+
+        let query = translate_str(&schema, r#"[:find ?e ?name :where [?e :foo/json ?v] [(json-get ?v "$.name") ?name]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms00.e, 0, json_values01.v, json_values01.value_type_tag \
+                               FROM datoms datoms00, \
+                               (SELECT DISTINCT j.v AS json, json_extract(j.v, $v0) AS v, \
+                               CASE json_type(j.v, $v0) WHEN 'true' THEN 1 WHEN 'false' THEN 1 \
+                               WHEN 'integer' THEN 5 WHEN 'real' THEN 5 WHEN 'text' THEN 10 ELSE 14 END AS value_type_tag \
+                               FROM datoms AS j WHERE j.value_type_tag = 14 AND json_type(j.v, $v0) != 'null') json_values01 \
+                               WHERE datoms00.a = 99 AND datoms00.value_type_tag = 14 AND datoms00.v = json_values01.json");
+        assert_eq!(query.args, vec![("$v0".to_string(), TypedValue::String("$.name".to_string()))]);
+    }
+
+    #[test]
+    fn test_tuple_get() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/span", 99, Attribute {
+            value_type: ValueType::Tuple,
+            tuple_types: vec![ValueType::Long, ValueType::Long],
+            ..Default::default()
+        });
+
+        let query = translate_str(&schema, "[:find ?e ?end :where [?e :foo/span ?t] [(tuple-get ?t 1) ?end]]").unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms00.e, 0, tuple_values01.v, tuple_values01.value_type_tag \
+                               FROM datoms datoms00, \
+                               (SELECT DISTINCT t.v AS tuple, json_extract(t.v, '$[1][1]') AS v, \
+                               json_extract(t.v, '$[1][0]') AS value_type_tag \
+                               FROM datoms AS t WHERE t.value_type_tag = 16 AND json_array_length(t.v) > 1) tuple_values01 \
+                               WHERE datoms00.a = 99 AND datoms00.value_type_tag = 16 AND datoms00.v = tuple_values01.tuple");
+        assert!(query.args.is_empty());
+
+        assert!(translate_str(&schema, "[:find ?e :where [?e :foo/span ?t] [(tuple-get ?t -1) ?x]]").is_err());
+        assert!(translate_str(&schema, "[:find ?x :where [(tuple-get ?t 0) ?x]]").is_err());
+    }
+
+    #[test]
+    fn test_count() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+
+        let query = translate_str(&schema, "[:find (count ?e) . :where [?e :foo/bar _]]").unwrap();
+        assert_eq!(query.sql, "SELECT COUNT(DISTINCT datoms00.e), 5 FROM datoms datoms00 WHERE datoms00.a = 99 LIMIT 1");
+
+        let query = translate_str(&schema, "[:find (count ?v) . :where [_ :foo/bar ?v]]").unwrap();
+        assert_eq!(query.sql, "SELECT COUNT(*), 5 FROM (SELECT DISTINCT datoms00.v, datoms00.value_type_tag \
+                               FROM datoms datoms00 WHERE datoms00.a = 99) LIMIT 1");
+
+        assert!(translate_str(&schema, "[:find (count ?e) ?v :where [?e :foo/bar ?v]]").is_err());
+        assert!(translate_str(&schema, "[:find (count ?x) . :where [?e :foo/bar _]]").is_err());
+    }
+
+    #[test]
+    fn test_instant_functions() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/at", 99, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+
+        let query = translate_str(&schema, "[:find ?day :where [?e :foo/at ?t] [(between ?t 1000 2000)] [(date ?t \"+05:30\") ?day]]").unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT instant_values01.v, instant_values01.value_type_tag \
+                               FROM datoms datoms00, \
+                               (SELECT DISTINCT i.v AS instant, strftime('%Y-%m-%d', i.v / 1000.0, 'unixepoch', '+330 minutes') AS v, \
+                               10 AS value_type_tag FROM datoms AS i WHERE i.value_type_tag = 5) instant_values01 \
+                               WHERE datoms00.a = 99 AND datoms00.value_type_tag = 5 AND datoms00.v = instant_values01.instant \
+                               AND datoms00.value_type_tag = 5 AND datoms00.v >= 1000 AND datoms00.v < 2000");
+
+        // Instants are longs, whatever the attribute.
+        let query = translate_str(&schema, r#"[:find ?e :where [?e :foo/at #inst "1970-01-01T00:00:01Z"]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms00.e, 0 FROM datoms datoms00 \
+                               WHERE datoms00.a = 99 AND datoms00.v = $v0 AND datoms00.value_type_tag = 5");
+        assert_eq!(query.args, vec![("$v0".to_string(), TypedValue::Long(1000))]);
+        let query = translate_str(&schema, r#"[:find ?e :where [?e :foo/at ?t] [(between ?t #inst "1970-01-01T00:00:01Z" 2000)]]"#).unwrap();
+        assert!(query.sql.ends_with("datoms00.v >= 1000 AND datoms00.v < 2000"));
+
+        let query = translate_str(&schema, "[:find ?month :where [?e :foo/at ?t] [(truncate ?t \"month\") ?month]]").unwrap();
+        assert!(query.sql.contains("(CAST(strftime('%s', strftime('%Y-%m-01 00:00:00', i.v / 1000.0, 'unixepoch', '+0 minutes')) AS INTEGER) - 0) * 1000 AS v, 5 AS value_type_tag"));
+
+        assert!(translate_str(&schema, "[:find ?day :where [?e :foo/at ?t] [(date ?t \"+5\") ?day]]").is_err());
+        assert!(translate_str(&schema, "[:find ?x :where [?e :foo/at ?t] [(truncate ?t) ?x]]").is_err());
+        assert!(translate_str(&schema, "[:find ?e :where [?e :foo/at ?t] [(between ?t 1000)]]").is_err());
+        assert!(translate_str(&schema, "[:find ?e :where [?e :foo/at _] [(between ?t 1000 2000)]]").is_err());
+    }
+
+    #[test]
+    fn test_not() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, ":foo/baz", 100, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+
+        // The body is correlated through the unified variables, wherever the `not` appears.
+        let query = translate_str(&schema, r#"[:find ?x :where (not [?x :foo/baz 1]) [?x :foo/bar "yyy"]]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms00.e, 0 FROM datoms datoms00 \
+                               WHERE datoms00.a = 99 AND datoms00.v = $v0 AND datoms00.value_type_tag = 10 \
+                               AND NOT EXISTS (SELECT 1 FROM datoms datoms01 \
+                               WHERE datoms00.e = datoms01.e AND datoms01.a = 100 AND datoms01.v = $v1 AND datoms01.value_type_tag = 5)");
+
+        // Variables that aren't unified are local to the body.
+        let query = translate_str(&schema, r#"[:find ?x :where [?x :foo/bar ?y] (not-join [?x] [?x :foo/baz ?y])]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms00.e, 0 FROM datoms datoms00 \
+                               WHERE datoms00.a = 99 \
+                               AND NOT EXISTS (SELECT 1 FROM datoms datoms01 WHERE datoms00.e = datoms01.e AND datoms01.a = 100)");
+        match translate_str(&schema, r#"[:find ?y :where [?x :foo/bar _] (not-join [?x] [?x :foo/baz ?y])]"#) {
+            Err(Error(ErrorKind::UnboundVariable(ref var), _)) if (var.0).0 == "?y" => (),
+            x => panic!("expected UnboundVariable, got {:?}", x),
+        }
+
+        // A `not` can't bind anything.
+        match translate_str(&schema, r#"[:find ?x :where [?x :foo/bar _] (not [?y :foo/baz ?x])]"#) {
+            Err(Error(ErrorKind::UnboundInScope(ref var, ref clause), _)) if (var.0).0 == "?y" && clause == "(not ...)" => (),
+            x => panic!("expected UnboundInScope, got {:?}", x),
+        }
+        match translate_str(&schema, r#"[:find ?x :where [?x :foo/bar _] (not-join [?x ?z] [?x :foo/baz ?z])]"#) {
+            Err(Error(ErrorKind::UnboundInScope(ref var, ref clause), _)) if (var.0).0 == "?z" && clause == "(not-join [?x ?z] ...)" => (),
+            x => panic!("expected UnboundInScope, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_or() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, ":foo/baz", 100, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+
+        let query = translate_str(&schema, r#"[:find ?x ?v :where (or [?x :foo/bar ?v] [?x :foo/baz ?v])]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT union02.v1, union02.value_type_tag1, union02.v0, union02.value_type_tag0 \
+                               FROM (SELECT datoms00.v AS v0, datoms00.value_type_tag AS value_type_tag0, datoms00.e AS v1, 0 AS value_type_tag1 \
+                               FROM datoms datoms00 WHERE datoms00.a = 99 \
+                               UNION SELECT datoms01.v AS v0, datoms01.value_type_tag AS value_type_tag0, datoms01.e AS v1, 0 AS value_type_tag1 \
+                               FROM datoms datoms01 WHERE datoms01.a = 100) union02");
+
+        // Only the declared variables are unified; `?y` is local to each leg.
+        let query = translate_str(&schema, r#"[:find ?x :where [?x :foo/bar ?y] (or-join [?x] [?x :foo/baz ?y] (and [?x :foo/bar ?y] [?y :foo/baz 1]))]"#).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms00.e, 0 FROM datoms datoms00, \
+                               (SELECT datoms01.e AS v0, 0 AS value_type_tag0 FROM datoms datoms01 WHERE datoms01.a = 100 \
+                               UNION SELECT datoms02.e AS v0, 0 AS value_type_tag0 FROM datoms datoms02, datoms datoms03 \
+                               WHERE datoms02.a = 99 AND datoms02.v = datoms03.e AND datoms03.a = 100 AND datoms03.v = $v0 AND datoms03.value_type_tag = 5) union04 \
+                               WHERE datoms00.a = 99 AND datoms00.e = union04.v0");
+
+        match translate_str(&schema, r#"[:find ?x :where (or [?x :foo/bar _] [?y :foo/baz _])]"#) {
+            Err(Error(ErrorKind::NonUnifiedVariable(ref var, ref clause), _)) if (var.0).0 == "?x" && clause == "(or ...)" => (),
+            x => panic!("expected NonUnifiedVariable, got {:?}", x),
+        }
+        match translate_str(&schema, r#"[:find ?x :where (or-join [?x] [?x :foo/bar ?y] [?y :foo/baz 1])]"#) {
+            Err(Error(ErrorKind::NonUnifiedVariable(ref var, ref clause), _)) if (var.0).0 == "?x" && clause == "(or-join [?x] ...)" => (),
+            x => panic!("expected NonUnifiedVariable, got {:?}", x),
+        }
+        match translate_str(&schema, r#"[:find ?y :where (or-join [?x] [?x :foo/bar ?y] [?x :foo/baz ?y])]"#) {
+            Err(Error(ErrorKind::UnboundVariable(ref var), _)) if (var.0).0 == "?y" => (),
+            x => panic!("expected UnboundVariable, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_index_hints() {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, ":db/txInstant", 3, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, ":foo/bar", 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, ":foo/text", 100, Attribute {
+            value_type: ValueType::String,
+            fulltext: true,
+            ..Default::default()
+        });
+        let translate_hinted = |input: &str, hints: &IndexHints| {
+            translate_with_hints(&schema, &parse_find_string(input).expect("to parse query"), QueryInputs::new(), hints)
+        };
+
+        let mut hints = IndexHints::new();
+        hints.insert(0, IndexHint::IndexedBy("idx_datoms_avet".to_string()));
+        hints.insert(2, IndexHint::NotIndexed);
+        let query = translate_hinted(r#"[:find ?x :where [?x :foo/bar "yyy"] (not [?x :foo/bar "zzz"]) [?x :foo/bar ?y]]"#, &hints).unwrap();
+        assert_eq!(query.sql, "SELECT DISTINCT datoms00.e, 0 FROM datoms datoms00 INDEXED BY idx_datoms_avet, datoms datoms01 NOT INDEXED \
+                               WHERE datoms00.a = 99 AND datoms00.v = $v0 AND datoms00.value_type_tag = 10 \
+                               AND datoms00.e = datoms01.e AND datoms01.a = 99 \
+                               AND NOT EXISTS (SELECT 1 FROM datoms datoms02 WHERE datoms00.e = datoms02.e AND datoms02.a = 99 AND datoms02.v = $v1 AND datoms02.value_type_tag = 10)");
+        assert_eq!(query.pattern_aliases.get(&2), Some(&"datoms01".to_string()));
+
+        let invalid = |input: &str, hint: IndexHint| {
+            let mut hints = IndexHints::new();
+            hints.insert(0, hint);
+            match translate_hinted(input, &hints) {
+                Err(Error(ErrorKind::InvalidIndexHint(_), _)) => (),
+                x => panic!("expected InvalidIndexHint, got {:?}", x),
+            }
+        };
+        // Only patterns reading the datoms table can take a hint.
+        invalid(r#"[:find ?x :where (not [?x :foo/bar "zzz"]) [?x :foo/bar _]]"#, IndexHint::NotIndexed);
+        invalid(r#"[:find ?x :where [?x :foo/text "zzz"]]"#, IndexHint::NotIndexed);
+        invalid(r#"[:find ?x :since 100 :where [?x :foo/bar "zzz"]]"#, IndexHint::NotIndexed);
+        invalid(r#"[:find ?x :where [?x :foo/bar "zzz"]]"#, IndexHint::IndexedBy("idx; DROP TABLE datoms".to_string()));
+    }
+
+    #[test]
+    fn test_unbound_variable() {
+        let schema = Schema::default();
+        match translate_str(&schema, r#"[:find ?y :where [?x _ _]]"#) {
+            Err(Error(ErrorKind::UnboundVariable(_), _)) => (),
+            x => panic!("expected UnboundVariable, got {:?}", x),
+        }
+    }
+}
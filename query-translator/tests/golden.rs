@@ -0,0 +1,141 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Golden-file tests of the translator.
+//!
+//! Each `tests/golden/<name>.edn` other than `schema.edn` holds a query.  The query is translated
+//! against the schema transacted from `schema.edn`, and the SQL and its arguments are compared
+//! with `tests/golden/<name>.sql`.  A missing or differing golden file fails the test.  To record
+//! the golden file of a new query, or after a deliberate change to the translator, run
+//!
+//! ```text
+//! MENTAT_UPDATE_GOLDEN=1 cargo test -p mentat_query_translator --test golden
+//! ```
+//!
+//! to write the golden files, and review the change as a diff of the SQL.
+
+extern crate edn;
+extern crate mentat_db;
+extern crate mentat_query_parser;
+extern crate mentat_query_translator;
+extern crate mentat_tx_parser;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use mentat_db::Schema;
+use mentat_db::db;
+use mentat_query_parser::parse_find_string;
+use mentat_query_translator::{SQLQuery, translate};
+use mentat_tx_parser::Tx;
+
+/// Set to write golden files that are missing or differ from the translator's output.
+const UPDATE_GOLDEN: &'static str = "MENTAT_UPDATE_GOLDEN";
+
+/// Keywords that start a line of rendered SQL, so that a change to one clause is a change to one
+/// line of the golden file.
+const BREAKS: &'static [&'static str] = &[" FROM ", " WHERE ", " AND ", " UNION ", " GROUP BY ", " ORDER BY ", " LIMIT "];
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+fn read(path: &Path) -> String {
+    let mut text = String::new();
+    File::open(path).and_then(|mut file| file.read_to_string(&mut text))
+        .unwrap_or_else(|e| panic!("could not read {}: {}", path.display(), e));
+    text
+}
+
+/// The schema of a fresh store after transacting `schema.edn`.
+fn schema() -> Schema {
+    let mut conn = db::new_connection("").expect("to open a store");
+    db::ensure_current_version(&mut conn).expect("to bootstrap");
+    let db = db::read_db(&conn).expect("to read the store");
+
+    let value = edn::parse::value(&read(&golden_dir().join("schema.edn"))).expect("to parse schema.edn");
+    let entities = Tx::parse(&[value][..]).expect("schema.edn to be a transaction");
+    let (_, db) = mentat_db::transact(&conn, &db, &entities).expect("to transact schema.edn");
+    db.schema
+}
+
+fn render_sql(sql: &str) -> String {
+    BREAKS.iter().fold(sql.to_string(), |sql, keyword| sql.replace(keyword, &format!("\n{}", &keyword[1..])))
+}
+
+/// The golden rendering of `query`: its temporary tables, its SQL, and its arguments, one per line.
+fn render(query: &SQLQuery) -> String {
+    let mut out = String::new();
+    if let Some(ref empty_because) = query.empty_because {
+        out.push_str(&format!("-- empty: {:?}\n", empty_because));
+    }
+    for table in query.temp_tables.iter() {
+        out.push_str(&format!("-- temp.{}\n{}\n", table.name, render_sql(&table.sql)));
+        for &(ref name, ref value) in table.args.iter() {
+            out.push_str(&format!("{} = {:?}\n", name, value));
+        }
+        out.push_str("\n");
+    }
+    out.push_str(&render_sql(&query.sql));
+    out.push_str("\n");
+    for &(ref name, ref value) in query.args.iter() {
+        out.push_str(&format!("{} = {:?}\n", name, value));
+    }
+    out
+}
+
+#[test]
+fn test_golden() {
+    let schema = schema();
+    let update = env::var(UPDATE_GOLDEN).map(|v| !v.is_empty() && v != "0").unwrap_or(false);
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(golden_dir()).expect("to list tests/golden")
+        .map(|entry| entry.expect("to list tests/golden").path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "edn"))
+        .filter(|path| path.file_stem().map_or(false, |stem| stem != "schema"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no queries in tests/golden");
+
+    let mut failures = vec![];
+    for path in paths.iter() {
+        let query = parse_find_string(&read(path))
+            .unwrap_or_else(|e| panic!("could not parse {}: {:?}", path.display(), e));
+        let actual = match translate(&schema, &query) {
+            Ok(sql) => render(&sql),
+            Err(e) => {
+                failures.push(format!("{}: could not translate: {}", path.display(), e));
+                continue;
+            },
+        };
+
+        let golden = path.with_extension("sql");
+        let expected = if golden.exists() { Some(read(&golden)) } else { None };
+        if expected.as_ref() == Some(&actual) {
+            continue;
+        }
+        if update {
+            File::create(&golden).and_then(|mut file| file.write_all(actual.as_bytes()))
+                .unwrap_or_else(|e| panic!("could not write {}: {}", golden.display(), e));
+            continue;
+        }
+        match expected {
+            Some(expected) => failures.push(format!("{}: SQL differs\n--- expected\n{}--- actual\n{}", golden.display(), expected, actual)),
+            None => failures.push(format!("{}: missing\n--- actual\n{}", golden.display(), actual)),
+        }
+    }
+
+    if !failures.is_empty() {
+        panic!("{} golden queries failed; set {}=1 to accept the new SQL\n\n{}",
+               failures.len(), UPDATE_GOLDEN, failures.join("\n"));
+    }
+}
@@ -0,0 +1,5 @@
+SELECT DISTINCT datoms01.v, datoms01.value_type_tag
+FROM datoms datoms00, datoms datoms01
+WHERE datoms00.a = 65538
+AND datoms00.v = datoms01.e
+AND datoms01.a = 65536
@@ -0,0 +1,5 @@
+SELECT DISTINCT datoms00.v, datoms00.value_type_tag, datoms00.added, 1
+FROM (SELECT t.e AS e, t.a AS a, t.v AS v, t.tx AS tx, t.value_type_tag AS value_type_tag, t.added AS added
+FROM transactions AS t
+WHERE 1) datoms00
+WHERE datoms00.a = 65536
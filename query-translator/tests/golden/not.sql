@@ -0,0 +1,7 @@
+SELECT DISTINCT datoms00.e, 0
+FROM datoms datoms00
+WHERE datoms00.a = 65536
+AND NOT EXISTS (SELECT 1
+FROM datoms datoms01
+WHERE datoms00.e = datoms01.e
+AND datoms01.a = 65538)
@@ -0,0 +1,13 @@
+SELECT DISTINCT union02.v0, union02.value_type_tag0
+FROM (SELECT datoms00.e AS v0, 0 AS value_type_tag0
+FROM datoms datoms00
+WHERE datoms00.a = 65536
+AND datoms00.v = $v0
+AND datoms00.value_type_tag = 10
+UNION SELECT datoms01.e AS v0, 0 AS value_type_tag0
+FROM datoms datoms01
+WHERE datoms01.a = 65537
+AND datoms01.v = $v1
+AND datoms01.value_type_tag = 5) union02
+$v0 = String("Alice")
+$v1 = Long(30)
@@ -0,0 +1,14 @@
+SELECT DISTINCT datoms00.e, 0
+FROM datoms datoms00, (SELECT datoms01.e AS v0, 0 AS value_type_tag0
+FROM datoms datoms01
+WHERE datoms01.a = 65538
+UNION SELECT datoms02.e AS v0, 0 AS value_type_tag0
+FROM datoms datoms02, datoms datoms03
+WHERE datoms02.a = 65537
+AND datoms02.e = datoms03.e
+AND datoms03.a = 65539
+AND datoms03.v = $v0
+AND datoms03.value_type_tag = 13) union04
+WHERE datoms00.a = 65536
+AND datoms00.e = union04.v0
+$v0 = Keyword(NamespacedKeyword { namespace: "task", name: "done" })
@@ -0,0 +1,8 @@
+SELECT DISTINCT datoms00.e, 0, datoms01.v, datoms01.value_type_tag
+FROM datoms datoms00, datoms datoms01
+WHERE datoms00.a = 65536
+AND datoms00.v = $v0
+AND datoms00.value_type_tag = 10
+AND datoms00.e = datoms01.e
+AND datoms01.a = 65537
+$v0 = String("Alice")
@@ -0,0 +1,4 @@
+SELECT datoms00.v, datoms00.value_type_tag
+FROM datoms datoms00
+WHERE datoms00.a = 65537
+LIMIT 1
@@ -0,0 +1,2 @@
+-- empty: UnknownAttribute(":person/nickname")
+
@@ -34,13 +34,15 @@ extern crate edn;
 extern crate num;
 extern crate ordered_float;
 
+use std::collections::BTreeSet;
+
 use num::BigInt;
 use ordered_float::OrderedFloat;
 use edn::{NamespacedKeyword, PlainSymbol};
 
 pub type SrcVarName = String;          // Do not include the required syntactic '$'.
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
 pub struct Variable(pub PlainSymbol);
 
 #[derive(Clone,Debug,Eq,PartialEq)]
@@ -56,8 +58,12 @@ pub enum NonIntegerConstant {
     BigInteger(BigInt),
     Float(OrderedFloat<f64>),
     Text(String),
+    Bytes(Vec<u8>),
+    /// An instant, like `#inst "2017-01-01T00:00:00Z"`, in milliseconds since the Unix epoch.
+    Instant(i64),
 }
 
+#[derive(Clone,Debug,Eq,PartialEq)]
 pub enum FnArg {
     Variable(Variable),
     SrcVar(SrcVar),
@@ -72,16 +78,18 @@ pub enum FnArg {
 /// This encoding allows us to represent integers that aren't
 /// entity IDs. That'll get filtered out in the context of the
 /// database.
+#[derive(Clone,Debug,Eq,PartialEq)]
 pub enum PatternNonValuePlace {
     Placeholder,
     Variable(Variable),
-    Entid(u64),                       // Note unsigned. See #190.
+    Entid(i64),                       // Never negative. See #190.
     Ident(NamespacedKeyword),
 }
 
 /// The `v` part of a pattern can be much broader: it can represent
 /// integers that aren't entity IDs (particularly negative integers),
 /// strings, and all the rest. We group those under `Constant`.
+#[derive(Clone,Debug,Eq,PartialEq)]
 pub enum PatternValuePlace {
     Placeholder,
     Variable(Variable),
@@ -110,13 +118,43 @@ pub struct Aggregate {
 }
 */
 
+/// The caller-chosen name of a `:find` element, written `?x :as :person/id`.
+///
+/// Holds the keyword's EDN text, like `:person/id`.
+pub type Alias = String;
+
 #[derive(Clone,Debug,Eq,PartialEq)]
 pub enum Element {
     Variable(Variable),
+    Aliased(Variable, Alias),
+    /// The number of distinct values of a variable, written `(count ?x)`.  Only a scalar find spec
+    /// can count, like `[:find (count ?e) . :where ...]`.
+    Count(Variable),
     // Aggregate(Aggregate),   // TODO
     // Pull(Pull),             // TODO
 }
 
+impl Element {
+    /// The variable whose values this element projects.
+    pub fn variable(&self) -> &Variable {
+        match self {
+            &Element::Variable(ref var) => var,
+            &Element::Aliased(ref var, _) => var,
+            &Element::Count(ref var) => var,
+        }
+    }
+
+    /// The name under which this element's values are projected: its alias, if it has one, or
+    /// else its variable's name, like `?x`.
+    pub fn name(&self) -> String {
+        match self {
+            &Element::Variable(ref var) => (var.0).0.clone(),
+            &Element::Aliased(_, ref alias) => alias.clone(),
+            &Element::Count(ref var) => format!("(count {})", (var.0).0),
+        }
+    }
+}
+
 /// A definition of the first part of a find query: the
 /// `[:find ?foo ?bar…]` bit.
 ///
@@ -166,11 +204,48 @@ pub enum FindSpec {
     FindScalar(Element),
 }
 
+/// A point in a store's history: a transaction ID, or an instant in milliseconds since the Unix
+/// epoch, which stands for the last transaction at or before that instant.
+#[derive(Clone,Copy,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum PointInTime {
+    Tx(i64),
+    Instant(i64),
+}
+
 #[derive(Clone,Debug,Eq,PartialEq)]
 #[allow(dead_code)]
 pub struct FindQuery {
     pub find_spec: FindSpec,
     pub default_source: SrcVar,
+    /// Scalar inputs bound at execution time, like `:in $ ?name`.
+    pub in_vars: Vec<Variable>,
+    /// Collection and relation inputs bound at execution time, like `:in $ [?name ...]` and
+    /// `:in $ [[?name ?age]]`: the variables of each, bound to every row of a set.  A collection
+    /// is a relation with one variable.
+    pub in_rels: Vec<Vec<Variable>>,
+    pub where_clauses: Vec<WhereClause>,
+    /// Query the store as it was at this point, like `:as-of 268435460`.
+    pub as_of: Option<PointInTime>,
+    /// Only consider datoms added after this point, like `:since #inst "2017-01-01T00:00:00Z"`.
+    pub since: Option<PointInTime>,
+    /// Query every assertion and retraction in the transaction log, rather than the datoms
+    /// present, like `:history true`.  Combines with `:as-of` and `:since` to query part of the log.
+    pub history: bool,
+    /// Project refs to entities that have idents as those idents, like `:task.status/done`, rather
+    /// than as entids, like `:idents true`.
+    pub idents: bool,
+}
+
+impl FindSpec {
+    /// The elements of this find spec, in projection order.
+    pub fn elements(&self) -> Vec<&Element> {
+        match self {
+            &FindSpec::FindRel(ref elements) => elements.iter().collect(),
+            &FindSpec::FindTuple(ref elements) => elements.iter().collect(),
+            &FindSpec::FindColl(ref element) => vec![element],
+            &FindSpec::FindScalar(ref element) => vec![element],
+        }
+    }
 }
 
 /// Returns true if the provided `FindSpec` returns at most one result.
@@ -209,27 +284,209 @@ pub fn requires_distinct(spec: &FindSpec) -> bool {
 // A pattern with a reversed attribute — :foo/_bar — is reversed
 // at the point of parsing. These `Pattern` instances only represent
 // one direction.
-#[allow(dead_code)]
+#[derive(Clone,Debug,Eq,PartialEq)]
 pub struct Pattern {
-    source: Option<SrcVar>,
-    entity: PatternNonValuePlace,
-    attribute: PatternNonValuePlace,
-    value: PatternValuePlace,
-    tx: PatternNonValuePlace,
+    pub source: Option<SrcVar>,
+    pub entity: PatternNonValuePlace,
+    pub attribute: PatternNonValuePlace,
+    pub value: PatternValuePlace,
+    pub tx: PatternNonValuePlace,
+    /// Whether the datom was asserted (`true`) or retracted (`false`), like the `?added` in
+    /// `[?e ?a ?v ?tx ?added]`.  Only a placeholder, a variable, or a boolean constant; anything but
+    /// a placeholder requires a `:history` query.
+    pub added: PatternValuePlace,
 }
 
-#[allow(dead_code)]
+impl Pattern {
+    /// Construct a `Pattern`, reversing `[?e :foo/_bar ?v]` into `[?v :foo/bar ?e]`.
+    ///
+    /// Returns `None` if the pattern is reversed and the value place can't be used as an entity.
+    pub fn new(src: Option<SrcVar>,
+               e: PatternNonValuePlace,
+               a: PatternNonValuePlace,
+               v: PatternValuePlace,
+               tx: PatternNonValuePlace,
+               added: PatternValuePlace) -> Option<Pattern> {
+        let reversed = match a {
+            PatternNonValuePlace::Ident(ref kw) if kw.name.starts_with('_') => {
+                Some(NamespacedKeyword::new(kw.namespace.as_str(), &kw.name[1..]))
+            },
+            _ => None,
+        };
+
+        if let Some(attribute) = reversed {
+            let v_e = match v {
+                PatternValuePlace::Placeholder => PatternNonValuePlace::Placeholder,
+                PatternValuePlace::Variable(var) => PatternNonValuePlace::Variable(var),
+                PatternValuePlace::EntidOrInteger(x) if x >= 0 => PatternNonValuePlace::Entid(x),
+                PatternValuePlace::Ident(kw) => PatternNonValuePlace::Ident(kw),
+                // Can't reverse a pattern whose value isn't an entity.
+                _ => return None,
+            };
+            let e_v = match e {
+                PatternNonValuePlace::Placeholder => PatternValuePlace::Placeholder,
+                PatternNonValuePlace::Variable(var) => PatternValuePlace::Variable(var),
+                PatternNonValuePlace::Entid(x) => PatternValuePlace::EntidOrInteger(x),
+                PatternNonValuePlace::Ident(kw) => PatternValuePlace::Ident(kw),
+            };
+            return Some(Pattern {
+                source: src,
+                entity: v_e,
+                attribute: PatternNonValuePlace::Ident(attribute),
+                value: e_v,
+                tx: tx,
+                added: added,
+            });
+        }
+
+        Some(Pattern {
+            source: src,
+            entity: e,
+            attribute: a,
+            value: v,
+            tx: tx,
+            added: added,
+        })
+    }
+}
+
+/// The form in which a function call binds its results.
+///
+/// Only the scalar, collection, and relation forms are supported.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum Binding {
+    /// A single value, like `?out`.
+    BindScalar(Variable),
+    /// Each of a collection of values, like `[?tx ...]`.
+    BindColl(Variable),
+    /// A relation, like `[[?e ?text _]]`.  Each place is a variable, or `None` for the placeholder
+    /// `_`.
+    BindRel(Vec<Option<Variable>>),
+}
+
+/// A function call that binds its results, like
+/// `[(fulltext $ :foo/text "search") [[?e ?text ?tx ?score ?snippet]]]` or
+/// `[(json-get ?v "$.path") ?out]`.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct WhereFn {
+    pub operator: PlainSymbol,
+    pub args: Vec<FnArg>,
+    pub binding: Binding,
+}
+
+/// A function call that binds nothing, but filters the results, like
+/// `[(between ?t #inst "2017-01-01T00:00:00Z" #inst "2017-02-01T00:00:00Z")]`.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct Predicate {
+    pub operator: PlainSymbol,
+    pub args: Vec<FnArg>,
+}
+
+/// The variables through which an `or` or `not` clause unifies with the enclosing query.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum UnifyVars {
+    /// Every variable in the clause, as for `or` and `not`.
+    Implicit,
+    /// Only the declared variables, as for `or-join` and `not-join`.
+    Explicit(Vec<Variable>),
+}
+
+/// One leg of an `or`: a single clause, or a conjunction like `(and [?x :foo/bar ?y] [?y :foo/baz 1])`.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum OrWhereClause {
+    Clause(WhereClause),
+    And(Vec<WhereClause>),
+}
+
+impl OrWhereClause {
+    pub fn clauses(&self) -> Vec<&WhereClause> {
+        match *self {
+            OrWhereClause::Clause(ref clause) => vec![clause],
+            OrWhereClause::And(ref clauses) => clauses.iter().collect(),
+        }
+    }
+}
+
+/// A disjunction, like `(or [?x :foo/bar 1] [?x :foo/baz 2])` or
+/// `(or-join [?x] [?x :foo/bar ?y] [?x :foo/baz ?z])`.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct OrJoin {
+    pub unify_vars: UnifyVars,
+    pub clauses: Vec<OrWhereClause>,
+}
+
+/// A negation, like `(not [?x :foo/bar 1])` or `(not-join [?x] [?x :foo/bar ?y] [?y :foo/baz 2])`.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct NotJoin {
+    pub unify_vars: UnifyVars,
+    pub clauses: Vec<WhereClause>,
+}
+
+#[derive(Clone,Debug,Eq,PartialEq)]
 pub enum WhereClause {
     /*
-    Not,
-    NotJoin,
-    Or,
-    OrJoin,
-    Pred,
-    WhereFn,
     RuleExpr,
     */
-    Pattern,
+    NotJoin(NotJoin),
+    OrJoin(OrJoin),
+    Pred(Predicate),
+    WhereFn(WhereFn),
+    Pattern(Pattern),
+}
+
+impl WhereClause {
+    /// Add the variables mentioned by this clause to `acc`.  The variables of nested `or-join` and
+    /// `not-join` clauses are only those they declare.
+    pub fn collect_variables(&self, acc: &mut BTreeSet<Variable>) {
+        match *self {
+            WhereClause::Pattern(ref pattern) => {
+                for place in [&pattern.entity, &pattern.attribute, &pattern.tx].iter() {
+                    if let PatternNonValuePlace::Variable(ref var) = **place {
+                        acc.insert(var.clone());
+                    }
+                }
+                for place in [&pattern.value, &pattern.added].iter() {
+                    if let PatternValuePlace::Variable(ref var) = **place {
+                        acc.insert(var.clone());
+                    }
+                }
+            },
+            WhereClause::Pred(ref predicate) => {
+                for arg in predicate.args.iter() {
+                    if let FnArg::Variable(ref var) = *arg {
+                        acc.insert(var.clone());
+                    }
+                }
+            },
+            WhereClause::WhereFn(ref where_fn) => {
+                for arg in where_fn.args.iter() {
+                    if let FnArg::Variable(ref var) = *arg {
+                        acc.insert(var.clone());
+                    }
+                }
+                match where_fn.binding {
+                    Binding::BindScalar(ref var) | Binding::BindColl(ref var) => { acc.insert(var.clone()); },
+                    Binding::BindRel(ref places) => acc.extend(places.iter().filter_map(|place| place.clone())),
+                }
+            },
+            WhereClause::OrJoin(OrJoin { unify_vars: UnifyVars::Explicit(ref vars), .. }) |
+            WhereClause::NotJoin(NotJoin { unify_vars: UnifyVars::Explicit(ref vars), .. }) => {
+                acc.extend(vars.iter().cloned());
+            },
+            WhereClause::OrJoin(ref or_join) => {
+                for leg in or_join.clauses.iter() {
+                    for clause in leg.clauses() {
+                        clause.collect_variables(acc);
+                    }
+                }
+            },
+            WhereClause::NotJoin(ref not_join) => {
+                for clause in not_join.clauses.iter() {
+                    clause.collect_variables(acc);
+                }
+            },
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -0,0 +1,124 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! A futures-returning API over a `SharedStore`, for applications built on an event loop.
+//!
+//! Every Mentat operation blocks on SQLite, which would stall an event loop.  An `AsyncStore`
+//! runs each operation on a pool of threads set aside for blocking work, and returns a future of
+//! its result.  Operations are still serialized on the store, as for `SharedStore`.
+//!
+//! Only built with the `async` feature.
+
+use std::sync::Arc;
+
+use futures_cpupool::{CpuFuture, CpuPool};
+
+use mentat_db::{Entid, Schema, TxReport};
+use mentat_query_translator::QueryInputs;
+
+use errors::*;
+use query::QueryOutput;
+use shared::SharedStore;
+use store::Store;
+
+/// A cloneable handle to a store whose operations run on a thread pool and return futures.
+#[derive(Clone)]
+pub struct AsyncStore {
+    store: SharedStore,
+    pool: CpuPool,
+}
+
+impl AsyncStore {
+    /// Run the operations on `store` on the threads of `pool`, which can be shared with other
+    /// blocking work.
+    pub fn new(store: SharedStore, pool: CpuPool) -> AsyncStore {
+        AsyncStore {
+            store: store,
+            pool: pool,
+        }
+    }
+
+    /// Open the store at `path`, with a pool of `threads` threads of its own.  Opening blocks.
+    /// See `Store::open`.
+    pub fn open(path: &str, threads: usize) -> Result<AsyncStore> {
+        Ok(AsyncStore::new(SharedStore::open(path)?, CpuPool::new(threads)))
+    }
+
+    /// The store the operations run on, for calls that don't need to be asynchronous.
+    pub fn shared(&self) -> &SharedStore {
+        &self.store
+    }
+
+    pub fn current_schema(&self) -> Arc<Schema> {
+        self.store.current_schema()
+    }
+
+    /// Run `f` on the pool with exclusive access to the store.
+    pub fn with_store_async<T, F>(&self, f: F) -> CpuFuture<T, Error>
+        where F: FnOnce(&mut Store) -> Result<T> + Send + 'static, T: Send + 'static {
+        let store = self.store.clone();
+        self.pool.spawn_fn(move || store.with_store(f))
+    }
+
+    pub fn q_once_async(&self, query: &str) -> CpuFuture<QueryOutput, Error> {
+        let query = query.to_string();
+        self.with_store_async(move |store| store.q_once(&query))
+    }
+
+    pub fn q_named_async(&self, name: &str, inputs: QueryInputs) -> CpuFuture<QueryOutput, Error> {
+        let name = name.to_string();
+        self.with_store_async(move |store| store.q_named(&name, inputs))
+    }
+
+    pub fn transact_async(&self, transaction: &str) -> CpuFuture<TxReport, Error> {
+        let transaction = transaction.to_string();
+        self.with_store_async(move |store| store.transact(&transaction))
+    }
+
+    pub fn transact_if_basis_async(&self, transaction: &str, expected_basis: Entid) -> CpuFuture<TxReport, Error> {
+        let transaction = transaction.to_string();
+        self.with_store_async(move |store| store.transact_if_basis(&transaction, expected_basis))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Future;
+
+    use mentat_db::TypedValue;
+
+    use query::QueryResults;
+
+    #[test]
+    fn test_async() {
+        let store = AsyncStore::open("", 2).unwrap();
+        let schema = store.transact_async(r#"[[:db/add "n" :db/ident :test/name]
+                                              [:db/add "n" :db/valueType :db.type/string]]"#);
+        let alice = schema
+            .and_then({
+                let store = store.clone();
+                move |_| store.transact_async(r#"[[:db/add "a" :test/name "Alice"]]"#)
+            })
+            .map(|report| report.tempids["a"])
+            .wait()
+            .unwrap();
+
+        assert_eq!(store.q_once_async(r#"[:find ?x . :where [?x :test/name "Alice"]]"#).wait().unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(alice))));
+
+        // Errors are delivered through the future.
+        assert!(store.transact_async(r#"[[:db/add "b" :test/unknown "Bob"]]"#).wait().is_err());
+        assert!(store.with_store_async(|store| store.q_once("[:find ?x :where [?x")).wait().is_err());
+    }
+}
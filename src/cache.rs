@@ -0,0 +1,78 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+
+use rusqlite;
+
+use mentat_db::{Cipher, Entid, TypedValue, decrypt_sql_value_pair};
+
+use errors::*;
+
+/// An in-memory cache of the values of a set of attributes, keyed by attribute and then entity.
+///
+/// Each cached attribute is loaded in full, and reloaded after every transaction.
+#[derive(Clone,Debug,Default,Eq,PartialEq)]
+pub struct AttributeCache {
+    attributes: BTreeMap<Entid, BTreeMap<Entid, Vec<TypedValue>>>,
+}
+
+impl AttributeCache {
+    pub fn is_cached(&self, attribute: Entid) -> bool {
+        self.attributes.contains_key(&attribute)
+    }
+
+    /// Return the cached values of `attribute` for `entity`, or `None` if the attribute isn't
+    /// cached.  A cached attribute with no values for `entity` yields an empty slice.
+    pub fn get(&self, attribute: Entid, entity: Entid) -> Option<&[TypedValue]> {
+        self.attributes.get(&attribute).map(|entities| {
+            entities.get(&entity).map(|values| &values[..]).unwrap_or(&[])
+        })
+    }
+
+    /// Start caching `attribute`, loading its current values from `conn`.  Encrypted values are
+    /// decrypted with `cipher`, and cached in plaintext.
+    pub fn register(&mut self, conn: &rusqlite::Connection, attribute: Entid, cipher: Option<&Cipher>) -> Result<()> {
+        let entities = load_attribute(conn, attribute, cipher)?;
+        self.attributes.insert(attribute, entities);
+        Ok(())
+    }
+
+    pub fn unregister(&mut self, attribute: Entid) {
+        self.attributes.remove(&attribute);
+    }
+
+    /// Reload every cached attribute from `conn`.
+    pub fn refresh(&mut self, conn: &rusqlite::Connection, cipher: Option<&Cipher>) -> Result<()> {
+        let attributes: Vec<Entid> = self.attributes.keys().cloned().collect();
+        for attribute in attributes {
+            self.register(conn, attribute, cipher)?;
+        }
+        Ok(())
+    }
+}
+
+fn load_attribute(conn: &rusqlite::Connection, attribute: Entid, cipher: Option<&Cipher>) -> Result<BTreeMap<Entid, Vec<TypedValue>>> {
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT e, v, value_type_tag FROM all_datoms WHERE a = ?")?;
+    let mut rows = stmt.query(&[&attribute])?;
+
+    let mut entities: BTreeMap<Entid, Vec<TypedValue>> = BTreeMap::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let e: Entid = row.get_checked(0)?;
+        let v: rusqlite::types::Value = row.get_checked(1)?;
+        let value_type_tag: i32 = row.get_checked(2)?;
+        let typed_value = decrypt_sql_value_pair(v, &value_type_tag, cipher)?;
+        entities.entry(e).or_insert(vec![]).push(typed_value);
+    }
+    Ok(entities)
+}
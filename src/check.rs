@@ -0,0 +1,74 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Linting a query against a store's schema, for `mentat lint --db todo.mentat QUERY`.
+//!
+//! `QUERY` is the EDN text of a query, or a file of it, ending `.edn`.  Each warning is written to
+//! standard output on a line of its own.  Warnings don't change the exit code, which is that of
+//! scripts; see `script`.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use mentat::Store;
+
+use script::{EXIT_FAILED, EXIT_IO, EXIT_OK};
+
+/// Lint `query` against the schema of the store at `db`, writing the warnings to `out`, and
+/// returning the exit code.
+pub fn run<W: Write>(db: &str, query: &str, out: &mut W) -> i32 {
+    let mut text = String::new();
+    if query.ends_with(".edn") {
+        if let Err(e) = File::open(query).and_then(|mut file| file.read_to_string(&mut text)) {
+            let _ = writeln!(io::stderr(), "could not read {}: {}", query, e);
+            return EXIT_IO;
+        }
+    } else {
+        text.push_str(query);
+    }
+    let store = match Store::open(db) {
+        Ok(store) => store,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "could not open {}: {}", db, e);
+            return EXIT_IO;
+        },
+    };
+    let lints = match store.lint(&text) {
+        Ok(lints) => lints,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}", e);
+            return EXIT_FAILED;
+        },
+    };
+    for lint in lints {
+        if let Err(e) = writeln!(out, "{}", lint) {
+            let _ = writeln!(io::stderr(), "could not write the warnings: {}", e);
+            return EXIT_IO;
+        }
+    }
+    EXIT_OK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let mut out = vec![];
+        assert_eq!(run("", "[:find ?e ?f :where [?e :db/doc _] [?f :db/doc _]]", &mut out), EXIT_OK);
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "clauses [0], [1] share no variables; the results are the product of each group's\n");
+
+        assert_eq!(run("", "[:find ?e :where [?e :db/doc _]]", &mut vec![]), EXIT_OK);
+        assert_eq!(run("", "[:find", &mut vec![]), EXIT_FAILED);
+        assert_eq!(run("", "/nonexistent/query.edn", &mut vec![]), EXIT_IO);
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Writing query results as CSV or TSV, for spreadsheets and text tools.
+//!
+//! `QueryOutput::to_csv` writes a header row naming the `:find` elements, like `?name` or an
+//! alias, and then a row per result.  Fields holding the delimiter, a double quote, or a line
+//! break are quoted, with inner quotes doubled, as in RFC 4180; rows end with a bare newline.
+//!
+//! Refs are written as entids, byte strings as base64, and tuples as EDN, like `[10 20]`.
+//! Instants are held as longs, so a query's results don't say which values are instants; the
+//! columns to format as timestamps are named in the `CsvOptions`.
+
+use std::collections::BTreeSet;
+use std::io::Write;
+
+use edn::types::{to_base64, to_rfc3339};
+use mentat_db::TypedValue;
+
+use errors::*;
+use query::{QueryOutput, QueryResults};
+
+/// How instants are written.
+#[derive(Clone,Copy,Debug,Eq,Hash,PartialEq)]
+pub enum InstantFormat {
+    /// Milliseconds since the Unix epoch, like `1483228800000`.
+    Millis,
+    /// UTC RFC 3339 timestamps, like `2017-01-01T00:00:00.000Z`.
+    Rfc3339,
+}
+
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct CsvOptions {
+    /// The field separator: `,` for CSV, or a tab for TSV.
+    pub delimiter: char,
+
+    /// Whether to write the header row.
+    pub header: bool,
+
+    /// Whether keywords keep their leading colon, like `:color/red`, or are written `color/red`.
+    pub keyword_colons: bool,
+
+    pub instant_format: InstantFormat,
+
+    /// The names of the columns holding instants, like `?when`.
+    pub instant_columns: BTreeSet<String>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> CsvOptions {
+        CsvOptions::new()
+    }
+}
+
+impl CsvOptions {
+    /// Comma-separated fields, with a header row, and values written as they're held.
+    pub fn new() -> CsvOptions {
+        CsvOptions {
+            delimiter: ',',
+            header: true,
+            keyword_colons: true,
+            instant_format: InstantFormat::Millis,
+            instant_columns: BTreeSet::new(),
+        }
+    }
+
+    /// Tab-separated fields.
+    pub fn tsv() -> CsvOptions {
+        CsvOptions::new().delimiter('\t')
+    }
+
+    pub fn delimiter(mut self, delimiter: char) -> CsvOptions {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn header(mut self, header: bool) -> CsvOptions {
+        self.header = header;
+        self
+    }
+
+    pub fn keyword_colons(mut self, keyword_colons: bool) -> CsvOptions {
+        self.keyword_colons = keyword_colons;
+        self
+    }
+
+    /// Write the given columns' values as instants, in `format`.
+    pub fn instants(mut self, format: InstantFormat, columns: &[&str]) -> CsvOptions {
+        self.instant_format = format;
+        self.instant_columns.extend(columns.iter().map(|column| column.to_string()));
+        self
+    }
+}
+
+impl QueryOutput {
+    /// Write the results as CSV, with a header row and values written as they're held.
+    pub fn to_csv<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.to_csv_with(writer, &CsvOptions::new())
+    }
+
+    /// Write the results as delimited text, as `options` direct.
+    pub fn to_csv_with<W: Write>(&self, writer: &mut W, options: &CsvOptions) -> Result<()> {
+        if options.header {
+            let names: Vec<String> = self.columns.iter().map(|name| quote(name, options.delimiter)).collect();
+            write_row(writer, &names, options.delimiter)?;
+        }
+
+        let instants: Vec<bool> = self.columns.iter().map(|name| options.instant_columns.contains(name)).collect();
+        let mut write = |row: &[TypedValue]| -> Result<()> {
+            let fields: Vec<String> = row.iter().enumerate().map(|(i, value)| {
+                quote(&field(value, instants.get(i).cloned().unwrap_or(false), options), options.delimiter)
+            }).collect();
+            write_row(writer, &fields, options.delimiter)
+        };
+        match self.results {
+            QueryResults::Scalar(ref x) => {
+                for x in x.iter() {
+                    write(&[x.clone()])?;
+                }
+            },
+            QueryResults::Tuple(ref x) => {
+                for x in x.iter() {
+                    write(x)?;
+                }
+            },
+            QueryResults::Coll(ref xs) => {
+                for x in xs.iter() {
+                    write(&[x.clone()])?;
+                }
+            },
+            QueryResults::Rel(ref rows) => {
+                for row in rows.iter() {
+                    write(row)?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+fn write_row<W: Write>(writer: &mut W, fields: &[String], delimiter: char) -> Result<()> {
+    writer.write_all(fields.join(&delimiter.to_string()).as_bytes())?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// `text`, quoted if it holds the delimiter, a double quote, or a line break.
+fn quote(text: &str, delimiter: char) -> String {
+    if text.contains(|c: char| c == delimiter || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// The text of a field holding `value`, which is an instant if `instant` is set.
+fn field(value: &TypedValue, instant: bool, options: &CsvOptions) -> String {
+    match value {
+        &TypedValue::Long(x) if instant && options.instant_format == InstantFormat::Rfc3339 => to_rfc3339(x),
+        &TypedValue::Ref(x) => x.to_string(),
+        &TypedValue::Boolean(x) => x.to_string(),
+        &TypedValue::Long(x) => x.to_string(),
+        &TypedValue::Double(x) => x.into_inner().to_string(),
+        &TypedValue::String(ref x) => x.clone(),
+        &TypedValue::Keyword(ref x) if options.keyword_colons => x.to_string(),
+        &TypedValue::Keyword(ref x) => format!("{}/{}", x.namespace, x.name),
+        &TypedValue::Bytes(ref x) => to_base64(x),
+        &TypedValue::Json(ref x) => x.clone(),
+        &TypedValue::Tuple(_) => value.to_edn_value_pair().0.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use store::Store;
+
+    fn csv(output: &QueryOutput, options: &CsvOptions) -> String {
+        let mut out = vec![];
+        output.to_csv_with(&mut out, options).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]
+                           [:db/add "c" :db/ident :test/color]
+                           [:db/add "c" :db/valueType :db.type/keyword]
+                           [:db/add "w" :db/ident :test/when]
+                           [:db/add "w" :db/valueType :db.type/long]]"#).unwrap();
+        store.transact(r#"[[:db/add "a" :test/name "Smith, \"Al\""]
+                           [:db/add "a" :test/color :color/red]
+                           [:db/add "a" :test/when 1483228800000]]"#).unwrap();
+
+        let output = store.q_once(r#"[:find ?name (?c :as :test/color) ?when :where [?e :test/name ?name] [?e :test/color ?c] [?e :test/when ?when]]"#).unwrap();
+        assert_eq!(output.columns, vec!["?name".to_string(), ":test/color".to_string(), "?when".to_string()]);
+
+        let mut out = vec![];
+        output.to_csv(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "?name,:test/color,?when\n\"Smith, \"\"Al\"\"\",:color/red,1483228800000\n");
+
+        let options = CsvOptions::tsv().keyword_colons(false).instants(InstantFormat::Rfc3339, &["?when"]);
+        assert_eq!(csv(&output, &options),
+                   "?name\t:test/color\t?when\n\"Smith, \"\"Al\"\"\"\tcolor/red\t2017-01-01T00:00:00.000Z\n");
+
+        // Double quotes are doubled.
+        assert_eq!(quote("say \"hi\"", ','), "\"say \"\"hi\"\"\"");
+
+        // A missing scalar writes only the header.
+        let output = store.q_once(r#"[:find ?e . :where [?e :test/name "Bob"]]"#).unwrap();
+        assert_eq!(csv(&output, &CsvOptions::new()), "?e\n");
+        assert_eq!(csv(&output, &CsvOptions::new().header(false)), "");
+    }
+}
@@ -0,0 +1,340 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Derived attributes: attributes whose values are computed from a query over the rest of the
+//! store, like a `:person/friend-count` counting each person's `:person/friend`s.
+//!
+//! Derived values are ordinary datoms, so they can be queried, pulled, and cached like any other
+//! attribute.  After each transaction, every derived attribute whose query reads an attribute the
+//! transaction changed is brought up to date, and only the difference is written, in a transaction
+//! of its own within the same SQL transaction.
+//!
+//! Maintenance is incremental when the query is local to `?e`: when each of its patterns matches
+//! datoms of `?e`, like `[?e :person/friend ?f]`, only the entities whose datoms the transaction
+//! changed can gain or lose values, so the query is only run for them.  Other queries, which can
+//! follow refs to other entities, are recomputed in full.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use rusqlite;
+
+use edn;
+use edn::symbols::NamespacedKeyword;
+use mentat_db;
+use mentat_db::{DB, Entid, Schema, TypedValue, ValueType};
+use mentat_query::{FindQuery, FindSpec, PatternNonValuePlace, Variable, WhereClause};
+use mentat_query_translator::{QueryDependencies, QueryInputs, RelationInputs};
+
+use errors::*;
+use query::{QueryResults, run_find_query_with_relations};
+use tx::parse_transaction_value;
+
+/// How the rows `[?e ?x]` of a derived attribute's query become values of the attribute for `?e`.
+#[derive(Clone,Copy,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum Derivation {
+    /// The number of distinct `?x`, as a long.  Entities with no rows have no value, rather than
+    /// zero.  The attribute must be a cardinality-one long.
+    Count,
+
+    /// Each distinct `?x`.  The attribute must be cardinality many.
+    Values,
+}
+
+/// An attribute maintained from a query.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct DerivedAttribute {
+    pub attribute: Entid,
+    pub query: FindQuery,
+    pub derivation: Derivation,
+
+    /// The attributes the query reads, as named by the schema it was last resolved against.
+    pub dependencies: BTreeSet<Entid>,
+
+    /// The entity variable, `?e`, if the query is local to it: if its only clauses are patterns
+    /// matching datoms of `?e`, and predicates.
+    local: Option<Variable>,
+}
+
+impl DerivedAttribute {
+    /// Check that `query` and `derivation` can maintain `attribute`, and return the derived
+    /// attribute.
+    pub fn new(schema: &Schema, attribute: Entid, query: FindQuery, derivation: Derivation) -> Result<DerivedAttribute> {
+        let a = schema.require_attribute_for_entid(&attribute)?;
+        match (derivation, &a.value_type, a.multival) {
+            (Derivation::Count, &ValueType::Long, false) => (),
+            (Derivation::Values, _, true) => (),
+            _ => bail!(ErrorKind::InvalidDerivedAttribute(format!("{:?} can't maintain attribute {} with {:?} values", derivation, attribute, a))),
+        }
+        let e = match query.find_spec {
+            FindSpec::FindRel(ref elements) if elements.len() == 2 => elements[0].variable().clone(),
+            ref spec => bail!(ErrorKind::InvalidDerivedAttribute(format!("expected [:find ?e ?x ...], got {:?}", spec))),
+        };
+        if !query.in_vars.is_empty() || !query.in_rels.is_empty() || query.as_of.is_some() || query.since.is_some() || query.history {
+            bail!(ErrorKind::InvalidDerivedAttribute("queries must read the current store, without inputs".to_string()));
+        }
+        let local = query.where_clauses.iter().all(|clause| match clause {
+            &WhereClause::Pattern(ref pattern) => pattern.entity == PatternNonValuePlace::Variable(e.clone()),
+            &WhereClause::Pred(_) => true,
+            _ => false,
+        });
+
+        let derived = DerivedAttribute {
+            attribute: attribute,
+            query: query,
+            derivation: derivation,
+            dependencies: BTreeSet::new(),
+            local: if local { Some(e) } else { None },
+        };
+        derived.resolve(schema)
+    }
+
+    /// Return this derived attribute with its query's dependencies resolved against `schema`, which
+    /// can name different attributes than the schema it was registered against.  Fails if the query
+    /// can no longer maintain the attribute.
+    pub fn resolve(&self, schema: &Schema) -> Result<DerivedAttribute> {
+        let attribute = self.attribute;
+        let dependencies = self.query.dependencies(schema);
+        if dependencies.any_attribute {
+            bail!(ErrorKind::InvalidDerivedAttribute("every pattern must name its attribute".to_string()));
+        }
+        let dependencies = dependencies.attributes;
+        // Deriving from encrypted values would write them back in plaintext.
+        let encrypted = |a: &Entid| schema.attribute_for_entid(a).map_or(false, |a| a.encrypted);
+        if encrypted(&attribute) || dependencies.iter().any(|a| encrypted(a)) {
+            bail!(ErrorKind::InvalidDerivedAttribute("derived attributes can't read or write encrypted attributes".to_string()));
+        }
+        if dependencies.contains(&attribute) {
+            bail!(ErrorKind::InvalidDerivedAttribute(format!("attribute {} can't be derived from itself", attribute)));
+        }
+
+        Ok(DerivedAttribute {
+            dependencies: dependencies,
+            ..self.clone()
+        })
+    }
+
+    /// `true` if a transaction that changed the given attributes can change this attribute.
+    pub fn depends_on(&self, changed: &BTreeSet<Entid>) -> bool {
+        !self.dependencies.is_disjoint(changed)
+    }
+
+    /// Return the entities whose values of this attribute a transaction with the given `changes`
+    /// can change, or `None` if it can change any entity's.
+    fn affected(&self, changes: &Changes) -> Option<BTreeSet<Entid>> {
+        self.local.as_ref().map(|_| {
+            self.dependencies.iter()
+                .filter_map(|a| changes.get(a))
+                .flat_map(|entities| entities.iter().cloned())
+                .collect()
+        })
+    }
+
+    /// Return the values this attribute should have, by entity: for the given entities, or for
+    /// every entity if `entities` is `None`.
+    fn derive(&self, conn: &rusqlite::Connection, schema: &Schema, entities: Option<&BTreeSet<Entid>>) -> Result<BTreeMap<Entid, BTreeSet<TypedValue>>> {
+        let mut query = self.query.clone();
+        let mut relations = RelationInputs::new();
+        if let (Some(entities), Some(e)) = (entities, self.local.as_ref()) {
+            query.in_rels = vec![vec![e.clone()]];
+            relations.insert(vec![e.clone()], entities.iter().map(|&e| vec![TypedValue::Ref(e)]).collect());
+        }
+        let rows = match run_find_query_with_relations(conn, schema, &query, QueryInputs::new(), relations, None)?.results {
+            QueryResults::Rel(rows) => rows,
+            results => bail!(ErrorKind::InvalidDerivedAttribute(format!("expected a relation, got {:?}", results))),
+        };
+
+        let mut found: BTreeMap<Entid, BTreeSet<TypedValue>> = BTreeMap::new();
+        for row in rows {
+            let e = match row[0] {
+                TypedValue::Ref(e) => e,
+                ref x => bail!(ErrorKind::InvalidDerivedAttribute(format!("expected an entity, got {:?}", x))),
+            };
+            found.entry(e).or_insert(BTreeSet::new()).insert(row[1].clone());
+        }
+
+        Ok(match self.derivation {
+            Derivation::Count => {
+                found.into_iter()
+                    .map(|(e, xs)| (e, vec![TypedValue::Long(xs.len() as i64)].into_iter().collect()))
+                    .collect()
+            },
+            Derivation::Values => found,
+        })
+    }
+}
+
+/// Add the value in `row`, a `(e, v, value_type_tag)`, to `values`.
+fn add_value(values: &mut BTreeMap<Entid, BTreeSet<TypedValue>>, row: &rusqlite::Row) -> Result<()> {
+    let e: Entid = row.get_checked(0)?;
+    let v: rusqlite::types::Value = row.get_checked(1)?;
+    let value_type_tag: i32 = row.get_checked(2)?;
+    values.entry(e).or_insert(BTreeSet::new()).insert(TypedValue::from_sql_value_pair(v, &value_type_tag)?);
+    Ok(())
+}
+
+/// Return the current values of `attribute`, by entity: of the given entities, or of every entity
+/// if `entities` is `None`.
+fn current_values(conn: &rusqlite::Connection, attribute: Entid, entities: Option<&BTreeSet<Entid>>) -> Result<BTreeMap<Entid, BTreeSet<TypedValue>>> {
+    let mut values: BTreeMap<Entid, BTreeSet<TypedValue>> = BTreeMap::new();
+    match entities {
+        None => {
+            let mut stmt: rusqlite::Statement = conn.prepare("SELECT e, v, value_type_tag FROM all_datoms WHERE a = ?")?;
+            let mut rows = stmt.query(&[&attribute])?;
+            while let Some(row) = rows.next() {
+                add_value(&mut values, &row?)?;
+            }
+        },
+        Some(entities) => {
+            let mut stmt: rusqlite::Statement = conn.prepare("SELECT e, v, value_type_tag FROM all_datoms WHERE a = ? AND e = ?")?;
+            for e in entities {
+                let mut rows = stmt.query(&[&attribute, e])?;
+                while let Some(row) = rows.next() {
+                    add_value(&mut values, &row?)?;
+                }
+            }
+        },
+    }
+    Ok(values)
+}
+
+fn term(op: &str, e: Entid, a: Entid, v: &TypedValue) -> edn::Value {
+    edn::Value::Vector(vec![edn::Value::NamespacedKeyword(NamespacedKeyword::new("db", op)),
+                            edn::Value::Integer(e),
+                            edn::Value::Integer(a),
+                            v.to_edn_value_pair().0])
+}
+
+/// Return the terms that take `attribute` from its `current` values to its `derived` values.
+fn diff(attribute: Entid,
+        current: &BTreeMap<Entid, BTreeSet<TypedValue>>,
+        derived: &BTreeMap<Entid, BTreeSet<TypedValue>>) -> Vec<edn::Value> {
+    let empty = BTreeSet::new();
+    let entities: BTreeSet<&Entid> = current.keys().chain(derived.keys()).collect();
+
+    let mut terms = vec![];
+    for e in entities {
+        let before = current.get(e).unwrap_or(&empty);
+        let after = derived.get(e).unwrap_or(&empty);
+        for v in before.difference(after) {
+            terms.push(term("retract", *e, attribute, v));
+        }
+        for v in after.difference(before) {
+            terms.push(term("add", *e, attribute, v));
+        }
+    }
+    terms
+}
+
+/// Return the attributes changed by transaction `tx_id`.
+pub fn changed_attributes(conn: &rusqlite::Connection, tx_id: Entid) -> Result<BTreeSet<Entid>> {
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT DISTINCT a FROM transactions WHERE tx = ?")?;
+    let attributes: Result<BTreeSet<Entid>> = stmt.query_and_then(&[&tx_id], |row| Ok(row.get_checked(0)?))?.collect();
+    attributes
+}
+
+/// The entities whose datoms a transaction changed, keyed by attribute.
+pub type Changes = BTreeMap<Entid, BTreeSet<Entid>>;
+
+/// Return the entities whose datoms transaction `tx_id` changed, keyed by attribute.
+pub fn changes(conn: &rusqlite::Connection, tx_id: Entid) -> Result<Changes> {
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT DISTINCT a, e FROM transactions WHERE tx = ?")?;
+    let mut rows = stmt.query(&[&tx_id])?;
+
+    let mut changes = Changes::new();
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let a: Entid = row.get_checked(0)?;
+        let e: Entid = row.get_checked(1)?;
+        changes.entry(a).or_insert(BTreeSet::new()).insert(e);
+    }
+    Ok(changes)
+}
+
+/// Bring the given derived attributes up to date in `conn` after a transaction with the given
+/// `changes`, or recompute all of them if `changes` is `None`.  Returns the `DB` after writing any
+/// changes.
+pub fn update(conn: &rusqlite::Connection, db: DB, derived: &BTreeMap<Entid, DerivedAttribute>, changes: Option<&Changes>) -> Result<DB> {
+    let mut terms = vec![];
+    for (&attribute, derived) in derived.iter() {
+        let entities = match changes {
+            Some(changes) => {
+                let changed: BTreeSet<Entid> = changes.keys().cloned().collect();
+                if !derived.depends_on(&changed) {
+                    continue;
+                }
+                derived.affected(changes)
+            },
+            None => None,
+        };
+        let after = derived.derive(conn, &db.schema, entities.as_ref())?;
+        let before = current_values(conn, attribute, entities.as_ref())?;
+        terms.extend(diff(attribute, &before, &after));
+    }
+
+    if terms.is_empty() {
+        return Ok(db);
+    }
+    let entities = parse_transaction_value(edn::Value::Vector(terms))?;
+    let (_, db) = mentat_db::transact(conn, &db, &entities[..])?;
+    Ok(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mentat_db::{Attribute, IdentMap, SchemaMap};
+    use query::parse_query;
+
+    #[test]
+    fn test_affected() {
+        let mut ident_map = IdentMap::new();
+        let mut schema_map = SchemaMap::new();
+        for &(ident, entid, value_type, multival) in [(":test/friend", 100, ValueType::Ref, true),
+                                                      (":test/name", 101, ValueType::String, false),
+                                                      (":test/count", 102, ValueType::Long, false)].iter() {
+            ident_map.insert(ident.to_string(), entid);
+            schema_map.insert(entid, Attribute { value_type: value_type.clone(), multival: multival, ..Attribute::default() });
+        }
+        let schema = Schema::from(ident_map, schema_map).unwrap();
+        let derived = |query: &str| DerivedAttribute::new(&schema, 102, parse_query(query).unwrap(), Derivation::Count).unwrap();
+
+        let mut changes = Changes::new();
+        changes.insert(100, vec![1, 2].into_iter().collect());
+        changes.insert(101, vec![3].into_iter().collect());
+
+        // Only the entities whose friends changed can have a different count.
+        let local = derived("[:find ?e ?f :where [?e :test/friend ?f]]");
+        assert_eq!(local.affected(&changes), Some(vec![1, 2].into_iter().collect()));
+
+        // Renaming a friend can change the count of whoever has that friend.
+        let following = derived("[:find ?e ?n :where [?e :test/friend ?f] [?f :test/name ?n]]");
+        assert_eq!(following.affected(&changes), None);
+    }
+
+    #[test]
+    fn test_diff() {
+        let s = |x: &str| TypedValue::String(x.to_string());
+        let mut current = BTreeMap::new();
+        current.insert(1, vec![s("a"), s("b")].into_iter().collect());
+        current.insert(2, vec![s("c")].into_iter().collect());
+        let mut derived = BTreeMap::new();
+        derived.insert(1, vec![s("b"), s("d")].into_iter().collect());
+        derived.insert(3, vec![s("e")].into_iter().collect());
+
+        assert_eq!(diff(99, &current, &derived),
+                   vec![term("retract", 1, 99, &s("a")),
+                        term("add", 1, 99, &s("d")),
+                        term("retract", 2, 99, &s("c")),
+                        term("add", 3, 99, &s("e"))]);
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Exporting and importing a store's transactions, for `mentat export` and `mentat import`.
+//!
+//! `mentat export --db todo.mentat out.edn [--since TX]` writes the transaction log, and
+//! `mentat import --db copy.mentat out.edn [--dry-run]` replays it, in one write transaction.  A
+//! dry run checks that the import would succeed, and then rolls it back.  Progress is reported on
+//! standard error, and the exit codes are those of scripts; see `script`.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+
+use mentat::{Entid, ExportFormat, Result, Store};
+
+use script::{EXIT_FAILED, EXIT_IO, EXIT_OK};
+
+/// The export format named `name`, as in `--format`.
+pub fn export_format(name: &str) -> Option<ExportFormat> {
+    match name {
+        "edn" => Some(ExportFormat::Edn),
+        "ndedn" => Some(ExportFormat::NdEdn),
+        "ndedn-datoms" => Some(ExportFormat::NdEdnDatoms),
+        _ => None,
+    }
+}
+
+fn open(db: &str) -> Option<Store> {
+    match Store::open(db) {
+        Ok(store) => Some(store),
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "could not open {}: {}", db, e);
+            None
+        },
+    }
+}
+
+/// Export the transactions of the store at `db` after `since_tx` to the file at `path`, returning
+/// the exit code.
+pub fn export(db: &str, path: &str, since_tx: Option<Entid>, format: ExportFormat) -> i32 {
+    let store = match open(db) {
+        Some(store) => store,
+        None => return EXIT_IO,
+    };
+    let written: Result<usize> = File::create(path).map_err(|e| e.into()).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        let written = store.export_datoms(&mut writer, since_tx, format)?;
+        writer.flush()?;
+        Ok(written)
+    });
+    match written {
+        Ok(written) => {
+            let _ = writeln!(io::stderr(), "exported {} transactions to {}", written, path);
+            EXIT_OK
+        },
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "could not export to {}: {}", path, e);
+            EXIT_IO
+        },
+    }
+}
+
+/// Import the transactions in the file at `path` into the store at `db`, or only check that they
+/// would import if `dry_run` is set, returning the exit code.
+pub fn import(db: &str, path: &str, format: ExportFormat, dry_run: bool) -> i32 {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "could not read {}: {}", path, e);
+            return EXIT_IO;
+        },
+    };
+    let mut store = match open(db) {
+        Some(store) => store,
+        None => return EXIT_IO,
+    };
+    let _ = writeln!(io::stderr(), "{} {}...", if dry_run { "checking" } else { "importing" }, path);
+    let reader = BufReader::new(file);
+    let imported = if dry_run {
+        store.validate_import(reader, format)
+    } else {
+        store.import_datoms(reader, format)
+    };
+    match imported {
+        Ok(reports) => {
+            let _ = writeln!(io::stderr(), "{} {} transactions",
+                             if dry_run { "would import" } else { "imported" }, reports.len());
+            EXIT_OK
+        },
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "could not import {}: {}", path, e);
+            EXIT_FAILED
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_export_and_import() {
+        let source = env::temp_dir().join(format!("mentat-test-dump-source-{}.db", process::id()));
+        let copy = env::temp_dir().join(format!("mentat-test-dump-copy-{}.db", process::id()));
+        let dump = env::temp_dir().join(format!("mentat-test-dump-{}.edn", process::id()));
+        let (source, copy, dump) = (source.to_str().unwrap(), copy.to_str().unwrap(), dump.to_str().unwrap());
+
+        Store::open(source).unwrap().transact(r#"[[:db/add "a" :db/doc "exported"]]"#).unwrap();
+        assert_eq!(export(source, dump, None, ExportFormat::NdEdn), EXIT_OK);
+
+        let count = |path: &str| Store::open(path).unwrap().count(r#"[:find ?e :where [?e :db/doc "exported"]]"#).unwrap();
+        assert_eq!(import(copy, dump, ExportFormat::NdEdn, true), EXIT_OK);
+        assert_eq!(count(copy), 0);
+        assert_eq!(import(copy, dump, ExportFormat::NdEdn, false), EXIT_OK);
+        assert_eq!(count(copy), 1);
+
+        assert_eq!(import(copy, "/nonexistent/dump.edn", ExportFormat::NdEdn, false), EXIT_IO);
+        assert_eq!(import(copy, dump, ExportFormat::Edn, false), EXIT_FAILED);
+
+        for path in &[source, copy, dump] {
+            let _ = fs::remove_file(path);
+        }
+        assert_eq!(export_format("ndedn-datoms"), Some(ExportFormat::NdEdnDatoms));
+        assert_eq!(export_format("csv"), None);
+    }
+}
@@ -0,0 +1,436 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Encoders turning query results, transaction reports, and observed transactions into bytes, for
+//! consumers on the other side of an FFI boundary.
+//!
+//! Three formats are supported:
+//!
+//! - JSON, where refs are written `{"ref": 65536}`, keywords `{"keyword": ":db/ident"}`, and bytes
+//!   `{"bytes": "aGk="}` in base64, since JSON can't otherwise distinguish them from longs and
+//!   strings;
+//! - EDN text, where refs are written as integers and bytes as `#bytes "aGk="`;
+//! - CBOR (RFC 7049), where refs and keywords are tagged with the identifier tag 39, and bytes are
+//!   byte strings.
+//!
+//! Missing scalar and tuple results are encoded as null (`nil` in EDN).
+//!
+//! Entids and longs are written as exact 64-bit integers in every format.  JSON consumers that
+//! parse numbers as doubles will lose precision beyond 2^53, and should prefer CBOR.
+
+use std::f64;
+
+use edn::types::to_base64;
+use mentat_db::{TxReport, TypedValue};
+
+use observe::TxChange;
+use query::QueryResults;
+
+/// The formats in which results can be encoded.
+#[derive(Clone,Copy,Debug,Eq,Hash,PartialEq)]
+pub enum Format {
+    Json,
+    Edn,
+    Cbor,
+}
+
+/// Something that can be encoded in any of the supported `Format`s.
+pub trait Encodable {
+    fn encode(&self, format: Format) -> Vec<u8>;
+}
+
+/// A format-independent tree of encodable values.
+enum Node {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    Keyword(String),
+    Ref(i64),
+    Bytes(Vec<u8>),
+    Array(Vec<Node>),
+    /// Map entries, in order.  Keys are `Keyword` or `Text` nodes.
+    Map(Vec<(Node, Node)>),
+}
+
+impl<'a> From<&'a TypedValue> for Node {
+    fn from(value: &'a TypedValue) -> Node {
+        match value {
+            &TypedValue::Ref(x) => Node::Ref(x),
+            &TypedValue::Boolean(x) => Node::Boolean(x),
+            &TypedValue::Long(x) => Node::Integer(x),
+            &TypedValue::Double(x) => Node::Float(x.into_inner()),
+            &TypedValue::String(ref x) => Node::Text(x.clone()),
+            &TypedValue::Keyword(ref x) => Node::Keyword(x.to_string()),
+            &TypedValue::Bytes(ref x) => Node::Bytes(x.clone()),
+            // JSON documents are passed along as text, for the consumer to parse.
+            &TypedValue::Json(ref x) => Node::Text(x.clone()),
+            &TypedValue::Tuple(ref x) => Node::Array(x.iter().map(Node::from).collect()),
+        }
+    }
+}
+
+fn row_node(row: &[TypedValue]) -> Node {
+    Node::Array(row.iter().map(Node::from).collect())
+}
+
+impl<'a> From<&'a QueryResults> for Node {
+    fn from(results: &'a QueryResults) -> Node {
+        match results {
+            &QueryResults::Scalar(ref value) => value.as_ref().map_or(Node::Null, Node::from),
+            &QueryResults::Tuple(ref row) => row.as_ref().map_or(Node::Null, |row| row_node(row)),
+            &QueryResults::Coll(ref values) => row_node(values),
+            &QueryResults::Rel(ref rows) => Node::Array(rows.iter().map(|row| row_node(row)).collect()),
+        }
+    }
+}
+
+impl<'a> From<&'a TxReport> for Node {
+    fn from(report: &'a TxReport) -> Node {
+        let tempids = report.tempids.iter()
+            .map(|(tempid, &entid)| (Node::Text(tempid.clone()), Node::Ref(entid)))
+            .collect();
+        Node::Map(vec![
+            (Node::Keyword(":tx".to_string()), Node::Ref(report.tx_id)),
+            (Node::Keyword(":txInstant".to_string()), Node::Integer(report.tx_instant)),
+            (Node::Keyword(":tempids".to_string()), Node::Map(tempids)),
+        ])
+    }
+}
+
+impl<'a> From<&'a TxChange> for Node {
+    fn from(change: &'a TxChange) -> Node {
+        let attributes = change.attributes.iter().map(|&a| Node::Ref(a)).collect();
+        Node::Map(vec![
+            (Node::Keyword(":tx".to_string()), Node::Ref(change.tx_id)),
+            (Node::Keyword(":txInstant".to_string()), Node::Integer(change.tx_instant)),
+            (Node::Keyword(":attributes".to_string()), Node::Array(attributes)),
+        ])
+    }
+}
+
+fn encode_node(node: &Node, format: Format) -> Vec<u8> {
+    let mut out = vec![];
+    match format {
+        Format::Json => {
+            let mut s = String::new();
+            write_json(&mut s, node);
+            out.extend_from_slice(s.as_bytes());
+        },
+        Format::Edn => {
+            let mut s = String::new();
+            write_edn(&mut s, node);
+            out.extend_from_slice(s.as_bytes());
+        },
+        Format::Cbor => write_cbor(&mut out, node),
+    }
+    out
+}
+
+impl Encodable for QueryResults {
+    fn encode(&self, format: Format) -> Vec<u8> {
+        encode_node(&Node::from(self), format)
+    }
+}
+
+impl Encodable for TxReport {
+    fn encode(&self, format: Format) -> Vec<u8> {
+        encode_node(&Node::from(self), format)
+    }
+}
+
+impl Encodable for TxChange {
+    fn encode(&self, format: Format) -> Vec<u8> {
+        encode_node(&Node::from(self), format)
+    }
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_json(out: &mut String, node: &Node) {
+    match node {
+        &Node::Null => out.push_str("null"),
+        &Node::Boolean(x) => out.push_str(if x { "true" } else { "false" }),
+        &Node::Integer(x) => out.push_str(&x.to_string()),
+        // JSON has no representation for NaN or the infinities.
+        &Node::Float(x) if !x.is_finite() => out.push_str("null"),
+        &Node::Float(x) => out.push_str(&format!("{:?}", x)),
+        &Node::Text(ref x) => write_json_string(out, x),
+        &Node::Keyword(ref x) => {
+            out.push_str("{\"keyword\":");
+            write_json_string(out, x);
+            out.push('}');
+        },
+        &Node::Ref(x) => out.push_str(&format!("{{\"ref\":{}}}", x)),
+        &Node::Bytes(ref x) => {
+            out.push_str("{\"bytes\":");
+            write_json_string(out, &to_base64(x));
+            out.push('}');
+        },
+        &Node::Array(ref xs) => {
+            out.push('[');
+            for (i, x) in xs.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(out, x);
+            }
+            out.push(']');
+        },
+        &Node::Map(ref entries) => {
+            out.push('{');
+            for (i, &(ref k, ref v)) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                // JSON keys are strings: keywords lose their leading colon.
+                match k {
+                    &Node::Keyword(ref k) => write_json_string(out, k.trim_left_matches(':')),
+                    &Node::Text(ref k) => write_json_string(out, k),
+                    _ => unreachable!(),
+                }
+                out.push(':');
+                write_json(out, v);
+            }
+            out.push('}');
+        },
+    }
+}
+
+fn write_edn_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_edn(out: &mut String, node: &Node) {
+    match node {
+        &Node::Null => out.push_str("nil"),
+        &Node::Boolean(x) => out.push_str(if x { "true" } else { "false" }),
+        &Node::Integer(x) | &Node::Ref(x) => out.push_str(&x.to_string()),
+        &Node::Float(x) if x.is_nan() => out.push_str("##NaN"),
+        &Node::Float(x) if x == f64::INFINITY => out.push_str("##Inf"),
+        &Node::Float(x) if x == f64::NEG_INFINITY => out.push_str("##-Inf"),
+        // `{:?}` always includes a decimal point or exponent, so this reads back as a float.
+        &Node::Float(x) => out.push_str(&format!("{:?}", x)),
+        &Node::Text(ref x) => write_edn_string(out, x),
+        &Node::Keyword(ref x) => out.push_str(x),
+        &Node::Bytes(ref x) => {
+            out.push_str("#bytes ");
+            write_edn_string(out, &to_base64(x));
+        },
+        &Node::Array(ref xs) => {
+            out.push('[');
+            for (i, x) in xs.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                write_edn(out, x);
+            }
+            out.push(']');
+        },
+        &Node::Map(ref entries) => {
+            out.push('{');
+            for (i, &(ref k, ref v)) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_edn(out, k);
+                out.push(' ');
+                write_edn(out, v);
+            }
+            out.push('}');
+        },
+    }
+}
+
+/// CBOR major types.
+const CBOR_UNSIGNED: u8 = 0;
+const CBOR_NEGATIVE: u8 = 1;
+const CBOR_BYTES: u8 = 2;
+const CBOR_TEXT: u8 = 3;
+const CBOR_ARRAY: u8 = 4;
+const CBOR_MAP: u8 = 5;
+const CBOR_TAG: u8 = 6;
+
+/// The CBOR tag for identifiers, used for refs and keywords.
+const CBOR_TAG_IDENTIFIER: u64 = 39;
+
+fn write_cbor_header(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    let (additional, width) = match n {
+        0...23 => (n as u8, 0),
+        24...0xff => (24, 1),
+        0x100...0xffff => (25, 2),
+        0x10000...0xffffffff => (26, 4),
+        _ => (27, 8),
+    };
+    out.push(major | additional);
+    for i in (0..width).rev() {
+        out.push((n >> (8 * i)) as u8);
+    }
+}
+
+fn write_cbor_integer(out: &mut Vec<u8>, x: i64) {
+    if x >= 0 {
+        write_cbor_header(out, CBOR_UNSIGNED, x as u64);
+    } else {
+        write_cbor_header(out, CBOR_NEGATIVE, (-1 - x) as u64);
+    }
+}
+
+fn write_cbor_text(out: &mut Vec<u8>, s: &str) {
+    write_cbor_header(out, CBOR_TEXT, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_cbor(out: &mut Vec<u8>, node: &Node) {
+    match node {
+        &Node::Null => out.push(0xf6),
+        &Node::Boolean(x) => out.push(if x { 0xf5 } else { 0xf4 }),
+        &Node::Integer(x) => write_cbor_integer(out, x),
+        &Node::Float(x) => {
+            // Always a double-precision float.
+            out.push(0xfb);
+            let bits = x.to_bits();
+            for i in (0..8).rev() {
+                out.push((bits >> (8 * i)) as u8);
+            }
+        },
+        &Node::Text(ref x) => write_cbor_text(out, x),
+        &Node::Keyword(ref x) => {
+            write_cbor_header(out, CBOR_TAG, CBOR_TAG_IDENTIFIER);
+            write_cbor_text(out, x);
+        },
+        &Node::Ref(x) => {
+            write_cbor_header(out, CBOR_TAG, CBOR_TAG_IDENTIFIER);
+            write_cbor_integer(out, x);
+        },
+        &Node::Bytes(ref x) => {
+            write_cbor_header(out, CBOR_BYTES, x.len() as u64);
+            out.extend_from_slice(x);
+        },
+        &Node::Array(ref xs) => {
+            write_cbor_header(out, CBOR_ARRAY, xs.len() as u64);
+            for x in xs {
+                write_cbor(out, x);
+            }
+        },
+        &Node::Map(ref entries) => {
+            write_cbor_header(out, CBOR_MAP, entries.len() as u64);
+            for &(ref k, ref v) in entries {
+                write_cbor(out, k);
+                write_cbor(out, v);
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use edn::NamespacedKeyword;
+
+    fn encode_str<T: Encodable>(x: &T, format: Format) -> String {
+        String::from_utf8(x.encode(format)).unwrap()
+    }
+
+    #[test]
+    fn test_encode_query_results() {
+        let rel = QueryResults::Rel(vec![
+            vec![TypedValue::Ref(65536), TypedValue::String("a \"b\"".to_string())],
+            vec![TypedValue::Keyword(NamespacedKeyword::new("foo", "bar")), TypedValue::Double(1.0.into())],
+        ]);
+        assert_eq!(encode_str(&rel, Format::Json),
+                   r#"[[{"ref":65536},"a \"b\""],[{"keyword":":foo/bar"},1.0]]"#);
+        assert_eq!(encode_str(&rel, Format::Edn),
+                   r#"[[65536 "a \"b\""] [:foo/bar 1.0]]"#);
+
+        let scalar = QueryResults::Scalar(None);
+        assert_eq!(encode_str(&scalar, Format::Json), "null");
+        assert_eq!(encode_str(&scalar, Format::Edn), "nil");
+
+        let coll = QueryResults::Coll(vec![TypedValue::Boolean(true), TypedValue::Long(-500), TypedValue::Ref(24)]);
+        assert_eq!(coll.encode(Format::Cbor),
+                   vec![0x83,              // array(3)
+                        0xf5,              // true
+                        0x39, 0x01, 0xf3,  // -500
+                        0xd8, 0x27, 0x18, 0x18]); // 39(24)
+
+        let bytes = QueryResults::Scalar(Some(TypedValue::Bytes(b"hi".to_vec())));
+        assert_eq!(encode_str(&bytes, Format::Json), r#"{"bytes":"aGk="}"#);
+        assert_eq!(encode_str(&bytes, Format::Edn), r#"#bytes "aGk=""#);
+        assert_eq!(bytes.encode(Format::Cbor), vec![0x42, b'h', b'i']); // bytes(2)
+
+        // Entids use the full 64 bits.
+        let large = QueryResults::Scalar(Some(TypedValue::Ref(i64::max_value())));
+        assert_eq!(encode_str(&large, Format::Json), r#"{"ref":9223372036854775807}"#);
+        assert_eq!(large.encode(Format::Cbor),
+                   vec![0xd8, 0x27, 0x1b, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]); // 39(2^63 - 1)
+    }
+
+    #[test]
+    fn test_encode_tx_report() {
+        let mut tempids = BTreeMap::new();
+        tempids.insert("a".to_string(), 65536);
+        let report = TxReport {
+            tx_id: 0x10000001,
+            tx_instant: 1500000000000,
+            tempids: tempids,
+            noop: false,
+            created_idents: BTreeMap::new(),
+        };
+
+        assert_eq!(encode_str(&report, Format::Json),
+                   r#"{"tx":{"ref":268435457},"txInstant":1500000000000,"tempids":{"a":{"ref":65536}}}"#);
+        assert_eq!(encode_str(&report, Format::Edn),
+                   r#"{:tx 268435457, :txInstant 1500000000000, :tempids {"a" 65536}}"#);
+        assert_eq!(report.encode(Format::Cbor)[0], 0xa3); // map(3)
+
+        let change = TxChange {
+            tx_id: 0x10000001,
+            tx_instant: 1500000000000,
+            attributes: vec![40, 65].into_iter().collect(),
+        };
+        assert_eq!(encode_str(&change, Format::Json),
+                   r#"{"tx":{"ref":268435457},"txInstant":1500000000000,"attributes":[{"ref":40},{"ref":65}]}"#);
+        assert_eq!(encode_str(&change, Format::Edn),
+                   r#"{:tx 268435457, :txInstant 1500000000000, :attributes [40 65]}"#);
+    }
+}
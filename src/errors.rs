@@ -0,0 +1,175 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+use rusqlite;
+
+use mentat_db;
+use mentat_query_translator;
+
+error_chain! {
+    types {
+        Error, ErrorKind, ResultExt, Result;
+    }
+
+    foreign_links {
+        Io(::std::io::Error);
+        Rusqlite(rusqlite::Error);
+    }
+
+    links {
+        DbError(mentat_db::Error, mentat_db::ErrorKind);
+        TranslatorError(mentat_query_translator::Error, mentat_query_translator::ErrorKind);
+    }
+
+    errors {
+        /// The input couldn't be parsed as EDN.
+        EdnParseError(t: String) {
+            description("could not parse EDN")
+            display("could not parse EDN: {}", t)
+        }
+
+        /// The input was EDN, but not a valid query.
+        QueryParseError(t: String) {
+            description("could not parse query")
+            display("could not parse query: {}", t)
+        }
+
+        /// No query has been registered under the given name.
+        UnknownNamedQuery(name: String) {
+            description("no query registered with name")
+            display("no query registered with name: '{}'", name)
+        }
+
+        /// A query names an attribute that isn't installed, and the store's `UnknownAttributePolicy`
+        /// is `Strict`.
+        UnknownAttribute(ident: String) {
+            description("unknown attribute")
+            display("unknown attribute: {}", ident)
+        }
+
+        /// The input was EDN, but not a valid transaction.
+        TxParseError(t: String) {
+            description("could not parse transaction")
+            display("could not parse transaction: {}", t)
+        }
+
+        /// A derived attribute can't be maintained from the given query.
+        InvalidDerivedAttribute(t: String) {
+            description("invalid derived attribute")
+            display("invalid derived attribute: {}", t)
+        }
+
+        /// An enumeration can't be declared with the given attribute and values.
+        InvalidEnum(t: String) {
+            description("invalid enumeration")
+            display("invalid enumeration: {}", t)
+        }
+
+        /// An ordered relationship can't be used or edited as asked.
+        InvalidOrdering(t: String) {
+            description("invalid ordering")
+            display("invalid ordering: {}", t)
+        }
+
+        /// A transaction wrote a derived attribute, which only its query can do.
+        DerivedAttributeWrite(attribute: mentat_db::Entid) {
+            description("transaction writes derived attribute")
+            display("transaction writes derived attribute {}", attribute)
+        }
+
+        /// A transaction was conditional on a basis transaction, but others have committed since.
+        StaleBasis(expected: mentat_db::Entid, actual: mentat_db::Entid) {
+            description("basis transaction is stale")
+            display("expected basis transaction {}, but the store is at {}", expected, actual)
+        }
+
+        /// An export read by `Store::import_datoms` isn't in the expected format.
+        InvalidExport(t: String) {
+            description("invalid export")
+            display("invalid export: {}", t)
+        }
+
+        /// No store is open under the given name in a `Stores`.
+        UnknownStore(name: String) {
+            description("no store open with name")
+            display("no store open with name: '{}'", name)
+        }
+
+        /// A store is already open under the given name in a `Stores`.
+        StoreAlreadyOpen(name: String) {
+            description("a store is already open with name")
+            display("a store is already open with name: '{}'", name)
+        }
+
+        /// The entities to copy between stores can't be found with the given query.
+        InvalidCopy(t: String) {
+            description("invalid copy")
+            display("invalid copy: {}", t)
+        }
+
+        /// Imported datoms use attributes that aren't installed, and whose definitions can't be
+        /// found.
+        UnknownAttributes(t: String) {
+            description("unknown attributes")
+            display("unknown attributes, with no definitions to install: {}", t)
+        }
+
+        /// Attributes are defined with different value types or cardinalities in two stores.
+        AttributeMismatch(t: String) {
+            description("attributes defined differently")
+            display("attributes defined differently in each store: {}", t)
+        }
+
+        /// Replaying a transaction committed to another store allocated a different transaction
+        /// ID, so this store has transactions of its own.
+        ReplicationDiverged(expected: mentat_db::Entid, actual: mentat_db::Entid) {
+            description("store has diverged from the store it replicates")
+            display("expected to replay transaction {}, but replayed it as {}", expected, actual)
+        }
+
+        /// A scoped store was asked to read or write an attribute outside its namespaces.
+        OutOfScope(t: String) {
+            description("outside the store's allowed namespaces")
+            display("outside the store's allowed namespaces: {}", t)
+        }
+
+        /// An interceptor denied a transaction, which was rolled back.
+        PolicyViolation(violation: ::intercept::PolicyViolation) {
+            description("transaction denied by policy")
+            display("transaction denied by policy '{}': {}", violation.policy, violation.reason)
+        }
+
+        /// A retention rule names no attributes that can be retracted.
+        InvalidRetentionRule(t: String) {
+            description("invalid retention rule")
+            display("invalid retention rule: {}", t)
+        }
+
+        /// The SQLite library predates `VACUUM INTO`, which snapshots need.
+        SnapshotUnsupported(version: String) {
+            description("snapshots need SQLite 3.27.0 or later")
+            display("snapshots need SQLite 3.27.0 or later, but this is SQLite {}", version)
+        }
+
+        /// A `TestStore` fixture isn't a map of `:schema` and `:seed` transactions.
+        InvalidFixture(t: String) {
+            description("invalid test fixture")
+            display("invalid test fixture: {}", t)
+        }
+
+        /// A `RowIds` mapping already holds `u32::max_value()` entids.
+        RowIdsExhausted {
+            description("no more row ids")
+            display("no more row ids")
+        }
+    }
+}
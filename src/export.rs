@@ -0,0 +1,563 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Exporting the transaction log as EDN text, and importing it again, so that stores can be
+//! diffed, inspected with text tools, and moved between Mentat versions.
+//!
+//! A transaction is written as a map, like
+//!
+//! ```edn
+//! {:tx 268435458 :txInstant 1500000000000 :datoms [[65536 :test/name "Alice" true]]}
+//! ```
+//!
+//! where each datom is `[e a v added]`.  A datom on its own line is written `[e a v tx added]`, and
+//! includes the transaction's `:db/txInstant`.
+//!
+//! Entities and ref values are written as their idents where they have them, and as entids
+//! otherwise.  Importing replays the transactions in order, with an entid or ident the importing
+//! store didn't already know standing for a new entity, so the importing store allocates entids
+//! of its own.
+//!
+//! The values of encrypted attributes are decrypted for export, so an export holds them in
+//! plaintext.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Read, Write};
+
+use rusqlite;
+
+use edn;
+use edn::{Keyword, NamespacedKeyword, Value};
+use mentat_db::{Cipher, Entid, Schema, TX_TEMPID, TxReport, TypedValue, ValueType, decrypt_sql_value_pair, read_log_value, to_namespaced_keyword};
+
+use errors::*;
+use store::InProgress;
+
+/// The formats the transaction log can be exported in.
+#[derive(Clone,Copy,Debug,Eq,Hash,PartialEq)]
+pub enum ExportFormat {
+    /// One EDN vector of transactions.
+    Edn,
+    /// One transaction per line.
+    NdEdn,
+    /// One datom per line.
+    NdEdnDatoms,
+}
+
+/// A datom in the log, with its entity, attribute, and value as they are written.
+#[derive(Clone,Debug,Eq,PartialEq)]
+struct LoggedDatom {
+    e: Value,
+    a: Value,
+    v: Value,
+    added: bool,
+}
+
+/// A transaction in the log, including its `:db/txInstant` datom.
+#[derive(Clone,Debug,Eq,PartialEq)]
+struct LoggedTx {
+    tx: Entid,
+    datoms: Vec<LoggedDatom>,
+}
+
+fn tx_instant_keyword() -> Value {
+    Value::NamespacedKeyword(NamespacedKeyword::new("db", "txInstant"))
+}
+
+fn entity_value(schema: &Schema, e: Entid) -> Value {
+    schema.get_ident(&e)
+        .and_then(|ident| to_namespaced_keyword(ident))
+        .map_or(Value::Integer(e), Value::NamespacedKeyword)
+}
+
+/// Read the transactions after `since_tx` from the log, or every transaction but the bootstrap
+/// transaction if `None`, up to and including `until_tx` if given, decrypting encrypted values
+/// with `cipher`.  Entities are written as their idents where they have them if `idents` is true,
+/// and as entids otherwise.
+fn read_log(conn: &rusqlite::Connection, schema: &Schema, since_tx: Option<Entid>, until_tx: Option<Entid>, idents: bool, cipher: Option<&Cipher>) -> Result<Vec<LoggedTx>> {
+    let since_tx = match since_tx {
+        Some(tx) => tx,
+        None => {
+            let first: Option<Entid> = conn.query_row("SELECT MIN(tx) FROM transactions", &[], |row| row.get(0))?;
+            first.unwrap_or(0)
+        },
+    };
+
+    let until_tx = until_tx.unwrap_or(Entid::max_value());
+
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT e, a, v, value_type_tag, tx, added FROM transactions WHERE tx > ? AND tx <= ? ORDER BY tx, rowid")?;
+    let entity = |e: Entid| if idents { entity_value(schema, e) } else { Value::Integer(e) };
+    let rows: Result<Vec<(Entid, LoggedDatom)>> = stmt.query_and_then(&[&since_tx, &until_tx], |row| {
+        let e: Entid = row.get_checked(0)?;
+        let a: Entid = row.get_checked(1)?;
+        let v: rusqlite::types::Value = row.get_checked(2)?;
+        let value_type_tag: i32 = row.get_checked(3)?;
+        let tx: Entid = row.get_checked(4)?;
+        let added: bool = row.get_checked(5)?;
+
+        let typed_value = read_log_value(conn, schema, a, v, &value_type_tag, cipher)?;
+        let v = match typed_value {
+            TypedValue::Ref(x) => entity(x),
+            typed_value => typed_value.to_edn_value_pair().0,
+        };
+
+        Ok((tx, LoggedDatom {
+            e: entity(e),
+            a: entity(a),
+            v: v,
+            added: added,
+        }))
+    })?.collect();
+
+    let mut transactions: Vec<LoggedTx> = vec![];
+    for (tx, datom) in rows? {
+        if transactions.last().map_or(true, |last| last.tx != tx) {
+            transactions.push(LoggedTx {
+                tx: tx,
+                datoms: vec![],
+            });
+        }
+        transactions.last_mut().expect("a transaction").datoms.push(datom);
+    }
+    Ok(transactions)
+}
+
+fn write_transaction<W: Write>(writer: &mut W, logged: &LoggedTx) -> Result<()> {
+    let mut instant = None;
+    let mut datoms = vec![];
+    for datom in logged.datoms.iter() {
+        if datom.added && datom.e == Value::Integer(logged.tx) && datom.a == tx_instant_keyword() {
+            instant = Some(&datom.v);
+        } else {
+            datoms.push(Value::Vector(vec![datom.e.clone(), datom.a.clone(), datom.v.clone(), Value::Boolean(datom.added)]));
+        }
+    }
+    write!(writer, "{{:tx {}", logged.tx)?;
+    if let Some(instant) = instant {
+        write!(writer, " :txInstant {}", instant)?;
+    }
+    write!(writer, " :datoms {}}}", Value::Vector(datoms))?;
+    Ok(())
+}
+
+fn write_datoms<W: Write>(writer: &mut W, logged: &LoggedTx) -> Result<()> {
+    for datom in logged.datoms.iter() {
+        writeln!(writer, "{}", Value::Vector(vec![datom.e.clone(), datom.a.clone(), datom.v.clone(), Value::Integer(logged.tx), Value::Boolean(datom.added)]))?;
+    }
+    Ok(())
+}
+
+/// Write the transactions after `since_tx`, or every transaction but the bootstrap transaction if
+/// `None`, to `writer` in the given `format`.  Returns the number of transactions written.
+/// Encrypted values are decrypted with `cipher`; without one, exporting them fails.
+pub fn export_datoms<W: Write>(conn: &rusqlite::Connection, schema: &Schema, writer: &mut W, since_tx: Option<Entid>, format: ExportFormat, cipher: Option<&Cipher>) -> Result<usize> {
+    let transactions = read_log(conn, schema, since_tx, None, true, cipher)?;
+    match format {
+        ExportFormat::Edn => {
+            write!(writer, "[")?;
+            for (i, logged) in transactions.iter().enumerate() {
+                if i > 0 {
+                    write!(writer, "\n ")?;
+                }
+                write_transaction(writer, logged)?;
+            }
+            writeln!(writer, "]")?;
+        },
+        ExportFormat::NdEdn => {
+            for logged in transactions.iter() {
+                write_transaction(writer, logged)?;
+                write!(writer, "\n")?;
+            }
+        },
+        ExportFormat::NdEdnDatoms => {
+            for logged in transactions.iter() {
+                write_datoms(writer, logged)?;
+            }
+        },
+    }
+    writer.flush()?;
+    Ok(transactions.len())
+}
+
+fn parse_value(text: &str) -> Result<Value> {
+    Ok(edn::parse::value(text).map_err(|e| ErrorKind::EdnParseError(format!("{:?}", e)))?)
+}
+
+fn parse_added(value: &Value) -> Result<bool> {
+    match value {
+        &Value::Boolean(added) => Ok(added),
+        _ => bail!(ErrorKind::InvalidExport(format!("expected true or false, got {}", value))),
+    }
+}
+
+fn parse_transaction(value: Value) -> Result<LoggedTx> {
+    let mut map = match value {
+        Value::Map(map) => map,
+        value => bail!(ErrorKind::InvalidExport(format!("expected a transaction, got {}", value))),
+    };
+    let tx = match map.remove(&Value::Keyword(Keyword::new("tx"))) {
+        Some(Value::Integer(tx)) => tx,
+        _ => bail!(ErrorKind::InvalidExport("transaction without :tx".to_string())),
+    };
+    let mut datoms = vec![];
+    match map.remove(&Value::Keyword(Keyword::new("datoms"))) {
+        Some(Value::Vector(values)) => {
+            for value in values {
+                match value {
+                    Value::Vector(ref parts) if parts.len() == 4 => {
+                        datoms.push(LoggedDatom {
+                            e: parts[0].clone(),
+                            a: parts[1].clone(),
+                            v: parts[2].clone(),
+                            added: parse_added(&parts[3])?,
+                        });
+                    },
+                    value => bail!(ErrorKind::InvalidExport(format!("expected [e a v added], got {}", value))),
+                }
+            }
+        },
+        None => (),
+        Some(value) => bail!(ErrorKind::InvalidExport(format!("expected a vector of datoms, got {}", value))),
+    }
+    if let Some(instant) = map.remove(&Value::Keyword(Keyword::new("txInstant"))) {
+        datoms.push(LoggedDatom {
+            e: Value::Integer(tx),
+            a: tx_instant_keyword(),
+            v: instant,
+            added: true,
+        });
+    }
+    Ok(LoggedTx {
+        tx: tx,
+        datoms: datoms,
+    })
+}
+
+fn parse_datom(value: Value) -> Result<(Entid, LoggedDatom)> {
+    match value {
+        Value::Vector(ref parts) if parts.len() == 5 => {
+            let tx = match parts[3] {
+                Value::Integer(tx) => tx,
+                ref value => bail!(ErrorKind::InvalidExport(format!("expected a transaction ID, got {}", value))),
+            };
+            Ok((tx, LoggedDatom {
+                e: parts[0].clone(),
+                a: parts[1].clone(),
+                v: parts[2].clone(),
+                added: parse_added(&parts[4])?,
+            }))
+        },
+        value => bail!(ErrorKind::InvalidExport(format!("expected [e a v tx added], got {}", value))),
+    }
+}
+
+/// Read the transactions written by `export_datoms` in the given `format`.
+fn read_export<R: BufRead>(mut reader: R, format: ExportFormat) -> Result<Vec<LoggedTx>> {
+    let mut transactions: Vec<LoggedTx> = vec![];
+    match format {
+        ExportFormat::Edn => {
+            let mut text = String::new();
+            reader.read_to_string(&mut text)?;
+            match parse_value(&text)? {
+                Value::Vector(values) => {
+                    for value in values {
+                        transactions.push(parse_transaction(value)?);
+                    }
+                },
+                value => bail!(ErrorKind::InvalidExport(format!("expected a vector of transactions, got {}", value))),
+            }
+        },
+        ExportFormat::NdEdn => {
+            for line in reader.lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    transactions.push(parse_transaction(parse_value(&line)?)?);
+                }
+            }
+        },
+        ExportFormat::NdEdnDatoms => {
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let (tx, datom) = parse_datom(parse_value(&line)?)?;
+                if transactions.last().map_or(true, |last| last.tx != tx) {
+                    transactions.push(LoggedTx {
+                        tx: tx,
+                        datoms: vec![],
+                    });
+                }
+                transactions.last_mut().expect("a transaction").datoms.push(datom);
+            }
+        },
+    }
+    Ok(transactions)
+}
+
+/// Replays exported transactions, keeping track of the entids the importing store allocates for
+/// the exported entities.
+struct Importer {
+    /// The schema before the import began: its idents name the same entities in the export.
+    existing: Schema,
+
+    /// The entids allocated so far, keyed by the exported entid or ident, as written.
+    entids: BTreeMap<String, Entid>,
+
+    /// Whether exported entids name the same entities in the importing store, rather than new ones.
+    mirror: bool,
+}
+
+impl Importer {
+    fn new(schema: &Schema) -> Importer {
+        Importer {
+            existing: schema.clone(),
+            entids: BTreeMap::new(),
+            mirror: false,
+        }
+    }
+
+    fn mirroring(schema: &Schema) -> Importer {
+        Importer {
+            existing: schema.clone(),
+            entids: BTreeMap::new(),
+            mirror: true,
+        }
+    }
+
+    /// The importing store's name for the exported entity `value` in transaction `tx`: an entid
+    /// or ident it knows, or a tempid for a new entity.
+    fn resolve(&self, tx: Entid, value: &Value) -> Value {
+        let key = match value {
+            &Value::Integer(e) if e == tx => return Value::Text(TX_TEMPID.to_string()),
+            &Value::Integer(_) if self.mirror => return value.clone(),
+            &Value::Integer(e) => e.to_string(),
+            &Value::NamespacedKeyword(ref ident) => ident.to_string(),
+            _ => return value.clone(),
+        };
+        match self.entids.get(&key) {
+            Some(&e) => Value::Integer(e),
+            None if self.existing.get_entid(&key).is_some() => value.clone(),
+            None => Value::Text(key),
+        }
+    }
+
+    /// The EDN text of the transaction replaying `logged` against `schema`.
+    ///
+    /// The values of composite tuple attributes are left out: the importing store derives them.
+    fn transaction(&self, schema: &Schema, logged: &LoggedTx) -> String {
+        let terms = logged.datoms.iter().filter_map(|datom| {
+            let a = self.resolve(logged.tx, &datom.a);
+            let a_entid = match a {
+                Value::Integer(a) => Some(a),
+                Value::NamespacedKeyword(ref ident) => schema.get_entid(&ident.to_string()).cloned(),
+                _ => None,
+            };
+            let attribute = a_entid.and_then(|a| schema.attribute_for_entid(&a));
+            if attribute.map_or(false, |attribute| !attribute.tuple_attrs.is_empty()) {
+                return None;
+            }
+            let is_ref = attribute.map_or(false, |attribute| attribute.value_type == ValueType::Ref);
+            let v = if is_ref { self.resolve(logged.tx, &datom.v) } else { datom.v.clone() };
+            let op = if datom.added { "add" } else { "retract" };
+            Some(Value::Vector(vec![Value::NamespacedKeyword(NamespacedKeyword::new("db", op)), self.resolve(logged.tx, &datom.e), a, v]))
+        }).collect();
+        Value::Vector(terms).to_string()
+    }
+
+    /// Record the entids allocated by replaying `logged`.
+    fn note(&mut self, logged: &LoggedTx, report: &TxReport) {
+        for (tempid, &entid) in report.tempids.iter() {
+            self.entids.insert(tempid.clone(), entid);
+        }
+        self.entids.insert(logged.tx.to_string(), report.tx_id);
+    }
+}
+
+/// The idents of the attributes of the datoms in `transactions` that neither `schema` nor
+/// `transactions` themselves define.
+fn missing_attributes(schema: &Schema, transactions: &[LoggedTx]) -> BTreeSet<String> {
+    let db_ident = Value::NamespacedKeyword(NamespacedKeyword::new("db", "ident"));
+    let db_value_type = Value::NamespacedKeyword(NamespacedKeyword::new("db", "valueType"));
+
+    let mut used = BTreeSet::new();
+    let mut defined = BTreeSet::new();
+    for datom in transactions.iter().flat_map(|logged| logged.datoms.iter()) {
+        if let Value::NamespacedKeyword(ref a) = datom.a {
+            used.insert(a.to_string());
+        }
+        match (&datom.e, &datom.v) {
+            (&Value::NamespacedKeyword(ref e), _) if datom.added && datom.a == db_value_type => {
+                defined.insert(e.to_string());
+            },
+            (_, &Value::NamespacedKeyword(ref v)) if datom.added && datom.a == db_ident => {
+                defined.insert(v.to_string());
+            },
+            _ => (),
+        }
+    }
+    used.into_iter()
+        .filter(|a| !defined.contains(a) && schema.get_entid(a).is_none())
+        .collect()
+}
+
+/// Fail if any of the attributes used in `transactions` are defined differently, by value type or
+/// cardinality, in `local` and `source`.
+fn check_attributes(local: &Schema, source: &Schema, transactions: &[LoggedTx]) -> Result<()> {
+    let mut mismatched = BTreeSet::new();
+    for datom in transactions.iter().flat_map(|logged| logged.datoms.iter()) {
+        let ident = match datom.a {
+            Value::NamespacedKeyword(ref a) => a.to_string(),
+            _ => continue,
+        };
+        if let (Some((l, _)), Some((s, _))) = (local.attribute_for_ident_str(&ident), source.attribute_for_ident_str(&ident)) {
+            if l.value_type != s.value_type || l.multival != s.multival {
+                mismatched.insert(ident);
+            }
+        }
+    }
+    if !mismatched.is_empty() {
+        bail!(ErrorKind::AttributeMismatch(mismatched.into_iter().collect::<Vec<_>>().join(", ")));
+    }
+    Ok(())
+}
+
+/// The terms installing the definitions of the attributes named by `missing`, as the store read
+/// through `conn` with `schema` defines them, along with those of any attributes they refer to,
+/// like the components of composite tuples, that `local` lacks.
+pub fn attribute_definitions(conn: &rusqlite::Connection, schema: &Schema, local: &Schema, missing: &BTreeSet<String>) -> Result<Vec<Value>> {
+    let mut pending: Vec<String> = missing.iter().cloned().collect();
+    let mut installing: BTreeSet<String> = missing.clone();
+    let mut unobtainable = BTreeSet::new();
+    let mut terms = vec![];
+
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT a, v, value_type_tag FROM all_datoms WHERE e = ?")?;
+    while let Some(ident) = pending.pop() {
+        let e = match schema.get_entid(&ident) {
+            Some(&e) if schema.attribute_for_entid(&e).is_some() => e,
+            _ => {
+                unobtainable.insert(ident);
+                continue;
+            },
+        };
+        let rows: Result<Vec<(Entid, TypedValue)>> = stmt.query_and_then(&[&e], |row| {
+            let a: Entid = row.get_checked(0)?;
+            let v: rusqlite::types::Value = row.get_checked(1)?;
+            let value_type_tag: i32 = row.get_checked(2)?;
+            Ok((a, decrypt_sql_value_pair(v, &value_type_tag, None)?))
+        })?.collect();
+        for (a, v) in rows? {
+            let a_ident = schema.require_ident(&a)?;
+            let is_ref = schema.attribute_for_entid(&a).map_or(false, |attribute| attribute.value_type == ValueType::Ref);
+            let v = match v {
+                TypedValue::Ref(x) if is_ref => {
+                    let x_ident = schema.require_ident(&x)?.clone();
+                    if local.get_entid(&x_ident).is_some() {
+                        entity_value(schema, x)
+                    } else {
+                        // An attribute this one depends on, installed in the same transaction.
+                        if installing.insert(x_ident.clone()) {
+                            pending.push(x_ident.clone());
+                        }
+                        Value::Text(x_ident)
+                    }
+                },
+                v => v.to_edn_value_pair().0,
+            };
+            let a = to_namespaced_keyword(a_ident).map_or(Value::Integer(a), Value::NamespacedKeyword);
+            terms.push(Value::Vector(vec![Value::NamespacedKeyword(NamespacedKeyword::new("db", "add")), Value::Text(ident.clone()), a, v]));
+        }
+    }
+    if !unobtainable.is_empty() {
+        bail!(ErrorKind::UnknownAttributes(unobtainable.into_iter().collect::<Vec<_>>().join(", ")));
+    }
+    Ok(terms)
+}
+
+/// Replay the transactions written by `export_datoms` in the given `format`, in order, within
+/// `in_progress`.  Returns the reports of the replayed transactions.
+///
+/// If the transactions use attributes that `in_progress` doesn't have, and doesn't get from the
+/// transactions themselves, their definitions are first installed from `source`, a connection to
+/// another store -- usually the exporting one -- and its schema.  Without a `source`, or if it
+/// lacks them too, nothing is replayed, and this fails with `UnknownAttributes`.  With a `source`,
+/// attributes it defines with another value type or cardinality fail with `AttributeMismatch`.
+pub fn import_datoms<R: BufRead>(in_progress: &mut InProgress, reader: R, format: ExportFormat, source: Option<(&rusqlite::Connection, &Schema)>) -> Result<Vec<TxReport>> {
+    let transactions = read_export(reader, format)?;
+
+    let missing = missing_attributes(in_progress.schema(), &transactions);
+    match source {
+        Some((conn, schema)) => {
+            check_attributes(in_progress.schema(), schema, &transactions)?;
+            if !missing.is_empty() {
+                let mut definitions = attribute_definitions(conn, schema, in_progress.schema(), &missing)?;
+                // Imported transactions keep their instants, which can't precede this one's.
+                let first_instant = transactions.first().and_then(|logged| {
+                    logged.datoms.iter().find(|datom| datom.e == Value::Integer(logged.tx) && datom.a == tx_instant_keyword())
+                });
+                if let Some(datom) = first_instant {
+                    definitions.push(Value::Vector(vec![Value::NamespacedKeyword(NamespacedKeyword::new("db", "add")),
+                                                        Value::Text(TX_TEMPID.to_string()),
+                                                        tx_instant_keyword(),
+                                                        datom.v.clone()]));
+                }
+                in_progress.transact(&Value::Vector(definitions).to_string())?;
+            }
+        },
+        None if !missing.is_empty() => {
+            bail!(ErrorKind::UnknownAttributes(missing.into_iter().collect::<Vec<_>>().join(", ")));
+        },
+        None => (),
+    }
+
+    let mut importer = Importer::new(in_progress.schema());
+    let mut reports = Vec::with_capacity(transactions.len());
+    for logged in transactions.iter() {
+        let transaction = importer.transaction(in_progress.schema(), logged);
+        let report = in_progress.transact(&transaction)?;
+        importer.note(logged, &report);
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+/// The IDs of the transactions in the log read through `conn` after `since_tx`, in order.
+pub fn logged_transactions(conn: &rusqlite::Connection, since_tx: Entid) -> Result<Vec<Entid>> {
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT DISTINCT tx FROM transactions WHERE tx > ? ORDER BY tx")?;
+    let txs: Result<Vec<Entid>> = stmt.query_and_then(&[&since_tx], |row| Ok(row.get_checked(0)?))?.collect();
+    txs
+}
+
+/// Replay the transactions committed to the store read through `conn` since `in_progress` began,
+/// up to and including `until_tx` if given, in order, within `in_progress`, keeping their entids.
+/// Returns the reports of the replayed transactions, and the length of their EDN text.
+///
+/// Replaying a transaction must allocate the same transaction ID as the original did, which it
+/// does for as long as the replaying store commits nothing else; otherwise the stores have
+/// diverged, and replaying fails.
+pub fn mirror_datoms(in_progress: &mut InProgress, conn: &rusqlite::Connection, schema: &Schema, until_tx: Option<Entid>, cipher: Option<&Cipher>) -> Result<(Vec<TxReport>, usize)> {
+    let since_tx = in_progress.basis_tx();
+    let transactions = read_log(conn, schema, Some(since_tx), until_tx, false, cipher)?;
+    let importer = Importer::mirroring(in_progress.schema());
+    let mut reports = Vec::with_capacity(transactions.len());
+    let mut bytes = 0;
+    for logged in transactions.iter() {
+        let transaction = importer.transaction(in_progress.schema(), logged);
+        bytes += transaction.len();
+        let report = in_progress.transact(&transaction)?;
+        if report.tx_id != logged.tx {
+            bail!(ErrorKind::ReplicationDiverged(logged.tx, report.tx_id));
+        }
+        reports.push(report);
+    }
+    Ok((reports, bytes))
+}
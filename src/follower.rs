@@ -0,0 +1,274 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Read-only replicas of a store.
+//!
+//! A `Follower` is a store that tails the transaction log of another store, its leader, and
+//! replays the leader's transactions as its own.  Replayed transactions keep their entids,
+//! transaction IDs, and `:db/txInstant`s, so the follower answers queries exactly as the leader
+//! did at the same basis transaction, and entids read from one can be used with the other.
+//!
+//! Followers spread reads over several stores -- for instance, one per process -- and mirror a
+//! store across devices that can share its file.  The leader is read through a read-only
+//! connection of its own, so it can go on transacting meanwhile.
+//!
+//! A follower can't be written to except by replaying: a transaction of its own would take a
+//! transaction ID the leader will use, and the follower would no longer be a replica.
+//!
+//! A follower's latest transaction records how far it has replayed, so catching up resumes where
+//! it left off, even after a crash.  `catch_up_with_progress` commits in chunks, and reports its
+//! progress as it goes, for followers on devices that may be interrupted mid-way through a long
+//! catch-up.
+
+use mentat_db::{Entid, Schema, TxReport};
+
+use errors::*;
+use observe::TxObserver;
+use query::{QueryOutput, basis_tx};
+use store::Store;
+
+/// What a catch-up is doing.
+#[derive(Clone,Copy,Debug,Eq,Hash,PartialEq)]
+pub enum SyncPhase {
+    /// The leader's log has been read, and nothing replayed yet.
+    Reading,
+
+    /// A chunk of transactions has been replayed and committed.
+    Replaying,
+
+    /// Every transaction has been replayed.
+    Done,
+}
+
+/// How far a catch-up has got.
+#[derive(Clone,Copy,Debug,Eq,Hash,PartialEq)]
+pub struct SyncProgress {
+    pub phase: SyncPhase,
+
+    /// The transactions replayed and committed so far.
+    pub transactions: usize,
+
+    /// The transactions to replay in all.
+    pub total_transactions: usize,
+
+    /// The size of the transactions replayed so far, as EDN text.
+    pub bytes: usize,
+}
+
+/// A store replicating the store at `leader`.  Call `catch_up` to replay the transactions the
+/// leader has committed since, for instance when it notifies an observer, or on a timer.
+pub struct Follower {
+    store: Store,
+    leader: String,
+}
+
+impl Follower {
+    /// Open the store at `path`, creating and bootstrapping it if necessary, as a follower of the
+    /// existing store at `leader`.  The store must be empty, or have only been written by
+    /// following the same leader.  An empty `path` opens an in-memory follower.
+    ///
+    /// Nothing is replayed until `catch_up` is called.
+    pub fn open(path: &str, leader: &str) -> Result<Follower> {
+        Ok(Follower {
+            store: Store::open(path)?,
+            leader: leader.to_string(),
+        })
+    }
+
+    /// The path of the store being followed.
+    pub fn leader(&self) -> &str {
+        &self.leader
+    }
+
+    /// The store, for querying.  It can't be transacted against.
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    pub fn schema(&self) -> &Schema {
+        self.store.schema()
+    }
+
+    /// The latest of the leader's transactions that has been replayed.
+    pub fn basis_tx(&self) -> Result<Entid> {
+        basis_tx(self.store.connection())
+    }
+
+    pub fn q_once(&self, query: &str) -> Result<QueryOutput> {
+        self.store.q_once(query)
+    }
+
+    /// Replay the transactions the leader has committed since the last call, in one write
+    /// transaction.  Returns their reports, which are empty if the follower was up to date.
+    pub fn catch_up(&mut self) -> Result<Vec<TxReport>> {
+        self.store.replicate_from(&self.leader)
+    }
+
+    /// Like `catch_up`, but commit every `chunk_size` transactions, and report progress to
+    /// `progress` as each chunk is committed.  If interrupted, only the chunk being replayed is
+    /// lost.  See `Store::replicate_from_with_progress`.
+    pub fn catch_up_with_progress<F>(&mut self, chunk_size: usize, progress: F) -> Result<Vec<TxReport>> where F: FnMut(&SyncProgress) {
+        self.store.replicate_from_with_progress(&self.leader, chunk_size, progress)
+    }
+
+    /// Observe the transactions replayed by `catch_up`.  See `Store::register_observer`.
+    pub fn register_observer(&mut self, key: &str, observer: TxObserver) -> Result<()> {
+        self.store.register_observer(key, observer)
+    }
+
+    pub fn unregister_observer(&mut self, key: &str) {
+        self.store.unregister_observer(key)
+    }
+
+    /// Stop following, and return the store, which can then be written to.  It will no longer be a
+    /// replica of the leader: this is for promoting a follower after the leader is lost.
+    pub fn into_store(self) -> Store {
+        self.store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::panic;
+    use std::process;
+
+    use mentat_db::TypedValue;
+
+    use query::QueryResults;
+
+    #[test]
+    fn test_follower() {
+        let path = env::temp_dir().join(format!("mentat-test-leader-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut leader = Store::open(path).unwrap();
+        leader.transact(r#"[[:db/add "n" :db/ident :test/name]
+                            [:db/add "n" :db/valueType :db.type/string]
+                            [:db/add "n" :db/unique :db.unique/identity]
+                            [:db/add "f" :db/ident :test/friend]
+                            [:db/add "f" :db/valueType :db.type/ref]]"#).unwrap();
+        let report = leader.transact(r#"[[:db/add "a" :test/name "Alice"]
+                                         [:db/add "b" :test/name "Bob"]
+                                         [:db/add "a" :test/friend "b"]]"#).unwrap();
+        let alice = report.tempids["a"];
+        let bob = report.tempids["b"];
+
+        let mut follower = Follower::open("", path).unwrap();
+        assert_eq!(follower.catch_up().unwrap().len(), 2);
+        assert_eq!(follower.basis_tx().unwrap(), report.tx_id);
+        assert_eq!(follower.q_once(r#"[:find ?f . :where [?a :test/name "Alice"] [?a :test/friend ?f]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(bob))));
+
+        // Up to date: nothing to replay.
+        assert!(follower.catch_up().unwrap().is_empty());
+
+        // Retractions and transaction instants are replayed too.
+        let retraction = leader.transact(&format!("[[:db/retract {} :test/name \"Alice\"]]", alice)).unwrap();
+        let reports = follower.catch_up().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].tx_id, retraction.tx_id);
+        assert_eq!(reports[0].tx_instant, retraction.tx_instant);
+        assert_eq!(follower.q_once(r#"[:find ?a . :where [?a :test/name "Alice"]]"#).unwrap().results,
+                   QueryResults::Scalar(None));
+
+        // A promoted follower allocates entids after the leader's, but once it commits a
+        // transaction of its own, it has diverged.
+        let mut promoted = follower.into_store();
+        let carol = promoted.transact(r#"[[:db/add "c" :test/name "Carol"]]"#).unwrap().tempids["c"];
+        assert!(carol > bob);
+        leader.transact(r#"[[:db/add "d" :test/name "Dave"]]"#).unwrap();
+        match promoted.replicate_from(path) {
+            Err(Error(ErrorKind::ReplicationDiverged(_, _), _)) => (),
+            x => panic!("expected ReplicationDiverged, got {:?}", x),
+        }
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_catch_up_with_progress() {
+        let path = env::temp_dir().join(format!("mentat-test-progress-leader-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut leader = Store::open(path).unwrap();
+        leader.transact(r#"[[:db/add "n" :db/ident :test/name]
+                            [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+        for i in 0..4 {
+            leader.transact(&format!(r#"[[:db/add "e" :test/name "{}"]]"#, i)).unwrap();
+        }
+
+        // Interrupt the catch-up after the first chunk of two.
+        let mut follower = Follower::open("", path).unwrap();
+        let interrupted = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            follower.catch_up_with_progress(2, |progress| {
+                if progress.transactions > 0 {
+                    panic!("interrupted");
+                }
+            })
+        }));
+        assert!(interrupted.is_err());
+
+        // Catching up again resumes after the chunk committed.
+        let mut updates = vec![];
+        let reports = follower.catch_up_with_progress(2, |progress| updates.push(*progress)).unwrap();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(updates.iter().map(|progress| (progress.phase, progress.transactions, progress.total_transactions)).collect::<Vec<_>>(),
+                   vec![(SyncPhase::Reading, 0, 3),
+                        (SyncPhase::Replaying, 2, 3),
+                        (SyncPhase::Replaying, 3, 3),
+                        (SyncPhase::Done, 3, 3)]);
+        assert!(updates[1].bytes > 0 && updates[2].bytes > updates[1].bytes);
+        assert_eq!(follower.basis_tx().unwrap(), reports[2].tx_id);
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_catch_up_in_chunks() {
+        let path = env::temp_dir().join(format!("mentat-test-chunks-leader-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut leader = Store::open(path).unwrap();
+        leader.transact(r#"[[:db/add "n" :db/ident :test/name]
+                            [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+        let mut last = None;
+        for i in 0..5 {
+            last = Some(leader.transact(&format!(r#"[[:db/add "e" :test/name "{}"]]"#, i)).unwrap());
+        }
+        let last = last.unwrap();
+
+        // Every chunk replays its transactions with the leader's transaction IDs.
+        let mut follower = Follower::open("", path).unwrap();
+        let reports = follower.catch_up_with_progress(2, |_| ()).unwrap();
+        assert_eq!(reports.len(), 6);
+        assert_eq!(reports[5].tx_id, last.tx_id);
+        match follower.q_once("[:find [?e ...] :where [?e :test/name _]]").unwrap().results {
+            QueryResults::Coll(entities) => assert_eq!(entities.len(), 5),
+            x => panic!("expected a collection, got {:?}", x),
+        }
+
+        // Once done, the follower allocates after the leader.
+        let mut promoted = follower.into_store();
+        let report = promoted.transact(r#"[[:db/add "f" :test/name "Fred"]]"#).unwrap();
+        assert!(report.tx_id > last.tx_id);
+        assert!(report.tempids["f"] > last.tempids["e"]);
+
+        let _ = fs::remove_file(path);
+    }
+}
@@ -11,8 +11,10 @@
 extern crate edn;
 
 use edn::symbols::Keyword;
+use mentat_db;
 
-pub type EntId = u32;            // TODO: u64? Not all DB values will be representable in a u32.
+/// Entids are `i64` throughout; see `rowid` for a compact `u32` mapping.
+pub type EntId = mentat_db::Entid;
 
 /// The ability to transform entity identifiers (entids) into keyword names (idents).
 pub trait ToIdent {
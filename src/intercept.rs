@@ -0,0 +1,268 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Write policies, checked against each transaction before it commits.
+//!
+//! An interceptor registered with `Store::register_interceptor` is shown every transaction once
+//! its tempids and lookup refs have been resolved and its datoms written, but before it commits:
+//! the terms it asserted and retracted, and the caller that transacted it, if the caller named
+//! itself with `transact_as`.  An interceptor that denies the transaction rolls it back, and the
+//! transaction fails with a `PolicyViolation` naming the interceptor, the caller, and the reason.
+//!
+//! Interceptors run in the order they were registered, on the writer's thread and with the
+//! store's write lock held, so they should be quick.  Like constraints, they belong to the
+//! connection: they aren't stored, and other connections to the same store don't run them.
+
+use std::collections::BTreeSet;
+
+use rusqlite;
+
+use mentat_db::{Cipher, Entid, Schema, TypedValue, read_log_value};
+
+use errors::*;
+
+/// A datom asserted or retracted by a transaction, with its entity and attribute resolved.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct Term {
+    pub e: Entid,
+    pub a: Entid,
+
+    /// The ident of `a`, like `:credentials/password`.
+    pub attribute: String,
+    pub v: TypedValue,
+    pub added: bool,
+}
+
+/// A transaction about to be committed, as shown to interceptors.
+#[derive(Debug)]
+pub struct Intercepted<'a> {
+    /// The name the caller gave with `transact_as`, or `None` for a plain `transact`.
+    pub caller: Option<&'a str>,
+    pub tx_id: Entid,
+
+    /// The schema including this transaction's changes.
+    pub schema: &'a Schema,
+
+    /// Every datom the transaction asserted or retracted, including its `:db/txInstant`, in the
+    /// order they were written.
+    pub terms: &'a [Term],
+}
+
+/// Why an interceptor denied a transaction.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct Denial {
+    /// The term at fault, if there is one.
+    pub term: Option<Term>,
+    pub reason: String,
+}
+
+impl Denial {
+    pub fn new(reason: &str) -> Denial {
+        Denial {
+            term: None,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// A denial of the transaction because of `term`.
+    pub fn of(term: &Term, reason: &str) -> Denial {
+        Denial {
+            term: Some(term.clone()),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// A transaction denied by an interceptor, as reported by the `PolicyViolation` error.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct PolicyViolation {
+    /// The key the denying interceptor was registered under.
+    pub policy: String,
+    pub caller: Option<String>,
+    pub term: Option<Term>,
+    pub reason: String,
+}
+
+/// Inspects a transaction before it commits, returning `Err` to deny it.
+pub type Interceptor = Box<Fn(&Intercepted) -> ::std::result::Result<(), Denial> + Send>;
+
+/// An interceptor denying writes to the attributes in `namespace`, like `"credentials"`, except by
+/// the named `callers`.
+pub fn deny_writes(namespace: &str, callers: &[&str]) -> Interceptor {
+    let prefix = format!(":{}/", namespace);
+    let callers: BTreeSet<String> = callers.iter().map(|caller| caller.to_string()).collect();
+    Box::new(move |tx: &Intercepted| {
+        if tx.caller.map_or(false, |caller| callers.contains(caller)) {
+            return Ok(());
+        }
+        match tx.terms.iter().find(|term| term.attribute.starts_with(&prefix)) {
+            Some(term) => Err(Denial::of(term, &format!("{} can't be written by {}", term.attribute, tx.caller.unwrap_or("an unnamed caller")))),
+            None => Ok(()),
+        }
+    })
+}
+
+/// The interceptors of a store, in the order they were registered.
+#[derive(Default)]
+pub struct Interceptors {
+    chain: Vec<(String, Interceptor)>,
+}
+
+impl Interceptors {
+    pub fn is_empty(&self) -> bool {
+        self.chain.is_empty()
+    }
+
+    /// Add `interceptor` to the end of the chain, or replace the one registered under `key` in place.
+    pub fn register(&mut self, key: &str, interceptor: Interceptor) {
+        match self.chain.iter().position(|&(ref k, _)| k == key) {
+            Some(i) => self.chain[i].1 = interceptor,
+            None => self.chain.push((key.to_string(), interceptor)),
+        }
+    }
+
+    pub fn unregister(&mut self, key: &str) {
+        self.chain.retain(|&(ref k, _)| k != key);
+    }
+
+    /// Show transaction `tx_id`, written but not committed through `conn`, to each interceptor in
+    /// turn.  Fails with `PolicyViolation` at the first that denies it.
+    pub fn check(&self, conn: &rusqlite::Connection, schema: &Schema, tx_id: Entid, caller: Option<&str>, cipher: Option<&Cipher>) -> Result<()> {
+        if self.chain.is_empty() {
+            return Ok(());
+        }
+        let terms = read_terms(conn, schema, tx_id, cipher)?;
+        let intercepted = Intercepted {
+            caller: caller,
+            tx_id: tx_id,
+            schema: schema,
+            terms: &terms,
+        };
+        for &(ref key, ref interceptor) in self.chain.iter() {
+            if let Err(denial) = interceptor(&intercepted) {
+                bail!(ErrorKind::PolicyViolation(PolicyViolation {
+                    policy: key.clone(),
+                    caller: caller.map(|caller| caller.to_string()),
+                    term: denial.term,
+                    reason: denial.reason,
+                }));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The datoms written by transaction `tx_id`, from the log.
+fn read_terms(conn: &rusqlite::Connection, schema: &Schema, tx_id: Entid, cipher: Option<&Cipher>) -> Result<Vec<Term>> {
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT e, a, v, value_type_tag, added FROM transactions WHERE tx = ? ORDER BY rowid")?;
+    let terms: Result<Vec<Term>> = stmt.query_and_then(&[&tx_id], |row| {
+        let a: Entid = row.get_checked(1)?;
+        let v: rusqlite::types::Value = row.get_checked(2)?;
+        let value_type_tag: i32 = row.get_checked(3)?;
+
+        let v = read_log_value(conn, schema, a, v, &value_type_tag, cipher)?;
+        Ok(Term {
+            e: row.get_checked(0)?,
+            a: a,
+            attribute: schema.get_ident(&a).cloned().unwrap_or_else(|| a.to_string()),
+            v: v,
+            added: row.get_checked(4)?,
+        })
+    })?.collect();
+    terms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use store::Store;
+
+    fn store() -> Store {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "p" :db/ident :credentials/password]
+                           [:db/add "p" :db/valueType :db.type/string]
+                           [:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_deny_writes() {
+        let mut store = store();
+        store.register_interceptor("credentials", deny_writes("credentials", &["login"]));
+
+        store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        store.transact_as("login", r#"[[:db/add "a" :credentials/password "hunter2"]]"#).unwrap();
+
+        match store.transact_as("sync", r#"[[:db/add "a" :test/name "Bob"] [:db/add "a" :credentials/password "x"]]"#) {
+            Err(Error(ErrorKind::PolicyViolation(violation), _)) => {
+                assert_eq!(violation.policy, "credentials");
+                assert_eq!(violation.caller, Some("sync".to_string()));
+                let term = violation.term.unwrap();
+                assert_eq!(term.attribute, ":credentials/password");
+                assert_eq!(term.v, TypedValue::String("x".to_string()));
+            },
+            x => panic!("expected PolicyViolation, got {:?}", x),
+        }
+        assert!(store.transact(r#"[[:db/add "a" :credentials/password "x"]]"#).is_err());
+
+        // Nothing denied was written.
+        assert_eq!(store.count(r#"[:find ?n :where [_ :test/name ?n]]"#).unwrap(), 1);
+
+        store.unregister_interceptor("credentials");
+        store.transact(r#"[[:db/add "a" :credentials/password "x"]]"#).unwrap();
+    }
+
+    #[test]
+    fn test_interceptor_chain() {
+        let mut store = store();
+        store.register_interceptor("names", Box::new(|tx: &Intercepted| {
+            match tx.terms.iter().find(|term| term.attribute == ":test/name" && !term.added) {
+                Some(term) => Err(Denial::of(term, "names can't be retracted")),
+                None => Ok(()),
+            }
+        }));
+        store.register_interceptor("size", Box::new(|tx: &Intercepted| {
+            // Three names, and the :db/txInstant.
+            if tx.terms.len() > 4 { Err(Denial::new("too many terms")) } else { Ok(()) }
+        }));
+
+        let alice = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap().tempids["a"];
+        {
+            let mut in_progress = store.begin_transaction().unwrap();
+            match in_progress.transact(&format!("[[:db/retract {} :test/name \"Alice\"]]", alice)) {
+                Err(Error(ErrorKind::PolicyViolation(violation), _)) => {
+                    assert_eq!(violation.policy, "names");
+                    assert_eq!(violation.caller, None);
+                    assert_eq!(violation.term.map(|term| term.e), Some(alice));
+                },
+                x => panic!("expected PolicyViolation, got {:?}", x),
+            }
+            in_progress.transact(r#"[[:db/add "b" :test/name "Bob"]]"#).unwrap();
+            in_progress.commit().unwrap();
+        }
+
+        match store.transact(r#"[[:db/add "c" :test/name "Carol"] [:db/add "d" :test/name "Dave"] [:db/add "e" :test/name "Eve"] [:db/add "f" :test/name "Frank"]]"#) {
+            Err(Error(ErrorKind::PolicyViolation(violation), _)) => {
+                assert_eq!(violation.policy, "size");
+                assert_eq!(violation.term, None);
+            },
+            x => panic!("expected PolicyViolation, got {:?}", x),
+        }
+
+        // Registering under an existing key replaces the interceptor in place.
+        store.register_interceptor("size", Box::new(|_: &Intercepted| Ok(())));
+        store.transact(r#"[[:db/add "c" :test/name "Carol"] [:db/add "d" :test/name "Dave"] [:db/add "e" :test/name "Eve"] [:db/add "f" :test/name "Frank"]]"#).unwrap();
+        assert_eq!(store.count(r#"[:find ?n :where [_ :test/name ?n]]"#).unwrap(), 6);
+    }
+}
@@ -8,19 +8,103 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
+#[macro_use]
+extern crate error_chain;
 #[macro_use]
 extern crate slog;
 #[macro_use]
 extern crate slog_scope;
 
-extern crate edn;
+// Users depend only on this crate: the types of the crates below are re-exported by `types`,
+// `tx`, and `query`, and EDN is re-exported whole.
+pub extern crate edn;
+extern crate mentat_db;
 extern crate mentat_query;
 extern crate mentat_query_parser;
+extern crate mentat_query_translator;
+extern crate mentat_tx;
+extern crate mentat_tx_parser;
 extern crate rusqlite;
 
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate futures_cpupool;
+
 use rusqlite::Connection;
 
+#[cfg(feature = "async")]
+pub mod async_store;
+pub mod cache;
+pub mod csv;
+pub mod derived;
+pub mod encode;
+pub mod errors;
+pub mod export;
+pub mod follower;
 pub mod ident;
+pub mod intercept;
+pub mod lint;
+pub mod merge;
+pub mod observe;
+pub mod ordered;
+pub mod pool;
+pub mod preview;
+pub mod project;
+pub mod query;
+pub mod result_cache;
+pub mod retention;
+pub mod rowid;
+pub mod schema_diff;
+pub mod scoped;
+pub mod shared;
+pub mod store;
+pub mod store_diff;
+pub mod stores;
+pub mod table;
+#[cfg(any(test, feature = "testing"))]
+#[macro_use]
+pub mod testing;
+pub mod tx;
+pub mod types;
+pub mod usage;
+pub mod walk;
+
+#[cfg(feature = "async")]
+pub use async_store::AsyncStore;
+pub use csv::{CsvOptions, InstantFormat};
+pub use derived::Derivation;
+pub use encode::{Encodable, Format};
+pub use errors::{Error, ErrorKind, Result};
+pub use export::ExportFormat;
+pub use follower::{Follower, SyncPhase, SyncProgress};
+pub use intercept::{Denial, Intercepted, Interceptor, PolicyViolation, Term};
+pub use lint::{Lint, lint_query};
+pub use merge::{Conflict, MergePolicies, MergePolicy};
+pub use observe::{Delivery, TxChange, TxObserver};
+pub use ordered::OrderedMany;
+pub use pool::{PooledRead, StorePool};
+pub use preview::{PreviewDatom, TxPreview};
+pub use mentat_db::options::{JournalMode, StoreOptions, Synchronous};
+pub use mentat_db::recovery::RecoveryPolicy;
+pub use mentat_db::to_namespaced_keyword;
+pub use query::{Dependencies, EmptyBecause, IndexHint, IndexHints, PointInTime, QueryDependencies, QueryInputs, QueryOutput, QueryPlan, QueryResults, QueryTimings, RelationInputs, Rewrite, RewriteKind, Variable};
+pub use result_cache::ResultCacheStats;
+pub use retention::RetentionRule;
+pub use rowid::{RowId, RowIds};
+pub use schema_diff::{AttributeChange, SchemaDiff, schema_diff};
+pub use scoped::ScopedStore;
+pub use shared::SharedStore;
+pub use store::{Assertion, Consistency, InProgress, ReadOnlyStore, ReadTransaction, Store, UnknownAttributePolicy};
+pub use store_diff::{DiffDatom, StoreDiff, diff_stores};
+pub use stores::{Stores, copy_entities};
+pub use table::TableOptions;
+#[cfg(any(test, feature = "testing"))]
+pub use testing::TestStore;
+pub use tx::{Constraint, RetractPolicy, TempidHints, TxReport};
+pub use types::{Cipher, DisplayEntid, Entid, TypedValue, ValueType};
+pub use usage::Usage;
+pub use walk::{Direction, Reached};
 
 pub fn get_name() -> String {
     info!("Called into mentat library"; "fn" => "get_name");
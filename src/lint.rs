@@ -0,0 +1,202 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Linting queries: warnings about queries that run, but probably not as their authors meant.
+//!
+//! A lint is never an error.  Each names the `:where` clause it's about by its position, counting
+//! from 0, as `explain` does.
+//!
+//! ```text
+//! clause 1: the pattern binds no variables, and only checks that some datom matches
+//! clauses [0], [2 3] share no variables; the results are the product of each group's
+//! ```
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use mentat_db::{Schema, ValueType};
+use mentat_query::{
+    Element,
+    FindQuery,
+    PatternNonValuePlace,
+    PatternValuePlace,
+    Variable,
+    WhereClause,
+};
+
+use errors::*;
+use query::parse_query;
+
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum Lint {
+    /// A pattern with no variables whose entity is `_`, like `[_ :person/name _]`, in the given
+    /// clause.  It binds nothing and joins with nothing: it only checks that some datom matches.
+    UnboundPattern(usize),
+
+    /// Groups of clauses, by position, that share no variables.  The results are the product of
+    /// each group's results.
+    CartesianProduct(Vec<Vec<usize>>),
+
+    /// A `:find` variable bound to the values of a ref attribute, which are returned as raw entids.
+    RawRef(Variable, String),
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Lint::UnboundPattern(clause) =>
+                write!(f, "clause {}: the pattern binds no variables, and only checks that some datom matches", clause),
+            &Lint::CartesianProduct(ref groups) => {
+                let groups: Vec<String> = groups.iter().map(|group| {
+                    let clauses: Vec<String> = group.iter().map(|clause| clause.to_string()).collect();
+                    format!("[{}]", clauses.join(" "))
+                }).collect();
+                write!(f, "clauses {} share no variables; the results are the product of each group's", groups.join(", "))
+            },
+            &Lint::RawRef(ref var, ref attribute) =>
+                write!(f, "{} holds values of the ref attribute {}, which are returned as entids", (var.0).0, attribute),
+        }
+    }
+}
+
+fn variables(clause: &WhereClause) -> BTreeSet<Variable> {
+    let mut acc = BTreeSet::new();
+    clause.collect_variables(&mut acc);
+    acc
+}
+
+/// Whether `clause` is, or nests in an `or` or `not`, a pattern that binds no variables.
+fn unbound_patterns(clause: &WhereClause) -> bool {
+    match clause {
+        &WhereClause::Pattern(ref pattern) =>
+            pattern.entity == PatternNonValuePlace::Placeholder && variables(clause).is_empty(),
+        &WhereClause::OrJoin(ref or_join) =>
+            or_join.clauses.iter().any(|leg| leg.clauses().into_iter().any(unbound_patterns)),
+        &WhereClause::NotJoin(ref not_join) =>
+            not_join.clauses.iter().any(unbound_patterns),
+        _ => false,
+    }
+}
+
+/// Group the clauses that join, directly or through other clauses.  `not` clauses only filter
+/// their group's results, and clauses without variables join nothing, so neither is grouped.
+fn groups(query: &FindQuery) -> Vec<Vec<usize>> {
+    let mut groups: Vec<(BTreeSet<Variable>, Vec<usize>)> = vec![];
+    for (i, clause) in query.where_clauses.iter().enumerate() {
+        if let &WhereClause::NotJoin(_) = clause {
+            continue;
+        }
+        let mut vars = variables(clause);
+        if vars.is_empty() {
+            continue;
+        }
+        let mut clauses = vec![i];
+        let (joined, rest): (Vec<_>, Vec<_>) = groups.into_iter().partition(|&(ref group_vars, _)| !group_vars.is_disjoint(&vars));
+        for (group_vars, group_clauses) in joined {
+            vars.extend(group_vars);
+            clauses.extend(group_clauses);
+        }
+        clauses.sort();
+        groups = rest;
+        groups.push((vars, clauses));
+    }
+    let mut groups: Vec<Vec<usize>> = groups.into_iter().map(|(_, clauses)| clauses).collect();
+    groups.sort();
+    groups
+}
+
+/// `:find` variables in the value place of a pattern whose attribute is a ref, unless the query
+/// projects refs as idents.
+fn raw_refs(schema: &Schema, query: &FindQuery) -> Vec<Lint> {
+    let mut lints = vec![];
+    if query.idents {
+        return lints;
+    }
+    for element in query.find_spec.elements() {
+        let var = match element {
+            &Element::Count(_) => continue,
+            _ => element.variable(),
+        };
+        for clause in query.where_clauses.iter() {
+            let pattern = match clause {
+                &WhereClause::Pattern(ref pattern) => pattern,
+                _ => continue,
+            };
+            let ident = match (&pattern.attribute, &pattern.value) {
+                (&PatternNonValuePlace::Ident(ref ident), &PatternValuePlace::Variable(ref v)) if v == var => ident,
+                _ => continue,
+            };
+            let is_ref = schema.attribute_for_ident(ident)
+                .map_or(false, |(attribute, _)| attribute.value_type == ValueType::Ref);
+            if is_ref {
+                lints.push(Lint::RawRef(var.clone(), ident.to_string()));
+                break;
+            }
+        }
+    }
+    lints
+}
+
+/// Lint `query` against `schema`, returning its warnings.
+pub fn lint(schema: &Schema, query: &FindQuery) -> Vec<Lint> {
+    let mut lints: Vec<Lint> = query.where_clauses.iter().enumerate()
+        .filter(|&(_, clause)| unbound_patterns(clause))
+        .map(|(i, _)| Lint::UnboundPattern(i))
+        .collect();
+    let groups = groups(query);
+    if groups.len() > 1 {
+        lints.push(Lint::CartesianProduct(groups));
+    }
+    lints.extend(raw_refs(schema, query));
+    lints
+}
+
+/// Parse and lint the EDN text of `query` against `schema`.
+pub fn lint_query(schema: &Schema, query: &str) -> Result<Vec<Lint>> {
+    Ok(lint(schema, &parse_query(query)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use edn::PlainSymbol;
+
+    use store::Store;
+
+    fn var(name: &str) -> Variable {
+        Variable(PlainSymbol(name.to_string()))
+    }
+
+    #[test]
+    fn test_lint() {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :person/name]
+                           [:db/add "n" :db/valueType :db.type/string]
+                           [:db/add "f" :db/ident :person/friend]
+                           [:db/add "f" :db/valueType :db.type/ref]]"#).unwrap();
+        let lint = |query: &str| store.lint(query).unwrap();
+
+        assert_eq!(lint(r#"[:find ?n :where [?e :person/name ?n] [?e :person/friend ?f] [?f :person/name "Bob"]]"#), vec![]);
+        assert_eq!(lint(r#"[:find ?e :where [?e :person/name "Alice"] [_ :person/friend _]]"#), vec![Lint::UnboundPattern(1)]);
+        assert_eq!(lint(r#"[:find ?e ?f :where [?e :person/name "Alice"] [?f :person/name "Bob"]]"#),
+                   vec![Lint::CartesianProduct(vec![vec![0], vec![1]])]);
+        assert_eq!(lint(r#"[:find ?f :where [?e :person/name "Alice"] [?e :person/friend ?f]]"#),
+                   vec![Lint::RawRef(var("?f"), ":person/friend".to_string())]);
+        assert_eq!(lint(r#"[:find (count ?f) . :where [?e :person/friend ?f]]"#), vec![]);
+        assert_eq!(lint(r#"[:find ?f :idents true :where [?e :person/name "Alice"] [?e :person/friend ?f]]"#), vec![]);
+
+        assert_eq!(Lint::CartesianProduct(vec![vec![0, 2], vec![1]]).to_string(),
+                   "clauses [0 2], [1] share no variables; the results are the product of each group's");
+        assert!(store.lint("[:find").is_err());
+    }
+}
@@ -9,9 +9,9 @@
 // specific language governing permissions and limitations under the License.
 
 extern crate clap;
-#[macro_use] extern crate nickel;
-
-use nickel::{Nickel, HttpRouter};
+#[macro_use] extern crate error_chain;
+extern crate nickel;
+extern crate rustc_serialize;
 
 #[macro_use]
 extern crate slog;
@@ -21,15 +21,44 @@ extern crate slog_term;
 
 extern crate mentat;
 
+mod check;
+mod dump;
+mod migrate;
+mod repl;
+mod script;
+mod server;
+
 use clap::{App, Arg, SubCommand, AppSettings};
 use slog::DrainExt;
 
+use mentat::{Format, SharedStore};
+
+use std::io;
+use std::process;
 use std::u16;
 use std::str::FromStr;
 
 fn main() {
     let app = App::new("Mentat").setting(AppSettings::ArgRequiredElseHelp);
-    let matches = app.subcommand(SubCommand::with_name("serve")
+    let matches = app.arg(Arg::with_name("file")
+            .long("file")
+            .value_name("FILE")
+            .help("Run the EDN script in FILE against the database, and exit")
+            .takes_value(true))
+        .arg(Arg::with_name("db")
+            .long("db")
+            .value_name("FILE")
+            .help("Path to the Mentat database to run the script against")
+            .default_value("")
+            .takes_value(true))
+        .arg(Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .help("Write the script's results as EDN or JSON")
+            .possible_values(&["edn", "json"])
+            .default_value("edn")
+            .takes_value(true))
+        .subcommand(SubCommand::with_name("serve")
             .about("Starts a server")
             .arg(Arg::with_name("debug")
                 .long("debug")
@@ -48,17 +77,110 @@ fn main() {
                 .help("Port to serve from, i.e. `localhost:PORT`")
                 .default_value("3333")
                 .takes_value(true)))
+        .subcommand(SubCommand::with_name("export")
+            .about("Exports the transaction log")
+            .arg(Arg::with_name("db")
+                .long("db")
+                .value_name("FILE")
+                .help("Path to the Mentat database to export")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("since")
+                .long("since")
+                .value_name("TX")
+                .help("Export only the transactions after TX")
+                .validator(|tx| i64::from_str(&tx).map(|_| ()).map_err(|_| "TX must be an integer".to_string()))
+                .takes_value(true))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["edn", "ndedn", "ndedn-datoms"])
+                .default_value("edn")
+                .takes_value(true))
+            .arg(Arg::with_name("out")
+                .value_name("OUT")
+                .help("Path to write the export to")
+                .required(true)))
+        .subcommand(SubCommand::with_name("import")
+            .about("Imports an exported transaction log")
+            .arg(Arg::with_name("db")
+                .long("db")
+                .value_name("FILE")
+                .help("Path to the Mentat database to import into")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Check that the import would succeed, without committing it"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["edn", "ndedn", "ndedn-datoms"])
+                .default_value("edn")
+                .takes_value(true))
+            .arg(Arg::with_name("dump")
+                .value_name("DUMP")
+                .help("Path to the export to import")
+                .required(true)))
+        .subcommand(SubCommand::with_name("schema-diff")
+            .about("Compares two schemas, and prints the transaction migrating the first to the second")
+            .arg(Arg::with_name("old")
+                .value_name("OLD")
+                .help("Path to the Mentat database or .edn schema file to migrate from")
+                .required(true))
+            .arg(Arg::with_name("new")
+                .value_name("NEW")
+                .help("Path to the Mentat database or .edn schema file to migrate to")
+                .required(true)))
+        .subcommand(SubCommand::with_name("repl")
+            .about("Runs queries and transactions typed at a prompt")
+            .arg(Arg::with_name("db")
+                .long("db")
+                .value_name("FILE")
+                .help("Path to the Mentat database to open")
+                .default_value("")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("lint")
+            .about("Warns about a query that runs, but probably not as intended")
+            .arg(Arg::with_name("db")
+                .long("db")
+                .value_name("FILE")
+                .help("Path to the Mentat database whose schema to lint against")
+                .default_value("")
+                .takes_value(true))
+            .arg(Arg::with_name("query")
+                .value_name("QUERY")
+                .help("The query, or a path to an .edn file of it")
+                .required(true)))
         .get_matches();
+    if let Some(file) = matches.value_of("file") {
+        let format = if matches.value_of("format") == Some("json") { Format::Json } else { Format::Edn };
+        process::exit(script::run_file(matches.value_of("db").unwrap(), file, format));
+    }
+    if let Some(ref matches) = matches.subcommand_matches("export") {
+        // Validated by clap.
+        let since = matches.value_of("since").and_then(|tx| i64::from_str(tx).ok());
+        let format = dump::export_format(matches.value_of("format").unwrap()).unwrap();
+        process::exit(dump::export(matches.value_of("db").unwrap(), matches.value_of("out").unwrap(), since, format));
+    }
+    if let Some(ref matches) = matches.subcommand_matches("import") {
+        let format = dump::export_format(matches.value_of("format").unwrap()).unwrap();
+        process::exit(dump::import(matches.value_of("db").unwrap(), matches.value_of("dump").unwrap(), format, matches.is_present("dry-run")));
+    }
+    if let Some(ref matches) = matches.subcommand_matches("schema-diff") {
+        let stdout = io::stdout();
+        process::exit(migrate::run(matches.value_of("old").unwrap(), matches.value_of("new").unwrap(), &mut stdout.lock()));
+    }
+    if let Some(ref matches) = matches.subcommand_matches("repl") {
+        process::exit(repl::run_stdio(matches.value_of("db").unwrap()));
+    }
+    if let Some(ref matches) = matches.subcommand_matches("lint") {
+        let stdout = io::stdout();
+        process::exit(check::run(matches.value_of("db").unwrap(), matches.value_of("query").unwrap(), &mut stdout.lock()));
+    }
     if let Some(ref matches) = matches.subcommand_matches("serve") {
         let debug = matches.is_present("debug");
         let port = u16::from_str(matches.value_of("port").unwrap()).expect("Port must be an integer");
-        if debug {
-            println!("This doesn't do anything yet, but it will eventually serve up the following database: {} \
-                      on port: {}.",
-                     matches.value_of("database").unwrap(),
-                     matches.value_of("port").unwrap());
-        }
-
         // Set up logging.
         let log_level = if debug {
             slog::Level::Debug
@@ -74,10 +196,7 @@ fn main() {
                                   "port" => port,
                                   "debug mode" => debug);
 
-        error!("Calling a function: {}", mentat::get_name());
-
-        let mut server = Nickel::new();
-        server.get("/", middleware!("This doesn't do anything yet"));
-        server.listen(("127.0.0.1", port)).expect("Failed to launch server");
+        let store = SharedStore::open(matches.value_of("database").unwrap()).expect("Failed to open database");
+        server::serve(store, port);
     }
 }
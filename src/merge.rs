@@ -0,0 +1,360 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Resolving conflicts between two copies of a store.
+//!
+//! Two stores that share a history up to a common ancestor transaction -- like a `Follower` and
+//! its leader, once either has been written to on its own -- conflict where both have since
+//! changed the value of the same cardinality-one attribute of the same entity, and disagree on
+//! it.  `Store::conflicts_with` finds those conflicts, and `Store::resolve_conflicts` settles each
+//! according to a `MergePolicy`, chosen per attribute.
+//!
+//! Only entities that existed at the common ancestor are compared: entids allocated since then
+//! may name different entities in each store.  Attributes are compared if both stores have them,
+//! under the same entid and ident.
+
+use std::collections::BTreeMap;
+
+use rusqlite;
+
+use edn::{NamespacedKeyword, Value};
+use mentat_db::{Cipher, Entid, Schema, TypedValue, decrypt_sql_value_pair, read_log_value};
+
+use errors::*;
+
+/// A store's version of a conflicting value.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct Version {
+    /// The value the store holds now; `None` if it has been retracted.
+    pub value: Option<TypedValue>,
+
+    /// The last transaction to change the value.
+    pub tx: Entid,
+
+    /// The transaction's `:db/txInstant`, in milliseconds since the Unix epoch.
+    pub tx_instant: Option<i64>,
+}
+
+/// A cardinality-one attribute of an entity whose value each store has changed differently since
+/// their common ancestor.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct Conflict {
+    pub entity: Entid,
+    pub attribute: Entid,
+
+    /// The value at the common ancestor, if there was one.
+    pub ancestor: Option<TypedValue>,
+
+    pub local: Version,
+    pub remote: Version,
+}
+
+/// How a conflict is resolved.
+pub enum MergePolicy {
+    /// Keep the version changed last, by `:db/txInstant`; on a tie, keep the local version.
+    LastWriterWins,
+
+    /// Keep the local version.
+    PreferLocal,
+
+    /// Keep the remote version.
+    PreferRemote,
+
+    /// Keep the value returned by the callback, which may be either version, or a new value
+    /// merging both; `None` retracts the value.
+    Manual(Box<Fn(&Conflict) -> Option<TypedValue> + Send>),
+}
+
+impl MergePolicy {
+    /// The value to keep for `conflict`.
+    fn resolve(&self, conflict: &Conflict) -> Option<TypedValue> {
+        match self {
+            &MergePolicy::LastWriterWins => {
+                if conflict.remote.tx_instant > conflict.local.tx_instant {
+                    conflict.remote.value.clone()
+                } else {
+                    conflict.local.value.clone()
+                }
+            },
+            &MergePolicy::PreferLocal => conflict.local.value.clone(),
+            &MergePolicy::PreferRemote => conflict.remote.value.clone(),
+            &MergePolicy::Manual(ref callback) => callback(conflict),
+        }
+    }
+}
+
+/// The merge policies of a store's attributes: one per attribute, with a default for the rest.
+pub struct MergePolicies {
+    default: MergePolicy,
+
+    /// Keyed by attribute ident, like `:todo/title`.
+    attributes: BTreeMap<String, MergePolicy>,
+}
+
+impl Default for MergePolicies {
+    /// Last writer wins, for every attribute.
+    fn default() -> MergePolicies {
+        MergePolicies::new(MergePolicy::LastWriterWins)
+    }
+}
+
+impl MergePolicies {
+    /// Resolve conflicts on every attribute by `default`.
+    pub fn new(default: MergePolicy) -> MergePolicies {
+        MergePolicies {
+            default: default,
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    /// Resolve conflicts on the named `attribute`, like `:todo/title`, by `policy` instead.
+    pub fn attribute(mut self, attribute: &str, policy: MergePolicy) -> MergePolicies {
+        self.attributes.insert(attribute.to_string(), policy);
+        self
+    }
+
+    fn policy(&self, schema: &Schema, attribute: Entid) -> &MergePolicy {
+        schema.get_ident(&attribute)
+            .and_then(|ident| self.attributes.get(ident))
+            .unwrap_or(&self.default)
+    }
+
+    /// The value to keep for `conflict`, named against `schema`.
+    pub fn resolve(&self, schema: &Schema, conflict: &Conflict) -> Option<TypedValue> {
+        self.policy(schema, conflict.attribute).resolve(conflict)
+    }
+}
+
+/// The cardinality-one attributes both `local` and `remote` have, under the same entid and ident.
+/// Composite tuple attributes are left out: their values follow from their components'.
+fn shared_attributes(local: &Schema, remote: &Schema) -> Vec<Entid> {
+    local.schema_map.iter()
+        .filter(|&(_, attribute)| !attribute.multival && attribute.tuple_attrs.is_empty())
+        .filter(|&(a, _)| local.get_ident(a).is_some() && local.get_ident(a) == remote.get_ident(a))
+        .map(|(&a, _)| a)
+        .collect()
+}
+
+/// The current versions of the values of `attribute` that the store read through `conn` has
+/// changed since `ancestor_tx`, keyed by entity.
+fn changed_since(conn: &rusqlite::Connection, ancestor_tx: Entid, attribute: Entid, tx_instant: Entid, cipher: Option<&Cipher>) -> Result<BTreeMap<Entid, Version>> {
+    let mut stmt: rusqlite::Statement = conn.prepare(
+        "SELECT c.e, c.tx, i.v
+         FROM (SELECT e, MAX(tx) AS tx FROM transactions WHERE a = ? AND tx > ? GROUP BY e) AS c
+         LEFT JOIN datoms AS i ON i.e = c.tx AND i.a = ?")?;
+    let rows: Result<Vec<(Entid, Entid, Option<i64>)>> = stmt.query_and_then(&[&attribute, &ancestor_tx, &tx_instant], |row| {
+        Ok((row.get_checked(0)?, row.get_checked(1)?, row.get_checked(2)?))
+    })?.collect();
+
+    let mut current: rusqlite::Statement = conn.prepare("SELECT v, value_type_tag FROM all_datoms WHERE e = ? AND a = ? LIMIT 1")?;
+    let mut versions = BTreeMap::new();
+    for (e, tx, instant) in rows? {
+        let mut values = current.query(&[&e, &attribute])?;
+        let value = match values.next() {
+            Some(row) => {
+                let row = row?;
+                let v: rusqlite::types::Value = row.get_checked(0)?;
+                let value_type_tag: i32 = row.get_checked(1)?;
+                Some(decrypt_sql_value_pair(v, &value_type_tag, cipher)?)
+            },
+            None => None,
+        };
+        versions.insert(e, Version {
+            value: value,
+            tx: tx,
+            tx_instant: instant,
+        });
+    }
+    Ok(versions)
+}
+
+/// The value of `attribute` on `entity` as of `ancestor_tx`, read from the log through `conn`.
+fn value_at(conn: &rusqlite::Connection, schema: &Schema, entity: Entid, attribute: Entid, ancestor_tx: Entid, cipher: Option<&Cipher>) -> Result<Option<TypedValue>> {
+    // A cardinality-one change retracts the old value and asserts the new one in the same
+    // transaction.
+    let mut stmt: rusqlite::Statement = conn.prepare(
+        "SELECT v, value_type_tag, added
+         FROM transactions WHERE e = ? AND a = ? AND tx <= ?
+         ORDER BY tx DESC, added DESC, rowid DESC LIMIT 1")?;
+    let mut rows = stmt.query(&[&entity, &attribute, &ancestor_tx])?;
+    match rows.next() {
+        Some(row) => {
+            let row = row?;
+            let added: bool = row.get_checked(2)?;
+            if !added {
+                return Ok(None);
+            }
+            let v: rusqlite::types::Value = row.get_checked(0)?;
+            let value_type_tag: i32 = row.get_checked(1)?;
+            Ok(Some(read_log_value(conn, schema, attribute, v, &value_type_tag, cipher)?))
+        },
+        None => Ok(None),
+    }
+}
+
+/// Whether `entity` existed at `ancestor_tx`, according to the log read through `conn`.
+fn existed_at(conn: &rusqlite::Connection, entity: Entid, ancestor_tx: Entid) -> Result<bool> {
+    let exists: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM transactions WHERE e = ? AND tx <= ?)",
+                                      &[&entity, &ancestor_tx], |row| row.get(0))?;
+    Ok(exists)
+}
+
+/// Find the conflicts between the store read through `local` and the store read through `remote`
+/// since their common ancestor, `ancestor_tx`.  Both stores' encrypted values are decrypted with
+/// `cipher`.
+pub fn find_conflicts(local: &rusqlite::Connection, local_schema: &Schema, remote: &rusqlite::Connection, remote_schema: &Schema, ancestor_tx: Entid, cipher: Option<&Cipher>) -> Result<Vec<Conflict>> {
+    let tx_instant = *local_schema.require_entid(&":db/txInstant".to_string())?;
+    let mut conflicts = vec![];
+    for a in shared_attributes(local_schema, remote_schema) {
+        let remote_versions = changed_since(remote, ancestor_tx, a, tx_instant, cipher)?;
+        if remote_versions.is_empty() {
+            continue;
+        }
+        for (e, local_version) in changed_since(local, ancestor_tx, a, tx_instant, cipher)? {
+            let remote_version = match remote_versions.get(&e) {
+                Some(remote_version) if remote_version.value != local_version.value => remote_version.clone(),
+                _ => continue,
+            };
+            if !existed_at(local, e, ancestor_tx)? {
+                continue;
+            }
+            conflicts.push(Conflict {
+                entity: e,
+                attribute: a,
+                ancestor: value_at(local, local_schema, e, a, ancestor_tx, cipher)?,
+                local: local_version,
+                remote: remote_version,
+            });
+        }
+    }
+    Ok(conflicts)
+}
+
+/// The EDN text of the transaction settling `conflicts` by `policies`, or `None` if the local
+/// store already holds every value kept.
+pub fn resolution(schema: &Schema, conflicts: &[Conflict], policies: &MergePolicies) -> Option<String> {
+    let terms: Vec<Value> = conflicts.iter().filter_map(|conflict| {
+        let kept = policies.resolve(schema, conflict);
+        if kept == conflict.local.value {
+            return None;
+        }
+        let (op, v) = match (kept, conflict.local.value.as_ref()) {
+            (Some(kept), _) => ("add", kept.to_edn_value_pair().0),
+            (None, Some(local)) => ("retract", local.to_edn_value_pair().0),
+            (None, None) => return None,
+        };
+        Some(Value::Vector(vec![Value::NamespacedKeyword(NamespacedKeyword::new("db", op)),
+                                Value::Integer(conflict.entity),
+                                Value::Integer(conflict.attribute),
+                                v]))
+    }).collect();
+    if terms.is_empty() {
+        None
+    } else {
+        Some(Value::Vector(terms).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use follower::Follower;
+    use query::QueryResults;
+    use store::Store;
+
+    fn version(value: &str, tx_instant: i64) -> Version {
+        Version {
+            value: Some(TypedValue::String(value.to_string())),
+            tx: 0,
+            tx_instant: Some(tx_instant),
+        }
+    }
+
+    #[test]
+    fn test_policies() {
+        let conflict = Conflict {
+            entity: 65536,
+            attribute: 65537,
+            ancestor: Some(TypedValue::String("a".to_string())),
+            local: version("local", 2000),
+            remote: version("remote", 1000),
+        };
+        let schema = Schema::default();
+
+        assert_eq!(MergePolicy::LastWriterWins.resolve(&conflict), Some(TypedValue::String("local".to_string())));
+        assert_eq!(MergePolicy::PreferLocal.resolve(&conflict), Some(TypedValue::String("local".to_string())));
+        assert_eq!(MergePolicy::PreferRemote.resolve(&conflict), Some(TypedValue::String("remote".to_string())));
+        let manual = MergePolicy::Manual(Box::new(|conflict: &Conflict| conflict.ancestor.clone()));
+        assert_eq!(manual.resolve(&conflict), Some(TypedValue::String("a".to_string())));
+
+        // Keeping the local value needs no transaction.
+        assert_eq!(resolution(&schema, &[conflict.clone()], &MergePolicies::default()), None);
+        assert_eq!(resolution(&schema, &[conflict.clone()], &MergePolicies::new(MergePolicy::PreferRemote)),
+                   Some(r#"[[:db/add 65536 65537 "remote"]]"#.to_string()));
+        let retract = MergePolicies::new(MergePolicy::Manual(Box::new(|_: &Conflict| None)));
+        assert_eq!(resolution(&schema, &[conflict], &retract),
+                   Some(r#"[[:db/retract 65536 65537 "local"]]"#.to_string()));
+    }
+
+    #[test]
+    fn test_conflicts_with() {
+        let remote_path = env::temp_dir().join(format!("mentat-test-merge-remote-{}.db", process::id()));
+        let remote_path = remote_path.to_str().unwrap();
+        let local_path = env::temp_dir().join(format!("mentat-test-merge-local-{}.db", process::id()));
+        let local_path = local_path.to_str().unwrap();
+        let _ = fs::remove_file(remote_path);
+        let _ = fs::remove_file(local_path);
+
+        let mut remote = Store::open(remote_path).unwrap();
+        remote.transact(r#"[[:db/add "n" :db/ident :test/name]
+                            [:db/add "n" :db/valueType :db.type/string]
+                            [:db/add "t" :db/ident :test/title]
+                            [:db/add "t" :db/valueType :db.type/string]]"#).unwrap();
+        let report = remote.transact(r#"[[:db/add "a" :test/name "Alice"]
+                                         [:db/add "a" :test/title "Dr"]]"#).unwrap();
+        let alice = report.tempids["a"];
+
+        let mut follower = Follower::open(local_path, remote_path).unwrap();
+        follower.catch_up().unwrap();
+        let ancestor_tx = follower.basis_tx().unwrap();
+        let mut local = follower.into_store();
+
+        // Both rename Alice, differently; only the remote changes her title, which isn't a conflict.
+        local.transact(&format!(r#"[[:db/add {} :test/name "Alicia"]]"#, alice)).unwrap();
+        remote.transact(&format!(r#"[[:db/add {} :test/name "Allie"]
+                                     [:db/add {} :test/title "Prof"]]"#, alice, alice)).unwrap();
+
+        let conflicts = local.conflicts_with(remote_path, ancestor_tx).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].entity, alice);
+        assert_eq!(conflicts[0].ancestor, Some(TypedValue::String("Alice".to_string())));
+        assert_eq!(conflicts[0].local.value, Some(TypedValue::String("Alicia".to_string())));
+        assert_eq!(conflicts[0].remote.value, Some(TypedValue::String("Allie".to_string())));
+
+        let policies = MergePolicies::new(MergePolicy::PreferLocal)
+            .attribute(":test/name", MergePolicy::PreferRemote);
+        assert!(local.resolve_conflicts(&conflicts, &policies).unwrap().is_some());
+        assert_eq!(local.q_once(&format!("[:find ?n . :where [{} :test/name ?n]]", alice)).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::String("Allie".to_string()))));
+
+        // Once resolved, the stores agree.
+        assert!(local.conflicts_with(remote_path, ancestor_tx).unwrap().is_empty());
+
+        let _ = fs::remove_file(remote_path);
+        let _ = fs::remove_file(local_path);
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Comparing the schemas of two stores, for `mentat schema-diff OLD NEW`.
+//!
+//! Each side is a store, or a schema file: an EDN transaction, ending `.edn`, that installs the
+//! schema into an empty store.  The differences are listed on standard error, and the transaction
+//! migrating the old schema to the new one is written to standard output, ready for `mentat
+//! --file`.  The exit codes are those of scripts; see `script`.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use mentat::{Result, Store, schema_diff};
+
+use script::{EXIT_FAILED, EXIT_IO, EXIT_OK};
+
+/// The store at `path`, or, if `path` names a schema file, an in-memory store with its schema,
+/// or the exit code if neither could be had.
+fn load(path: &str) -> ::std::result::Result<Store, i32> {
+    let loaded: Result<Store> = if path.ends_with(".edn") {
+        let mut text = String::new();
+        if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut text)) {
+            let _ = writeln!(io::stderr(), "could not read {}: {}", path, e);
+            return Err(EXIT_IO);
+        }
+        Store::open("").and_then(|mut store| store.transact(&text).map(|_| store)).map_err(|e| {
+            let _ = writeln!(io::stderr(), "could not install the schema in {}: {}", path, e);
+            e
+        })
+    } else {
+        Store::open(path).map_err(|e| {
+            let _ = writeln!(io::stderr(), "could not open {}: {}", path, e);
+            e
+        })
+    };
+    loaded.map_err(|_| if path.ends_with(".edn") { EXIT_FAILED } else { EXIT_IO })
+}
+
+/// Compare the schemas at `old` and `new`, writing the migration to `out`, and returning the exit
+/// code.
+pub fn run<W: Write>(old: &str, new: &str, out: &mut W) -> i32 {
+    let (old, new) = match (load(old), load(new)) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(code), _) | (_, Err(code)) => return code,
+    };
+    let diff = schema_diff(old.schema(), new.schema());
+    let _ = write!(io::stderr(), "{}", diff);
+    match writeln!(out, "{}", diff.migration()) {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "could not write the migration: {}", e);
+            EXIT_IO
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_run() {
+        let schema = env::temp_dir().join(format!("mentat-test-schema-{}.edn", process::id()));
+        let schema = schema.to_str().unwrap();
+        File::create(schema).unwrap().write_all(br#"[[:db/add "n" :db/ident :test/name]
+                                                     [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+
+        // An empty store migrates to the schema by installing :test/name.
+        let mut out = vec![];
+        assert_eq!(run("", schema, &mut out), EXIT_OK);
+        let mut store = Store::open("").unwrap();
+        store.transact(&String::from_utf8(out).unwrap()).unwrap();
+        assert!(store.schema().get_entid(&":test/name".to_string()).is_some());
+
+        assert_eq!(run("", "/nonexistent/schema.edn", &mut vec![]), EXIT_IO);
+        assert_eq!(run("/nonexistent/db.mentat", schema, &mut vec![]), EXIT_IO);
+
+        File::create(schema).unwrap().write_all(br#"[[:db/add "n" :test/missing "x"]]"#).unwrap();
+        assert_eq!(run("", schema, &mut vec![]), EXIT_FAILED);
+        let _ = fs::remove_file(schema);
+    }
+}
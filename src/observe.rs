@@ -0,0 +1,317 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Telling observers about committed transactions.
+//!
+//! An observer registered with `Store::register_observer` names the attributes it cares about, and
+//! is called with each committed transaction that asserted or retracted one of them.  Callbacks
+//! run on a notification thread of the store's own, which the store starts when the first
+//! observer is registered: committing only queues a message, so a slow observer delays other
+//! observers but never the writer.
+//!
+//! An observer can ask for batched delivery: the transactions committed within a window of the
+//! first are delivered together, in one call, when the window closes.  This coalesces bursts of
+//! rapid transactions, like an import, into a single notification.
+//!
+//! A callback that panics is unregistered; the other observers carry on.  Transactions still
+//! waiting in a batch are delivered when the store is dropped.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::panic;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mentat_db::Entid;
+
+/// A committed transaction, as delivered to observers.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct TxChange {
+    pub tx_id: Entid,
+
+    /// The timestamp of the transaction, in milliseconds since the Unix epoch.
+    pub tx_instant: i64,
+
+    /// The attributes of the datoms the transaction asserted or retracted.
+    pub attributes: BTreeSet<Entid>,
+}
+
+/// Called with the key the observer was registered under and the transactions being delivered, in
+/// the order they were committed.
+pub type Callback = Box<Fn(&str, &[TxChange]) + Send>;
+
+/// When an observer is called.
+#[derive(Clone,Copy,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum Delivery {
+    /// Once per transaction, as soon as the notification thread gets to it after the transaction
+    /// commits.  A transaction made through an `InProgress` is only delivered once the
+    /// `InProgress` commits, and not at all if it's rolled back.
+    Immediate,
+
+    /// Once per window: the transactions committed within the given duration of the first are
+    /// delivered together.
+    Batched(Duration),
+}
+
+/// An observer of the transactions that touch some attributes.
+pub struct TxObserver {
+    /// The idents of the attributes observed; empty to observe every transaction.
+    pub attributes: Vec<String>,
+    pub delivery: Delivery,
+    callback: Callback,
+}
+
+impl TxObserver {
+    /// Observe the transactions touching the named `attributes`, like `:todo/title`, or every
+    /// transaction if `attributes` is empty.  Delivery is immediate; see `batched`.
+    pub fn new<F>(attributes: &[&str], callback: F) -> TxObserver where F: Fn(&str, &[TxChange]) + Send + 'static {
+        TxObserver {
+            attributes: attributes.iter().map(|a| a.to_string()).collect(),
+            delivery: Delivery::Immediate,
+            callback: Box::new(callback),
+        }
+    }
+
+    /// Deliver the transactions committed within `window` of the first together.
+    pub fn batched(mut self, window: Duration) -> TxObserver {
+        self.delivery = Delivery::Batched(window);
+        self
+    }
+}
+
+/// An observer whose attributes have been resolved to entids.
+struct Registered {
+    /// `None` to observe every transaction.
+    attributes: Option<BTreeSet<Entid>>,
+    delivery: Delivery,
+    callback: Callback,
+
+    /// Transactions waiting for a batch to close, and when it closes.
+    pending: Vec<TxChange>,
+    deadline: Option<Instant>,
+}
+
+impl Registered {
+    fn observes(&self, change: &TxChange) -> bool {
+        match self.attributes {
+            None => true,
+            Some(ref attributes) => !attributes.is_disjoint(&change.attributes),
+        }
+    }
+}
+
+enum Message {
+    Register(String, Registered),
+    Unregister(String),
+    Committed(Vec<TxChange>),
+}
+
+/// The sending end of a store's notification thread.  Dropping it lets the thread deliver any
+/// pending batches and exit, without waiting for it.
+pub struct Dispatcher {
+    sender: mpsc::Sender<Message>,
+}
+
+impl Dispatcher {
+    /// Start a notification thread.
+    pub fn start() -> Dispatcher {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || run(receiver));
+        Dispatcher {
+            sender: sender,
+        }
+    }
+
+    /// Register `observer` under `key`, replacing any observer already registered under it.
+    /// `attributes` are the entids of the observer's attributes, or `None` for every transaction.
+    pub fn register(&self, key: &str, observer: TxObserver, attributes: Option<BTreeSet<Entid>>) {
+        let registered = Registered {
+            attributes: attributes,
+            delivery: observer.delivery,
+            callback: observer.callback,
+            pending: vec![],
+            deadline: None,
+        };
+        // The thread only exits once this sender is dropped, so sending can't fail.
+        let _ = self.sender.send(Message::Register(key.to_string(), registered));
+    }
+
+    pub fn unregister(&self, key: &str) {
+        let _ = self.sender.send(Message::Unregister(key.to_string()));
+    }
+
+    /// Queue the given committed transactions for delivery.  Never blocks.
+    pub fn committed(&self, changes: Vec<TxChange>) {
+        if !changes.is_empty() {
+            let _ = self.sender.send(Message::Committed(changes));
+        }
+    }
+}
+
+/// Call `observer`, returning `false` if it panicked.
+fn deliver(key: &str, observer: &Registered, changes: &[TxChange]) -> bool {
+    panic::catch_unwind(panic::AssertUnwindSafe(|| (observer.callback)(key, changes))).is_ok()
+}
+
+/// Deliver the batches that close by `now`, or every batch if `now` is `None`.  Unregisters
+/// observers that panic.
+fn flush(observers: &mut BTreeMap<String, Registered>, now: Option<Instant>) {
+    let mut panicked = vec![];
+    for (key, observer) in observers.iter_mut() {
+        let due = match (observer.deadline, now) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(deadline), Some(now)) => deadline <= now,
+        };
+        if due {
+            let pending = ::std::mem::replace(&mut observer.pending, vec![]);
+            observer.deadline = None;
+            if !deliver(key, observer, &pending) {
+                panicked.push(key.clone());
+            }
+        }
+    }
+    for key in panicked {
+        observers.remove(&key);
+    }
+}
+
+fn run(receiver: mpsc::Receiver<Message>) {
+    let mut observers: BTreeMap<String, Registered> = BTreeMap::new();
+    loop {
+        let next_deadline = observers.values().filter_map(|observer| observer.deadline).min();
+        let message = match next_deadline {
+            None => receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            Some(deadline) => {
+                let now = Instant::now();
+                let timeout = if deadline > now { deadline - now } else { Duration::from_millis(0) };
+                receiver.recv_timeout(timeout)
+            },
+        };
+
+        match message {
+            Ok(Message::Register(key, observer)) => {
+                observers.insert(key, observer);
+            },
+            Ok(Message::Unregister(key)) => {
+                observers.remove(&key);
+            },
+            Ok(Message::Committed(changes)) => {
+                let mut panicked = vec![];
+                for (key, observer) in observers.iter_mut() {
+                    let observed: Vec<TxChange> = changes.iter().filter(|change| observer.observes(change)).cloned().collect();
+                    if observed.is_empty() {
+                        continue;
+                    }
+                    match observer.delivery {
+                        Delivery::Immediate => {
+                            if !deliver(key, observer, &observed) {
+                                panicked.push(key.clone());
+                            }
+                        },
+                        Delivery::Batched(window) => {
+                            observer.pending.extend(observed);
+                            if observer.deadline.is_none() {
+                                observer.deadline = Some(Instant::now() + window);
+                            }
+                        },
+                    }
+                }
+                for key in panicked {
+                    observers.remove(&key);
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                flush(&mut observers, None);
+                return;
+            },
+        }
+
+        flush(&mut observers, Some(Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::Receiver;
+
+    fn change(tx_id: Entid, attributes: &[Entid]) -> TxChange {
+        TxChange {
+            tx_id: tx_id,
+            tx_instant: 0,
+            attributes: attributes.iter().cloned().collect(),
+        }
+    }
+
+    /// An observer that sends the IDs of the transactions delivered to it down a channel.
+    fn observer(delivery: Delivery) -> (TxObserver, Receiver<Vec<Entid>>) {
+        let (sender, receiver) = mpsc::channel();
+        let mut observer = TxObserver::new(&[], move |_, changes| {
+            sender.send(changes.iter().map(|change| change.tx_id).collect()).unwrap();
+        });
+        observer.delivery = delivery;
+        (observer, receiver)
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let dispatcher = Dispatcher::start();
+        let timeout = Duration::from_secs(5);
+
+        let (immediate, immediate_rx) = observer(Delivery::Immediate);
+        dispatcher.register("immediate", immediate, Some(vec![100].into_iter().collect()));
+        let (batched, batched_rx) = observer(Delivery::Batched(Duration::from_millis(100)));
+        dispatcher.register("batched", batched, None);
+
+        dispatcher.committed(vec![change(1, &[100])]);
+        dispatcher.committed(vec![change(2, &[101])]);
+        dispatcher.committed(vec![change(3, &[100, 101])]);
+
+        // The immediate observer only sees the transactions touching its attribute, one at a time.
+        assert_eq!(immediate_rx.recv_timeout(timeout).unwrap(), vec![1]);
+        assert_eq!(immediate_rx.recv_timeout(timeout).unwrap(), vec![3]);
+
+        // The batched observer sees them all at once.
+        assert_eq!(batched_rx.recv_timeout(timeout).unwrap(), vec![1, 2, 3]);
+
+        dispatcher.unregister("immediate");
+        dispatcher.committed(vec![change(4, &[100])]);
+        assert_eq!(batched_rx.recv_timeout(timeout).unwrap(), vec![4]);
+        assert!(immediate_rx.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+
+    #[test]
+    fn test_panicking_observer() {
+        let dispatcher = Dispatcher::start();
+        dispatcher.register("panics", TxObserver::new(&[], |_, _| panic!("observer failed")), None);
+        let (survivor, survivor_rx) = observer(Delivery::Immediate);
+        dispatcher.register("survivor", survivor, None);
+
+        dispatcher.committed(vec![change(1, &[100])]);
+        dispatcher.committed(vec![change(2, &[100])]);
+        assert_eq!(survivor_rx.recv_timeout(Duration::from_secs(5)).unwrap(), vec![1]);
+        assert_eq!(survivor_rx.recv_timeout(Duration::from_secs(5)).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn test_pending_batches_delivered_on_drop() {
+        let dispatcher = Dispatcher::start();
+        let (batched, batched_rx) = observer(Delivery::Batched(Duration::from_secs(3600)));
+        dispatcher.register("batched", batched, None);
+        dispatcher.committed(vec![change(1, &[100])]);
+        drop(dispatcher);
+        assert_eq!(batched_rx.recv_timeout(Duration::from_secs(5)).unwrap(), vec![1]);
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Ordered cardinality-many relationships, like the bookmarks in a folder.
+//!
+//! The values of a cardinality-many attribute are a set, so an ordered relationship pairs a
+//! cardinality-many ref attribute from the parent to its items, like `:folder/items`, with a
+//! cardinality-one long attribute on each item giving its position, like `:item/position`.
+//!
+//! Items are listed by position, then entid; items without a position come last.  Each edit
+//! renumbers the items densely from 0, and asserts only the positions that change.
+
+use std::collections::BTreeMap;
+
+use rusqlite;
+
+use mentat_db::{Entid, Schema, TypedValue, ValueType};
+
+use errors::*;
+use query::{QueryResults, q_once};
+
+/// An ordered relationship: the ref attribute from a parent to its items, and the long attribute
+/// giving each item's position.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct OrderedMany {
+    /// A cardinality-many ref attribute, like `:folder/items`.
+    pub items: String,
+    /// A cardinality-one long attribute, like `:item/position`.
+    pub position: String,
+}
+
+impl OrderedMany {
+    pub fn new(items: &str, position: &str) -> OrderedMany {
+        OrderedMany {
+            items: items.to_string(),
+            position: position.to_string(),
+        }
+    }
+
+    /// Fail unless both attributes are installed in `schema` with the right value type and
+    /// cardinality.
+    fn check(&self, schema: &Schema) -> Result<()> {
+        let (items, _) = schema.require_attribute_for_ident_str(&self.items)?;
+        if items.value_type != ValueType::Ref || !items.multival {
+            bail!(ErrorKind::InvalidOrdering(format!("{} isn't a cardinality-many ref attribute", self.items)));
+        }
+        let (position, _) = schema.require_attribute_for_ident_str(&self.position)?;
+        if position.value_type != ValueType::Long || position.multival {
+            bail!(ErrorKind::InvalidOrdering(format!("{} isn't a cardinality-one long attribute", self.position)));
+        }
+        Ok(())
+    }
+}
+
+/// Return the items of `parent`, in order, each with its stored position, if any.
+fn positioned(conn: &rusqlite::Connection, schema: &Schema, ordered: &OrderedMany, parent: Entid) -> Result<Vec<(Entid, Option<i64>)>> {
+    ordered.check(schema)?;
+
+    let rows = |query: String| -> Result<Vec<Vec<TypedValue>>> {
+        match q_once(conn, schema, &query)?.results {
+            QueryResults::Rel(rows) => Ok(rows),
+            _ => Ok(vec![]),
+        }
+    };
+
+    let mut positions: BTreeMap<Entid, Option<i64>> = BTreeMap::new();
+    for row in rows(format!("[:find ?item :where [{} {} ?item]]", parent, ordered.items))? {
+        if let &TypedValue::Ref(item) = &row[0] {
+            positions.insert(item, None);
+        }
+    }
+    for row in rows(format!("[:find ?item ?position :where [{} {} ?item] [?item {} ?position]]", parent, ordered.items, ordered.position))? {
+        if let (&TypedValue::Ref(item), &TypedValue::Long(position)) = (&row[0], &row[1]) {
+            positions.insert(item, Some(position));
+        }
+    }
+
+    let mut items: Vec<(Entid, Option<i64>)> = positions.into_iter().collect();
+    items.sort_by_key(|&(item, position)| (position.is_none(), position, item));
+    Ok(items)
+}
+
+/// Return the assertions that give each item in `order` its index as its position, for those whose
+/// position in `current` differs.
+fn renumber(ordered: &OrderedMany, current: &[(Entid, Option<i64>)], order: &[Entid]) -> Vec<String> {
+    let positions: BTreeMap<Entid, Option<i64>> = current.iter().cloned().collect();
+    order.iter().enumerate()
+        .filter(|&(index, item)| positions.get(item).cloned().and_then(|position| position) != Some(index as i64))
+        .map(|(index, item)| format!("[:db/add {} {} {}]", item, ordered.position, index))
+        .collect()
+}
+
+fn index_of(items: &[(Entid, Option<i64>)], item: Entid) -> Option<usize> {
+    items.iter().position(|&(entid, _)| entid == item)
+}
+
+/// Return the items of `parent`, in order.
+pub fn items(conn: &rusqlite::Connection, schema: &Schema, ordered: &OrderedMany, parent: Entid) -> Result<Vec<Entid>> {
+    Ok(positioned(conn, schema, ordered, parent)?.into_iter().map(|(item, _)| item).collect())
+}
+
+/// Return the transaction that adds `item` to the items of `parent` at `index`, moving those at
+/// and after `index` along.  Fails if `item` is already an item of `parent`, or if `index` is past
+/// the end of the items.
+pub fn insert_at(conn: &rusqlite::Connection, schema: &Schema, ordered: &OrderedMany, parent: Entid, index: usize, item: Entid) -> Result<String> {
+    let current = positioned(conn, schema, ordered, parent)?;
+    if index_of(&current, item).is_some() {
+        bail!(ErrorKind::InvalidOrdering(format!("{} is already an item of {}", item, parent)));
+    }
+    if index > current.len() {
+        bail!(ErrorKind::InvalidOrdering(format!("index {} is past the end of the {} items of {}", index, current.len(), parent)));
+    }
+
+    let mut order: Vec<Entid> = current.iter().map(|&(entid, _)| entid).collect();
+    order.insert(index, item);
+
+    let mut assertions = vec![format!("[:db/add {} {} {}]", parent, ordered.items, item)];
+    assertions.extend(renumber(ordered, &current, &order));
+    Ok(format!("[{}]", assertions.join(" ")))
+}
+
+/// Return the transaction that moves `item` to `index` among the items of `parent`.  Fails if
+/// `item` isn't an item of `parent`, or if `index` isn't the index of an item.
+pub fn move_to(conn: &rusqlite::Connection, schema: &Schema, ordered: &OrderedMany, parent: Entid, item: Entid, index: usize) -> Result<String> {
+    let current = positioned(conn, schema, ordered, parent)?;
+    let from = match index_of(&current, item) {
+        Some(from) => from,
+        None => bail!(ErrorKind::InvalidOrdering(format!("{} isn't an item of {}", item, parent))),
+    };
+    if index >= current.len() {
+        bail!(ErrorKind::InvalidOrdering(format!("index {} is past the end of the {} items of {}", index, current.len(), parent)));
+    }
+
+    let mut order: Vec<Entid> = current.iter().map(|&(entid, _)| entid).collect();
+    order.remove(from);
+    order.insert(index, item);
+    Ok(format!("[{}]", renumber(ordered, &current, &order).join(" ")))
+}
+
+/// Return the transaction that removes `item` from the items of `parent`, retracting its position
+/// and moving the items after it back.  Fails if `item` isn't an item of `parent`.
+pub fn remove(conn: &rusqlite::Connection, schema: &Schema, ordered: &OrderedMany, parent: Entid, item: Entid) -> Result<String> {
+    let current = positioned(conn, schema, ordered, parent)?;
+    let from = match index_of(&current, item) {
+        Some(from) => from,
+        None => bail!(ErrorKind::InvalidOrdering(format!("{} isn't an item of {}", item, parent))),
+    };
+
+    let mut assertions = vec![format!("[:db/retract {} {} {}]", parent, ordered.items, item)];
+    if let Some(position) = current[from].1 {
+        assertions.push(format!("[:db/retract {} {} {}]", item, ordered.position, position));
+    }
+
+    let mut order: Vec<Entid> = current.iter().map(|&(entid, _)| entid).collect();
+    order.remove(from);
+    assertions.extend(renumber(ordered, &current, &order));
+    Ok(format!("[{}]", assertions.join(" ")))
+}
@@ -0,0 +1,279 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! A pool of read connections with a single writer, for embedders that read from many threads.
+//!
+//! A `SharedStore` serializes every operation on one connection, so readers wait on each other and
+//! on the writer.  A `StorePool` keeps one `Store` for transactions and a fixed number of read-only
+//! connections for queries.  Stores on disk are in WAL mode, so readers don't block the writer or
+//! each other; a read waits only when every reader is busy.
+//!
+//! Each read runs inside a SQLite read transaction, so it sees one consistent state of the store.
+//! Readers keep the schema and partition map they last read, and only read them again when the
+//! store has been transacted since.
+//!
+//! In-memory stores can only be read through their own connection, so they can't be pooled.
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use rusqlite;
+
+use mentat_db::{Cipher, DB, Entid, Schema, TxReport};
+use mentat_db::db;
+use mentat_db::options::StoreOptions;
+
+use errors::*;
+use query::{KeyedRow, QueryOutput, basis_tx, prepare_query, run_query};
+use shared::lock;
+use store::Store;
+
+/// A read-only connection, with the metadata it last read.
+struct Reader {
+    conn: rusqlite::Connection,
+    db: DB,
+    basis_tx: Entid,
+}
+
+/// A consistent view of the store through one of a pool's readers, passed to `StorePool::read`.
+pub struct PooledRead<'a> {
+    reader: &'a Reader,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl<'a> PooledRead<'a> {
+    /// The last transaction visible to this read.
+    pub fn basis_tx(&self) -> Entid {
+        self.reader.basis_tx
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.reader.db.schema
+    }
+
+    pub fn q_once(&self, query: &str) -> Result<QueryOutput> {
+        run_query(&self.reader.conn, &prepare_query(&self.reader.db.schema, query)?, self.cipher.as_ref().map(|cipher| &**cipher))
+    }
+
+    pub fn q_once_keyed(&self, query: &str) -> Result<Vec<KeyedRow>> {
+        let sql_query = prepare_query(&self.reader.db.schema, query)?;
+        let output = run_query(&self.reader.conn, &sql_query, self.cipher.as_ref().map(|cipher| &**cipher))?;
+        Ok(output.results.into_keyed(&sql_query.find_spec))
+    }
+}
+
+struct Pool {
+    writer: Mutex<Store>,
+    readers: Mutex<Vec<Reader>>,
+    /// Signalled whenever a reader is returned to `readers`.
+    returned: Condvar,
+    cipher: Mutex<Option<Arc<Cipher>>>,
+}
+
+/// A thread-safe, cloneable handle to a store with one writer and a pool of readers.  Every clone
+/// refers to the same pool.
+#[derive(Clone)]
+pub struct StorePool {
+    pool: Arc<Pool>,
+}
+
+impl StorePool {
+    /// Open the store at `path`, creating it if necessary, with `readers` read connections.  At
+    /// least one reader is always opened.  See `Store::open`.
+    pub fn open(path: &str, readers: usize) -> Result<StorePool> {
+        StorePool::open_with_options(path, &StoreOptions::default(), readers)
+    }
+
+    /// Like `open`, but tune every connection with `options`.  The journal mode and page size only
+    /// apply to the writer, which creates the store.
+    pub fn open_with_options(path: &str, options: &StoreOptions, readers: usize) -> Result<StorePool> {
+        let writer = Store::open_with_options(path, options)?;
+
+        let reader_options = StoreOptions {
+            page_size: None,
+            journal_mode: None,
+            ..options.clone()
+        };
+        let mut pooled = Vec::with_capacity(readers.max(1));
+        for _ in 0..readers.max(1) {
+            let conn = db::new_read_only_connection(path)?;
+            reader_options.apply(&conn, false)?;
+            let db = db::read_db(&conn)?;
+            let basis_tx = basis_tx(&conn)?;
+            pooled.push(Reader {
+                conn: conn,
+                db: db,
+                basis_tx: basis_tx,
+            });
+        }
+
+        Ok(StorePool {
+            pool: Arc::new(Pool {
+                writer: Mutex::new(writer),
+                readers: Mutex::new(pooled),
+                returned: Condvar::new(),
+                cipher: Mutex::new(None),
+            }),
+        })
+    }
+
+    fn writer(&self) -> MutexGuard<Store> {
+        lock(&self.pool.writer)
+    }
+
+    /// Run `f` with exclusive access to the writer.  Readers go on reading meanwhile.
+    pub fn with_writer<T, F>(&self, f: F) -> T where F: FnOnce(&mut Store) -> T {
+        f(&mut *self.writer())
+    }
+
+    pub fn transact(&self, transaction: &str) -> Result<TxReport> {
+        self.writer().transact(transaction)
+    }
+
+    /// Decrypt the values of `:db/encrypted` attributes with `cipher`, for the writer and every
+    /// reader.
+    pub fn set_cipher(&self, cipher: Arc<Cipher>) {
+        *lock(&self.pool.cipher) = Some(cipher.clone());
+        self.writer().set_cipher(cipher);
+    }
+
+    /// Take an idle reader, waiting for one if they're all busy.
+    fn take_reader(&self) -> Checkout {
+        let mut readers = lock(&self.pool.readers);
+        loop {
+            if let Some(reader) = readers.pop() {
+                return Checkout {
+                    pool: &self.pool,
+                    reader: Some(reader),
+                };
+            }
+            readers = self.pool.returned.wait(readers).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Run `f` against a consistent view of the store, through one of the pool's readers.
+    pub fn read<T, F>(&self, f: F) -> Result<T> where F: FnOnce(&PooledRead) -> Result<T> {
+        let mut checkout = self.take_reader();
+        let cipher = lock(&self.pool.cipher).clone();
+        let reader = checkout.reader.as_mut().expect("a checked-out reader");
+        read_with(reader, cipher, f)
+    }
+
+    pub fn q_once(&self, query: &str) -> Result<QueryOutput> {
+        self.read(|read| read.q_once(query))
+    }
+
+    pub fn q_once_keyed(&self, query: &str) -> Result<Vec<KeyedRow>> {
+        self.read(|read| read.q_once_keyed(query))
+    }
+}
+
+/// A reader taken from the pool, which goes back when dropped, even if the read panics.
+struct Checkout<'a> {
+    pool: &'a Pool,
+    reader: Option<Reader>,
+}
+
+impl<'a> Drop for Checkout<'a> {
+    fn drop(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            // Nothing was written, so there's nothing to lose.
+            let _ = reader.conn.execute("ROLLBACK", &[]);
+            lock(&self.pool.readers).push(reader);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+fn read_with<T, F>(reader: &mut Reader, cipher: Option<Arc<Cipher>>, f: F) -> Result<T> where F: FnOnce(&PooledRead) -> Result<T> {
+    reader.conn.execute("BEGIN DEFERRED", &[])?;
+    // A deferred transaction takes its snapshot at its first read, which is the basis query; the
+    // metadata read after it agrees with what `f` sees.
+    let basis = basis_tx(&reader.conn)?;
+    if basis != reader.basis_tx {
+        reader.db = db::read_db(&reader.conn)?;
+        reader.basis_tx = basis;
+    }
+    f(&PooledRead {
+        reader: reader,
+        cipher: cipher,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::thread;
+
+    use mentat_db::TypedValue;
+
+    use query::QueryResults;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_bounds() {
+        assert_send_sync::<StorePool>();
+    }
+
+    #[test]
+    fn test_pool() {
+        let path = env::temp_dir().join(format!("mentat-test-pool-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let pool = StorePool::open(path, 2).unwrap();
+        pool.transact(r#"[[:db/add "n" :db/ident :test/name]
+                          [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+
+        // Readers pick up the schema change, and see each transaction once it commits.
+        let handles: Vec<_> = (0..4).map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                pool.transact(&format!("[[:db/add \"e\" :test/name \"{}\"]]", i)).unwrap();
+                let query = format!("[:find ?e . :where [?e :test/name \"{}\"]]", i);
+                match pool.q_once(&query).unwrap().results {
+                    QueryResults::Scalar(Some(TypedValue::Ref(_))) => (),
+                    x => panic!("expected a Ref, got {:?}", x),
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let count = pool.read(|read| {
+            match read.q_once(r#"[:find [?name ...] :where [_ :test/name ?name]]"#)?.results {
+                QueryResults::Coll(names) => Ok(names.len()),
+                _ => Ok(0),
+            }
+        }).unwrap();
+        assert_eq!(count, 4);
+
+        // A failed read returns its reader to the pool.
+        for _ in 0..3 {
+            assert!(pool.q_once("[:find ?x :where [?x").is_err());
+        }
+        assert!(pool.q_once_keyed("[:find ?x :where [?x :test/name _]]").is_ok());
+
+        drop(pool);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_in_memory() {
+        assert!(StorePool::open("", 2).is_err());
+    }
+}
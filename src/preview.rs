@@ -0,0 +1,143 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Previewing what a transaction would do, for review before it is committed.
+//!
+//! `Store::preview_transaction` applies a transaction through the whole pipeline -- constraints,
+//! interceptors, and derived attributes included -- and then rolls it back, reporting the entids
+//! its tempids were given and every datom it would assert or retract, including the retractions
+//! of replaced cardinality-one values.  `TxPreview::to_diff` shows them in a diff-like form for a
+//! person to confirm:
+//!
+//! ```text
+//! "a" 65536
+//! - [65536 :person/name "Al"]
+//! + [65536 :person/name "Alice"]
+//! ```
+
+use std::collections::BTreeMap;
+
+use rusqlite;
+
+use mentat_db::{Cipher, Entid, Schema, TxReport, TypedValue, read_log_value};
+
+use errors::*;
+
+/// A datom a transaction would assert or retract.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct PreviewDatom {
+    pub e: Entid,
+
+    /// The attribute's ident, like `:person/name`.
+    pub a: String,
+
+    pub v: TypedValue,
+    pub added: bool,
+}
+
+/// What a transaction would do if it were committed.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct TxPreview {
+    /// The entid each tempid would be given.
+    pub tempids: BTreeMap<String, Entid>,
+
+    /// The datoms that would be asserted or retracted, in the order they'd be applied, leaving out
+    /// the transaction's own `:db/txInstant`.
+    pub datoms: Vec<PreviewDatom>,
+
+    /// `true` if the transaction would change nothing.
+    pub noop: bool,
+}
+
+impl TxPreview {
+    /// The preview as text: a line per tempid and its entid, then a line per datom, prefixed `+`
+    /// for assertions and `-` for retractions.
+    pub fn to_diff(&self) -> String {
+        let mut out = String::new();
+        for (tempid, e) in self.tempids.iter() {
+            out.push_str(&format!("{:?} {}\n", tempid, e));
+        }
+        for datom in self.datoms.iter() {
+            out.push_str(&format!("{} [{} {} {}]\n",
+                                  if datom.added { "+" } else { "-" },
+                                  datom.e,
+                                  datom.a,
+                                  datom.v.to_edn_value_pair().0));
+        }
+        out
+    }
+}
+
+/// The preview of the transaction described by `report`, read from the log before it is rolled
+/// back.  `schema` must include any attributes the transaction installs.
+pub fn read(conn: &rusqlite::Connection, schema: &Schema, report: &TxReport, cipher: Option<&Cipher>) -> Result<TxPreview> {
+    let tx_instant = *schema.require_entid(&":db/txInstant".to_string())?;
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT e, a, v, value_type_tag, added FROM transactions WHERE tx = ? ORDER BY rowid")?;
+    let rows: Result<Vec<Option<PreviewDatom>>> = stmt.query_and_then(&[&report.tx_id], |row| {
+        let e: Entid = row.get_checked(0)?;
+        let a: Entid = row.get_checked(1)?;
+        if e == report.tx_id && a == tx_instant {
+            return Ok(None);
+        }
+        let v: rusqlite::types::Value = row.get_checked(2)?;
+        let value_type_tag: i32 = row.get_checked(3)?;
+
+        let v = read_log_value(conn, schema, a, v, &value_type_tag, cipher)?;
+        Ok(Some(PreviewDatom {
+            e: e,
+            a: schema.require_ident(&a)?.clone(),
+            v: v,
+            added: row.get_checked(4)?,
+        }))
+    })?.collect();
+
+    Ok(TxPreview {
+        tempids: report.tempids.clone(),
+        datoms: rows?.into_iter().filter_map(|datom| datom).collect(),
+        noop: report.noop,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use store::Store;
+
+    #[test]
+    fn test_preview_transaction() {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+        let a = store.transact(r#"[[:db/add "a" :test/name "Al"]]"#).unwrap().tempids["a"];
+
+        let transaction = format!(r#"[[:db/add {} :test/name "Alice"] [:db/add "b" :test/name "Bob"]]"#, a);
+        let preview = store.preview_transaction(&transaction).unwrap();
+        let b = preview.tempids["b"];
+        assert!(!preview.noop);
+        let diff = preview.to_diff();
+        let mut lines: Vec<&str> = diff.lines().collect();
+        lines[1..].sort();
+        assert_eq!(lines, vec![format!("\"b\" {}", b),
+                               format!("+ [{} :test/name \"Alice\"]", a),
+                               format!("+ [{} :test/name \"Bob\"]", b),
+                               format!("- [{} :test/name \"Al\"]", a)]);
+
+        // Nothing was committed.
+        assert_eq!(store.count(r#"[:find ?e :where [?e :test/name "Bob"]]"#).unwrap(), 0);
+        assert_eq!(store.count(r#"[:find ?e :where [?e :test/name "Al"]]"#).unwrap(), 1);
+
+        // Transactions that would fail report why.
+        assert!(store.preview_transaction(r#"[[:db/add "c" :test/missing "x"]]"#).is_err());
+        assert!(store.preview_transaction(&format!(r#"[[:db/add {} :test/name "Al"]]"#, a)).unwrap().noop);
+    }
+}
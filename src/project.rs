@@ -0,0 +1,147 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Projecting query results and entities into EDN data.
+//!
+//! Generic tools -- pretty-printers, servers, language bridges -- can consume a single
+//! representation: a `Value`.  Query results become a vector of maps, one per row, keyed by the
+//! `:find` elements: their aliases, like `:person/id` for `(?x :as :person/id)`, or else their
+//! variables, like `?x`, or `(count ?x)`.  Pulled entities become maps keyed by attribute, like
+//! `{:db/id 65536 :person/name "Alice"}`, with a vector of values for cardinality-many
+//! attributes.
+//!
+//! Refs are projected as their entids, and keywords, like enumerated values, as keywords.  Pulled
+//! entities hold refs to entities with idents as those idents; see `resolve_idents`.
+
+use std::collections::{BTreeMap, LinkedList};
+
+use edn::{Keyword, NamespacedKeyword, PlainSymbol, Value};
+use mentat_db::{Entid, Schema, TypedValue, to_namespaced_keyword};
+use mentat_query::{Element, FindSpec};
+
+use query::QueryResults;
+
+/// A keyword from its EDN text, like `:person/id` or `:id`.
+fn keyword(text: &str) -> Value {
+    match to_namespaced_keyword(text) {
+        Some(keyword) => Value::NamespacedKeyword(keyword),
+        None => Value::Keyword(Keyword::new(text.trim_left_matches(':'))),
+    }
+}
+
+/// The key under which `element`'s values are projected.
+pub fn element_key(element: &Element) -> Value {
+    match element {
+        &Element::Variable(ref var) => Value::PlainSymbol(var.0.clone()),
+        &Element::Aliased(_, ref alias) => keyword(alias),
+        &Element::Count(ref var) => {
+            let mut list = LinkedList::new();
+            list.push_back(Value::PlainSymbol(PlainSymbol::new("count")));
+            list.push_back(Value::PlainSymbol(var.0.clone()));
+            Value::List(list)
+        },
+    }
+}
+
+/// A value as EDN.
+pub fn value(value: &TypedValue) -> Value {
+    value.to_edn_value_pair().0
+}
+
+/// `value`, or, if it's a ref to an entity with an ident in `schema`, that ident as a keyword.
+pub fn resolve_ident(schema: &Schema, value: TypedValue) -> TypedValue {
+    match value {
+        TypedValue::Ref(e) => {
+            match schema.get_ident(&e).and_then(|ident| to_namespaced_keyword(ident)) {
+                Some(keyword) => TypedValue::Keyword(keyword),
+                None => TypedValue::Ref(e),
+            }
+        },
+        _ => value,
+    }
+}
+
+/// `pulled`, keyed by ident as `Store::pull_raw` returns it, with each ref to an entity with an
+/// ident resolved to that ident.
+pub fn resolve_idents(schema: &Schema, pulled: BTreeMap<String, Vec<TypedValue>>) -> BTreeMap<String, Vec<TypedValue>> {
+    pulled.into_iter()
+        .map(|(ident, values)| (ident, values.into_iter().map(|value| resolve_ident(schema, value)).collect()))
+        .collect()
+}
+
+/// `results` as a vector of maps, one per row, keyed by the elements of `find_spec`, which must be
+/// the find spec that produced them.  Missing scalar and tuple results give an empty vector.
+pub fn results(results: &QueryResults, find_spec: &FindSpec) -> Value {
+    let keys: Vec<Value> = find_spec.elements().into_iter().map(element_key).collect();
+    let row = |row: &[TypedValue]| -> Value {
+        Value::Map(keys.iter().cloned().zip(row.iter().map(value)).collect())
+    };
+    Value::Vector(match results {
+        &QueryResults::Scalar(ref x) => x.iter().map(|x| row(&[x.clone()])).collect(),
+        &QueryResults::Tuple(ref x) => x.iter().map(|x| row(x)).collect(),
+        &QueryResults::Coll(ref xs) => xs.iter().map(|x| row(&[x.clone()])).collect(),
+        &QueryResults::Rel(ref rows) => rows.iter().map(|x| row(x)).collect(),
+    })
+}
+
+/// The attributes `pulled` for entity `e`, keyed by ident as `Store::pull` returns them, as a map
+/// keyed by attribute keyword, with `:db/id` holding `e`.  Cardinality-many attributes hold a
+/// vector of values.
+pub fn entity(schema: &Schema, e: Entid, pulled: &BTreeMap<String, Vec<TypedValue>>) -> Value {
+    let mut map = BTreeMap::new();
+    map.insert(Value::NamespacedKeyword(NamespacedKeyword::new("db", "id")), Value::Integer(e));
+    for (ident, values) in pulled.iter() {
+        let multival = schema.attribute_for_ident_str(ident)
+            .map_or(true, |(attribute, _)| attribute.multival);
+        let v = if multival || values.len() != 1 {
+            Value::Vector(values.iter().map(value).collect())
+        } else {
+            value(&values[0])
+        };
+        map.insert(keyword(ident), v);
+    }
+    Value::Map(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use edn;
+
+    use store::Store;
+
+    #[test]
+    fn test_project_results() {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]
+                           [:db/add "t" :db/ident :test/tag]
+                           [:db/add "t" :db/valueType :db.type/keyword]
+                           [:db/add "t" :db/cardinality :db.cardinality/many]]"#).unwrap();
+        let alice = store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                                       [:db/add "a" :test/tag :tag/friend]
+                                       [:db/add "a" :test/tag :tag/work]]"#).unwrap().tempids["a"];
+
+        let projected = store.q_once_edn(r#"[:find ?e (?n :as :person/name) :where [?e :test/name ?n]]"#).unwrap();
+        assert_eq!(projected, edn::parse::value(&format!(r#"[{{?e {} :person/name "Alice"}}]"#, alice)).unwrap());
+
+        assert_eq!(store.q_once_edn(r#"[:find (count ?t) . :where [_ :test/tag ?t]]"#).unwrap(),
+                   edn::parse::value("[{(count ?t) 2}]").unwrap());
+        assert_eq!(store.q_once_edn(r#"[:find ?e . :where [?e :test/name "Bob"]]"#).unwrap(),
+                   Value::Vector(vec![]));
+
+        assert_eq!(store.pull_edn(alice, &[":test/name", ":test/tag"]).unwrap(),
+                   edn::parse::value(&format!(r#"{{:db/id {} :test/name "Alice" :test/tag [:tag/friend :tag/work]}}"#, alice)).unwrap());
+        assert_eq!(store.entity_edn(alice).unwrap(), store.pull_edn(alice, &[":test/name", ":test/tag"]).unwrap());
+    }
+}
@@ -0,0 +1,662 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use rusqlite;
+use rusqlite::types::{ToSql, ToSqlOutput};
+
+use edn::NamespacedKeyword;
+
+use mentat_db::{Cipher, Entid, Schema, TypedValue, ValueType, decrypt_sql_value_pair};
+use mentat_query_parser::{parse_find_string, render_error};
+use mentat_query_translator::{
+    MAX_SQL_VARIABLES,
+    algebrize,
+    find_spec_names,
+    find_spec_variables,
+    query_to_select,
+    translate,
+    translate_with_hints,
+    translate_with_inputs,
+    translate_with_relations,
+};
+
+pub use mentat_query::{
+    Element,
+    FindQuery,
+    FindSpec,
+    PointInTime,
+    Variable,
+};
+use mentat_query::{
+    FnArg,
+    NonIntegerConstant,
+    Pattern,
+    PatternNonValuePlace,
+    PatternValuePlace,
+    WhereClause,
+};
+pub use mentat_query_translator::{
+    Dependencies,
+    EmptyBecause,
+    IndexHint,
+    IndexHints,
+    QueryDependencies,
+    QueryInputs,
+    RelationInputs,
+    SQLQuery,
+};
+
+use errors::*;
+
+/// The results of running a query, shaped by its find spec.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum QueryResults {
+    Scalar(Option<TypedValue>),
+    Tuple(Option<Vec<TypedValue>>),
+    Coll(Vec<TypedValue>),
+    Rel(Vec<Vec<TypedValue>>),
+}
+
+/// The results of running a query, together with the transaction they reflect.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct QueryOutput {
+    /// The store's latest transaction when the query ran.  Running the query again gives the same
+    /// results for as long as this is still the latest transaction, so callers can detect stale
+    /// results by comparing basis transactions.  `:as-of` queries are stamped the same way.
+    pub basis_tx: Entid,
+
+    /// The names of the `:find` elements, in order: their variables, like `?x`, or aliases.
+    pub columns: Vec<String>,
+
+    pub results: QueryResults,
+
+    /// Why the results are empty, if the query was known to match nothing without running it.
+    pub empty_because: Option<EmptyBecause>,
+}
+
+/// How SQLite runs a translated query.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct QueryPlan {
+    /// The SQL that would run; empty if the query is known to be empty.
+    pub sql: String,
+
+    /// The detail of each step of SQLite's `EXPLAIN QUERY PLAN`, in order.
+    pub steps: Vec<String>,
+
+    /// The index each top-level pattern reads through, keyed by the pattern's position in `:where`,
+    /// or `None` if it scans its table.
+    pub pattern_indexes: BTreeMap<usize, Option<String>>,
+
+    /// Rewrites of the query that would likely run faster; see `suggest_rewrites`.
+    pub rewrites: Vec<Rewrite>,
+}
+
+/// The number of datoms a leading pattern without an index must read before `explain` suggests
+/// starting the query elsewhere.
+pub const UNANCHORED_ROWS: i64 = 1000;
+
+/// The number of legs an `or` may have before `explain` suggests binding an input instead.
+pub const OR_FAN_OUT: usize = 4;
+
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum RewriteKind {
+    /// A fulltext search for a term with a leading wildcard, like `*fox`, which the fulltext index
+    /// can't match.
+    LeadingWildcard,
+    /// The first clause is a pattern that reads every datom of an unindexed attribute.
+    Unanchored,
+    /// An `or` with more than `OR_FAN_OUT` legs, each run as a subquery.
+    OrFanOut,
+}
+
+/// A suggested rewrite of one top-level clause of a query.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct Rewrite {
+    pub kind: RewriteKind,
+
+    /// The clause's position in `:where`.
+    pub clause: usize,
+
+    /// The estimated number of rows the clause reads, which triggered the suggestion.
+    pub estimated_rows: i64,
+
+    /// The rewrite, in words.
+    pub suggestion: String,
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "clause {} (about {} rows): {}", self.clause, self.estimated_rows, self.suggestion)
+    }
+}
+
+/// How long each phase of running a query took.  Executing includes fetching and decrypting the
+/// rows; projecting shapes them as the find spec asks.
+#[derive(Clone,Copy,Debug,Default,Eq,PartialEq)]
+pub struct QueryTimings {
+    pub parse: Duration,
+    pub algebrize: Duration,
+    pub translate: Duration,
+    pub execute: Duration,
+    pub project: Duration,
+}
+
+impl QueryTimings {
+    pub fn total(&self) -> Duration {
+        self.parse + self.algebrize + self.translate + self.execute + self.project
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1_000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+impl fmt::Display for QueryTimings {
+    /// Like `parse 0.012ms, algebrize 0.030ms, ..., total 0.153ms`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "parse {:.3}ms, algebrize {:.3}ms, translate {:.3}ms, execute {:.3}ms, project {:.3}ms, total {:.3}ms",
+               millis(self.parse), millis(self.algebrize), millis(self.translate), millis(self.execute), millis(self.project), millis(self.total()))
+    }
+}
+
+impl fmt::Display for QueryPlan {
+    /// The SQL, then a line per step of the plan, then a line per suggested rewrite.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.sql)?;
+        for step in self.steps.iter() {
+            writeln!(f, "  {}", step)?;
+        }
+        for rewrite in self.rewrites.iter() {
+            writeln!(f, "suggestion: {}", rewrite)?;
+        }
+        Ok(())
+    }
+}
+
+/// A single result row, keyed by `:find` element name: the variable, like `?x`, or its alias.
+pub type KeyedRow = BTreeMap<String, TypedValue>;
+
+impl QueryResults {
+    /// Key each result row by the names of the elements of `find_spec`, which must be the find
+    /// spec that produced these results.
+    pub fn into_keyed(self, find_spec: &FindSpec) -> Vec<KeyedRow> {
+        let names = find_spec_names(find_spec);
+        let rows: Vec<Vec<TypedValue>> = match self {
+            QueryResults::Scalar(value) => value.into_iter().map(|v| vec![v]).collect(),
+            QueryResults::Tuple(row) => row.into_iter().collect(),
+            QueryResults::Coll(values) => values.into_iter().map(|v| vec![v]).collect(),
+            QueryResults::Rel(rows) => rows,
+        };
+        rows.into_iter()
+            .map(|row| names.iter().cloned().zip(row.into_iter()).collect())
+            .collect()
+    }
+}
+
+/// Parse the given query string.
+pub fn parse_query(query: &str) -> Result<FindQuery> {
+    let parsed = parse_find_string(query).map_err(|e| ErrorKind::QueryParseError(render_error(query, &e)))?;
+    Ok(parsed)
+}
+
+/// The first attribute named by ident in the patterns of `clauses`, including those in `or` and
+/// `not` clauses, that `schema` lacks.
+fn unknown_attribute(schema: &Schema, clauses: Vec<&WhereClause>) -> Option<String> {
+    for clause in clauses {
+        let unknown = match clause {
+            &WhereClause::Pattern(Pattern { attribute: PatternNonValuePlace::Ident(ref ident), .. }) => {
+                let name = ident.to_string();
+                match schema.get_entid(&name).and_then(|entid| schema.attribute_for_entid(entid)) {
+                    Some(_) => None,
+                    None => Some(name),
+                }
+            },
+            &WhereClause::OrJoin(ref or_join) =>
+                unknown_attribute(schema, or_join.clauses.iter().flat_map(|leg| leg.clauses()).collect()),
+            &WhereClause::NotJoin(ref not_join) =>
+                unknown_attribute(schema, not_join.clauses.iter().collect()),
+            _ => None,
+        };
+        if unknown.is_some() {
+            return unknown;
+        }
+    }
+    None
+}
+
+/// Fail with `UnknownAttribute` if a pattern of `query` names an attribute that `schema` lacks,
+/// rather than letting the translator treat the pattern as matching nothing.
+pub fn require_known_attributes(schema: &Schema, query: &FindQuery) -> Result<()> {
+    match unknown_attribute(schema, query.where_clauses.iter().collect()) {
+        Some(name) => bail!(ErrorKind::UnknownAttribute(name)),
+        None => Ok(()),
+    }
+}
+
+/// Parse and translate the given query string in the context of the given `schema`.
+pub fn prepare_query(schema: &Schema, query: &str) -> Result<SQLQuery> {
+    let sql_query = translate(schema, &parse_query(query)?)?;
+    Ok(sql_query)
+}
+
+/// Parse and translate the given query string, with index hints for some of its patterns.
+pub fn prepare_query_with_hints(schema: &Schema, query: &str, hints: &IndexHints) -> Result<SQLQuery> {
+    let sql_query = translate_with_hints(schema, &parse_query(query)?, QueryInputs::new(), hints)?;
+    Ok(sql_query)
+}
+
+/// Return the latest transaction in the store.
+pub fn basis_tx(conn: &rusqlite::Connection) -> Result<Entid> {
+    let tx = conn.query_row("SELECT max(tx) FROM transactions", &[], |row| row.get(0))?;
+    Ok(tx)
+}
+
+/// Translate the given parsed query, binding its `:in` variables to `inputs`, and run it.
+pub fn run_find_query(conn: &rusqlite::Connection, schema: &Schema, query: &FindQuery, inputs: QueryInputs, cipher: Option<&Cipher>) -> Result<QueryOutput> {
+    let sql_query = translate_with_inputs(schema, query, inputs)?;
+    run_query(conn, &sql_query, cipher)
+}
+
+/// Like `run_find_query`, but binding the query's collection and relation inputs to the rows in
+/// `relations` too.  Inputs too large to pass as arguments are written to temporary tables.
+pub fn run_find_query_with_relations(conn: &rusqlite::Connection, schema: &Schema, query: &FindQuery, inputs: QueryInputs, relations: RelationInputs, cipher: Option<&Cipher>) -> Result<QueryOutput> {
+    let sql_query = translate_with_relations(schema, query, inputs, relations)?;
+    run_query(conn, &sql_query, cipher)
+}
+
+/// Run `f` with the temporary tables the given query reads in place: those holding its large
+/// inputs, and those holding the subqueries it reads more than once.  The tables are dropped
+/// afterwards whether or not `f` succeeds.
+///
+/// The result is `f`'s, or the failure to create the tables: failing to drop a table doesn't hide
+/// it.  A table left behind is dropped before its name is next used.
+fn with_temp_tables<T, F>(conn: &rusqlite::Connection, query: &SQLQuery, f: F) -> Result<T> where F: FnOnce() -> Result<T> {
+    let result = create_input_tables(conn, query)
+        .and_then(|_| create_temp_tables(conn, query))
+        .and_then(|_| f());
+    let names = query.input_tables.iter().map(|table| &table.name)
+        .chain(query.temp_tables.iter().map(|table| &table.name));
+    for name in names {
+        let _ = drop_temp_table(conn, name);
+    }
+    result
+}
+
+fn drop_temp_table(conn: &rusqlite::Connection, name: &str) -> Result<()> {
+    conn.execute_batch(&format!("DROP TABLE IF EXISTS temp.{}", name))?;
+    Ok(())
+}
+
+fn create_temp_tables(conn: &rusqlite::Connection, query: &SQLQuery) -> Result<()> {
+    for table in query.temp_tables.iter() {
+        let values: Vec<(ToSqlOutput, i32)> = table.args.iter().map(|&(_, ref value)| value.to_sql_value_pair()).collect();
+        let params: Vec<(&str, &ToSql)> = table.args.iter().zip(values.iter())
+            .map(|(&(ref name, _), &(ref value, _))| (name.as_str(), value as &ToSql))
+            .collect();
+        drop_temp_table(conn, &table.name)?;
+        conn.execute_named(&format!("CREATE TEMP TABLE {} AS {}", table.name, table.sql), &params[..])?;
+    }
+    Ok(())
+}
+
+fn create_input_tables(conn: &rusqlite::Connection, query: &SQLQuery) -> Result<()> {
+    for table in query.input_tables.iter() {
+        let mut columns: Vec<String> = vec![];
+        for i in 0..table.width {
+            columns.push(format!("v{}", i));
+            columns.push(format!("value_type_tag{}", i));
+        }
+        drop_temp_table(conn, &table.name)?;
+        conn.execute_batch(&format!("CREATE TEMP TABLE {} ({})", table.name, columns.join(", ")))?;
+
+        // Insert as many rows at a time as SQLite allows arguments.
+        let row_sql = format!("({})", vec!["?"; columns.len()].join(", "));
+        let chunk_size = cmp::max(1, MAX_SQL_VARIABLES / cmp::max(1, columns.len()));
+        for rows in table.rows.chunks(chunk_size) {
+            let values: Vec<(ToSqlOutput, i32)> = rows.iter()
+                .flat_map(|row| row.iter())
+                .map(|value| value.to_sql_value_pair())
+                .collect();
+            let mut params: Vec<&ToSql> = Vec::with_capacity(2 * values.len());
+            for &(ref value, ref value_type_tag) in values.iter() {
+                params.push(value);
+                params.push(value_type_tag);
+            }
+            let sql = format!("INSERT INTO temp.{} VALUES {}", table.name, vec![row_sql.as_str(); rows.len()].join(", "));
+            conn.execute(&sql, &params[..])?;
+        }
+    }
+    Ok(())
+}
+
+/// Run the given translated `query` against `conn`, stamping the results with their basis
+/// transaction.  The values of encrypted attributes are decrypted with `cipher`; without one,
+/// results that include them fail with `MissingCipher`.
+///
+/// A query known to match nothing isn't run at all.
+pub fn run_query(conn: &rusqlite::Connection, query: &SQLQuery, cipher: Option<&Cipher>) -> Result<QueryOutput> {
+    run_query_timed(conn, query, cipher, &mut QueryTimings::default())
+}
+
+/// Like `run_query`, but recording how long the query took to execute and project in `timings`.
+fn run_query_timed(conn: &rusqlite::Connection, query: &SQLQuery, cipher: Option<&Cipher>, timings: &mut QueryTimings) -> Result<QueryOutput> {
+    if query.empty_because.is_some() {
+        return Ok(QueryOutput {
+            basis_tx: basis_tx(conn)?,
+            columns: find_spec_names(&query.find_spec),
+            results: shape_results(&query.find_spec, vec![]),
+            empty_because: query.empty_because.clone(),
+        });
+    }
+
+    // Read the basis and the results inside one read transaction, so that they agree even if
+    // another connection commits in between.  A savepoint nests inside any open transaction.
+    conn.execute_batch("SAVEPOINT run_query")?;
+    let output = with_temp_tables(conn, query, || {
+        Ok(QueryOutput {
+            basis_tx: basis_tx(conn)?,
+            columns: find_spec_names(&query.find_spec),
+            results: {
+                let start = Instant::now();
+                let rows = query_rows(conn, query, cipher)?;
+                timings.execute = start.elapsed();
+
+                let start = Instant::now();
+                let results = shape_results(&query.find_spec, rows);
+                timings.project = start.elapsed();
+                results
+            },
+            empty_because: None,
+        })
+    });
+    conn.execute_batch("RELEASE run_query")?;
+    output
+}
+
+/// Shape result rows according to the given find spec.
+fn shape_results(find_spec: &FindSpec, results: Vec<Vec<TypedValue>>) -> QueryResults {
+    match *find_spec {
+        // A count of nothing is zero, even if the query isn't run.
+        FindSpec::FindScalar(Element::Count(_)) => {
+            QueryResults::Scalar(Some(results.into_iter().next().and_then(|r| r.into_iter().next()).unwrap_or(TypedValue::Long(0))))
+        },
+        FindSpec::FindScalar(_) => QueryResults::Scalar(results.into_iter().next().and_then(|r| r.into_iter().next())),
+        FindSpec::FindTuple(_) => QueryResults::Tuple(results.into_iter().next()),
+        FindSpec::FindColl(_) => QueryResults::Coll(results.into_iter().filter_map(|r| r.into_iter().next()).collect()),
+        FindSpec::FindRel(_) => QueryResults::Rel(results),
+    }
+}
+
+/// Run the given translated `query`, returning its rows of decrypted values.
+fn query_rows(conn: &rusqlite::Connection, query: &SQLQuery, cipher: Option<&Cipher>) -> Result<Vec<Vec<TypedValue>>> {
+    let width = find_spec_variables(&query.find_spec).len();
+
+    let values: Vec<(ToSqlOutput, i32)> = query.args.iter().map(|&(_, ref value)| value.to_sql_value_pair()).collect();
+    let params: Vec<(&str, &ToSql)> = query.args.iter().zip(values.iter())
+        .map(|(&(ref name, _), &(ref value, _))| (name.as_str(), value as &ToSql))
+        .collect();
+
+    let mut stmt: rusqlite::Statement = conn.prepare(&query.sql)?;
+    let mut rows = stmt.query_named(&params[..])?;
+
+    let mut results: Vec<Vec<TypedValue>> = vec![];
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let mut result = Vec::with_capacity(width);
+        for i in 0..width {
+            let value: rusqlite::types::Value = row.get_checked((2 * i) as i32)?;
+            let value_type_tag: i32 = row.get_checked((2 * i + 1) as i32)?;
+            result.push(decrypt_sql_value_pair(value, &value_type_tag, cipher)?);
+        }
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// Parse, algebrize, translate, and run the given query string, timing each phase.
+pub fn time_query(conn: &rusqlite::Connection, schema: &Schema, query: &str, cipher: Option<&Cipher>) -> Result<(QueryOutput, QueryTimings)> {
+    let mut timings = QueryTimings::default();
+
+    let start = Instant::now();
+    let parsed = parse_query(query)?;
+    timings.parse = start.elapsed();
+
+    let start = Instant::now();
+    let algebrized = algebrize(schema, &parsed)?;
+    timings.algebrize = start.elapsed();
+
+    let start = Instant::now();
+    let sql_query = query_to_select(algebrized)?;
+    timings.translate = start.elapsed();
+
+    let output = run_query_timed(conn, &sql_query, cipher, &mut timings)?;
+    Ok((output, timings))
+}
+
+/// Ask SQLite how it would run the given translated `query` against `conn`.
+pub fn explain_query(conn: &rusqlite::Connection, query: &SQLQuery) -> Result<QueryPlan> {
+    let mut plan = QueryPlan {
+        sql: query.sql.clone(),
+        steps: vec![],
+        pattern_indexes: BTreeMap::new(),
+        rewrites: vec![],
+    };
+    if query.empty_because.is_some() {
+        return Ok(plan);
+    }
+
+    let values: Vec<(ToSqlOutput, i32)> = query.args.iter().map(|&(_, ref value)| value.to_sql_value_pair()).collect();
+    let params: Vec<(&str, &ToSql)> = query.args.iter().zip(values.iter())
+        .map(|(&(ref name, _), &(ref value, _))| (name.as_str(), value as &ToSql))
+        .collect();
+
+    plan.steps = with_temp_tables(conn, query, || {
+        let mut stmt: rusqlite::Statement = conn.prepare(&format!("EXPLAIN QUERY PLAN {}", query.sql))?;
+        let mut rows = stmt.query_named(&params[..])?;
+        let mut steps: Vec<String> = vec![];
+        while let Some(row) = rows.next() {
+            steps.push(row?.get_checked(3)?);
+        }
+        Ok(steps)
+    })?;
+
+    // Steps name the table alias they read, like `SEARCH TABLE datoms AS datoms00 USING INDEX
+    // idx_datoms_avet (a=?)`, or just `SEARCH datoms00 USING INDEX ...` in newer SQLites.
+    for (&i, alias) in query.pattern_aliases.iter() {
+        let step = plan.steps.iter().find(|step| step.split_whitespace().any(|word| word == alias));
+        if let Some(step) = step {
+            let index = step.find(" INDEX ")
+                .and_then(|start| step[start + " INDEX ".len()..].split_whitespace().next())
+                .map(|index| index.to_string());
+            plan.pattern_indexes.insert(i, index);
+        }
+    }
+    Ok(plan)
+}
+
+/// The number of datoms of `attribute`, or 0 if it isn't an attribute of `schema`.
+fn attribute_rows(conn: &rusqlite::Connection, schema: &Schema, attribute: &NamespacedKeyword) -> Result<i64> {
+    match schema.get_entid(&attribute.to_string()) {
+        Some(a) => Ok(conn.query_row("SELECT COUNT(*) FROM datoms WHERE a = ?", &[a], |row| row.get(0))?),
+        None => Ok(0),
+    }
+}
+
+/// The number of datoms of `attribute` with the constant value in `place`, or with any value if
+/// `place` isn't a constant, or is one that can't be looked up by value, like a fulltext string.
+fn pattern_rows(conn: &rusqlite::Connection, schema: &Schema, attribute: &NamespacedKeyword, place: &PatternValuePlace) -> Result<i64> {
+    let (a, value_type, fulltext) = match schema.attribute_for_ident(attribute) {
+        Some((attribute, a)) => (a, attribute.value_type.clone(), attribute.fulltext),
+        None => return Ok(0),
+    };
+    let value = match *place {
+        PatternValuePlace::EntidOrInteger(x) => match value_type {
+            ValueType::Ref => TypedValue::Ref(x),
+            ValueType::Double => TypedValue::Double((x as f64).into()),
+            _ => TypedValue::Long(x),
+        },
+        PatternValuePlace::Ident(ref kw) if value_type == ValueType::Keyword => TypedValue::Keyword(kw.clone()),
+        PatternValuePlace::Ident(ref kw) => match schema.get_entid(&kw.to_string()) {
+            Some(&entid) => TypedValue::Ref(entid),
+            None => return Ok(0),
+        },
+        PatternValuePlace::Constant(NonIntegerConstant::Text(ref x)) if !fulltext => TypedValue::String(x.clone()),
+        PatternValuePlace::Constant(NonIntegerConstant::Boolean(x)) => TypedValue::Boolean(x),
+        PatternValuePlace::Constant(NonIntegerConstant::Float(x)) => TypedValue::Double(x),
+        PatternValuePlace::Constant(NonIntegerConstant::Bytes(ref x)) => TypedValue::Bytes(x.clone()),
+        // Instants are stored as longs.
+        PatternValuePlace::Constant(NonIntegerConstant::Instant(x)) => TypedValue::Long(x),
+        _ => return attribute_rows(conn, schema, attribute),
+    };
+    let (v, value_type_tag) = value.to_sql_value_pair();
+    Ok(conn.query_row("SELECT COUNT(*) FROM datoms WHERE a = ? AND v = ? AND value_type_tag = ?",
+                      &[&a, &v, &value_type_tag], |row| row.get(0))?)
+}
+
+/// Whether `attribute` is indexed by value, so that a pattern with a constant value reads only the
+/// datoms with that value.
+fn indexed(schema: &Schema, attribute: &NamespacedKeyword) -> bool {
+    schema.attribute_for_ident(attribute)
+        .map_or(false, |(attribute, _)| attribute.index || attribute.unique_value || attribute.unique_identity)
+}
+
+/// Suggest rewrites of the top-level clauses of `query` that follow common anti-patterns, with the
+/// number of rows each clause is estimated to read in `conn`:
+///
+/// - a fulltext search for a term with a leading wildcard, like `*fox`;
+/// - a first pattern that reads at least `UNANCHORED_ROWS` datoms of an unindexed attribute, where a
+///   later clause or an index could narrow it;
+/// - an `or` with more than `OR_FAN_OUT` legs, estimated by the datoms matching the first pattern of
+///   each leg, with its constant value if it has one.
+pub fn suggest_rewrites(conn: &rusqlite::Connection, schema: &Schema, query: &FindQuery) -> Result<Vec<Rewrite>> {
+    let mut rewrites = vec![];
+    for (i, clause) in query.where_clauses.iter().enumerate() {
+        match clause {
+            &WhereClause::WhereFn(ref where_fn) if where_fn.operator.0 == "fulltext" => {
+                let (attribute, terms) = match (where_fn.args.get(1), where_fn.args.get(2)) {
+                    (Some(&FnArg::Ident(ref attribute)), Some(&FnArg::Constant(NonIntegerConstant::Text(ref terms)))) => (attribute, terms),
+                    _ => continue,
+                };
+                if terms.split_whitespace().any(|term| term.starts_with('*') || term.starts_with('%')) {
+                    let rows = attribute_rows(conn, schema, attribute)?;
+                    rewrites.push(Rewrite {
+                        kind: RewriteKind::LeadingWildcard,
+                        clause: i,
+                        estimated_rows: rows,
+                        suggestion: format!("the fulltext index can't match a leading wildcard in \"{}\"; search for whole words, or a prefix like `fox*`", terms),
+                    });
+                }
+            },
+            &WhereClause::Pattern(ref pattern) if i == 0 => {
+                let attribute = match (&pattern.entity, &pattern.attribute) {
+                    (&PatternNonValuePlace::Variable(_), &PatternNonValuePlace::Ident(ref attribute)) |
+                    (&PatternNonValuePlace::Placeholder, &PatternNonValuePlace::Ident(ref attribute)) => attribute,
+                    _ => continue,
+                };
+                let constant = match pattern.value {
+                    PatternValuePlace::Placeholder | PatternValuePlace::Variable(_) => false,
+                    _ => true,
+                };
+                if indexed(schema, attribute) || !(constant || query.where_clauses.len() > 1) {
+                    continue;
+                }
+                let rows = attribute_rows(conn, schema, attribute)?;
+                if rows >= UNANCHORED_ROWS {
+                    rewrites.push(Rewrite {
+                        kind: RewriteKind::Unanchored,
+                        clause: i,
+                        estimated_rows: rows,
+                        suggestion: format!("the query starts by reading every {} datom; start from a clause that binds fewer rows, like a constant value of an indexed attribute, or index {} with :db/index", attribute.to_string(), attribute.to_string()),
+                    });
+                }
+            },
+            &WhereClause::OrJoin(ref or_join) if or_join.clauses.len() > OR_FAN_OUT => {
+                let mut rows = 0;
+                for leg in or_join.clauses.iter() {
+                    for clause in leg.clauses() {
+                        if let &WhereClause::Pattern(Pattern { attribute: PatternNonValuePlace::Ident(ref attribute), ref value, .. }) = clause {
+                            rows += pattern_rows(conn, schema, attribute, value)?;
+                            break;
+                        }
+                    }
+                }
+                rewrites.push(Rewrite {
+                    kind: RewriteKind::OrFanOut,
+                    clause: i,
+                    estimated_rows: rows,
+                    suggestion: format!("the `or` runs a subquery for each of its {} legs; if they differ only in a constant, bind it with a collection input, like `:in $ [?v ...]`", or_join.clauses.len()),
+                });
+            },
+            _ => (),
+        }
+    }
+    Ok(rewrites)
+}
+
+/// Run `summary`, a statement over the results of the given translated query that yields a single
+/// value, like `SELECT COUNT(*), 5 FROM (...)`, and return its value.  The query's find spec is
+/// replaced with a scalar spec of `element`.
+fn run_summary<F>(conn: &rusqlite::Connection, mut sql_query: SQLQuery, element: Element, summary: F) -> Result<TypedValue>
+    where F: FnOnce(&str) -> String {
+    sql_query.sql = summary(&sql_query.sql);
+    sql_query.find_spec = FindSpec::FindScalar(element);
+    sql_query.empty_because = None;
+    match run_query(conn, &sql_query, None)?.results {
+        QueryResults::Scalar(Some(value)) => Ok(value),
+        results => unreachable!("summarized {:?}", results),
+    }
+}
+
+/// Return the number of distinct results of the given parsed query, counted by SQLite rather than
+/// by fetching them.  A count query, like `[:find (count ?e) . ...]`, returns its count.
+pub fn count_query(conn: &rusqlite::Connection, schema: &Schema, query: &FindQuery) -> Result<i64> {
+    let sql_query = translate(schema, query)?;
+    if sql_query.empty_because.is_some() {
+        return Ok(0);
+    }
+    let counted = match query.find_spec {
+        FindSpec::FindScalar(Element::Count(_)) => true,
+        _ => false,
+    };
+    let var = find_spec_variables(&query.find_spec)[0].clone();
+    let count = run_summary(conn, sql_query, Element::Count(var), |sql| {
+        if counted { sql.to_string() } else { format!("SELECT COUNT(*), 5 FROM ({})", sql) }
+    })?;
+    match count {
+        TypedValue::Long(count) => Ok(count),
+        value => unreachable!("counted {:?}", value),
+    }
+}
+
+/// Return `true` if the given parsed query has any results, without fetching them.  SQLite stops
+/// looking at the first result.
+pub fn exists_query(conn: &rusqlite::Connection, schema: &Schema, query: &FindQuery) -> Result<bool> {
+    let sql_query = translate(schema, query)?;
+    if sql_query.empty_because.is_some() {
+        return Ok(false);
+    }
+    let var = find_spec_variables(&query.find_spec)[0].clone();
+    match run_summary(conn, sql_query, Element::Variable(var), |sql| format!("SELECT EXISTS ({}), 1", sql))? {
+        TypedValue::Boolean(exists) => Ok(exists),
+        value => unreachable!("exists {:?}", value),
+    }
+}
+
+/// Parse, translate, and run the given query string once, without decrypting encrypted values.
+pub fn q_once(conn: &rusqlite::Connection, schema: &Schema, query: &str) -> Result<QueryOutput> {
+    run_query(conn, &prepare_query(schema, query)?, None)
+}
@@ -0,0 +1,268 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! An interactive shell, for `mentat repl --db path`.
+//!
+//! Each line is a command.  A line starting with `[` is a query, as is `.query QUERY`; its
+//! results are written as a table.  `.transact TRANSACTION` transacts, and writes the report as
+//! `Store::display_tx_report` does, with entids shown by ident or partition.  `.transact --dry-run
+//! TRANSACTION` shows what the transaction would do, as `TxPreview::to_diff` does, and asks before
+//! committing it.  `.timer on` writes how long each phase of each query took, as
+//! `Store::q_once_timed` reports, until `.timer off`; `.plan` writes how SQLite runs the last
+//! query, as `Store::explain` does.  `.help` lists the commands, and `.exit`, or the end of the
+//! input, leaves.  A command that fails writes the error, and the shell goes on reading.
+
+use std::io::{self, BufRead, Write};
+
+use mentat::{IndexHints, Result, Store};
+
+use script::{EXIT_IO, EXIT_OK};
+
+const PROMPT: &'static str = "mentat=> ";
+
+const HELP: &'static str = "\
+.query QUERY              Run QUERY, like [:find ?e :where [?e :db/ident _]], and show a table.
+                          A line starting with [ is a query too.
+.transact TRANSACTION     Transact TRANSACTION, like [[:db/add \"a\" :db/doc \"x\"]].
+.transact --dry-run TRANSACTION
+                          Show the tempids TRANSACTION would allocate and the datoms it would
+                          assert (+) and retract (-), and ask before committing it.
+.timer on|off             Show how long each phase of each query takes, or stop.
+.plan                     Show how SQLite runs the last query.
+.help                     Show this help.
+.exit                     Leave.
+";
+
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum Command {
+    /// The EDN text of a query.
+    Query(String),
+    /// The EDN text of a transaction.
+    Transact(String),
+    /// The EDN text of a transaction to preview, and commit if confirmed.
+    DryRun(String),
+    Timer(bool),
+    Plan,
+    Help,
+    Exit,
+}
+
+/// Parse a line of input.  Blank lines are `None`.
+pub fn parse_command(line: &str) -> Result<Option<Command>> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.starts_with('[') {
+        return Ok(Some(Command::Query(line.to_string())));
+    }
+    let (name, rest) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    };
+    let command = match (name, rest.is_empty()) {
+        (".query", false) => Command::Query(rest.to_string()),
+        (".transact", false) if rest.split_whitespace().next() == Some("--dry-run") => {
+            let transaction = rest["--dry-run".len()..].trim();
+            if transaction.is_empty() {
+                bail!(".transact --dry-run expects an argument");
+            }
+            Command::DryRun(transaction.to_string())
+        },
+        (".transact", false) => Command::Transact(rest.to_string()),
+        (".timer", false) if rest == "on" || rest == "off" => Command::Timer(rest == "on"),
+        (".timer", _) => bail!(".timer expects on or off"),
+        (".plan", true) => Command::Plan,
+        (".help", true) => Command::Help,
+        (".exit", true) => Command::Exit,
+        (".query", true) | (".transact", true) => bail!("{} expects an argument", name),
+        _ => bail!("unknown command {}; try .help", line),
+    };
+    Ok(Some(command))
+}
+
+/// Read commands from `input` and run them against `store`, writing their output to `out`, until
+/// `.exit` or the end of the input.
+pub fn run<R: BufRead, W: Write>(store: &mut Store, input: &mut R, out: &mut W) -> Result<()> {
+    let mut timer = false;
+    let mut last_query: Option<String> = None;
+    loop {
+        write!(out, "{}", PROMPT)?;
+        out.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            writeln!(out, "")?;
+            return Ok(());
+        }
+        let command = match parse_command(&line) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(e) => {
+                writeln!(out, "{}", e)?;
+                continue;
+            },
+        };
+        let executed = match command {
+            Command::Query(ref query) => {
+                last_query = Some(query.clone());
+                if timer {
+                    store.q_once_timed(query).map(|(output, timings)| format!("{}Time: {}\n", output.to_table(), timings))
+                } else {
+                    store.q_once(query).map(|output| output.to_table())
+                }
+            },
+            Command::Transact(ref transaction) => transact(store, transaction),
+            Command::DryRun(ref transaction) => dry_run(store, transaction, input, out),
+            Command::Timer(on) => {
+                timer = on;
+                Ok(format!("Timer {}.\n", if on { "on" } else { "off" }))
+            },
+            Command::Plan => match last_query {
+                Some(ref query) => store.explain(query, &IndexHints::new()).map(|plan| plan.to_string()),
+                None => Ok("No query has run yet.\n".to_string()),
+            },
+            Command::Help => Ok(HELP.to_string()),
+            Command::Exit => return Ok(()),
+        };
+        match executed {
+            Ok(text) => write!(out, "{}", text)?,
+            Err(e) => writeln!(out, "{}", e)?,
+        }
+    }
+}
+
+fn transact(store: &mut Store, transaction: &str) -> Result<String> {
+    let report = store.transact(transaction)?;
+    Ok(format!("{}\n", store.display_tx_report(&report)))
+}
+
+/// Preview `transaction`, and commit it if the answer read from `input` is yes.
+fn dry_run<R: BufRead, W: Write>(store: &mut Store, transaction: &str, input: &mut R, out: &mut W) -> Result<String> {
+    let preview = store.preview_transaction(transaction)?;
+    if preview.noop {
+        return Ok("The transaction would change nothing.\n".to_string());
+    }
+    write!(out, "{}Commit? [y/N] ", preview.to_diff())?;
+    out.flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    match answer.trim() {
+        "y" | "Y" | "yes" => transact(store, transaction),
+        _ => Ok("Not committed.\n".to_string()),
+    }
+}
+
+/// Run the shell against the store at `db`, on standard input and output, returning the exit code.
+pub fn run_stdio(db: &str) -> i32 {
+    let mut store = match Store::open(db) {
+        Ok(store) => store,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "could not open {}: {}", db, e);
+            return EXIT_IO;
+        },
+    };
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    match run(&mut store, &mut stdin.lock(), &mut stdout.lock()) {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}", e);
+            EXIT_IO
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn session(store: &mut Store, input: &str) -> String {
+        let mut out = vec![];
+        run(store, &mut Cursor::new(input.as_bytes()), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("  ").unwrap(), None);
+        assert_eq!(parse_command("[:find ?e :where [?e :db/doc _]]\n").unwrap(),
+                   Some(Command::Query("[:find ?e :where [?e :db/doc _]]".to_string())));
+        assert_eq!(parse_command(".transact [[:db/add \"a\" :db/doc \"x\"]]").unwrap(),
+                   Some(Command::Transact("[[:db/add \"a\" :db/doc \"x\"]]".to_string())));
+        assert_eq!(parse_command(".exit").unwrap(), Some(Command::Exit));
+        assert_eq!(parse_command(".transact --dry-run [[:db/add \"a\" :db/doc \"x\"]]").unwrap(),
+                   Some(Command::DryRun("[[:db/add \"a\" :db/doc \"x\"]]".to_string())));
+        assert!(parse_command(".transact").is_err());
+        assert!(parse_command(".transact --dry-run").is_err());
+        assert_eq!(parse_command(".timer off").unwrap(), Some(Command::Timer(false)));
+        assert!(parse_command(".timer").is_err());
+        assert!(parse_command(".delete [1]").is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+        let out = session(&mut store, ".transact [[:db/add \"a\" :test/name \"Alice\"]]\n\
+                                       .transact [[:db/add \"b\" :test/missing \"Bob\"]]\n\
+                                       [:find ?name :where [_ :test/name ?name]]\n\
+                                       .exit\n\
+                                       [:find ?name :where [_ :test/name ?name]]\n");
+        let lines: Vec<&str> = out.split(PROMPT).collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[1].starts_with("tx #db/id[:db.part/tx "));
+        // The shell goes on after an error.
+        assert!(!lines[2].starts_with("tx "));
+        assert_eq!(lines[3], "?name\n-----\nAlice\n");
+        // Nothing is read after `.exit`.
+        assert_eq!(lines[4], "");
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+        let a = store.transact(r#"[[:db/add "a" :test/name "Al"]]"#).unwrap().tempids["a"];
+
+        // Declining commits nothing.
+        let out = session(&mut store, &format!(".transact --dry-run [[:db/add {} :test/name \"Alice\"]]\nn\n", a));
+        assert!(out.contains(&format!("- [{} :test/name \"Al\"]\n", a)));
+        assert!(out.contains(&format!("+ [{} :test/name \"Alice\"]\n", a)));
+        assert!(out.contains("Commit? [y/N] Not committed.\n"));
+        assert_eq!(store.count(r#"[:find ?e :where [?e :test/name "Al"]]"#).unwrap(), 1);
+
+        // Confirming commits it.
+        let out = session(&mut store, &format!(".transact --dry-run [[:db/add {} :test/name \"Alice\"]]\ny\n", a));
+        assert!(out.contains("Commit? [y/N] tx #db/id[:db.part/tx "));
+        assert_eq!(store.count(r#"[:find ?e :where [?e :test/name "Alice"]]"#).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_timer_and_plan() {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+        let out = session(&mut store, ".plan\n\
+                                       .timer on\n\
+                                       [:find ?e :where [?e :test/name \"Alice\"]]\n\
+                                       .plan\n\
+                                       .timer off\n\
+                                       [:find ?e :where [?e :test/name \"Alice\"]]\n");
+        let lines: Vec<&str> = out.split(PROMPT).collect();
+        assert_eq!(lines[1], "No query has run yet.\n");
+        assert!(lines[3].starts_with("?e\n--\nTime: parse "));
+        assert!(lines[4].starts_with("SELECT "));
+        assert_eq!(lines[6], "?e\n--\n");
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Caching query results until the data they were read from changes.
+//!
+//! A `ResultCache`, enabled with `Store::enable_result_cache`, holds the results of recent
+//! queries, keyed by query text and inputs.  A cached result is returned for as long as no
+//! transaction committed since it was read has touched an attribute the query reads, as found
+//! by `QueryDependencies`.  Queries whose patterns can match any attribute, like `[?e ?a ?v]`,
+//! are invalidated by every transaction.
+//!
+//! Transactions are found in the log, so those committed through other connections invalidate
+//! results too.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use rusqlite;
+
+use mentat_db::{Cipher, Entid, Schema};
+use mentat_query_translator::{QueryDependencies, QueryInputs};
+
+use errors::*;
+use query::{QueryOutput, basis_tx, parse_query, run_find_query};
+
+/// How often a `ResultCache` has answered from its entries.
+#[derive(Clone,Copy,Debug,Default,Eq,Hash,PartialEq)]
+pub struct ResultCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+struct Entry {
+    output: QueryOutput,
+
+    /// The attributes the query reads, or `None` if it can read any attribute.
+    dependencies: Option<BTreeSet<Entid>>,
+
+    /// The latest transaction the results are known to be current as of.
+    basis_tx: Entid,
+
+    /// When the entry was last used, by the cache's clock.
+    used: u64,
+}
+
+/// The results of recent queries, keyed by query text and inputs.
+pub struct ResultCache {
+    capacity: usize,
+    entries: BTreeMap<(String, QueryInputs), Entry>,
+    clock: u64,
+    stats: ResultCacheStats,
+}
+
+impl ResultCache {
+    /// A cache of the results of at most `capacity` queries.  The least recently used results are
+    /// dropped first.
+    pub fn new(capacity: usize) -> ResultCache {
+        ResultCache {
+            capacity: capacity,
+            entries: BTreeMap::new(),
+            clock: 0,
+            stats: ResultCacheStats::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn stats(&self) -> ResultCacheStats {
+        self.stats
+    }
+
+    /// Run `query` with `inputs`, or return its cached results if they're still current.  The
+    /// results are stamped with the current basis transaction either way.
+    pub fn q(&mut self, conn: &rusqlite::Connection, schema: &Schema, query: &str, inputs: QueryInputs, cipher: Option<&Cipher>) -> Result<QueryOutput> {
+        self.clock += 1;
+        let key = (query.to_string(), inputs);
+        // Read before running the query, so a transaction committed meanwhile is checked later.
+        let basis = basis_tx(conn)?;
+
+        if let Some(entry) = self.entries.get_mut(&key) {
+            if entry.basis_tx == basis || !changed_since(conn, entry.basis_tx, entry.dependencies.as_ref())? {
+                entry.basis_tx = basis;
+                entry.used = self.clock;
+                self.stats.hits += 1;
+                let mut output = entry.output.clone();
+                output.basis_tx = basis;
+                return Ok(output);
+            }
+        }
+        self.stats.misses += 1;
+
+        let parsed = parse_query(query)?;
+        let output = run_find_query(conn, schema, &parsed, key.1.clone(), cipher)?;
+        if self.capacity == 0 {
+            return Ok(output);
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let oldest = self.entries.iter().min_by_key(|&(_, entry)| entry.used).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, Entry {
+            output: output.clone(),
+            dependencies: parsed.dependencies(schema).known_attributes().cloned(),
+            basis_tx: basis,
+            used: self.clock,
+        });
+        Ok(output)
+    }
+}
+
+/// `true` if a transaction after `since` changed one of `attributes`, or any attribute if
+/// `attributes` is `None`.
+fn changed_since(conn: &rusqlite::Connection, since: Entid, attributes: Option<&BTreeSet<Entid>>) -> Result<bool> {
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT DISTINCT a FROM transactions WHERE tx > ?")?;
+    let changed: Result<BTreeSet<Entid>> = stmt.query_and_then(&[&since], |row| Ok(row.get_checked(0)?))?.collect();
+    let changed = changed?;
+    Ok(match attributes {
+        Some(attributes) => !attributes.is_disjoint(&changed),
+        None => !changed.is_empty(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use edn::PlainSymbol;
+    use mentat_db::TypedValue;
+
+    use query::{QueryResults, Variable};
+    use store::Store;
+
+    #[test]
+    fn test_result_cache() {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]
+                           [:db/add "a" :db/ident :test/age]
+                           [:db/add "a" :db/valueType :db.type/long]]"#).unwrap();
+        store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        store.enable_result_cache(2);
+
+        let query = r#"[:find ?e . :in ?name :where [?e :test/name ?name]]"#;
+        let mut inputs = QueryInputs::new();
+        inputs.insert(Variable(PlainSymbol::new("?name")), TypedValue::String("Alice".to_string()));
+        let first = store.q_cached(query, inputs.clone()).unwrap();
+        assert_eq!(store.q_cached(query, inputs.clone()).unwrap(), first);
+        assert_eq!(store.result_cache_stats(), Some(ResultCacheStats { hits: 1, misses: 1 }));
+
+        // A transaction that doesn't touch :test/name leaves the results cached, stamped with the
+        // new basis.
+        let report = store.transact(r#"[[:db/add "b" :test/age 30]]"#).unwrap();
+        let cached = store.q_cached(query, inputs.clone()).unwrap();
+        assert_eq!(cached.results, first.results);
+        assert_eq!(cached.basis_tx, report.tx_id);
+        assert_eq!(store.result_cache_stats(), Some(ResultCacheStats { hits: 2, misses: 1 }));
+
+        // One that does invalidates them.
+        store.transact(r#"[[:db/add "b" :test/name "Alice"]]"#).unwrap();
+        match store.q_cached(r#"[:find [?e ...] :in ?name :where [?e :test/name ?name]]"#, inputs.clone()).unwrap().results {
+            QueryResults::Coll(ref es) => assert_eq!(es.len(), 2),
+            x => panic!("expected a collection, got {:?}", x),
+        }
+        store.q_cached(query, inputs.clone()).unwrap();
+        assert_eq!(store.result_cache_stats(), Some(ResultCacheStats { hits: 2, misses: 3 }));
+
+        // Different inputs are cached separately.  Caching them drops the least recently used
+        // results, of the collection query.
+        let mut bob = inputs.clone();
+        bob.insert(Variable(PlainSymbol::new("?name")), TypedValue::String("Bob".to_string()));
+        assert_eq!(store.q_cached(query, bob).unwrap().results, QueryResults::Scalar(None));
+        store.q_cached(query, inputs.clone()).unwrap();
+        store.q_cached(r#"[:find [?e ...] :in ?name :where [?e :test/name ?name]]"#, inputs).unwrap();
+        assert_eq!(store.result_cache_stats(), Some(ResultCacheStats { hits: 3, misses: 5 }));
+
+        store.disable_result_cache();
+        assert_eq!(store.result_cache_stats(), None);
+    }
+
+    #[test]
+    fn test_any_attribute() {
+        let mut store = Store::open("").unwrap();
+        store.enable_result_cache(10);
+        let query = r#"[:find (count ?e) . :where [?e ?a _]]"#;
+        let before = store.q_cached(query, QueryInputs::new()).unwrap();
+        store.transact(r#"[[:db/add "d" :db/doc "a change"]]"#).unwrap();
+        assert!(store.q_cached(query, QueryInputs::new()).unwrap().results != before.results);
+        assert_eq!(store.result_cache_stats(), Some(ResultCacheStats { hits: 0, misses: 2 }));
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Retention rules, retracting data that is no longer worth keeping.
+//!
+//! A rule added with `Store::add_retention_rule` names some attributes -- one, like
+//! `:visit/at`, or a whole namespace, like `:visit/*` -- and which of their datoms to keep: those
+//! asserted within some age, or the newest few values of each entity.  `Store::apply_retention`
+//! is the maintenance task that enforces them: it retracts every datom that has outlived its rules,
+//! in one transaction.  Applications run it when convenient, like at startup or when idle.
+//!
+//! A datom's age is that of the transaction that asserted it, by `:db/txInstant`.  Retracted
+//! datoms remain in the transaction log, so history queries still see them.
+//!
+//! Like constraints, rules belong to the connection: they aren't stored.  Attributes in the `db`
+//! namespace, which hold the schema, can't be given rules.
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use rusqlite;
+
+use edn::{NamespacedKeyword, Value};
+use mentat_db::{Cipher, Entid, Schema, TypedValue, decrypt_sql_value_pair};
+
+use errors::*;
+
+/// Which datoms of some attributes to keep.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum RetentionRule {
+    /// Keep the datoms of `attributes` asserted within `max_age`.
+    MaxAge {
+        attributes: String,
+        max_age: Duration,
+    },
+
+    /// Keep the `count` newest values of `attributes` for each entity.
+    MaxPerEntity {
+        attributes: String,
+        count: usize,
+    },
+}
+
+impl RetentionRule {
+    /// Retract the datoms of `attributes` -- an ident, like `:visit/at`, or a namespace, like
+    /// `:visit/*` -- once they're older than `max_age`.
+    pub fn max_age(attributes: &str, max_age: Duration) -> RetentionRule {
+        RetentionRule::MaxAge {
+            attributes: attributes.to_string(),
+            max_age: max_age,
+        }
+    }
+
+    /// Retract all but the `count` newest values of `attributes` for each entity.
+    pub fn max_per_entity(attributes: &str, count: usize) -> RetentionRule {
+        RetentionRule::MaxPerEntity {
+            attributes: attributes.to_string(),
+            count: count,
+        }
+    }
+
+    fn attributes(&self) -> &str {
+        match self {
+            &RetentionRule::MaxAge { ref attributes, .. } => attributes,
+            &RetentionRule::MaxPerEntity { ref attributes, .. } => attributes,
+        }
+    }
+
+    /// The entids of the attributes this rule applies to.  Fails if it names an attribute that
+    /// isn't installed, or attributes in the `db` namespace.
+    pub fn resolve(&self, schema: &Schema) -> Result<Vec<Entid>> {
+        let attributes = self.attributes();
+        if attributes.starts_with(":db/") || attributes.starts_with(":db.") {
+            bail!(ErrorKind::InvalidRetentionRule(format!("{} holds the schema", attributes)));
+        }
+        if attributes.ends_with("/*") {
+            let prefix = &attributes[..attributes.len() - 1];
+            // Composite tuples are derived from other attributes, which rules can name instead.
+            Ok(schema.ident_map.iter()
+                .filter(|&(ident, entid)| {
+                    ident.starts_with(prefix) && schema.attribute_for_entid(entid).map_or(false, |attribute| attribute.tuple_attrs.is_empty())
+                })
+                .map(|(_, &entid)| entid)
+                .collect())
+        } else {
+            let entid = *schema.require_entid(&attributes.to_string())?;
+            schema.require_attribute_for_entid(&entid)?;
+            Ok(vec![entid])
+        }
+    }
+}
+
+/// A datom to retract: entity, attribute, and value.
+type Expired = (Entid, Entid, TypedValue);
+
+/// The datoms that have outlived `rules` at `now`, in milliseconds since the Unix epoch.
+pub fn expired(conn: &rusqlite::Connection, schema: &Schema, rules: &[RetentionRule], now: i64, cipher: Option<&Cipher>) -> Result<BTreeSet<Expired>> {
+    let tx_instant = *schema.require_entid(&":db/txInstant".to_string())?;
+    let mut expired = BTreeSet::new();
+    for rule in rules {
+        for a in rule.resolve(schema)? {
+            match rule {
+                &RetentionRule::MaxAge { max_age, .. } => {
+                    let cutoff = now - (max_age.as_secs() as i64 * 1_000 + max_age.subsec_nanos() as i64 / 1_000_000);
+                    let mut stmt: rusqlite::Statement = conn.prepare("SELECT d.e, d.v, d.value_type_tag FROM all_datoms d, datoms t WHERE d.a = ? AND t.e = d.tx AND t.a = ? AND t.v < ?")?;
+                    let rows: Result<Vec<Expired>> = stmt.query_and_then(&[&a, &tx_instant, &cutoff], |row| {
+                        let v: rusqlite::types::Value = row.get_checked(1)?;
+                        let value_type_tag: i32 = row.get_checked(2)?;
+                        Ok((row.get_checked(0)?, a, decrypt_sql_value_pair(v, &value_type_tag, cipher)?))
+                    })?.collect();
+                    expired.extend(rows?);
+                },
+                &RetentionRule::MaxPerEntity { count, .. } => {
+                    let mut stmt: rusqlite::Statement = conn.prepare("SELECT e, v, value_type_tag FROM all_datoms WHERE a = ? ORDER BY e, tx DESC")?;
+                    let rows: Result<Vec<Expired>> = stmt.query_and_then(&[&a], |row| {
+                        let v: rusqlite::types::Value = row.get_checked(1)?;
+                        let value_type_tag: i32 = row.get_checked(2)?;
+                        Ok((row.get_checked(0)?, a, decrypt_sql_value_pair(v, &value_type_tag, cipher)?))
+                    })?.collect();
+                    let mut kept: Option<(Entid, usize)> = None;
+                    for (e, a, v) in rows? {
+                        let seen = match kept {
+                            Some((entity, seen)) if entity == e => seen + 1,
+                            _ => 1,
+                        };
+                        kept = Some((e, seen));
+                        if seen > count {
+                            expired.insert((e, a, v));
+                        }
+                    }
+                },
+            }
+        }
+    }
+    Ok(expired)
+}
+
+/// The EDN transaction retracting `expired`.
+pub fn retractions(expired: &BTreeSet<Expired>) -> Value {
+    Value::Vector(expired.iter().map(|&(e, a, ref v)| {
+        Value::Vector(vec![Value::NamespacedKeyword(NamespacedKeyword::new("db", "retract")),
+                           Value::Integer(e),
+                           Value::Integer(a),
+                           v.to_edn_value_pair().0])
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use query::QueryResults;
+    use store::Store;
+
+    const DAY: i64 = 24 * 60 * 60 * 1_000;
+
+    fn store() -> Store {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "a" :db/ident :visit/at]
+                           [:db/add "a" :db/valueType :db.type/long]
+                           [:db/add "a" :db/cardinality :db.cardinality/many]
+                           [:db/add "u" :db/ident :visit/url]
+                           [:db/add "u" :db/valueType :db.type/string]
+                           [:db/add "t" :db/ident :page/title]
+                           [:db/add "t" :db/valueType :db.type/string]]"#).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_max_age() {
+        let mut store = store();
+        store.add_retention_rule(RetentionRule::max_age(":visit/*", Duration::from_secs(90 * 24 * 60 * 60))).unwrap();
+        let old = store.transact(r#"[[:db/add "v" :visit/url "https://example.com"]
+                                     [:db/add "v" :visit/at 1]
+                                     [:db/add "p" :page/title "Example"]]"#).unwrap();
+
+        // Nothing has expired yet.
+        assert!(store.apply_retention_at(old.tx_instant + 89 * DAY).unwrap().is_none());
+
+        store.transact(r#"[[:db/add "v" :visit/url "https://example.org"]]"#).unwrap();
+        let report = store.apply_retention_at(old.tx_instant + 91 * DAY).unwrap().unwrap();
+        assert!(report.tx_instant >= old.tx_instant);
+
+        // The old visit is retracted, but not the newer one, nor the page outside :visit/*.
+        assert_eq!(store.q_once(r#"[:find [?u ...] :where [_ :visit/url ?u]]"#).unwrap().results,
+                   QueryResults::Coll(vec![TypedValue::String("https://example.org".to_string())]));
+        assert_eq!(store.count(r#"[:find ?t :where [_ :visit/at ?t]]"#).unwrap(), 0);
+        assert_eq!(store.count(r#"[:find ?t :where [_ :page/title ?t]]"#).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_max_per_entity() {
+        let mut store = store();
+        store.add_retention_rule(RetentionRule::max_per_entity(":visit/at", 2)).unwrap();
+        let v = store.transact(r#"[[:db/add "v" :visit/at 1]]"#).unwrap().tempids["v"];
+        for at in 2..5 {
+            store.transact(&format!("[[:db/add {} :visit/at {}]]", v, at)).unwrap();
+        }
+        store.apply_retention().unwrap().unwrap();
+        match store.q_once(r#"[:find [?t ...] :where [_ :visit/at ?t]]"#).unwrap().results {
+            QueryResults::Coll(mut values) => {
+                values.sort();
+                assert_eq!(values, vec![TypedValue::Long(3), TypedValue::Long(4)]);
+            },
+            x => panic!("expected a collection, got {:?}", x),
+        }
+        assert!(store.apply_retention().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let mut store = store();
+        assert!(store.add_retention_rule(RetentionRule::max_per_entity(":db/ident", 1)).is_err());
+        assert!(store.add_retention_rule(RetentionRule::max_per_entity(":db/*", 1)).is_err());
+        assert!(store.add_retention_rule(RetentionRule::max_per_entity(":visit/missing", 1)).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! A compact mapping from entids to dense `u32` row ids.
+//!
+//! Entids are `i64`s, sparse, and mostly large: the user partition starts at `0x10000`, and
+//! transaction IDs start at `0x10000000`.  Consumers that keep large in-memory sets of entities,
+//! like bitmaps of search results, can instead intern each entid into a `RowIds` mapping and keep
+//! the dense row id, converting back at the edges.
+//!
+//! Row ids are only meaningful to the `RowIds` that issued them, and are not persisted.
+
+use std::collections::BTreeMap;
+
+use mentat_db::Entid;
+
+use errors::*;
+
+/// A dense stand-in for an entid, issued by a `RowIds` mapping.
+pub type RowId = u32;
+
+/// A bidirectional mapping between entids and row ids.  Row ids are issued in order, starting from
+/// 0, as entids are interned.
+#[derive(Clone,Debug,Default,Eq,PartialEq)]
+pub struct RowIds {
+    /// Map row id->entid.
+    entids: Vec<Entid>,
+
+    /// Map entid->row id.
+    ///
+    /// Invariant: is the inverse map of `entids`.
+    row_ids: BTreeMap<Entid, RowId>,
+}
+
+impl RowIds {
+    pub fn new() -> RowIds {
+        RowIds::default()
+    }
+
+    /// Return the row id of `entid`, issuing a new one if necessary.
+    ///
+    /// Fails with `RowIdsExhausted` rather than wrapping once every `u32` has been issued.
+    pub fn intern(&mut self, entid: Entid) -> Result<RowId> {
+        if let Some(&row_id) = self.row_ids.get(&entid) {
+            return Ok(row_id);
+        }
+        if self.entids.len() > RowId::max_value() as usize {
+            bail!(ErrorKind::RowIdsExhausted);
+        }
+        let row_id = self.entids.len() as RowId;
+        self.entids.push(entid);
+        self.row_ids.insert(entid, row_id);
+        Ok(row_id)
+    }
+
+    /// Return the row id of `entid`, if it has been interned.
+    pub fn row_id(&self, entid: Entid) -> Option<RowId> {
+        self.row_ids.get(&entid).cloned()
+    }
+
+    /// Return the entid of `row_id`, if this mapping issued it.
+    pub fn entid(&self, row_id: RowId) -> Option<Entid> {
+        self.entids.get(row_id as usize).cloned()
+    }
+
+    /// Return the number of interned entids.
+    pub fn len(&self) -> usize {
+        self.entids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entids.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut row_ids = RowIds::new();
+        assert!(row_ids.is_empty());
+
+        let large = i64::max_value() - 1;
+        assert_eq!(row_ids.intern(0x10000).unwrap(), 0);
+        assert_eq!(row_ids.intern(large).unwrap(), 1);
+        // Interning is idempotent.
+        assert_eq!(row_ids.intern(0x10000).unwrap(), 0);
+        assert_eq!(row_ids.len(), 2);
+
+        assert_eq!(row_ids.row_id(large), Some(1));
+        assert_eq!(row_ids.row_id(0x10001), None);
+        assert_eq!(row_ids.entid(1), Some(large));
+        assert_eq!(row_ids.entid(2), None);
+    }
+}
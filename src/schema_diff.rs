@@ -0,0 +1,333 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Comparing two schemas, and migrating from one to the other.
+//!
+//! `schema_diff` compares schemas by ident, so stores that gave the same attribute different
+//! entids compare equal.  The resulting `SchemaDiff` lists the attributes and other idents, like
+//! enumerated values, that the new schema adds; the attributes whose definitions changed, and how;
+//! and the idents the new schema lacks.  `SchemaDiff::migration` is the EDN transaction that brings
+//! a store with the old schema up to the new one.
+//!
+//! Removed idents aren't retracted by the migration, since the store's data may still use them.
+//! Whether a changed definition can be transacted depends on the store's data: making an attribute
+//! unique fails if it already has duplicate values, for example.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use edn::{NamespacedKeyword, Value};
+use mentat_db::{Attribute, Entid, Schema, ValueType};
+
+use tx::ident_value;
+
+/// An attribute whose definition differs between two schemas.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct AttributeChange {
+    pub old: Attribute,
+    pub new: Attribute,
+}
+
+impl AttributeChange {
+    /// The schema attributes whose values differ, like `:db/cardinality`.
+    pub fn properties(&self) -> Vec<&'static str> {
+        let (old, new) = (&self.old, &self.new);
+        let mut properties = vec![];
+        {
+            let mut differ = |property: &'static str, differs: bool| if differs { properties.push(property) };
+            differ(":db/valueType", old.value_type != new.value_type);
+            differ(":db/cardinality", old.multival != new.multival);
+            differ(":db/unique", old.unique_value != new.unique_value || old.unique_identity != new.unique_identity);
+            differ(":db/index", old.index != new.index);
+            differ(":db/fulltext", old.fulltext != new.fulltext);
+            differ(":db.fulltext/tokenizer", old.fulltext_tokenizer != new.fulltext_tokenizer);
+            differ(":db.fulltext/prefix", old.fulltext_prefixes != new.fulltext_prefixes);
+            differ(":db/isComponent", old.component != new.component);
+            differ(":db/encrypted", old.encrypted != new.encrypted);
+            differ(":db/default", old.default != new.default);
+            differ(":db/enum", old.enumerated != new.enumerated);
+            differ(":db/tupleTypes", old.tuple_types != new.tuple_types);
+            differ(":db/tupleAttrs", old.tuple_attrs != new.tuple_attrs);
+        }
+        properties
+    }
+}
+
+/// How one schema differs from another.
+#[derive(Clone,Debug,Default,Eq,PartialEq)]
+pub struct SchemaDiff {
+    /// The attributes only the new schema has, keyed by ident.
+    pub added: BTreeMap<String, Attribute>,
+
+    /// The idents of other entities, like enumerated values, that only the new schema has.
+    pub added_idents: BTreeSet<String>,
+
+    /// The attributes whose definitions differ, keyed by ident.
+    pub changed: BTreeMap<String, AttributeChange>,
+
+    /// The idents only the old schema has, attributes or not, and the attributes of the old schema
+    /// that are only idents in the new one.
+    pub removed: BTreeSet<String>,
+
+    /// The idents of the new schema's entids, for the refs of composite tuples.
+    new_idents: BTreeMap<Entid, String>,
+}
+
+/// How `new` differs from `old`.
+pub fn schema_diff(old: &Schema, new: &Schema) -> SchemaDiff {
+    let mut diff = SchemaDiff::default();
+    diff.new_idents = (*new.entid_map).clone();
+
+    for (ident, entid) in new.ident_map.iter() {
+        let attribute = new.attribute_for_entid(entid);
+        let old_attribute = old.attribute_for_ident_str(ident).map(|(attribute, _)| attribute);
+        match (attribute, old.get_entid(ident)) {
+            (Some(attribute), None) => { diff.added.insert(ident.clone(), attribute.clone()); },
+            (None, None) => { diff.added_idents.insert(ident.clone()); },
+            (Some(attribute), Some(_)) => {
+                // An ident that becomes an attribute is compared with an attribute with no
+                // properties.
+                let mut old_attribute = old_attribute.cloned().unwrap_or_default();
+                old_attribute.tuple_attrs = old_attribute.tuple_attrs.iter().map(|a| translate(old, new, *a)).collect();
+                if &old_attribute != attribute {
+                    diff.changed.insert(ident.clone(), AttributeChange {
+                        old: old_attribute,
+                        new: attribute.clone(),
+                    });
+                }
+            },
+            // An attribute that becomes a plain ident is removed as an attribute.
+            (None, Some(_)) => {
+                if old_attribute.is_some() {
+                    diff.removed.insert(ident.clone());
+                }
+            },
+        }
+    }
+    for ident in old.ident_map.keys() {
+        if !new.ident_map.contains_key(ident) {
+            diff.removed.insert(ident.clone());
+        }
+    }
+    diff
+}
+
+/// The entid `new` gives the ident that `old` gives `entid`, so that the attributes composite
+/// tuples are derived from compare by ident.
+fn translate(old: &Schema, new: &Schema, entid: Entid) -> Entid {
+    old.get_ident(&entid).and_then(|ident| new.get_entid(ident)).cloned().unwrap_or(entid)
+}
+
+fn keyword(namespace: &str, name: &str) -> Value {
+    Value::NamespacedKeyword(NamespacedKeyword::new(namespace, name))
+}
+
+fn value_type_keyword(value_type: &ValueType) -> Value {
+    keyword("db.type", match value_type {
+        &ValueType::Ref => "ref",
+        &ValueType::Boolean => "boolean",
+        &ValueType::Instant => "instant",
+        &ValueType::Long => "long",
+        &ValueType::Double => "double",
+        &ValueType::String => "string",
+        &ValueType::Keyword => "keyword",
+        &ValueType::Bytes => "bytes",
+        &ValueType::Json => "json",
+        &ValueType::Tuple => "tuple",
+    })
+}
+
+fn term(op: &str, e: &Value, a: Value, v: Value) -> Value {
+    Value::Vector(vec![keyword("db", op), e.clone(), a, v])
+}
+
+impl SchemaDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.added_idents.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    /// The entity naming `ident` in the migration: a tempid for idents it adds, and the ident
+    /// itself otherwise.
+    fn entity(&self, ident: &str) -> Value {
+        if self.added.contains_key(ident) || self.added_idents.contains(ident) {
+            Value::Text(ident.to_string())
+        } else {
+            ident_value(ident)
+        }
+    }
+
+    fn unique(attribute: &Attribute) -> Option<Value> {
+        if attribute.unique_identity {
+            Some(keyword("db.unique", "identity"))
+        } else if attribute.unique_value {
+            Some(keyword("db.unique", "value"))
+        } else {
+            None
+        }
+    }
+
+    fn tuple_attrs(&self, attribute: &Attribute) -> Value {
+        Value::Vector(attribute.tuple_attrs.iter().map(|a| {
+            match self.new_idents.get(a) {
+                Some(ident) => self.entity(ident),
+                None => Value::Integer(*a),
+            }
+        }).collect())
+    }
+
+    /// The terms changing the definition of the attribute `e` from `old` to `new`.  An attribute
+    /// being added changes from an attribute with no properties.
+    fn definition(&self, terms: &mut Vec<Value>, e: &Value, old: Option<&Attribute>, new: &Attribute) {
+        let add = |terms: &mut Vec<Value>, a: Value, v: Value| terms.push(term("db/add", e, a, v));
+        let retract = |terms: &mut Vec<Value>, a: Value, v: Value| terms.push(term("db/retract", e, a, v));
+        // An ident that becomes an attribute is defined from scratch, like an attribute being
+        // added.  Restating an existing `:db/valueType` is harmless.
+        let default = Attribute::default();
+        let old = old.unwrap_or(&default);
+        let adding = old == &default;
+
+        if adding || old.value_type != new.value_type {
+            add(terms, keyword("db", "valueType"), value_type_keyword(&new.value_type));
+        }
+        if old.multival != new.multival || (adding && new.multival) {
+            add(terms, keyword("db", "cardinality"), keyword("db.cardinality", if new.multival { "many" } else { "one" }));
+        }
+        match (SchemaDiff::unique(old), SchemaDiff::unique(new)) {
+            (ref o, Some(ref n)) if o.as_ref() != Some(n) => add(terms, keyword("db", "unique"), n.clone()),
+            (Some(o), None) => retract(terms, keyword("db", "unique"), o),
+            _ => (),
+        }
+        // Fulltext attributes are always indexed.
+        if old.index != new.index && !new.fulltext {
+            add(terms, keyword("db", "index"), Value::Boolean(new.index));
+        }
+        let flags = [("fulltext", old.fulltext, new.fulltext),
+                     ("isComponent", old.component, new.component),
+                     ("encrypted", old.encrypted, new.encrypted),
+                     ("enum", old.enumerated, new.enumerated)];
+        for &(name, old, new) in flags.iter() {
+            if old != new {
+                add(terms, keyword("db", name), Value::Boolean(new));
+            }
+        }
+        match (&old.fulltext_tokenizer, &new.fulltext_tokenizer) {
+            (o, &Some(ref n)) if o.as_ref() != Some(n) => add(terms, keyword("db.fulltext", "tokenizer"), Value::Text(n.clone())),
+            (&Some(ref o), &None) => retract(terms, keyword("db.fulltext", "tokenizer"), Value::Text(o.clone())),
+            _ => (),
+        }
+        for prefix in old.fulltext_prefixes.difference(&new.fulltext_prefixes) {
+            retract(terms, keyword("db.fulltext", "prefix"), Value::Integer(*prefix));
+        }
+        for prefix in new.fulltext_prefixes.difference(&old.fulltext_prefixes) {
+            add(terms, keyword("db.fulltext", "prefix"), Value::Integer(*prefix));
+        }
+        match (&old.default, &new.default) {
+            (o, &Some(ref n)) if o.as_ref() != Some(n) => add(terms, keyword("db", "default"), n.to_edn_value_pair().0),
+            (&Some(ref o), &None) => retract(terms, keyword("db", "default"), o.to_edn_value_pair().0),
+            _ => (),
+        }
+        if old.tuple_types != new.tuple_types && !new.tuple_types.is_empty() {
+            add(terms, keyword("db", "tupleTypes"), Value::Vector(new.tuple_types.iter().map(value_type_keyword).collect()));
+        }
+        if old.tuple_attrs != new.tuple_attrs && !new.tuple_attrs.is_empty() {
+            add(terms, keyword("db", "tupleAttrs"), self.tuple_attrs(new));
+        }
+    }
+
+    /// The EDN transaction that brings a store with the old schema up to the new one.  Removed
+    /// idents are left alone.
+    pub fn migration(&self) -> Value {
+        let mut terms = vec![];
+        for ident in self.added_idents.iter() {
+            terms.push(term("db/add", &self.entity(ident), keyword("db", "ident"), ident_value(ident)));
+        }
+        for (ident, attribute) in self.added.iter() {
+            let e = self.entity(ident);
+            terms.push(term("db/add", &e, keyword("db", "ident"), ident_value(ident)));
+            self.definition(&mut terms, &e, None, attribute);
+        }
+        for (ident, change) in self.changed.iter() {
+            self.definition(&mut terms, &self.entity(ident), Some(&change.old), &change.new);
+        }
+        Value::Vector(terms)
+    }
+}
+
+impl fmt::Display for SchemaDiff {
+    /// A line per difference: `+` for additions, `-` for removals, and `~` for changed attributes,
+    /// with the properties that changed.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for ident in self.added.keys().chain(self.added_idents.iter()) {
+            writeln!(f, "+ {}", ident)?;
+        }
+        for (ident, change) in self.changed.iter() {
+            writeln!(f, "~ {} {}", ident, change.properties().join(" "))?;
+        }
+        for ident in self.removed.iter() {
+            writeln!(f, "- {}", ident)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use store::Store;
+
+    fn store(transaction: &str) -> Store {
+        let mut store = Store::open("").unwrap();
+        store.transact(transaction).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_schema_diff() {
+        let mut old = store(r#"[[:db/add "n" :db/ident :person/name]
+                                [:db/add "n" :db/valueType :db.type/string]
+                                [:db/add "n" :db/unique :db.unique/value]
+                                [:db/add "a" :db/ident :person/nickname]
+                                [:db/add "a" :db/valueType :db.type/string]
+                                [:db/add "o" :db/ident :person/age]
+                                [:db/add "o" :db/valueType :db.type/long]
+                                [:db/add "h" :db/ident :person/height]
+                                [:db/add "h" :db/valueType :db.type/double]]"#);
+        let new = store(r#"[[:db/add "n" :db/ident :person/name]
+                            [:db/add "n" :db/valueType :db.type/string]
+                            [:db/add "a" :db/ident :person/nickname]
+                            [:db/add "a" :db/valueType :db.type/string]
+                            [:db/add "a" :db/cardinality :db.cardinality/many]
+                            [:db/add "a" :db/index true]
+                            [:db/add "e" :db/ident :person/email]
+                            [:db/add "e" :db/valueType :db.type/string]
+                            [:db/add "e" :db/unique :db.unique/identity]
+                            [:db/add "r" :db/ident :color/red]
+                            [:db/add "h" :db/ident :person/height]]"#);
+
+        let diff = schema_diff(old.schema(), new.schema());
+        assert_eq!(diff.added.keys().collect::<Vec<_>>(), vec![":person/email"]);
+        assert_eq!(diff.added_idents.iter().collect::<Vec<_>>(), vec![":color/red"]);
+        assert_eq!(diff.removed.iter().collect::<Vec<_>>(), vec![":person/age", ":person/height"]);
+        assert_eq!(diff.changed[":person/name"].properties(), vec![":db/unique"]);
+        assert_eq!(diff.changed[":person/nickname"].properties(), vec![":db/cardinality", ":db/index"]);
+        assert_eq!(diff.to_string(), "+ :person/email\n+ :color/red\n~ :person/name :db/unique\n~ :person/nickname :db/cardinality :db/index\n- :person/age\n- :person/height\n");
+
+        // Migrating leaves only the removals.
+        old.transact(&diff.migration().to_string()).unwrap();
+        let after = schema_diff(old.schema(), new.schema());
+        assert!(after.added.is_empty() && after.added_idents.is_empty() && after.changed.is_empty());
+        assert_eq!(after.removed.iter().collect::<Vec<_>>(), vec![":person/age", ":person/height"]);
+
+        assert!(schema_diff(new.schema(), new.schema()).is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Stores restricted to the attributes in some namespaces.
+//!
+//! A `ScopedStore` is a handle to a `SharedStore` that can only read and write attributes in the
+//! namespaces it was given, like `:sync/*` and `:bookmark/*`.  It's for handing a store to
+//! semi-trusted components, like extensions, that should see and change their own data and
+//! nothing else.
+//!
+//! Queries and transactions are checked before they run, and fail with `OutOfScope` if they name
+//! an attribute outside those namespaces.  A query clause that could match any attribute, like
+//! `[?e ?a ?v]` or `tx-data`, is out of scope, as is `:db/retractEntity`, which retracts every
+//! attribute of an entity.  A query can name an attribute that isn't installed, since it matches
+//! nothing.  Namespaces are matched exactly: `:bookmark.folder/name` isn't in `bookmark`.  Schema
+//! attributes are in `db`, so a scoped store can only define attributes if `db` is allowed.
+
+use std::collections::BTreeSet;
+
+use mentat_db::{Entid, Schema, TxReport};
+use mentat_query::FindQuery;
+use mentat_query_translator::{QueryDependencies, QueryInputs};
+
+use errors::*;
+use query::{QueryOutput, parse_query};
+use shared::SharedStore;
+use tx::{Entity, EntidOrIdent, EntidOrLookupRefOrTempId, ValueOrLookupRef, parse_transaction};
+
+/// A `SharedStore` restricted to the attributes in some namespaces.  Clones share the store and
+/// the restriction.
+#[derive(Clone)]
+pub struct ScopedStore {
+    store: SharedStore,
+    namespaces: BTreeSet<String>,
+}
+
+impl ScopedStore {
+    /// Restrict `store` to the attributes in `namespaces`, given without colons, like `"bookmark"`.
+    pub fn new(store: SharedStore, namespaces: &[&str]) -> ScopedStore {
+        ScopedStore {
+            store: store,
+            namespaces: namespaces.iter().map(|namespace| namespace.to_string()).collect(),
+        }
+    }
+
+    /// The namespaces whose attributes can be read and written.
+    pub fn namespaces(&self) -> &BTreeSet<String> {
+        &self.namespaces
+    }
+
+    pub fn q_once(&self, query: &str) -> Result<QueryOutput> {
+        self.q_once_with_inputs(query, QueryInputs::new())
+    }
+
+    /// See `Store::q_once_with_inputs`.
+    pub fn q_once_with_inputs(&self, query: &str, inputs: QueryInputs) -> Result<QueryOutput> {
+        let parsed = parse_query(query)?;
+        self.store.with_store(|store| {
+            check_query(store.schema(), &parsed, &self.namespaces)?;
+            store.q_once_with_inputs(query, inputs)
+        })
+    }
+
+    pub fn transact(&self, transaction: &str) -> Result<TxReport> {
+        let entities = parse_transaction(transaction)?;
+        self.store.with_store(|store| {
+            check_transaction(store.schema(), &entities, &self.namespaces)?;
+            store.transact(transaction)
+        })
+    }
+
+    /// See `Store::transact_if_basis`.
+    pub fn transact_if_basis(&self, transaction: &str, expected_basis: Entid) -> Result<TxReport> {
+        let entities = parse_transaction(transaction)?;
+        self.store.with_store(|store| {
+            check_transaction(store.schema(), &entities, &self.namespaces)?;
+            store.transact_if_basis(transaction, expected_basis)
+        })
+    }
+}
+
+impl SharedStore {
+    /// A handle to this store restricted to the attributes in `namespaces`.  See `ScopedStore`.
+    pub fn scoped(&self, namespaces: &[&str]) -> ScopedStore {
+        ScopedStore::new(self.clone(), namespaces)
+    }
+}
+
+/// Fail with `OutOfScope` unless the attribute named by `ident` is in one of `namespaces`.
+fn check_ident(ident: &str, namespaces: &BTreeSet<String>) -> Result<()> {
+    let namespace = ident.trim_left_matches(':').splitn(2, '/').next().unwrap_or("");
+    if ident.contains('/') && namespaces.contains(namespace) {
+        Ok(())
+    } else {
+        bail!(ErrorKind::OutOfScope(ident.to_string()))
+    }
+}
+
+/// Like `check_ident`, for an attribute named by entid.  Unknown entids are out of scope.
+fn check_entid(schema: &Schema, entid: Entid, namespaces: &BTreeSet<String>) -> Result<()> {
+    match schema.get_ident(&entid) {
+        Some(ident) => check_ident(ident, namespaces),
+        None => bail!(ErrorKind::OutOfScope(entid.to_string())),
+    }
+}
+
+fn check_attribute(schema: &Schema, attribute: &EntidOrIdent, namespaces: &BTreeSet<String>) -> Result<()> {
+    match attribute {
+        &EntidOrIdent::Entid(entid) => check_entid(schema, entid, namespaces),
+        &EntidOrIdent::Ident(ref kw) => check_ident(&kw.to_string(), namespaces),
+    }
+}
+
+/// Fail with `OutOfScope` if `query` reads an attribute outside `namespaces`, as found by
+/// `QueryDependencies`.
+pub fn check_query(schema: &Schema, query: &FindQuery, namespaces: &BTreeSet<String>) -> Result<()> {
+    let dependencies = query.dependencies(schema);
+    if dependencies.any_attribute {
+        bail!(ErrorKind::OutOfScope("a clause matching any attribute".to_string()));
+    }
+    for &entid in dependencies.attributes.iter() {
+        check_entid(schema, entid, namespaces)?;
+    }
+    Ok(())
+}
+
+/// Fail with `OutOfScope` if `entities` write an attribute outside `namespaces`, or read one
+/// through a lookup ref.
+pub fn check_transaction(schema: &Schema, entities: &[Entity], namespaces: &BTreeSet<String>) -> Result<()> {
+    for entity in entities {
+        let (e, a, v) = match entity {
+            &Entity::Add { ref e, ref a, ref v, .. } => (e, a, Some(v)),
+            &Entity::Retract { ref e, ref a, ref v } => (e, a, Some(v)),
+            &Entity::RetractAttribute { ref e, ref a } => (e, a, None),
+            &Entity::RetractEntity { .. } => bail!(ErrorKind::OutOfScope(":db/retractEntity".to_string())),
+        };
+        check_attribute(schema, a, namespaces)?;
+        if let &EntidOrLookupRefOrTempId::LookupRef(ref lookup_ref) = e {
+            check_attribute(schema, &lookup_ref.a, namespaces)?;
+        }
+        if let Some(&ValueOrLookupRef::LookupRef(ref lookup_ref)) = v {
+            check_attribute(schema, &lookup_ref.a, namespaces)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mentat_db::TypedValue;
+
+    use query::QueryResults;
+    use store::Store;
+
+    fn scoped_store() -> (SharedStore, ScopedStore) {
+        let store = SharedStore::new(Store::open("").unwrap());
+        store.transact(r#"[[:db/add "t" :db/ident :bookmark/title]
+                           [:db/add "t" :db/valueType :db.type/string]
+                           [:db/add "u" :db/ident :bookmark/url]
+                           [:db/add "u" :db/valueType :db.type/string]
+                           [:db/add "u" :db/unique :db.unique/identity]
+                           [:db/add "p" :db/ident :secret/password]
+                           [:db/add "p" :db/valueType :db.type/string]]"#).unwrap();
+        store.transact(r#"[[:db/add "b" :bookmark/url "https://example.com"]
+                           [:db/add "b" :secret/password "hunter2"]]"#).unwrap();
+        let scoped = store.scoped(&["bookmark", "sync"]);
+        (store, scoped)
+    }
+
+    fn assert_out_of_scope<T: ::std::fmt::Debug>(result: Result<T>) {
+        match result {
+            Err(Error(ErrorKind::OutOfScope(_), _)) => (),
+            x => panic!("expected OutOfScope, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_scoped_queries() {
+        let (_, scoped) = scoped_store();
+        assert_eq!(scoped.q_once(r#"[:find ?u . :where [?b :bookmark/url ?u]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::String("https://example.com".to_string()))));
+
+        assert_out_of_scope(scoped.q_once(r#"[:find ?p . :where [?b :bookmark/url _] [?b :secret/password ?p]]"#));
+        assert_out_of_scope(scoped.q_once(r#"[:find ?v :where [?b :bookmark/url _] [?b ?a ?v]]"#));
+        assert_out_of_scope(scoped.q_once(r#"[:find ?v :where [?b :bookmark/url _] [?b _ ?v]]"#));
+        assert_out_of_scope(scoped.q_once(r#"[:find ?b :where [?b :bookmark/url _] (not [?b :secret/password "hunter2"])]"#));
+        assert_out_of_scope(scoped.q_once(r#"[:find ?b :where (or [?b :bookmark/url "x"] [?b :secret/password "hunter2"])]"#));
+        assert_out_of_scope(scoped.q_once(r#"[:find ?a :where [?a :db/ident :bookmark/url]]"#));
+    }
+
+    #[test]
+    fn test_scoped_transactions() {
+        let (store, scoped) = scoped_store();
+        scoped.transact(r#"[[:db/add "b" :bookmark/url "https://example.org"]
+                            [:db/add "b" :bookmark/title "Example"]]"#).unwrap();
+
+        assert_out_of_scope(scoped.transact(r#"[[:db/add "b" :secret/password "letmein"]]"#));
+        assert_out_of_scope(scoped.transact(r#"[[:db/add "a" :db/ident :sync/token]]"#));
+        assert_out_of_scope(scoped.transact(r#"[[:db/add [:bookmark/url "https://example.com"] :bookmark/title [:secret/password "hunter2"]]]"#));
+        let b = match store.q_once(r#"[:find ?b . :where [?b :bookmark/url "https://example.com"]]"#).unwrap().results {
+            QueryResults::Scalar(Some(TypedValue::Ref(b))) => b,
+            x => panic!("expected an entity, got {:?}", x),
+        };
+        assert_out_of_scope(scoped.transact(&format!("[[:db/retractEntity {}]]", b)));
+
+        // Nothing out of scope was written.
+        assert_eq!(store.q_once(r#"[:find (count ?b) . :where [?b :secret/password _]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Long(1))));
+    }
+}
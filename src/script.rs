@@ -0,0 +1,155 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Running a script of operations against a store, for `mentat --file script.edn --db path`.
+//!
+//! A script is an EDN vector of operations, each a map naming a query or a transaction:
+//!
+//! ```edn
+//! [{:transact [[:db/add "a" :person/name "Alice"]]}
+//!  {:query [:find ?e . :where [?e :person/name "Alice"]]}]
+//! ```
+//!
+//! The operations run in order, in a single transaction: if one fails, none of the script's
+//! transactions are committed.  Each operation's result -- a query's results, or a transaction's
+//! report -- is written to standard output on a line of its own, as EDN or JSON.  Errors are
+//! written to standard error, and the exit code says what went wrong; see the `EXIT_` constants.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use mentat::{Consistency, Encodable, Format, Result, Store};
+use mentat::edn;
+use mentat::edn::Value;
+
+/// Every operation succeeded, and the script's transactions were committed.
+pub const EXIT_OK: i32 = 0;
+
+/// The store or the script couldn't be opened or read.
+pub const EXIT_IO: i32 = 1;
+
+/// The script isn't a vector of operations.  Nothing was run.
+pub const EXIT_INVALID_SCRIPT: i32 = 2;
+
+/// An operation failed, so none of the script's transactions were committed.
+pub const EXIT_FAILED: i32 = 3;
+
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum Operation {
+    /// The EDN text of a query.
+    Query(String),
+    /// The EDN text of a transaction.
+    Transact(String),
+}
+
+/// Parse the EDN text of a script.
+pub fn parse_script(text: &str) -> Result<Vec<Operation>> {
+    let value = edn::parse::value(text).map_err(|e| format!("could not parse script: {:?}", e))?;
+    let forms = match value {
+        Value::Vector(forms) => forms,
+        _ => bail!("expected a vector of operations"),
+    };
+    forms.iter().enumerate().map(|(i, form)| -> Result<Operation> {
+        let map = match form {
+            &Value::Map(ref map) if map.len() == 1 => map,
+            _ => bail!("operation {}: expected a map with one key, :query or :transact", i),
+        };
+        let (key, body) = map.iter().next().unwrap();
+        match (key, body) {
+            (&Value::Keyword(ref key), &Value::Vector(_)) if key.0 == "query" => Ok(Operation::Query(body.to_string())),
+            (&Value::Keyword(ref key), &Value::Vector(_)) if key.0 == "transact" => Ok(Operation::Transact(body.to_string())),
+            _ => bail!("operation {}: expected {{:query [...]}} or {{:transact [...]}}", i),
+        }
+    }).collect()
+}
+
+/// Run `operations` against `store` in a single transaction, writing each one's result to `out`
+/// in `format`.  The transaction is committed only if every operation succeeds.
+pub fn run<W: Write>(store: &mut Store, operations: &[Operation], format: Format, out: &mut W) -> Result<()> {
+    let mut in_progress = store.begin_transaction()?;
+    for operation in operations {
+        let encoded = match operation {
+            &Operation::Query(ref query) => in_progress.q_once(query, Consistency::IncludeInFlight)?.results.encode(format),
+            &Operation::Transact(ref transaction) => in_progress.transact(transaction)?.encode(format),
+        };
+        out.write_all(&encoded)?;
+        out.write_all(b"\n")?;
+    }
+    in_progress.commit()
+}
+
+/// Run the script at `path` against the store at `db`, returning the exit code.
+pub fn run_file(db: &str, path: &str, format: Format) -> i32 {
+    let mut text = String::new();
+    if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut text)) {
+        let _ = writeln!(io::stderr(), "could not read {}: {}", path, e);
+        return EXIT_IO;
+    }
+    let operations = match parse_script(&text) {
+        Ok(operations) => operations,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}: {}", path, e);
+            return EXIT_INVALID_SCRIPT;
+        },
+    };
+    let mut store = match Store::open(db) {
+        Ok(store) => store,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "could not open {}: {}", db, e);
+            return EXIT_IO;
+        },
+    };
+    let stdout = io::stdout();
+    match run(&mut store, &operations, format, &mut stdout.lock()) {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}", e);
+            EXIT_FAILED
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() {
+        let operations = parse_script(r#"[{:transact [[:db/add "a" :db/doc "x"]]} {:query [:find ?e :where [?e :db/doc "x"]]}]"#).unwrap();
+        assert_eq!(operations.len(), 2);
+        match operations[1] {
+            Operation::Query(ref query) => assert_eq!(edn::parse::value(query).unwrap(), edn::parse::value(r#"[:find ?e :where [?e :db/doc "x"]]"#).unwrap()),
+            ref x => panic!("expected a query, got {:?}", x),
+        }
+
+        assert!(parse_script("{:query []}").is_err());
+        assert!(parse_script("[{:query [] :transact []}]").is_err());
+        assert!(parse_script("[{:delete []}]").is_err());
+        assert!(parse_script("[").is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let mut store = Store::open("").unwrap();
+        let operations = parse_script(r#"[{:transact [[:db/add "a" :db/doc "x"]]} {:query [:find (count ?e) . :where [?e :db/doc "x"]]}]"#).unwrap();
+        let mut out = vec![];
+        run(&mut store, &operations, Format::Json, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"tx\":"));
+        assert_eq!(lines[1], "1");
+
+        // A failure rolls back the whole script.
+        let operations = parse_script(r#"[{:transact [[:db/add "b" :db/doc "y"]]} {:transact [[:db/add "c" :test/missing "z"]]}]"#).unwrap();
+        assert!(run(&mut store, &operations, Format::Edn, &mut vec![]).is_err());
+        assert_eq!(store.count(r#"[:find ?e :where [?e :db/doc "y"]]"#).unwrap(), 0);
+    }
+}
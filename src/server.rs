@@ -0,0 +1,266 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Serving a store over HTTP, for `mentat serve`.
+//!
+//! - `POST /query` runs a query.  The body is the query's EDN text, and the results are returned
+//!   as EDN.  With a JSON content type, the body is an object like
+//!   `{"query": "[:find ?e :in ?name :where [?e :person/name ?name]]", "inputs": {"?name": "Alice"}}`,
+//!   and the results are returned as JSON.  Inputs are JSON strings, numbers, and booleans, or
+//!   `{"ref": 65536}` and `{"keyword": ":todo.status/open"}`, as in results.  The query's basis
+//!   transaction is returned in the `X-Mentat-Basis-Tx` header.
+//! - `POST /transact` transacts.  The body is the transaction's EDN text or, with a JSON content
+//!   type, an object like `{"transaction": "[[:db/add ...]]"}`.  The transaction report is
+//!   returned in the same format.
+//! - `GET /tx-stream` streams every committed transaction, as server-sent events whose data is
+//!   the JSON encoding of a `TxChange`.  A comment is sent when the store has been idle for
+//!   `KEEPALIVE_SECS`, so that a disconnected client is noticed, and its observer unregistered,
+//!   even if nothing is transacted.
+//!
+//! Requests that fail are answered with 400 Bad Request and the error message.
+
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+use nickel::{Halt, HttpRouter, MiddlewareResult, Nickel, Request, Response};
+use nickel::status::StatusCode;
+use rustc_serialize::json::Json;
+
+use mentat::{Encodable, Format, QueryInputs, Result, SharedStore, TxObserver, TypedValue, Variable, to_namespaced_keyword};
+use mentat::edn::PlainSymbol;
+
+/// Distinguishes the observers registered for concurrent `/tx-stream` requests.
+static NEXT_STREAM: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// How long, in seconds, a `/tx-stream` waits for a transaction before writing a keepalive.
+const KEEPALIVE_SECS: u64 = 15;
+
+/// An observer registered for a `/tx-stream`, unregistered when the stream ends, however it ends.
+struct Registration {
+    store: SharedStore,
+    key: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.store.unregister_observer(&self.key);
+    }
+}
+
+/// A request body: the EDN text of a query or transaction, and, for queries, its inputs.
+#[derive(Debug)]
+struct Body {
+    text: String,
+    inputs: QueryInputs,
+    format: Format,
+}
+
+pub fn serve(store: SharedStore, port: u16) {
+    let mut server = Nickel::with_data(store);
+    server.post("/query", query);
+    server.post("/transact", transact);
+    server.get("/tx-stream", tx_stream);
+    server.listen(("127.0.0.1", port)).expect("Failed to launch server");
+}
+
+fn query<'mw, 'conn>(req: &mut Request<'mw, 'conn, SharedStore>, mut res: Response<'mw, SharedStore>) -> MiddlewareResult<'mw, SharedStore> {
+    let result = read_body(req, "query").and_then(|body| {
+        let output = req.server_data().q_once_with_inputs(&body.text, body.inputs)?;
+        Ok((output.basis_tx, output.results.encode(body.format), body.format))
+    });
+    match result {
+        Ok((basis_tx, encoded, format)) => {
+            res.headers_mut().set_raw("X-Mentat-Basis-Tx", vec![basis_tx.to_string().into_bytes()]);
+            send(res, encoded, format)
+        },
+        Err(e) => bad_request(res, e.to_string()),
+    }
+}
+
+fn transact<'mw, 'conn>(req: &mut Request<'mw, 'conn, SharedStore>, res: Response<'mw, SharedStore>) -> MiddlewareResult<'mw, SharedStore> {
+    let result = read_body(req, "transaction").and_then(|body| {
+        let report = req.server_data().transact(&body.text)?;
+        Ok((report.encode(body.format), body.format))
+    });
+    match result {
+        Ok((encoded, format)) => send(res, encoded, format),
+        Err(e) => bad_request(res, e.to_string()),
+    }
+}
+
+fn tx_stream<'mw, 'conn>(req: &mut Request<'mw, 'conn, SharedStore>, mut res: Response<'mw, SharedStore>) -> MiddlewareResult<'mw, SharedStore> {
+    let store = req.server_data().clone();
+    let key = format!("tx-stream-{}", NEXT_STREAM.fetch_add(1, Ordering::SeqCst));
+
+    let (sender, receiver) = mpsc::channel();
+    let observer = TxObserver::new(&[], move |_, changes| {
+        for change in changes {
+            // The stream unregisters this observer once its client disconnects.
+            let _ = sender.send(change.encode(Format::Json));
+        }
+    });
+    if let Err(e) = store.register_observer(&key, observer) {
+        return bad_request(res, e.to_string());
+    }
+    let _registration = Registration {
+        store: store,
+        key: key,
+    };
+
+    res.headers_mut().set_raw("Content-Type", vec![b"text/event-stream".to_vec()]);
+    res.headers_mut().set_raw("Cache-Control", vec![b"no-cache".to_vec()]);
+    let mut stream = res.start()?;
+    loop {
+        let written = match receiver.recv_timeout(Duration::from_secs(KEEPALIVE_SECS)) {
+            Ok(encoded) => stream.write_all(b"data: ")
+                .and_then(|_| stream.write_all(&encoded))
+                .and_then(|_| stream.write_all(b"\n\n")),
+            Err(RecvTimeoutError::Timeout) => stream.write_all(b": keepalive\n\n"),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if written.and_then(|_| stream.flush()).is_err() {
+            break;
+        }
+    }
+    Ok(Halt(stream))
+}
+
+fn read_body<'mw, 'conn>(req: &mut Request<'mw, 'conn, SharedStore>, field: &str) -> Result<Body> {
+    let json = match req.origin.headers.get_raw("Content-Type") {
+        Some(values) => values.iter().any(|v| v.starts_with(b"application/json")),
+        None => false,
+    };
+    let mut body = String::new();
+    req.origin.read_to_string(&mut body).map_err(|e| e.to_string())?;
+    parse_body(&body, json, field)
+}
+
+/// Parse a request body: EDN text, or a JSON object holding the text in `field`.
+fn parse_body(body: &str, json: bool, field: &str) -> Result<Body> {
+    if !json {
+        return Ok(Body {
+            text: body.to_string(),
+            inputs: QueryInputs::new(),
+            format: Format::Edn,
+        });
+    }
+
+    let json = Json::from_str(body).map_err(|e| e.to_string())?;
+    let text = match json.find(field) {
+        Some(&Json::String(ref text)) => text.clone(),
+        _ => bail!("expected a string \"{}\"", field),
+    };
+    let mut inputs = QueryInputs::new();
+    match json.find("inputs") {
+        Some(&Json::Object(ref object)) => {
+            for (name, value) in object {
+                inputs.insert(Variable(PlainSymbol::new(name.as_str())), typed_value(value)?);
+            }
+        },
+        Some(&Json::Null) | None => (),
+        Some(_) => bail!("expected an object \"inputs\""),
+    }
+    Ok(Body {
+        text: text,
+        inputs: inputs,
+        format: Format::Json,
+    })
+}
+
+/// Parse a query input, encoded as in JSON results.
+fn typed_value(json: &Json) -> Result<TypedValue> {
+    match json {
+        &Json::Boolean(x) => Ok(TypedValue::Boolean(x)),
+        &Json::I64(x) => Ok(TypedValue::Long(x)),
+        &Json::U64(x) if x <= i64::max_value() as u64 => Ok(TypedValue::Long(x as i64)),
+        &Json::F64(x) => Ok(TypedValue::Double(x.into())),
+        &Json::String(ref x) => Ok(TypedValue::String(x.clone())),
+        &Json::Object(ref object) if object.len() == 1 => {
+            match (object.get("ref"), object.get("keyword")) {
+                (Some(&Json::I64(x)), None) => Ok(TypedValue::Ref(x)),
+                (Some(&Json::U64(x)), None) if x <= i64::max_value() as u64 => Ok(TypedValue::Ref(x as i64)),
+                (None, Some(&Json::String(ref x))) => {
+                    match to_namespaced_keyword(x) {
+                        Some(keyword) => Ok(TypedValue::Keyword(keyword)),
+                        None => bail!("not a namespaced keyword: {}", x),
+                    }
+                },
+                _ => bail!("unsupported input: {}", json),
+            }
+        },
+        _ => bail!("unsupported input: {}", json),
+    }
+}
+
+fn send<'mw>(mut res: Response<'mw, SharedStore>, encoded: Vec<u8>, format: Format) -> MiddlewareResult<'mw, SharedStore> {
+    let content_type = match format {
+        Format::Json => "application/json",
+        Format::Edn => "application/edn",
+        Format::Cbor => "application/cbor",
+    };
+    res.headers_mut().set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+    res.send(encoded)
+}
+
+fn bad_request<'mw>(mut res: Response<'mw, SharedStore>, message: String) -> MiddlewareResult<'mw, SharedStore> {
+    res.set(StatusCode::BadRequest);
+    res.send(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mentat::Store;
+    use mentat::edn::NamespacedKeyword;
+
+    #[test]
+    fn test_parse_body() {
+        let edn = parse_body("[:find ?x :where [?x :db/ident _]]", false, "query").unwrap();
+        assert_eq!(edn.text, "[:find ?x :where [?x :db/ident _]]");
+        assert!(edn.inputs.is_empty());
+        assert_eq!(edn.format, Format::Edn);
+
+        let json = parse_body(r#"{"query": "[:find ?e :in ?n ?e ?k]",
+                                  "inputs": {"?n": "Alice", "?e": {"ref": 65536}, "?k": {"keyword": ":a/b"}}}"#,
+                              true, "query").unwrap();
+        assert_eq!(json.text, "[:find ?e :in ?n ?e ?k]");
+        assert_eq!(json.format, Format::Json);
+        assert_eq!(json.inputs[&Variable(PlainSymbol::new("?n"))], TypedValue::String("Alice".to_string()));
+        assert_eq!(json.inputs[&Variable(PlainSymbol::new("?e"))], TypedValue::Ref(65536));
+        assert_eq!(json.inputs[&Variable(PlainSymbol::new("?k"))], TypedValue::Keyword(NamespacedKeyword::new("a", "b")));
+
+        assert!(parse_body(r#"{"transaction": "[]"}"#, true, "query").is_err());
+        assert!(parse_body(r#"{"query": "[]", "inputs": {"?x": [1]}}"#, true, "query").is_err());
+        assert!(parse_body(r#"{"query": "[]", "inputs": {"?k": {"keyword": "a"}}}"#, true, "query").is_err());
+        assert!(parse_body("not json", true, "query").is_err());
+    }
+
+    #[test]
+    fn test_typed_value() {
+        assert_eq!(typed_value(&Json::from_str("true").unwrap()).unwrap(), TypedValue::Boolean(true));
+        assert_eq!(typed_value(&Json::from_str("-3").unwrap()).unwrap(), TypedValue::Long(-3));
+        assert_eq!(typed_value(&Json::from_str("1.5").unwrap()).unwrap(), TypedValue::Double(1.5.into()));
+        assert!(typed_value(&Json::from_str("null").unwrap()).is_err());
+        assert!(typed_value(&Json::from_str(r#"{"ref": 1, "keyword": ":a/b"}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_query_with_json_inputs() {
+        let store = SharedStore::new(Store::open("").unwrap());
+        let body = parse_body(r#"{"query": "[:find ?e . :in ?ident :where [?e :db/ident ?ident]]",
+                                  "inputs": {"?ident": {"keyword": ":db/ident"}}}"#,
+                              true, "query").unwrap();
+        let output = store.q_once_with_inputs(&body.text, body.inputs).unwrap();
+        assert_eq!(String::from_utf8(output.results.encode(body.format)).unwrap(), r#"{"ref":1}"#);
+    }
+}
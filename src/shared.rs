@@ -0,0 +1,202 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Sharing a `Store` between threads.
+//!
+//! A `Store` owns a SQLite connection, which may be moved between threads but not used from two
+//! threads at once: `Store` is `Send` but not `Sync`.  The compiler enforces this, so handing a
+//! `&Store` to another thread is a compile-time error rather than a runtime crash.
+//!
+//! Applications that want to issue queries and transactions from several threads -- like mobile
+//! apps with a UI thread and background workers -- should wrap the store in a `SharedStore`.  A
+//! `SharedStore` is `Clone`, `Send`, and `Sync`; every clone refers to the same store, and
+//! operations are serialized, each running to completion before the next begins.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use mentat_db::{Entid, Schema, TempidHints, TxReport, ValidationError};
+use mentat_db::options::StoreOptions;
+use mentat_query_translator::{QueryInputs, RelationInputs};
+
+use errors::*;
+use observe::TxObserver;
+use preview::TxPreview;
+use query::QueryOutput;
+use store::{ReadTransaction, Store};
+
+/// Lock `mutex`, even if a thread panicked while holding it.
+///
+/// A panic while a store's lock was held can't leave the store inconsistent: a failed transaction
+/// is rolled back, and the in-memory metadata is only replaced after a commit.  Every lock guarding
+/// a store, or state beside one, is taken this way.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A thread-safe, cloneable handle to a `Store`.
+#[derive(Clone)]
+pub struct SharedStore {
+    store: Arc<Mutex<Store>>,
+}
+
+impl SharedStore {
+    pub fn new(store: Store) -> SharedStore {
+        SharedStore {
+            store: Arc::new(Mutex::new(store)),
+        }
+    }
+
+    /// Open the store at `path` and share it.  See `Store::open`.
+    pub fn open(path: &str) -> Result<SharedStore> {
+        Ok(SharedStore::new(Store::open(path)?))
+    }
+
+    /// Open the store at `path`, tuned with `options`, and share it.  See
+    /// `Store::open_with_options`.
+    pub fn open_with_options(path: &str, options: &StoreOptions) -> Result<SharedStore> {
+        Ok(SharedStore::new(Store::open_with_options(path, options)?))
+    }
+
+    fn lock(&self) -> MutexGuard<Store> {
+        lock(&self.store)
+    }
+
+    /// Run `f` with exclusive access to the underlying store.
+    pub fn with_store<T, F>(&self, f: F) -> T where F: FnOnce(&mut Store) -> T {
+        f(&mut *self.lock())
+    }
+
+    pub fn current_schema(&self) -> Arc<Schema> {
+        self.lock().current_schema()
+    }
+
+    pub fn transact(&self, transaction: &str) -> Result<TxReport> {
+        self.lock().transact(transaction)
+    }
+
+    pub fn transact_as(&self, caller: &str, transaction: &str) -> Result<TxReport> {
+        self.lock().transact_as(caller, transaction)
+    }
+
+    pub fn transact_with_hints(&self, transaction: &str, hints: &TempidHints) -> Result<TxReport> {
+        self.lock().transact_with_hints(transaction, hints)
+    }
+
+    pub fn transact_unless_noop(&self, transaction: &str) -> Result<TxReport> {
+        self.lock().transact_unless_noop(transaction)
+    }
+
+    pub fn transact_if_basis(&self, transaction: &str, expected_basis: Entid) -> Result<TxReport> {
+        self.lock().transact_if_basis(transaction, expected_basis)
+    }
+
+    /// See `Store::register_observer`.  Observers are called on the store's notification thread,
+    /// not with the lock held.
+    pub fn register_observer(&self, key: &str, observer: TxObserver) -> Result<()> {
+        self.lock().register_observer(key, observer)
+    }
+
+    pub fn unregister_observer(&self, key: &str) {
+        self.lock().unregister_observer(key)
+    }
+
+    pub fn validate_transaction(&self, transaction: &str) -> Result<Vec<ValidationError>> {
+        self.lock().validate_transaction(transaction)
+    }
+
+    pub fn preview_transaction(&self, transaction: &str) -> Result<TxPreview> {
+        self.lock().preview_transaction(transaction)
+    }
+
+    pub fn q_once(&self, query: &str) -> Result<QueryOutput> {
+        self.lock().q_once(query)
+    }
+
+    pub fn q_once_with_inputs(&self, query: &str, inputs: QueryInputs) -> Result<QueryOutput> {
+        self.lock().q_once_with_inputs(query, inputs)
+    }
+
+    /// Begin a read transaction.  See `Store::begin_read`.
+    ///
+    /// The lock is only held while the transaction begins: other threads can go on transacting
+    /// while it is read from.
+    pub fn begin_read(&self) -> Result<ReadTransaction> {
+        self.lock().begin_read()
+    }
+
+    /// Write a consistent copy of the store to a new file at `path`.  See `Store::snapshot_to`.
+    ///
+    /// The copy is read through a connection of its own, without holding the lock, so other
+    /// threads can go on transacting while it is written.  In-memory stores can only be read
+    /// through their own connection, so they are copied with the lock held.
+    pub fn snapshot_to(&self, path: &str) -> Result<()> {
+        let source = self.lock().path().to_string();
+        if source.is_empty() {
+            return self.lock().snapshot_to(path);
+        }
+        Store::open_read_only(&source)?.snapshot_to(path)
+    }
+
+    pub fn q_named(&self, name: &str, inputs: QueryInputs) -> Result<QueryOutput> {
+        self.lock().q_named(name, inputs)
+    }
+
+    pub fn q_named_with_relations(&self, name: &str, inputs: QueryInputs, relations: RelationInputs) -> Result<QueryOutput> {
+        self.lock().q_named_with_relations(name, inputs, relations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use mentat_db::TypedValue;
+
+    use query::QueryResults;
+
+    fn assert_send<T: Send>() {}
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_bounds() {
+        assert_send::<Store>();
+        assert_send_sync::<SharedStore>();
+        assert_send_sync::<Arc<Schema>>();
+    }
+
+    #[test]
+    fn test_threads() {
+        let store = SharedStore::open("").unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+
+        let handles: Vec<_> = (0..4).map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                store.transact(&format!("[[:db/add \"e\" :test/name \"{}\"]]", i)).unwrap();
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        match store.q_once(r#"[:find [?name ...] :where [_ :test/name ?name]]"#).unwrap().results {
+            QueryResults::Coll(mut names) => {
+                names.sort();
+                assert_eq!(names, (0..4).map(|i| TypedValue::String(i.to_string())).collect::<Vec<_>>());
+            },
+            x => panic!("expected Coll, got {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,3389 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+
+use rusqlite;
+
+use edn;
+
+use mentat_db;
+use mentat_db::{Cipher, Constraint, Constraints, DB, DisplayEntid, DisplayTxReport, Entid, PartitionMap, RetractPolicy, Schema, TempidHints, TxOptions, TxReport, TypedValue, ValidationError, decrypt_sql_value_pair, enum_namespace, read_log_value, to_namespaced_keyword};
+use mentat_db::db;
+use mentat_db::options::StoreOptions;
+use mentat_db::recovery;
+use mentat_db::recovery::RecoveryPolicy;
+use mentat_query::{FindQuery, PointInTime};
+use mentat_query_translator::{QueryInputs, RelationInputs, SQLQuery, translate, translate_with_hints, translate_with_inputs};
+
+use cache::AttributeCache;
+use derived;
+use derived::{Derivation, DerivedAttribute};
+use errors::*;
+use export;
+use export::ExportFormat;
+use follower::{SyncPhase, SyncProgress};
+use intercept::{Interceptor, Interceptors};
+use lint::{Lint, lint_query};
+use merge;
+use merge::{Conflict, MergePolicies};
+use observe::{Dispatcher, TxChange, TxObserver};
+use ordered;
+use ordered::OrderedMany;
+use preview;
+use preview::TxPreview;
+use project;
+use query::{
+    IndexHints,
+    KeyedRow,
+    QueryOutput,
+    QueryPlan,
+    QueryTimings,
+    basis_tx,
+    count_query,
+    exists_query,
+    explain_query,
+    parse_query,
+    require_known_attributes,
+    run_find_query,
+    run_find_query_with_relations,
+    run_query,
+    suggest_rewrites,
+    time_query,
+};
+use result_cache::{ResultCache, ResultCacheStats};
+use retention;
+use retention::RetentionRule;
+use tx::{Entity, guess_attributes, parse_transaction};
+use usage::{Usage, UsageTracker};
+use walk;
+use walk::{Direction, Reached};
+
+/// The first SQLite version, as (major, minor), with `VACUUM INTO`.
+const VACUUM_INTO_VERSION: (u32, u32) = (3, 27);
+
+/// The most translations of one named query that a `Store` keeps, one for each set of inputs.
+const NAMED_QUERY_CACHE_CAPACITY: usize = 64;
+
+/// Write a copy of the main database of `conn` to a new file at `path`.
+fn vacuum_into(conn: &rusqlite::Connection, path: &str) -> Result<()> {
+    let version: String = conn.query_row("SELECT sqlite_version()", &[], |row| row.get(0))?;
+    let mut parts = version.split('.').map(|part| part.parse::<u32>().unwrap_or(0));
+    let major_minor = (parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+    if major_minor < VACUUM_INTO_VERSION {
+        bail!(ErrorKind::SnapshotUnsupported(version));
+    }
+    conn.execute("VACUUM main INTO ?", &[&path.to_string()])?;
+    Ok(())
+}
+
+/// A value asserted for an attribute, with the transaction that asserted it.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct Assertion {
+    pub value: TypedValue,
+    pub tx: Entid,
+    /// The transaction's `:db/txInstant`, in milliseconds since the Unix epoch.  The bootstrap
+    /// transaction has none.
+    pub tx_instant: Option<i64>,
+}
+
+/// A Mentat store: a SQLite connection together with the metadata needed to query and transact
+/// against it.
+///
+/// The `Store` is the single entry point for applications.  It keeps its in-memory view of the
+/// schema and partition map in sync with the SQL store as transactions are applied.
+pub struct Store {
+    /// The path the store was opened from; empty for an in-memory store.
+    path: String,
+
+    conn: rusqlite::Connection,
+
+    /// The current partition map and schema.
+    db: DB,
+
+    /// A shareable snapshot of `db.schema`, replaced whenever a transaction changes the schema.
+    schema: Arc<Schema>,
+
+    /// Values of attributes the application has asked to be cached.
+    attribute_cache: AttributeCache,
+
+    /// Translated queries, keyed by query string.  Cleared when the schema changes.
+    query_cache: BTreeMap<String, SQLQuery>,
+
+    /// Parsed queries registered by name with `register_query`.
+    named_queries: BTreeMap<String, FindQuery>,
+
+    /// Translated named queries, keyed by name and then by inputs, whose values translation
+    /// inlines.  Cleared when the schema changes.
+    named_query_cache: BTreeMap<String, BTreeMap<QueryInputs, SQLQuery>>,
+
+    /// What `transact` does with retractions of datoms that aren't present.
+    retract_policy: RetractPolicy,
+
+    /// What transactions and queries do with attributes that aren't installed.
+    unknown_attributes: UnknownAttributePolicy,
+
+    /// The namespaces in which `transact` creates the idents of new keyword values, added with
+    /// `allow_new_idents`.
+    ident_namespaces: BTreeSet<String>,
+
+    /// Attributes maintained from queries with `register_derived_attribute`, keyed by attribute.
+    derived: BTreeMap<Entid, DerivedAttribute>,
+
+    /// The cipher for the values of `:db/encrypted` attributes, set with `set_cipher`.
+    cipher: Option<Arc<Cipher>>,
+
+    /// The constraints `transact` checks asserted values against, added with `add_constraint`.
+    constraints: Constraints,
+
+    /// The notification thread of the observers registered with `register_observer`, started with
+    /// the first.
+    observers: Option<Dispatcher>,
+
+    /// The policies `transact` checks transactions against before committing them, registered
+    /// with `register_interceptor`.
+    interceptors: Interceptors,
+
+    /// The storage used by each attribute, counted the first time `usage` is called.
+    usage: Option<UsageTracker>,
+
+    /// The rules `apply_retention` enforces, added with `add_retention_rule`.
+    retention_rules: Vec<RetentionRule>,
+
+    /// The results of recent queries run with `q_cached`, if enabled with `enable_result_cache`.
+    result_cache: Option<ResultCache>,
+}
+
+impl Store {
+    /// Open the Mentat store at the given `path`, creating and bootstrapping it if necessary.
+    ///
+    /// An empty `path` opens an in-memory store.  Fails if the store is damaged; see
+    /// `open_with_recovery`.
+    pub fn open(path: &str) -> Result<Store> {
+        Store::open_with_recovery(path, RecoveryPolicy::Fail)
+    }
+
+    /// Like `open`, but if the store fails verification, try to repair it according to `policy`.
+    pub fn open_with_recovery(path: &str, policy: RecoveryPolicy) -> Result<Store> {
+        Store::open_with_options_and_recovery(path, &StoreOptions::default(), policy)
+    }
+
+    /// Like `open`, but tune the SQLite connection with `options`, like `StoreOptions::mobile()`,
+    /// instead of the defaults.
+    pub fn open_with_options(path: &str, options: &StoreOptions) -> Result<Store> {
+        Store::open_with_options_and_recovery(path, options, RecoveryPolicy::Fail)
+    }
+
+    fn open_with_options_and_recovery(path: &str, options: &StoreOptions, policy: RecoveryPolicy) -> Result<Store> {
+        let mut conn = db::new_connection_with_options(path, options)?;
+        db::ensure_current_version(&mut conn)?;
+        recovery::verify_or_recover(&mut conn, policy)?;
+        Store::from_connection(path, conn)
+    }
+
+    /// Open the existing Mentat store at the given `path` for reading only.
+    ///
+    /// The store must already exist and be at the current version: nothing is created, upgraded,
+    /// or written.  The returned handle has no `transact` method, and SQLite itself refuses writes
+    /// through its connection.
+    pub fn open_read_only(path: &str) -> Result<ReadOnlyStore> {
+        let conn = db::new_read_only_connection(path)?;
+        db::check_current_version(&conn)?;
+        Ok(ReadOnlyStore {
+            store: Store::from_connection(path, conn)?,
+        })
+    }
+
+    fn from_connection(path: &str, conn: rusqlite::Connection) -> Result<Store> {
+        let db = db::read_db(&conn)?;
+        let schema = Arc::new(db.schema.clone());
+        Ok(Store {
+            path: path.to_string(),
+            conn: conn,
+            db: db,
+            schema: schema,
+            attribute_cache: AttributeCache::default(),
+            query_cache: BTreeMap::new(),
+            named_queries: BTreeMap::new(),
+            named_query_cache: BTreeMap::new(),
+            retract_policy: RetractPolicy::default(),
+            unknown_attributes: UnknownAttributePolicy::default(),
+            ident_namespaces: BTreeSet::new(),
+            derived: BTreeMap::new(),
+            cipher: None,
+            constraints: Constraints::default(),
+            observers: None,
+            interceptors: Interceptors::default(),
+            usage: None,
+            retention_rules: vec![],
+            result_cache: None,
+        })
+    }
+
+    pub fn connection(&self) -> &rusqlite::Connection {
+        &self.conn
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.db.schema
+    }
+
+    /// Return an immutable snapshot of the current schema.
+    ///
+    /// The snapshot is cheap to clone and can be held across transactions: it is never mutated,
+    /// and transactions that change the schema install a new snapshot rather than updating this one.
+    pub fn current_schema(&self) -> Arc<Schema> {
+        self.schema.clone()
+    }
+
+    pub fn partition_map(&self) -> &PartitionMap {
+        &self.db.partition_map
+    }
+
+    /// Display `entid` for people: by its ident, like `:person/name`, if it has one, and otherwise
+    /// with its partition, like `#db/id[:db.part/user 65537]`.
+    pub fn display_entid(&self, entid: Entid) -> DisplayEntid {
+        self.db.display_entid(entid)
+    }
+
+    /// Display `report` for people, with its entids displayed as by `display_entid`.
+    pub fn display_tx_report<'a>(&'a self, report: &'a TxReport) -> DisplayTxReport<'a> {
+        report.display(&self.db)
+    }
+
+    /// Set what `transact` and `validate_transaction` do with a `:db/retract` of a datom that isn't
+    /// present.  By default, such retractions are ignored.
+    pub fn set_retract_policy(&mut self, policy: RetractPolicy) {
+        self.retract_policy = policy;
+    }
+
+    /// Set what transactions and queries do with attributes that aren't installed.  By default,
+    /// both fail; see `UnknownAttributePolicy`.
+    pub fn set_unknown_attributes(&mut self, policy: UnknownAttributePolicy) {
+        self.unknown_attributes = policy;
+    }
+
+    /// Let transactions assert keyword values in `namespace`, like `:task.status/blocked` in
+    /// `task.status`, for ref attributes even if no entity has that ident yet: the transaction
+    /// creates it, and lists it in `TxReport::created_idents`.  New values of an enumeration need
+    /// no transaction of their own.  In other namespaces, such values still fail.
+    pub fn allow_new_idents(&mut self, namespace: &str) {
+        self.ident_namespaces.insert(namespace.trim_left_matches(':').to_string());
+    }
+
+    /// Stop creating idents in `namespace`; see `allow_new_idents`.
+    pub fn disallow_new_idents(&mut self, namespace: &str) {
+        self.ident_namespaces.remove(namespace.trim_left_matches(':'));
+    }
+
+    /// Parse `query`, failing if it names an attribute that isn't installed, unless the policy for
+    /// unknown attributes is permissive.
+    fn parse_known_query(&self, query: &str) -> Result<FindQuery> {
+        let parsed = parse_query(query)?;
+        self.unknown_attributes.check_query(&self.db.schema, &parsed)?;
+        Ok(parsed)
+    }
+
+    /// Encrypt and decrypt the values of attributes flagged `:db/encrypted true` with `cipher`,
+    /// which holds the caller's key.
+    ///
+    /// Without a cipher, transactions that write such values, and queries, pulls, and exports that
+    /// read them, fail with `MissingCipher`.  The key can't be changed: values already written can
+    /// only be decrypted with the key they were encrypted with.
+    pub fn set_cipher(&mut self, cipher: Arc<Cipher>) {
+        self.cipher = Some(cipher);
+        if let Some(ref mut result_cache) = self.result_cache {
+            result_cache.clear();
+        }
+    }
+
+    fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_ref().map(|cipher| &**cipher)
+    }
+
+    /// The options with which this store transacts, allocating tempids as `hints` ask.
+    fn tx_options<'a>(&'a self, hints: &'a TempidHints) -> TxOptions<'a> {
+        TxOptions {
+            retract_policy: self.retract_policy,
+            cipher: self.cipher(),
+            constraints: Some(&self.constraints),
+            hints: Some(hints),
+            ident_namespaces: Some(&self.ident_namespaces),
+        }
+    }
+
+    /// Check every value asserted for the named `attribute` against `constraint`, as well as any
+    /// constraints added before.  A transaction asserting a violating value fails with a
+    /// `Constraint` conflict naming the offending entity; values already stored aren't checked.
+    ///
+    /// Fails if `attribute` isn't installed, or if `constraint` can't apply to its values, like a
+    /// pattern for a long attribute.
+    pub fn add_constraint(&mut self, attribute: &str, constraint: Constraint) -> Result<()> {
+        let a = *self.db.schema.require_entid(&attribute.to_string())?;
+        Ok(self.constraints.add(&self.db.schema, a, constraint)?)
+    }
+
+    /// Drop every constraint on the named `attribute`.
+    pub fn remove_constraints(&mut self, attribute: &str) -> Result<()> {
+        let a = *self.db.schema.require_entid(&attribute.to_string())?;
+        self.constraints.remove(a);
+        Ok(())
+    }
+
+    /// Enforce `rule` whenever `apply_retention` runs, as well as any rules added before.  Fails
+    /// if the rule names an attribute that isn't installed, or attributes in the `db` namespace.
+    pub fn add_retention_rule(&mut self, rule: RetentionRule) -> Result<()> {
+        rule.resolve(&self.db.schema)?;
+        self.retention_rules.push(rule);
+        Ok(())
+    }
+
+    pub fn clear_retention_rules(&mut self) {
+        self.retention_rules.clear();
+    }
+
+    /// Retract the datoms that have outlived the retention rules, in one transaction.  Returns
+    /// its report, or `None` if nothing had expired.  See the `retention` module.
+    pub fn apply_retention(&mut self) -> Result<Option<TxReport>> {
+        self.apply_retention_at(mentat_db::now())
+    }
+
+    /// Like `apply_retention`, but as if it were `now`, in milliseconds since the Unix epoch.
+    pub fn apply_retention_at(&mut self, now: i64) -> Result<Option<TxReport>> {
+        let expired = retention::expired(&self.conn, &self.db.schema, &self.retention_rules, now, self.cipher())?;
+        if expired.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.transact(&retention::retractions(&expired).to_string())?))
+    }
+
+    /// Declare the enumeration `attribute`, like `:task/status`, with the given `values`, like
+    /// `:task.status/open`, which must be in the namespace named for the attribute.
+    ///
+    /// Installs the attribute, as a cardinality-one ref flagged `:db/enum true`, or flags it if it's
+    /// already installed; and installs each value not already an ident.  From then on, transactions
+    /// can only assert the enumeration's values for the attribute, and queries project its values
+    /// as keywords rather than entids.  Declaring more values later extends the enumeration.
+    pub fn declare_enum(&mut self, attribute: &str, values: &[&str]) -> Result<TxReport> {
+        if to_namespaced_keyword(attribute).is_none() {
+            bail!(ErrorKind::InvalidEnum(format!("{} isn't a namespaced keyword", attribute)));
+        }
+        let namespace = format!(":{}/", enum_namespace(attribute));
+
+        let mut assertions: Vec<String> = vec![];
+        if self.db.schema.get_entid(&attribute.to_string()).is_some() {
+            assertions.push(format!("[:db/add {} :db/enum true]", attribute));
+        } else {
+            assertions.push(format!("[:db/add \"a\" :db/ident {}]", attribute));
+            assertions.push("[:db/add \"a\" :db/valueType :db.type/ref]".to_string());
+            assertions.push("[:db/add \"a\" :db/cardinality :db.cardinality/one]".to_string());
+            assertions.push("[:db/add \"a\" :db/enum true]".to_string());
+        }
+        for (i, value) in values.iter().enumerate() {
+            if to_namespaced_keyword(value).is_none() || !value.starts_with(&namespace) {
+                bail!(ErrorKind::InvalidEnum(format!("{} isn't a keyword in the namespace {}", value, &namespace[1..namespace.len() - 1])));
+            }
+            if self.db.schema.get_entid(&value.to_string()).is_none() {
+                assertions.push(format!("[:db/add \"v{}\" :db/ident {}]", i, value));
+            }
+        }
+        self.transact(&format!("[{}]", assertions.join(" ")))
+    }
+
+    /// Parse and apply the given EDN transaction, committing it to the SQL store.
+    pub fn transact(&mut self, transaction: &str) -> Result<TxReport> {
+        self.transact_with(transaction, false, None, None, &TempidHints::default())
+    }
+
+    /// Like `transact`, but allocate entids to the transaction's tempids as `hints` ask, for entids
+    /// that are the same from run to run.  See `TempidHints`.
+    pub fn transact_with_hints(&mut self, transaction: &str, hints: &TempidHints) -> Result<TxReport> {
+        self.transact_with(transaction, false, None, None, hints)
+    }
+
+    /// Like `transact`, but name the `caller` transacting, for the interceptors to check.  See
+    /// `register_interceptor`.
+    pub fn transact_as(&mut self, caller: &str, transaction: &str) -> Result<TxReport> {
+        self.transact_with(transaction, false, None, Some(caller), &TempidHints::default())
+    }
+
+    /// Parse and apply the given EDN transaction, committing it to the SQL store only if it changes
+    /// some datom.
+    ///
+    /// A transaction whose report is flagged `noop` is rolled back: no `:db/txInstant` is written,
+    /// and its transaction ID and any tempids it allocated will be reused by the next transaction.
+    pub fn transact_unless_noop(&mut self, transaction: &str) -> Result<TxReport> {
+        self.transact_with(transaction, true, None, None, &TempidHints::default())
+    }
+
+    /// Parse and apply the given EDN transaction only if no transaction has committed since
+    /// `expected_basis`, like the `basis_tx` of the query results the transaction was computed
+    /// from.  Otherwise, fail with `StaleBasis` without writing anything; the caller can re-read
+    /// and try again.
+    pub fn transact_if_basis(&mut self, transaction: &str, expected_basis: Entid) -> Result<TxReport> {
+        self.transact_with(transaction, false, Some(expected_basis), None, &TempidHints::default())
+    }
+
+    fn transact_with(&mut self, transaction: &str, skip_noop: bool, expected_basis: Option<Entid>, caller: Option<&str>, hints: &TempidHints) -> Result<TxReport> {
+        let entities = parse_transaction(transaction)?;
+
+        let (report, db) = {
+            // Dropping the SQL transaction without committing rolls it back.  Comparing the basis
+            // must hold off other writers until we commit, so take the write lock up front.
+            let tx = match expected_basis {
+                Some(_) => self.conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?,
+                None => self.conn.transaction()?,
+            };
+            if let Some(expected) = expected_basis {
+                let actual = basis_tx(&tx)?;
+                if actual != expected {
+                    bail!(ErrorKind::StaleBasis(expected, actual));
+                }
+            }
+            let (report, db) = match self.unknown_attributes.guess(&self.db, &entities[..])? {
+                Some(guessed) => guessed.transact(&tx, &self.tx_options(hints))?,
+                None => mentat_db::transact_with_options(&tx, &self.db, &entities[..], &self.tx_options(hints))?,
+            };
+            if skip_noop && report.noop {
+                return Ok(report);
+            }
+            self.interceptors.check(&tx, &db.schema, report.tx_id, caller, self.cipher())?;
+            let db = self.update_derived(&tx, report.tx_id, db)?;
+            let change = self.tx_change(&tx, &report)?;
+            tx.commit()?;
+            self.notify(change.into_iter().collect());
+            (report, db)
+        };
+
+        self.install_db(db)?;
+        Ok(report)
+    }
+
+    /// Call `observer` on the store's notification thread with each transaction committed from now
+    /// on that touches its attributes.  An observer already registered under `key` is replaced.
+    /// Fails if one of the observer's attributes isn't installed.
+    pub fn register_observer(&mut self, key: &str, observer: TxObserver) -> Result<()> {
+        let attributes = if observer.attributes.is_empty() {
+            None
+        } else {
+            let mut entids = BTreeSet::new();
+            for attribute in observer.attributes.iter() {
+                entids.insert(*self.db.schema.require_entid(attribute)?);
+            }
+            Some(entids)
+        };
+        if self.observers.is_none() {
+            self.observers = Some(Dispatcher::start());
+        }
+        if let Some(ref observers) = self.observers {
+            observers.register(key, observer, attributes);
+        }
+        Ok(())
+    }
+
+    pub fn unregister_observer(&mut self, key: &str) {
+        if let Some(ref observers) = self.observers {
+            observers.unregister(key);
+        }
+    }
+
+    /// Show each transaction to `interceptor` before it commits, after the interceptors registered
+    /// before it.  A transaction it denies fails with `PolicyViolation`, and nothing is written.
+    /// An interceptor already registered under `key` is replaced, keeping its place in the chain.
+    pub fn register_interceptor(&mut self, key: &str, interceptor: Interceptor) {
+        self.interceptors.register(key, interceptor);
+    }
+
+    pub fn unregister_interceptor(&mut self, key: &str) {
+        self.interceptors.unregister(key);
+    }
+
+    /// Describe the transaction reported by `report` for observers, if there are any.
+    fn tx_change(&self, conn: &rusqlite::Connection, report: &TxReport) -> Result<Option<TxChange>> {
+        if self.observers.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(TxChange {
+            tx_id: report.tx_id,
+            tx_instant: report.tx_instant,
+            attributes: derived::changed_attributes(conn, report.tx_id)?,
+        }))
+    }
+
+    /// Queue `changes`, which have been committed, for the observers.
+    fn notify(&self, changes: Vec<TxChange>) {
+        if let Some(ref observers) = self.observers {
+            observers.committed(changes);
+        }
+    }
+
+    /// Bring derived attributes up to date after transaction `tx_id`, which produced `db`.  Fails
+    /// if the transaction wrote a derived attribute itself, or changed the schema so that a derived
+    /// attribute's query can no longer maintain it.
+    ///
+    /// If the transaction changed the schema, each query's dependencies are resolved afresh, and
+    /// those whose dependencies changed are recomputed in full.
+    fn update_derived(&self, conn: &rusqlite::Connection, tx_id: Entid, db: DB) -> Result<DB> {
+        if self.derived.is_empty() {
+            return Ok(db);
+        }
+        let changes = derived::changes(conn, tx_id)?;
+        if let Some(&a) = changes.keys().find(|a| self.derived.contains_key(a)) {
+            bail!(ErrorKind::DerivedAttributeWrite(a));
+        }
+        if db.schema == self.db.schema {
+            return Ok(derived::update(conn, db, &self.derived, Some(&changes))?);
+        }
+
+        let mut resolved = BTreeMap::new();
+        let mut moved = BTreeMap::new();
+        for (&a, registered) in self.derived.iter() {
+            let derived = registered.resolve(&db.schema)?;
+            if derived.dependencies == registered.dependencies {
+                resolved.insert(a, derived);
+            } else {
+                moved.insert(a, derived);
+            }
+        }
+        let db = derived::update(conn, db, &resolved, Some(&changes))?;
+        Ok(derived::update(conn, db, &moved, None)?)
+    }
+
+    /// Adopt the partition map and schema of committed transactions.
+    fn install_db(&mut self, db: DB) -> Result<()> {
+        if db.schema != self.db.schema {
+            self.query_cache.clear();
+            self.named_query_cache.clear();
+            if let Some(ref mut result_cache) = self.result_cache {
+                result_cache.clear();
+            }
+            self.schema = Arc::new(db.schema.clone());
+            // Checked by `update_derived` before the schema change was committed.
+            let mut derived = BTreeMap::new();
+            for (&a, registered) in self.derived.iter() {
+                derived.insert(a, registered.resolve(&db.schema)?);
+            }
+            self.derived = derived;
+        }
+        self.db = db;
+        self.attribute_cache.refresh(&self.conn, self.cipher.as_ref().map(|cipher| &**cipher))?;
+        if let Some(ref mut usage) = self.usage {
+            usage.catch_up(&self.conn, &self.db.schema)?;
+        }
+        Ok(())
+    }
+
+    /// Begin a write transaction, in which any number of transactions can be applied and queried
+    /// before they are committed or rolled back together.
+    ///
+    /// The transaction holds the store's write lock until it ends.  Dropping it without committing
+    /// rolls it back.
+    pub fn begin_transaction(&mut self) -> Result<InProgress> {
+        self.conn.execute_batch("BEGIN IMMEDIATE")?;
+        let basis_tx = match basis_tx(&self.conn) {
+            Ok(basis_tx) => basis_tx,
+            Err(e) => {
+                let _ = self.conn.execute_batch("ROLLBACK");
+                return Err(e);
+            },
+        };
+        let db = self.db.clone();
+        Ok(InProgress {
+            store: self,
+            db: db,
+            basis_tx: basis_tx,
+            changes: vec![],
+            finished: false,
+        })
+    }
+
+    /// Parse the given EDN transaction and check it as `transact` would, without writing anything.
+    ///
+    /// Returns every problem found with the transaction's entities, like unknown attributes,
+    /// mistyped values, and uniqueness violations; if there are none, `transact` would succeed
+    /// against the current store.  Fails if the transaction can't be parsed at all.
+    pub fn validate_transaction(&self, transaction: &str) -> Result<Vec<ValidationError>> {
+        let entities = parse_transaction(transaction)?;
+        let hints = TempidHints::default();
+        let options = self.tx_options(&hints);
+        match self.unknown_attributes.guess(&self.db, &entities[..])? {
+            Some(guessed) => guessed.validate(&self.conn, &options),
+            None => Ok(mentat_db::validate_with_options(&self.conn, &self.db, &entities[..], &options)?),
+        }
+    }
+
+    /// Apply the given EDN transaction as `transact` would, and roll it back, reporting the entids
+    /// its tempids would be given and the datoms it would assert and retract.  Fails as `transact`
+    /// would.  See `preview`.
+    pub fn preview_transaction(&mut self, transaction: &str) -> Result<TxPreview> {
+        let mut in_progress = self.begin_transaction()?;
+        let report = in_progress.transact(transaction)?;
+        let previewed = preview::read(&in_progress.store.conn, &in_progress.db.schema, &report, in_progress.store.cipher())?;
+        in_progress.rollback()?;
+        Ok(previewed)
+    }
+
+    /// Begin a read transaction against the store as it is now.
+    ///
+    /// The transaction reads through a connection of its own, so every query it runs sees the same
+    /// basis transaction, however many transactions this `Store` commits in the meantime.  The
+    /// snapshot is released when the `ReadTransaction` is dropped.  In-memory stores can't be read
+    /// from a second connection, so this fails for them.
+    pub fn begin_read(&self) -> Result<ReadTransaction> {
+        ReadTransaction::begin(&self.path, self.cipher.clone(), self.unknown_attributes)
+    }
+
+    /// The path the store was opened from; empty for an in-memory store.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The storage used by the attributes of each namespace, like `bookmark` for
+    /// `:bookmark/title`: the datoms asserted, and the bytes of their variable-length values.
+    /// Namespaces with no datoms are left out.  The figures are approximate; see the `usage` module.
+    ///
+    /// The first call counts every datom; after that, the counts are kept up to date as
+    /// transactions commit, including those committed by other connections.
+    pub fn usage(&mut self) -> Result<BTreeMap<String, Usage>> {
+        let mut usage = match self.usage.take() {
+            Some(usage) => usage,
+            None => UsageTracker::load(&self.conn)?,
+        };
+        usage.catch_up(&self.conn, &self.db.schema)?;
+        let by_namespace = usage.by_namespace(&self.db.schema);
+        self.usage = Some(usage);
+        Ok(by_namespace)
+    }
+
+    /// Write a consistent copy of the store as it is now to a new SQLite file at `path`, which
+    /// mustn't already exist.  The copy is a compacted, ordinary store, which `Store::open` can
+    /// open.
+    ///
+    /// The copy is made with `VACUUM INTO`, which reads from a single read transaction: other
+    /// connections can go on transacting while it is written, and their transactions are left
+    /// out.  Needs SQLite 3.27.0 or later.
+    pub fn snapshot_to(&self, path: &str) -> Result<()> {
+        vacuum_into(&self.conn, path)
+    }
+
+    /// Parse, translate, and run the given query string once, without caching its translation.
+    pub fn q_once(&self, query: &str) -> Result<QueryOutput> {
+        run_query(&self.conn, &translate(&self.db.schema, &self.parse_known_query(query)?)?, self.cipher())
+    }
+
+    /// Like `q_once`, but binding the scalar inputs named by the query's `:in`, like `?name`, to
+    /// the values in `inputs`, as `q_named` does.
+    pub fn q_once_with_inputs(&self, query: &str, inputs: QueryInputs) -> Result<QueryOutput> {
+        run_find_query(&self.conn, &self.db.schema, &self.parse_known_query(query)?, inputs, self.cipher())
+    }
+
+    /// Return the number of distinct results of the given query, like `[:find ?e :where ...]`,
+    /// counted by SQLite rather than by fetching them.  A count query, like
+    /// `[:find (count ?e) . :where ...]`, returns its count.
+    pub fn count(&self, query: &str) -> Result<i64> {
+        count_query(&self.conn, &self.db.schema, &self.parse_known_query(query)?)
+    }
+
+    /// Return `true` if the given query, like `[:find ?e :where [?e :person/name "Alice"]]`, has
+    /// any results, without fetching them.
+    pub fn exists(&self, query: &str) -> Result<bool> {
+        exists_query(&self.conn, &self.db.schema, &self.parse_known_query(query)?)
+    }
+
+    /// Like `q_once`, but query the store as it was at the given point in its history.  This
+    /// overrides any `:as-of` in the query itself.
+    pub fn q_once_as_of(&self, query: &str, as_of: PointInTime) -> Result<QueryOutput> {
+        let mut parsed = self.parse_known_query(query)?;
+        parsed.as_of = Some(as_of);
+        run_find_query(&self.conn, &self.db.schema, &parsed, QueryInputs::new(), self.cipher())
+    }
+
+    /// Like `q_once`, but with index hints for some of the query's patterns, keyed by their position
+    /// in `:where`.  This is an escape hatch for when SQLite's query planner chooses badly; see
+    /// `explain` for the plan it chooses.
+    pub fn q_once_with_hints(&self, query: &str, hints: &IndexHints) -> Result<QueryOutput> {
+        let parsed = self.parse_known_query(query)?;
+        run_query(&self.conn, &translate_with_hints(&self.db.schema, &parsed, QueryInputs::new(), hints)?, self.cipher())
+    }
+
+    /// Describe how SQLite would run the given query, with the given index hints, including the
+    /// index each top-level pattern would read through and any rewrites that would likely run
+    /// faster.
+    pub fn explain(&self, query: &str, hints: &IndexHints) -> Result<QueryPlan> {
+        let parsed = parse_query(query)?;
+        let mut plan = explain_query(&self.conn, &translate_with_hints(&self.db.schema, &parsed, QueryInputs::new(), hints)?)?;
+        plan.rewrites = suggest_rewrites(&self.conn, &self.db.schema, &parsed)?;
+        Ok(plan)
+    }
+
+    /// Warnings about the given query that don't stop it running; see `lint`.
+    pub fn lint(&self, query: &str) -> Result<Vec<Lint>> {
+        lint_query(&self.db.schema, query)
+    }
+
+    /// Like `q_once`, but also report how long each phase of running the query took.
+    pub fn q_once_timed(&self, query: &str) -> Result<(QueryOutput, QueryTimings)> {
+        self.parse_known_query(query)?;
+        time_query(&self.conn, &self.db.schema, query, self.cipher())
+    }
+
+    /// Like `q_once`, but key each result row by `:find` element name.
+    pub fn q_once_keyed(&self, query: &str) -> Result<Vec<KeyedRow>> {
+        let sql_query = translate(&self.db.schema, &self.parse_known_query(query)?)?;
+        let output = run_query(&self.conn, &sql_query, self.cipher())?;
+        Ok(output.results.into_keyed(&sql_query.find_spec))
+    }
+
+    /// Like `q_once`, but project the results as EDN: a vector of maps, one per row, keyed by
+    /// `:find` element.  See `project`.
+    pub fn q_once_edn(&self, query: &str) -> Result<edn::Value> {
+        let sql_query = translate(&self.db.schema, &self.parse_known_query(query)?)?;
+        let output = run_query(&self.conn, &sql_query, self.cipher())?;
+        Ok(project::results(&output.results, &sql_query.find_spec))
+    }
+
+    /// Run the given query string, caching its translation for subsequent calls.
+    pub fn q(&mut self, query: &str) -> Result<QueryOutput> {
+        if !self.query_cache.contains_key(query) {
+            let sql_query = translate(&self.db.schema, &self.parse_known_query(query)?)?;
+            self.query_cache.insert(query.to_string(), sql_query);
+        }
+        run_query(&self.conn, &self.query_cache[query], self.cipher())
+    }
+
+    /// Cache the results of at most `capacity` queries run with `q_cached`, replacing any cache
+    /// enabled before.  See the `result_cache` module.
+    pub fn enable_result_cache(&mut self, capacity: usize) {
+        self.result_cache = Some(ResultCache::new(capacity));
+    }
+
+    pub fn disable_result_cache(&mut self) {
+        self.result_cache = None;
+    }
+
+    /// How often `q_cached` has answered from the cache, or `None` if it isn't enabled.
+    pub fn result_cache_stats(&self) -> Option<ResultCacheStats> {
+        self.result_cache.as_ref().map(|result_cache| result_cache.stats())
+    }
+
+    /// Like `q_once_with_inputs`, but return the cached results of the same query with the same
+    /// inputs if no transaction since has changed an attribute the query reads.  Without a result
+    /// cache, just runs the query.
+    pub fn q_cached(&mut self, query: &str, inputs: QueryInputs) -> Result<QueryOutput> {
+        let parsed = self.parse_known_query(query)?;
+        let cipher = self.cipher.as_ref().map(|cipher| &**cipher);
+        match self.result_cache {
+            Some(ref mut result_cache) => result_cache.q(&self.conn, &self.db.schema, query, inputs, cipher),
+            None => run_find_query(&self.conn, &self.db.schema, &parsed, inputs, cipher),
+        }
+    }
+
+    /// Register the given query under `name`, replacing any query already registered with that
+    /// name.
+    ///
+    /// The query is parsed once, here.  Each invocation with `q_named` translates it once for its
+    /// inputs, and reuses that translation until the schema changes.
+    pub fn register_query(&mut self, name: &str, query: &str) -> Result<()> {
+        let parsed = parse_query(query)?;
+        self.named_queries.insert(name.to_string(), parsed);
+        self.named_query_cache.remove(name);
+        Ok(())
+    }
+
+    pub fn unregister_query(&mut self, name: &str) {
+        self.named_queries.remove(name);
+        self.named_query_cache.remove(name);
+    }
+
+    /// Run the query registered under `name`, binding its `:in` variables to `inputs`.
+    ///
+    /// The translation is cached for the name and inputs, keeping at most
+    /// `NAMED_QUERY_CACHE_CAPACITY` sets of inputs for each name.
+    pub fn q_named(&mut self, name: &str, inputs: QueryInputs) -> Result<QueryOutput> {
+        let query = match self.named_queries.get(name) {
+            Some(query) => query,
+            None => bail!(ErrorKind::UnknownNamedQuery(name.to_string())),
+        };
+        self.unknown_attributes.check_query(&self.db.schema, query)?;
+
+        let translations = self.named_query_cache.entry(name.to_string()).or_insert_with(BTreeMap::new);
+        if !translations.contains_key(&inputs) {
+            let sql_query = translate_with_inputs(&self.db.schema, query, inputs.clone())?;
+            if translations.len() >= NAMED_QUERY_CACHE_CAPACITY {
+                translations.clear();
+            }
+            translations.insert(inputs.clone(), sql_query);
+        }
+        run_query(&self.conn, &translations[&inputs], self.cipher.as_ref().map(|cipher| &**cipher))
+    }
+
+    /// Like `q_named`, but binding the query's collection and relation inputs, like `[?x ...]` and
+    /// `[[?x ?y]]`, to the rows in `relations` too.  There's no limit on the number of rows.
+    pub fn q_named_with_relations(&self, name: &str, inputs: QueryInputs, relations: RelationInputs) -> Result<QueryOutput> {
+        match self.named_queries.get(name) {
+            Some(query) => {
+                self.unknown_attributes.check_query(&self.db.schema, query)?;
+                run_find_query_with_relations(&self.conn, &self.db.schema, query, inputs, relations, self.cipher())
+            },
+            None => bail!(ErrorKind::UnknownNamedQuery(name.to_string())),
+        }
+    }
+
+    /// Maintain the values of the given installed attribute from `query`, a `[:find ?e ?x :where
+    /// ...]` relation, as described by `derivation`.
+    ///
+    /// The attribute is brought up to date now, and after every transaction that changes an
+    /// attribute the query reads; derived values are written as ordinary datoms, in transactions of
+    /// their own.  Transactions can't otherwise write the attribute.  Derived attributes can't be
+    /// derived from each other.  Unregistering leaves the values in place.
+    ///
+    /// Registrations last as long as this `Store`, and aren't persisted: like constraints and
+    /// interceptors, a derivation is part of the application rather than the data, so register it
+    /// each time the store is opened.  Registering brings the values up to date with any
+    /// transactions committed while the attribute wasn't registered.
+    pub fn register_derived_attribute(&mut self, attribute: &str, query: &str, derivation: Derivation) -> Result<()> {
+        let a = *self.db.schema.require_entid(&attribute.to_string())?;
+        let derived = DerivedAttribute::new(&self.db.schema, a, parse_query(query)?, derivation)?;
+        for other in self.derived.values() {
+            if other.attribute != a && (derived.dependencies.contains(&other.attribute) || other.dependencies.contains(&a)) {
+                bail!(ErrorKind::InvalidDerivedAttribute(format!("attribute {} depends on derived attribute {}", attribute, other.attribute)));
+            }
+        }
+
+        let mut registering = BTreeMap::new();
+        registering.insert(a, derived);
+        let db = {
+            let tx = self.conn.transaction()?;
+            let db = derived::update(&tx, self.db.clone(), &registering, None)?;
+            tx.commit()?;
+            db
+        };
+        self.db = db;
+        self.attribute_cache.refresh(&self.conn, self.cipher.as_ref().map(|cipher| &**cipher))?;
+        self.derived.append(&mut registering);
+        Ok(())
+    }
+
+    pub fn unregister_derived_attribute(&mut self, attribute: &str) -> Result<()> {
+        let a = *self.db.schema.require_entid(&attribute.to_string())?;
+        self.derived.remove(&a);
+        Ok(())
+    }
+
+    /// Keep the values of the given attribute in memory, so that `pull` doesn't hit the SQL store.
+    pub fn cache_attribute(&mut self, attribute: &str) -> Result<()> {
+        let a = *self.db.schema.require_entid(&attribute.to_string())?;
+        self.db.schema.require_attribute_for_entid(&a)?;
+        self.attribute_cache.register(&self.conn, a, self.cipher.as_ref().map(|cipher| &**cipher))
+    }
+
+    fn values_for(&self, entid: Entid, a: Entid) -> Result<Vec<TypedValue>> {
+        if let Some(values) = self.attribute_cache.get(a, entid) {
+            return Ok(values.to_vec());
+        }
+
+        let mut stmt: rusqlite::Statement = self.conn.prepare("SELECT v, value_type_tag FROM all_datoms WHERE e = ? AND a = ?")?;
+        let values: Result<Vec<TypedValue>> = stmt.query_and_then(&[&entid, &a], |row| {
+            let v: rusqlite::types::Value = row.get_checked(0)?;
+            let value_type_tag: i32 = row.get_checked(1)?;
+            Ok(decrypt_sql_value_pair(v, &value_type_tag, self.cipher())?)
+        })?.collect();
+        values
+    }
+
+    /// Return the values of the given attributes for `entid`, keyed by attribute ident.
+    ///
+    /// Attributes without values are omitted.  Refs to entities with idents are resolved to those
+    /// idents, like `:task.status/done`; other refs are entids.  See `pull_raw`.
+    pub fn pull(&self, entid: Entid, attributes: &[&str]) -> Result<BTreeMap<String, Vec<TypedValue>>> {
+        Ok(project::resolve_idents(&self.db.schema, self.pull_raw(entid, attributes)?))
+    }
+
+    /// Like `pull`, but with every ref as an entid.
+    pub fn pull_raw(&self, entid: Entid, attributes: &[&str]) -> Result<BTreeMap<String, Vec<TypedValue>>> {
+        let mut result = BTreeMap::new();
+        for attribute in attributes {
+            let a = *self.db.schema.require_entid(&attribute.to_string())?;
+            let values = self.values_for(entid, a)?;
+            if !values.is_empty() {
+                result.insert(attribute.to_string(), values);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Return every entity reachable from `start` by following the ref `attribute` in the given
+    /// `direction`, at most `max_depth` steps, with a shortest path to each.  See `walk::walk`.
+    ///
+    /// This answers questions like "which folders contain this one?" and "who are this person's
+    /// friends of friends?".
+    pub fn walk(&self, start: Entid, attribute: &str, direction: Direction, max_depth: Option<i64>) -> Result<Vec<Reached>> {
+        walk::walk(&self.conn, &self.db.schema, start, attribute, direction, max_depth)
+    }
+
+    /// Return the items of `parent` in the ordered relationship `ordered`, in order.  See
+    /// `ordered`.
+    pub fn ordered_items(&self, ordered: &OrderedMany, parent: Entid) -> Result<Vec<Entid>> {
+        ordered::items(&self.conn, &self.db.schema, ordered, parent)
+    }
+
+    /// Add `item` to the items of `parent` at `index`, moving those at and after `index` along.
+    pub fn insert_at(&mut self, ordered: &OrderedMany, parent: Entid, index: usize, item: Entid) -> Result<TxReport> {
+        let transaction = ordered::insert_at(&self.conn, &self.db.schema, ordered, parent, index, item)?;
+        self.transact(&transaction)
+    }
+
+    /// Move `item`, which must be an item of `parent`, to `index`.
+    pub fn move_to(&mut self, ordered: &OrderedMany, parent: Entid, item: Entid, index: usize) -> Result<TxReport> {
+        let transaction = ordered::move_to(&self.conn, &self.db.schema, ordered, parent, item, index)?;
+        self.transact(&transaction)
+    }
+
+    /// Remove `item` from the items of `parent`, closing the gap it leaves.
+    pub fn remove_from(&mut self, ordered: &OrderedMany, parent: Entid, item: Entid) -> Result<TxReport> {
+        let transaction = ordered::remove(&self.conn, &self.db.schema, ordered, parent, item)?;
+        self.transact(&transaction)
+    }
+
+    /// Write the transactions after `since_tx`, or every transaction but the bootstrap transaction
+    /// if `None`, to `writer` as EDN text.  Returns the number of transactions written.  See
+    /// `export` for the format.
+    pub fn export_datoms<W: Write>(&self, writer: &mut W, since_tx: Option<Entid>, format: ExportFormat) -> Result<usize> {
+        export::export_datoms(&self.conn, &self.db.schema, writer, since_tx, format, self.cipher())
+    }
+
+    /// Replay the transactions exported by `export_datoms`, from this or another store, in one
+    /// write transaction: either all of them are committed, or none.
+    ///
+    /// Exported entities the store doesn't already know are allocated new entids.  Transactions
+    /// keep their `:db/txInstant`s, so the store can't have transactions later than the first one
+    /// imported.
+    ///
+    /// Fails with `UnknownAttributes`, importing nothing, if the export uses attributes that the
+    /// store doesn't have and that the export doesn't define; see `import_datoms_from`.
+    pub fn import_datoms<R: BufRead>(&mut self, reader: R, format: ExportFormat) -> Result<Vec<TxReport>> {
+        let mut in_progress = self.begin_transaction()?;
+        let reports = export::import_datoms(&mut in_progress, reader, format, None)?;
+        in_progress.rebuild_fulltext_tables()?;
+        in_progress.commit()?;
+        Ok(reports)
+    }
+
+    /// Check that `import_datoms` would succeed, by importing and then rolling back.  Returns the
+    /// reports of the transactions that would be imported.
+    pub fn validate_import<R: BufRead>(&mut self, reader: R, format: ExportFormat) -> Result<Vec<TxReport>> {
+        let mut in_progress = self.begin_transaction()?;
+        let reports = export::import_datoms(&mut in_progress, reader, format, None)?;
+        in_progress.rollback()?;
+        Ok(reports)
+    }
+
+    /// Like `import_datoms`, but first install the definitions of the attributes the export uses
+    /// that this store lacks from the store at `source_path`, which is usually the exporting store,
+    /// as part of the same write transaction.
+    ///
+    /// This lets stores with different versions of a vocabulary exchange exports.  Attributes that
+    /// the source store defines differently from this one, by value type or cardinality, fail with
+    /// `AttributeMismatch`; those neither store has fail with `UnknownAttributes`.
+    pub fn import_datoms_from<R: BufRead>(&mut self, reader: R, format: ExportFormat, source_path: &str) -> Result<Vec<TxReport>> {
+        let source = db::new_read_only_connection(source_path)?;
+        db::check_current_version(&source)?;
+        source.execute("BEGIN DEFERRED", &[])?;
+        let source_db = db::read_db(&source)?;
+
+        let mut in_progress = self.begin_transaction()?;
+        let reports = export::import_datoms(&mut in_progress, reader, format, Some((&source, &source_db.schema)))?;
+        in_progress.rebuild_fulltext_tables()?;
+        in_progress.commit()?;
+        Ok(reports)
+    }
+
+    /// Replay the transactions committed to the store at `leader_path` since this store's latest
+    /// transaction, in one write transaction, keeping their entids and `:db/txInstant`s.  Returns
+    /// the reports of the replayed transactions.  See `Follower`.
+    ///
+    /// This only mirrors the leader for as long as this store commits nothing else: if it has, it
+    /// has diverged, and this fails with `ReplicationDiverged`.  Encrypted values are decrypted
+    /// with this store's cipher, so the leader must use the same key.
+    pub fn replicate_from(&mut self, leader_path: &str) -> Result<Vec<TxReport>> {
+        self.replicate_from_with_progress(leader_path, usize::max_value(), |_| ())
+    }
+
+    /// Like `replicate_from`, but replay the leader's transactions in chunks of at most
+    /// `chunk_size`, each in its own write transaction, calling `progress` before the first chunk,
+    /// after each, and when done.
+    ///
+    /// Each chunk is committed before the next is replayed, and this store's latest transaction
+    /// marks how far replication got: if it's interrupted, by an error or by the process being
+    /// killed, at most one chunk is lost, and replicating again resumes after the last chunk
+    /// committed.
+    pub fn replicate_from_with_progress<F>(&mut self, leader_path: &str, chunk_size: usize, mut progress: F) -> Result<Vec<TxReport>> where F: FnMut(&SyncProgress) {
+        let leader = db::new_read_only_connection(leader_path)?;
+        db::check_current_version(&leader)?;
+        // Read the leader's schema and log from one snapshot.
+        leader.execute("BEGIN DEFERRED", &[])?;
+        let leader_db = db::read_db(&leader)?;
+
+        let pending = export::logged_transactions(&leader, basis_tx(&self.conn)?)?;
+        let mut sync = SyncProgress {
+            phase: SyncPhase::Reading,
+            transactions: 0,
+            total_transactions: pending.len(),
+            bytes: 0,
+        };
+        progress(&sync);
+
+        let mut reports = Vec::with_capacity(pending.len());
+        let chunks: Vec<&[Entid]> = pending.chunks(cmp::max(chunk_size, 1)).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let last = i + 1 == chunks.len();
+            let (chunk_reports, bytes) = self.replicate_chunk(&leader, &leader_db, *chunk.last().expect("a non-empty chunk"), last)?;
+            sync.phase = SyncPhase::Replaying;
+            sync.transactions += chunk_reports.len();
+            sync.bytes += bytes;
+            reports.extend(chunk_reports);
+            progress(&sync);
+        }
+
+        sync.phase = SyncPhase::Done;
+        progress(&sync);
+        Ok(reports)
+    }
+
+    /// Replay the transactions of the leader read through `leader` up to and including
+    /// `until_tx`, and commit them.  The `last` chunk also catches up with the leader's
+    /// partitions.
+    fn replicate_chunk(&mut self, leader: &rusqlite::Connection, leader_db: &DB, until_tx: Entid, last: bool) -> Result<(Vec<TxReport>, usize)> {
+        let cipher = self.cipher.clone();
+        let mut in_progress = self.begin_transaction()?;
+        let replayed = export::mirror_datoms(&mut in_progress, leader, &leader_db.schema, Some(until_tx), cipher.as_ref().map(|cipher| &**cipher))?;
+        if !last {
+            in_progress.commit()?;
+            return Ok(replayed);
+        }
+
+        // Replaying allocates no entids of its own, so catch up with the leader's allocations too,
+        // lest this store reuse them if it's ever written to.  Not before the last chunk, though:
+        // the transactions of later chunks must still be allocated the leader's transaction IDs.
+        for (part, partition) in leader_db.partition_map.iter() {
+            if let Some(local) = in_progress.db.partition_map.get_mut(part) {
+                if partition.index > local.index {
+                    local.index = partition.index;
+                    in_progress.store.conn.execute("UPDATE parts SET idx = ? WHERE part = ?", &[&local.index, part])?;
+                }
+            }
+        }
+        in_progress.commit()?;
+        Ok(replayed)
+    }
+
+    /// Find the cardinality-one values that both this store and the store at `remote_path` have
+    /// changed, and disagree on, since their common ancestor transaction `ancestor_tx`.  See
+    /// `merge`.  Derived attributes aren't compared: their queries maintain them.
+    pub fn conflicts_with(&self, remote_path: &str, ancestor_tx: Entid) -> Result<Vec<Conflict>> {
+        let remote = db::new_read_only_connection(remote_path)?;
+        db::check_current_version(&remote)?;
+        remote.execute("BEGIN DEFERRED", &[])?;
+        let remote_db = db::read_db(&remote)?;
+        let conflicts = merge::find_conflicts(&self.conn, &self.db.schema, &remote, &remote_db.schema, ancestor_tx, self.cipher())?;
+        Ok(conflicts.into_iter().filter(|conflict| !self.derived.contains_key(&conflict.attribute)).collect())
+    }
+
+    /// Settle `conflicts`, found by `conflicts_with`, by `policies`, in one transaction.  Returns
+    /// its report, or `None` if this store already holds every value kept.
+    pub fn resolve_conflicts(&mut self, conflicts: &[Conflict], policies: &MergePolicies) -> Result<Option<TxReport>> {
+        match merge::resolution(&self.db.schema, conflicts, policies) {
+            Some(transaction) => self.transact(&transaction).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Return the first value ever asserted for `attribute` on `entid`, even if it has since been
+    /// retracted, with the transaction that asserted it.
+    ///
+    /// This answers questions like "when was this bookmark created?".
+    pub fn first_asserted(&self, entid: Entid, attribute: &str) -> Result<Option<Assertion>> {
+        self.asserted(entid, attribute, "ASC")
+    }
+
+    /// Return the last value asserted for `attribute` on `entid`, even if it has since been
+    /// retracted, with the transaction that asserted it.
+    pub fn last_asserted(&self, entid: Entid, attribute: &str) -> Result<Option<Assertion>> {
+        self.asserted(entid, attribute, "DESC")
+    }
+
+    fn asserted(&self, entid: Entid, attribute: &str, order: &str) -> Result<Option<Assertion>> {
+        let a = *self.db.schema.require_entid(&attribute.to_string())?;
+        self.db.schema.require_attribute_for_entid(&a)?;
+        let tx_instant = *self.db.schema.require_entid(&":db/txInstant".to_string())?;
+
+        let sql = format!("SELECT t.v, t.value_type_tag, t.tx, i.v
+                           FROM transactions AS t LEFT JOIN datoms AS i ON i.e = t.tx AND i.a = ?
+                           WHERE t.e = ? AND t.a = ? AND t.added IS NOT 0
+                           ORDER BY t.tx {}, t.rowid {} LIMIT 1", order, order);
+        let mut stmt: rusqlite::Statement = self.conn.prepare(&sql)?;
+        let mut rows = stmt.query(&[&tx_instant, &entid, &a])?;
+        match rows.next() {
+            Some(row) => {
+                let row = row?;
+                let v: rusqlite::types::Value = row.get_checked(0)?;
+                let value_type_tag: i32 = row.get_checked(1)?;
+                Ok(Some(Assertion {
+                    value: read_log_value(&self.conn, &self.db.schema, a, v, &value_type_tag, self.cipher())?,
+                    tx: row.get_checked(2)?,
+                    tx_instant: row.get_checked(3)?,
+                }))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Return every attribute and value asserted for `entid`, keyed by attribute ident.
+    ///
+    /// Refs are resolved to idents where they can be, as by `pull`.  See `entity_raw`.
+    pub fn entity(&self, entid: Entid) -> Result<BTreeMap<String, Vec<TypedValue>>> {
+        Ok(project::resolve_idents(&self.db.schema, self.entity_raw(entid)?))
+    }
+
+    /// Like `entity`, but with every ref as an entid.
+    pub fn entity_raw(&self, entid: Entid) -> Result<BTreeMap<String, Vec<TypedValue>>> {
+        let mut stmt: rusqlite::Statement = self.conn.prepare("SELECT a, v, value_type_tag FROM all_datoms WHERE e = ?")?;
+        let mut rows = stmt.query(&[&entid])?;
+
+        let mut result: BTreeMap<String, Vec<TypedValue>> = BTreeMap::new();
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let a: Entid = row.get_checked(0)?;
+            let v: rusqlite::types::Value = row.get_checked(1)?;
+            let value_type_tag: i32 = row.get_checked(2)?;
+            let ident = self.db.schema.require_ident(&a)?.clone();
+            result.entry(ident).or_insert(vec![]).push(decrypt_sql_value_pair(v, &value_type_tag, self.cipher())?);
+        }
+        Ok(result)
+    }
+
+    /// Like `pull`, but project the entity as an EDN map keyed by attribute, with its entid under
+    /// `:db/id`.
+    pub fn pull_edn(&self, entid: Entid, attributes: &[&str]) -> Result<edn::Value> {
+        Ok(project::entity(&self.db.schema, entid, &self.pull(entid, attributes)?))
+    }
+
+    /// Like `entity`, but project the entity as an EDN map keyed by attribute, with its entid
+    /// under `:db/id`.
+    pub fn entity_edn(&self, entid: Entid) -> Result<edn::Value> {
+        Ok(project::entity(&self.db.schema, entid, &self.entity(entid)?))
+    }
+}
+
+/// A Mentat store opened with `Store::open_read_only`.
+///
+/// Exposes only the reading half of `Store`.
+pub struct ReadOnlyStore {
+    store: Store,
+}
+
+impl ReadOnlyStore {
+    pub fn set_cipher(&mut self, cipher: Arc<Cipher>) {
+        self.store.set_cipher(cipher)
+    }
+
+    pub fn connection(&self) -> &rusqlite::Connection {
+        self.store.connection()
+    }
+
+    pub fn schema(&self) -> &Schema {
+        self.store.schema()
+    }
+
+    pub fn current_schema(&self) -> Arc<Schema> {
+        self.store.current_schema()
+    }
+
+    pub fn partition_map(&self) -> &PartitionMap {
+        self.store.partition_map()
+    }
+
+    pub fn q_once(&self, query: &str) -> Result<QueryOutput> {
+        self.store.q_once(query)
+    }
+
+    pub fn count(&self, query: &str) -> Result<i64> {
+        self.store.count(query)
+    }
+
+    pub fn lint(&self, query: &str) -> Result<Vec<Lint>> {
+        self.store.lint(query)
+    }
+
+    pub fn exists(&self, query: &str) -> Result<bool> {
+        self.store.exists(query)
+    }
+
+    pub fn q_once_as_of(&self, query: &str, as_of: PointInTime) -> Result<QueryOutput> {
+        self.store.q_once_as_of(query, as_of)
+    }
+
+    pub fn q_once_timed(&self, query: &str) -> Result<(QueryOutput, QueryTimings)> {
+        self.store.q_once_timed(query)
+    }
+
+    pub fn q_once_keyed(&self, query: &str) -> Result<Vec<KeyedRow>> {
+        self.store.q_once_keyed(query)
+    }
+
+    pub fn q_once_edn(&self, query: &str) -> Result<edn::Value> {
+        self.store.q_once_edn(query)
+    }
+
+    pub fn q(&mut self, query: &str) -> Result<QueryOutput> {
+        self.store.q(query)
+    }
+
+    pub fn register_query(&mut self, name: &str, query: &str) -> Result<()> {
+        self.store.register_query(name, query)
+    }
+
+    pub fn q_named(&mut self, name: &str, inputs: QueryInputs) -> Result<QueryOutput> {
+        self.store.q_named(name, inputs)
+    }
+
+    pub fn q_named_with_relations(&self, name: &str, inputs: QueryInputs, relations: RelationInputs) -> Result<QueryOutput> {
+        self.store.q_named_with_relations(name, inputs, relations)
+    }
+
+    pub fn pull(&self, entid: Entid, attributes: &[&str]) -> Result<BTreeMap<String, Vec<TypedValue>>> {
+        self.store.pull(entid, attributes)
+    }
+
+    pub fn pull_raw(&self, entid: Entid, attributes: &[&str]) -> Result<BTreeMap<String, Vec<TypedValue>>> {
+        self.store.pull_raw(entid, attributes)
+    }
+
+    pub fn pull_edn(&self, entid: Entid, attributes: &[&str]) -> Result<edn::Value> {
+        self.store.pull_edn(entid, attributes)
+    }
+
+    pub fn entity(&self, entid: Entid) -> Result<BTreeMap<String, Vec<TypedValue>>> {
+        self.store.entity(entid)
+    }
+
+    pub fn entity_raw(&self, entid: Entid) -> Result<BTreeMap<String, Vec<TypedValue>>> {
+        self.store.entity_raw(entid)
+    }
+
+    pub fn entity_edn(&self, entid: Entid) -> Result<edn::Value> {
+        self.store.entity_edn(entid)
+    }
+
+    pub fn walk(&self, start: Entid, attribute: &str, direction: Direction, max_depth: Option<i64>) -> Result<Vec<Reached>> {
+        self.store.walk(start, attribute, direction, max_depth)
+    }
+
+    pub fn ordered_items(&self, ordered: &OrderedMany, parent: Entid) -> Result<Vec<Entid>> {
+        self.store.ordered_items(ordered, parent)
+    }
+
+    pub fn export_datoms<W: Write>(&self, writer: &mut W, since_tx: Option<Entid>, format: ExportFormat) -> Result<usize> {
+        self.store.export_datoms(writer, since_tx, format)
+    }
+
+    pub fn snapshot_to(&self, path: &str) -> Result<()> {
+        self.store.snapshot_to(path)
+    }
+
+    pub fn first_asserted(&self, entid: Entid, attribute: &str) -> Result<Option<Assertion>> {
+        self.store.first_asserted(entid, attribute)
+    }
+
+    pub fn last_asserted(&self, entid: Entid, attribute: &str) -> Result<Option<Assertion>> {
+        self.store.last_asserted(entid, attribute)
+    }
+}
+
+/// What transactions and queries do with attributes that aren't installed, set with
+/// `Store::set_unknown_attributes`.
+#[derive(Clone,Copy,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum UnknownAttributePolicy {
+    /// Transactions asserting an unknown attribute fail, as do queries with a pattern naming one,
+    /// with `UnknownAttribute`.  This is the default, and what production stores want: a typo in an
+    /// attribute is an error, not a new attribute or an empty result.
+    Strict,
+    /// For exploratory tools.  A transaction asserting an unknown attribute installs it, with
+    /// cardinality one and a value type guessed from the value asserted; see `guess_attributes`.
+    /// The attribute is listed in the report's `created_idents`.  Queries naming an unknown
+    /// attribute match nothing.
+    Permissive,
+}
+
+impl Default for UnknownAttributePolicy {
+    fn default() -> UnknownAttributePolicy {
+        UnknownAttributePolicy::Strict
+    }
+}
+
+impl UnknownAttributePolicy {
+    fn check_query(&self, schema: &Schema, query: &FindQuery) -> Result<()> {
+        match *self {
+            UnknownAttributePolicy::Strict => require_known_attributes(schema, query),
+            UnknownAttributePolicy::Permissive => Ok(()),
+        }
+    }
+
+    /// If permissive, guess the unknown attributes `entities` assert, to install in the same
+    /// transaction, or return `None` if there are none.
+    fn guess(&self, db: &DB, entities: &[Entity]) -> Result<Option<Guessed>> {
+        if *self == UnknownAttributePolicy::Strict {
+            return Ok(None);
+        }
+        let mut db = db.clone();
+        let (install, installed) = guess_attributes(&mut db, entities)?;
+        if install.is_empty() {
+            return Ok(None);
+        }
+        let mut entities = entities.to_vec();
+        // Appended, so that validation errors still index the caller's entities.
+        entities.extend(install);
+        Ok(Some(Guessed {
+            db: db,
+            entities: entities,
+            installed: installed,
+        }))
+    }
+}
+
+/// A transaction with the attributes it asserts but the store lacks, as guessed by a permissive
+/// `UnknownAttributePolicy`.
+struct Guessed {
+    /// The store's metadata, with the guessed attributes installed.
+    db: DB,
+    /// The transaction's entities, followed by the terms installing the guessed attributes.
+    entities: Vec<Entity>,
+    installed: BTreeMap<String, Entid>,
+}
+
+impl Guessed {
+    fn transact(&self, conn: &rusqlite::Connection, options: &TxOptions) -> Result<(TxReport, DB)> {
+        let (mut report, db) = mentat_db::transact_with_options(conn, &self.db, &self.entities[..], options)?;
+        report.created_idents.extend(self.installed.iter().map(|(ident, &e)| (ident.clone(), e)));
+        Ok((report, db))
+    }
+
+    fn validate(&self, conn: &rusqlite::Connection, options: &TxOptions) -> Result<Vec<ValidationError>> {
+        Ok(mentat_db::validate_with_options(conn, &self.db, &self.entities[..], options)?)
+    }
+}
+
+/// Whether a query run within an `InProgress` transaction sees that transaction's writes.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum Consistency {
+    /// See the transaction's uncommitted writes, as it will be if committed.
+    IncludeInFlight,
+    /// See the store as it was committed when the transaction began.
+    ExcludeInFlight,
+}
+
+/// A write transaction in progress, opened with `Store::begin_transaction`.
+///
+/// Transactions applied with `transact` are written through the store's connection inside one
+/// SQLite transaction, so they are invisible to other connections until `commit`.
+pub struct InProgress<'a> {
+    store: &'a mut Store,
+
+    /// The partition map and schema including the uncommitted transactions.
+    db: DB,
+
+    /// The last transaction committed before this one began.
+    basis_tx: Entid,
+
+    /// The transactions applied so far, for observers.
+    changes: Vec<TxChange>,
+
+    /// `true` once committed or rolled back.
+    finished: bool,
+}
+
+impl<'a> InProgress<'a> {
+    /// The last transaction committed before this one began.
+    pub fn basis_tx(&self) -> Entid {
+        self.basis_tx
+    }
+
+    /// The schema including any uncommitted changes.
+    pub fn schema(&self) -> &Schema {
+        &self.db.schema
+    }
+
+    pub fn partition_map(&self) -> &PartitionMap {
+        &self.db.partition_map
+    }
+
+    /// Rebuild the own fulltext tables of the attributes that have one, as of the uncommitted
+    /// transactions.
+    fn rebuild_fulltext_tables(&self) -> Result<()> {
+        Ok(db::rebuild_fulltext_tables(&self.store.conn, &self.db.schema)?)
+    }
+
+    /// Parse and apply the given EDN transaction, without committing it.
+    ///
+    /// A transaction that fails leaves the earlier transactions in place.
+    pub fn transact(&mut self, transaction: &str) -> Result<TxReport> {
+        self.transact_with(transaction, None)
+    }
+
+    /// Like `transact`, but name the `caller` transacting, for the store's interceptors to check.
+    pub fn transact_as(&mut self, caller: &str, transaction: &str) -> Result<TxReport> {
+        self.transact_with(transaction, Some(caller))
+    }
+
+    fn transact_with(&mut self, transaction: &str, caller: Option<&str>) -> Result<TxReport> {
+        let entities = parse_transaction(transaction)?;
+
+        self.store.conn.execute_batch("SAVEPOINT in_progress")?;
+        match self.apply(&entities[..], caller) {
+            Ok((report, db)) => {
+                self.store.conn.execute_batch("RELEASE in_progress")?;
+                self.db = db;
+                Ok(report)
+            },
+            Err(e) => {
+                self.store.conn.execute_batch("ROLLBACK TO in_progress; RELEASE in_progress")?;
+                Err(e)
+            },
+        }
+    }
+
+    fn apply(&mut self, entities: &[Entity], caller: Option<&str>) -> Result<(TxReport, DB)> {
+        let hints = TempidHints::default();
+        let options = self.store.tx_options(&hints);
+        let (report, db) = match self.store.unknown_attributes.guess(&self.db, entities)? {
+            Some(guessed) => guessed.transact(&self.store.conn, &options)?,
+            None => mentat_db::transact_with_options(&self.store.conn, &self.db, entities, &options)?,
+        };
+        self.store.interceptors.check(&self.store.conn, &db.schema, report.tx_id, caller, self.store.cipher())?;
+        let db = self.store.update_derived(&self.store.conn, report.tx_id, db)?;
+        if let Some(change) = self.store.tx_change(&self.store.conn, &report)? {
+            self.changes.push(change);
+        }
+        Ok((report, db))
+    }
+
+    /// Parse, translate, and run the given query string once, with or without the uncommitted
+    /// transactions.
+    ///
+    /// Excluding them queries the store as of `basis_tx`, as `Store::q_once_as_of` does, so any
+    /// `:as-of` in the query is overridden.  The results are stamped with `basis_tx`.
+    pub fn q_once(&self, query: &str, consistency: Consistency) -> Result<QueryOutput> {
+        match consistency {
+            Consistency::IncludeInFlight => {
+                let parsed = parse_query(query)?;
+                self.store.unknown_attributes.check_query(&self.db.schema, &parsed)?;
+                run_query(&self.store.conn, &translate(&self.db.schema, &parsed)?, self.store.cipher())
+            },
+            Consistency::ExcludeInFlight => {
+                let mut parsed = self.store.parse_known_query(query)?;
+                parsed.as_of = Some(PointInTime::Tx(self.basis_tx));
+                let mut output = run_find_query(&self.store.conn, &self.store.db.schema, &parsed, QueryInputs::new(), self.store.cipher())?;
+                output.basis_tx = self.basis_tx;
+                Ok(output)
+            },
+        }
+    }
+
+    /// Commit the transactions applied so far.
+    pub fn commit(mut self) -> Result<()> {
+        self.store.conn.execute_batch("COMMIT")?;
+        self.finished = true;
+        let changes = ::std::mem::replace(&mut self.changes, vec![]);
+        self.store.notify(changes);
+        let db = ::std::mem::replace(&mut self.db, DB::default());
+        self.store.install_db(db)
+    }
+
+    /// Discard the transactions applied so far.
+    pub fn rollback(mut self) -> Result<()> {
+        self.finished = true;
+        self.store.conn.execute_batch("ROLLBACK")?;
+        Ok(())
+    }
+}
+
+impl<'a> Drop for InProgress<'a> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.store.conn.execute_batch("ROLLBACK");
+        }
+    }
+}
+
+/// A consistent view of a Mentat store, opened with `Store::begin_read`.
+///
+/// Owns its own read-only connection, holding a SQLite read transaction open on it; the schema and
+/// partition map are read inside that transaction, so they agree with the datoms queried.
+pub struct ReadTransaction {
+    conn: rusqlite::Connection,
+
+    /// The partition map and schema as of `basis_tx`.
+    db: DB,
+
+    /// The last transaction visible to this read transaction.
+    basis_tx: Entid,
+
+    /// The cipher of the store this read transaction was begun from, if any.
+    cipher: Option<Arc<Cipher>>,
+
+    /// The policy for unknown attributes of the store this read transaction was begun from.
+    unknown_attributes: UnknownAttributePolicy,
+}
+
+impl ReadTransaction {
+    fn begin(path: &str, cipher: Option<Arc<Cipher>>, unknown_attributes: UnknownAttributePolicy) -> Result<ReadTransaction> {
+        let conn = db::new_read_only_connection(path)?;
+        // A deferred transaction takes its snapshot at its first read, which is the basis query.
+        conn.execute("BEGIN DEFERRED", &[])?;
+        let basis_tx = basis_tx(&conn)?;
+        let db = db::read_db(&conn)?;
+        Ok(ReadTransaction {
+            conn: conn,
+            db: db,
+            basis_tx: basis_tx,
+            cipher: cipher,
+            unknown_attributes: unknown_attributes,
+        })
+    }
+
+    /// The last transaction visible to this read transaction.
+    pub fn basis_tx(&self) -> Entid {
+        self.basis_tx
+    }
+
+    fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_ref().map(|cipher| &**cipher)
+    }
+
+    fn prepare_query(&self, query: &str) -> Result<SQLQuery> {
+        let parsed = parse_query(query)?;
+        self.unknown_attributes.check_query(&self.db.schema, &parsed)?;
+        Ok(translate(&self.db.schema, &parsed)?)
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.db.schema
+    }
+
+    pub fn partition_map(&self) -> &PartitionMap {
+        &self.db.partition_map
+    }
+
+    pub fn q_once(&self, query: &str) -> Result<QueryOutput> {
+        run_query(&self.conn, &self.prepare_query(query)?, self.cipher())
+    }
+
+    pub fn q_once_keyed(&self, query: &str) -> Result<Vec<KeyedRow>> {
+        let sql_query = self.prepare_query(query)?;
+        let output = run_query(&self.conn, &sql_query, self.cipher())?;
+        Ok(output.results.into_keyed(&sql_query.find_spec))
+    }
+
+    pub fn q_once_edn(&self, query: &str) -> Result<edn::Value> {
+        let sql_query = self.prepare_query(query)?;
+        let output = run_query(&self.conn, &sql_query, self.cipher())?;
+        Ok(project::results(&output.results, &sql_query.find_spec))
+    }
+}
+
+impl Drop for ReadTransaction {
+    fn drop(&mut self) {
+        // Nothing was written, so there's nothing to lose; closing the connection would end the
+        // transaction anyway.
+        let _ = self.conn.execute("ROLLBACK", &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use edn::{NamespacedKeyword, PlainSymbol};
+    use mentat_db::XorCipher;
+    use mentat_query::Variable;
+    use mentat_query_translator;
+
+    use query::{EmptyBecause, IndexHint, QueryResults};
+
+    fn test_store() -> Store {
+        let mut store = Store::open("").expect("Couldn't open in-memory store");
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]
+                           [:db/add "n" :db/unique :db.unique/identity]
+                           [:db/add "t" :db/ident :test/tag]
+                           [:db/add "t" :db/valueType :db.type/keyword]
+                           [:db/add "t" :db/cardinality :db.cardinality/many]]"#).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_open_and_transact() {
+        let mut store = test_store();
+        assert!(store.schema().get_entid(&":test/name".to_string()).is_some());
+
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                                        [:db/add "a" :test/tag :tag/one]
+                                        [:db/add "a" :test/tag :tag/two]]"#).unwrap();
+        let alice = report.tempids["a"];
+
+        assert_eq!(store.q_once(r#"[:find ?x . :where [?x :test/name "Alice"]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(alice))));
+        assert_eq!(store.q(r#"[:find [?x ...] :where [?x :test/name "Bob"]]"#).unwrap().results,
+                   QueryResults::Coll(vec![]));
+
+        let pulled = store.pull(alice, &[":test/name"]).unwrap();
+        assert_eq!(pulled[":test/name"], vec![TypedValue::String("Alice".to_string())]);
+
+        let entity = store.entity(alice).unwrap();
+        assert_eq!(entity.len(), 2);
+        assert_eq!(entity[":test/tag"].len(), 2);
+    }
+
+    #[test]
+    fn test_schema_introspection() {
+        let mut store = test_store();
+
+        // The bootstrap schema is made of ordinary datoms.
+        assert_eq!(store.q_once(r#"[:find [?ident ...] :where [?a :db/ident ?ident] [?a :db/index true]]"#).unwrap().results,
+                   QueryResults::Coll(vec![TypedValue::Keyword(NamespacedKeyword::new("db", "txInstant"))]));
+
+        // So are user-installed attributes, and their idents resolve in queries.
+        store.transact(r#"[[:db/add "x" :db/ident :test/age]
+                           [:db/add "x" :db/valueType :db.type/long]
+                           [:db/add "x" :db/index true]]"#).unwrap();
+        let age = *store.schema().get_entid(&":test/age".to_string()).unwrap();
+        assert_eq!(store.q_once(r#"[:find ?a . :where [?a :db/valueType :db.type/long] [?a :db/index true] [?a :db/ident :test/age]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(age))));
+        assert_eq!(store.q_once(r#"[:find ?type . :where [:test/age :db/valueType ?t] [?t :db/ident ?type]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Keyword(NamespacedKeyword::new("db.type", "long")))));
+    }
+
+    #[test]
+    fn test_keyword_values() {
+        let mut store = test_store();
+        let report = store.transact(r#"[[:db/add "a" :test/tag :tag/one]
+                                        [:db/add "b" :test/tag :tag/two]]"#).unwrap();
+
+        assert_eq!(store.q_once(r#"[:find ?x . :where [?x :test/tag :tag/two]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(report.tempids["b"]))));
+
+        match store.q_once(r#"[:find [?t ...] :where [_ :test/tag ?t]]"#).unwrap().results {
+            QueryResults::Coll(mut tags) => {
+                tags.sort();
+                assert_eq!(tags, vec![TypedValue::Keyword(NamespacedKeyword::new("tag", "one")),
+                                      TypedValue::Keyword(NamespacedKeyword::new("tag", "two"))]);
+            },
+            x => panic!("expected Coll, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_constant_round_trip() {
+        let mut store = test_store();
+
+        // Every storable value type, a transaction value, and the same value as a query constant.
+        let cases = vec![
+            ("ref", "65536", "65536"),
+            ("boolean", "true", "true"),
+            ("long", "7", "7"),
+            ("double", "1.5", "1.5"),
+            ("string", r#""7""#, r#""7""#),
+            ("keyword", ":foo/bar", ":foo/bar"),
+        ];
+
+        let mut entities = BTreeMap::new();
+        for (value_type, tx_value, query_value) in cases {
+            store.transact(&format!("[[:db/add \"a\" :db/ident :test/{}]
+                                      [:db/add \"a\" :db/valueType :db.type/{}]]", value_type, value_type)).unwrap();
+            let report = store.transact(&format!("[[:db/add \"e\" :test/{} {}]]", value_type, tx_value)).unwrap();
+            let e = report.tempids["e"];
+            entities.insert(value_type, e);
+
+            let query = format!("[:find ?e . :where [?e :test/{} {}]]", value_type, query_value);
+            assert_eq!(store.q_once(&query).unwrap().results, QueryResults::Scalar(Some(TypedValue::Ref(e))),
+                       "constant round trip for {}", value_type);
+
+            // Projecting the value yields a value of the attribute's type.
+            let query = format!("[:find ?v . :where [{} :test/{} ?v]]", e, value_type);
+            match store.q_once(&query).unwrap().results {
+                QueryResults::Scalar(Some(ref v)) => assert_eq!(format!("{:?}", v.value_type()).to_lowercase(), value_type),
+                x => panic!("expected a scalar for {}, got {:?}", value_type, x),
+            }
+        }
+
+        // Affinity: even without a known attribute, the string "7" and the long 7 don't match each
+        // other, and neither do the double 7.0 and the long 7.
+        store.transact("[[:db/add \"d\" :test/double 7.0]]").unwrap();
+        assert_eq!(store.q_once(r#"[:find [?e ...] :where [?e ?a 7]]"#).unwrap().results,
+                   QueryResults::Coll(vec![TypedValue::Ref(entities["long"])]));
+        assert_eq!(store.q_once(r#"[:find [?e ...] :where [?e ?a "7"]]"#).unwrap().results,
+                   QueryResults::Coll(vec![TypedValue::Ref(entities["string"])]));
+    }
+
+    #[test]
+    fn test_time_travel() {
+        // Instants never decrease, so start from scratch and write our own.
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]
+                           [:db/add "datomic.tx" :db/txInstant 500]]"#).unwrap();
+        let first = store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                                       [:db/add "datomic.tx" :db/txInstant 1000]]"#).unwrap();
+        let alice = first.tempids["a"];
+        let second = store.transact(&format!("[[:db/add {} :test/name \"Alicia\"]
+                                                [:db/add \"b\" :test/name \"Bob\"]
+                                                [:db/add \"datomic.tx\" :db/txInstant 2000]]", alice)).unwrap();
+
+        let names = |store: &Store, query: &str| -> Vec<TypedValue> {
+            match store.q_once(query).unwrap().results {
+                QueryResults::Coll(mut names) => { names.sort(); names },
+                x => panic!("expected Coll, got {:?}", x),
+            }
+        };
+        let s = |x: &str| TypedValue::String(x.to_string());
+
+        assert_eq!(names(&store, r#"[:find [?name ...] :where [_ :test/name ?name]]"#),
+                   vec![s("Alicia"), s("Bob")]);
+        assert_eq!(names(&store, &format!("[:find [?name ...] :as-of {} :where [_ :test/name ?name]]", first.tx_id)),
+                   vec![s("Alice")]);
+        assert_eq!(names(&store, r#"{:find [[?name ...]] :where [[_ :test/name ?name]] :as-of #inst "1970-01-01T00:00:01.500Z"}"#),
+                   vec![s("Alice")]);
+        assert_eq!(names(&store, r#"[:find [?name ...] :as-of #inst "1970-01-01T00:00:00.999Z" :where [_ :test/name ?name]]"#),
+                   vec![]);
+        assert_eq!(names(&store, &format!("[:find [?name ...] :since {} :where [_ :test/name ?name]]", first.tx_id)),
+                   vec![s("Alicia"), s("Bob")]);
+        assert_eq!(names(&store, &format!("[:find [?name ...] :as-of {} :since {} :where [_ :test/name ?name]]", second.tx_id, first.tx_id)),
+                   vec![s("Alicia"), s("Bob")]);
+
+        // The API parameter.
+        assert_eq!(store.q_once_as_of(r#"[:find ?x . :where [?x :test/name "Alice"]]"#, PointInTime::Tx(first.tx_id)).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(alice))));
+        assert_eq!(store.q_once_as_of(r#"[:find ?x . :where [?x :test/name "Bob"]]"#, PointInTime::Instant(1000)).unwrap().results,
+                   QueryResults::Scalar(None));
+
+        // Named queries carry their own window.
+        store.register_query("then", &format!("[:find ?x . :in $ ?name :as-of {} :where [?x :test/name ?name]]", first.tx_id)).unwrap();
+        let mut inputs = QueryInputs::new();
+        inputs.insert(Variable(PlainSymbol::new("?name")), s("Bob"));
+        assert_eq!(store.q_named("then", inputs).unwrap().results, QueryResults::Scalar(None));
+
+        let mut inputs = QueryInputs::new();
+        inputs.insert(Variable(PlainSymbol::new("?name")), s("Alicia"));
+        assert_eq!(store.q_once_with_inputs("[:find ?x . :in $ ?name :where [?x :test/name ?name]]", inputs).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(alice))));
+    }
+
+    #[test]
+    fn test_derived_attribute() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "f" :db/ident :test/friend]
+                           [:db/add "f" :db/valueType :db.type/ref]
+                           [:db/add "f" :db/cardinality :db.cardinality/many]
+                           [:db/add "c" :db/ident :test/friend-count]
+                           [:db/add "c" :db/valueType :db.type/long]]"#).unwrap();
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                                        [:db/add "b" :test/name "Bob"]
+                                        [:db/add "c" :test/name "Carol"]
+                                        [:db/add "a" :test/friend "b"]]"#).unwrap();
+        let (alice, bob, carol) = (report.tempids["a"], report.tempids["b"], report.tempids["c"]);
+
+        let count = |store: &Store, e: Entid| -> QueryResults {
+            store.q_once(&format!("[:find ?n . :where [{} :test/friend-count ?n]]", e)).unwrap().results
+        };
+
+        // Existing data is derived on registration.
+        store.register_derived_attribute(":test/friend-count",
+                                         "[:find ?e ?f :where [?e :test/friend ?f]]",
+                                         Derivation::Count).unwrap();
+        assert_eq!(count(&store, alice), QueryResults::Scalar(Some(TypedValue::Long(1))));
+        assert_eq!(count(&store, bob), QueryResults::Scalar(None));
+
+        // Maintained on transact, and queryable like any attribute.
+        store.transact(&format!("[[:db/add {} :test/friend {}] [:db/add {} :test/friend {}]]", alice, carol, bob, alice)).unwrap();
+        assert_eq!(count(&store, alice), QueryResults::Scalar(Some(TypedValue::Long(2))));
+        assert_eq!(count(&store, bob), QueryResults::Scalar(Some(TypedValue::Long(1))));
+        assert_eq!(store.q_once("[:find ?e . :where [?e :test/friend-count 2]]").unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(alice))));
+
+        // Unrelated transactions don't recompute anything.
+        let before = store.partition_map()[":db.part/tx"].index;
+        store.transact(r#"[[:db/add "d" :test/name "Dave"]]"#).unwrap();
+        assert_eq!(store.partition_map()[":db.part/tx"].index, before + 1);
+
+        store.transact(&format!("[[:db/retract {} :test/friend {}]]", bob, alice)).unwrap();
+        assert_eq!(count(&store, bob), QueryResults::Scalar(None));
+
+        match store.transact(&format!("[[:db/add {} :test/friend-count 10]]", alice)) {
+            Err(Error(ErrorKind::DerivedAttributeWrite(_), _)) => (),
+            x => panic!("expected DerivedAttributeWrite, got {:?}", x),
+        }
+        match store.register_derived_attribute(":test/name", "[:find ?e ?f :where [?e :test/friend ?f]]", Derivation::Count) {
+            Err(Error(ErrorKind::InvalidDerivedAttribute(_), _)) => (),
+            x => panic!("expected InvalidDerivedAttribute, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_derived_attribute_schema_change() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "c" :db/ident :test/follower-count]
+                           [:db/add "c" :db/valueType :db.type/long]]"#).unwrap();
+        let alice = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap().tempids["a"];
+        let count = |store: &Store| -> QueryResults {
+            store.q_once(&format!("[:find ?n . :where [{} :test/follower-count ?n]]", alice)).unwrap().results
+        };
+
+        // The query reads an attribute that isn't installed yet, and is resolved once it is.
+        store.register_derived_attribute(":test/follower-count",
+                                         "[:find ?e ?f :where [?e :test/follower ?f]]",
+                                         Derivation::Count).unwrap();
+        store.transact(r#"[[:db/add "f" :db/ident :test/follower]
+                           [:db/add "f" :db/valueType :db.type/ref]
+                           [:db/add "f" :db/cardinality :db.cardinality/many]]"#).unwrap();
+        store.transact(&format!("[[:db/add {} :test/follower {}]]", alice, alice)).unwrap();
+        assert_eq!(count(&store), QueryResults::Scalar(Some(TypedValue::Long(1))));
+
+        // Renaming the attribute leaves the query reading nothing.
+        store.transact(r#"[[:db/add :test/follower :db/ident :test/fan]]"#).unwrap();
+        assert_eq!(count(&store), QueryResults::Scalar(None));
+    }
+
+    #[test]
+    fn test_history() {
+        let mut store = test_store();
+        let created = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let alice = created.tempids["a"];
+        let renamed = store.transact(&format!("[[:db/add {} :test/name \"Alicia\"]]", alice)).unwrap();
+
+        // Every value the attribute ever had, and when it was removed.
+        let query = format!("[:find ?name ?tx ?added :history true :where [{} :test/name ?name ?tx ?added]]", alice);
+        let mut rows = match store.q_once(&query).unwrap().results {
+            QueryResults::Rel(rows) => rows,
+            x => panic!("expected Rel, got {:?}", x),
+        };
+        rows.sort();
+        let s = |x: &str| TypedValue::String(x.to_string());
+        assert_eq!(rows, vec![vec![s("Alice"), TypedValue::Ref(created.tx_id), TypedValue::Boolean(true)],
+                              vec![s("Alice"), TypedValue::Ref(renamed.tx_id), TypedValue::Boolean(false)],
+                              vec![s("Alicia"), TypedValue::Ref(renamed.tx_id), TypedValue::Boolean(true)]]);
+
+        let query = format!("[:find [?name ...] :history true :where [{} :test/name ?name _ false]]", alice);
+        assert_eq!(store.q_once(&query).unwrap().results, QueryResults::Coll(vec![s("Alice")]));
+    }
+
+    #[test]
+    fn test_schema_history() {
+        let mut store = test_store();
+        let installed = store.transact(r#"[[:db/add "t" :db/ident :test/tags]
+                                           [:db/add "t" :db/valueType :db.type/string]
+                                           [:db/add "t" :db/cardinality :db.cardinality/one]]"#).unwrap();
+        let tags = installed.tempids["t"];
+        let altered = store.transact(r#"[[:db/add :test/tags :db/cardinality :db.cardinality/many]]"#).unwrap();
+
+        // Schema changes are datoms in the log, so their history is queried like any other.
+        let query = format!("[:find ?c ?tx ?added :history true :where [{} :db/cardinality ?c ?tx ?added]]", tags);
+        let mut rows = match store.q_once(&query).unwrap().results {
+            QueryResults::Rel(rows) => rows,
+            x => panic!("expected Rel, got {:?}", x),
+        };
+        rows.sort();
+        let entid = |ident: &str| TypedValue::Ref(*store.schema().get_entid(&ident.to_string()).unwrap());
+        let (one, many) = (entid(":db.cardinality/one"), entid(":db.cardinality/many"));
+        assert_eq!(rows, vec![vec![one.clone(), TypedValue::Ref(installed.tx_id), TypedValue::Boolean(true)],
+                              vec![one, TypedValue::Ref(altered.tx_id), TypedValue::Boolean(false)],
+                              vec![many, TypedValue::Ref(altered.tx_id), TypedValue::Boolean(true)]]);
+    }
+
+    #[test]
+    fn test_first_and_last_asserted() {
+        let mut store = test_store();
+        let created = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let alice = created.tempids["a"];
+        let renamed = store.transact(&format!("[[:db/add {} :test/name \"Alicia\"]]", alice)).unwrap();
+        store.transact(&format!("[[:db/retract {} :test/name \"Alicia\"]]", alice)).unwrap();
+
+        assert_eq!(store.first_asserted(alice, ":test/name").unwrap(),
+                   Some(Assertion {
+                       value: TypedValue::String("Alice".to_string()),
+                       tx: created.tx_id,
+                       tx_instant: Some(created.tx_instant),
+                   }));
+        assert_eq!(store.last_asserted(alice, ":test/name").unwrap(),
+                   Some(Assertion {
+                       value: TypedValue::String("Alicia".to_string()),
+                       tx: renamed.tx_id,
+                       tx_instant: Some(renamed.tx_instant),
+                   }));
+
+        assert_eq!(store.first_asserted(alice, ":test/tag").unwrap(), None);
+        assert!(store.first_asserted(alice, ":test/unknown").is_err());
+    }
+
+    #[test]
+    fn test_fulltext_search() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "x" :db/ident :test/text]
+                           [:db/add "x" :db/valueType :db.type/string]
+                           [:db/add "x" :db/fulltext true]]"#).unwrap();
+        let report = store.transact(r#"[[:db/add "a" :test/text "the quick brown fox jumps over the lazy dog"]
+                                        [:db/add "b" :test/text "fox, fox, and more fox"]
+                                        [:db/add "c" :test/text "no match here"]]"#).unwrap();
+
+        let results = store.q_once(r#"[:find ?e ?score ?snippet
+                                        :where [(fulltext $ :test/text "fox") [[?e _ _ ?score ?snippet]]]]"#).unwrap().results;
+        let mut rows = match results {
+            QueryResults::Rel(rows) => rows,
+            x => panic!("expected Rel, got {:?}", x),
+        };
+        assert_eq!(rows.len(), 2);
+
+        // The value that's mostly fox ranks higher.
+        rows.sort_by(|x, y| y[1].cmp(&x[1]));
+        assert_eq!(rows[0][0], TypedValue::Ref(report.tempids["b"]));
+        assert_eq!(rows[1][0], TypedValue::Ref(report.tempids["a"]));
+        match rows[1][2] {
+            TypedValue::String(ref snippet) => assert!(snippet.contains("brown <b>fox</b> jumps"), "unexpected snippet: {}", snippet),
+            ref x => panic!("expected String, got {:?}", x),
+        }
+
+        // The value and transaction can be bound too.
+        assert_eq!(store.q_once(r#"[:find ?text . :where [(fulltext $ :test/text "lazy") [[_ ?text ?tx]]]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::String("the quick brown fox jumps over the lazy dog".to_string()))));
+    }
+
+    #[test]
+    fn test_fulltext_tokenizer() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "x" :db/ident :test/text]
+                           [:db/add "x" :db/valueType :db.type/string]
+                           [:db/add "x" :db/fulltext true]]"#).unwrap();
+        let report = store.transact(r#"[[:db/add "a" :test/text "the fox jumps"]]"#).unwrap();
+        let query = r#"[:find ?e . :where [(fulltext $ :test/text "jumping") [[?e]]]]"#;
+        assert_eq!(store.q_once(query).unwrap().results, QueryResults::Scalar(None));
+
+        // Configuring a stemming tokenizer indexes the existing values in the attribute's own table.
+        store.transact(r#"[[:db/add :test/text :db.fulltext/tokenizer "porter unicode61"]
+                           [:db/add :test/text :db.fulltext/prefix 2]
+                           [:db/add :test/text :db.fulltext/prefix 3]]"#).unwrap();
+        let attribute = store.schema().attribute_for_entid(store.schema().get_entid(&":test/text".to_string()).unwrap()).unwrap().clone();
+        assert_eq!(attribute.fulltext_tokenizer, Some("porter unicode61".to_string()));
+        assert_eq!(attribute.fulltext_prefixes.into_iter().collect::<Vec<i64>>(), vec![2, 3]);
+        assert_eq!(store.q_once(query).unwrap().results, QueryResults::Scalar(Some(TypedValue::Ref(report.tempids["a"]))));
+
+        // New values are indexed too.
+        let report = store.transact(r#"[[:db/add "b" :test/text "jumped the shark"]]"#).unwrap();
+        assert_eq!(store.q_once(r#"[:find ?e . :where [(fulltext $ :test/text "shark") [[?e]]]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(report.tempids["b"]))));
+
+        // Tokenizers are interpolated into SQL, so they're restricted to words.
+        assert!(store.transact(r#"[[:db/add :test/text :db.fulltext/tokenizer "porter'); DROP TABLE datoms; --"]]"#).is_err());
+
+        // An imported copy has the attribute's own table too.
+        let mut exported = vec![];
+        store.export_datoms(&mut exported, None, ExportFormat::NdEdn).unwrap();
+        let mut copy = Store::open("").unwrap();
+        copy.import_datoms(&exported[..], ExportFormat::NdEdn).unwrap();
+        match copy.q_once(query).unwrap().results {
+            QueryResults::Scalar(Some(TypedValue::Ref(_))) => (),
+            x => panic!("expected the imported entity, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_bytes() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "x" :db/ident :test/icon]
+                           [:db/add "x" :db/valueType :db.type/bytes]]"#).unwrap();
+        let report = store.transact(r#"[[:db/add "a" :test/icon #bytes "aGk="]]"#).unwrap();
+        let a = report.tempids["a"];
+        let icon = TypedValue::Bytes(b"hi".to_vec());
+
+        assert_eq!(store.q_once(r#"[:find ?v . :where [_ :test/icon ?v]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(icon.clone())));
+        assert_eq!(store.q_once(r#"[:find ?e . :where [?e :test/icon #bytes "aGk="]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(a))));
+        assert_eq!(store.q_once(r#"[:find ?e . :where [?e :test/icon #bytes "aGo="]]"#).unwrap().results,
+                   QueryResults::Scalar(None));
+
+        store.register_query("byicon", r#"[:find ?e . :in $ ?icon :where [?e :test/icon ?icon]]"#).unwrap();
+        let mut inputs = QueryInputs::new();
+        inputs.insert(Variable(PlainSymbol::new("?icon")), icon);
+        assert_eq!(store.q_named("byicon", inputs).unwrap().results, QueryResults::Scalar(Some(TypedValue::Ref(a))));
+
+        // Strings aren't bytes.
+        assert!(store.transact(r#"[[:db/add "b" :test/icon "hi"]]"#).is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "x" :db/ident :test/payload]
+                           [:db/add "x" :db/valueType :db.type/json]]"#).unwrap();
+        let report = store.transact(r#"[[:db/add "a" :test/payload "{\"name\": \"Alice\", \"age\": 30, \"tags\": [\"x\"]}"]
+                                        [:db/add "b" :test/payload "{\"name\": null}"]]"#).unwrap();
+        let a = report.tempids["a"];
+
+        assert_eq!(store.q_once(r#"[:find ?e ?name :where [?e :test/payload ?v] [(json-get ?v "$.name") ?name]]"#).unwrap().results,
+                   QueryResults::Rel(vec![vec![TypedValue::Ref(a), TypedValue::String("Alice".to_string())]]));
+        assert_eq!(store.q_once(r#"[:find ?age . :where [_ :test/payload ?v] [(json-get ?v "$.age") ?age]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Long(30))));
+        assert_eq!(store.q_once(r#"[:find ?tags . :where [_ :test/payload ?v] [(json-get ?v "$.tags") ?tags]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Json(r#"["x"]"#.to_string()))));
+
+        // Extracted values join like any other.
+        store.transact(r#"[[:db/add "c" :test/name "Alice"]]"#).unwrap();
+        assert_eq!(store.q_once(r#"[:find ?e . :where [?e :test/payload ?v] [(json-get ?v "$.name") ?name] [_ :test/name ?name]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(a))));
+
+        // Documents must parse.
+        assert!(store.transact(r#"[[:db/add "d" :test/payload "{name: 1}"]]"#).is_err());
+        assert!(store.transact(r#"[[:db/add "d" :test/payload "[1] [2]"]]"#).is_err());
+    }
+
+    #[test]
+    fn test_tuples() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "x" :db/ident :test/point]
+                           [:db/add "x" :db/valueType :db.type/tuple]
+                           [:db/add "x" :db/tupleTypes [:db.type/double :db.type/string :db.type/keyword]]]"#).unwrap();
+        let report = store.transact(r#"[[:db/add "a" :test/point [1.0 "north" :test/admin]]
+                                        [:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let a = report.tempids["a"];
+        let point = TypedValue::Tuple(vec![TypedValue::Double(1.0.into()),
+                                           TypedValue::String("north".to_string()),
+                                           TypedValue::Keyword(NamespacedKeyword::new("test", "admin"))]);
+
+        assert_eq!(store.q_once("[:find ?p . :where [_ :test/point ?p]]").unwrap().results,
+                   QueryResults::Scalar(Some(point)));
+        for (index, component) in vec![TypedValue::Double(1.0.into()),
+                                       TypedValue::String("north".to_string()),
+                                       TypedValue::Keyword(NamespacedKeyword::new("test", "admin"))].into_iter().enumerate() {
+            assert_eq!(store.q_once(&format!("[:find ?x . :where [_ :test/point ?p] [(tuple-get ?p {}) ?x]]", index)).unwrap().results,
+                       QueryResults::Scalar(Some(component)));
+        }
+
+        // Extracted components can be constrained like any other value.
+        assert_eq!(store.q_once(r#"[:find ?e . :where [?e :test/point ?p] [(tuple-get ?p 1) ?dir] [_ :test/name ?dir]]"#).unwrap().results,
+                   QueryResults::Scalar(None));
+        store.transact(r#"[[:db/add "b" :test/name "north"]]"#).unwrap();
+        assert_eq!(store.q_once(r#"[:find ?e . :where [?e :test/point ?p] [(tuple-get ?p 1) ?dir] [_ :test/name ?dir]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(a))));
+        assert_eq!(store.q_once("[:find ?x . :where [_ :test/point ?p] [(tuple-get ?p 3) ?x]]").unwrap().results,
+                   QueryResults::Scalar(None));
+    }
+
+    #[test]
+    fn test_count() {
+        let mut store = test_store();
+        store.set_unknown_attributes(UnknownAttributePolicy::Permissive);
+        store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                           [:db/add "a" :test/tag :test/admin]
+                           [:db/add "a" :test/tag :test/guest]
+                           [:db/add "b" :test/name "Bob"]
+                           [:db/add "b" :test/tag :test/guest]]"#).unwrap();
+
+        // Alice has two tags, but is counted once.
+        assert_eq!(store.q_once("[:find (count ?e) . :where [?e :test/tag _]]").unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Long(2))));
+        assert_eq!(store.q_once("[:find (count ?t) . :where [_ :test/tag ?t]]").unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Long(2))));
+        assert_eq!(store.q_once("[:find (count ?e) . :where [?e :test/unknown _]]").unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Long(0))));
+
+        assert_eq!(store.count("[:find (count ?e) . :where [?e :test/tag _]]").unwrap(), 2);
+        assert_eq!(store.count("[:find ?e ?t :where [?e :test/tag ?t]]").unwrap(), 3);
+        assert_eq!(store.count("[:find [?t ...] :where [_ :test/tag ?t]]").unwrap(), 2);
+        assert_eq!(store.count("[:find ?e . :where [?e :test/tag _]]").unwrap(), 1);
+        assert_eq!(store.count("[:find ?e :where [?e :test/unknown _]]").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_exists() {
+        let mut store = test_store();
+        store.set_unknown_attributes(UnknownAttributePolicy::Permissive);
+        store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                           [:db/add "a" :test/tag :test/admin]]"#).unwrap();
+
+        assert!(store.exists(r#"[:find ?e :where [?e :test/name "Alice"]]"#).unwrap());
+        assert!(store.exists("[:find ?e . :where [?e :test/tag :test/admin]]").unwrap());
+        assert!(!store.exists(r#"[:find ?e :where [?e :test/name "Bob"]]"#).unwrap());
+        assert!(!store.exists("[:find ?e :where [?e :test/unknown _]]").unwrap());
+        assert!(store.exists("[:find ?e ?x :where [?e :test/name ?x]]").is_ok());
+        assert!(store.exists("[:find ?e :where [?x :test/name _]]").is_err());
+    }
+
+    #[test]
+    fn test_instant_functions() {
+        let mut store = test_store();
+        // 2030-01-01T00:00:00Z, and 2030-01-02T01:00:00Z.
+        store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                           [:db/add "datomic.tx" :db/txInstant 1893456000000]]"#).unwrap();
+        store.transact(r#"[[:db/add "b" :test/name "Bob"]
+                           [:db/add "datomic.tx" :db/txInstant 1893546000000]]"#).unwrap();
+        let days = |store: &Store, query: &str| {
+            match store.q_once(query).unwrap().results {
+                QueryResults::Coll(mut days) => {
+                    days.sort();
+                    days
+                },
+                results => panic!("expected a collection, got {:?}", results),
+            }
+        };
+        let s = |x: &str| TypedValue::String(x.to_string());
+
+        assert_eq!(days(&store, r#"[:find [?day ...] :where [_ :db/txInstant ?t]
+                                    [(between ?t #inst "2030-01-01T00:00:00Z" #inst "2030-01-03T00:00:00Z")]
+                                    [(date ?t) ?day]]"#),
+                   vec![s("2030-01-01"), s("2030-01-02")]);
+        // Five hours behind UTC, both transactions were on New Year's Day.
+        assert_eq!(days(&store, r#"[:find [?day ...] :where [_ :db/txInstant ?t]
+                                    [(between ?t #inst "2030-01-01T00:00:00Z" #inst "2030-01-03T00:00:00Z")]
+                                    [(date ?t "-05:00") ?day]]"#),
+                   vec![s("2030-01-01")]);
+        // The end of a range is excluded.
+        assert_eq!(days(&store, r#"[:find [?day ...] :where [_ :db/txInstant ?t]
+                                    [(between ?t #inst "2030-01-01T00:00:00Z" #inst "2030-01-02T01:00:00Z")]
+                                    [(date ?t) ?day]]"#),
+                   vec![s("2030-01-01")]);
+
+        // 2029-12-31T00:00:00-05:00, and 2030-01-02T01:00:00Z.
+        assert_eq!(store.q_once(r#"[:find ?start . :where [_ :db/txInstant ?t] [(between ?t 1893456000000 1893456000001)]
+                                    [(truncate ?t "day" "-05:00") ?start]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Long(1893387600000))));
+        assert_eq!(store.q_once(r#"[:find ?start . :where [_ :db/txInstant ?t] [(between ?t 1893546000000 1893546000001)]
+                                    [(truncate ?t "hour") ?start]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Long(1893546000000))));
+
+        assert!(store.q_once(r#"[:find ?day :where [_ :db/txInstant ?t] [(date ?t "EST") ?day]]"#).is_err());
+        assert!(store.q_once(r#"[:find ?start :where [_ :db/txInstant ?t] [(truncate ?t "fortnight") ?start]]"#).is_err());
+    }
+
+    #[test]
+    fn test_or_and_not() {
+        let mut store = test_store();
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                                        [:db/add "a" :test/tag :test/admin]
+                                        [:db/add "b" :test/name "Bob"]
+                                        [:db/add "b" :test/tag :test/guest]
+                                        [:db/add "c" :test/name "Carol"]]"#).unwrap();
+        let (alice, bob, carol) = (report.tempids["a"], report.tempids["b"], report.tempids["c"]);
+        let s = |x: &str| TypedValue::String(x.to_string());
+
+        // Unions don't come back in any particular order.
+        let sorted = |mut xs: Vec<TypedValue>| { xs.sort(); xs };
+        let coll = |store: &Store, query: &str| -> Vec<TypedValue> {
+            match store.q_once(query).unwrap().results {
+                QueryResults::Coll(mut xs) => { xs.sort(); xs },
+                results => panic!("expected a collection, got {:?}", results),
+            }
+        };
+
+        assert_eq!(coll(&store, "[:find [?name ...] :where [?e :test/name ?name] (not [?e :test/tag _])]"),
+                   vec![s("Carol")]);
+        assert_eq!(coll(&store, "[:find [?name ...] :where [?e :test/name ?name] (not-join [?e] [?e :test/tag :test/admin])]"),
+                   vec![s("Bob"), s("Carol")]);
+        assert_eq!(coll(&store, r#"[:find [?e ...] :where (or [?e :test/tag :test/admin] [?e :test/name "Carol"])]"#),
+                   sorted(vec![TypedValue::Ref(alice), TypedValue::Ref(carol)]));
+        assert_eq!(coll(&store, r#"[:find [?name ...] :where [?e :test/name ?name] (or-join [?e] [?e :test/tag ?tag] [?e :test/name "Carol"])]"#),
+                   vec![s("Alice"), s("Bob"), s("Carol")]);
+
+        // Legs can bind values of different types.
+        let mut rows = match store.q_once(r#"[:find ?e ?v :where (or [?e :test/name ?v] (and [?e :test/tag ?v] [?e :test/name "Bob"]))]"#).unwrap().results {
+            QueryResults::Rel(rows) => rows,
+            results => panic!("expected a relation, got {:?}", results),
+        };
+        let mut expected = vec![vec![TypedValue::Ref(alice), s("Alice")],
+                                vec![TypedValue::Ref(bob), s("Bob")],
+                                vec![TypedValue::Ref(bob), TypedValue::Keyword(NamespacedKeyword::new("test", "guest"))],
+                                vec![TypedValue::Ref(carol), s("Carol")]];
+        rows.sort();
+        expected.sort();
+        assert_eq!(rows, expected);
+
+        assert!(store.q_once("[:find ?e :where (or [?e :test/name _] [?f :test/tag _])]").is_err());
+    }
+
+    #[test]
+    fn test_index_hints() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "a" :test/name "Alice"] [:db/add "a" :test/tag :test/admin]]"#).unwrap();
+        let query = r#"[:find ?tag :where [?x :test/name "Alice"] [?x :test/tag ?tag]]"#;
+
+        let plan = store.explain(query, &IndexHints::new()).unwrap();
+        assert!(!plan.steps.is_empty());
+        assert_eq!(plan.pattern_indexes.len(), 2);
+
+        let mut hints = IndexHints::new();
+        hints.insert(0, IndexHint::NotIndexed);
+        hints.insert(1, IndexHint::IndexedBy("idx_datoms_aevt".to_string()));
+        let plan = store.explain(query, &hints).unwrap();
+        assert_eq!(plan.pattern_indexes.get(&0), Some(&None));
+        assert_eq!(plan.pattern_indexes.get(&1), Some(&Some("idx_datoms_aevt".to_string())));
+
+        // Hints change the plan, not the results.
+        assert_eq!(store.q_once_with_hints(query, &hints).unwrap().results, store.q_once(query).unwrap().results);
+        let mut hints = IndexHints::new();
+        hints.insert(1, IndexHint::IndexedBy("idx_nonexistent".to_string()));
+        assert!(store.q_once_with_hints(query, &hints).is_err());
+    }
+
+    #[test]
+    fn test_explain_rewrites() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "x" :db/ident :test/text]
+                           [:db/add "x" :db/valueType :db.type/string]
+                           [:db/add "x" :db/fulltext true]]"#).unwrap();
+        let tags: Vec<String> = (0..UNANCHORED_ROWS).map(|i| format!("[:db/add \"a\" :test/tag :tag/t{}]", i)).collect();
+        store.transact(&format!(r#"[[:db/add "a" :test/name "Alice"] [:db/add "a" :test/text "quick fox"] {}]"#, tags.join(" "))).unwrap();
+
+        let rewrites = |query: &str| store.explain(query, &IndexHints::new()).unwrap().rewrites;
+        assert_eq!(rewrites(r#"[:find ?x :where [?x :test/name "Alice"] [?x :test/tag ?t]]"#), vec![]);
+
+        let unanchored = rewrites(r#"[:find ?x :where [?x :test/tag ?t] [?x :test/name "Alice"]]"#);
+        assert_eq!(unanchored.iter().map(|r| (r.kind, r.clause, r.estimated_rows)).collect::<Vec<_>>(),
+                   vec![(RewriteKind::Unanchored, 0, UNANCHORED_ROWS)]);
+
+        let wildcard = rewrites(r#"[:find ?e :where [(fulltext $ :test/text "*fox") [[?e _ _ _ _]]]]"#);
+        assert_eq!(wildcard.iter().map(|r| (r.kind, r.clause, r.estimated_rows)).collect::<Vec<_>>(),
+                   vec![(RewriteKind::LeadingWildcard, 0, 1)]);
+
+        let fan_out = rewrites(r#"[:find ?x :where [?x :test/name _]
+                                   (or [?x :test/tag :tag/t0] [?x :test/tag :tag/t1] [?x :test/tag :tag/t2]
+                                       [?x :test/tag :tag/t3] [?x :test/tag :tag/t4])]"#);
+        assert_eq!(fan_out.iter().map(|r| (r.kind, r.clause, r.estimated_rows)).collect::<Vec<_>>(),
+                   vec![(RewriteKind::OrFanOut, 1, 5)]);
+        assert!(store.explain(r#"[:find ?x :where [?x :test/name _] (or [?x :test/tag :tag/t0] [?x :test/tag :tag/t1])]"#, &IndexHints::new())
+                .unwrap().to_string().lines().all(|line| !line.starts_with("suggestion:")));
+        assert!(fan_out[0].to_string().starts_with("clause 1 (about 5 rows): "));
+    }
+
+    #[test]
+    fn test_tx_provenance() {
+        let mut store = test_store();
+        let first = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let second = store.transact(r#"[[:db/add "b" :test/name "Bob"] [:db/add "b" :test/tag :test/guest]]"#).unwrap();
+        let bob = second.tempids["b"];
+
+        // The fourth place of a pattern binds the transaction, which is an entity in its own right.
+        assert_eq!(store.q_once(r#"[:find ?tx . :where [?e :test/name "Alice" ?tx]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(first.tx_id))));
+        let query = r#"[:find ?when . :where [?e :test/name "Bob" ?tx] [?tx :db/txInstant ?when]]"#;
+        assert_eq!(store.q_once(query).unwrap().results, QueryResults::Scalar(Some(TypedValue::Long(second.tx_instant))));
+
+        let query = format!(r#"[:find [?tx ...] :where [(tx-ids $ {} {}) [?tx ...]]]"#, first.tx_id, second.tx_id + 1);
+        let mut txs = match store.q_once(&query).unwrap().results {
+            QueryResults::Coll(txs) => txs,
+            x => panic!("expected a collection, got {:?}", x),
+        };
+        txs.sort();
+        assert_eq!(txs, vec![TypedValue::Ref(first.tx_id), TypedValue::Ref(second.tx_id)]);
+
+        // The datoms of a transaction, including its own :db/txInstant.
+        let query = format!(r#"[:find ?e ?a :where [(tx-data $ {}) [[?e ?a]]]]"#, second.tx_id);
+        assert_eq!(store.count(&query).unwrap(), 3);
+        let query = format!(r#"[:find ?e ?v ?added :where [(tx-data $ {}) [[?e ?a ?v _ ?added]]] [?a :db/ident :test/name]]"#, second.tx_id);
+        assert_eq!(store.q_once(&query).unwrap().results,
+                   QueryResults::Rel(vec![vec![TypedValue::Ref(bob), TypedValue::String("Bob".to_string()), TypedValue::Boolean(true)]]));
+    }
+
+    #[test]
+    fn test_q_once_timed() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let query = r#"[:find ?x :where [?x :test/name "Alice"]]"#;
+        let (output, timings) = store.q_once_timed(query).unwrap();
+        assert_eq!(output, store.q_once(query).unwrap());
+        assert_eq!(timings.total(), timings.parse + timings.algebrize + timings.translate + timings.execute + timings.project);
+        assert!(timings.to_string().starts_with("parse "));
+        assert!(store.explain(query, &IndexHints::new()).unwrap().to_string().starts_with("SELECT"));
+    }
+
+    #[test]
+    fn test_known_empty() {
+        let mut store = test_store();
+        store.set_unknown_attributes(UnknownAttributePolicy::Permissive);
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+
+        // Nothing is run, but the results are still stamped.
+        let output = store.q_once(r#"[:find ?x :where [?x :test/unknown "Alice"]]"#).unwrap();
+        assert_eq!(output.results, QueryResults::Rel(vec![]));
+        assert_eq!(output.empty_because, Some(EmptyBecause::UnknownAttribute(":test/unknown".to_string())));
+        assert_eq!(output.basis_tx, report.tx_id);
+
+        let output = store.q_once(r#"[:find ?x . :where [?x :test/name true]]"#).unwrap();
+        assert_eq!(output.results, QueryResults::Scalar(None));
+        assert_eq!(output.empty_because, Some(EmptyBecause::ValueTypeMismatch(mentat_db::ValueType::String, TypedValue::Boolean(true))));
+
+        let output = store.q_once(r#"[:find ?x . :where [?x :test/name "Bob"]]"#).unwrap();
+        assert_eq!(output.results, QueryResults::Scalar(None));
+        assert_eq!(output.empty_because, None);
+    }
+
+    #[test]
+    fn test_keyed_results() {
+        let mut store = test_store();
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let alice = report.tempids["a"];
+
+        let rows = store.q_once_keyed(r#"[:find ?x :as :person/id ?name :where [?x :test/name ?name]]"#).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][":person/id"], TypedValue::Ref(alice));
+        assert_eq!(rows[0]["?name"], TypedValue::String("Alice".to_string()));
+    }
+
+    #[test]
+    fn test_named_queries() {
+        let mut store = test_store();
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                                        [:db/add "b" :test/name "Bob"]]"#).unwrap();
+
+        store.register_query("byname", r#"[:find ?x . :in $ ?name :where [?x :test/name ?name]]"#).unwrap();
+        assert!(store.register_query("bad", r#"[:find ?x :where"#).is_err());
+
+        let name = Variable(PlainSymbol::new("?name"));
+        for (tempid, value) in vec![("a", "Alice"), ("b", "Bob")] {
+            let mut inputs = QueryInputs::new();
+            inputs.insert(name.clone(), TypedValue::String(value.to_string()));
+            assert_eq!(store.q_named("byname", inputs).unwrap().results,
+                       QueryResults::Scalar(Some(TypedValue::Ref(report.tempids[tempid]))));
+        }
+
+        // Inputs are required.
+        assert!(store.q_named("byname", QueryInputs::new()).is_err());
+
+        match store.q_named("missing", QueryInputs::new()) {
+            Err(Error(ErrorKind::UnknownNamedQuery(_), _)) => (),
+            x => panic!("expected UnknownNamedQuery, got {:?}", x),
+        }
+
+        // Translations are cached until the schema changes: here, until an unknown attribute,
+        // which matches nothing, is installed.
+        store.set_unknown_attributes(UnknownAttributePolicy::Permissive);
+        store.register_query("bynick", r#"[:find ?x . :in $ ?nick :where [?x :test/nick ?nick]]"#).unwrap();
+        let mut inputs = QueryInputs::new();
+        inputs.insert(Variable(PlainSymbol::new("?nick")), TypedValue::String("Al".to_string()));
+        assert_eq!(store.q_named("bynick", inputs.clone()).unwrap().results, QueryResults::Scalar(None));
+        assert_eq!(store.named_query_cache["bynick"].len(), 1);
+
+        store.transact(&format!(r#"[[:db/add {} :test/nick "Al"]]"#, report.tempids["a"])).unwrap();
+        assert!(store.named_query_cache.is_empty());
+        assert_eq!(store.q_named("bynick", inputs).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(report.tempids["a"]))));
+    }
+
+    #[test]
+    fn test_ancestors_and_descendants() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "p" :db/ident :test/parent]
+                           [:db/add "p" :db/valueType :db.type/ref]]"#).unwrap();
+        // A cycle: a's parent is b, whose parent is c, whose parent is a.
+        let report = store.transact(r#"[[:db/add "a" :test/parent "b"]
+                                        [:db/add "b" :test/parent "c"]
+                                        [:db/add "c" :test/parent "a"]]"#).unwrap();
+        let (a, b, c) = (report.tempids["a"], report.tempids["b"], report.tempids["c"]);
+        let walk = |store: &Store, query: String| -> Vec<Vec<TypedValue>> {
+            match store.q_once(&query).unwrap().results {
+                QueryResults::Rel(mut rows) => {
+                    rows.sort_by_key(|row| row[1].clone());
+                    rows
+                },
+                x => panic!("expected Rel, got {:?}", x),
+            }
+        };
+        let row = |e: Entid, depth: i64| vec![TypedValue::Ref(e), TypedValue::Long(depth)];
+
+        // Each entity is reached once, by its shortest path, and the walk ends at the cycle.
+        assert_eq!(walk(&store, format!("[:find ?x ?depth :where [(ancestors {} :test/parent) [[?x ?depth]]]]", a)),
+                   vec![row(b, 1), row(c, 2), row(a, 3)]);
+        assert_eq!(walk(&store, format!("[:find ?x ?depth :where [(descendants {} :test/parent) [[?x ?depth]]]]", c)),
+                   vec![row(b, 1), row(a, 2), row(c, 3)]);
+        assert_eq!(walk(&store, format!("[:find ?x ?depth :where [(ancestors {} :test/parent 2) [[?x ?depth]]]]", a)),
+                   vec![row(b, 1), row(c, 2)]);
+
+        // The walk stops where it comes back around, not at the depth limit, which here it would
+        // take a billion steps to reach.
+        assert_eq!(walk(&store, format!("[:find ?x ?depth :where [(ancestors {} :test/parent 1000000000) [[?x ?depth]]]]", a)),
+                   vec![row(b, 1), row(c, 2), row(a, 3)]);
+        assert_eq!(store.count("[:find ?s ?x :where [?s :test/parent] [(descendants ?s :test/parent 1000000000) ?x]]").unwrap(), 9);
+    }
+
+    #[test]
+    fn test_walk() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "p" :db/ident :test/parent]
+                           [:db/add "p" :db/valueType :db.type/ref]
+                           [:db/add "p" :db/cardinality :db.cardinality/many]]"#).unwrap();
+        // A diamond: d's parents are b and c, whose parent is a.
+        let report = store.transact(r#"[[:db/add "d" :test/parent "b"]
+                                        [:db/add "d" :test/parent "c"]
+                                        [:db/add "b" :test/parent "a"]
+                                        [:db/add "c" :test/parent "a"]]"#).unwrap();
+        let (a, b, c, d) = (report.tempids["a"], report.tempids["b"], report.tempids["c"], report.tempids["d"]);
+        let (first, second) = if b < c { (b, c) } else { (c, b) };
+
+        assert_eq!(store.walk(d, ":test/parent", Direction::Forward, None).unwrap(),
+                   vec![Reached { entid: first, depth: 1, path: vec![d, first] },
+                        Reached { entid: second, depth: 1, path: vec![d, second] },
+                        Reached { entid: a, depth: 2, path: vec![d, first, a] }]);
+        assert_eq!(store.walk(a, ":test/parent", Direction::Backward, Some(1)).unwrap(),
+                   vec![Reached { entid: first, depth: 1, path: vec![a, first] },
+                        Reached { entid: second, depth: 1, path: vec![a, second] }]);
+        assert_eq!(store.walk(a, ":test/parent", Direction::Forward, None).unwrap(), vec![]);
+
+        assert!(store.walk(d, ":test/unknown", Direction::Forward, None).is_err());
+        assert!(store.walk(d, ":test/name", Direction::Forward, None).is_err());
+    }
+
+    #[test]
+    fn test_ordered_many() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "i" :db/ident :test/items]
+                           [:db/add "i" :db/valueType :db.type/ref]
+                           [:db/add "i" :db/cardinality :db.cardinality/many]
+                           [:db/add "p" :db/ident :test/position]
+                           [:db/add "p" :db/valueType :db.type/long]]"#).unwrap();
+        let report = store.transact(r#"[[:db/add "f" :test/name "folder"]
+                                        [:db/add "a" :test/name "a"]
+                                        [:db/add "b" :test/name "b"]
+                                        [:db/add "c" :test/name "c"]]"#).unwrap();
+        let (f, a, b, c) = (report.tempids["f"], report.tempids["a"], report.tempids["b"], report.tempids["c"]);
+        let ordered = OrderedMany::new(":test/items", ":test/position");
+
+        store.insert_at(&ordered, f, 0, a).unwrap();
+        store.insert_at(&ordered, f, 0, b).unwrap();
+        store.insert_at(&ordered, f, 1, c).unwrap();
+        assert_eq!(store.ordered_items(&ordered, f).unwrap(), vec![b, c, a]);
+        assert_eq!(store.pull(a, &[":test/position"]).unwrap()[":test/position"], vec![TypedValue::Long(2)]);
+
+        store.move_to(&ordered, f, b, 2).unwrap();
+        assert_eq!(store.ordered_items(&ordered, f).unwrap(), vec![c, a, b]);
+
+        store.remove_from(&ordered, f, c).unwrap();
+        assert_eq!(store.ordered_items(&ordered, f).unwrap(), vec![a, b]);
+        assert!(store.pull(c, &[":test/position"]).unwrap().is_empty());
+        assert_eq!(store.pull(b, &[":test/position"]).unwrap()[":test/position"], vec![TypedValue::Long(1)]);
+
+        assert!(store.insert_at(&ordered, f, 0, a).is_err());
+        assert!(store.insert_at(&ordered, f, 3, c).is_err());
+        assert!(store.move_to(&ordered, f, c, 0).is_err());
+        assert!(store.move_to(&ordered, f, a, 2).is_err());
+        assert!(store.remove_from(&ordered, f, c).is_err());
+        assert!(store.ordered_items(&OrderedMany::new(":test/position", ":test/items"), f).is_err());
+    }
+
+    #[test]
+    fn test_export_and_import_datoms() {
+        let mut store = test_store();
+        let schema_tx = store.transact(r#"[[:db/add "f" :db/ident :test/friend]
+                                            [:db/add "f" :db/valueType :db.type/ref]]"#).unwrap().tx_id;
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                                        [:db/add "datomic.tx" :db/txInstant 4000000000000]]"#).unwrap();
+        let (alice, tx1) = (report.tempids["a"], report.tx_id);
+        let report = store.transact(&format!(r#"[[:db/add "b" :test/name "Bob"]
+                                                 [:db/add "b" :test/friend {}]
+                                                 [:db/retract {} :test/name "Alice"]
+                                                 [:db/add "datomic.tx" :db/txInstant 4000000001000]]"#, alice, alice)).unwrap();
+        let (bob, tx2) = (report.tempids["b"], report.tx_id);
+
+        let export = |store: &Store, since_tx: Option<Entid>, format: ExportFormat| {
+            let mut out = vec![];
+            store.export_datoms(&mut out, since_tx, format).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        // Refs without idents are written as entids.
+        let transactions = format!("{{:tx {} :txInstant 4000000000000 :datoms [[{} :test/name \"Alice\" true]]}}\n\
+                                    {{:tx {} :txInstant 4000000001000 :datoms [[{} :test/name \"Bob\" true] [{} :test/friend {} true] [{} :test/name \"Alice\" false]]}}\n",
+                                   tx1, alice, tx2, bob, bob, alice, alice);
+        assert_eq!(export(&store, Some(schema_tx), ExportFormat::NdEdn), transactions);
+        assert_eq!(export(&store, Some(tx1), ExportFormat::NdEdnDatoms),
+                   format!("[{} :test/name \"Bob\" {} true]\n\
+                            [{} :test/friend {} {} true]\n\
+                            [{} :test/name \"Alice\" {} false]\n\
+                            [{} :db/txInstant 4000000001000 {} true]\n",
+                           bob, tx2, bob, alice, tx2, alice, tx2, tx2, tx2));
+        assert_eq!(export(&store, Some(tx2), ExportFormat::Edn), "[]\n");
+
+        // Replaying the whole log into an empty store allocates the same entids and transaction
+        // IDs, so the data transactions export identically.
+        for &format in &[ExportFormat::Edn, ExportFormat::NdEdn, ExportFormat::NdEdnDatoms] {
+            let mut copy = Store::open("").unwrap();
+            let reports = copy.import_datoms(export(&store, None, format).as_bytes(), format).unwrap();
+            assert_eq!(reports.len(), 4);
+            assert_eq!(export(&copy, Some(schema_tx), ExportFormat::NdEdn), transactions);
+            assert_eq!(copy.q_once(r#"[:find ?name . :where [?b :test/friend ?a] [?b :test/name ?name]]"#).unwrap().results,
+                       QueryResults::Scalar(Some(TypedValue::String("Bob".to_string()))));
+
+            // A failed import leaves nothing behind.
+            assert!(copy.import_datoms("{:tx 1 :datoms [[1 :test/unknown 2 true]]}".as_bytes(), ExportFormat::NdEdn).is_err());
+            assert_eq!(export(&copy, Some(tx2), ExportFormat::NdEdn), "");
+        }
+
+        assert!(store.import_datoms("[1 2 3]".as_bytes(), ExportFormat::NdEdn).is_err());
+    }
+
+    #[test]
+    fn test_export_and_import_escaped_strings() {
+        let mut store = test_store();
+        let text = r#"say "hi" to C:\temp"#;
+        store.transact(&format!("[[:db/add \"a\" :test/name {}]]", edn::Value::Text(text.to_string()))).unwrap();
+
+        for &format in &[ExportFormat::Edn, ExportFormat::NdEdn, ExportFormat::NdEdnDatoms] {
+            let mut out = vec![];
+            store.export_datoms(&mut out, None, format).unwrap();
+            let mut copy = Store::open("").unwrap();
+            copy.import_datoms(&out[..], format).unwrap();
+            assert_eq!(copy.q_once("[:find ?name . :where [_ :test/name ?name]]").unwrap().results,
+                       QueryResults::Scalar(Some(TypedValue::String(text.to_string()))));
+        }
+    }
+
+    #[test]
+    fn test_validate_import() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let mut exported = vec![];
+        store.export_datoms(&mut exported, None, ExportFormat::NdEdn).unwrap();
+
+        // Validating an import reports what it would do, and leaves nothing behind.
+        let mut copy = Store::open("").unwrap();
+        let basis = basis_tx(&copy.conn).unwrap();
+        let validated = copy.validate_import(&exported[..], ExportFormat::NdEdn).unwrap();
+        assert_eq!(basis_tx(&copy.conn).unwrap(), basis);
+        let imported = copy.import_datoms(&exported[..], ExportFormat::NdEdn).unwrap();
+        assert_eq!(validated, imported);
+
+        // An import that would fail doesn't validate.
+        assert!(copy.validate_import("{:tx 1 :datoms [[1 :test/unknown 2 true]]}".as_bytes(), ExportFormat::NdEdn).is_err());
+    }
+
+    #[test]
+    fn test_import_datoms_from() {
+        let path = env::temp_dir().join(format!("mentat-test-import-source-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut source = Store::open(path).unwrap();
+        let schema_tx = source.transact(r#"[[:db/add "n" :db/ident :test/name]
+                                            [:db/add "n" :db/valueType :db.type/string]
+                                            [:db/add "n" :db/unique :db.unique/identity]
+                                            [:db/add "t" :db/ident :test/tag]
+                                            [:db/add "t" :db/valueType :db.type/keyword]
+                                            [:db/add "t" :db/cardinality :db.cardinality/many]]"#).unwrap().tx_id;
+        source.transact(r#"[[:db/add "a" :test/name "Alice"]
+                            [:db/add "a" :test/tag :tag/one]
+                            [:db/add "datomic.tx" :db/txInstant 4000000000000]]"#).unwrap();
+        let mut out = vec![];
+        source.export_datoms(&mut out, Some(schema_tx), ExportFormat::NdEdn).unwrap();
+
+        // The export doesn't define its attributes, so they can't be imported on their own.
+        let mut copy = Store::open("").unwrap();
+        match copy.import_datoms(&out[..], ExportFormat::NdEdn) {
+            Err(Error(ErrorKind::UnknownAttributes(ref attributes), _)) => assert_eq!(attributes, ":test/name, :test/tag"),
+            x => panic!("expected UnknownAttributes, got {:?}", x),
+        }
+
+        // But their definitions can be had from the exporting store.
+        let reports = copy.import_datoms_from(&out[..], ExportFormat::NdEdn, path).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert!(copy.schema().attribute_for_entid(copy.schema().get_entid(&":test/tag".to_string()).unwrap()).unwrap().multival);
+        assert_eq!(copy.q_once(r#"[:find ?t . :where [?a :test/name "Alice"] [?a :test/tag ?t]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Keyword(NamespacedKeyword::new("tag", "one")))));
+
+        // Stores that define an attribute differently don't exchange its datoms.
+        let mut other = Store::open("").unwrap();
+        other.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/long]]"#).unwrap();
+        match other.import_datoms_from(&out[..], ExportFormat::NdEdn, path) {
+            Err(Error(ErrorKind::AttributeMismatch(ref attributes), _)) => assert_eq!(attributes, ":test/name"),
+            x => panic!("expected AttributeMismatch, got {:?}", x),
+        }
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_repeated_or() {
+        let mut store = test_store();
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                                        [:db/add "a" :test/tag :tag/one]
+                                        [:db/add "b" :test/name "Bob"]
+                                        [:db/add "b" :test/tag :tag/two]
+                                        [:db/add "c" :test/name "Carol"]
+                                        [:db/add "c" :test/tag :tag/three]]"#).unwrap();
+        let query = r#"[:find [?x ...] :where (or (and (or [?x :test/tag :tag/one] [?x :test/tag :tag/two]) [?x :test/name "Alice"])
+                                                  (and (or [?x :test/tag :tag/one] [?x :test/tag :tag/two]) [?x :test/name "Bob"]))]"#;
+
+        // Twice over: the temporary table doesn't outlive the query.
+        for _ in 0..2 {
+            match store.q_once(query).unwrap().results {
+                QueryResults::Coll(mut xs) => {
+                    xs.sort();
+                    assert_eq!(xs, vec![TypedValue::Ref(report.tempids["a"]), TypedValue::Ref(report.tempids["b"])]);
+                },
+                x => panic!("expected Coll, got {:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn test_relation_inputs() {
+        let mut store = test_store();
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                                        [:db/add "a" :test/tag :tag/one]
+                                        [:db/add "b" :test/name "Bob"]
+                                        [:db/add "b" :test/tag :tag/two]]"#).unwrap();
+        let (alice, bob) = (report.tempids["a"], report.tempids["b"]);
+        let name = Variable(PlainSymbol::new("?name"));
+        let tag = Variable(PlainSymbol::new("?tag"));
+        let s = |x: &str| TypedValue::String(x.to_string());
+
+        store.register_query("bynames", r#"[:find [?x ...] :in $ [?name ...] :where [?x :test/name ?name]]"#).unwrap();
+        store.register_query("bypairs", r#"[:find ?x :in $ [[?name ?tag]] :where [?x :test/name ?name] [?x :test/tag ?tag]]"#).unwrap();
+        let by_names = |names: Vec<TypedValue>| -> Vec<TypedValue> {
+            let mut relations = RelationInputs::new();
+            relations.insert(vec![name.clone()], names.into_iter().map(|n| vec![n]).collect());
+            match store.q_named_with_relations("bynames", QueryInputs::new(), relations).unwrap().results {
+                QueryResults::Coll(mut xs) => {
+                    xs.sort();
+                    xs
+                },
+                x => panic!("expected Coll, got {:?}", x),
+            }
+        };
+        assert_eq!(by_names(vec![s("Alice"), s("Bob"), s("Carol")]), vec![TypedValue::Ref(alice), TypedValue::Ref(bob)]);
+        assert_eq!(by_names(vec![]), vec![]);
+
+        // More names than SQLite takes arguments, twice over: the temporary table doesn't outlive
+        // the query.
+        let mut names: Vec<TypedValue> = (0..2000).map(|i| s(&format!("name{}", i))).collect();
+        names.push(s("Bob"));
+        assert_eq!(by_names(names.clone()), vec![TypedValue::Ref(bob)]);
+        assert_eq!(by_names(names), vec![TypedValue::Ref(bob)]);
+
+        let mut relations = RelationInputs::new();
+        relations.insert(vec![name.clone(), tag.clone()],
+                         vec![vec![s("Alice"), TypedValue::Keyword(NamespacedKeyword::new("tag", "one"))],
+                              vec![s("Bob"), TypedValue::Keyword(NamespacedKeyword::new("tag", "one"))]]);
+        assert_eq!(store.q_named_with_relations("bypairs", QueryInputs::new(), relations).unwrap().results,
+                   QueryResults::Rel(vec![vec![TypedValue::Ref(alice)]]));
+    }
+
+    #[test]
+    fn test_current_schema() {
+        let mut store = test_store();
+        let before = store.current_schema();
+
+        // Data-only transactions don't replace the snapshot.
+        store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        assert!(Arc::ptr_eq(&before, &store.current_schema()));
+
+        store.transact(r#"[[:db/add "x" :db/ident :test/age]
+                           [:db/add "x" :db/valueType :db.type/long]]"#).unwrap();
+        let after = store.current_schema();
+        assert!(before.get_entid(&":test/age".to_string()).is_none());
+        assert!(after.get_entid(&":test/age".to_string()).is_some());
+        assert_eq!(*after, *store.schema());
+    }
+
+    #[test]
+    fn test_attribute_cache() {
+        let mut store = test_store();
+        store.cache_attribute(":test/name").unwrap();
+
+        let report = store.transact(r#"[[:db/add "b" :test/name "Bob"]]"#).unwrap();
+        let bob = report.tempids["b"];
+        let a = *store.schema().get_entid(&":test/name".to_string()).unwrap();
+        assert_eq!(store.attribute_cache.get(a, bob), Some(&[TypedValue::String("Bob".to_string())][..]));
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let path = env::temp_dir().join(format!("mentat-test-read-only-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        // Nothing to open yet, and we won't create it.
+        assert!(Store::open_read_only(path).is_err());
+        assert!(Store::open_read_only("").is_err());
+
+        let alice = {
+            let mut store = Store::open(path).unwrap();
+            store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                               [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+            store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap().tempids["a"]
+        };
+
+        let store = Store::open_read_only(path).unwrap();
+        assert_eq!(store.q_once(r#"[:find ?x . :where [?x :test/name "Alice"]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(alice))));
+        assert_eq!(store.pull(alice, &[":test/name"]).unwrap()[":test/name"],
+                   vec![TypedValue::String("Alice".to_string())]);
+
+        // Even going around the handle, SQLite refuses to write.
+        assert!(store.connection().execute("DELETE FROM datoms", &[]).is_err());
+
+        drop(store);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_query_parse_error() {
+        let store = test_store();
+        match store.q_once("[:find ?x :in x :where [?x :test/name _]]") {
+            Err(Error(ErrorKind::QueryParseError(message), _)) => {
+                assert!(message.starts_with("unexpected `x` at line 1, column 15\n"), "{}", message);
+                assert!(message.ends_with("hint: expected a variable like `?x`"));
+            },
+            x => panic!("expected QueryParseError, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_basis_tx() {
+        let mut store = test_store();
+        let query = r#"[:find ?x . :where [?x :test/name "Alice"]]"#;
+        let first = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        assert_eq!(store.q_once(query).unwrap().basis_tx, first.tx_id);
+
+        // Unrelated transactions move the basis on, even when the results don't change.
+        let second = store.transact(r#"[[:db/add "b" :test/name "Bob"]]"#).unwrap();
+        let output = store.q(query).unwrap();
+        assert_eq!(output.basis_tx, second.tx_id);
+        assert_eq!(output.results, QueryResults::Scalar(Some(TypedValue::Ref(first.tempids["a"]))));
+
+        // Queries into the past are stamped with the present.
+        assert_eq!(store.q_once_as_of(query, PointInTime::Tx(first.tx_id)).unwrap().basis_tx, second.tx_id);
+    }
+
+    #[test]
+    fn test_transact_if_basis() {
+        let mut store = test_store();
+        let query = r#"[:find ?name . :where [_ :test/name ?name]]"#;
+        store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let basis = store.q_once(query).unwrap().basis_tx;
+
+        let report = store.transact_if_basis(r#"[[:db/add "b" :test/tag :tag/one]]"#, basis).unwrap();
+
+        // That transaction moved the basis on, so a second write computed from the same read fails,
+        // leaving the store as it was.
+        match store.transact_if_basis(r#"[[:db/add "c" :test/tag :tag/two]]"#, basis) {
+            Err(Error(ErrorKind::StaleBasis(expected, actual), _)) => {
+                assert_eq!(expected, basis);
+                assert_eq!(actual, report.tx_id);
+            },
+            x => panic!("expected StaleBasis, got {:?}", x),
+        }
+        assert_eq!(store.q_once(r#"[:find ?t . :where [_ :test/tag ?t]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Keyword(NamespacedKeyword::new("tag", "one")))));
+        assert_eq!(store.q_once(query).unwrap().basis_tx, report.tx_id);
+    }
+
+    #[test]
+    fn test_snapshot_to() {
+        let path = env::temp_dir().join(format!("mentat-test-snapshot-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        let copy_path = env::temp_dir().join(format!("mentat-test-snapshot-copy-{}.db", process::id()));
+        let copy_path = copy_path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(copy_path);
+
+        let mut store = Store::open(path).unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+
+        match store.snapshot_to(copy_path) {
+            // The SQLite library is too old to snapshot at all.
+            Err(Error(ErrorKind::SnapshotUnsupported(_), _)) => {
+                drop(store);
+                fs::remove_file(path).unwrap();
+                return;
+            },
+            result => result.unwrap(),
+        }
+        store.transact(r#"[[:db/add "b" :test/name "Bob"]]"#).unwrap();
+
+        // The copy is an ordinary store as of the snapshot, independent of the original.
+        let names = r#"[:find [?name ...] :where [_ :test/name ?name]]"#;
+        let mut copy = Store::open(copy_path).unwrap();
+        let output = copy.q_once(names).unwrap();
+        assert_eq!(output.results, QueryResults::Coll(vec![TypedValue::String("Alice".to_string())]));
+        assert_eq!(output.basis_tx, report.tx_id);
+        copy.transact(r#"[[:db/add "c" :test/name "Carol"]]"#).unwrap();
+        match store.q_once(names).unwrap().results {
+            QueryResults::Coll(names) => assert_eq!(names.len(), 2),
+            x => panic!("expected Coll, got {:?}", x),
+        }
+
+        // Snapshots don't overwrite existing files.
+        assert!(store.snapshot_to(copy_path).is_err());
+
+        drop(copy);
+        drop(store);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(copy_path).unwrap();
+    }
+
+    #[test]
+    fn test_begin_read() {
+        let path = env::temp_dir().join(format!("mentat-test-begin-read-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        // In-memory stores have no second connection to read through.
+        assert!(test_store().begin_read().is_err());
+
+        let mut store = Store::open(path).unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+
+        let names = r#"[:find [?name ...] :where [_ :test/name ?name]]"#;
+        let read = store.begin_read().unwrap();
+        assert_eq!(read.basis_tx(), report.tx_id);
+
+        // Transactions committed while the read transaction is open, including schema changes, are
+        // invisible to it.
+        store.transact(r#"[[:db/add "b" :test/name "Bob"]
+                           [:db/add "x" :db/ident :test/age]
+                           [:db/add "x" :db/valueType :db.type/long]]"#).unwrap();
+        assert_eq!(read.q_once(names).unwrap().results,
+                   QueryResults::Coll(vec![TypedValue::String("Alice".to_string())]));
+        assert_eq!(read.basis_tx(), report.tx_id);
+        assert!(read.schema().get_entid(&":test/age".to_string()).is_none());
+        match store.q_once(names).unwrap().results {
+            QueryResults::Coll(names) => assert_eq!(names.len(), 2),
+            x => panic!("expected Coll, got {:?}", x),
+        }
+
+        // A new read transaction sees the new basis.
+        drop(read);
+        let read = store.begin_read().unwrap();
+        assert!(read.basis_tx() > report.tx_id);
+        assert!(read.schema().get_entid(&":test/age".to_string()).is_some());
+
+        drop(read);
+        drop(store);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_in_progress() {
+        let mut store = test_store();
+        let committed = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let names = "[:find [?name ...] :where [_ :test/name ?name]]";
+        let s = |x: &str| TypedValue::String(x.to_string());
+        let sorted = |results: QueryResults| match results {
+            QueryResults::Coll(mut xs) => { xs.sort(); xs },
+            results => panic!("expected a collection, got {:?}", results),
+        };
+
+        {
+            let mut in_progress = store.begin_transaction().unwrap();
+            assert_eq!(in_progress.basis_tx(), committed.tx_id);
+            in_progress.transact(r#"[[:db/add "b" :test/name "Bob"]]"#).unwrap();
+            in_progress.transact(r#"[[:db/add "x" :db/ident :test/age]
+                                     [:db/add "x" :db/valueType :db.type/long]]"#).unwrap();
+            in_progress.transact(r#"[[:db/add "c" :test/name "Carol"] [:db/add "c" :test/age 30]]"#).unwrap();
+            // A failed transaction leaves the others in place.
+            assert!(in_progress.transact(r#"[[:db/add "d" :test/unknown 1]]"#).is_err());
+
+            let output = in_progress.q_once(names, Consistency::IncludeInFlight).unwrap();
+            assert_eq!(sorted(output.results), vec![s("Alice"), s("Bob"), s("Carol")]);
+            let output = in_progress.q_once(names, Consistency::ExcludeInFlight).unwrap();
+            assert_eq!(output.results, QueryResults::Coll(vec![s("Alice")]));
+            assert_eq!(output.basis_tx, committed.tx_id);
+
+            // Uncommitted schema is only visible to queries that include it.
+            let ages = "[:find [?age ...] :where [_ :test/age ?age]]";
+            assert_eq!(in_progress.q_once(ages, Consistency::IncludeInFlight).unwrap().results,
+                       QueryResults::Coll(vec![TypedValue::Long(30)]));
+            match in_progress.q_once(ages, Consistency::ExcludeInFlight) {
+                Err(Error(ErrorKind::UnknownAttribute(ref ident), _)) => assert_eq!(ident, ":test/age"),
+                x => panic!("expected UnknownAttribute, got {:?}", x),
+            }
+        }
+
+        // Dropping the transaction rolled it back.
+        assert_eq!(store.q_once(names).unwrap().results, QueryResults::Coll(vec![s("Alice")]));
+        assert!(store.schema().get_entid(&":test/age".to_string()).is_none());
+
+        let mut in_progress = store.begin_transaction().unwrap();
+        in_progress.transact(r#"[[:db/add "b" :test/name "Bob"]]"#).unwrap();
+        in_progress.commit().unwrap();
+        assert_eq!(sorted(store.q_once(names).unwrap().results), vec![s("Alice"), s("Bob")]);
+    }
+
+    #[test]
+    fn test_open_with_recovery() {
+        let path = env::temp_dir().join(format!("mentat-test-recovery-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let alice = {
+            let mut store = Store::open(path).unwrap();
+            store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                               [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+            let alice = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap().tempids["a"];
+            store.connection().execute("DROP TABLE datoms", &[]).unwrap();
+            alice
+        };
+
+        match Store::open(path) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::CorruptStore(_)), _)) => (),
+            x => panic!("expected CorruptStore, got {:?}", x.map(|_| ())),
+        }
+
+        let store = Store::open_with_recovery(path, RecoveryPolicy::RederiveDatoms).unwrap();
+        assert_eq!(store.q_once(r#"[:find ?x . :where [?x :test/name "Alice"]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(alice))));
+
+        drop(store);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_open_with_options() {
+        let path = env::temp_dir().join(format!("mentat-test-options-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        {
+            let mut store = Store::open_with_options(path, &StoreOptions::mobile().page_size(8192)).unwrap();
+            store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                               [:db/add "n" :db/valueType :db.type/string]]"#).unwrap();
+            let page_size: i64 = store.connection().query_row("PRAGMA page_size", &[], |row| row.get(0)).unwrap();
+            assert_eq!(page_size, 8192);
+            let journal_mode: String = store.connection().query_row("PRAGMA journal_mode", &[], |row| row.get(0)).unwrap();
+            assert_eq!(journal_mode.to_lowercase(), "wal");
+        }
+
+        match Store::open_with_options(path, &StoreOptions::desktop().page_size(3000)) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::BadStoreOptions(_)), _)) => (),
+            x => panic!("expected BadStoreOptions, got {:?}", x.map(|_| ())),
+        }
+
+        // An in-memory store takes options too.
+        assert!(Store::open_with_options("", &StoreOptions::desktop()).is_ok());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_observers() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let mut store = test_store();
+        let name = *store.schema().require_entid(&":test/name".to_string()).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        store.register_observer("names", TxObserver::new(&[":test/name"], move |key, changes| {
+            sender.send((key.to_string(), changes.to_vec())).unwrap();
+        })).unwrap();
+        assert!(store.register_observer("unknown", TxObserver::new(&[":test/unknown"], |_, _| ())).is_err());
+
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let (key, changes) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(key, "names");
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].tx_id, report.tx_id);
+        assert_eq!(changes[0].tx_instant, report.tx_instant);
+        assert!(changes[0].attributes.contains(&name));
+
+        // Transactions not touching the attribute, and rolled back transactions, aren't delivered.
+        store.transact(r#"[[:db/add "b" :test/tag :test/x]]"#).unwrap();
+        {
+            let mut in_progress = store.begin_transaction().unwrap();
+            in_progress.transact(r#"[[:db/add "c" :test/name "Carol"]]"#).unwrap();
+            in_progress.rollback().unwrap();
+        }
+        let mut in_progress = store.begin_transaction().unwrap();
+        let first = in_progress.transact(r#"[[:db/add "d" :test/name "Dave"]]"#).unwrap();
+        let second = in_progress.transact(r#"[[:db/add "e" :test/name "Eve"]]"#).unwrap();
+        in_progress.commit().unwrap();
+        let (_, changes) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(changes.iter().map(|change| change.tx_id).collect::<Vec<_>>(), vec![first.tx_id, second.tx_id]);
+
+        store.unregister_observer("names");
+        store.transact(r#"[[:db/add "f" :test/name "Frank"]]"#).unwrap();
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_slow_observer_doesnt_block_writer() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let mut store = test_store();
+        let (release, released) = mpsc::channel::<()>();
+        let (sender, receiver) = mpsc::channel();
+        store.register_observer("slow", TxObserver::new(&[], move |_, changes| {
+            // Hold up the notification thread until the test has finished transacting.
+            let _ = released.recv();
+            sender.send(changes.len()).unwrap();
+        }).batched(Duration::from_millis(10))).unwrap();
+
+        for i in 0..5 {
+            store.transact(&format!("[[:db/add \"x\" :test/name \"{}\"]]", i)).unwrap();
+        }
+        release.send(()).unwrap();
+        let mut delivered = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        while delivered < 5 {
+            release.send(()).unwrap();
+            delivered += receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(delivered, 5);
+    }
+
+    #[test]
+    fn test_failed_transaction_rolls_back() {
+        let mut store = test_store();
+        let before = store.partition_map().clone();
+        assert!(store.transact(r#"[[:db/add "a" :test/unknown "x"]]"#).is_err());
+        assert_eq!(store.partition_map(), &before);
+        assert_eq!(db::read_db(store.connection()).unwrap().partition_map, before);
+    }
+
+    #[test]
+    fn test_validate_transaction() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let before = store.partition_map().clone();
+
+        assert!(store.validate_transaction(r#"[[:db/add "b" :test/name "Bob"]]"#).unwrap().is_empty());
+
+        let problems = store.validate_transaction(r#"[[:db/add "b" :test/name "Bob"]
+                                                      [:db/add "c" :test/tag "not a keyword"]
+                                                      [:db/add "d" :test/unknown "x"]]"#).unwrap();
+        assert_eq!(problems.iter().map(|p| p.entity).collect::<Vec<_>>(), vec![Some(1), Some(2)]);
+
+        // Nothing was written, and the transaction can be fixed and applied.
+        assert_eq!(store.q_once(r#"[:find ?x . :where [?x :test/name "Bob"]]"#).unwrap().results, QueryResults::Scalar(None));
+        assert_eq!(store.partition_map(), &before);
+        store.transact(r#"[[:db/add "b" :test/name "Bob"]]"#).unwrap();
+
+        match store.validate_transaction(r#"[[:db/add "b" :test/name"#) {
+            Err(Error(ErrorKind::EdnParseError(_), _)) => (),
+            x => panic!("expected EdnParseError, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_retract_policy() {
+        let mut store = test_store();
+        let alice = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap().tempids["a"];
+        let phantom = format!("[[:db/retract {} :test/name \"Bob\"]]", alice);
+
+        // Ignored by default.
+        store.transact(&phantom).unwrap();
+
+        store.set_retract_policy(RetractPolicy::Fail);
+        assert_eq!(store.validate_transaction(&phantom).unwrap().len(), 1);
+        match store.transact(&phantom) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::TxConflict(ref conflict)), _)) => {
+                assert_eq!(conflict.kind, mentat_db::ConflictKind::Absent);
+                assert_eq!(conflict.existing.iter().map(|d| d.v.clone()).collect::<Vec<_>>(),
+                           vec![TypedValue::String("Alice".to_string())]);
+            },
+            x => panic!("expected TxConflict, got {:?}", x),
+        }
+
+        // Retracting a present datom is fine either way.
+        store.transact(&format!("[[:db/retract {} :test/name \"Alice\"]]", alice)).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_attributes() {
+        let mut store = test_store();
+        let scores = "[:find [?s ...] :where [_ :test/score ?s]]";
+
+        // Strict by default.
+        assert!(store.transact(r#"[[:db/add "a" :test/score 10]]"#).is_err());
+        match store.q_once(scores) {
+            Err(Error(ErrorKind::UnknownAttribute(ref ident), _)) => assert_eq!(ident, ":test/score"),
+            x => panic!("expected UnknownAttribute, got {:?}", x),
+        }
+        assert!(store.count(r#"[:find ?e :where (or [?e :test/name "Alice"] [?e :test/score 10])]"#).is_err());
+
+        store.set_unknown_attributes(UnknownAttributePolicy::Permissive);
+        assert_eq!(store.q_once(scores).unwrap().results, QueryResults::Coll(vec![]));
+        assert_eq!(store.count(r#"[:find ?e :where [?e :test/score _]]"#).unwrap(), 0);
+
+        // Validating applies the policy too, but installs nothing.
+        assert!(store.validate_transaction(r#"[[:db/add "a" :test/rank 1]]"#).unwrap().is_empty());
+        assert!(store.schema().attribute_for_ident_str(":test/rank").is_none());
+
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]
+                                        [:db/add "a" :test/score 10]
+                                        [:db/add "a" :test/seen #inst "2017-01-01T00:00:00Z"]
+                                        [:db/add "a" :test/mood :test.mood/happy]]"#).unwrap();
+        // The guesses are installed in the user's transaction, and reported with it.
+        let score = *store.schema().get_entid(&":test/score".to_string()).unwrap();
+        assert_eq!(report.created_idents.get(":test/score"), Some(&score));
+        assert_eq!(report.created_idents.len(), 3);
+        assert_eq!(store.q_once(&format!("[:find ?tx . :where [{} :db/ident _ ?tx]]", score)).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(report.tx_id))));
+        let value_type = |ident: &str| store.schema().attribute_for_ident_str(ident).map(|(attribute, _)| attribute.value_type.clone());
+        assert_eq!(value_type(":test/score"), Some(mentat_db::ValueType::Long));
+        assert_eq!(value_type(":test/seen"), Some(mentat_db::ValueType::Instant));
+        assert_eq!(value_type(":test/mood"), Some(mentat_db::ValueType::Keyword));
+        assert_eq!(store.q_once(scores).unwrap().results, QueryResults::Coll(vec![TypedValue::Long(10)]));
+
+        // Values with no good guess still fail.
+        assert!(store.transact(r#"[[:db/add "b" :test/tags [1 2]]]"#).is_err());
+        assert!(store.schema().attribute_for_ident_str(":test/tags").is_none());
+    }
+
+    #[test]
+    fn test_new_idents() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "s" :db/ident :test/status]
+                           [:db/add "s" :db/valueType :db.type/ref]
+                           [:db/add "s" :db/enum true]
+                           [:db/add "o" :db/ident :test.status/open]]"#).unwrap();
+        let blocked = r#"[[:db/add "a" :test/name "Alice"] [:db/add "a" :test/status :test.status/blocked]]"#;
+        assert!(store.transact(blocked).is_err());
+
+        store.allow_new_idents("test.status");
+        let report = store.transact(blocked).unwrap();
+        let e = *store.schema().get_entid(&":test.status/blocked".to_string()).unwrap();
+        assert_eq!(report.created_idents.get(":test.status/blocked"), Some(&e));
+        assert_eq!(store.q_once(r#"[:find ?s . :where [_ :test/status ?s]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(e))));
+        assert!(store.transact(blocked).unwrap().created_idents.is_empty());
+
+        store.disallow_new_idents(":test.status");
+        assert!(store.transact(r#"[[:db/add "b" :test/status :test.status/done]]"#).is_err());
+    }
+
+    #[test]
+    fn test_constraints() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "s" :db/ident :test/status]
+                           [:db/add "s" :db/valueType :db.type/ref]
+                           [:db/add "o" :db/ident :test.status/open]
+                           [:db/add "c" :db/ident :test.status/closed]]"#).unwrap();
+        store.add_constraint(":test/name", Constraint::matches("^[A-Z]").unwrap()).unwrap();
+        store.add_constraint(":test/name", Constraint::predicate(|value| {
+            match value {
+                &TypedValue::String(ref x) if x.len() > 10 => Err(format!("{} is too long", x)),
+                _ => Ok(()),
+            }
+        })).unwrap();
+        store.add_constraint(":test/status", Constraint::OneOf(vec![":test.status/open".to_string()].into_iter().collect())).unwrap();
+        assert!(store.add_constraint(":test/name", Constraint::Min(TypedValue::Long(0))).is_err());
+        assert!(store.add_constraint(":test/unknown", Constraint::Min(TypedValue::Long(0))).is_err());
+
+        store.transact(r#"[[:db/add "a" :test/name "Alice"] [:db/add "a" :test/status :test.status/open]]"#).unwrap();
+
+        let constraint_reason = |result: Result<TxReport>| -> String {
+            match result {
+                Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::TxConflict(conflict)), _)) => {
+                    match conflict.kind {
+                        mentat_db::ConflictKind::Constraint(reason) => reason,
+                        kind => panic!("expected a Constraint conflict, got {:?}", kind),
+                    }
+                },
+                x => panic!("expected TxConflict, got {:?}", x),
+            }
+        };
+        constraint_reason(store.transact(r#"[[:db/add "b" :test/name "bob"]]"#));
+        assert_eq!(constraint_reason(store.transact(r#"[[:db/add "b" :test/name "Bartholomew"]]"#)), "Bartholomew is too long");
+        constraint_reason(store.transact(r#"[[:db/add "b" :test/name "Bob"] [:db/add "b" :test/status :test.status/closed]]"#));
+
+        // Each violating assertion is reported.
+        let problems = store.validate_transaction(r#"[[:db/add "b" :test/name "bob"]
+                                                      [:db/add "b" :test/status :test.status/open]
+                                                      [:db/add "c" :test/status :test.status/closed]]"#).unwrap();
+        assert_eq!(problems.iter().map(|p| p.entity).collect::<Vec<_>>(), vec![Some(0), Some(2)]);
+
+        store.remove_constraints(":test/name").unwrap();
+        store.transact(r#"[[:db/add "b" :test/name "bob"]]"#).unwrap();
+    }
+
+    #[test]
+    fn test_declare_enum() {
+        let mut store = test_store();
+        store.declare_enum(":task/status", &[":task.status/open", ":task.status/done"]).unwrap();
+        let status = *store.schema().get_entid(&":task/status".to_string()).unwrap();
+        assert!(store.schema().attribute_for_entid(&status).unwrap().enumerated);
+        assert!(store.schema().get_entid(&":task.status/done".to_string()).is_some());
+
+        // Values are keywords on the way in and on the way out.
+        let t = store.transact(r#"[[:db/add "t" :task/status :task.status/open]]"#).unwrap().tempids["t"];
+        let open = TypedValue::Keyword(NamespacedKeyword::new("task.status", "open"));
+        assert_eq!(store.q_once("[:find ?s . :where [_ :task/status ?s]]").unwrap().results,
+                   QueryResults::Scalar(Some(open.clone())));
+        store.register_query("by-status", "[:find [?t ...] :in $ ?s :where [?t :task/status ?s]]").unwrap();
+        let mut inputs = QueryInputs::new();
+        inputs.insert(Variable(PlainSymbol::new("?s")), open);
+        assert_eq!(store.q_named("by-status", inputs).unwrap().results, QueryResults::Coll(vec![TypedValue::Ref(t)]));
+
+        // Other entities aren't values.
+        assert!(store.transact(&format!("[[:db/add {} :task/status :test/name]]", t)).is_err());
+
+        // Declaring again extends the enumeration.
+        store.declare_enum(":task/status", &[":task.status/blocked"]).unwrap();
+        store.transact(&format!("[[:db/add {} :task/status :task.status/blocked]]", t)).unwrap();
+
+        assert!(store.declare_enum(":task/status", &[":other/open"]).is_err());
+        assert!(store.declare_enum(":test/name", &[":test.name/x"]).is_err());
+    }
+
+    #[test]
+    fn test_idents() {
+        let mut store = test_store();
+        let alice = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap().tempids["a"];
+        let string_type = *store.schema().get_entid(&":db.type/string".to_string()).unwrap();
+
+        // Refs are entids, unless the query asks for idents.
+        let query = "[:find ?t . :where [?a :db/ident :test/name] [?a :db/valueType ?t]]";
+        assert_eq!(store.q_once(query).unwrap().results, QueryResults::Scalar(Some(TypedValue::Ref(string_type))));
+        let query = "[:find ?t . :idents true :where [?a :db/ident :test/name] [?a :db/valueType ?t]]";
+        assert_eq!(store.q_once(query).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Keyword(NamespacedKeyword::new("db.type", "string")))));
+
+        // Entities without idents, and values that aren't refs, are unchanged.
+        let query = r#"[:find ?e ?n :idents true :where [?e :test/name ?n]]"#;
+        assert_eq!(store.q_once(query).unwrap().results,
+                   QueryResults::Rel(vec![vec![TypedValue::Ref(alice), TypedValue::String("Alice".to_string())]]));
+    }
+
+    #[test]
+    fn test_pull_idents() {
+        let mut store = test_store();
+        let person = store.transact(r#"[[:db/add "o" :db/ident :test/owner]
+                                        [:db/add "o" :db/valueType :db.type/ref]
+                                        [:db/add "o" :db/cardinality :db.cardinality/many]
+                                        [:db/add "k" :db/ident :test.kind/person]]"#).unwrap().tempids["k"];
+        let alice = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap().tempids["a"];
+        let b = store.transact(&format!("[[:db/add \"b\" :test/owner {}] [:db/add \"b\" :test/owner {}]]", alice, person))
+            .unwrap().tempids["b"];
+
+        // Refs to entities with idents are idents; others stay entids.
+        let mut owners = store.pull(b, &[":test/owner"]).unwrap()[":test/owner"].clone();
+        owners.sort();
+        let mut expected = vec![TypedValue::Ref(alice), TypedValue::Keyword(NamespacedKeyword::new("test.kind", "person"))];
+        expected.sort();
+        assert_eq!(owners, expected);
+        assert_eq!(store.entity(b).unwrap(), store.pull(b, &[":test/owner"]).unwrap());
+
+        // Raw mode leaves every ref an entid.
+        let mut owners = store.entity_raw(b).unwrap()[":test/owner"].clone();
+        owners.sort();
+        let mut expected = vec![TypedValue::Ref(alice), TypedValue::Ref(person)];
+        expected.sort();
+        assert_eq!(owners, expected);
+        assert_eq!(store.pull_raw(b, &[":test/owner"]).unwrap(), store.entity_raw(b).unwrap());
+    }
+
+    #[test]
+    fn test_display_entid() {
+        let mut store = test_store();
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        let alice = report.tempids["a"];
+        let name = *store.schema().get_entid(&":test/name".to_string()).unwrap();
+
+        assert_eq!(store.display_entid(name).to_string(), ":test/name");
+        assert_eq!(store.display_entid(alice).to_string(), format!("#db/id[:db.part/user {}]", alice));
+        assert_eq!(store.display_tx_report(&report).to_string(),
+                   format!("tx #db/id[:db.part/tx {}] at {}: \"a\" #db/id[:db.part/user {}]", report.tx_id, report.tx_instant, alice));
+    }
+
+    #[test]
+    fn test_transact_unless_noop() {
+        let mut store = test_store();
+        let report = store.transact_unless_noop(r#"[[:db/add "a" :test/name "Alice"]]"#).unwrap();
+        assert!(!report.noop);
+        let alice = report.tempids["a"];
+        let before = store.partition_map().clone();
+
+        // Nothing changes, so nothing is written, not even a transaction instant.
+        let report = store.transact_unless_noop(&format!("[[:db/add {} :test/name \"Alice\"]]", alice)).unwrap();
+        assert!(report.noop);
+        assert_eq!(store.partition_map(), &before);
+        let count: i64 = store.connection().query_row("SELECT COUNT(*) FROM transactions WHERE tx = ?", &[&report.tx_id], |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+
+        // Plain `transact` still commits no-op transactions.
+        let report = store.transact(&format!("[[:db/add {} :test/name \"Alice\"]]", alice)).unwrap();
+        assert!(report.noop);
+        assert!(store.partition_map() != &before);
+    }
+
+    #[test]
+    fn test_encrypted_attributes() {
+        let mut store = test_store();
+        store.transact(r#"[[:db/add "t" :db/ident :test/token]
+                           [:db/add "t" :db/valueType :db.type/string]
+                           [:db/add "t" :db/encrypted true]]"#).unwrap();
+
+        // Without a cipher, encrypted values can't be written.
+        match store.transact(r#"[[:db/add "a" :test/name "Alice"] [:db/add "a" :test/token "hunter2"]]"#) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::MissingCipher), _)) => (),
+            x => panic!("expected MissingCipher, got {:?}", x),
+        }
+
+        store.set_cipher(Arc::new(XorCipher(b"key".to_vec())));
+        let report = store.transact(r#"[[:db/add "a" :test/name "Alice"] [:db/add "a" :test/token "hunter2"]]"#).unwrap();
+        let alice = report.tempids["a"];
+        let token = TypedValue::String("hunter2".to_string());
+
+        // The plaintext never reaches the datoms or the log.
+        let a = *store.schema().get_entid(&":test/token".to_string()).unwrap();
+        for table in &["datoms", "transactions"] {
+            let plaintext: i64 = store.connection().query_row(&format!("SELECT COUNT(*) FROM {} WHERE a = ? AND (value_type_tag != ? OR instr(v, 'hunter2') > 0)", table),
+                                                              &[&a, &mentat_db::ENCRYPTED_VALUE_TYPE_TAG], |row| row.get(0)).unwrap();
+            assert_eq!(plaintext, 0);
+        }
+
+        // Values are decrypted wherever they're read.
+        assert_eq!(store.q_once(r#"[:find ?t . :where [?e :test/name "Alice"] [?e :test/token ?t]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(token.clone())));
+        assert_eq!(store.pull(alice, &[":test/token"]).unwrap()[":test/token"], vec![token.clone()]);
+        assert_eq!(store.entity(alice).unwrap()[":test/token"], vec![token.clone()]);
+        assert_eq!(store.last_asserted(alice, ":test/token").unwrap().map(|assertion| assertion.value), Some(token.clone()));
+        store.cache_attribute(":test/token").unwrap();
+        assert_eq!(store.pull(alice, &[":test/token"]).unwrap()[":test/token"], vec![token.clone()]);
+
+        // Encrypted values can't be matched in queries.
+        match store.q_once(r#"[:find ?e . :where [?e :test/token "hunter2"]]"#) {
+            Err(Error(ErrorKind::TranslatorError(mentat_query_translator::ErrorKind::EncryptedValue(_)), _)) => (),
+            x => panic!("expected EncryptedValue, got {:?}", x),
+        }
+
+        // Retraction finds the stored value.
+        store.transact(&format!("[[:db/retract {} :test/token \"hunter2\"]]", alice)).unwrap();
+        assert_eq!(store.pull(alice, &[":test/token"]).unwrap().get(":test/token"), None);
+
+        // Another key can't read what's stored.
+        store.set_cipher(Arc::new(XorCipher(b"other".to_vec())));
+        match store.first_asserted(alice, ":test/token") {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::DecryptionFailed), _)) => (),
+            x => panic!("expected DecryptionFailed, got {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Comparing the datoms of two stores, to check a sync, a migration, or an import.
+//!
+//! Two stores seldom give the same entity the same entid, so `diff_stores` matches entities by
+//! what identifies them: an ident, or else a value of a `:db.unique/identity` attribute.  Entities
+//! with neither are matched by entid, which an import preserves.  Attributes are matched by ident,
+//! and refs are compared by what identifies the entities they refer to.
+//!
+//! ```text
+//! - [65536 :person/name "Al"]
+//! + [65540 :person/name "Alice"]
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use rusqlite;
+
+use mentat_db::{Entid, TypedValue, ValueType, decrypt_sql_value_pair};
+
+use errors::*;
+use store::Store;
+
+/// A datom only one of two stores has, with that store's entids.
+#[derive(Clone,Debug,Eq,Ord,PartialEq,PartialOrd)]
+pub struct DiffDatom {
+    pub e: Entid,
+
+    /// The attribute's ident, like `:person/name`.
+    pub a: String,
+
+    pub v: TypedValue,
+}
+
+/// The datoms each of two stores has that the other doesn't.
+#[derive(Clone,Debug,Default,Eq,PartialEq)]
+pub struct StoreDiff {
+    pub only_in_first: Vec<DiffDatom>,
+    pub only_in_second: Vec<DiffDatom>,
+}
+
+impl StoreDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_first.is_empty() && self.only_in_second.is_empty()
+    }
+}
+
+impl fmt::Display for StoreDiff {
+    /// A line per datom, prefixed `-` if only the first store has it and `+` if only the second
+    /// does.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (sign, datoms) in vec![("-", &self.only_in_first), ("+", &self.only_in_second)] {
+            for datom in datoms.iter() {
+                writeln!(f, "{} [{} {} {}]", sign, datom.e, datom.a, datom.v.to_edn_value_pair().0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What identifies an entity across stores.
+#[derive(Clone,Debug,Eq,Ord,PartialEq,PartialOrd)]
+enum Identity {
+    Ident(String),
+    Unique(String, TypedValue),
+    Entid(Entid),
+}
+
+/// A value as compared across stores: refs by the identity of the entity referred to.
+#[derive(Clone,Debug,Eq,Ord,PartialEq,PartialOrd)]
+enum Comparable {
+    Entity(Identity),
+    Value(TypedValue),
+}
+
+/// The identities of the entities of `store` that have idents or unique identities.  An entity
+/// with several unique identities is identified by the attribute whose ident sorts first.
+fn identities(store: &Store) -> Result<BTreeMap<Entid, Identity>> {
+    let schema = store.schema();
+    let mut unique: BTreeMap<Entid, (String, TypedValue)> = BTreeMap::new();
+    for (ident, a, attribute) in schema.attributes() {
+        if !attribute.unique_identity {
+            continue;
+        }
+        let mut stmt: rusqlite::Statement = store.connection().prepare("SELECT e, v, value_type_tag FROM all_datoms WHERE a = ?")?;
+        let rows: Result<Vec<(Entid, TypedValue)>> = stmt.query_and_then(&[&a], |row| {
+            let v: rusqlite::types::Value = row.get_checked(1)?;
+            let value_type_tag: i32 = row.get_checked(2)?;
+            Ok((row.get_checked(0)?, decrypt_sql_value_pair(v, &value_type_tag, None)?))
+        })?.collect();
+        for (e, v) in rows? {
+            let first = unique.get(&e).map_or(true, |&(ref other, _)| ident < other);
+            if first {
+                unique.insert(e, (ident.clone(), v));
+            }
+        }
+    }
+
+    let mut identities: BTreeMap<Entid, Identity> = unique.into_iter().map(|(e, (a, v))| (e, Identity::Unique(a, v))).collect();
+    for (e, ident) in schema.entid_map.iter() {
+        identities.insert(*e, Identity::Ident(ident.clone()));
+    }
+    Ok(identities)
+}
+
+/// The datoms of `store` whose attributes are in `namespaces`, keyed by how they compare across
+/// stores.
+fn comparable_datoms(store: &Store, namespaces: &[&str]) -> Result<BTreeMap<(Identity, String, Comparable), DiffDatom>> {
+    let schema = store.schema();
+    let attributes: BTreeSet<String> = schema.attributes().into_iter()
+        .map(|(ident, _, _)| ident)
+        .filter(|ident| compared(ident, namespaces))
+        .cloned()
+        .collect();
+    let identities = identities(store)?;
+    let identity = |e: Entid| identities.get(&e).cloned().unwrap_or(Identity::Entid(e));
+
+    let mut stmt: rusqlite::Statement = store.connection().prepare("SELECT DISTINCT e FROM datoms")?;
+    let entities: Result<Vec<Entid>> = stmt.query_and_then(&[], |row| Ok(row.get_checked(0)?))?.collect();
+
+    let mut datoms = BTreeMap::new();
+    for e in entities? {
+        for (a, values) in store.entity_raw(e)? {
+            if !attributes.contains(&a) {
+                continue;
+            }
+            let value_type = schema.require_attribute_for_ident_str(&a)?.0.value_type;
+            for v in values {
+                let comparable = match v {
+                    TypedValue::Ref(x) if value_type == ValueType::Ref => Comparable::Entity(identity(x)),
+                    _ => Comparable::Value(v.clone()),
+                };
+                datoms.insert((identity(e), a.clone(), comparable), DiffDatom {
+                    e: e,
+                    a: a.clone(),
+                    v: v,
+                });
+            }
+        }
+    }
+    Ok(datoms)
+}
+
+/// Whether the attribute `ident` is compared: it's in one of `namespaces`, or, if there are none,
+/// it's outside the `db` namespaces, which hold the schema and transactions' own datoms.
+pub fn compared(ident: &str, namespaces: &[&str]) -> bool {
+    let namespace = ident.trim_left_matches(':').split('/').next().unwrap_or("");
+    if namespaces.is_empty() {
+        namespace != "db" && !namespace.starts_with("db.")
+    } else {
+        namespaces.contains(&namespace)
+    }
+}
+
+/// The datoms of `first` and `second`, whose attributes are in `namespaces`, that the other store
+/// lacks.  With no namespaces, every attribute outside the `db` namespaces is compared.
+pub fn diff_stores(first: &Store, second: &Store, namespaces: &[&str]) -> Result<StoreDiff> {
+    let mut first = comparable_datoms(first, namespaces)?;
+    let second = comparable_datoms(second, namespaces)?;
+
+    let mut only_in_second = vec![];
+    for (key, datom) in second {
+        if first.remove(&key).is_none() {
+            only_in_second.push(datom);
+        }
+    }
+    let mut only_in_first: Vec<DiffDatom> = first.into_iter().map(|(_, datom)| datom).collect();
+    only_in_first.sort();
+    only_in_second.sort();
+    Ok(StoreDiff {
+        only_in_first: only_in_first,
+        only_in_second: only_in_second,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use query::QueryResults;
+
+    fn store() -> Store {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :person/name]
+                           [:db/add "n" :db/valueType :db.type/string]
+                           [:db/add "n" :db/unique :db.unique/identity]
+                           [:db/add "f" :db/ident :person/friend]
+                           [:db/add "f" :db/valueType :db.type/ref]
+                           [:db/add "c" :db/ident :note/text]
+                           [:db/add "c" :db/valueType :db.type/string]]"#).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_diff_stores() {
+        let mut first = store();
+        let mut second = store();
+        // Different entids for the same people.
+        second.transact(r#"[[:db/add "x" :note/text "padding"]]"#).unwrap();
+        first.transact(r#"[[:db/add "a" :person/name "Alice"]
+                           [:db/add "b" :person/name "Bob"]
+                           [:db/add "a" :person/friend "b"]]"#).unwrap();
+        second.transact(r#"[[:db/add "b" :person/name "Bob"]
+                            [:db/add "a" :person/name "Alice"]
+                            [:db/add "a" :person/friend "b"]]"#).unwrap();
+        assert_eq!(diff_stores(&first, &second, &["person"]).unwrap(), StoreDiff::default());
+
+        let diff = diff_stores(&first, &second, &[]).unwrap();
+        assert_eq!(diff.only_in_first, vec![]);
+        assert_eq!(diff.only_in_second.iter().map(|datom| datom.v.clone()).collect::<Vec<_>>(),
+                   vec![TypedValue::String("padding".to_string())]);
+
+        // Alice's friend differs.
+        second.transact(r#"[[:db/add "c" :person/name "Carol"]
+                            [:db/add [:person/name "Alice"] :person/friend "c"]]"#).unwrap();
+        let diff = diff_stores(&first, &second, &["person"]).unwrap();
+        let alice = first.q_once(r#"[:find ?e . :where [?e :person/name "Alice"]]"#).unwrap().results;
+        let bob = first.q_once(r#"[:find ?e . :where [?e :person/name "Bob"]]"#).unwrap().results;
+        match (alice, bob) {
+            (QueryResults::Scalar(Some(TypedValue::Ref(a))), QueryResults::Scalar(Some(TypedValue::Ref(b)))) => {
+                assert_eq!(diff.only_in_first, vec![DiffDatom { e: a, a: ":person/friend".to_string(), v: TypedValue::Ref(b) }]);
+            },
+            x => panic!("expected Alice and Bob, got {:?}", x),
+        }
+        assert_eq!(diff.only_in_second.len(), 2);
+        assert!(diff.to_string().starts_with("- ["));
+    }
+}
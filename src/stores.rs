@@ -0,0 +1,268 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Several stores open at once, and copying entities between them.
+//!
+//! Applications with more than one store -- a profile per user, say, or a scratch store beside
+//! the main one -- can keep them in a `Stores`, keyed by name.  `copy_entities` transacts the
+//! entities a query finds in one store into another, translating attributes and idents by name,
+//! so a subset of one profile's data can be migrated into a fresh one, and `Stores::diff`
+//! compares the datoms of two of them.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use edn::Value;
+use mentat_db::{Entid, TypedValue, ValueType, entity_with_value};
+
+use errors::*;
+use export;
+use query::QueryResults;
+use store::Store;
+use store_diff::{StoreDiff, diff_stores};
+use tx::{add_term, ident_value};
+
+/// Stores open at once, keyed by name.
+#[derive(Default)]
+pub struct Stores {
+    stores: BTreeMap<String, Store>,
+}
+
+impl Stores {
+    pub fn new() -> Stores {
+        Stores::default()
+    }
+
+    /// Open the store at `path`, as `Store::open` does, under `name`.
+    pub fn open(&mut self, name: &str, path: &str) -> Result<&mut Store> {
+        if self.stores.contains_key(name) {
+            bail!(ErrorKind::StoreAlreadyOpen(name.to_string()));
+        }
+        let store = Store::open(path)?;
+        self.insert(name, store)
+    }
+
+    /// Hold the already open `store` under `name`.
+    pub fn insert(&mut self, name: &str, store: Store) -> Result<&mut Store> {
+        if self.stores.contains_key(name) {
+            bail!(ErrorKind::StoreAlreadyOpen(name.to_string()));
+        }
+        Ok(self.stores.entry(name.to_string()).or_insert(store))
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Store> {
+        self.stores.get(name).ok_or_else(|| ErrorKind::UnknownStore(name.to_string()).into())
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Result<&mut Store> {
+        self.stores.get_mut(name).ok_or_else(|| ErrorKind::UnknownStore(name.to_string()).into())
+    }
+
+    /// Stop holding the store named `name`, and return it.  It's closed when dropped.
+    pub fn close(&mut self, name: &str) -> Result<Store> {
+        self.stores.remove(name).ok_or_else(|| ErrorKind::UnknownStore(name.to_string()).into())
+    }
+
+    /// The names of the stores held, in order.
+    pub fn names(&self) -> Vec<&str> {
+        self.stores.keys().map(|name| name.as_str()).collect()
+    }
+
+    /// Copy the entities found by `query` in the store named `from` into the store named `to`.  See
+    /// `copy_entities`.
+    pub fn copy_entities(&mut self, from: &str, to: &str, query: &str) -> Result<BTreeMap<Entid, Entid>> {
+        if from == to {
+            bail!(ErrorKind::InvalidCopy(format!("can't copy from '{}' into itself", from)));
+        }
+        let mut target = self.close(to)?;
+        let copied = self.get(from).and_then(|source| copy_entities(source, &mut target, query));
+        self.stores.insert(to.to_string(), target);
+        copied
+    }
+
+    /// The datoms, with attributes in `namespaces`, that one of the stores named `first` and
+    /// `second` has and the other lacks.  See `diff_stores`.
+    pub fn diff(&self, first: &str, second: &str, namespaces: &[&str]) -> Result<StoreDiff> {
+        diff_stores(self.get(first)?, self.get(second)?, namespaces)
+    }
+}
+
+/// The entities in the first column of `results`.
+fn found_entities(results: QueryResults) -> Result<BTreeSet<Entid>> {
+    let values = match results {
+        QueryResults::Scalar(value) => value.into_iter().collect(),
+        QueryResults::Tuple(row) => row.into_iter().flat_map(|row| row.into_iter().take(1)).collect(),
+        QueryResults::Coll(values) => values,
+        QueryResults::Rel(rows) => rows.into_iter().flat_map(|row| row.into_iter().take(1)).collect(),
+    };
+    values.into_iter().map(|value| match value {
+        TypedValue::Ref(e) => Ok(e),
+        value => bail!(ErrorKind::InvalidCopy(format!("expected entities, got {:?}", value))),
+    }).collect()
+}
+
+/// Transact the entities in the first column of the results of `query` against `from` into `to`,
+/// with all their attributes, in one write transaction.  Returns the entids they were given in
+/// `to`, keyed by their entids in `from`.
+///
+/// Attributes and idents are matched by name.  Attributes that `to` lacks are installed first,
+/// defined as in `from`.  Refs between copied entities are kept; refs to other entities are kept
+/// if those entities have idents, which are installed in `to` if need be, and left out otherwise.
+/// A copied entity with the same value of a `:db.unique/identity` attribute as an entity already
+/// in `to` is copied onto that entity.  Composite tuples are left for `to` to derive.
+pub fn copy_entities(from: &Store, to: &mut Store, query: &str) -> Result<BTreeMap<Entid, Entid>> {
+    let entities = found_entities(from.q_once(query)?.results)?;
+    let schema = from.schema();
+    let tempid = |e: Entid| format!("e{}", e);
+
+    // The entities already in `to`, by unique identity.
+    let mut existing: BTreeMap<Entid, Entid> = BTreeMap::new();
+    for &e in entities.iter() {
+        for (ident, values) in from.entity_raw(e)? {
+            let unique_identity = schema.require_attribute_for_ident_str(&ident)?.0.unique_identity;
+            let a = match to.schema().get_entid(&ident) {
+                Some(&a) if unique_identity => a,
+                _ => continue,
+            };
+            if let Some(copy) = entity_with_value(to.connection(), a, &values[0])? {
+                existing.insert(e, copy);
+                break;
+            }
+        }
+    }
+    let entity = |e: Entid| existing.get(&e).map_or_else(|| Value::Text(tempid(e)), |&copy| Value::Integer(copy));
+
+    let mut missing_attributes = BTreeSet::new();
+    let mut missing_idents = BTreeSet::new();
+    let mut terms = vec![];
+    for &e in entities.iter() {
+        for (ident, values) in from.entity_raw(e)? {
+            let (attribute, _) = schema.require_attribute_for_ident_str(&ident)?;
+            if !attribute.tuple_attrs.is_empty() {
+                continue;
+            }
+            if to.schema().get_entid(&ident).is_none() {
+                missing_attributes.insert(ident.clone());
+            }
+            for value in values {
+                let v = match value {
+                    TypedValue::Ref(x) if attribute.value_type == ValueType::Ref => {
+                        if entities.contains(&x) {
+                            entity(x)
+                        } else if let Some(x_ident) = schema.get_ident(&x) {
+                            if to.schema().get_entid(x_ident).is_none() {
+                                missing_idents.insert(x_ident.clone());
+                            }
+                            ident_value(x_ident)
+                        } else {
+                            continue;
+                        }
+                    },
+                    value => value.to_edn_value_pair().0,
+                };
+                terms.push(add_term(entity(e), ident_value(&ident), v));
+            }
+        }
+    }
+    if terms.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    // Definitions go in a transaction of their own, before the entities that use them.
+    let mut definitions = vec![];
+    if !missing_attributes.is_empty() {
+        definitions = export::attribute_definitions(from.connection(), schema, to.schema(), &missing_attributes)?;
+    }
+    for ident in missing_idents.iter().filter(|ident| !missing_attributes.contains(*ident)) {
+        definitions.push(add_term(Value::Text(ident.clone()), ident_value(":db/ident"), ident_value(ident)));
+    }
+
+    let mut in_progress = to.begin_transaction()?;
+    if !definitions.is_empty() {
+        in_progress.transact(&Value::Vector(definitions).to_string())?;
+    }
+    let report = in_progress.transact(&Value::Vector(terms).to_string())?;
+    in_progress.commit()?;
+
+    Ok(entities.iter()
+        .filter_map(|&e| existing.get(&e).or_else(|| report.tempids.get(&tempid(e))).map(|&copy| (e, copy)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stores() {
+        let mut stores = Stores::new();
+        stores.open("main", "").unwrap();
+        stores.open("scratch", "").unwrap();
+        assert_eq!(stores.names(), vec!["main", "scratch"]);
+        assert!(stores.open("main", "").is_err());
+
+        match stores.get("missing") {
+            Err(Error(ErrorKind::UnknownStore(ref name), _)) => assert_eq!(name, "missing"),
+            _ => panic!("expected UnknownStore"),
+        }
+        stores.close("scratch").unwrap();
+        assert_eq!(stores.names(), vec!["main"]);
+    }
+
+    #[test]
+    fn test_copy_entities() {
+        let mut stores = Stores::new();
+        {
+            let old = stores.open("old", "").unwrap();
+            old.transact(r#"[[:db/add "n" :db/ident :test/name]
+                             [:db/add "n" :db/valueType :db.type/string]
+                             [:db/add "n" :db/unique :db.unique/identity]
+                             [:db/add "f" :db/ident :test/friend]
+                             [:db/add "f" :db/valueType :db.type/ref]
+                             [:db/add "f" :db/cardinality :db.cardinality/many]
+                             [:db/add "s" :db/ident :test/status]
+                             [:db/add "s" :db/valueType :db.type/ref]
+                             [:db/add "o" :db/ident :status/open]]"#).unwrap();
+            old.transact(r#"[[:db/add "a" :test/name "Alice"]
+                             [:db/add "a" :test/status :status/open]
+                             [:db/add "a" :test/friend "b"]
+                             [:db/add "a" :test/friend "c"]
+                             [:db/add "b" :test/name "Bob"]
+                             [:db/add "b" :test/status :status/open]
+                             [:db/add "c" :test/name "Carol"]]"#).unwrap();
+        }
+        stores.open("new", "").unwrap();
+
+        let copied = stores.copy_entities("old", "new", r#"[:find [?e ...] :where [?e :test/status :status/open]]"#).unwrap();
+        assert_eq!(copied.len(), 2);
+
+        // The attributes and the :status/open ident are installed, and Alice's friendship with Bob
+        // is kept, but not hers with Carol, who wasn't copied.
+        let new = stores.get("new").unwrap();
+        assert_eq!(new.q_once(r#"[:find [?n ...] :where [?a :test/name "Alice"] [?a :test/friend ?f] [?f :test/name ?n]]"#).unwrap().results,
+                   QueryResults::Coll(vec![TypedValue::String("Bob".to_string())]));
+        assert_eq!(new.q_once(r#"[:find ?s . :where [?a :test/name "Alice"] [?a :test/status ?s]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Ref(*new.schema().get_entid(&":status/open".to_string()).unwrap()))));
+
+        // Copying again copies onto the earlier copies, identified by :test/name.
+        let again = stores.copy_entities("old", "new", r#"[:find [?e ...] :where [?e :test/name "Alice"]]"#).unwrap();
+        assert_eq!(again.len(), 1);
+        for (e, copy) in again {
+            assert_eq!(copied[&e], copy);
+        }
+        assert_eq!(stores.get("new").unwrap().q_once(r#"[:find (count ?e) . :where [?e :test/name _]]"#).unwrap().results,
+                   QueryResults::Scalar(Some(TypedValue::Long(2))));
+
+        assert!(stores.copy_entities("old", "old", "[:find ?e . :where [?e :test/name _]]").is_err());
+        assert!(stores.copy_entities("old", "new", r#"[:find ?n . :where [_ :test/name ?n]]"#).is_err());
+        assert_eq!(stores.names(), vec!["new", "old"]);
+    }
+}
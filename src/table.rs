@@ -0,0 +1,220 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Formatting query results as a text table, for terminals and logs.  `mentat repl` shows query
+//! results this way.
+//!
+//! ```text
+//! ?name | ?age
+//! ------+-----
+//! Alice | 30
+//! Bob   | 4
+//! ```
+//!
+//! Columns are as wide as their widest cell, measured in terminal columns: combining marks take
+//! none, and East Asian wide characters and most emoji take two.  Cells wider than the limit set
+//! with `TableOptions::max_width` are truncated with an ellipsis.  Strings are written as they
+//! are, with line breaks and tabs escaped so each row stays on one line; other values are written
+//! as EDN.
+
+use mentat_db::TypedValue;
+
+use query::{QueryOutput, QueryResults};
+
+#[derive(Clone,Debug,Default,Eq,Hash,PartialEq)]
+pub struct TableOptions {
+    /// The width, in terminal columns, beyond which cells are truncated.
+    pub max_width: Option<usize>,
+}
+
+impl TableOptions {
+    pub fn new() -> TableOptions {
+        TableOptions::default()
+    }
+
+    pub fn max_width(mut self, width: usize) -> TableOptions {
+        self.max_width = Some(width);
+        self
+    }
+}
+
+impl QueryOutput {
+    /// The results as a text table, with a header row naming the `:find` elements.
+    pub fn to_table(&self) -> String {
+        self.to_table_with(&TableOptions::new())
+    }
+
+    pub fn to_table_with(&self, options: &TableOptions) -> String {
+        let rows: Vec<Vec<TypedValue>> = match self.results {
+            QueryResults::Scalar(ref x) => x.iter().map(|x| vec![x.clone()]).collect(),
+            QueryResults::Tuple(ref x) => x.iter().cloned().collect(),
+            QueryResults::Coll(ref xs) => xs.iter().map(|x| vec![x.clone()]).collect(),
+            QueryResults::Rel(ref rows) => rows.clone(),
+        };
+        format_table(&self.columns, &rows, options)
+    }
+}
+
+/// `rows` as a text table under a header row of `columns`, each row ending with a newline.
+pub fn format_table(columns: &[String], rows: &[Vec<TypedValue>], options: &TableOptions) -> String {
+    let fit = |text: String| match options.max_width {
+        Some(max) => truncate(&text, max),
+        None => text,
+    };
+    let header: Vec<String> = columns.iter().map(|name| fit(escape(name))).collect();
+    let cells: Vec<Vec<String>> = rows.iter().map(|row| row.iter().map(|v| fit(cell(v))).collect()).collect();
+
+    let mut widths: Vec<usize> = header.iter().map(|name| width(name)).collect();
+    for row in cells.iter() {
+        for (i, cell) in row.iter().enumerate() {
+            if i < widths.len() && width(cell) > widths[i] {
+                widths[i] = width(cell);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    write_row(&mut out, &header, &widths);
+    let rule: Vec<String> = widths.iter().map(|&width| "-".repeat(width)).collect();
+    out.push_str(&rule.join("-+-"));
+    out.push('\n');
+    for row in cells.iter() {
+        write_row(&mut out, row, &widths);
+    }
+    out
+}
+
+fn write_row(out: &mut String, cells: &[String], widths: &[usize]) {
+    let last = widths.len().saturating_sub(1);
+    for (i, &column_width) in widths.iter().enumerate() {
+        let cell = cells.get(i).map_or("", |cell| cell.as_str());
+        if i > 0 {
+            out.push_str(" | ");
+        }
+        out.push_str(cell);
+        // Don't pad the last column with trailing spaces.
+        if i < last {
+            out.push_str(&" ".repeat(column_width - width(cell)));
+        }
+    }
+    out.push('\n');
+}
+
+/// The text of a cell holding `value`.
+fn cell(value: &TypedValue) -> String {
+    match value {
+        &TypedValue::String(ref x) => escape(x),
+        &TypedValue::Json(ref x) => escape(x),
+        _ => value.to_edn_value_pair().0.to_string(),
+    }
+}
+
+/// `text` with line breaks, tabs, and other control characters escaped.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `text`, cut to at most `max` terminal columns, ending with an ellipsis if it was cut.
+fn truncate(text: &str, max: usize) -> String {
+    if width(text) <= max {
+        return text.to_string();
+    }
+    let mut truncated = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        // Leave a column for the ellipsis.
+        if used + char_width(c) + 1 > max {
+            break;
+        }
+        used += char_width(c);
+        truncated.push(c);
+    }
+    if max > 0 {
+        truncated.push('…');
+    }
+    truncated
+}
+
+/// The number of terminal columns `text` takes.
+pub fn width(text: &str) -> usize {
+    text.chars().map(char_width).sum()
+}
+
+/// The number of terminal columns `c` takes: 0 for combining marks and zero-width characters, 2
+/// for East Asian wide and fullwidth characters and emoji, and 1 otherwise.
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300...0x036F | 0x0483...0x0489 | 0x0591...0x05BD | 0x0610...0x061A | 0x064B...0x065F |
+        0x0E31 | 0x0E34...0x0E3A | 0x0E47...0x0E4E | 0x1AB0...0x1AFF | 0x1DC0...0x1DFF |
+        0x200B...0x200F | 0x20D0...0x20FF | 0xFE00...0xFE0F | 0xFE20...0xFE2F | 0xFEFF => 0,
+        0x1100...0x115F | 0x2E80...0x303E | 0x3041...0x33FF | 0x3400...0x4DBF | 0x4E00...0x9FFF |
+        0xA000...0xA4CF | 0xAC00...0xD7A3 | 0xF900...0xFAFF | 0xFE30...0xFE4F | 0xFF00...0xFF60 |
+        0xFFE0...0xFFE6 | 0x1F300...0x1F64F | 0x1F900...0x1F9FF | 0x20000...0x2FFFD | 0x30000...0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use store::Store;
+
+    #[test]
+    fn test_width() {
+        assert_eq!(width("Alice"), 5);
+        assert_eq!(width("e\u{301}te\u{301}"), 3);
+        assert_eq!(width("日本"), 4);
+        assert_eq!(truncate("日本語です", 5), "日本…");
+        assert_eq!(truncate("Alice", 5), "Alice");
+        assert_eq!(truncate("Alexander", 5), "Alex…");
+    }
+
+    #[test]
+    fn test_format_table() {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "n" :db/ident :test/name]
+                           [:db/add "n" :db/valueType :db.type/string]
+                           [:db/add "k" :db/ident :test/kind]
+                           [:db/add "k" :db/valueType :db.type/keyword]]"#).unwrap();
+        store.transact(r#"[[:db/add "a" :test/name "Zoë"]
+                           [:db/add "a" :test/kind :kind/person]
+                           [:db/add "b" :test/name "東京\nTokyo"]
+                           [:db/add "b" :test/kind :kind/place]]"#).unwrap();
+
+        let output = store.q_once(r#"[:find ?kind ?name :where [?e :test/name ?name] [?e :test/kind ?kind]]"#).unwrap();
+        let mut lines: Vec<String> = output.to_table().lines().map(|line| line.to_string()).collect();
+        lines[2..].sort();
+        assert_eq!(lines, vec!["?kind        | ?name",
+                               "-------------+------------",
+                               ":kind/person | Zoë",
+                               ":kind/place  | 東京\\nTokyo"]);
+
+        let table = output.to_table_with(&TableOptions::new().max_width(8));
+        assert!(table.starts_with("?kind    | ?name\n---------+---------\n"));
+        assert!(table.contains(":kind/p… | Zoë\n"));
+        assert!(table.contains(":kind/p… | 東京\\nT…\n"));
+
+        let output = store.q_once(r#"[:find ?e . :where [?e :test/name "Bob"]]"#).unwrap();
+        assert_eq!(output.to_table(), "?e\n--\n");
+    }
+}
@@ -0,0 +1,269 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Support for the tests of crates using Mentat, with the `testing` feature.
+//!
+//! A `TestStore` is an in-memory store built from a fixture: a schema, written as a map from
+//! attribute to definition, and seed transactions.
+//!
+//! ```edn
+//! {:schema {:person/name   {:db/valueType :db.type/string :db/unique :db.unique/identity}
+//!           :person/friend {:db/valueType :db.type/ref}}
+//!  :seed   [[[:db/add "alice" :person/name "Alice"]]
+//!           [[:db/add "bob" :person/name "Bob"] [:db/add "alice" :person/friend "bob"]]]}
+//! ```
+//!
+//! Its clock is frozen: every transaction has the `:db/txInstant` `FROZEN_INSTANT`, until the test
+//! moves it on with `advance`.  A tempid, once transacted, keeps naming the same entity in later
+//! transactions, so tests can refer to fixture entities by name, and `assert_datoms_eq!` compares
+//! the store's datoms with an EDN vector of `[e a v]` that names entities the same way.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use edn;
+use edn::{Keyword, Value};
+use mentat_db::{Entid, TempidHints, TypedValue, ValueType};
+
+use errors::*;
+use query::QueryOutput;
+use store::Store;
+use store_diff::compared;
+use tx::{TxReport, add_term, ident_value};
+
+/// The `:db/txInstant` of a `TestStore`'s transactions: 2017-01-01T00:00:00Z.
+pub const FROZEN_INSTANT: i64 = 1483228800000;
+
+pub struct TestStore {
+    store: Store,
+
+    /// The `:db/txInstant` of the next transaction.
+    instant: i64,
+
+    /// The entid of each tempid transacted so far.
+    tempids: BTreeMap<String, Entid>,
+}
+
+/// The transaction installing `schema`: a map from attribute ident to a map of its definition, or
+/// already a transaction.
+fn schema_transaction(schema: Value) -> Result<Value> {
+    let attributes = match schema {
+        Value::Vector(_) => return Ok(schema),
+        Value::Map(attributes) => attributes,
+        _ => bail!(ErrorKind::InvalidFixture("expected the schema to be a map or a transaction".to_string())),
+    };
+    let mut terms = vec![];
+    for (ident, definition) in attributes {
+        let tempid = Value::Text(ident.to_string());
+        terms.push(add_term(tempid.clone(), ident_value(":db/ident"), ident.clone()));
+        match definition {
+            Value::Map(definition) => {
+                for (a, v) in definition {
+                    terms.push(add_term(tempid.clone(), a, v));
+                }
+            },
+            _ => bail!(ErrorKind::InvalidFixture(format!("expected a map defining {}", ident))),
+        }
+    }
+    Ok(Value::Vector(terms))
+}
+
+impl TestStore {
+    /// An in-memory store with `schema` installed -- a map from attribute to definition, or a
+    /// transaction -- and then each of `seed` transacted in turn.
+    pub fn new(schema: &str, seed: &[&str]) -> Result<TestStore> {
+        let mut store = TestStore {
+            store: Store::open("")?,
+            instant: FROZEN_INSTANT,
+            tempids: BTreeMap::new(),
+        };
+        let schema = edn::parse::value(schema).map_err(|e| ErrorKind::EdnParseError(format!("{:?}", e)))?;
+        store.transact(&schema_transaction(schema)?.to_string())?;
+        // The schema's tempids are its idents, which needn't name anything else.
+        store.tempids.clear();
+        for transaction in seed {
+            store.transact(transaction)?;
+        }
+        Ok(store)
+    }
+
+    /// A store built from the EDN `fixture`, a map of the `:schema` and the `:seed` transactions.
+    pub fn from_fixture(fixture: &str) -> Result<TestStore> {
+        let fixture = match edn::parse::value(fixture).map_err(|e| ErrorKind::EdnParseError(format!("{:?}", e)))? {
+            Value::Map(fixture) => fixture,
+            _ => bail!(ErrorKind::InvalidFixture("expected a map of :schema and :seed".to_string())),
+        };
+        let schema = fixture.get(&Value::Keyword(Keyword::new("schema"))).cloned().unwrap_or(Value::Vector(vec![]));
+        let seed: Vec<String> = match fixture.get(&Value::Keyword(Keyword::new("seed"))) {
+            Some(&Value::Vector(ref seed)) => seed.iter().map(|transaction| transaction.to_string()).collect(),
+            None => vec![],
+            _ => bail!(ErrorKind::InvalidFixture("expected :seed to be a vector of transactions".to_string())),
+        };
+        let seed: Vec<&str> = seed.iter().map(|transaction| transaction.as_str()).collect();
+        TestStore::new(&schema.to_string(), &seed)
+    }
+
+    /// Apply `transaction` at the frozen instant, with the tempids of earlier transactions naming
+    /// the same entities.  The transaction mustn't assert its own `:db/txInstant`.
+    pub fn transact(&mut self, transaction: &str) -> Result<TxReport> {
+        let mut terms = match edn::parse::value(transaction).map_err(|e| ErrorKind::EdnParseError(format!("{:?}", e)))? {
+            Value::Vector(terms) => terms,
+            _ => bail!(ErrorKind::TxParseError("expected a vector of transaction entities".to_string())),
+        };
+        terms.push(add_term(Value::Text("datomic.tx".to_string()), ident_value(":db/txInstant"), Value::Integer(self.instant)));
+
+        let hints = self.tempids.iter().fold(TempidHints::new(), |hints, (tempid, &e)| hints.entid(tempid, e));
+        let report = self.store.transact_with_hints(&Value::Vector(terms).to_string(), &hints)?;
+        self.tempids.extend(report.tempids.iter().map(|(tempid, &e)| (tempid.clone(), e)));
+        Ok(report)
+    }
+
+    /// Move the frozen clock on by `millis`.
+    pub fn advance(&mut self, millis: i64) {
+        self.instant += millis;
+    }
+
+    /// The entid of the entity named `tempid` by a transaction so far.
+    pub fn entid(&self, tempid: &str) -> Option<Entid> {
+        self.tempids.get(tempid).cloned()
+    }
+
+    pub fn q_once(&self, query: &str) -> Result<QueryOutput> {
+        self.store.q_once(query)
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    pub fn into_store(self) -> Store {
+        self.store
+    }
+
+    /// The name of entity `e`: the tempid that named it, its ident, or else its entid.
+    fn name(&self, names: &BTreeMap<Entid, &String>, e: Entid) -> Value {
+        match names.get(&e) {
+            Some(tempid) => Value::Text(tempid.to_string()),
+            None => self.store.schema().get_ident(&e).map_or(Value::Integer(e), |ident| ident_value(ident)),
+        }
+    }
+
+    /// The datoms, as `[e a v]` vectors, whose attributes are in `namespaces`, or outside the `db`
+    /// namespaces if there are none.  Entities, and the values of refs, are named as by
+    /// `assert_datoms_eq!`.
+    pub fn datoms(&self, namespaces: &[&str]) -> Result<BTreeSet<Value>> {
+        let names: BTreeMap<Entid, &String> = self.tempids.iter().map(|(tempid, &e)| (e, tempid)).collect();
+        let schema = self.store.schema();
+
+        let mut stmt = self.store.connection().prepare("SELECT DISTINCT e FROM datoms")?;
+        let entities: Result<Vec<Entid>> = stmt.query_and_then(&[], |row| Ok(row.get_checked(0)?))?.collect();
+
+        let mut datoms = BTreeSet::new();
+        for e in entities? {
+            for (a, values) in self.store.entity_raw(e)? {
+                if !compared(&a, namespaces) {
+                    continue;
+                }
+                let value_type = schema.require_attribute_for_ident_str(&a)?.0.value_type;
+                for v in values {
+                    let v = match v {
+                        TypedValue::Ref(x) if value_type == ValueType::Ref => self.name(&names, x),
+                        _ => v.to_edn_value_pair().0,
+                    };
+                    datoms.insert(Value::Vector(vec![self.name(&names, e), ident_value(&a), v]));
+                }
+            }
+        }
+        Ok(datoms)
+    }
+
+    /// Compare `datoms(namespaces)` with the EDN vector of `[e a v]` vectors `expected`,
+    /// describing the differences if there are any.  See `assert_datoms_eq!`.
+    pub fn check_datoms(&self, namespaces: &[&str], expected: &str) -> ::std::result::Result<(), String> {
+        let expected: BTreeSet<Value> = match edn::parse::value(expected) {
+            Ok(Value::Vector(datoms)) => datoms.into_iter().collect(),
+            _ => return Err(format!("expected datoms aren't an EDN vector: {}", expected)),
+        };
+        let actual = self.datoms(namespaces).map_err(|e| format!("couldn't read datoms: {}", e))?;
+        if actual == expected {
+            return Ok(());
+        }
+        let mut message = "datoms differ".to_string();
+        for (heading, datoms) in vec![("missing", expected.difference(&actual)), ("unexpected", actual.difference(&expected))] {
+            let datoms: Vec<String> = datoms.map(|datom| format!("\n  {}", datom)).collect();
+            if !datoms.is_empty() {
+                message.push_str(&format!("\n{}:{}", heading, datoms.concat()));
+            }
+        }
+        Err(message)
+    }
+}
+
+/// Assert that the datoms of a `TestStore` are those of an EDN vector of `[e a v]` vectors, in any
+/// order.  Entities, and the values of refs, are written as the tempids that named them in the
+/// store's transactions, or as their idents or entids.  Only attributes in the given namespaces are
+/// compared; without them, those outside the `db` namespaces are.
+///
+/// ```ignore
+/// assert_datoms_eq!(store, &["person"], r#"[["alice" :person/name "Alice"]
+///                                          ["alice" :person/friend "bob"]
+///                                          ["bob" :person/name "Bob"]]"#);
+/// ```
+#[macro_export]
+macro_rules! assert_datoms_eq {
+    ($store:expr, $expected:expr) => {
+        assert_datoms_eq!($store, &[], $expected)
+    };
+    ($store:expr, $namespaces:expr, $expected:expr) => {
+        if let Err(message) = $store.check_datoms($namespaces, $expected) {
+            panic!("{}", message);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &'static str = r#"
+        {:schema {:person/name   {:db/valueType :db.type/string :db/unique :db.unique/identity}
+                  :person/friend {:db/valueType :db.type/ref}}
+         :seed   [[[:db/add "alice" :person/name "Alice"]]
+                  [[:db/add "bob" :person/name "Bob"] [:db/add "alice" :person/friend "bob"]]]}"#;
+
+    #[test]
+    fn test_test_store() {
+        let mut store = TestStore::from_fixture(FIXTURE).unwrap();
+        assert_datoms_eq!(store, r#"[["alice" :person/name "Alice"]
+                                     ["alice" :person/friend "bob"]
+                                     ["bob" :person/name "Bob"]]"#);
+
+        // The fixture's tempids still name its entities, and the clock stands still until moved.
+        let report = store.transact(r#"[[:db/add "carol" :person/name "Carol"] [:db/add "carol" :person/friend "alice"]]"#).unwrap();
+        assert_eq!(report.tx_instant, FROZEN_INSTANT);
+        assert_eq!(report.tempids["alice"], store.entid("alice").unwrap());
+        store.advance(1000);
+        assert_eq!(store.transact(r#"[[:db/add "bob" :person/name "Robert"]]"#).unwrap().tx_instant, FROZEN_INSTANT + 1000);
+        assert_datoms_eq!(store, &["person"], r#"[["alice" :person/name "Alice"]
+                                                  ["alice" :person/friend "bob"]
+                                                  ["bob" :person/name "Robert"]
+                                                  ["carol" :person/name "Carol"]
+                                                  ["carol" :person/friend "alice"]]"#);
+
+        let message = store.check_datoms(&["person"], r#"[["alice" :person/name "Alice"]]"#).unwrap_err();
+        assert!(message.contains("unexpected:\n  [\"bob\" :person/name \"Robert\"]"));
+
+        // Stores built from the same fixture are alike, entids included.
+        let again = TestStore::from_fixture(FIXTURE).unwrap();
+        assert_eq!(again.entid("bob"), TestStore::from_fixture(FIXTURE).unwrap().entid("bob"));
+        assert!(TestStore::from_fixture("[]").is_err());
+    }
+}
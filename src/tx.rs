@@ -0,0 +1,131 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Transactions: the entities a transaction is made of, parsing them from EDN, and what applying
+//! them reports.
+
+use std::collections::BTreeMap;
+
+use edn;
+use mentat_db::{DB, Entid, TypedValue, partition_end, to_namespaced_keyword};
+use mentat_db;
+use mentat_tx_parser;
+
+pub use mentat_db::{
+    Ambiguity,
+    Conflict,
+    ConflictKind,
+    Constraint,
+    DisplayTxReport,
+    Predicate,
+    RetractPolicy,
+    TX_TEMPID,
+    TempidHints,
+    TxReport,
+    ValidationError,
+};
+pub use mentat_tx::entities::{
+    EntidOrLookupRefOrTempId,
+    Entity,
+    LookupRef,
+    ValueOrLookupRef,
+};
+/// An attribute or entity named by entid or by ident, like `:db/doc`.
+pub use mentat_tx::entities::Entid as EntidOrIdent;
+
+use errors::*;
+
+/// Parse the EDN text of a transaction, like `[[:db/add "a" :db/doc "x"]]`, into its entities.
+pub fn parse_transaction(transaction: &str) -> Result<Vec<Entity>> {
+    let value = edn::parse::value(transaction).map_err(|e| ErrorKind::EdnParseError(format!("{:?}", e)))?;
+    parse_transaction_value(value)
+}
+
+/// Parse an EDN vector of terms into the entities of a transaction.
+pub fn parse_transaction_value(value: edn::Value) -> Result<Vec<Entity>> {
+    let entities = mentat_tx_parser::Tx::parse(&[value][..]).map_err(|e| ErrorKind::TxParseError(format!("{:?}", e)))?;
+    Ok(entities)
+}
+
+/// The term `[:db/add e a v]`.
+pub fn add_term(e: edn::Value, a: edn::Value, v: edn::Value) -> edn::Value {
+    edn::Value::Vector(vec![edn::Value::NamespacedKeyword(edn::NamespacedKeyword::new("db", "add")), e, a, v])
+}
+
+/// The ident `ident`, like `:db/doc`, as an EDN keyword, or as a string if it isn't a namespaced
+/// keyword.
+pub fn ident_value(ident: &str) -> edn::Value {
+    to_namespaced_keyword(ident).map_or_else(|| edn::Value::Text(ident.to_string()), edn::Value::NamespacedKeyword)
+}
+
+/// The value type of an attribute that holds `value`, as the EDN keyword of the type, or `None` if
+/// there's no good guess.
+fn guess_value_type(value: &ValueOrLookupRef) -> Option<&'static str> {
+    match value {
+        &ValueOrLookupRef::LookupRef(_) => Some(":db.type/ref"),
+        &ValueOrLookupRef::Value(ref value) => match value {
+            &edn::Value::Boolean(_) => Some(":db.type/boolean"),
+            &edn::Value::Integer(_) => Some(":db.type/long"),
+            &edn::Value::Float(_) => Some(":db.type/double"),
+            &edn::Value::Text(_) => Some(":db.type/string"),
+            &edn::Value::Instant(_) => Some(":db.type/instant"),
+            &edn::Value::Bytes(_) => Some(":db.type/bytes"),
+            &edn::Value::NamespacedKeyword(_) => Some(":db.type/keyword"),
+            _ => None,
+        },
+    }
+}
+
+/// Install, in `db`, each attribute that `entities` assert by an ident `db`'s schema lacks, with
+/// cardinality one and the value type guessed from the first value asserted for it, at an entid
+/// allocated from `db`'s user partition.  Attributes whose values give no good guess, like `nil`,
+/// are left out.
+///
+/// Returns the terms asserting the installed attributes, to transact along with `entities` against
+/// `db`, and the installed attributes by ident.  Both are empty if there's nothing to install.
+pub fn guess_attributes(db: &mut DB, entities: &[Entity]) -> Result<(Vec<Entity>, BTreeMap<String, Entid>)> {
+    let mut guesses: BTreeMap<String, &'static str> = BTreeMap::new();
+    for entity in entities {
+        if let &Entity::Add { a: EntidOrIdent::Ident(ref a), ref v, .. } = entity {
+            let ident = a.to_string();
+            if db.schema.get_entid(&ident).is_some() || guesses.contains_key(&ident) {
+                continue;
+            }
+            if let Some(value_type) = guess_value_type(v) {
+                guesses.insert(ident, value_type);
+            }
+        }
+    }
+    if guesses.is_empty() {
+        return Ok((vec![], BTreeMap::new()));
+    }
+
+    let value_type_attr = *db.schema.require_entid(&":db/valueType".to_string())?;
+    let cardinality_attr = *db.schema.require_entid(&":db/cardinality".to_string())?;
+    let one = *db.schema.require_entid(&":db.cardinality/one".to_string())?;
+    let mut terms = vec![];
+    let mut installed = BTreeMap::new();
+    for (ident, value_type) in guesses {
+        let end = partition_end(&db.partition_map, ":db.part/user").unwrap_or(i64::max_value());
+        let entid = match db.partition_map.get_mut(":db.part/user").and_then(|p| p.allocate_entid()) {
+            Some(entid) if entid < end => entid,
+            _ => return Err(mentat_db::Error::from(mentat_db::ErrorKind::PartitionExhausted(":db.part/user".to_string())).into()),
+        };
+        let assertions = vec![(value_type_attr, TypedValue::Ref(*db.schema.require_entid(&value_type.to_string())?)),
+                              (cardinality_attr, TypedValue::Ref(one))];
+        let attribute = db.schema.attribute_from_assertions(entid, assertions)?;
+        db.schema.add_ident(ident.clone(), entid);
+        db.schema.set_attribute(entid, attribute)?;
+        terms.push(format!("[:db/add {0} :db/ident {1}] [:db/add {0} :db/valueType {2}] [:db/add {0} :db/cardinality :db.cardinality/one]",
+                           entid, ident, value_type));
+        installed.insert(ident, entid);
+    }
+    Ok((parse_transaction(&format!("[{}]", terms.join(" ")))?, installed))
+}
@@ -0,0 +1,192 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Accounting for the storage used by each attribute namespace.
+//!
+//! `Store::usage` reports, for each namespace of attributes, like `bookmark` for
+//! `:bookmark/title`, the number of datoms currently asserted and the bytes of their string,
+//! fulltext, and other variable-length values, so embedders can hold each feature to a storage
+//! budget.  The figures are approximate: they leave out the transaction log, indices, and
+//! per-row overhead.
+//!
+//! The store counts every datom once, the first time it's asked, and then keeps the counts up to
+//! date from the transaction log as transactions commit.
+
+use std::collections::BTreeMap;
+use std::ops::{Add, Sub};
+
+use rusqlite;
+
+use mentat_db::{Entid, Schema, TypedValue, read_log_value};
+
+use errors::*;
+use query::basis_tx;
+
+/// The storage used by the attributes of a namespace.
+#[derive(Clone,Copy,Debug,Default,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct Usage {
+    /// The datoms currently asserted.
+    pub datoms: i64,
+
+    /// The bytes of the string, fulltext, and other variable-length values of those datoms.
+    pub bytes: i64,
+}
+
+impl Add for Usage {
+    type Output = Usage;
+
+    fn add(self, other: Usage) -> Usage {
+        Usage {
+            datoms: self.datoms + other.datoms,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+
+impl Sub for Usage {
+    type Output = Usage;
+
+    fn sub(self, other: Usage) -> Usage {
+        Usage {
+            datoms: self.datoms - other.datoms,
+            bytes: self.bytes - other.bytes,
+        }
+    }
+}
+
+/// The bytes of a value as stored: the length of text and blobs, and nothing for numbers.
+const VALUE_BYTES: &'static str = "CASE WHEN typeof(v) IN ('text', 'blob') THEN length(CAST(v AS BLOB)) ELSE 0 END";
+
+/// The usage of each attribute, as of a transaction.
+#[derive(Clone,Debug)]
+pub struct UsageTracker {
+    by_attribute: BTreeMap<Entid, Usage>,
+
+    /// The last transaction counted.
+    tx: Entid,
+}
+
+impl UsageTracker {
+    /// Count the datoms in `conn`.
+    pub fn load(conn: &rusqlite::Connection) -> Result<UsageTracker> {
+        // Counting and reading the basis in one read transaction keeps them consistent.
+        conn.execute_batch("BEGIN DEFERRED")?;
+        let loaded = UsageTracker::read(conn);
+        conn.execute_batch("COMMIT")?;
+        loaded
+    }
+
+    fn read(conn: &rusqlite::Connection) -> Result<UsageTracker> {
+        let tx = basis_tx(conn)?;
+        // all_datoms holds fulltext values as text.
+        let sql = format!("SELECT a, COUNT(*), TOTAL({}) FROM all_datoms GROUP BY a", VALUE_BYTES);
+        let mut stmt: rusqlite::Statement = conn.prepare(&sql)?;
+        let rows: Result<BTreeMap<Entid, Usage>> = stmt.query_and_then(&[], |row| {
+            let bytes: f64 = row.get_checked(2)?;
+            Ok((row.get_checked(0)?, Usage {
+                datoms: row.get_checked(1)?,
+                bytes: bytes as i64,
+            }))
+        })?.collect();
+        Ok(UsageTracker {
+            by_attribute: rows?,
+            tx: tx,
+        })
+    }
+
+    /// Count the datoms asserted and retracted by the transactions committed since the last
+    /// counted.
+    pub fn catch_up(&mut self, conn: &rusqlite::Connection, schema: &Schema) -> Result<()> {
+        let mut stmt: rusqlite::Statement = conn.prepare(&format!("SELECT a, v, value_type_tag, {}, added, tx FROM transactions WHERE tx > ? ORDER BY tx", VALUE_BYTES))?;
+        let mut rows = stmt.query(&[&self.tx])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let a: Entid = row.get_checked(0)?;
+            let mut bytes: i64 = row.get_checked(3)?;
+            let added: bool = row.get_checked(4)?;
+
+            // Fulltext values are counted by their text, not by where the log keeps it.
+            if schema.attribute_for_entid(&a).map_or(false, |attribute| attribute.fulltext) {
+                let value_type_tag: i32 = row.get_checked(2)?;
+                if let TypedValue::String(text) = read_log_value(conn, schema, a, row.get_checked(1)?, &value_type_tag, None)? {
+                    bytes = text.len() as i64;
+                }
+            }
+
+            let change = Usage {
+                datoms: 1,
+                bytes: bytes,
+            };
+            let usage = self.by_attribute.entry(a).or_insert_with(Usage::default);
+            *usage = if added { *usage + change } else { *usage - change };
+            self.tx = row.get_checked(5)?;
+        }
+        Ok(())
+    }
+
+    /// The usage of each namespace of attributes named in `schema`.
+    pub fn by_namespace(&self, schema: &Schema) -> BTreeMap<String, Usage> {
+        let mut by_namespace: BTreeMap<String, Usage> = BTreeMap::new();
+        for (a, &usage) in self.by_attribute.iter() {
+            if usage == Usage::default() {
+                continue;
+            }
+            let namespace = match schema.get_ident(a) {
+                Some(ident) if ident.contains('/') => ident.trim_left_matches(':').splitn(2, '/').next().unwrap_or("").to_string(),
+                _ => continue,
+            };
+            let total = by_namespace.entry(namespace).or_insert_with(Usage::default);
+            *total = *total + usage;
+        }
+        by_namespace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use store::Store;
+
+    #[test]
+    fn test_usage() {
+        let mut store = Store::open("").unwrap();
+        store.transact(r#"[[:db/add "t" :db/ident :bookmark/title]
+                           [:db/add "t" :db/valueType :db.type/string]
+                           [:db/add "n" :db/ident :bookmark/notes]
+                           [:db/add "n" :db/valueType :db.type/string]
+                           [:db/add "n" :db/fulltext true]
+                           [:db/add "c" :db/ident :sync/count]
+                           [:db/add "c" :db/valueType :db.type/long]]"#).unwrap();
+        let before = store.usage().unwrap();
+        assert!(before["db"].datoms > 0);
+        assert!(before.get("bookmark").is_none());
+
+        // Counted as transactions commit.
+        let b = store.transact(r#"[[:db/add "b" :bookmark/title "Mozilla"]
+                                   [:db/add "b" :bookmark/notes "ünïcode"]
+                                   [:db/add "b" :sync/count 3]]"#).unwrap().tempids["b"];
+        let usage = store.usage().unwrap();
+        assert_eq!(usage["bookmark"], Usage { datoms: 2, bytes: 7 + 9 });
+        assert_eq!(usage["sync"], Usage { datoms: 1, bytes: 0 });
+
+        // Replacing a value counts the retraction of the old value too.
+        store.transact(&format!("[[:db/add {} :bookmark/title \"MDN\"]]", b)).unwrap();
+        assert_eq!(store.usage().unwrap()["bookmark"], Usage { datoms: 2, bytes: 3 + 9 });
+
+        // Counting afresh agrees.
+        assert_eq!(UsageTracker::load(store.connection()).unwrap().by_namespace(store.schema()), store.usage().unwrap());
+
+        store.transact(&format!("[[:db/retract {} :sync/count 3]]", b)).unwrap();
+        assert!(store.usage().unwrap().get("sync").is_none());
+    }
+}
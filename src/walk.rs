@@ -0,0 +1,118 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![allow(dead_code)]
+
+//! Walks along a ref attribute, like a folder's `:folder/parent` or a person's `:person/friend`:
+//! every entity reachable from a start entity, with a shortest path to each.
+//!
+//! A walk is an `ancestors` or `descendants` query, which SQLite runs as a recursive CTE, together
+//! with the steps that reach each entity, from which we pick the paths.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use rusqlite;
+
+use mentat_db::{Entid, Schema, TypedValue};
+
+use errors::*;
+use query::{QueryResults, q_once};
+
+/// Which way a walk follows its attribute.
+#[derive(Clone,Copy,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub enum Direction {
+    /// From entity to value, like from a folder to its parent: the `ancestors` of the start.
+    Forward,
+    /// From value to entity, like from a folder to its children: the `descendants` of the start.
+    Backward,
+}
+
+impl Direction {
+    fn operator(&self) -> &'static str {
+        match *self {
+            Direction::Forward => "ancestors",
+            Direction::Backward => "descendants",
+        }
+    }
+}
+
+/// An entity reached by a walk.
+#[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
+pub struct Reached {
+    pub entid: Entid,
+    /// The least number of steps from the start to this entity.
+    pub depth: i64,
+    /// A shortest path from the start to this entity, both included.  Where there are several,
+    /// each step is to the least entid.
+    pub path: Vec<Entid>,
+}
+
+/// Walk from `start` along the named ref `attribute` in the given `direction`, at most `max_depth`
+/// steps, or the query translator's default limit if `None`.
+///
+/// Each entity is reached once, however many paths lead to it; the start itself is only reached
+/// if the walk comes back around to it.  The results are ordered by depth, then entid.
+pub fn walk(conn: &rusqlite::Connection, schema: &Schema, start: Entid, attribute: &str, direction: Direction, max_depth: Option<i64>) -> Result<Vec<Reached>> {
+    let a = *schema.require_entid(&attribute.to_string())?;
+    let max_depth = max_depth.map(|depth| format!(" {}", depth)).unwrap_or(String::new());
+
+    // Each step that reaches an entity comes from an entity one step closer to the start, or from
+    // the start itself.
+    let step = match direction {
+        Direction::Forward => format!("[?from {} ?to]", a),
+        Direction::Backward => format!("[?to {} ?from]", a),
+    };
+    let query = format!("[:find ?to ?depth ?from :where [({} {} {}{}) [[?to ?depth]]] {}]",
+                        direction.operator(), start, a, max_depth, step);
+    let rows = match q_once(conn, schema, &query)?.results {
+        QueryResults::Rel(rows) => rows,
+        _ => vec![],
+    };
+
+    let mut depths: BTreeMap<Entid, i64> = BTreeMap::new();
+    let mut steps: BTreeMap<Entid, BTreeSet<Entid>> = BTreeMap::new();
+    for row in rows {
+        if let (&TypedValue::Ref(to), &TypedValue::Long(depth), &TypedValue::Ref(from)) = (&row[0], &row[1], &row[2]) {
+            depths.insert(to, depth);
+            steps.entry(to).or_insert_with(BTreeSet::new).insert(from);
+        }
+    }
+
+    let mut order: Vec<(i64, Entid)> = depths.iter().map(|(&entid, &depth)| (depth, entid)).collect();
+    order.sort();
+
+    // Paths are found in order of depth, so each entity's path extends one found already.
+    let mut paths: BTreeMap<Entid, Vec<Entid>> = BTreeMap::new();
+    let mut reached = Vec::with_capacity(order.len());
+    for (depth, entid) in order {
+        let previous = steps.get(&entid).and_then(|froms| {
+            froms.iter()
+                .filter_map(|from| {
+                    if depth == 1 {
+                        if *from == start { Some(vec![start]) } else { None }
+                    } else if depths.get(from) == Some(&(depth - 1)) {
+                        paths.get(from).cloned()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+        });
+        let mut path = previous.unwrap_or_else(|| vec![start]);
+        path.push(entid);
+        paths.insert(entid, path.clone());
+        reached.push(Reached {
+            entid: entid,
+            depth: depth,
+            path: path,
+        });
+    }
+    Ok(reached)
+}
@@ -56,3 +56,24 @@ fn can_import_sqlite() {
         assert_eq!(me.data, p.data);
     }
 }
+
+#[test]
+fn can_use_facade() {
+    use mentat::{Store, TypedValue};
+    use mentat::query::QueryResults;
+    use mentat::tx::{Entity, parse_transaction};
+
+    let value = mentat::edn::parse::value(":doc").unwrap();
+    assert!(value.is_keyword());
+
+    let entities = parse_transaction(r#"[[:db/add "a" :db/doc "x"]]"#).unwrap();
+    match entities[0] {
+        Entity::Add { .. } => (),
+        ref x => panic!("expected Add, got {:?}", x),
+    }
+
+    let mut store = Store::open("").unwrap();
+    let report = store.transact(r#"[[:db/add "a" :db/doc "x"]]"#).unwrap();
+    let query = format!("[:find ?doc . :where [{} :db/doc ?doc]]", report.tempids["a"]);
+    assert_eq!(store.q_once(&query).unwrap().results, QueryResults::Scalar(Some(TypedValue::String("x".to_string()))));
+}
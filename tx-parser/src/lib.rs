@@ -91,14 +91,28 @@ impl<I> Tx<I>
             .parse_stream(input);
     }
 
-    fn entid_or_lookup_ref() -> TxParser<EntidOrLookupRef, I> {
-        fn_parser(Tx::<I>::entid_or_lookup_ref_, "entid|lookup-ref")
+    fn temp_id() -> TxParser<String, I> {
+        fn_parser(Tx::<I>::temp_id_, "temp-id")
     }
 
-    fn entid_or_lookup_ref_(input: I) -> ParseResult<EntidOrLookupRef, I> {
+    fn temp_id_(input: I) -> ParseResult<String, I> {
+        return satisfy_map(|x: Value| if let Value::Text(y) = x {
+                Some(y)
+            } else {
+                None
+            })
+            .parse_stream(input);
+    }
+
+    fn entid_or_lookup_ref_or_temp_id() -> TxParser<EntidOrLookupRefOrTempId, I> {
+        fn_parser(Tx::<I>::entid_or_lookup_ref_or_temp_id_, "entid|lookup-ref|temp-id")
+    }
+
+    fn entid_or_lookup_ref_or_temp_id_(input: I) -> ParseResult<EntidOrLookupRefOrTempId, I> {
         let p = Tx::<I>::entid()
-            .map(|x| EntidOrLookupRef::Entid(x))
-            .or(Tx::<I>::lookup_ref().map(|x| EntidOrLookupRef::LookupRef(x)))
+            .map(|x| EntidOrLookupRefOrTempId::Entid(x))
+            .or(Tx::<I>::lookup_ref().map(|x| EntidOrLookupRefOrTempId::LookupRef(x)))
+            .or(Tx::<I>::temp_id().map(|x| EntidOrLookupRefOrTempId::TempId(x)))
             .parse_lazy(input)
             .into();
         return p;
@@ -110,7 +124,7 @@ impl<I> Tx<I>
                 if let Value::Vector(y) = x {
                     let mut p = (token(Value::NamespacedKeyword(NamespacedKeyword::new("db",
                                                                                        "add"))),
-                                 Tx::<&[Value]>::entid_or_lookup_ref(),
+                                 Tx::<&[Value]>::entid_or_lookup_ref_or_temp_id(),
                                  Tx::<&[Value]>::entid(),
                                  // TODO: handle lookup-ref.
                                  any(),
@@ -146,7 +160,7 @@ impl<I> Tx<I>
                 if let Value::Vector(y) = x {
                     let mut p = (token(Value::NamespacedKeyword(NamespacedKeyword::new("db",
                                                                                        "retract"))),
-                                 Tx::<&[Value]>::entid_or_lookup_ref(),
+                                 Tx::<&[Value]>::entid_or_lookup_ref_or_temp_id(),
                                  Tx::<&[Value]>::entid(),
                                  // TODO: handle lookup-ref.
                                  any(),
@@ -174,11 +188,14 @@ impl<I> Tx<I>
         fn_parser(Tx::<I>::retract_, "[:db/retract e a v]")
     }
 
+    /// Parse `[:db/retractAttribute e a]`, or its Datomic spelling `[:db.fn/retractAttribute e a]`,
+    /// which retracts every value of `a` for `e`.
     fn retract_attribute_(input: I) -> ParseResult<Entity, I> {
         return satisfy_map(|x: Value| -> Option<Entity> {
                 if let Value::Vector(y) = x {
-                    let mut p = (token(Value::NamespacedKeyword(NamespacedKeyword::new("db", "retractAttribute"))),
-                                 Tx::<&[Value]>::entid_or_lookup_ref(),
+                    let mut p = (token(Value::NamespacedKeyword(NamespacedKeyword::new("db", "retractAttribute")))
+                                     .or(token(Value::NamespacedKeyword(NamespacedKeyword::new("db.fn", "retractAttribute")))),
+                                 Tx::<&[Value]>::entid_or_lookup_ref_or_temp_id(),
                                  Tx::<&[Value]>::entid(),
                                  eof())
                         .map(|(_, e, a, _)| Entity::RetractAttribute { e: e, a: a });
@@ -195,7 +212,7 @@ impl<I> Tx<I>
     }
 
     fn retract_attribute() -> TxParser<Entity, I> {
-        fn_parser(Tx::<I>::retract_attribute_, "[:db/retractAttribute e a]")
+        fn_parser(Tx::<I>::retract_attribute_, "[:db/retractAttribute|:db.fn/retractAttribute e a]")
     }
 
     fn retract_entity_(input: I) -> ParseResult<Entity, I> {
@@ -204,7 +221,7 @@ impl<I> Tx<I>
                     let mut p =
                         (token(Value::NamespacedKeyword(NamespacedKeyword::new("db",
                                                                                "retractEntity"))),
-                         Tx::<&[Value]>::entid_or_lookup_ref(),
+                         Tx::<&[Value]>::entid_or_lookup_ref_or_temp_id(),
                          eof())
                             .map(|(_, e, _)| Entity::RetractEntity { e: e });
                     // TODO: use ok() with a type annotation rather than explicit match.
@@ -287,7 +304,7 @@ mod tests {
         let result = parser.parse(&input[..]);
         assert_eq!(result,
                    Ok((Entity::Add {
-                       e: EntidOrLookupRef::Entid(Entid::Ident(NamespacedKeyword::new("test",
+                       e: EntidOrLookupRefOrTempId::Entid(Entid::Ident(NamespacedKeyword::new("test",
                                                                                       "entid"))),
                        a: Entid::Ident(NamespacedKeyword::new("test", "a")),
                        v: ValueOrLookupRef::Value(Value::Text("v".into())),
@@ -306,13 +323,26 @@ mod tests {
         let result = parser.parse(&input[..]);
         assert_eq!(result,
                    Ok((Entity::Retract {
-                       e: EntidOrLookupRef::Entid(Entid::Entid(101)),
+                       e: EntidOrLookupRefOrTempId::Entid(Entid::Entid(101)),
                        a: Entid::Ident(NamespacedKeyword::new("test", "a")),
                        v: ValueOrLookupRef::Value(Value::Text("v".into())),
                    },
                        &[][..])));
     }
 
+    #[test]
+    fn test_retract_attribute() {
+        let expected = Entity::RetractAttribute {
+            e: EntidOrLookupRefOrTempId::Entid(Entid::Entid(101)),
+            a: Entid::Ident(NamespacedKeyword::new("test", "a")),
+        };
+        for op in vec![kw("db", "retractAttribute"), kw("db.fn", "retractAttribute")] {
+            let input = [Value::Vector(vec![op, Value::Integer(101), kw("test", "a")])];
+            let mut parser = Tx::entity();
+            assert_eq!(parser.parse(&input[..]), Ok((expected.clone(), &[][..])));
+        }
+    }
+
     #[test]
     fn test_lookup_ref() {
         let input = [Value::Vector(vec![kw("db", "add"),
@@ -324,7 +354,7 @@ mod tests {
         let result = parser.parse(&input[..]);
         assert_eq!(result,
                    Ok((Entity::Add {
-                       e: EntidOrLookupRef::LookupRef(LookupRef {
+                       e: EntidOrLookupRefOrTempId::LookupRef(LookupRef {
                            a: Entid::Ident(NamespacedKeyword::new("test", "a1")),
                            v: Value::Text("v1".into()),
                        }),
@@ -334,4 +364,22 @@ mod tests {
                    },
                        &[][..])));
     }
+
+    #[test]
+    fn test_temp_id() {
+        let input = [Value::Vector(vec![kw("db", "add"),
+                                        Value::Text("t".into()),
+                                        kw("test", "a"),
+                                        Value::Text("v".into())])];
+        let mut parser = Tx::entity();
+        let result = parser.parse(&input[..]);
+        assert_eq!(result,
+                   Ok((Entity::Add {
+                       e: EntidOrLookupRefOrTempId::TempId("t".into()),
+                       a: Entid::Ident(NamespacedKeyword::new("test", "a")),
+                       v: ValueOrLookupRef::Value(Value::Text("v".into())),
+                       tx: None,
+                   },
+                       &[][..])));
+    }
 }
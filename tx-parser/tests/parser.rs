@@ -33,13 +33,13 @@ fn test_entities() {
     assert_eq!(result,
                Ok(vec![
                    Entity::Add {
-                       e: EntidOrLookupRef::Entid(Entid::Entid(101)),
+                       e: EntidOrLookupRefOrTempId::Entid(Entid::Entid(101)),
                        a: Entid::Ident(NamespacedKeyword::new("test", "a")),
                        v: ValueOrLookupRef::Value(Value::Text("v".into())),
                        tx: None,
                    },
                    Entity::Retract {
-                       e: EntidOrLookupRef::Entid(Entid::Entid(102)),
+                       e: EntidOrLookupRefOrTempId::Entid(Entid::Entid(102)),
                        a: Entid::Ident(NamespacedKeyword::new("test", "b")),
                        v: ValueOrLookupRef::Value(Value::Text("w".into())),
                    },
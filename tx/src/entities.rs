@@ -29,9 +29,10 @@ pub struct LookupRef {
 }
 
 #[derive(Clone, Debug, PartialEq)]
-pub enum EntidOrLookupRef {
+pub enum EntidOrLookupRefOrTempId {
     Entid(Entid),
     LookupRef(LookupRef),
+    TempId(String),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -43,16 +44,16 @@ pub enum ValueOrLookupRef {
 #[derive(Clone, Debug, PartialEq)]
 pub enum Entity {
     Add {
-        e: EntidOrLookupRef,
+        e: EntidOrLookupRefOrTempId,
         a: Entid,
         v: ValueOrLookupRef,
         tx: Option<Entid>,
     },
     Retract {
-        e: EntidOrLookupRef,
+        e: EntidOrLookupRefOrTempId,
         a: Entid,
         v: ValueOrLookupRef,
     },
-    RetractAttribute { e: EntidOrLookupRef, a: Entid },
-    RetractEntity { e: EntidOrLookupRef },
+    RetractAttribute { e: EntidOrLookupRefOrTempId, a: Entid },
+    RetractEntity { e: EntidOrLookupRefOrTempId },
 }